@@ -0,0 +1,154 @@
+//! Criterion benchmarks of the crypto and storage hot paths.
+//!
+//! Run with `cargo bench --bench hot_paths [filter]`. Save a baseline
+//! with `-- --save-baseline <name>` and compare a later run against it
+//! with `-- --baseline <name>`; criterion reports which cases regressed.
+
+use std::hint::black_box;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tempfile::TempDir;
+use time::OffsetDateTime;
+
+use securechat_core::crypto::{DoubleRatchet, MasterKey, MessageKeyPair};
+use securechat_core::durability::{Durability, FsyncPolicy};
+use securechat_core::protocol::{self, DeliveryStatus, LocalMessage, MessageContent, MessageEnvelope, ProtocolMessage};
+use securechat_core::storage::SecureStorage;
+
+/// Messages in the conversations paged through
+const HISTORY_SIZES: [usize; 2] = [10_000, 100_000];
+const PAGE: usize = 50;
+
+fn message(conversation_id: &str, i: usize) -> LocalMessage {
+    LocalMessage {
+        id: protocol::generate_id(),
+        conversation_id: conversation_id.to_string(),
+        sender_id: "self".to_string(),
+        is_outgoing: i.is_multiple_of(2),
+        content: MessageContent::Text { text: format!("message number {} of the history", i) },
+        timestamp: OffsetDateTime::now_utc(),
+        status: DeliveryStatus::Read,
+        reply_to: None,
+        forwarded_from: None,
+        starred: false,
+        lamport: i as u64,
+    }
+}
+
+fn unlock(c: &mut Criterion) {
+    let (master_key, _) = MasterKey::from_password("correct horse battery staple", &mut rand::thread_rng()).unwrap();
+    // Slow by design; fewer samples rather than minutes
+    let mut group = c.benchmark_group("argon2");
+    group.sample_size(10);
+    group.bench_function("unlock", |b| b.iter(|| {
+        black_box(master_key.unlock("correct horse battery staple").unwrap());
+    }));
+    group.finish();
+}
+
+fn crypto(c: &mut Criterion) {
+    let mut ratchet = DoubleRatchet::initialize(&[7; 32]);
+    c.bench_function("ratchet step", |b| b.iter(|| {
+        ratchet.ratchet(&[9; 32]).unwrap();
+    }));
+    c.bench_function("message key", |b| b.iter(|| {
+        black_box(ratchet.next_sending_key().unwrap());
+    }));
+    
+    let sender = MessageKeyPair::generate();
+    let recipient = MessageKeyPair::generate();
+    let plaintext = protocol::wire::encode(&MessageContent::Text { text: "x".repeat(200) }).unwrap();
+    let encrypted = sender.encrypt_message(&recipient.public_key, &plaintext).unwrap();
+    c.bench_function("message encrypt", |b| b.iter(|| {
+        black_box(sender.encrypt_message(&recipient.public_key, &plaintext).unwrap());
+    }));
+    c.bench_function("message decrypt", |b| b.iter(|| {
+        black_box(recipient.decrypt_message(&encrypted).unwrap());
+    }));
+    
+    let envelope = MessageEnvelope {
+        id: protocol::generate_id(),
+        sender_id: protocol::generate_id(),
+        recipient_id: protocol::generate_id(),
+        timestamp: OffsetDateTime::now_utc(),
+        encrypted_content: encrypted,
+        signature: vec![0; 64],
+        reply_to: None,
+        ratchet_header: None,
+        lamport: Some(42),
+        invite: None,
+        thumbnail: None,
+        sender_device: None,
+        recipient_device: None,
+        message_id: None,
+        view_once: false,
+        policy: None,
+        capabilities: None,
+    };
+    let encoded = protocol::wire::encode(&envelope).unwrap();
+    c.bench_function("envelope encode", |b| b.iter(|| {
+        black_box(protocol::wire::encode(&envelope).unwrap());
+    }));
+    c.bench_function("envelope decode", |b| b.iter(|| {
+        black_box(protocol::wire::decode::<MessageEnvelope>(&encoded).unwrap());
+    }));
+    
+    // What goes over gossipsub
+    let gossip = ProtocolMessage::Encrypted { envelope };
+    let frame = gossip.encode().unwrap();
+    c.bench_function("gossip encode", |b| b.iter(|| {
+        black_box(gossip.encode().unwrap());
+    }));
+    c.bench_function("gossip decode", |b| b.iter(|| {
+        black_box(ProtocolMessage::decode_untrusted(&frame).unwrap());
+    }));
+}
+
+fn storage(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let storage = SecureStorage::create(dir.path().join("bench.db"), "password").unwrap();
+    // Measuring the code, not the disk
+    storage.set_durability(Durability { flush_every_ms: None, flush_on_message: false, fsync: FsyncPolicy::Background }).unwrap();
+    
+    let record = vec![0x5a; 256];
+    let sealed = storage.encrypt_record(b"ct:bench", &record).unwrap();
+    c.bench_function("record encrypt", |b| b.iter(|| {
+        black_box(storage.encrypt_record(b"ct:bench", &record).unwrap());
+    }));
+    c.bench_function("record decrypt", |b| b.iter(|| {
+        black_box(storage.decrypt_record(b"ct:bench", &sealed).unwrap());
+    }));
+    
+    let mut group = c.benchmark_group("page");
+    group.sample_size(20).measurement_time(Duration::from_secs(10));
+    let mut stored = 0;
+    for size in HISTORY_SIZES {
+        let mut middle = None;
+        for i in stored..size {
+            let message = message("bench", i);
+            if i == size / 2 {
+                middle = Some(message.id.clone());
+            }
+            storage.store_message(&message).unwrap();
+        }
+        stored = size;
+        let middle = middle.unwrap();
+        
+        group.bench_with_input(BenchmarkId::new("load", size), &size, |b, _| b.iter(|| {
+            storage.clear_cache();
+            assert_eq!(storage.get_messages("bench", PAGE).unwrap().len(), PAGE);
+        }));
+        group.bench_with_input(BenchmarkId::new("load cached", size), &size, |b, _| b.iter(|| {
+            assert_eq!(storage.get_messages("bench", PAGE).unwrap().len(), PAGE);
+        }));
+        group.bench_with_input(BenchmarkId::new("before", size), &size, |b, _| b.iter(|| {
+            storage.clear_cache();
+            assert_eq!(storage.get_messages_before("bench", &middle, PAGE).unwrap().len(), PAGE);
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, unlock, crypto, storage);
+criterion_main!(benches);
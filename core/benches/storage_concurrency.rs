@@ -0,0 +1,83 @@
+//! Sends while another task scans a long conversation.
+//!
+//! Storage calls share the database instead of queueing on one lock, so
+//! send latency should stay close to the idle figure while the scans run.
+//! For comparison the same calls are then made behind one lock, as they
+//! were when storage sat behind a global lock. Run with
+//! `cargo bench --bench storage_concurrency`.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Mutex, Notify};
+
+use securechat_core::durability::{Durability, FsyncPolicy};
+use securechat_core::SecureChat;
+
+const HISTORY: usize = 5_000;
+const SENDS: usize = 1_000;
+
+/// Time sends while another task keeps scanning the conversation, all
+/// behind `lock` if given
+async fn time_sends(chat: &SecureChat, conversation_id: &str, lock: Option<Arc<Mutex<()>>>) -> Duration {
+    let scanning = Arc::new(Notify::new());
+    let scanner = {
+        let chat = chat.clone();
+        let conversation_id = conversation_id.to_string();
+        let lock = lock.clone();
+        let scanning = scanning.clone();
+        tokio::spawn(async move {
+            loop {
+                let _held = match &lock {
+                    Some(lock) => Some(lock.lock().await),
+                    None => None,
+                };
+                scanning.notify_one();
+                chat.get_messages(&conversation_id, usize::MAX).await.unwrap();
+                drop(_held);
+                tokio::task::yield_now().await;
+            }
+        })
+    };
+    
+    scanning.notified().await;
+    let start = Instant::now();
+    for i in 0..SENDS {
+        let _held = match &lock {
+            Some(lock) => Some(lock.lock().await),
+            None => None,
+        };
+        chat.send_text_message(conversation_id, &format!("timed {}", i)).await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    scanner.abort();
+    elapsed
+}
+
+#[tokio::main]
+async fn main() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let chat = SecureChat::new(None);
+    // Measuring contention, not the disk
+    chat.set_durability(Durability { flush_every_ms: None, flush_on_message: false, fsync: FsyncPolicy::Background }).await.unwrap();
+    chat.create_account(dir.path().join("bench.db"), "password", "Bench").await.unwrap();
+    let contact = chat.add_contact([7; 32], "Peer").await.unwrap();
+    let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap().id;
+    for i in 0..HISTORY {
+        chat.send_text_message(&conversation, &format!("history {}", i)).await.unwrap();
+    }
+    
+    let start = Instant::now();
+    for i in 0..SENDS {
+        chat.send_text_message(&conversation, &format!("idle {}", i)).await.unwrap();
+    }
+    let idle = start.elapsed();
+    let shared = time_sends(&chat, &conversation, None).await;
+    let serialized = time_sends(&chat, &conversation, Some(Arc::new(Mutex::new(())))).await;
+    
+    let per_send = |total: Duration| total / SENDS as u32;
+    println!("{} sends, {} messages of history", SENDS, HISTORY);
+    println!("  idle:                    {:?} per send", per_send(idle));
+    println!("  scanning, shared:        {:?} per send ({:.1}x)", per_send(shared), shared.as_secs_f64() / idle.as_secs_f64());
+    println!("  scanning, behind a lock: {:?} per send ({:.1}x)", per_send(serialized), serialized.as_secs_f64() / idle.as_secs_f64());
+}
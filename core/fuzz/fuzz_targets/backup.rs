@@ -0,0 +1,20 @@
+//! Backup files. Inputs with a well-formed header pay for an Argon2 run,
+//! so expect a few executions per second once the fuzzer finds one.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use securechat_core::backup::{BackupArchive, BackupReader};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(reader) = BackupReader::open(data, "fuzz") {
+        for record in reader {
+            if record.is_err() {
+                break;
+            }
+        }
+    }
+    if let Ok(mut archive) = BackupArchive::open(std::io::Cursor::new(data), "fuzz") {
+        archive.verify();
+        archive.records_with_prefix(b"msg:").ok();
+    }
+});
@@ -0,0 +1,13 @@
+//! Frames as received from peers: protocol messages and bare envelopes.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use securechat_core::protocol::{MessageEnvelope, ProtocolMessage};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = ProtocolMessage::decode_untrusted(data) {
+        // Whatever decodes must encode again
+        message.encode().unwrap();
+    }
+    let _ = MessageEnvelope::deserialize_untrusted(data);
+});
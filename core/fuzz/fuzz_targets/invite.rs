@@ -0,0 +1,13 @@
+//! Invite links and contact QR codes, as pasted or scanned.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use securechat_core::invite::Invite;
+use securechat_core::network::utils::parse_contact_qr;
+use time::OffsetDateTime;
+
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data);
+    let _ = Invite::parse(&text, OffsetDateTime::UNIX_EPOCH);
+    let _ = parse_contact_qr(&text);
+});
@@ -0,0 +1,17 @@
+//! Sealed storage records, as read back from a possibly tampered database.
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use securechat_core::storage::SecureStorage;
+
+fuzz_target!(|data: &[u8]| {
+    static STORAGE: OnceLock<(tempfile::TempDir, SecureStorage)> = OnceLock::new();
+    let (_, storage) = STORAGE.get_or_init(|| {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SecureStorage::create(dir.path().join("db"), "fuzz").unwrap();
+        (dir, storage)
+    });
+    let _ = storage.decrypt_record(b"ct:fuzz", data);
+});
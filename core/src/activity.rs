@@ -0,0 +1,32 @@
+//! The activity feed.
+//!
+//! One list of what happened lately across every conversation, newest
+//! first, for a client's home screen. Messages are read from an index
+//! storage keeps ordered by time across conversations, so only as many
+//! are decrypted as the feed shows; waiting message requests are merged
+//! in by when their latest message arrived.
+
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::protocol::LocalMessage;
+
+/// Most items one feed request returns
+pub const MAX_FEED_LEN: usize = 500;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ActivityItem {
+    /// A message sent or received in a conversation
+    Message { contact_id: String, message: Box<LocalMessage> },
+    /// Messages waiting from someone who isn't a contact
+    MessageRequest { sender_id: String, messages: usize, received_at: OffsetDateTime },
+}
+
+impl ActivityItem {
+    pub fn timestamp(&self) -> OffsetDateTime {
+        match self {
+            Self::Message { message, .. } => message.timestamp,
+            Self::MessageRequest { received_at, .. } => *received_at,
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Per-contact security audit log.
+//!
+//! Every security-relevant change for a contact is appended as an entry
+//! that commits to the hash of the entry before it, and the newest hash is
+//! kept in a separate head record. Removing, reordering or editing entries
+//! breaks the chain, which `verify_chain` reports instead of returning a
+//! silently altered history.
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::devices::{CapabilityTranscript, Downgrade, SessionRequirements};
+use crate::transparency::Inconsistency;
+
+/// Hash of the (absent) entry before the first one
+pub const GENESIS_HASH: [u8; 32] = [0u8; 32];
+
+const HASH_CONTEXT: &str = "SecureChat audit log v1";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SecurityEventKind {
+    /// The contact's identity key was replaced
+    KeyChanged { old_key: [u8; 32], new_key: [u8; 32] },
+    VerificationChanged { verified: bool },
+    SessionReset,
+    DecryptFailed { reason: String },
+    RequirementsChanged { requirements: SessionRequirements },
+    /// A message was refused for falling short of the requirements
+    DowngradeRefused { downgrade: Downgrade },
+    /// The contact's device saw other capabilities than were announced
+    CapabilityMismatch { transcript: CapabilityTranscript },
+    /// Keys we were shown for the contact are missing from their key log
+    KeyLogMismatch { inconsistencies: Vec<Inconsistency> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecurityEvent {
+    pub contact_id: String,
+    /// Position in the contact's log, from 0
+    pub seq: u64,
+    pub kind: SecurityEventKind,
+    pub timestamp: OffsetDateTime,
+    pub prev_hash: [u8; 32],
+    pub hash: [u8; 32],
+}
+
+/// Newest entry of a contact's log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditHead {
+    /// Number of entries
+    pub len: u64,
+    pub hash: [u8; 32],
+}
+
+impl SecurityEvent {
+    /// Entry following `head` (None for an empty log)
+    pub fn next(contact_id: &str, head: Option<&AuditHead>, kind: SecurityEventKind) -> Result<Self> {
+        let mut event = Self {
+            contact_id: contact_id.to_string(),
+            seq: head.map_or(0, |h| h.len),
+            kind,
+            timestamp: OffsetDateTime::now_utc(),
+            prev_hash: head.map_or(GENESIS_HASH, |h| h.hash),
+            hash: GENESIS_HASH,
+        };
+        event.hash = event.compute_hash()?;
+        Ok(event)
+    }
+    
+    pub fn compute_hash(&self) -> Result<[u8; 32]> {
+        let mut hasher = blake3::Hasher::new_derive_key(HASH_CONTEXT);
+        hasher.update(&self.prev_hash);
+        hasher.update(&self.seq.to_be_bytes());
+        hasher.update(&(self.contact_id.len() as u64).to_be_bytes());
+        hasher.update(self.contact_id.as_bytes());
+        hasher.update(&self.timestamp.unix_timestamp_nanos().to_be_bytes());
+        hasher.update(&bincode::serialize(&self.kind)?);
+        Ok(*hasher.finalize().as_bytes())
+    }
+}
+
+/// Check that `events`, oldest first, form one unbroken chain ending at `head`
+pub fn verify_chain(contact_id: &str, events: &[SecurityEvent], head: Option<&AuditHead>) -> Result<()> {
+    let mut prev = GENESIS_HASH;
+    for (i, event) in events.iter().enumerate() {
+        if event.contact_id != contact_id
+            || event.seq != i as u64
+            || event.prev_hash != prev
+            || event.compute_hash()? != event.hash
+        {
+            return Err(anyhow::anyhow!("Audit log broken at entry {}", i));
+        }
+        prev = event.hash;
+    }
+    
+    let expected = head.copied().unwrap_or(AuditHead { len: 0, hash: GENESIS_HASH });
+    if expected.len != events.len() as u64 || expected.hash != prev {
+        return Err(anyhow::anyhow!("Audit log is missing entries"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn chain(n: usize) -> (Vec<SecurityEvent>, AuditHead) {
+        let mut events: Vec<SecurityEvent> = Vec::new();
+        let mut head = None;
+        for i in 0..n {
+            let kind = SecurityEventKind::VerificationChanged { verified: i % 2 == 0 };
+            let event = SecurityEvent::next("c1", head.as_ref(), kind).unwrap();
+            head = Some(AuditHead { len: event.seq + 1, hash: event.hash });
+            events.push(event);
+        }
+        (events, head.unwrap())
+    }
+    
+    #[test]
+    fn test_chain_detects_tampering() {
+        let (events, head) = chain(4);
+        assert!(verify_chain("c1", &events, Some(&head)).is_ok());
+        assert!(verify_chain("c2", &events, Some(&head)).is_err());
+        
+        // Edited entry
+        let mut edited = events.clone();
+        edited[1].kind = SecurityEventKind::SessionReset;
+        assert!(verify_chain("c1", &edited, Some(&head)).is_err());
+        
+        // Dropped entry in the middle, and at the end
+        let mut dropped = events.clone();
+        dropped.remove(2);
+        assert!(verify_chain("c1", &dropped, Some(&head)).is_err());
+        assert!(verify_chain("c1", &events[..3], Some(&head)).is_err());
+        
+        // Reordered entries
+        let mut reordered = events;
+        reordered.swap(0, 1);
+        assert!(verify_chain("c1", &reordered, Some(&head)).is_err());
+    }
+}
@@ -0,0 +1,1109 @@
+//! Encrypted backup container, format version 3.
+//!
+//! A backup is a stream, so arbitrarily large histories can be written and
+//! restored with memory bounded by the chunk size (or the largest single
+//! record, whichever is bigger):
+//!
+//! ```text
+//! magic      "SCBK"
+//! version    u8                      = 3
+//! header_len u32 (big endian)
+//! header     bincode(BackupHeader)
+//! chunk*     len u32 (big endian) | XChaCha20-Poly1305 ciphertext
+//! manifest   len u32 (big endian) | XChaCha20-Poly1305 ciphertext
+//! trailer    chunks u32 | manifest_offset u64 (big endian)
+//! ```
+//!
+//! Chunks use the STREAM construction of `stream`: chunk `i` is sealed
+//! with nonce `nonce_prefix[19] | i as u32 (big endian) | last_flag u8`
+//! and the raw header bytes as associated data. The AEAD tag is the per-chunk MAC;
+//! the counter and final flag make reordering, dropping or truncating
+//! chunks detectable, and the associated data binds every chunk to its
+//! header. A backup ends with exactly one chunk whose flag is 1.
+//!
+//! A chunk's plaintext is a sequence of records, each
+//! `key_len u32 | key | value_len u32 | value` with the storage key and the
+//! record's plaintext value (see `SecureStorage::export_records`). Records
+//! never span chunks.
+//!
+//! The final chunk holds the `BackupManifest` rather than records: counts
+//! and sizes per record category and per conversation, a hash of each
+//! category's records, and the offset, hash and key range of every chunk.
+//! The trailer points at it, so a `BackupArchive` can show what a backup
+//! holds, check every chunk and read only the chunks a partial restore
+//! needs, without decrypting the rest. The trailer isn't authenticated,
+//! but a manifest found through a changed one fails to open under its
+//! chunk number and final flag.
+//!
+//! The content key is wrapped either with a backup password, or, for
+//! unattended scheduled backups, with the account's backup subkey. In the
+//! latter case the header also carries the account's password-wrapped
+//! master key, so both kinds are restored with a single password. On
+//! another of our devices an account backup also opens with the backup key
+//! handed over from the device that made it (see `escrow`).
+//!
+//! Version 2 backups end with a chunk of records and have neither manifest
+//! nor trailer. `BackupReader` still restores them whole. Version 1
+//! backups (a single AES-GCM blob of contacts, conversations and profile)
+//! predate this container and cannot be restored.
+
+use anyhow::{Result, Context};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+use crate::backup_sink::{BackupStore, DirectorySink, UploadWriter};
+use crate::cancel::CancellationToken;
+use crate::crypto::{KeyHierarchy, MasterKey};
+use crate::escrow::EscrowedBackupKey;
+use crate::storage::{self, SecureStorage};
+use crate::stream::{self, StreamOpener, StreamSealer, NONCE_PREFIX_LEN, TAG_LEN};
+
+pub const MAGIC: [u8; 4] = *b"SCBK";
+pub const VERSION: u8 = 3;
+/// Oldest version still read, without a manifest
+pub const LEGACY_VERSION: u8 = 2;
+
+/// Target plaintext size of a chunk before it is sealed
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Upper bound on a single sealed chunk accepted by the parser
+pub const MAX_CHUNK_LEN: usize = 256 * 1024 * 1024;
+
+/// Records written to the database at once when restoring
+pub const RESTORE_BATCH: usize = 1000;
+
+/// Further attempts of a failed scheduled backup before it is reported
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Upper bound on the encoded header accepted by the parser
+const MAX_HEADER_LEN: usize = 64 * 1024;
+
+const TRAILER_LEN: usize = 12;
+
+
+/// Unencrypted backup header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupHeader {
+    pub created_at: OffsetDateTime,
+    /// How the random content key is protected
+    pub key_wrap: BackupKeyWrap,
+    pub nonce_prefix: [u8; NONCE_PREFIX_LEN],
+}
+
+/// Protection of a backup's content key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BackupKeyWrap {
+    /// Wrapped with a backup password via Argon2id
+    Password(MasterKey),
+    /// Wrapped with the account's backup subkey; `master_key` is the
+    /// account's own password-wrapped master key
+    Account {
+        master_key: MasterKey,
+        nonce: [u8; 24],
+        encrypted_key: Vec<u8>,
+    },
+}
+
+/// Automatic backup settings
+#[derive(Clone)]
+pub struct BackupSchedule {
+    pub interval: Duration,
+    /// Where backups are written, see `backup_sink`
+    pub store: Arc<dyn BackupStore>,
+    /// Number of most recent backups to keep
+    pub keep: usize,
+    /// Further attempts, with backoff, before a failed run is reported
+    pub retries: u32,
+}
+
+impl BackupSchedule {
+    /// Backups into a local directory
+    pub fn in_directory(interval: Duration, directory: impl Into<PathBuf>, keep: usize) -> Self {
+        Self {
+            interval,
+            store: Arc::new(DirectorySink::new(directory)),
+            keep,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+}
+
+impl std::fmt::Debug for BackupSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackupSchedule")
+            .field("interval", &self.interval)
+            .field("store", &self.store.name())
+            .field("keep", &self.keep)
+            .field("retries", &self.retries)
+            .finish()
+    }
+}
+
+/// A single storage record carried in a backup
+#[derive(Debug, Clone)]
+pub struct BackupRecord {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// Kind of a record, as a manifest counts them (see
+/// `storage::record_category`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordCategory {
+    /// Identity, profile, devices and settings
+    Account,
+    /// Contacts and everything kept per contact
+    Contacts,
+    Conversations,
+    Messages,
+    Attachments,
+    /// Indexes and references between records
+    Indexes,
+    Other,
+}
+
+/// Records of one category or conversation
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordSummary {
+    pub records: u64,
+    /// Plaintext bytes of keys and values
+    pub size: u64,
+    /// BLAKE3 of the records in backup order, encoded as in chunks
+    pub hash: [u8; 32],
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSummary {
+    /// Position of the chunk's length in the file
+    pub offset: u64,
+    /// Sealed length
+    pub len: u32,
+    /// BLAKE3 of the sealed chunk
+    pub hash: [u8; 32],
+    pub records: u32,
+    /// Smallest and largest key in the chunk
+    pub first_key: Vec<u8>,
+    pub last_key: Vec<u8>,
+}
+
+/// What a backup holds, sealed as its final chunk
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub records: u64,
+    pub size: u64,
+    pub categories: BTreeMap<RecordCategory, RecordSummary>,
+    /// Message records of each conversation
+    pub conversations: BTreeMap<String, RecordSummary>,
+    /// Chunks of records, in order
+    pub chunks: Vec<ChunkSummary>,
+}
+
+/// Part of a backup to restore into an existing account (see
+/// `SecureStorage::import_selected`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RestoreSelection {
+    /// Contacts with their settings, notes and devices
+    Contacts,
+    /// One conversation with its messages, attachments and contact
+    Conversation { conversation_id: String },
+}
+
+#[derive(Default)]
+struct Tally {
+    records: u64,
+    size: u64,
+    hasher: blake3::Hasher,
+}
+
+impl Tally {
+    fn add(&mut self, key: &[u8], value: &[u8]) {
+        self.records += 1;
+        self.size += (key.len() + value.len()) as u64;
+        self.hasher.update(&(key.len() as u32).to_be_bytes());
+        self.hasher.update(key);
+        self.hasher.update(&(value.len() as u32).to_be_bytes());
+        self.hasher.update(value);
+    }
+    
+    fn summary(&self) -> RecordSummary {
+        RecordSummary {
+            records: self.records,
+            size: self.size,
+            hash: *self.hasher.finalize().as_bytes(),
+        }
+    }
+}
+
+/// Builds the manifest of the records written or read so far
+#[derive(Default)]
+struct ManifestBuilder {
+    total: Tally,
+    categories: BTreeMap<RecordCategory, Tally>,
+    conversations: BTreeMap<String, Tally>,
+    chunks: Vec<ChunkSummary>,
+    /// Of the records since the last chunk ended
+    chunk: Option<ChunkSummary>,
+}
+
+impl ManifestBuilder {
+    fn add_record(&mut self, key: &[u8], value: &[u8]) {
+        self.total.add(key, value);
+        self.categories.entry(storage::record_category(key)).or_default().add(key, value);
+        if let Some(conversation_id) = storage::message_conversation_id(key) {
+            self.conversations.entry(conversation_id).or_default().add(key, value);
+        }
+        
+        let chunk = self.chunk.get_or_insert_with(|| ChunkSummary {
+            first_key: key.to_vec(),
+            last_key: key.to_vec(),
+            ..Default::default()
+        });
+        chunk.records += 1;
+        if key < chunk.first_key.as_slice() {
+            chunk.first_key = key.to_vec();
+        }
+        if key > chunk.last_key.as_slice() {
+            chunk.last_key = key.to_vec();
+        }
+    }
+    
+    /// The records added since the last chunk were sealed as `sealed`,
+    /// written at `offset`
+    fn end_chunk(&mut self, offset: u64, sealed: &[u8]) {
+        let mut chunk = self.chunk.take().unwrap_or_default();
+        chunk.offset = offset;
+        chunk.len = sealed.len() as u32;
+        chunk.hash = *blake3::hash(sealed).as_bytes();
+        self.chunks.push(chunk);
+    }
+    
+    fn finish(self) -> BackupManifest {
+        BackupManifest {
+            records: self.total.records,
+            size: self.total.size,
+            categories: self.categories.iter().map(|(category, tally)| (*category, tally.summary())).collect(),
+            conversations: self.conversations.iter().map(|(id, tally)| (id.clone(), tally.summary())).collect(),
+            chunks: self.chunks,
+        }
+    }
+}
+
+/// Streams records into an encrypted backup
+pub struct BackupWriter<W: Write> {
+    writer: W,
+    sealer: StreamSealer,
+    buffer: Vec<u8>,
+    content_key: [u8; 32],
+    /// Bytes written so far
+    offset: u64,
+    manifest: ManifestBuilder,
+}
+
+impl<W: Write> BackupWriter<W> {
+    /// Start a backup protected by its own password
+    pub fn new(writer: W, password: &str) -> Result<Self> {
+        let mut rng = rand::thread_rng();
+        let (key_wrap, content_key) = MasterKey::from_password(password, &mut rng)
+            .context("Failed to derive backup key")?;
+        
+        Self::with_key_wrap(writer, BackupKeyWrap::Password(key_wrap), content_key)
+    }
+    
+    /// Start an unattended backup, restorable with the account password
+    pub fn for_account(writer: W, storage: &SecureStorage) -> Result<Self> {
+        use chacha20poly1305::aead::{AeadCore, OsRng};
+        
+        let mut content_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut content_key);
+        
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let encrypted_key = XChaCha20Poly1305::new((&storage.keys().backup).into())
+            .encrypt(&nonce, content_key.as_ref())
+            .map_err(|e| anyhow::anyhow!("Failed to wrap backup key: {:?}", e))?;
+        
+        let key_wrap = BackupKeyWrap::Account {
+            master_key: storage.master_key_store()?,
+            nonce: nonce.into(),
+            encrypted_key,
+        };
+        Self::with_key_wrap(writer, key_wrap, content_key)
+    }
+    
+    /// Write the header and prepare to stream records
+    fn with_key_wrap(mut writer: W, key_wrap: BackupKeyWrap, content_key: [u8; 32]) -> Result<Self> {
+        let nonce_prefix = stream::random_nonce_prefix();
+        let header = BackupHeader {
+            created_at: OffsetDateTime::now_utc(),
+            key_wrap,
+            nonce_prefix,
+        };
+        let header_bytes = bincode::serialize(&header)
+            .context("Failed to serialize backup header")?;
+        
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.write_all(&(header_bytes.len() as u32).to_be_bytes())?;
+        writer.write_all(&header_bytes)?;
+        
+        Ok(Self {
+            writer,
+            sealer: StreamSealer::new(&content_key, nonce_prefix, &header_bytes),
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            content_key,
+            offset: (MAGIC.len() + 1 + 4 + header_bytes.len()) as u64,
+            manifest: ManifestBuilder::default(),
+        })
+    }
+    
+    /// Key protecting this backup's chunks. Identity keys inside the backup
+    /// are wrapped with it (see `SecureStorage::export_records`).
+    pub fn content_key(&self) -> &[u8; 32] {
+        &self.content_key
+    }
+    
+    pub fn write_record(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        encode_record(&mut self.buffer, key, value);
+        self.manifest.add_record(key, value);
+        
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.flush_chunk()?;
+        }
+        Ok(())
+    }
+    
+    /// Seal the manifest as the final chunk, write the trailer and return
+    /// the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        if !self.buffer.is_empty() {
+            self.flush_chunk()?;
+        }
+        let manifest = std::mem::take(&mut self.manifest).finish();
+        let manifest_bytes = bincode::serialize(&manifest)
+            .context("Failed to serialize backup manifest")?;
+        let manifest_offset = self.offset;
+        let sealed = self.sealer.seal(&manifest_bytes, true)
+            .context("Failed to seal backup manifest")?;
+        self.write_sealed(&sealed)?;
+        
+        self.writer.write_all(&(manifest.chunks.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&manifest_offset.to_be_bytes())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+    
+    fn flush_chunk(&mut self) -> Result<()> {
+        let sealed = self.sealer.seal(&self.buffer, false)
+            .context("Failed to seal backup chunk")?;
+        self.manifest.end_chunk(self.offset, &sealed);
+        self.write_sealed(&sealed)?;
+        self.buffer.clear();
+        Ok(())
+    }
+    
+    fn write_sealed(&mut self, sealed: &[u8]) -> Result<()> {
+        self.writer.write_all(&(sealed.len() as u32).to_be_bytes())?;
+        self.writer.write_all(sealed)?;
+        self.offset += 4 + sealed.len() as u64;
+        Ok(())
+    }
+}
+
+/// Parses and authenticates a backup stream, yielding its records
+pub struct BackupReader<R: Read> {
+    reader: R,
+    version: u8,
+    header: BackupHeader,
+    opener: StreamOpener,
+    pending: std::vec::IntoIter<BackupRecord>,
+    finished: bool,
+    content_key: [u8; 32],
+    /// Bytes read so far
+    offset: u64,
+    /// Of the records read, to check against the manifest
+    tally: ManifestBuilder,
+    manifest: Option<BackupManifest>,
+}
+
+impl<R: Read> BackupReader<R> {
+    /// Read the header and unlock the content key with the password
+    pub fn open(reader: R, password: &str) -> Result<Self> {
+        Self::open_with(reader, password, &[])
+    }
+    
+    /// Like `open`, trying the backup keys received from our other devices
+    /// before the password
+    pub fn open_with(mut reader: R, password: &str, backup_keys: &[EscrowedBackupKey]) -> Result<Self> {
+        let (version, header_bytes, header) = read_header(&mut reader)?;
+        let content_key = header.key_wrap.unlock_with(password, backup_keys)?;
+        
+        Ok(Self {
+            reader,
+            version,
+            opener: StreamOpener::new(&content_key, header.nonce_prefix, &header_bytes),
+            header,
+            pending: Vec::new().into_iter(),
+            finished: false,
+            content_key,
+            offset: (MAGIC.len() + 1 + 4 + header_bytes.len()) as u64,
+            tally: ManifestBuilder::default(),
+            manifest: None,
+        })
+    }
+    
+    pub fn header(&self) -> &BackupHeader {
+        &self.header
+    }
+    
+    /// The backup's manifest, once every record was read and matched it.
+    /// Version 2 backups have none.
+    pub fn manifest(&self) -> Option<&BackupManifest> {
+        self.manifest.as_ref()
+    }
+    
+    /// Key the backup's identity records are wrapped with
+    pub fn content_key(&self) -> &[u8; 32] {
+        &self.content_key
+    }
+    
+    /// Decrypt the next chunk; returns false once the final chunk is consumed
+    fn read_chunk(&mut self) -> Result<bool> {
+        if self.finished {
+            return Ok(false);
+        }
+        
+        let len = read_u32(&mut self.reader)
+            .context("Backup truncated before final chunk")? as usize;
+        if !(TAG_LEN..=MAX_CHUNK_LEN).contains(&len) {
+            return Err(anyhow::anyhow!("Invalid backup chunk length {}", len));
+        }
+        let mut sealed = vec![0u8; len];
+        self.reader.read_exact(&mut sealed)
+            .context("Truncated backup chunk")?;
+        let offset = self.offset;
+        self.offset += 4 + len as u64;
+        
+        let (plaintext, last) = self.opener.open(&sealed)
+            .context("Invalid backup chunk")?;
+        
+        if self.version == LEGACY_VERSION || !last {
+            let records = parse_records(&plaintext)?;
+            if self.version != LEGACY_VERSION {
+                for record in &records {
+                    self.tally.add_record(&record.key, &record.value);
+                }
+                self.tally.end_chunk(offset, &sealed);
+            }
+            self.pending = records.into_iter();
+        } else {
+            self.check_manifest(&plaintext, offset)?;
+        }
+        
+        if last {
+            self.finished = true;
+            let mut trailing = [0u8; 1];
+            if self.reader.read(&mut trailing)? != 0 {
+                return Err(anyhow::anyhow!("Unexpected data after final backup chunk"));
+            }
+        }
+        Ok(true)
+    }
+    
+    /// Match the manifest, sealed at `offset`, and the trailer after it
+    /// against what was read
+    fn check_manifest(&mut self, plaintext: &[u8], offset: u64) -> Result<()> {
+        let manifest: BackupManifest = bincode::deserialize(plaintext)
+            .context("Malformed backup manifest")?;
+        if std::mem::take(&mut self.tally).finish() != manifest {
+            return Err(anyhow::anyhow!("Backup doesn't match its manifest"));
+        }
+        if read_trailer(&mut self.reader)? != (manifest.chunks.len() as u32, offset) {
+            return Err(anyhow::anyhow!("Backup trailer doesn't match its manifest"));
+        }
+        self.manifest = Some(manifest);
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for BackupReader<R> {
+    type Item = Result<BackupRecord>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.pending.next() {
+                return Some(Ok(record));
+            }
+            match self.read_chunk() {
+                Ok(true) => continue,
+                Ok(false) => return None,
+                Err(e) => {
+                    // Stop after the first error; the stream can't be trusted
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// A backup file read through its manifest: what it holds and single
+/// chunks, without reading the rest
+pub struct BackupArchive<R: Read + Seek> {
+    reader: R,
+    header: BackupHeader,
+    opener: StreamOpener,
+    content_key: [u8; 32],
+    manifest: BackupManifest,
+    /// Records of the chunk read last, which a partial restore asks for
+    /// again and again
+    cached: Option<(usize, Vec<BackupRecord>)>,
+}
+
+impl<R: Read + Seek> BackupArchive<R> {
+    /// Unlock the backup with the password and read its manifest
+    pub fn open(reader: R, password: &str) -> Result<Self> {
+        Self::open_with(reader, password, &[])
+    }
+    
+    /// Like `open`, trying the backup keys received from our other devices
+    /// before the password
+    pub fn open_with(mut reader: R, password: &str, backup_keys: &[EscrowedBackupKey]) -> Result<Self> {
+        let (version, header_bytes, header) = read_header(&mut reader)?;
+        if version == LEGACY_VERSION {
+            return Err(anyhow::anyhow!("Version {} backups have no manifest and can only be restored whole", version));
+        }
+        let content_key = header.key_wrap.unlock_with(password, backup_keys)?;
+        let opener = StreamOpener::new(&content_key, header.nonce_prefix, &header_bytes);
+        
+        reader.seek(SeekFrom::End(-(TRAILER_LEN as i64)))
+            .context("Backup is too short")?;
+        let (chunks, manifest_offset) = read_trailer(&mut reader)?;
+        let sealed = read_sealed_at(&mut reader, manifest_offset)
+            .context("Backup manifest is missing")?;
+        let plaintext = opener.open_at(chunks, &sealed, true)
+            .context("Backup manifest is damaged")?;
+        let manifest: BackupManifest = bincode::deserialize(&plaintext)
+            .context("Malformed backup manifest")?;
+        if manifest.chunks.len() != chunks as usize {
+            return Err(anyhow::anyhow!("Backup trailer doesn't match its manifest"));
+        }
+        
+        Ok(Self { reader, header, opener, content_key, manifest, cached: None })
+    }
+    
+    pub fn header(&self) -> &BackupHeader {
+        &self.header
+    }
+    
+    pub fn manifest(&self) -> &BackupManifest {
+        &self.manifest
+    }
+    
+    /// Key the backup's identity records are wrapped with
+    pub fn content_key(&self) -> &[u8; 32] {
+        &self.content_key
+    }
+    
+    /// Check every chunk against its hash in the manifest, without
+    /// decrypting it. Returns the numbers of those damaged or missing.
+    pub fn verify(&mut self) -> Vec<usize> {
+        (0..self.manifest.chunks.len())
+            .filter(|index| self.read_sealed(*index).is_err())
+            .collect()
+    }
+    
+    /// Records whose key starts with `prefix`, read from the chunks whose
+    /// key range can hold them
+    pub fn records_with_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let indexes: Vec<usize> = self.manifest.chunks.iter()
+            .enumerate()
+            .filter(|(_, chunk)| {
+                // Keys with the prefix sort from the prefix itself up to
+                // the first key after them that doesn't have it
+                chunk.last_key.as_slice() >= prefix
+                    && (chunk.first_key.as_slice() <= prefix || chunk.first_key.starts_with(prefix))
+            })
+            .map(|(index, _)| index)
+            .collect();
+        
+        let mut records = Vec::new();
+        for index in indexes {
+            records.extend(self.chunk_records(index)?.iter()
+                .filter(|record| record.key.starts_with(prefix))
+                .map(|record| (record.key.clone(), record.value.clone())));
+        }
+        Ok(records)
+    }
+    
+    fn chunk_records(&mut self, index: usize) -> Result<&[BackupRecord]> {
+        let records = match self.cached.take() {
+            Some((cached, records)) if cached == index => records,
+            _ => {
+                let sealed = self.read_sealed(index)?;
+                let plaintext = self.opener.open_at(index as u32, &sealed, false)
+                    .with_context(|| format!("Invalid backup chunk {}", index))?;
+                parse_records(&plaintext)?
+            }
+        };
+        Ok(&self.cached.insert((index, records)).1)
+    }
+    
+    /// Chunk `index` as sealed, if it matches the manifest
+    fn read_sealed(&mut self, index: usize) -> Result<Vec<u8>> {
+        let chunk = &self.manifest.chunks[index];
+        let sealed = read_sealed_at(&mut self.reader, chunk.offset)?;
+        if sealed.len() != chunk.len as usize || *blake3::hash(&sealed).as_bytes() != chunk.hash {
+            return Err(anyhow::anyhow!("Backup chunk {} is damaged", index));
+        }
+        Ok(sealed)
+    }
+}
+
+impl BackupKeyWrap {
+    /// Recover the content key. Account-wrapped backups take the account
+    /// password that was current when the backup was made.
+    pub fn unlock(&self, password: &str) -> Result<[u8; 32]> {
+        self.unlock_with(password, &[])
+    }
+    
+    /// Like `unlock`, but an account-wrapped backup is first tried with
+    /// `backup_keys`, so it opens without the password of the account on
+    /// another device that made it
+    pub fn unlock_with(&self, password: &str, backup_keys: &[EscrowedBackupKey]) -> Result<[u8; 32]> {
+        match self {
+            BackupKeyWrap::Password(key_wrap) => key_wrap.unlock(password)
+                .context("Failed to unlock backup - wrong password?"),
+            BackupKeyWrap::Account { master_key, nonce, encrypted_key } => {
+                let escrowed = backup_keys.iter()
+                    .find_map(|key| unwrap_content_key(key.key(), nonce, encrypted_key).ok());
+                if let Some(content_key) = escrowed {
+                    return Ok(content_key);
+                }
+                let master_key = master_key.unlock(password)
+                    .context("Failed to unlock backup - wrong account password?")?;
+                let keys = KeyHierarchy::derive(&master_key)?;
+                unwrap_content_key(&keys.backup, nonce, encrypted_key)
+            }
+        }
+    }
+}
+
+/// The content key of an account backup, unwrapped with a backup subkey
+fn unwrap_content_key(backup_key: &[u8; 32], nonce: &[u8; 24], encrypted_key: &[u8]) -> Result<[u8; 32]> {
+    let content_key = XChaCha20Poly1305::new(backup_key.into())
+        .decrypt(XNonce::from_slice(nonce), encrypted_key)
+        .map_err(|_| anyhow::anyhow!("Backup key is corrupt"))?;
+    content_key.try_into()
+        .map_err(|_| anyhow::anyhow!("Backup key has wrong length"))
+}
+
+/// Stream every record of `storage` into `backup`, stopping at the next
+/// record once `cancel` is cancelled. `progress` gets the number of
+/// records written so far.
+pub fn export_storage<W: Write>(
+    storage: &SecureStorage,
+    mut backup: BackupWriter<W>,
+    cancel: &CancellationToken,
+    mut progress: impl FnMut(usize),
+) -> Result<W> {
+    let transport_key = *backup.content_key();
+    for (written, record) in storage.export_records(&transport_key).enumerate() {
+        cancel.check()?;
+        let (key, value) = record?;
+        backup.write_record(&key, &value)?;
+        progress(written + 1);
+    }
+    backup.finish()
+}
+
+/// Outcome of one scheduled run
+#[derive(Debug, Clone)]
+pub enum ScheduledBackup {
+    /// `location` is where the store put it, e.g. a path or URL
+    Written { location: String, size: u64, pruned: usize },
+    /// Nothing changed since the last backup (matching database checksum)
+    Unchanged,
+}
+
+/// Write one scheduled backup into `schedule.store`, verify it and
+/// apply the retention policy. `last_checksum` carries the database
+/// checksum between runs so unchanged databases are not backed up again.
+/// A run that fails or is cancelled leaves no backup behind.
+pub fn run_scheduled_backup(
+    storage: &SecureStorage,
+    schedule: &BackupSchedule,
+    last_checksum: &mut Option<u32>,
+    cancel: &CancellationToken,
+) -> Result<ScheduledBackup> {
+    let checksum = storage.checksum()?;
+    if *last_checksum == Some(checksum) {
+        return Ok(ScheduledBackup::Unchanged);
+    }
+    
+    let name = format!(
+        "{}{:020}{}",
+        SCHEDULED_PREFIX,
+        OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000,
+        SCHEDULED_EXTENSION,
+    );
+    
+    let upload = UploadWriter::new(schedule.store.create(&name)?);
+    let writer = BackupWriter::for_account(upload, storage)?;
+    // Dropped on error, the upload is discarded
+    let upload = export_storage(storage, writer, cancel, |_| {})?;
+    let size = upload.written();
+    let location = upload.finalize()?;
+    
+    let verified = schedule.store.read(&name)
+        .and_then(|reader| verify_backup_header(reader, storage));
+    if let Err(e) = verified {
+        schedule.store.remove(&name).ok();
+        return Err(e);
+    }
+    
+    *last_checksum = Some(checksum);
+    let pruned = prune_backups(&*schedule.store, schedule.keep)?;
+    
+    Ok(ScheduledBackup::Written { location, size, pruned })
+}
+
+const SCHEDULED_PREFIX: &str = "securechat-";
+const SCHEDULED_EXTENSION: &str = ".scbk";
+
+/// Test-decrypt a written backup's header: the content key must unwrap and
+/// the first chunk must authenticate
+fn verify_backup_header<R: Read>(mut reader: R, storage: &SecureStorage) -> Result<()> {
+    let (version, header_bytes, header) = read_header(&mut reader)
+        .context("Backup verification failed")?;
+    if version != VERSION {
+        return Err(anyhow::anyhow!("Backup verification failed: version {}", version));
+    }
+    
+    let content_key: [u8; 32] = match &header.key_wrap {
+        BackupKeyWrap::Account { nonce, encrypted_key, .. } => {
+            XChaCha20Poly1305::new((&storage.keys().backup).into())
+                .decrypt(XNonce::from_slice(nonce), encrypted_key.as_ref())
+                .map_err(|_| anyhow::anyhow!("Backup verification failed: key does not unwrap"))?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Backup key has wrong length"))?
+        }
+        BackupKeyWrap::Password(_) => {
+            return Err(anyhow::anyhow!("Backup verification failed: not an account backup"));
+        }
+    };
+    
+    let len = read_u32(&mut reader)? as usize;
+    if !(TAG_LEN..=MAX_CHUNK_LEN).contains(&len) {
+        return Err(anyhow::anyhow!("Backup verification failed: bad chunk length"));
+    }
+    let mut sealed = vec![0u8; len];
+    reader.read_exact(&mut sealed)?;
+    if StreamOpener::new(&content_key, header.nonce_prefix, &header_bytes).open(&sealed).is_err() {
+        return Err(anyhow::anyhow!("Backup verification failed: first chunk does not authenticate"));
+    }
+    Ok(())
+}
+
+/// Delete all but the `keep` most recent scheduled backups in `store`
+pub fn prune_backups(store: &dyn BackupStore, keep: usize) -> Result<usize> {
+    let mut backups: Vec<String> = store.list()?
+        .into_iter()
+        .filter(|name| name.starts_with(SCHEDULED_PREFIX) && name.ends_with(SCHEDULED_EXTENSION))
+        .collect();
+    
+    // Names embed a zero-padded timestamp, so lexical order is chronological
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep);
+    for name in &backups[..excess] {
+        store.remove(name)
+            .with_context(|| format!("Failed to remove old backup {}", name))?;
+    }
+    Ok(excess)
+}
+
+/// Read up to the chunks: the version, the raw header the chunks are
+/// bound to, and the header
+fn read_header<R: Read>(reader: &mut R) -> Result<(u8, Vec<u8>, BackupHeader)> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)
+        .context("Backup is too short")?;
+    if magic != MAGIC {
+        return Err(anyhow::anyhow!("Not a SecureChat backup (or a version 1 backup, which cannot be restored)"));
+    }
+    
+    let mut version = [0u8; 1];
+    reader.read_exact(&mut version)?;
+    if !(LEGACY_VERSION..=VERSION).contains(&version[0]) {
+        return Err(anyhow::anyhow!("Unsupported backup version {}", version[0]));
+    }
+    
+    let header_len = read_u32(reader)? as usize;
+    if header_len > MAX_HEADER_LEN {
+        return Err(anyhow::anyhow!("Backup header too large"));
+    }
+    let mut header_bytes = vec![0u8; header_len];
+    reader.read_exact(&mut header_bytes)
+        .context("Truncated backup header")?;
+    let header: BackupHeader = bincode::deserialize(&header_bytes)
+        .context("Malformed backup header")?;
+    Ok((version[0], header_bytes, header))
+}
+
+/// Number of chunks before the manifest, and the manifest's offset
+fn read_trailer<R: Read>(reader: &mut R) -> Result<(u32, u64)> {
+    let mut trailer = [0u8; TRAILER_LEN];
+    reader.read_exact(&mut trailer)
+        .context("Backup truncated before trailer")?;
+    Ok((
+        u32::from_be_bytes(trailer[..4].try_into().unwrap()),
+        u64::from_be_bytes(trailer[4..].try_into().unwrap()),
+    ))
+}
+
+fn read_sealed_at<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let len = read_u32(reader)? as usize;
+    if !(TAG_LEN..=MAX_CHUNK_LEN).contains(&len) {
+        return Err(anyhow::anyhow!("Invalid backup chunk length {}", len));
+    }
+    let mut sealed = vec![0u8; len];
+    reader.read_exact(&mut sealed)
+        .context("Truncated backup chunk")?;
+    Ok(sealed)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn encode_record(buffer: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    buffer.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(key);
+    buffer.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(value);
+}
+
+fn parse_records(mut data: &[u8]) -> Result<Vec<BackupRecord>> {
+    fn take<'a>(data: &mut &'a [u8]) -> Result<&'a [u8]> {
+        if data.len() < 4 {
+            return Err(anyhow::anyhow!("Truncated backup record"));
+        }
+        let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        if data.len() - 4 < len {
+            return Err(anyhow::anyhow!("Truncated backup record"));
+        }
+        let (field, rest) = data[4..].split_at(len);
+        *data = rest;
+        Ok(field)
+    }
+    
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        let key = take(&mut data)?.to_vec();
+        let value = take(&mut data)?.to_vec();
+        records.push(BackupRecord { key, value });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    
+    fn sample_backup(records: usize) -> Vec<u8> {
+        let mut writer = BackupWriter::new(Vec::new(), "backup-pw").unwrap();
+        for i in 0..records {
+            writer.write_record(format!("msg:{}", i).as_bytes(), &vec![i as u8; 1024]).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+    
+    #[test]
+    fn test_roundtrip_spans_chunks() {
+        let data = sample_backup(200);
+        let records: Vec<_> = BackupReader::open(data.as_slice(), "backup-pw").unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        
+        assert_eq!(records.len(), 200);
+        assert_eq!(records[150].key, b"msg:150");
+        assert_eq!(records[150].value, vec![150u8; 1024]);
+    }
+    
+    #[test]
+    fn test_empty_backup() {
+        let data = sample_backup(0);
+        assert_eq!(BackupReader::open(data.as_slice(), "backup-pw").unwrap().count(), 0);
+    }
+    
+    #[test]
+    fn test_rejects_wrong_password_and_tampering() {
+        let data = sample_backup(200);
+        assert!(BackupReader::open(data.as_slice(), "wrong").is_err());
+        
+        let mut tampered = data.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let result: Result<Vec<_>> = BackupReader::open(tampered.as_slice(), "backup-pw").unwrap().collect();
+        assert!(result.is_err());
+    }
+    
+    #[test]
+    fn test_scheduled_backups_rotate() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let storage = SecureStorage::create(temp_dir.path().join("db"), "account-pw").unwrap();
+        let directory = temp_dir.path().join("backups");
+        let schedule = BackupSchedule::in_directory(Duration::from_secs(60), &directory, 2);
+        
+        let mut checksum = None;
+        let mut written = Vec::new();
+        for i in 0..3 {
+            storage.set_setting("counter", &i.to_string()).unwrap();
+            match run_scheduled_backup(&storage, &schedule, &mut checksum, &CancellationToken::new()).unwrap() {
+                ScheduledBackup::Written { location, .. } => written.push(PathBuf::from(location)),
+                ScheduledBackup::Unchanged => panic!("database changed"),
+            }
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        
+        // Nothing changed since the last run
+        assert!(matches!(
+            run_scheduled_backup(&storage, &schedule, &mut checksum, &CancellationToken::new()).unwrap(),
+            ScheduledBackup::Unchanged
+        ));
+        
+        assert!(!written[0].exists());
+        assert!(written[1].exists() && written[2].exists());
+        
+        // Restorable with the account password alone
+        let file = fs::File::open(&written[2]).unwrap();
+        let records: Vec<_> = BackupReader::open(file, "account-pw").unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert!(records.iter().any(|r| r.key == b"st:counter" && r.value == b"2"));
+        
+        // A cancelled run leaves nothing behind and keeps the checksum
+        storage.set_setting("counter", "3").unwrap();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let error = run_scheduled_backup(&storage, &schedule, &mut checksum, &cancel).unwrap_err();
+        assert!(crate::cancel::is_cancelled(&error));
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 2);
+        assert!(matches!(
+            run_scheduled_backup(&storage, &schedule, &mut checksum, &CancellationToken::new()).unwrap(),
+            ScheduledBackup::Written { .. }
+        ));
+    }
+    
+    #[test]
+    fn test_rejects_truncation() {
+        let data = sample_backup(200);
+        
+        // Cut cleanly after the first chunk: every remaining chunk is intact
+        // but the final flag was never seen
+        let header_end = 9 + u32::from_be_bytes(data[5..9].try_into().unwrap()) as usize;
+        let first_len = u32::from_be_bytes(data[header_end..header_end + 4].try_into().unwrap()) as usize;
+        let truncated = &data[..header_end + 4 + first_len];
+        
+        let result: Result<Vec<_>> = BackupReader::open(truncated, "backup-pw").unwrap().collect();
+        assert!(result.is_err());
+    }
+    
+    #[test]
+    fn test_manifest_describes_records() {
+        let mut writer = BackupWriter::new(Vec::new(), "backup-pw").unwrap();
+        writer.write_record(b"ct:alice", b"alice").unwrap();
+        // Ids are base64, so may hold '/'; message keys escape the
+        // conversation's
+        writer.write_record(b"cv:c/1", b"first").unwrap();
+        writer.write_record(b"cv:c2", b"second").unwrap();
+        for i in 0..100 {
+            writer.write_record(format!("msg:c%2F1/m/{:03}", i).as_bytes(), &[1u8; 1024]).unwrap();
+        }
+        writer.write_record(b"msg:c2/000", b"hello").unwrap();
+        let data = writer.finish().unwrap();
+        
+        let archive = BackupArchive::open(std::io::Cursor::new(&data), "backup-pw").unwrap();
+        let manifest = archive.manifest();
+        assert_eq!(manifest.records, 104);
+        assert_eq!(manifest.categories[&RecordCategory::Contacts].records, 1);
+        assert_eq!(manifest.categories[&RecordCategory::Messages].records, 101);
+        assert_eq!(manifest.conversations.len(), 2);
+        assert_eq!(manifest.conversations["c/1"].records, 100);
+        assert_eq!(manifest.conversations["c2"].size, 15);
+        assert!(manifest.chunks.len() > 1);
+        assert_eq!(manifest.chunks.iter().map(|chunk| chunk.records as u64).sum::<u64>(), 104);
+        
+        // Reading it all gives the same manifest
+        let mut reader = BackupReader::open(data.as_slice(), "backup-pw").unwrap();
+        assert_eq!(reader.by_ref().count(), 104);
+        assert_eq!(reader.manifest(), Some(archive.manifest()));
+    }
+    
+    #[test]
+    fn test_archive_verifies_and_reads_chunks() {
+        let mut writer = BackupWriter::new(Vec::new(), "backup-pw").unwrap();
+        writer.write_record(b"ct:alice", b"alice").unwrap();
+        for i in 0..200 {
+            writer.write_record(format!("msg:c1/{:03}", i).as_bytes(), &[1u8; 1024]).unwrap();
+        }
+        let mut data = writer.finish().unwrap();
+        let mut archive = BackupArchive::open(std::io::Cursor::new(data.clone()), "backup-pw").unwrap();
+        assert!(archive.verify().is_empty());
+        let chunks = archive.manifest().chunks.clone();
+        assert_eq!(archive.records_with_prefix(b"msg:c1/").unwrap().len(), 200);
+        
+        // Damage the last chunk of records
+        let damaged = chunks.len() - 1;
+        data[chunks[damaged].offset as usize + 10] ^= 1;
+        let mut archive = BackupArchive::open(std::io::Cursor::new(&data), "backup-pw").unwrap();
+        assert_eq!(archive.verify(), [damaged]);
+        // Only chunks that can hold the prefix are read
+        assert_eq!(archive.records_with_prefix(b"ct:").unwrap(), [(b"ct:alice".to_vec(), b"alice".to_vec())]);
+        assert!(archive.records_with_prefix(b"msg:").is_err());
+        
+        let result: Result<Vec<_>> = BackupReader::open(data.as_slice(), "backup-pw").unwrap().collect();
+        assert!(result.is_err());
+    }
+    
+    #[test]
+    fn test_reads_version_2() {
+        let (key_wrap, content_key) = MasterKey::from_password("backup-pw", &mut rand::thread_rng()).unwrap();
+        let header = BackupHeader {
+            created_at: OffsetDateTime::now_utc(),
+            key_wrap: BackupKeyWrap::Password(key_wrap),
+            nonce_prefix: stream::random_nonce_prefix(),
+        };
+        let header_bytes = bincode::serialize(&header).unwrap();
+        let mut records = Vec::new();
+        encode_record(&mut records, b"ct:alice", b"alice");
+        let sealed = StreamSealer::new(&content_key, header.nonce_prefix, &header_bytes).seal(&records, true).unwrap();
+        
+        let mut data = MAGIC.to_vec();
+        data.push(LEGACY_VERSION);
+        data.extend_from_slice(&(header_bytes.len() as u32).to_be_bytes());
+        data.extend_from_slice(&header_bytes);
+        data.extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+        data.extend_from_slice(&sealed);
+        
+        let mut reader = BackupReader::open(data.as_slice(), "backup-pw").unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().key, b"ct:alice");
+        assert!(reader.next().is_none());
+        assert!(reader.manifest().is_none());
+        assert!(BackupArchive::open(std::io::Cursor::new(&data), "backup-pw").is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Where scheduled backups go.
+//!
+//! A `BackupSink` takes a backup chunk by chunk as it is written and
+//! makes it visible only once it is finalized, so a run that fails or is
+//! cancelled leaves nothing that looks like a backup. A `BackupSource`
+//! lists finished backups and reads them back, to verify, prune and
+//! restore them. Backups are encrypted before they reach either, so a
+//! store only ever holds ciphertext.
+//!
+//! `DirectorySink` keeps backups in a local directory, which may be on
+//! removable media. With the `webdav` feature, `webdav::WebDavSink` keeps
+//! them on an HTTP server speaking WebDAV. Other stores plug in by
+//! implementing the traits. Calls may block; the scheduler makes them off
+//! the async runtime.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, Context};
+
+/// Largest chunk an `UploadWriter` hands its upload at once
+pub const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+
+const PARTIAL_EXTENSION: &str = ".partial";
+
+/// Takes backups as they are written
+pub trait BackupSink: Send + Sync {
+    /// Where backups go, for logs, e.g. the directory or server
+    fn name(&self) -> String;
+    /// Start writing the backup `name`
+    fn create(&self, name: &str) -> Result<Box<dyn BackupUpload>>;
+    /// Remove a finished backup
+    fn remove(&self, name: &str) -> Result<()>;
+}
+
+/// A backup being written. Dropped before `finalize`, it is discarded.
+pub trait BackupUpload: Send {
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()>;
+    /// Complete the backup, returning where it is now
+    fn finalize(self: Box<Self>) -> Result<String>;
+}
+
+/// Reads finished backups back
+pub trait BackupSource: Send + Sync {
+    /// Names of the finished backups, in no particular order
+    fn list(&self) -> Result<Vec<String>>;
+    fn read(&self, name: &str) -> Result<Box<dyn Read + Send>>;
+}
+
+/// A sink whose backups can be read back, as the scheduler needs
+pub trait BackupStore: BackupSink + BackupSource {}
+
+impl<T: BackupSink + BackupSource> BackupStore for T {}
+
+/// Check `name` can't reach outside the store
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) || name.ends_with(PARTIAL_EXTENSION) {
+        return Err(anyhow::anyhow!("Invalid backup name {:?}", name));
+    }
+    Ok(())
+}
+
+/// Hands what is written to it to a `BackupUpload` in chunks of
+/// `UPLOAD_CHUNK_SIZE`, for a `BackupWriter` to write into
+pub struct UploadWriter {
+    upload: Box<dyn BackupUpload>,
+    buffer: Vec<u8>,
+    written: u64,
+}
+
+impl UploadWriter {
+    pub fn new(upload: Box<dyn BackupUpload>) -> Self {
+        Self { upload, buffer: Vec::new(), written: 0 }
+    }
+
+    /// Bytes written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Hand over the rest and complete the backup, returning where it is
+    pub fn finalize(mut self) -> Result<String> {
+        self.write_buffer()?;
+        self.upload.finalize()
+    }
+
+    fn write_buffer(&mut self) -> Result<()> {
+        if !self.buffer.is_empty() {
+            self.upload.write_chunk(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Write for UploadWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        self.written += data.len() as u64;
+        if self.buffer.len() >= UPLOAD_CHUNK_SIZE {
+            self.write_buffer().map_err(io::Error::other)?;
+        }
+        Ok(data.len())
+    }
+
+    /// Chunks are handed over as they fill; `finalize` hands over the rest
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Backups as files in a local directory
+#[derive(Debug, Clone)]
+pub struct DirectorySink {
+    directory: PathBuf,
+}
+
+impl DirectorySink {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self { directory: directory.into() }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        check_name(name)?;
+        Ok(self.directory.join(name))
+    }
+}
+
+impl BackupSink for DirectorySink {
+    fn name(&self) -> String {
+        self.directory.display().to_string()
+    }
+
+    fn create(&self, name: &str) -> Result<Box<dyn BackupUpload>> {
+        let path = self.path(name)?;
+        fs::create_dir_all(&self.directory)
+            .context("Failed to create backup directory")?;
+        let partial = self.directory.join(format!("{}{}", name, PARTIAL_EXTENSION));
+        let file = File::create(&partial)
+            .context("Failed to create backup file")?;
+        Ok(Box::new(FileUpload { writer: Some(BufWriter::new(file)), partial, path, finished: false }))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let path = self.path(name)?;
+        fs::remove_file(&path)
+            .with_context(|| format!("Failed to remove backup {}", path.display()))
+    }
+}
+
+impl BackupSource for DirectorySink {
+    fn list(&self) -> Result<Vec<String>> {
+        if !self.directory.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.directory).context("Failed to read backup directory")? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if entry.file_type()?.is_file() && check_name(&name).is_ok() {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn read(&self, name: &str) -> Result<Box<dyn Read + Send>> {
+        let file = File::open(self.path(name)?).context("Failed to open backup")?;
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Written next to its final name, and renamed once complete
+struct FileUpload {
+    writer: Option<BufWriter<File>>,
+    partial: PathBuf,
+    path: PathBuf,
+    finished: bool,
+}
+
+impl BackupUpload for FileUpload {
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        let writer = self.writer.as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Backup already finalized"))?;
+        writer.write_all(data).context("Failed to write backup")
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<String> {
+        let writer = self.writer.take()
+            .ok_or_else(|| anyhow::anyhow!("Backup already finalized"))?;
+        let file = writer.into_inner()
+            .map_err(|e| anyhow::anyhow!("Failed to write backup: {}", e))?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&self.partial, &self.path)
+            .context("Failed to move backup into place")?;
+        self.finished = true;
+        Ok(self.path.display().to_string())
+    }
+}
+
+impl Drop for FileUpload {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.writer.take();
+            fs::remove_file(&self.partial).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directory_sink() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let sink = DirectorySink::new(temp_dir.path().join("backups"));
+        assert!(sink.list().unwrap().is_empty());
+
+        let mut abandoned = sink.create("a.scbk").unwrap();
+        abandoned.write_chunk(b"partial").unwrap();
+        drop(abandoned);
+
+        let mut writer = UploadWriter::new(sink.create("b.scbk").unwrap());
+        writer.write_all(&vec![7u8; UPLOAD_CHUNK_SIZE + 10]).unwrap();
+        // Nothing is listed until it is complete
+        assert!(sink.list().unwrap().is_empty());
+        assert_eq!(writer.written(), UPLOAD_CHUNK_SIZE as u64 + 10);
+        let location = writer.finalize().unwrap();
+        assert_eq!(PathBuf::from(location), sink.directory().join("b.scbk"));
+
+        assert_eq!(sink.list().unwrap(), ["b.scbk"]);
+        let mut read = Vec::new();
+        sink.read("b.scbk").unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read.len(), UPLOAD_CHUNK_SIZE + 10);
+
+        sink.remove("b.scbk").unwrap();
+        assert_eq!(fs::read_dir(sink.directory()).unwrap().count(), 0);
+        assert!(sink.create("../escape").is_err());
+        assert!(sink.read(".hidden").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Bootstrap nodes.
+//!
+//! Bootstrap nodes are the first peers dialed when the network starts. The
+//! list is kept in storage and edited at runtime; while it is empty the
+//! compiled-in `DEFAULT_NODES` are used instead. Every node is dialed again
+//! each `HEALTH_CHECK_INTERVAL` to see whether it still answers and how
+//! fast, and the network starts by dialing the fastest healthy ones.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+/// Used while no bootstrap nodes are stored
+pub const DEFAULT_NODES: &[&str] = &[
+    "/dns4/bootstrap.securechat.dev/tcp/4001",
+    "/dns4/bootstrap.securechat.dev/udp/4001/quic-v1",
+];
+
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Failed checks in a row before a node is tried only after healthy ones
+pub const MAX_FAILURES: u32 = 3;
+
+/// Nodes dialed when the network starts
+pub const STARTUP_DIALS: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootstrapNode {
+    pub address: String,
+    pub added_at: OffsetDateTime,
+    pub last_checked: Option<OffsetDateTime>,
+    /// Time to connect on the last successful check
+    pub rtt: Option<Duration>,
+    /// Failed checks since the last success
+    pub failures: u32,
+}
+
+impl BootstrapNode {
+    pub fn new(address: &str) -> Result<Self> {
+        let address = address.trim();
+        address.parse::<libp2p::Multiaddr>()
+            .map_err(|e| anyhow::anyhow!("Invalid bootstrap address '{}': {}", address, e))?;
+        Ok(Self {
+            address: address.to_string(),
+            added_at: OffsetDateTime::now_utc(),
+            last_checked: None,
+            rtt: None,
+            failures: 0,
+        })
+    }
+    
+    /// Record a health check; `rtt` is `None` if the node didn't answer
+    pub fn record_check(&mut self, rtt: Option<Duration>, now: OffsetDateTime) {
+        self.last_checked = Some(now);
+        match rtt {
+            Some(rtt) => {
+                self.rtt = Some(rtt);
+                self.failures = 0;
+            }
+            None => self.failures += 1,
+        }
+    }
+    
+    pub fn is_healthy(&self) -> bool {
+        self.failures < MAX_FAILURES
+    }
+}
+
+/// Addresses to dial, fastest healthy nodes first, then unchecked ones,
+/// then failing ones. Falls back to `DEFAULT_NODES` if `nodes` is empty.
+pub fn dial_order(nodes: &[BootstrapNode]) -> Vec<String> {
+    if nodes.is_empty() {
+        return DEFAULT_NODES.iter().map(|addr| addr.to_string()).collect();
+    }
+    let mut nodes: Vec<_> = nodes.iter().collect();
+    nodes.sort_by_key(|node| (!node.is_healthy(), node.rtt.is_none(), node.rtt, node.failures));
+    nodes.into_iter().map(|node| node.address.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_dial_order_prefers_fast_healthy_nodes() {
+        assert_eq!(dial_order(&[]).len(), DEFAULT_NODES.len());
+        assert!(BootstrapNode::new("not an address").is_err());
+        
+        let now = OffsetDateTime::now_utc();
+        let node = |address: &str, checks: &[Option<u64>]| {
+            let mut node = BootstrapNode::new(address).unwrap();
+            for rtt in checks {
+                node.record_check(rtt.map(Duration::from_millis), now);
+            }
+            node
+        };
+        let nodes = [
+            node("/ip4/192.0.2.1/tcp/1", &[Some(10), None, None, None]),
+            node("/ip4/192.0.2.2/tcp/1", &[]),
+            node("/ip4/192.0.2.3/tcp/1", &[None, Some(200)]),
+            node("/ip4/192.0.2.4/tcp/1", &[Some(40), None]),
+        ];
+        assert!(!nodes[0].is_healthy());
+        assert_eq!(nodes[2].failures, 0);
+        assert_eq!(dial_order(&nodes), [
+            "/ip4/192.0.2.4/tcp/1",
+            "/ip4/192.0.2.3/tcp/1",
+            "/ip4/192.0.2.2/tcp/1",
+            "/ip4/192.0.2.1/tcp/1",
+        ]);
+    }
+}
@@ -0,0 +1,140 @@
+//! Broadcast lists.
+//!
+//! A broadcast sends one message to several contacts as separate 1:1
+//! messages: each recipient gets a copy in their own conversation, sealed
+//! through their own sessions, and sees nothing of the other recipients.
+//! The `Broadcast` record remembers which message went to whom, so the
+//! delivery of the whole can be followed and failed recipients retried.
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::protocol::{generate_id, DeliveryStatus};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BroadcastList {
+    pub id: String,
+    pub name: String,
+    /// Ids of the contacts a broadcast goes to
+    pub members: Vec<String>,
+    pub created_at: OffsetDateTime,
+}
+
+impl BroadcastList {
+    /// Longest list name accepted, in characters
+    pub const MAX_NAME_LEN: usize = 64;
+    /// Most contacts a list can hold
+    pub const MAX_MEMBERS: usize = 256;
+
+    pub fn new(name: &str) -> Result<Self> {
+        Ok(Self {
+            id: generate_id(),
+            name: Self::check_name(name)?,
+            members: Vec::new(),
+            created_at: OffsetDateTime::now_utc(),
+        })
+    }
+
+    /// The name trimmed, if it is one a list can have
+    pub fn check_name(name: &str) -> Result<String> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > Self::MAX_NAME_LEN {
+            return Err(anyhow::anyhow!("Broadcast list names are 1 to {} characters", Self::MAX_NAME_LEN));
+        }
+        Ok(name.to_string())
+    }
+}
+
+/// One message sent to a list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Broadcast {
+    pub id: String,
+    pub list_id: String,
+    pub sent_at: OffsetDateTime,
+    /// Every member of the list at the time, in list order
+    pub recipients: Vec<BroadcastRecipient>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BroadcastRecipient {
+    pub contact_id: String,
+    pub outcome: RecipientOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecipientOutcome {
+    /// Stored in the contact's conversation and handed to their sessions
+    Sent { conversation_id: String, message_id: String },
+    /// Nothing was stored for the contact
+    Failed { reason: String },
+}
+
+impl Broadcast {
+    /// Recipients the message never went out to, with the reason
+    pub fn failures(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.recipients.iter().filter_map(|recipient| match &recipient.outcome {
+            RecipientOutcome::Failed { reason } => Some((recipient.contact_id.as_str(), reason.as_str())),
+            RecipientOutcome::Sent { .. } => None,
+        })
+    }
+}
+
+/// Delivery of a broadcast across its recipients
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BroadcastStatus {
+    /// Queued or still being handed to the network
+    pub pending: usize,
+    pub sent: usize,
+    pub delivered: usize,
+    pub read: usize,
+    /// Contact id and reason for every recipient the message failed for,
+    /// whether when sending or later on the network
+    pub failed: Vec<(String, String)>,
+    /// Recipients whose copy has since been deleted here
+    pub unknown: usize,
+}
+
+impl BroadcastStatus {
+    pub fn add(&mut self, contact_id: &str, status: Option<&DeliveryStatus>) {
+        match status {
+            Some(DeliveryStatus::Queued | DeliveryStatus::Sending) => self.pending += 1,
+            Some(DeliveryStatus::Sent) => self.sent += 1,
+            Some(DeliveryStatus::Delivered) => self.delivered += 1,
+            Some(DeliveryStatus::Read) => self.read += 1,
+            Some(DeliveryStatus::Failed { reason }) => self.failed.push((contact_id.to_string(), reason.clone())),
+            None => self.unknown += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.pending + self.sent + self.delivered + self.read + self.failed.len() + self.unknown
+    }
+
+    /// Every recipient's device confirmed the message
+    pub fn all_delivered(&self) -> bool {
+        self.delivered + self.read == self.total()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_counts() {
+        let mut status = BroadcastStatus::default();
+        status.add("a", Some(&DeliveryStatus::Queued));
+        status.add("b", Some(&DeliveryStatus::Read));
+        status.add("c", Some(&DeliveryStatus::Failed { reason: "offline".to_string() }));
+        status.add("d", None);
+        assert_eq!(status.total(), 4);
+        assert_eq!(status.failed, vec![("c".to_string(), "offline".to_string())]);
+        assert!(!status.all_delivered());
+
+        let mut status = BroadcastStatus::default();
+        status.add("a", Some(&DeliveryStatus::Delivered));
+        status.add("b", Some(&DeliveryStatus::Read));
+        assert!(status.all_delivered());
+    }
+}
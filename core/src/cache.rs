@@ -0,0 +1,187 @@
+//! Cache of decrypted records.
+//!
+//! Opening a conversation decrypts the same recent messages again and
+//! again. `RecordCache` keeps the plaintext of recently read records up to
+//! a total size, evicting the least recently used first. An entry is keyed
+//! by the storage key and the nonce its record was sealed with: every
+//! write seals with a fresh nonce, so a rewritten or deleted record is
+//! never served from the cache. Clearing it starts a new generation, and
+//! plaintext decrypted before the clear is not taken in.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use lru::LruCache;
+use serde::{Serialize, Deserialize};
+
+/// Plaintext held before the least recently used records are evicted
+pub const DEFAULT_CACHE_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    /// Plaintext and keys held
+    pub bytes: usize,
+    pub capacity: usize,
+}
+
+impl CacheStats {
+    /// Share of lookups served from the cache, 0 before any
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+struct Entry {
+    nonce: Vec<u8>,
+    plaintext: Arc<[u8]>,
+}
+
+impl Entry {
+    fn size(&self, key: &[u8]) -> usize {
+        key.len() + self.nonce.len() + self.plaintext.len()
+    }
+}
+
+struct Inner {
+    entries: LruCache<Vec<u8>, Entry>,
+    generation: u64,
+    stats: CacheStats,
+}
+
+impl Inner {
+    fn evict_to(&mut self, capacity: usize) {
+        while self.stats.bytes > capacity {
+            let Some((key, entry)) = self.entries.pop_lru() else { break };
+            self.stats.bytes -= entry.size(&key);
+            self.stats.evictions += 1;
+        }
+        self.stats.entries = self.entries.len();
+    }
+}
+
+pub(crate) struct RecordCache {
+    inner: Mutex<Inner>,
+}
+
+impl RecordCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                entries: LruCache::unbounded(),
+                generation: 0,
+                stats: CacheStats { capacity, ..Default::default() },
+            }),
+        }
+    }
+    
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        // Every update leaves the cache consistent, poisoned or not
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    
+    /// Generation to hand back to `insert` with what gets decrypted now
+    pub(crate) fn generation(&self) -> u64 {
+        self.inner().generation
+    }
+    
+    /// Plaintext of the record under `key` if it was sealed with `nonce`.
+    /// An entry for an older record is dropped.
+    pub(crate) fn get(&self, key: &[u8], nonce: &[u8]) -> Option<Arc<[u8]>> {
+        let mut inner = self.inner();
+        let stale = match inner.entries.peek(key) {
+            Some(entry) if entry.nonce == nonce => {
+                let plaintext = entry.plaintext.clone();
+                inner.entries.promote(key);
+                inner.stats.hits += 1;
+                return Some(plaintext);
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if stale {
+            if let Some(old) = inner.entries.pop(key) {
+                inner.stats.bytes -= old.size(key);
+                inner.stats.entries = inner.entries.len();
+            }
+        }
+        inner.stats.misses += 1;
+        None
+    }
+    
+    pub(crate) fn insert(&self, key: &[u8], nonce: &[u8], plaintext: Arc<[u8]>, generation: u64) {
+        let mut inner = self.inner();
+        let entry = Entry { nonce: nonce.to_vec(), plaintext };
+        let size = entry.size(key);
+        if generation != inner.generation || size > inner.stats.capacity {
+            return;
+        }
+        if let Some(old) = inner.entries.put(key.to_vec(), entry) {
+            inner.stats.bytes -= old.size(key);
+        }
+        inner.stats.bytes += size;
+        let capacity = inner.stats.capacity;
+        inner.evict_to(capacity);
+    }
+    
+    /// Drop every entry, e.g. as the storage is locked
+    pub(crate) fn clear(&self) {
+        let mut inner = self.inner();
+        inner.entries.clear();
+        inner.generation += 1;
+        inner.stats.bytes = 0;
+        inner.stats.entries = 0;
+    }
+    
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner();
+        inner.stats.capacity = capacity;
+        inner.evict_to(capacity);
+    }
+    
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.inner().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_cache_evicts_and_checks_nonces() {
+        let cache = RecordCache::new(40);
+        let plaintext = |text: &str| -> Arc<[u8]> { text.as_bytes().into() };
+        cache.insert(b"k1", b"n1", plaintext("0123456789"), 0);
+        cache.insert(b"k2", b"n1", plaintext("0123456789"), 0);
+        
+        // k2 was used least recently
+        assert_eq!(cache.get(b"k1", b"n1").as_deref(), Some(&b"0123456789"[..]));
+        cache.insert(b"k3", b"n1", plaintext("0123456789"), 0);
+        assert!(cache.get(b"k2", b"n1").is_none());
+        // Rewritten since, under a new nonce
+        assert!(cache.get(b"k3", b"n2").is_none());
+        assert!(cache.get(b"k3", b"n1").is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions, stats.entries, stats.bytes), (1, 3, 1, 1, 14));
+        assert_eq!(stats.hit_rate(), 0.25);
+        
+        // Decrypted before the clear, so not taken in after it
+        let generation = cache.generation();
+        cache.clear();
+        cache.insert(b"k1", b"n1", plaintext("stale"), generation);
+        assert!(cache.get(b"k1", b"n1").is_none());
+        assert_eq!(cache.stats().bytes, 0);
+        
+        cache.insert(b"k1", b"n1", plaintext("0123456789"), cache.generation());
+        cache.set_capacity(10);
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
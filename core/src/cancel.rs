@@ -0,0 +1,159 @@
+//! Cancelling long operations.
+//!
+//! A `CancellationToken` is handed to an operation that may run for a
+//! while, such as exporting a backup, moving an attachment or serving
+//! history sync, and cancelled from anywhere it was cloned to. The
+//! operation checks it between units of work and fails with `Cancelled`
+//! at the next check, after cleaning up: staged attachment chunks and
+//! partial backup files are removed, and nothing reaches the outbox for a
+//! message whose attachment didn't finish. `ChatEvent::OperationCancelled`
+//! reports which operation stopped.
+//!
+//! Background tasks run on children of a token `SecureChat::close`
+//! cancels, so they stop there too.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::Notify;
+
+/// An operation that can be cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    BackupExport,
+    BackupRestore,
+    HistorySync,
+    AttachmentUpload,
+    AttachmentDownload,
+    Reindex,
+}
+
+/// Error of an operation stopped by its token. Find it with
+/// `is_cancelled` or `downcast_ref`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Operation cancelled")]
+pub struct Cancelled;
+
+/// Whether `error` means the operation was cancelled
+pub fn is_cancelled(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.downcast_ref::<Cancelled>().is_some())
+}
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+    children: Mutex<Vec<Weak<Inner>>>,
+}
+
+impl Inner {
+    fn cancel(&self) {
+        if self.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.notify.notify_waiters();
+        let children = std::mem::take(&mut *self.children.lock().unwrap_or_else(|p| p.into_inner()));
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+    }
+}
+
+/// Cancels every clone at once. Cancelling can't be undone.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token cancelled along with this one, that can also be cancelled
+    /// on its own
+    pub fn child_token(&self) -> Self {
+        let child = Self::new();
+        let mut children = self.inner.children.lock().unwrap_or_else(|p| p.into_inner());
+        if self.is_cancelled() {
+            child.cancel();
+        } else {
+            children.retain(|child| child.strong_count() > 0);
+            children.push(Arc::downgrade(&child.inner));
+        }
+        child
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// `Cancelled` once cancelled, for checks between units of work
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            return Err(Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Completes once cancelled
+    pub async fn cancelled(&self) {
+        loop {
+            // Created before checking, so a cancel in between still wakes it
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_children_follow_parent() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let grandchild = child.child_token();
+
+        child.cancel();
+        assert!(grandchild.is_cancelled());
+        assert!(!parent.is_cancelled());
+
+        let other = parent.child_token();
+        parent.cancel();
+        assert!(other.is_cancelled());
+        assert!(parent.child_token().is_cancelled());
+        assert_eq!(other.check(), Err(Cancelled));
+        assert!(is_cancelled(&anyhow::Error::from(Cancelled).context("Backup failed")));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_wakes_waiters() {
+        let token = CancellationToken::new();
+        let waiter = tokio::spawn({
+            let token = token.clone();
+            async move { token.cancelled().await }
+        });
+        tokio::task::yield_now().await;
+        token.cancel();
+        tokio::time::timeout(std::time::Duration::from_secs(1), waiter).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+//! Contact cards.
+//!
+//! A card is what a user hands out to be added by: their display name,
+//! the hash of their avatar, their identity key and an announcement of the
+//! device that made it, whose message key others seal to. It is signed
+//! with the identity key and valid until `expires_at`. Unlike an invite it
+//! holds no prekey of its own, so it can be shared widely and for long:
+//! first messages from someone who imported it arrive as message
+//! requests.
+//!
+//! Cards are saved as `.sccard` files holding a wire frame, or shared as
+//! `securechat://card#<base64url(frame)>` links. A card can be revoked
+//! before it expires by handing out a `CardRevocation`, signed by the same
+//! key; once imported, the revoked card is refused.
+
+use std::time::Duration;
+
+use anyhow::{Result, Context};
+use base64::Engine;
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::crypto::IdentityKeyPair;
+use crate::devices::DeviceAnnouncement;
+use crate::protocol::wire;
+
+pub const LINK_PREFIX: &str = "securechat://card#";
+pub const REVOCATION_PREFIX: &str = "securechat://card-revocation#";
+pub const FILE_EXTENSION: &str = "sccard";
+
+/// Longest a card may stay valid
+pub const MAX_VALIDITY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+/// Longest display name carried in a card
+pub const MAX_NAME_LEN: usize = crate::invite::MAX_NAME_LEN;
+/// Largest card or revocation read; real ones are well under 1 KB
+pub const MAX_CARD_LEN: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContactCard {
+    /// Random; names the card in a revocation
+    pub id: String,
+    pub identity_key: [u8; 32],
+    pub display_name: String,
+    /// BLAKE3 of the avatar image, hex encoded, so an avatar that arrives
+    /// later can be checked against the card
+    pub avatar_hash: Option<String>,
+    pub device: DeviceAnnouncement,
+    pub issued_at: OffsetDateTime,
+    pub expires_at: OffsetDateTime,
+    pub signature: Vec<u8>,
+}
+
+/// Withdraws a card before it expires
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CardRevocation {
+    pub card_id: String,
+    pub identity_key: [u8; 32],
+    pub revoked_at: OffsetDateTime,
+    pub signature: Vec<u8>,
+}
+
+/// A card we handed out, kept so it can be revoked
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IssuedCard {
+    pub id: String,
+    pub issued_at: OffsetDateTime,
+    pub expires_at: OffsetDateTime,
+    pub revoked_at: Option<OffsetDateTime>,
+}
+
+/// Hex BLAKE3 of an avatar image, as carried in cards
+pub fn avatar_hash(avatar: &[u8]) -> String {
+    blake3::hash(avatar).to_hex().to_string()
+}
+
+impl ContactCard {
+    pub fn new(
+        identity: &IdentityKeyPair,
+        display_name: &str,
+        avatar: Option<&[u8]>,
+        device: DeviceAnnouncement,
+        valid_for: Duration,
+    ) -> Result<Self> {
+        if valid_for.is_zero() || valid_for > MAX_VALIDITY {
+            return Err(anyhow::anyhow!("Cards are valid for up to {} days", MAX_VALIDITY.as_secs() / 86400));
+        }
+        let issued_at = OffsetDateTime::now_utc();
+        let mut card = Self {
+            id: crate::protocol::generate_id(),
+            identity_key: identity.public_key.to_bytes(),
+            display_name: display_name.chars().take(MAX_NAME_LEN).collect(),
+            avatar_hash: avatar.map(avatar_hash),
+            device,
+            issued_at,
+            expires_at: issued_at + valid_for,
+            signature: Vec::new(),
+        };
+        card.signature = identity.sign(&card.signing_bytes()?).to_bytes().to_vec();
+        Ok(card)
+    }
+
+    fn signing_bytes(&self) -> Result<Vec<u8>> {
+        wire::encode(&(
+            b"SecureChat contact card v1",
+            &self.id,
+            &self.identity_key,
+            &self.display_name,
+            &self.avatar_hash,
+            &self.device,
+            &self.issued_at,
+            &self.expires_at,
+        ))
+    }
+
+    /// Check the signatures and that the card is current at `now`
+    pub fn verify(&self, now: OffsetDateTime) -> Result<()> {
+        if self.display_name.chars().count() > MAX_NAME_LEN {
+            return Err(anyhow::anyhow!("Card display name too long"));
+        }
+        if self.id.len() > crate::protocol::MAX_ID_LEN || self.avatar_hash.as_ref().is_some_and(|h| h.len() != 64) {
+            return Err(anyhow::anyhow!("Card is malformed"));
+        }
+        verify_signature(&self.identity_key, &self.signing_bytes()?, &self.signature)
+            .context("Card signature is invalid")?;
+        if self.device.identity_key != self.identity_key {
+            return Err(anyhow::anyhow!("Card announces another identity's device"));
+        }
+        self.device.verify()?;
+        if now >= self.expires_at {
+            return Err(anyhow::anyhow!("Card has expired"));
+        }
+        Ok(())
+    }
+
+    /// Contents of a card file
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        wire::encode(self)
+    }
+
+    pub fn to_link(&self) -> Result<String> {
+        Ok(format!("{}{}", LINK_PREFIX, base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(self.to_bytes()?)))
+    }
+
+    /// Parse and verify a card file or link. Revocations are checked by
+    /// the caller.
+    pub fn parse(data: &[u8], now: OffsetDateTime) -> Result<Self> {
+        let card: Self = decode(data, LINK_PREFIX).context("Not a contact card")?;
+        card.verify(now)?;
+        Ok(card)
+    }
+}
+
+impl CardRevocation {
+    pub fn new(identity: &IdentityKeyPair, card_id: &str) -> Result<Self> {
+        let mut revocation = Self {
+            card_id: card_id.to_string(),
+            identity_key: identity.public_key.to_bytes(),
+            revoked_at: OffsetDateTime::now_utc(),
+            signature: Vec::new(),
+        };
+        revocation.signature = identity.sign(&revocation.signing_bytes()?).to_bytes().to_vec();
+        Ok(revocation)
+    }
+
+    fn signing_bytes(&self) -> Result<Vec<u8>> {
+        wire::encode(&(b"SecureChat card revocation v1", &self.card_id, &self.identity_key, &self.revoked_at))
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        if self.card_id.is_empty() || self.card_id.len() > crate::protocol::MAX_ID_LEN {
+            return Err(anyhow::anyhow!("Revocation is malformed"));
+        }
+        verify_signature(&self.identity_key, &self.signing_bytes()?, &self.signature)
+            .context("Revocation signature is invalid")
+    }
+
+    /// Whether this revokes `card`
+    pub fn revokes(&self, card: &ContactCard) -> bool {
+        self.card_id == card.id && self.identity_key == card.identity_key
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        wire::encode(self)
+    }
+
+    pub fn to_link(&self) -> Result<String> {
+        Ok(format!("{}{}", REVOCATION_PREFIX, base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(self.to_bytes()?)))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let revocation: Self = decode(data, REVOCATION_PREFIX).context("Not a card revocation")?;
+        revocation.verify()?;
+        Ok(revocation)
+    }
+}
+
+/// A wire frame, as is or behind `prefix` in a link
+fn decode<T: serde::de::DeserializeOwned>(data: &[u8], prefix: &str) -> Result<T> {
+    if data.len() > MAX_CARD_LEN {
+        return Err(anyhow::anyhow!("Too large"));
+    }
+    if wire::frame_version(data).is_ok() {
+        return wire::decode(data);
+    }
+    let link = std::str::from_utf8(data)?.trim();
+    let encoded = link.strip_prefix(prefix)
+        .ok_or_else(|| anyhow::anyhow!("Unrecognized format"))?;
+    wire::decode(&base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(encoded)?)
+}
+
+fn verify_signature(key: &[u8; 32], message: &[u8], signature: &[u8]) -> Result<()> {
+    let key = VerifyingKey::from_bytes(key).context("Invalid identity key")?;
+    let signature = Signature::from_slice(signature).context("Malformed signature")?;
+    IdentityKeyPair::verify(&key, message, &signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn card(identity: &IdentityKeyPair, valid_for: Duration) -> ContactCard {
+        let device = DeviceAnnouncement::new(identity, "laptop", [9u8; 32]).unwrap();
+        ContactCard::new(identity, "Alice", Some(b"avatar"), device, valid_for).unwrap()
+    }
+
+    #[test]
+    fn test_card_roundtrip_and_expiry() {
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let card = card(&identity, Duration::from_secs(3600));
+        assert_eq!(card.avatar_hash.as_deref(), Some(avatar_hash(b"avatar").as_str()));
+
+        let now = OffsetDateTime::now_utc();
+        assert_eq!(ContactCard::parse(&card.to_bytes().unwrap(), now).unwrap(), card);
+        assert_eq!(ContactCard::parse(card.to_link().unwrap().as_bytes(), now).unwrap(), card);
+        assert!(ContactCard::parse(&card.to_bytes().unwrap(), now + Duration::from_secs(7200)).is_err());
+
+        // Edited fields break the signature
+        let mut forged = card.clone();
+        forged.display_name = "Mallory".to_string();
+        assert!(ContactCard::parse(&forged.to_bytes().unwrap(), now).is_err());
+        // As does a device announced by someone else
+        let mallory = IdentityKeyPair::generate(&mut OsRng);
+        let mut forged = card.clone();
+        forged.device = DeviceAnnouncement::new(&mallory, "laptop", [9u8; 32]).unwrap();
+        assert!(forged.verify(now).is_err());
+
+        assert!(ContactCard::new(&identity, "Alice", None, card.device.clone(), MAX_VALIDITY + Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_revocation() {
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let card = card(&identity, Duration::from_secs(3600));
+        let revocation = CardRevocation::new(&identity, &card.id).unwrap();
+        let parsed = CardRevocation::parse(revocation.to_link().unwrap().as_bytes()).unwrap();
+        assert!(parsed.revokes(&card));
+
+        // Only the card's own key can revoke it
+        let mallory = IdentityKeyPair::generate(&mut OsRng);
+        let mut forged = CardRevocation::new(&mallory, &card.id).unwrap();
+        assert!(!forged.revokes(&card));
+        forged.identity_key = card.identity_key;
+        assert!(CardRevocation::parse(&forged.to_bytes().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Network conditions reported by the platform.
+//!
+//! The app tells the core when the connection is metered, gone, or the
+//! device is saving power. Under those conditions outgoing messages are
+//! sent, deferred or dropped: attachment transfers wait for an unmetered
+//! connection, everything but ephemeral signals waits while offline, and
+//! presence beacons pause in low-power mode. Deferred messages are sent in
+//! order once conditions allow.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::protocol::ProtocolMessage;
+
+/// Gossip heartbeat on a normal connection
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Gossip heartbeat on a metered connection or in low-power mode
+pub const CONSTRAINED_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Liveness pings on a normal connection
+pub const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Liveness pings on a metered connection or in low-power mode
+pub const CONSTRAINED_PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most messages held while conditions are poor
+pub const MAX_DEFERRED: usize = 4096;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkConditions {
+    /// Data costs money, e.g. mobile data or a hotspot
+    pub metered: bool,
+    /// No connectivity at all
+    pub offline: bool,
+    /// The device is saving battery
+    pub low_power: bool,
+}
+
+/// What to do with an outgoing message under the current conditions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    Send,
+    /// Hold until conditions improve
+    Defer,
+    /// Stale by the time it could be sent
+    Drop,
+}
+
+impl NetworkConditions {
+    pub fn is_constrained(&self) -> bool {
+        self.metered || self.low_power
+    }
+    
+    pub fn disposition(&self, message: &ProtocolMessage) -> Disposition {
+        match message {
+            ProtocolMessage::Presence { .. } if self.low_power => Disposition::Drop,
+            _ if self.offline && message.is_ephemeral() => Disposition::Drop,
+            ProtocolMessage::AttachmentChunk { .. } if self.offline || self.is_constrained() => Disposition::Defer,
+            _ if self.offline => Disposition::Defer,
+            _ => Disposition::Send,
+        }
+    }
+    
+    /// Whether presence beacons are paused
+    pub fn pauses_presence(&self) -> bool {
+        self.offline || self.low_power
+    }
+    
+    pub fn heartbeat_interval(&self) -> Duration {
+        if self.is_constrained() {
+            CONSTRAINED_HEARTBEAT_INTERVAL
+        } else {
+            HEARTBEAT_INTERVAL
+        }
+    }
+    
+    pub fn ping_interval(&self) -> Duration {
+        if self.is_constrained() {
+            CONSTRAINED_PING_INTERVAL
+        } else {
+            PING_INTERVAL
+        }
+    }
+}
+
+/// Outgoing messages waiting for better conditions, in send order
+#[derive(Debug, Default)]
+pub struct DeferredQueue {
+    messages: VecDeque<ProtocolMessage>,
+}
+
+impl DeferredQueue {
+    pub fn push(&mut self, message: ProtocolMessage) -> Result<()> {
+        if self.messages.len() >= MAX_DEFERRED {
+            return Err(anyhow::anyhow!("Too many messages waiting for the network"));
+        }
+        self.messages.push_back(message);
+        Ok(())
+    }
+    
+    /// Remove and return the messages `conditions` allow sending now,
+    /// keeping the order of the rest
+    pub fn take_sendable(&mut self, conditions: &NetworkConditions) -> Vec<ProtocolMessage> {
+        let (ready, waiting): (Vec<_>, Vec<_>) = self.messages.drain(..)
+            .filter(|m| conditions.disposition(m) != Disposition::Drop)
+            .partition(|m| conditions.disposition(m) == Disposition::Send);
+        self.messages = waiting.into();
+        ready
+    }
+    
+    /// Drop waiting messages carrying any of `message_ids`, returning how
+    /// many were removed
+    pub fn remove(&mut self, message_ids: &[String]) -> usize {
+        let before = self.messages.len();
+        self.messages.retain(|m| !m.message_id().is_some_and(|id| message_ids.iter().any(|m| m == id)));
+        before - self.messages.len()
+    }
+    
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn chunk(index: u32) -> ProtocolMessage {
+        ProtocolMessage::AttachmentChunk { transfer_id: [0; 32], index, ciphertext: Vec::new() }
+    }
+    
+    #[test]
+    fn test_deferred_messages_follow_conditions() {
+        let offline = NetworkConditions { offline: true, ..Default::default() };
+        let metered = NetworkConditions { metered: true, ..Default::default() };
+        let typing = ProtocolMessage::Typing { conversation_id: "c".to_string(), is_typing: true };
+        let presence = ProtocolMessage::Presence { online: true };
+        assert_eq!(offline.disposition(&typing), Disposition::Drop);
+        assert_eq!(metered.disposition(&typing), Disposition::Send);
+        assert_eq!(metered.disposition(&presence), Disposition::Send);
+        assert_eq!(metered.heartbeat_interval(), CONSTRAINED_HEARTBEAT_INTERVAL);
+        
+        let receipt = ProtocolMessage::DeliveryReceipt { message_id: "m".to_string(), timestamp: time::OffsetDateTime::UNIX_EPOCH };
+        let mut queue = DeferredQueue::default();
+        for message in [chunk(0), receipt, chunk(1)] {
+            assert_eq!(offline.disposition(&message), Disposition::Defer);
+            queue.push(message).unwrap();
+        }
+        
+        // Back online but metered: only the receipt goes out
+        let ready = queue.take_sendable(&metered);
+        assert!(matches!(&ready[..], [ProtocolMessage::DeliveryReceipt { .. }]));
+        assert_eq!(queue.len(), 2);
+        
+        let ready = queue.take_sendable(&NetworkConditions::default());
+        assert!(matches!(&ready[..], [
+            ProtocolMessage::AttachmentChunk { index: 0, .. },
+            ProtocolMessage::AttachmentChunk { index: 1, .. },
+        ]));
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,187 @@
+//! Portable, signed contact list export.
+//!
+//! ```text
+//! magic    "SCCT"
+//! version  u8                      = 1
+//! body     CBOR(ContactExportFile)
+//! ```
+//!
+//! The contact list is signed with the exporting identity key and then
+//! sealed with XChaCha20-Poly1305 under a key derived from the export
+//! password, with magic and version as associated data. Importing checks
+//! the signature, so a list edited by anyone without the identity key is
+//! rejected even if they learned the password.
+
+use anyhow::{Result, Context};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::crypto::{IdentityKeyPair, MasterKey};
+use crate::protocol::{wire, Contact};
+
+pub const MAGIC: [u8; 4] = *b"SCCT";
+pub const VERSION: u8 = 1;
+
+/// Most contacts accepted in one import
+pub const MAX_CONTACTS: usize = 10_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ContactExportFile {
+    key_wrap: MasterKey,
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+/// One contact as carried in an export
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedContact {
+    pub id: String,
+    pub display_name: String,
+    pub public_key: [u8; 32],
+    pub added_at: OffsetDateTime,
+    pub verified: bool,
+    pub blocked: bool,
+}
+
+/// Decrypted and verified contents of an export
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedContactList {
+    pub exported_at: OffsetDateTime,
+    pub contacts: Vec<ExportedContact>,
+    /// Identity key of the exporting account
+    pub signer: [u8; 32],
+    signature: Vec<u8>,
+}
+
+/// Outcome of merging an export into the local contact list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContactImport {
+    /// Identity key that signed the export; our own if it came from
+    /// another of our devices
+    pub signer: [u8; 32],
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+}
+
+impl From<&Contact> for ExportedContact {
+    fn from(contact: &Contact) -> Self {
+        Self {
+            id: contact.id.clone(),
+            display_name: contact.display_name.clone(),
+            public_key: contact.public_key,
+            added_at: contact.added_at,
+            verified: contact.verified,
+            blocked: contact.blocked,
+        }
+    }
+}
+
+impl SignedContactList {
+    fn signing_bytes(exported_at: &OffsetDateTime, contacts: &[ExportedContact]) -> Result<Vec<u8>> {
+        wire::encode(&(b"SecureChat contact export v1", exported_at, contacts))
+    }
+    
+    fn verify(&self) -> Result<()> {
+        let signer = VerifyingKey::from_bytes(&self.signer)
+            .context("Invalid signer key")?;
+        let signature = Signature::from_slice(&self.signature)
+            .context("Malformed signature")?;
+        IdentityKeyPair::verify(&signer, &Self::signing_bytes(&self.exported_at, &self.contacts)?, &signature)
+            .context("Contact export has been tampered with")
+    }
+}
+
+/// Sign `contacts` with `identity` and seal them with `password`
+pub fn seal(contacts: Vec<ExportedContact>, identity: &IdentityKeyPair, password: &str) -> Result<Vec<u8>> {
+    let exported_at = OffsetDateTime::now_utc();
+    let signature = identity.sign(&SignedContactList::signing_bytes(&exported_at, &contacts)?);
+    let list = SignedContactList {
+        exported_at,
+        contacts,
+        signer: identity.public_key.to_bytes(),
+        signature: signature.to_bytes().to_vec(),
+    };
+    
+    let (key_wrap, key) = MasterKey::from_password(password, &mut rand::thread_rng())
+        .context("Failed to derive export key")?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new((&key).into())
+        .encrypt(&nonce, Payload { msg: &wire::encode(&list)?, aad: &header() })
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt contact export"))?;
+    
+    let mut data = header().to_vec();
+    ciborium::into_writer(&ContactExportFile { key_wrap, nonce: nonce.into(), ciphertext }, &mut data)
+        .map_err(|e| anyhow::anyhow!("CBOR encoding failed: {}", e))?;
+    Ok(data)
+}
+
+/// Decrypt an export and check its signature
+pub fn open(data: &[u8], password: &str) -> Result<SignedContactList> {
+    let body = data.strip_prefix(header().as_slice())
+        .ok_or_else(|| anyhow::anyhow!("Not a contact export, or an unsupported version"))?;
+    let file: ContactExportFile = ciborium::from_reader(body)
+        .map_err(|e| anyhow::anyhow!("Contact export is corrupt: {}", e))?;
+    
+    let key = file.key_wrap.unlock(password)
+        .context("Failed to unlock contact export - wrong password?")?;
+    let plaintext = XChaCha20Poly1305::new((&key).into())
+        .decrypt(XNonce::from_slice(&file.nonce), Payload { msg: &file.ciphertext, aad: &header() })
+        .map_err(|_| anyhow::anyhow!("Contact export is corrupt"))?;
+    
+    let list: SignedContactList = wire::decode(&plaintext)?;
+    if list.contacts.len() > MAX_CONTACTS {
+        return Err(anyhow::anyhow!("Contact export holds more than {} contacts", MAX_CONTACTS));
+    }
+    list.verify()?;
+    Ok(list)
+}
+
+fn header() -> [u8; 5] {
+    [MAGIC[0], MAGIC[1], MAGIC[2], MAGIC[3], VERSION]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn contact(name: &str, key: u8) -> ExportedContact {
+        ExportedContact {
+            id: name.to_lowercase(),
+            display_name: name.to_string(),
+            public_key: [key; 32],
+            added_at: OffsetDateTime::UNIX_EPOCH,
+            verified: key.is_multiple_of(2),
+            blocked: false,
+        }
+    }
+    
+    #[test]
+    fn test_signed_export_roundtrip_and_tampering() {
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let contacts = vec![contact("Alice", 2), contact("Bob", 3)];
+        let data = seal(contacts.clone(), &identity, "pw").unwrap();
+        
+        let list = open(&data, "pw").unwrap();
+        assert_eq!(list.contacts, contacts);
+        assert_eq!(list.signer, identity.public_key.to_bytes());
+        assert!(open(&data, "wrong").is_err());
+        
+        // Re-sealed with the right password but without the identity key
+        let mut forged = list.clone();
+        forged.contacts[1].verified = true;
+        let (key_wrap, key) = MasterKey::from_password("pw", &mut rand::thread_rng()).unwrap();
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new((&key).into())
+            .encrypt(&nonce, Payload { msg: &wire::encode(&forged).unwrap(), aad: &header() })
+            .unwrap();
+        let mut data = header().to_vec();
+        ciborium::into_writer(&ContactExportFile { key_wrap, nonce: nonce.into(), ciphertext }, &mut data).unwrap();
+        assert!(open(&data, "pw").is_err());
+    }
+}
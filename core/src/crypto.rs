@@ -1,5 +1,5 @@
 use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
     Aes256Gcm, Key, Nonce,
 };
 use argon2::{
@@ -12,8 +12,13 @@ use rand::RngCore as RandRngCore;
 use sha2::Sha256;
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519SecretKey};
 use serde::{Serialize, Deserialize};
+use subtle::ConstantTimeEq;
+use time::OffsetDateTime;
+use zeroize::Zeroize;
 use anyhow::{Result, Context};
 
+use crate::limits::LimitError;
+
 /// Master key derived from password, encrypted with AES-256-GCM
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MasterKey {
@@ -22,6 +27,25 @@ pub struct MasterKey {
     pub nonce: [u8; 12],
 }
 
+/// Purpose-specific keys derived from the master key, so that a key used
+/// in one context is never reused in another
+#[derive(Clone)]
+pub struct KeyHierarchy {
+    /// Encrypts database records
+    pub storage: [u8; 32],
+    /// Wraps the identity secret key
+    pub identity_wrap: [u8; 32],
+    /// Keys the tokens naming the local index lists, search terms'
+    /// among them
+    pub search_index: [u8; 32],
+    /// Encrypts backups
+    pub backup: [u8; 32],
+    /// Keys attachment blob ids, so stored keys don't reveal content hashes
+    pub attachment_id: [u8; 32],
+    /// Names the database tree of a duress profile
+    pub profile_id: [u8; 32],
+}
+
 /// Identity key pair for signing
 #[derive(Debug, Clone)]
 pub struct IdentityKeyPair {
@@ -54,7 +78,7 @@ impl std::fmt::Debug for MessageKeyPair {
 }
 
 /// Encrypted message structure
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct EncryptedMessage {
     pub ciphertext: Vec<u8>,
     pub nonce: [u8; 12],
@@ -62,6 +86,16 @@ pub struct EncryptedMessage {
     pub ephemeral_pubkey: [u8; 32],
 }
 
+/// Identity fingerprint for out-of-band verification.
+///
+/// Equality is constant-time so comparing a user-entered value against the
+/// expected one leaks nothing about where they differ.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub version: u8,
+    digest: [u8; 32],
+}
+
 /// Double Ratchet state for perfect forward secrecy
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DoubleRatchet {
@@ -70,12 +104,66 @@ pub struct DoubleRatchet {
     pub receiving_chain_key: Option<[u8; 32]>,
     pub sending_message_number: u32,
     pub receiving_message_number: u32,
-    pub skipped_message_keys: Vec<(u32, [u8; 32])>,
+    /// Keys of messages that haven't arrived yet, oldest first
+    pub skipped_message_keys: Vec<SkippedKey>,
+    /// Ratchet steps taken so far, counted per direction
+    pub sending_chain: u32,
+    pub receiving_chain: u32,
+    /// Length of the sending chain before the last ratchet step
+    pub previous_sending_length: u32,
+}
+
+/// Counters sent alongside every ratchet message so the receiver can
+/// derive the right key even when messages arrive out of order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RatchetHeader {
+    pub chain: u32,
+    /// Messages sent in the sender's previous chain
+    pub previous_chain_length: u32,
+    pub message_number: u32,
+}
+
+/// Message key held for a message that was skipped over
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SkippedKey {
+    pub chain: u32,
+    pub message_number: u32,
+    pub key: [u8; 32],
+    /// When the message was skipped over; unset on keys kept before this
+    /// was recorded until the next trim
+    #[serde(default)]
+    pub skipped_at: Option<OffsetDateTime>,
+}
+
+/// Keys are wiped as they are dropped, whether used, trimmed or evicted
+impl Drop for SkippedKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl std::fmt::Debug for SkippedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SkippedKey")
+            .field("chain", &self.chain)
+            .field("message_number", &self.message_number)
+            .field("key", &"[REDACTED]")
+            .finish()
+    }
 }
 
 impl MasterKey {
     /// Derive a master key from password using Argon2id
+    #[tracing::instrument(level = "debug", skip_all)]
     pub fn from_password(password: &str, rng: &mut impl RngCore) -> Result<(Self, [u8; 32])> {
+        // Generate random master key and encrypt it
+        let master_key: [u8; 32] = Self::generate_random_bytes(rng);
+        Ok((Self::wrap(password, &master_key, rng)?, master_key))
+    }
+    
+    /// Wrap an existing master key under a password, e.g. a new one set
+    /// with the recovery phrase
+    pub fn wrap(password: &str, master_key: &[u8; 32], rng: &mut impl RngCore) -> Result<Self> {
         let salt = Self::generate_random_bytes(rng);
         let nonce = Self::generate_random_bytes_12(rng);
         
@@ -92,22 +180,20 @@ impl MasterKey {
             .as_ref()
             .map(|hash| derived_key.copy_from_slice(&hash.as_bytes()[..32]));
         
-        // Generate random master key and encrypt it
-        let master_key: [u8; 32] = Self::generate_random_bytes(rng);
-        
         let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&derived_key));
         let encrypted_key = cipher
             .encrypt(Nonce::from_slice(&nonce), master_key.as_ref())
             .map_err(|e| anyhow::anyhow!("Failed to encrypt master key: {:?}", e))?;
         
-        Ok((Self {
+        Ok(Self {
             encrypted_key,
             salt,
             nonce,
-        }, master_key))
+        })
     }
     
     /// Unlock master key with password
+    #[tracing::instrument(level = "debug", skip_all)]
     pub fn unlock(&self, password: &str) -> Result<[u8; 32]> {
         // Re-derive key from password
         let argon2 = Argon2::default();
@@ -128,10 +214,21 @@ impl MasterKey {
             .decrypt(Nonce::from_slice(&self.nonce), self.encrypted_key.as_ref())
             .map_err(|e| anyhow::anyhow!("Failed to decrypt master key - wrong password?: {:?}", e))?;
         
-        let mut master_key = [0u8; 32];
-        master_key.copy_from_slice(&decrypted);
-        
-        Ok(master_key)
+        decrypted.try_into()
+            .map_err(|_| anyhow::anyhow!("Master key has wrong length"))
+    }
+    
+    /// A slot shaped like a wrapped key that no password opens, stored
+    /// alongside the real one when no duress password is registered
+    pub fn empty_slot(rng: &mut impl RandRngCore) -> Self {
+        // A 32-byte key plus the 16-byte GCM tag
+        let mut encrypted_key = vec![0u8; 48];
+        rng.fill_bytes(&mut encrypted_key);
+        Self {
+            encrypted_key,
+            salt: Self::generate_random_bytes(rng),
+            nonce: Self::generate_random_bytes_12(rng),
+        }
     }
     
     pub fn generate_random_bytes(rng: &mut impl RandRngCore) -> [u8; 32] {
@@ -147,6 +244,41 @@ impl MasterKey {
     }
 }
 
+impl KeyHierarchy {
+    pub const INFO_STORAGE: &'static [u8] = b"SecureChat v1 storage";
+    pub const INFO_IDENTITY_WRAP: &'static [u8] = b"SecureChat v1 identity-wrap";
+    pub const INFO_SEARCH_INDEX: &'static [u8] = b"SecureChat v1 search-index";
+    pub const INFO_BACKUP: &'static [u8] = b"SecureChat v1 backup";
+    pub const INFO_ATTACHMENT_ID: &'static [u8] = b"SecureChat v1 attachment-id";
+    pub const INFO_PROFILE_ID: &'static [u8] = b"SecureChat v1 profile-id";
+    
+    /// Derive all subkeys from the master key with HKDF-SHA256
+    pub fn derive(master_key: &[u8; 32]) -> Result<Self> {
+        let hk = Hkdf::<Sha256>::new(None, master_key);
+        let expand = |info: &[u8]| -> Result<[u8; 32]> {
+            let mut key = [0u8; 32];
+            hk.expand(info, &mut key)
+                .map_err(|e| anyhow::anyhow!("Subkey derivation failed: {:?}", e))?;
+            Ok(key)
+        };
+        
+        Ok(Self {
+            storage: expand(Self::INFO_STORAGE)?,
+            identity_wrap: expand(Self::INFO_IDENTITY_WRAP)?,
+            search_index: expand(Self::INFO_SEARCH_INDEX)?,
+            backup: expand(Self::INFO_BACKUP)?,
+            attachment_id: expand(Self::INFO_ATTACHMENT_ID)?,
+            profile_id: expand(Self::INFO_PROFILE_ID)?,
+        })
+    }
+}
+
+impl std::fmt::Debug for KeyHierarchy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("KeyHierarchy([REDACTED])")
+    }
+}
+
 impl IdentityKeyPair {
     /// Generate new identity key pair
     pub fn generate(rng: &mut impl rand_core::CryptoRngCore) -> Self {
@@ -159,6 +291,16 @@ impl IdentityKeyPair {
         }
     }
     
+    /// The key pair with secret key `seed`, e.g. one derived from a
+    /// recovery phrase
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let secret_key = SigningKey::from_bytes(seed);
+        Self {
+            public_key: secret_key.verifying_key(),
+            secret_key,
+        }
+    }
+    
     /// Sign a message
     pub fn sign(&self, message: &[u8]) -> Signature {
         self.secret_key.sign(message)
@@ -194,8 +336,8 @@ impl IdentityKeyPair {
             .decrypt(Nonce::from_slice(&encrypted.nonce), encrypted.encrypted_secret.as_ref())
             .map_err(|e| anyhow::anyhow!("Failed to decrypt identity keys: {:?}", e))?;
         
-        let mut secret_bytes = [0u8; 32];
-        secret_bytes.copy_from_slice(&decrypted);
+        let secret_bytes: [u8; 32] = decrypted.try_into()
+            .map_err(|_| anyhow::anyhow!("Identity secret key has wrong length"))?;
         
         let secret_key = SigningKey::from_bytes(&secret_bytes);
         let public_key = secret_key.verifying_key();
@@ -225,41 +367,71 @@ impl MessageKeyPair {
         }
     }
     
+    /// Rebuild a key pair from its raw secret scalar
+    pub fn from_secret_bytes(secret: [u8; 32]) -> Self {
+        let secret_key = X25519SecretKey::from(secret);
+        let public_key = X25519PublicKey::from(&secret_key);
+        
+        Self {
+            public_key,
+            secret_key,
+        }
+    }
+    
     /// Encrypt a message using X3DH + Double Ratchet
+    #[tracing::instrument(level = "trace", skip_all)]
     pub fn encrypt_message(
         &self,
         recipient_pubkey: &X25519PublicKey,
         message: &[u8],
+    ) -> Result<EncryptedMessage> {
+        self.encrypt_message_with_aad(recipient_pubkey, message, &[])
+    }
+    
+    /// Encrypt a message bound to associated data, which decrypting must
+    /// be given unchanged
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn encrypt_message_with_aad(
+        &self,
+        recipient_pubkey: &X25519PublicKey,
+        message: &[u8],
+        aad: &[u8],
     ) -> Result<EncryptedMessage> {
         // Generate ephemeral key for forward secrecy
         let ephemeral_secret = X25519SecretKey::random_from_rng(OsRng);
-        let ephemeral_pubkey = X25519PublicKey::from(&ephemeral_secret);
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        
+        self.encrypt_message_with(recipient_pubkey, message, aad, &ephemeral_secret, nonce.into())
+    }
+    
+    /// Encrypt with caller-supplied ephemeral key and nonce. Only ever called
+    /// with fresh randomness outside of the deterministic test vectors.
+    fn encrypt_message_with(
+        &self,
+        recipient_pubkey: &X25519PublicKey,
+        message: &[u8],
+        aad: &[u8],
+        ephemeral_secret: &X25519SecretKey,
+        nonce: [u8; 12],
+    ) -> Result<EncryptedMessage> {
+        let ephemeral_pubkey = X25519PublicKey::from(ephemeral_secret);
         
         // Perform DH exchanges for X3DH
         let dh1 = self.secret_key.diffie_hellman(recipient_pubkey);
         let dh2 = ephemeral_secret.diffie_hellman(recipient_pubkey);
-        
-        // Derive shared secret using HKDF
-        let mut shared_secret = [0u8; 32];
-        let mut dh_bytes = Vec::with_capacity(64);
-        dh_bytes.extend_from_slice(dh1.as_bytes());
-        dh_bytes.extend_from_slice(dh2.as_bytes());
-        let hk = Hkdf::<Sha256>::new(None, &dh_bytes);
-        hk.expand(b"SecureChat-v1", &mut shared_secret)
-            .map_err(|e| anyhow::anyhow!("HKDF expand failed: {:?}", e))?;
+        let shared_secret = derive_shared_secret(dh1.as_bytes(), dh2.as_bytes())?;
         
         // Encrypt message
         let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&shared_secret));
-        let nonce = Aes256Gcm::generate_nonce(OsRng);
         let ciphertext = cipher
-            .encrypt(&nonce, message)
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: message, aad })
             .map_err(|e| anyhow::anyhow!("Encryption failed: {:?}", e))?;
         
         Ok(EncryptedMessage {
             ciphertext,
-            nonce: nonce.into(),
-            sender_pubkey: self.public_key.as_bytes().clone(),
-            ephemeral_pubkey: ephemeral_pubkey.as_bytes().clone(),
+            nonce,
+            sender_pubkey: *self.public_key.as_bytes(),
+            ephemeral_pubkey: *ephemeral_pubkey.as_bytes(),
         })
     }
     
@@ -267,6 +439,16 @@ impl MessageKeyPair {
     pub fn decrypt_message(
         &self,
         encrypted: &EncryptedMessage,
+    ) -> Result<Vec<u8>> {
+        self.decrypt_message_with_aad(encrypted, &[])
+    }
+    
+    /// Decrypt a message sealed by `encrypt_message_with_aad`
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn decrypt_message_with_aad(
+        &self,
+        encrypted: &EncryptedMessage,
+        aad: &[u8],
     ) -> Result<Vec<u8>> {
         // Reconstruct ephemeral public key
         let ephemeral_pubkey = X25519PublicKey::from(encrypted.ephemeral_pubkey);
@@ -275,26 +457,64 @@ impl MessageKeyPair {
         // Perform DH exchanges
         let dh1 = self.secret_key.diffie_hellman(&sender_pubkey);
         let dh2 = self.secret_key.diffie_hellman(&ephemeral_pubkey);
-        
-        // Derive shared secret
-        let mut shared_secret = [0u8; 32];
-        let mut dh_bytes = Vec::with_capacity(64);
-        dh_bytes.extend_from_slice(dh1.as_bytes());
-        dh_bytes.extend_from_slice(dh2.as_bytes());
-        let hk = Hkdf::<Sha256>::new(None, &dh_bytes);
-        hk.expand(b"SecureChat-v1", &mut shared_secret)
-            .map_err(|e| anyhow::anyhow!("HKDF expand failed: {:?}", e))?;
+        let shared_secret = derive_shared_secret(dh1.as_bytes(), dh2.as_bytes())?;
         
         // Decrypt message
         let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&shared_secret));
         let plaintext = cipher
-            .decrypt(Nonce::from_slice(&encrypted.nonce), encrypted.ciphertext.as_ref())
+            .decrypt(Nonce::from_slice(&encrypted.nonce), Payload { msg: &encrypted.ciphertext, aad })
             .map_err(|e| anyhow::anyhow!("Decryption failed - wrong key or tampered message: {:?}", e))?;
         
         Ok(plaintext)
     }
 }
 
+/// Derive the message key from the two X3DH shared secrets
+/// Name of the scheme `MessageKeyPair::encrypt_message` seals with
+pub const MESSAGE_CIPHER_SUITE: &str = "X25519-HKDF-SHA256-AES-256-GCM";
+
+fn derive_shared_secret(dh1: &[u8; 32], dh2: &[u8; 32]) -> Result<[u8; 32]> {
+    let mut shared_secret = [0u8; 32];
+    let mut dh_bytes = Vec::with_capacity(64);
+    dh_bytes.extend_from_slice(dh1);
+    dh_bytes.extend_from_slice(dh2);
+    let hk = Hkdf::<Sha256>::new(None, &dh_bytes);
+    hk.expand(b"SecureChat-v1", &mut shared_secret)
+        .map_err(|e| anyhow::anyhow!("HKDF expand failed: {:?}", e))?;
+    Ok(shared_secret)
+}
+
+/// Encrypt one chunk of an attachment for transfer. Attachment keys are
+/// random and never reused across attachments, so the chunk index serves
+/// as nonce; binding the index as associated data stops reordering.
+pub fn seal_attachment_chunk(key: &[u8; 32], transfer_id: &[u8; 32], index: u32, plaintext: &[u8]) -> Result<Vec<u8>> {
+    use chacha20poly1305::{aead::Payload, XChaCha20Poly1305, XNonce};
+    
+    let (nonce, aad) = attachment_chunk_nonce_aad(transfer_id, index);
+    XChaCha20Poly1305::new(key.into())
+        .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad: &aad })
+        .map_err(|e| anyhow::anyhow!("Failed to encrypt attachment chunk: {:?}", e))
+}
+
+/// Decrypt a chunk sealed by `seal_attachment_chunk`
+pub fn open_attachment_chunk(key: &[u8; 32], transfer_id: &[u8; 32], index: u32, ciphertext: &[u8]) -> Result<Vec<u8>> {
+    use chacha20poly1305::{aead::Payload, XChaCha20Poly1305, XNonce};
+    
+    let (nonce, aad) = attachment_chunk_nonce_aad(transfer_id, index);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(&nonce), Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| anyhow::anyhow!("Attachment chunk failed authentication"))
+}
+
+fn attachment_chunk_nonce_aad(transfer_id: &[u8; 32], index: u32) -> ([u8; 24], [u8; 36]) {
+    let mut nonce = [0u8; 24];
+    nonce[20..].copy_from_slice(&index.to_be_bytes());
+    let mut aad = [0u8; 36];
+    aad[..32].copy_from_slice(transfer_id);
+    aad[32..].copy_from_slice(&index.to_be_bytes());
+    (nonce, aad)
+}
+
 impl DoubleRatchet {
     /// Initialize with shared secret from X3DH
     pub fn initialize(shared_secret: &[u8; 32]) -> Self {
@@ -305,11 +525,14 @@ impl DoubleRatchet {
             sending_message_number: 0,
             receiving_message_number: 0,
             skipped_message_keys: Vec::new(),
+            sending_chain: 0,
+            receiving_chain: 0,
+            previous_sending_length: 0,
         }
     }
     
     /// Ratchet step - derive new chain keys
-    pub fn ratchet(&mut self, new_remote_pubkey: &[u8; 32]) -> Result<()> {
+    pub fn ratchet(&mut self, _new_remote_pubkey: &[u8; 32]) -> Result<()> {
         let hk = Hkdf::<Sha256>::new(None, &self.root_key);
         let mut new_root = [0u8; 32];
         hk.expand(b"ratchet-root", &mut new_root)
@@ -326,11 +549,237 @@ impl DoubleRatchet {
         self.root_key = new_root;
         self.sending_chain_key = Some(sending);
         self.receiving_chain_key = Some(receiving);
+        self.previous_sending_length = self.sending_message_number;
         self.sending_message_number = 0;
         self.receiving_message_number = 0;
+        self.sending_chain += 1;
+        self.receiving_chain += 1;
         
         Ok(())
     }
+    
+    /// Most keys skipped over within one chain for a single message
+    pub const MAX_SKIP: u32 = 1000;
+    
+    /// Most skipped keys kept in total; the oldest are dropped first
+    pub const MAX_SKIPPED_KEYS: usize = 2000;
+    
+    /// Most ratchet steps taken for a single message
+    pub const MAX_CHAIN_SKIP: u32 = 100;
+    
+    /// Key for the next outgoing message and the header to send with it
+    pub fn next_sending_key(&mut self) -> Result<(RatchetHeader, [u8; 32])> {
+        let chain_key = self.sending_chain_key
+            .ok_or_else(|| anyhow::anyhow!("Sending chain not initialized"))?;
+        let (next_chain_key, message_key) = Self::step_chain(&chain_key)?;
+        
+        let header = RatchetHeader {
+            chain: self.sending_chain,
+            previous_chain_length: self.previous_sending_length,
+            message_number: self.sending_message_number,
+        };
+        self.sending_chain_key = Some(next_chain_key);
+        self.sending_message_number += 1;
+        Ok((header, message_key))
+    }
+    
+    /// Key for an incoming message. A message that arrives after later
+    /// ones uses the key kept when it was skipped; keys of messages still
+    /// missing are kept for when they turn up. Each key is handed out once.
+    pub fn receiving_key(&mut self, header: &RatchetHeader) -> Result<[u8; 32]> {
+        self.receiving_key_within(header, Self::MAX_SKIP)
+    }
+    
+    /// `receiving_key`, skipping at most `max_skip` keys within a chain
+    pub fn receiving_key_within(&mut self, header: &RatchetHeader, max_skip: u32) -> Result<[u8; 32]> {
+        if let Some(pos) = self.skipped_message_keys.iter()
+            .position(|k| k.chain == header.chain && k.message_number == header.message_number)
+        {
+            return Ok(self.skipped_message_keys.remove(pos).key);
+        }
+        if header.chain < self.receiving_chain {
+            return Err(anyhow::anyhow!("Message key from an old chain is no longer available"));
+        }
+        
+        if header.chain > self.receiving_chain {
+            let steps = header.chain - self.receiving_chain;
+            if steps > Self::MAX_CHAIN_SKIP {
+                return Err(LimitError::TooManyChains { count: steps, max: Self::MAX_CHAIN_SKIP }.into());
+            }
+            // Keep the rest of the current chain, then step to the new one.
+            // Chains skipped entirely can't be recovered.
+            if self.receiving_chain_key.is_some() {
+                self.skip_receiving_keys(header.previous_chain_length, max_skip)?;
+            }
+            while self.receiving_chain < header.chain {
+                self.ratchet(&[0u8; 32])?;
+            }
+        }
+        
+        if header.message_number < self.receiving_message_number {
+            return Err(anyhow::anyhow!("Message key already used"));
+        }
+        self.skip_receiving_keys(header.message_number, max_skip)?;
+        
+        let chain_key = self.receiving_chain_key
+            .ok_or_else(|| anyhow::anyhow!("Receiving chain not initialized"))?;
+        let (next_chain_key, message_key) = Self::step_chain(&chain_key)?;
+        self.receiving_chain_key = Some(next_chain_key);
+        self.receiving_message_number += 1;
+        Ok(message_key)
+    }
+    
+    /// Advance the receiving chain to `until`, keeping the keys passed over
+    fn skip_receiving_keys(&mut self, until: u32, max_skip: u32) -> Result<()> {
+        let count = until.saturating_sub(self.receiving_message_number);
+        if count > max_skip {
+            return Err(LimitError::TooManySkipped { count, max: max_skip }.into());
+        }
+        while self.receiving_message_number < until {
+            let chain_key = self.receiving_chain_key
+                .ok_or_else(|| anyhow::anyhow!("Receiving chain not initialized"))?;
+            let (next_chain_key, message_key) = Self::step_chain(&chain_key)?;
+            self.skipped_message_keys.push(SkippedKey {
+                chain: self.receiving_chain,
+                message_number: self.receiving_message_number,
+                key: message_key,
+                skipped_at: Some(OffsetDateTime::now_utc()),
+            });
+            self.receiving_chain_key = Some(next_chain_key);
+            self.receiving_message_number += 1;
+        }
+        
+        let excess = self.skipped_message_keys.len().saturating_sub(Self::MAX_SKIPPED_KEYS);
+        self.skipped_message_keys.drain(..excess);
+        Ok(())
+    }
+    
+    /// Drop skipped keys older than `max_age`, then the oldest beyond
+    /// `max_count`. Returns how many were dropped.
+    pub fn trim_skipped_keys(&mut self, now: OffsetDateTime, max_age: time::Duration, max_count: usize) -> usize {
+        let before = self.skipped_message_keys.len();
+        for key in &mut self.skipped_message_keys {
+            key.skipped_at.get_or_insert(now);
+        }
+        self.skipped_message_keys.retain(|key| key.skipped_at.is_some_and(|at| now - at <= max_age));
+        let excess = self.skipped_message_keys.len().saturating_sub(max_count);
+        self.skipped_message_keys.drain(..excess);
+        before - self.skipped_message_keys.len()
+    }
+    
+    /// Symmetric ratchet: next chain key and this step's message key
+    fn step_chain(chain_key: &[u8; 32]) -> Result<([u8; 32], [u8; 32])> {
+        let hk = Hkdf::<Sha256>::from_prk(chain_key)
+            .map_err(|e| anyhow::anyhow!("Invalid chain key: {:?}", e))?;
+        let mut next_chain_key = [0u8; 32];
+        hk.expand(b"chain-key", &mut next_chain_key)
+            .map_err(|e| anyhow::anyhow!("Chain key derivation failed: {:?}", e))?;
+        let mut message_key = [0u8; 32];
+        hk.expand(b"message-key", &mut message_key)
+            .map_err(|e| anyhow::anyhow!("Message key derivation failed: {:?}", e))?;
+        Ok((next_chain_key, message_key))
+    }
+}
+
+impl Fingerprint {
+    /// Current derivation version, mixed into every digest
+    pub const VERSION: u8 = 1;
+    
+    /// Number of 5-digit groups in a safety number
+    const SAFETY_NUMBER_GROUPS: usize = 6;
+    
+    /// Fingerprint of a single identity key
+    pub fn of_key(public_key: &[u8; 32]) -> Self {
+        let mut hasher = blake3::Hasher::new_derive_key("SecureChat identity fingerprint v1");
+        hasher.update(&[Self::VERSION]);
+        hasher.update(public_key);
+        
+        Self {
+            version: Self::VERSION,
+            digest: *hasher.finalize().as_bytes(),
+        }
+    }
+    
+    /// Safety number binding both parties' identity keys.
+    ///
+    /// Keys are ordered before hashing so both ends compute the same value,
+    /// and the result only matches if each side holds the other's real key.
+    pub fn for_conversation(local_key: &[u8; 32], remote_key: &[u8; 32]) -> Self {
+        let (first, second) = if local_key <= remote_key {
+            (local_key, remote_key)
+        } else {
+            (remote_key, local_key)
+        };
+        
+        let mut hasher = blake3::Hasher::new_derive_key("SecureChat safety number v1");
+        hasher.update(&[Self::VERSION]);
+        hasher.update(first);
+        hasher.update(second);
+        
+        Self {
+            version: Self::VERSION,
+            digest: *hasher.finalize().as_bytes(),
+        }
+    }
+    
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.digest
+    }
+    
+    /// Full hex encoding
+    pub fn to_hex(&self) -> String {
+        self.digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+    
+    /// First eight hex digits in two groups, shown next to a name to tell
+    /// apart contacts who call themselves the same
+    pub fn short_hint(&self) -> String {
+        let hex = self.to_hex();
+        format!("{} {}", &hex[..4], &hex[4..8])
+    }
+
+    /// Numeric encoding for reading aloud or comparing on screen:
+    /// six groups of five digits, each taken from 40 bits of the digest.
+    pub fn safety_number(&self) -> String {
+        self.digest
+            .chunks_exact(5)
+            .take(Self::SAFETY_NUMBER_GROUPS)
+            .map(|chunk| {
+                let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+                format!("{:05}", value % 100_000)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+    
+    /// Compare against a safety number typed or scanned by the user.
+    /// Whitespace is ignored; the digit comparison is constant-time.
+    pub fn matches_safety_number(&self, input: &str) -> bool {
+        let expected: Vec<u8> = self.safety_number().bytes().filter(u8::is_ascii_digit).collect();
+        let actual: Vec<u8> = input.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        expected.len() == actual.len() && bool::from(expected.ct_eq(&actual))
+    }
+}
+
+impl PartialEq for Fingerprint {
+    fn eq(&self, other: &Self) -> bool {
+        let same_version = self.version.ct_eq(&other.version);
+        bool::from(same_version & self.digest.ct_eq(&other.digest))
+    }
+}
+
+impl Eq for Fingerprint {}
+
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl std::fmt::Debug for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Fingerprint(v{}, {})", self.version, self.to_hex())
+    }
 }
 
 /// Utility function to hash a password for storage
@@ -353,9 +802,234 @@ pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
     Ok(argon2.verify_password(password.as_bytes(), &parsed_hash).is_ok())
 }
 
+/// Deterministic crypto test vectors for interoperability audits.
+///
+/// Every input (static secrets, ephemeral secrets, nonces) is fixed so that
+/// independent implementations can reproduce our outputs byte for byte. The
+/// published set lives in `test-vectors/crypto-v1.json`.
+#[cfg(any(test, feature = "test-vectors"))]
+pub mod test_vectors {
+    use super::*;
+    
+    /// Published vectors, checked against the implementation in tests
+    pub const PUBLISHED: &str = include_str!("../test-vectors/crypto-v1.json");
+    
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct TestVectors {
+        pub version: u32,
+        pub x3dh: Vec<X3dhVector>,
+        pub ratchet: Vec<RatchetVector>,
+        pub envelope: Vec<EnvelopeVector>,
+    }
+    
+    /// Shared secret derivation from sender static + ephemeral keys
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct X3dhVector {
+        pub sender_secret: String,
+        pub recipient_secret: String,
+        pub ephemeral_secret: String,
+        pub sender_public: String,
+        pub recipient_public: String,
+        pub ephemeral_public: String,
+        pub shared_secret: String,
+    }
+    
+    /// Successive root/chain keys after repeated ratchet steps
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct RatchetVector {
+        pub initial_root_key: String,
+        pub steps: Vec<RatchetStep>,
+    }
+    
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct RatchetStep {
+        pub root_key: String,
+        pub sending_chain_key: String,
+        pub receiving_chain_key: String,
+    }
+    
+    /// Full envelope encryption with a fixed ephemeral key and nonce
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct EnvelopeVector {
+        pub sender_secret: String,
+        pub recipient_secret: String,
+        pub ephemeral_secret: String,
+        pub nonce: String,
+        pub plaintext: String,
+        pub ciphertext: String,
+    }
+    
+    const RATCHET_STEPS: usize = 3;
+    
+    /// Fixed inputs: (sender, recipient, ephemeral, nonce, plaintext)
+    type Inputs = ([u8; 32], [u8; 32], [u8; 32], [u8; 12], &'static [u8]);
+    
+    fn inputs() -> Vec<Inputs> {
+        vec![
+            ([0x11; 32], [0x22; 32], [0x33; 32], [0x44; 12], b"Hello, secure world!"),
+            ([0xA5; 32], [0x5A; 32], [0x0F; 32], [0x00; 12], b""),
+        ]
+    }
+    
+    /// Compute the vectors from the fixed inputs
+    pub fn generate() -> Result<TestVectors> {
+        let mut x3dh = Vec::new();
+        let mut envelope = Vec::new();
+        
+        for (sender, recipient, ephemeral, nonce, plaintext) in inputs() {
+            let sender_keys = MessageKeyPair::from_secret_bytes(sender);
+            let recipient_keys = MessageKeyPair::from_secret_bytes(recipient);
+            let ephemeral_keys = MessageKeyPair::from_secret_bytes(ephemeral);
+            
+            let dh1 = sender_keys.secret_key.diffie_hellman(&recipient_keys.public_key);
+            let dh2 = ephemeral_keys.secret_key.diffie_hellman(&recipient_keys.public_key);
+            let shared = derive_shared_secret(dh1.as_bytes(), dh2.as_bytes())?;
+            
+            x3dh.push(X3dhVector {
+                sender_secret: to_hex(&sender),
+                recipient_secret: to_hex(&recipient),
+                ephemeral_secret: to_hex(&ephemeral),
+                sender_public: to_hex(sender_keys.public_key.as_bytes()),
+                recipient_public: to_hex(recipient_keys.public_key.as_bytes()),
+                ephemeral_public: to_hex(ephemeral_keys.public_key.as_bytes()),
+                shared_secret: to_hex(&shared),
+            });
+            
+            let encrypted = sender_keys.encrypt_message_with(
+                &recipient_keys.public_key,
+                plaintext,
+                &[],
+                &ephemeral_keys.secret_key,
+                nonce,
+            )?;
+            envelope.push(EnvelopeVector {
+                sender_secret: to_hex(&sender),
+                recipient_secret: to_hex(&recipient),
+                ephemeral_secret: to_hex(&ephemeral),
+                nonce: to_hex(&nonce),
+                plaintext: to_hex(plaintext),
+                ciphertext: to_hex(&encrypted.ciphertext),
+            });
+        }
+        
+        let ratchet = [[0x00u8; 32], [0x7Eu8; 32]]
+            .iter()
+            .map(|root| {
+                let mut state = DoubleRatchet::initialize(root);
+                let mut steps = Vec::with_capacity(RATCHET_STEPS);
+                for _ in 0..RATCHET_STEPS {
+                    state.ratchet(&[0u8; 32])?;
+                    steps.push(RatchetStep {
+                        root_key: to_hex(&state.root_key),
+                        sending_chain_key: to_hex(&state.sending_chain_key.unwrap_or_default()),
+                        receiving_chain_key: to_hex(&state.receiving_chain_key.unwrap_or_default()),
+                    });
+                }
+                Ok(RatchetVector {
+                    initial_root_key: to_hex(root),
+                    steps,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        
+        Ok(TestVectors {
+            version: 1,
+            x3dh,
+            ratchet,
+            envelope,
+        })
+    }
+    
+    /// Check the implementation against a set of vectors
+    pub fn verify(vectors: &TestVectors) -> Result<()> {
+        for (i, v) in vectors.x3dh.iter().enumerate() {
+            let sender = MessageKeyPair::from_secret_bytes(from_hex(&v.sender_secret)?);
+            let recipient = MessageKeyPair::from_secret_bytes(from_hex(&v.recipient_secret)?);
+            let ephemeral = MessageKeyPair::from_secret_bytes(from_hex(&v.ephemeral_secret)?);
+            
+            check(i, "x3dh sender_public", sender.public_key.as_bytes(), &v.sender_public)?;
+            check(i, "x3dh recipient_public", recipient.public_key.as_bytes(), &v.recipient_public)?;
+            check(i, "x3dh ephemeral_public", ephemeral.public_key.as_bytes(), &v.ephemeral_public)?;
+            
+            // Both sides must arrive at the same secret
+            let sender_side = derive_shared_secret(
+                sender.secret_key.diffie_hellman(&recipient.public_key).as_bytes(),
+                ephemeral.secret_key.diffie_hellman(&recipient.public_key).as_bytes(),
+            )?;
+            let recipient_side = derive_shared_secret(
+                recipient.secret_key.diffie_hellman(&sender.public_key).as_bytes(),
+                recipient.secret_key.diffie_hellman(&ephemeral.public_key).as_bytes(),
+            )?;
+            check(i, "x3dh sender shared_secret", &sender_side, &v.shared_secret)?;
+            check(i, "x3dh recipient shared_secret", &recipient_side, &v.shared_secret)?;
+        }
+        
+        for (i, v) in vectors.ratchet.iter().enumerate() {
+            let mut state = DoubleRatchet::initialize(&from_hex(&v.initial_root_key)?);
+            for step in &v.steps {
+                state.ratchet(&[0u8; 32])?;
+                check(i, "ratchet root_key", &state.root_key, &step.root_key)?;
+                check(i, "ratchet sending_chain_key", &state.sending_chain_key.unwrap_or_default(), &step.sending_chain_key)?;
+                check(i, "ratchet receiving_chain_key", &state.receiving_chain_key.unwrap_or_default(), &step.receiving_chain_key)?;
+            }
+        }
+        
+        for (i, v) in vectors.envelope.iter().enumerate() {
+            let sender = MessageKeyPair::from_secret_bytes(from_hex(&v.sender_secret)?);
+            let recipient = MessageKeyPair::from_secret_bytes(from_hex(&v.recipient_secret)?);
+            let ephemeral = MessageKeyPair::from_secret_bytes(from_hex(&v.ephemeral_secret)?);
+            let plaintext = from_hex_vec(&v.plaintext)?;
+            
+            let encrypted = sender.encrypt_message_with(
+                &recipient.public_key,
+                &plaintext,
+                &[],
+                &ephemeral.secret_key,
+                from_hex(&v.nonce)?,
+            )?;
+            check(i, "envelope ciphertext", &encrypted.ciphertext, &v.ciphertext)?;
+            
+            let decrypted = recipient.decrypt_message(&encrypted)?;
+            if decrypted != plaintext {
+                return Err(anyhow::anyhow!("Vector {}: envelope decryption mismatch", i));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn check(index: usize, what: &str, actual: &[u8], expected: &str) -> Result<()> {
+        if to_hex(actual) != expected {
+            return Err(anyhow::anyhow!("Vector {}: {} mismatch", index, what));
+        }
+        Ok(())
+    }
+    
+    pub fn to_hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+    
+    pub fn from_hex_vec(s: &str) -> Result<Vec<u8>> {
+        if !s.len().is_multiple_of(2) {
+            return Err(anyhow::anyhow!("Odd-length hex string"));
+        }
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).context("Invalid hex"))
+            .collect()
+    }
+    
+    pub fn from_hex<const N: usize>(s: &str) -> Result<[u8; N]> {
+        from_hex_vec(s)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Expected {} bytes of hex", N))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     
     #[test]
     fn test_master_key_encryption() {
@@ -415,4 +1089,154 @@ mod tests {
         IdentityKeyPair::verify(&identity.public_key, message, &signature)
             .expect("Signature verification failed");
     }
+    
+    #[test]
+    fn test_published_vectors() {
+        let published: test_vectors::TestVectors = serde_json::from_str(test_vectors::PUBLISHED)
+            .expect("Published vectors must parse");
+        
+        test_vectors::verify(&published).expect("Implementation diverges from published vectors");
+        assert_eq!(test_vectors::generate().unwrap(), published);
+    }
+    
+    #[test]
+    fn test_vectors_detect_divergence() {
+        let mut vectors = test_vectors::generate().unwrap();
+        vectors.envelope[0].ciphertext.replace_range(0..2, "00");
+        assert!(test_vectors::verify(&vectors).is_err());
+    }
+    
+    #[test]
+    fn test_fingerprints() {
+        let alice = [1u8; 32];
+        let bob = [2u8; 32];
+        let mallory = [3u8; 32];
+        
+        // Both ends agree, independent of argument order
+        let ab = Fingerprint::for_conversation(&alice, &bob);
+        assert_eq!(ab, Fingerprint::for_conversation(&bob, &alice));
+        assert_ne!(ab, Fingerprint::for_conversation(&alice, &mallory));
+        
+        // Single-key and pairwise derivations are domain separated
+        assert_ne!(Fingerprint::of_key(&alice), Fingerprint::of_key(&bob));
+        assert_ne!(Fingerprint::of_key(&alice).as_bytes(), ab.as_bytes());
+        
+        let number = ab.safety_number();
+        assert_eq!(number.len(), 6 * 5 + 5);
+        assert!(ab.matches_safety_number(&number));
+        assert!(ab.matches_safety_number(&number.replace(' ', "")));
+        assert!(!ab.matches_safety_number(&Fingerprint::for_conversation(&alice, &mallory).safety_number()));
+        assert!(!ab.matches_safety_number(&number[..20]));
+    }
+    
+    /// Two ends of a session: `alice`'s sending chain is `bob`'s receiving one
+    fn session_pair() -> (DoubleRatchet, DoubleRatchet) {
+        let mut alice = DoubleRatchet::initialize(&[3u8; 32]);
+        alice.ratchet(&[0u8; 32]).unwrap();
+        let mut bob = alice.clone();
+        bob.receiving_chain_key = alice.sending_chain_key;
+        (alice, bob)
+    }
+    
+    #[test]
+    fn test_ratchet_out_of_order() {
+        let (mut alice, mut bob) = session_pair();
+        
+        let sent: Vec<_> = (0..4).map(|_| alice.next_sending_key().unwrap()).collect();
+        assert_eq!(bob.receiving_key(&sent[2].0).unwrap(), sent[2].1);
+        assert_eq!(bob.skipped_message_keys.len(), 2);
+        assert_eq!(bob.receiving_key(&sent[0].0).unwrap(), sent[0].1);
+        // Each key is handed out once
+        assert!(bob.receiving_key(&sent[2].0).is_err());
+        assert_eq!(bob.receiving_key(&sent[3].0).unwrap(), sent[3].1);
+        assert_eq!(bob.receiving_key(&sent[1].0).unwrap(), sent[1].1);
+        assert!(bob.skipped_message_keys.is_empty());
+        
+        // The tail of a chain stays available after the next one starts
+        let late = alice.next_sending_key().unwrap();
+        alice.ratchet(&[0u8; 32]).unwrap();
+        let (header, _) = alice.next_sending_key().unwrap();
+        assert_eq!(header.previous_chain_length, 5);
+        bob.receiving_key(&header).unwrap();
+        assert_eq!(bob.receiving_chain, header.chain);
+        assert_eq!(bob.receiving_key(&late.0).unwrap(), late.1);
+        
+        let far = RatchetHeader {
+            chain: bob.receiving_chain,
+            previous_chain_length: 0,
+            message_number: DoubleRatchet::MAX_SKIP + 5,
+        };
+        assert!(bob.receiving_key(&far).is_err());
+        let far = RatchetHeader {
+            chain: u32::MAX,
+            previous_chain_length: 0,
+            message_number: 0,
+        };
+        let error = bob.receiving_key(&far).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(LimitError::TooManyChains { .. })));
+        assert_eq!(bob.receiving_chain, header.chain);
+    }
+    
+    #[test]
+    fn test_trim_skipped_keys() {
+        let (mut alice, mut bob) = session_pair();
+        let sent: Vec<_> = (0..5).map(|_| alice.next_sending_key().unwrap()).collect();
+        bob.receiving_key(&sent[4].0).unwrap();
+        bob.skipped_message_keys[0].skipped_at = None;
+        let now = OffsetDateTime::now_utc();
+        
+        // Unstamped keys count from the first trim
+        assert_eq!(bob.trim_skipped_keys(now, time::Duration::hours(1), 10), 0);
+        assert_eq!(bob.trim_skipped_keys(now, time::Duration::hours(1), 3), 1);
+        assert_eq!(bob.skipped_message_keys[0].message_number, 1);
+        assert_eq!(bob.trim_skipped_keys(now + time::Duration::hours(2), time::Duration::hours(1), 10), 3);
+        assert!(bob.receiving_key(&sent[2].0).is_err());
+    }
+    
+    #[test]
+    fn test_key_hierarchy_separation() {
+        let keys = KeyHierarchy::derive(&[7u8; 32]).unwrap();
+        let all = [keys.storage, keys.identity_wrap, keys.search_index, keys.backup, keys.attachment_id, keys.profile_id];
+        
+        for (i, a) in all.iter().enumerate() {
+            assert_ne!(a, &[7u8; 32]);
+            for b in &all[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        
+        // Deterministic for a given master key
+        assert_eq!(KeyHierarchy::derive(&[7u8; 32]).unwrap().storage, keys.storage);
+    }
+    
+    proptest! {
+        /// Every message that arrives decrypts, in any order and with any
+        /// others lost, and its key is handed out only once
+        #[test]
+        fn prop_ratchet_survives_loss_and_reordering(
+            plan in prop::collection::vec((prop::bool::weighted(0.2), any::<u16>()), 1..300)
+        ) {
+            let (mut alice, mut bob) = session_pair();
+            let sent: Vec<_> = plan.iter().map(|_| alice.next_sending_key().unwrap()).collect();
+            
+            let mut arriving: Vec<_> = sent.iter().zip(&plan)
+                .filter(|(_, (lost, _))| !lost)
+                .map(|(message, (_, position))| (position, message))
+                .collect();
+            arriving.sort_by_key(|(position, _)| **position);
+            for (_, (header, key)) in &arriving {
+                prop_assert_eq!(&bob.receiving_key(header).unwrap(), key);
+            }
+            for (_, (header, _)) in &arriving {
+                prop_assert!(bob.receiving_key(header).is_err());
+            }
+            
+            // Exactly the lost messages below the newest arrival are held
+            let newest = arriving.iter().map(|(_, (header, _))| header.message_number).max();
+            let held = sent.iter().zip(&plan)
+                .filter(|((header, _), (lost, _))| *lost && newest.is_some_and(|n| header.message_number < n))
+                .count();
+            prop_assert_eq!(bob.skipped_message_keys.len(), held);
+        }
+    }
 }
@@ -0,0 +1,557 @@
+//! Contacts with several devices.
+//!
+//! Every device announces itself to contacts with a `DeviceAnnouncement`
+//! signed by the account's identity key: its device id and the message key
+//! it can be reached under. Contacts keep the announced devices in a
+//! registry and fan each message out as one envelope per device, sealed to
+//! that device's key and numbered in a session of its own, so a device
+//! can put its messages back in order without seeing the other devices'
+//! envelopes. All envelopes of one message carry its id, and the receiver
+//! stores it once however many copies arrive.
+//!
+//! Users can hold a contact to `SessionRequirements`, e.g. per-device
+//! sessions only. A message that would go or come any weaker way is
+//! refused rather than downgraded, and the refusal reported.
+//!
+//! Announcements also carry the device's `Capabilities`, under the same
+//! signature, so a relay can't strip features from them. Envelopes to a
+//! device that supports it bind a `CapabilityTranscript` of both ends'
+//! sets as associated data. The receiver checks it against the set it
+//! announced and the one it registered for the sender, and a mismatch,
+//! meaning one side was shown a different set, fails the message with
+//! `CapabilityMismatch`. A device that just gained capabilities can see
+//! this until its new announcement has reached the contact. Devices that
+//! also take `ENVELOPE_AAD` get the rest of the header bound the same way,
+//! so a relay can't move content under another envelope's id, device or
+//! place in the session.
+//!
+//! Envelopes to a device are authenticated one of two ways, chosen per
+//! conversation as its `Authentication`. By default they are signed with
+//! the identity key, which proves authorship to anyone shown the message.
+//! In deniable mode they go unsigned. Their content is then sealed under
+//! a key that comes in part from a DH between the sending device's
+//! announced message key and the recipient's. This works like the static
+//! half of a triple DH. The recipient knows the message came from that
+//! device, but could have sealed it themselves, so it proves nothing to a
+//! third party.
+
+use anyhow::{Result, Context};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::crypto::{DoubleRatchet, IdentityKeyPair, RatchetHeader};
+use crate::limits::LimitError;
+use crate::protocol::{wire, MessageEnvelope, MAX_ID_LEN};
+
+/// Most devices kept for one contact; the longest silent go first
+pub const MAX_DEVICES_PER_CONTACT: usize = 16;
+
+/// One of a contact's devices
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteDevice {
+    pub device_id: String,
+    /// X25519 key messages to the device are sealed to
+    pub message_key: [u8; 32],
+    pub announced_at: OffsetDateTime,
+}
+
+/// A device introducing itself to contacts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceAnnouncement {
+    pub identity_key: [u8; 32],
+    pub device: RemoteDevice,
+    /// Empty from devices that predate capabilities
+    #[serde(default, skip_serializing_if = "Capabilities::is_empty")]
+    pub capabilities: Capabilities,
+    pub signature: Vec<u8>,
+}
+
+/// Optional features of a device, one bit each. Bits this build doesn't
+/// know are kept, so sets from newer devices compare as sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// Numbers its messages in per-device sessions
+    pub const DEVICE_SESSIONS: Self = Self(1);
+    /// Sends and holds to conversation policies
+    pub const CONVERSATION_POLICIES: Self = Self(1 << 1);
+    /// Opens view-once media
+    pub const VIEW_ONCE: Self = Self(1 << 2);
+    /// Checks a `CapabilityTranscript` bound into its envelopes
+    pub const TRANSCRIPT: Self = Self(1 << 3);
+    /// Takes envelopes with the whole header bound into the content, see
+    /// `MessageEnvelope::content_aad`
+    pub const ENVELOPE_AAD: Self = Self(1 << 4);
+    /// Everything this build supports
+    pub const SUPPORTED: Self = Self(
+        Self::DEVICE_SESSIONS.0 | Self::CONVERSATION_POLICIES.0 | Self::VIEW_ONCE.0 | Self::TRANSCRIPT.0
+            | Self::ENVELOPE_AAD.0,
+    );
+    
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// The capabilities of both ends of a device session, as the sender saw
+/// them, bound into an envelope as associated data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapabilityTranscript {
+    pub sender: Capabilities,
+    /// As the recipient device announced it to the sender
+    pub recipient: Capabilities,
+}
+
+impl CapabilityTranscript {
+    /// Associated data the envelope's content is sealed with
+    pub fn aad(&self) -> Result<Vec<u8>> {
+        wire::encode(&(b"SecureChat capabilities v1", self))
+    }
+    
+    /// Check the transcript of an envelope we received against what we
+    /// announced and what we registered for its sender, if anything
+    pub fn check(&self, ours: Capabilities, registered: Option<Capabilities>) -> Result<(), CapabilityMismatch> {
+        let sender_matches = registered.is_none_or(|registered| registered == self.sender);
+        if self.recipient != ours || !sender_matches {
+            return Err(CapabilityMismatch { transcript: *self, ours, registered });
+        }
+        Ok(())
+    }
+}
+
+/// The two ends of a device session were shown different capabilities,
+/// as a relay stripping features would cause
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Capabilities don't match: the sender saw {transcript:?}, we announced {ours:?} and registered {registered:?} for the sender")]
+pub struct CapabilityMismatch {
+    pub transcript: CapabilityTranscript,
+    pub ours: Capabilities,
+    pub registered: Option<Capabilities>,
+}
+
+/// What we received in one of a contact's device sessions, the other end
+/// of their `DeviceSession` with us. Envelopes are sealed one by one, so
+/// there are no chain keys to hold for messages still missing; their
+/// numbers stand in for a ratchet's skipped keys, and each number is
+/// taken once.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReceivingSession {
+    pub chain: u32,
+    /// Number the next message in `chain` should have
+    pub next_message: u32,
+    /// `(chain, message number)` of messages passed over, oldest first
+    pub skipped: Vec<(u32, u32)>,
+}
+
+/// Our side of the session with one of a contact's devices
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceSession {
+    /// The device's key this session seals to
+    pub message_key: [u8; 32],
+    /// Bumped whenever the device announces a new key
+    pub chain: u32,
+    pub next_message: u32,
+    /// Messages sent in the previous chain
+    pub previous_chain_length: u32,
+}
+
+/// What can be shown about our session with a contact without giving
+/// away any key material
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub contact_id: String,
+    /// Wire format version we send
+    pub protocol_version: u8,
+    pub cipher_suite: String,
+    /// Weakest way messages to the contact go at the moment
+    pub mode: SessionMode,
+    pub requirements: SessionRequirements,
+    /// How our messages to the contact's devices prove they're ours;
+    /// messages through an invite's prekey are always signed
+    pub authentication: Authentication,
+    pub verified: bool,
+    /// Messages still go through the prekey of an invite
+    pub invite_prekey: bool,
+    /// When a device of the contact last announced a new key
+    pub last_rekey_at: Option<OffsetDateTime>,
+    pub devices: Vec<DeviceSessionInfo>,
+    /// Counters of the conversation's ratchet, if it has one
+    pub ratchet: Option<RatchetInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceSessionInfo {
+    pub device_id: String,
+    pub announced_at: OffsetDateTime,
+    /// Absent until the first message is sealed to the device
+    pub session: Option<SessionCounters>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionCounters {
+    /// Key changes of the device seen so far
+    pub chain: u32,
+    /// Messages sealed in the current chain
+    pub messages_sent: u32,
+    pub previous_chain_length: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RatchetInfo {
+    pub sending_chain: u32,
+    pub receiving_chain: u32,
+    pub sending_message_number: u32,
+    pub receiving_message_number: u32,
+    pub previous_sending_length: u32,
+    pub skipped_keys: usize,
+}
+
+impl From<&DeviceSession> for SessionCounters {
+    fn from(session: &DeviceSession) -> Self {
+        Self {
+            chain: session.chain,
+            messages_sent: session.next_message,
+            previous_chain_length: session.previous_chain_length,
+        }
+    }
+}
+
+impl From<&DoubleRatchet> for RatchetInfo {
+    fn from(ratchet: &DoubleRatchet) -> Self {
+        Self {
+            sending_chain: ratchet.sending_chain,
+            receiving_chain: ratchet.receiving_chain,
+            sending_message_number: ratchet.sending_message_number,
+            receiving_message_number: ratchet.receiving_message_number,
+            previous_sending_length: ratchet.previous_sending_length,
+            skipped_keys: ratchet.skipped_message_keys.len(),
+        }
+    }
+}
+
+/// How messages to a contact are sealed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionMode {
+    /// No device announced and no invite to go through; nothing can be
+    /// sent yet
+    Unestablished,
+    /// To the prekey of the invite the contact was added through, shared
+    /// by all its devices
+    InvitePrekey,
+    /// To each announced device, numbered in a session of its own
+    DeviceSessions,
+}
+
+/// What sessions with a contact must offer, set per contact by users who
+/// want more than the defaults
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRequirements {
+    /// Nothing is sent or accepted until the safety number is verified
+    pub verified: bool,
+    /// Per-device sessions only: nothing through an invite's prekey, and
+    /// nothing from clients that don't number their messages per device
+    pub device_sessions: bool,
+}
+
+/// How our envelopes in a conversation prove they come from us
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Authentication {
+    /// Signed with the identity key: anyone shown a message can check it
+    /// is ours, so we can't deny having written it
+    #[default]
+    Signed,
+    /// Only the recipient can tell a message is ours, as they could have
+    /// made it too. Needs the recipient to know our device's message key,
+    /// so messages from a device wait for its announcement.
+    Deniable,
+}
+
+impl Authentication {
+    /// Check who sent an envelope from one of a contact's devices, given
+    /// their identity key and the device as we registered it. Returns how
+    /// it was authenticated.
+    pub fn check(envelope: &MessageEnvelope, identity_key: &[u8; 32], registered: Option<&RemoteDevice>) -> Result<Self> {
+        if !envelope.signature.is_empty() {
+            let key = VerifyingKey::from_bytes(identity_key).context("Invalid identity key")?;
+            let signature = Signature::from_slice(&envelope.signature).context("Malformed signature")?;
+            IdentityKeyPair::verify(&key, &envelope.signing_bytes()?, &signature)
+                .context("Envelope signature is invalid")?;
+            return Ok(Self::Signed);
+        }
+        // The content opened under the DH with this key, so it came from
+        // whoever holds it
+        match registered {
+            Some(device) if device.message_key == envelope.encrypted_content.sender_pubkey => Ok(Self::Deniable),
+            Some(_) => Err(anyhow::anyhow!("Envelope is sealed with a key its device didn't announce")),
+            None => Err(anyhow::anyhow!("Unsigned envelope from a device that hasn't announced itself")),
+        }
+    }
+}
+
+/// What a refused message fell short of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Downgrade {
+    /// The contact's safety number isn't verified
+    Unverified,
+    /// It went through an invite's prekey
+    InvitePrekey,
+    /// It came without a per-device session
+    NoDeviceSession,
+}
+
+impl SessionRequirements {
+    /// What an envelope from a contact, verified or not, falls short of
+    pub fn check_envelope(&self, envelope: &MessageEnvelope, verified: bool) -> Option<Downgrade> {
+        if self.verified && !verified {
+            return Some(Downgrade::Unverified);
+        }
+        if self.device_sessions {
+            if envelope.invite.is_some() {
+                return Some(Downgrade::InvitePrekey);
+            }
+            if envelope.ratchet_header.is_none() || envelope.sender_device.is_none() {
+                return Some(Downgrade::NoDeviceSession);
+            }
+        }
+        None
+    }
+}
+
+impl RemoteDevice {
+    pub fn validate(&self) -> Result<()> {
+        if self.device_id.is_empty() || self.device_id.len() > MAX_ID_LEN {
+            return Err(anyhow::anyhow!("Invalid device id"));
+        }
+        Ok(())
+    }
+}
+
+impl DeviceAnnouncement {
+    pub fn new(identity: &IdentityKeyPair, device_id: &str, message_key: [u8; 32]) -> Result<Self> {
+        let mut announcement = Self {
+            identity_key: identity.public_key.to_bytes(),
+            device: RemoteDevice {
+                device_id: device_id.to_string(),
+                message_key,
+                announced_at: OffsetDateTime::now_utc(),
+            },
+            capabilities: Capabilities::SUPPORTED,
+            signature: Vec::new(),
+        };
+        announcement.device.validate()?;
+        announcement.signature = identity.sign(&announcement.signing_bytes()?).to_bytes().to_vec();
+        Ok(announcement)
+    }
+    
+    /// Capabilities are signed too; announcements without any keep the
+    /// format from before them, which a stripped one then fails to match
+    fn signing_bytes(&self) -> Result<Vec<u8>> {
+        if self.capabilities.is_empty() {
+            return wire::encode(&(b"SecureChat device v1", &self.identity_key, &self.device));
+        }
+        wire::encode(&(b"SecureChat device v2", &self.identity_key, &self.device, self.capabilities))
+    }
+    
+    pub fn verify(&self) -> Result<()> {
+        self.device.validate()?;
+        let key = VerifyingKey::from_bytes(&self.identity_key).context("Invalid identity key")?;
+        let signature = Signature::from_slice(&self.signature).context("Malformed signature")?;
+        IdentityKeyPair::verify(&key, &self.signing_bytes()?, &signature)
+            .context("Device announcement signature is invalid")
+    }
+}
+
+/// Add or update `device` in a contact's registry. Returns false if the
+/// registry already has a newer announcement from it.
+pub fn register(devices: &mut Vec<RemoteDevice>, device: RemoteDevice) -> bool {
+    match devices.iter_mut().find(|d| d.device_id == device.device_id) {
+        Some(known) if known.announced_at >= device.announced_at => return false,
+        Some(known) => *known = device,
+        None => devices.push(device),
+    }
+    devices.sort_by_key(|device| std::cmp::Reverse(device.announced_at));
+    devices.truncate(MAX_DEVICES_PER_CONTACT);
+    true
+}
+
+impl DeviceSession {
+    pub fn new(message_key: [u8; 32]) -> Self {
+        Self { message_key, chain: 0, next_message: 0, previous_chain_length: 0 }
+    }
+    
+    /// Header of the next message sealed to `message_key`, starting a new
+    /// chain if the device's key changed
+    pub fn next_header(&mut self, message_key: [u8; 32]) -> RatchetHeader {
+        if message_key != self.message_key {
+            self.message_key = message_key;
+            self.chain += 1;
+            self.previous_chain_length = self.next_message;
+            self.next_message = 0;
+        }
+        let header = RatchetHeader {
+            chain: self.chain,
+            previous_chain_length: self.previous_chain_length,
+            message_number: self.next_message,
+        };
+        self.next_message += 1;
+        header
+    }
+}
+
+impl ReceivingSession {
+    /// A session first seen at `header`. Earlier numbers in its chain, at
+    /// most `max_skip` of them, are taken as passed over, as the messages
+    /// may still be on their way.
+    pub fn starting_at(header: &RatchetHeader, max_skip: u32) -> Self {
+        let first = header.message_number.saturating_sub(max_skip);
+        Self {
+            chain: header.chain,
+            next_message: first,
+            skipped: Vec::new(),
+        }
+    }
+    
+    /// Take the number of a message received, as `DoubleRatchet` takes
+    /// its key: a later one passes over those in between, an earlier one
+    /// must be one passed over. Numbers already taken, chains left behind
+    /// and gaps beyond `max_skip` messages or `DoubleRatchet::MAX_CHAIN_SKIP`
+    /// chains are errors, and leave the session as it was.
+    pub fn accept(&mut self, header: &RatchetHeader, max_skip: u32) -> Result<()> {
+        let mut next = self.clone();
+        next.take(header, max_skip)?;
+        *self = next;
+        Ok(())
+    }
+    
+    fn take(&mut self, header: &RatchetHeader, max_skip: u32) -> Result<()> {
+        let number = (header.chain, header.message_number);
+        if let Some(pos) = self.skipped.iter().position(|skipped| *skipped == number) {
+            self.skipped.remove(pos);
+            return Ok(());
+        }
+        if header.chain < self.chain {
+            return Err(anyhow::anyhow!("Message from a chain already left behind"));
+        }
+        if header.chain > self.chain {
+            let steps = header.chain - self.chain;
+            if steps > DoubleRatchet::MAX_CHAIN_SKIP {
+                return Err(LimitError::TooManyChains { count: steps, max: DoubleRatchet::MAX_CHAIN_SKIP }.into());
+            }
+            // The header only tells how long the chain right before it was
+            if steps == 1 {
+                self.skip(header.previous_chain_length, max_skip)?;
+            }
+            self.chain = header.chain;
+            self.next_message = 0;
+        }
+        if header.message_number < self.next_message {
+            return Err(anyhow::anyhow!("Message number already received"));
+        }
+        self.skip(header.message_number, max_skip)?;
+        self.next_message += 1;
+        Ok(())
+    }
+    
+    /// Pass over the numbers up to `until` in the current chain
+    fn skip(&mut self, until: u32, max_skip: u32) -> Result<()> {
+        let count = until.saturating_sub(self.next_message);
+        if count > max_skip {
+            return Err(LimitError::TooManySkipped { count, max: max_skip }.into());
+        }
+        self.skipped.extend((self.next_message..until).map(|number| (self.chain, number)));
+        self.next_message = self.next_message.max(until);
+        let excess = self.skipped.len().saturating_sub(DoubleRatchet::MAX_SKIPPED_KEYS);
+        self.skipped.drain(..excess);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    
+    #[test]
+    fn test_announcements_and_registry() {
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let announcement = DeviceAnnouncement::new(&identity, "laptop", [1; 32]).unwrap();
+        announcement.verify().unwrap();
+        let mut forged = announcement.clone();
+        forged.device.message_key = [2; 32];
+        assert!(forged.verify().is_err());
+        // Features can't be stripped, down to none at all
+        for capabilities in [Capabilities::DEVICE_SESSIONS, Capabilities::default()] {
+            let stripped = DeviceAnnouncement { capabilities, ..announcement.clone() };
+            assert!(stripped.verify().is_err());
+        }
+        assert!(DeviceAnnouncement::new(&identity, "", [1; 32]).is_err());
+        
+        let mut devices = Vec::new();
+        assert!(register(&mut devices, announcement.device.clone()));
+        // A replayed, older announcement doesn't roll the key back
+        assert!(!register(&mut devices, announcement.device.clone()));
+        let newer = RemoteDevice {
+            message_key: [3; 32],
+            announced_at: announcement.device.announced_at + std::time::Duration::from_secs(1),
+            ..announcement.device
+        };
+        assert!(register(&mut devices, newer.clone()));
+        assert_eq!(devices, vec![newer]);
+        
+        let mut session = DeviceSession::new([1; 32]);
+        assert_eq!(session.next_header([1; 32]).message_number, 0);
+        assert_eq!(session.next_header([1; 32]).message_number, 1);
+        let rekeyed = session.next_header([3; 32]);
+        assert_eq!((rekeyed.chain, rekeyed.previous_chain_length, rekeyed.message_number), (1, 2, 0));
+    }
+    
+    #[test]
+    fn test_receiving_session_takes_each_number_once() {
+        let header = |chain, previous_chain_length, message_number| RatchetHeader { chain, previous_chain_length, message_number };
+        let mut received = ReceivingSession::starting_at(&header(0, 0, 2), 10);
+        assert_eq!(received.next_message, 0);
+        received.accept(&header(0, 0, 2), 10).unwrap();
+        assert!(received.accept(&header(0, 0, 2), 10).is_err());
+        // Sent before the first one seen, and still welcome
+        received.accept(&header(0, 0, 0), 10).unwrap();
+        
+        // The rest of a chain stays open after the next one starts
+        received.accept(&header(1, 5, 0), 10).unwrap();
+        assert_eq!(received.skipped, [(0, 1), (0, 3), (0, 4)]);
+        received.accept(&header(0, 0, 4), 10).unwrap();
+        assert!(received.accept(&header(0, 0, 5), 10).is_err());
+        
+        // Gaps past the limits change nothing
+        let before = received.clone();
+        let error = received.accept(&header(1, 0, 12), 10).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(LimitError::TooManySkipped { count: 11, max: 10 })));
+        let error = received.accept(&header(1 + DoubleRatchet::MAX_CHAIN_SKIP + 1, 0, 0), 10).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(LimitError::TooManyChains { .. })));
+        assert_eq!(received, before);
+    }
+    
+    #[test]
+    fn test_capability_transcript() {
+        let ours = Capabilities::SUPPORTED;
+        let theirs = Capabilities(Capabilities::SUPPORTED.0 | 1 << 20);
+        let transcript = CapabilityTranscript { sender: theirs, recipient: ours };
+        assert!(transcript.check(ours, Some(theirs)).is_ok());
+        assert!(transcript.check(ours, None).is_ok());
+        
+        // Either side shown less than was announced
+        let stripped = CapabilityTranscript { recipient: Capabilities::DEVICE_SESSIONS, ..transcript };
+        assert!(stripped.check(ours, Some(theirs)).is_err());
+        assert!(transcript.check(ours, Some(Capabilities::SUPPORTED)).is_err());
+        assert_ne!(transcript.aad().unwrap(), stripped.aad().unwrap());
+    }
+}
@@ -0,0 +1,166 @@
+//! Diagnostic bundles for bug reports.
+//!
+//! A bundle describes the setup a problem happened in, so a user can
+//! attach it to an issue without giving anything away: versions, settings,
+//! the network as the core sees it, counts of what storage holds, the
+//! outcome of the last integrity check and the recent log lines, which the
+//! telemetry subscriber has already redacted. It names no contact, peer or
+//! record, and holds no message content or key.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::conditions::NetworkConditions;
+use crate::durability::Durability;
+use crate::integrity::{IntegrityReport, Problem};
+use crate::network::{NetworkStatus, PeerStats};
+use crate::protocol::PrivacySettings;
+use crate::retention::RetentionPolicy;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiagnosticBundle {
+    pub generated_at: OffsetDateTime,
+    pub version: VersionInfo,
+    pub settings: DiagnosticSettings,
+    pub network: NetworkDiagnostics,
+    pub storage: StorageDiagnostics,
+    /// Latest tracing output, oldest first; empty unless telemetry was set
+    /// up with `SecureChat::init_telemetry`
+    pub logs: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub core: String,
+    pub wire_protocol: u8,
+    pub cipher_suite: String,
+    pub os: String,
+    pub arch: String,
+}
+
+impl VersionInfo {
+    pub fn current() -> Self {
+        Self {
+            core: env!("CARGO_PKG_VERSION").to_string(),
+            wire_protocol: crate::protocol::wire::VERSION,
+            cipher_suite: crate::crypto::MESSAGE_CIPHER_SUITE.to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+        }
+    }
+}
+
+/// Settings that change how the core behaves. Bootstrap nodes and push
+/// endpoints are only counted, as they can point at the user's servers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiagnosticSettings {
+    pub privacy: PrivacySettings,
+    pub durability: Durability,
+    pub conditions: NetworkConditions,
+    pub default_retention: RetentionPolicy,
+    pub contact_request_difficulty: u8,
+    pub bootstrap_nodes: usize,
+    pub push_endpoints: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkDiagnostics {
+    pub status: NetworkStatus,
+    /// Peers seen this run, without their ids
+    pub peers: Vec<PeerDiagnostics>,
+    /// Outgoing messages held back by network conditions
+    pub deferred_messages: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerDiagnostics {
+    pub connected: bool,
+    pub rtt: Option<Duration>,
+    pub last_seen: OffsetDateTime,
+    pub agent_version: Option<String>,
+}
+
+impl From<PeerStats> for PeerDiagnostics {
+    fn from(stats: PeerStats) -> Self {
+        Self {
+            connected: stats.connected,
+            rtt: stats.rtt,
+            last_seen: stats.last_seen,
+            agent_version: stats.agent_version,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageDiagnostics {
+    pub contacts: usize,
+    pub conversations: usize,
+    pub message_requests: usize,
+    pub integrity: IntegritySummary,
+}
+
+/// An integrity report with the record keys, which hold ids, reduced to
+/// counts per record kind
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IntegritySummary {
+    pub records_checked: usize,
+    pub undecryptable: usize,
+    pub malformed: usize,
+    pub dangling: usize,
+    /// Records written in a newer format
+    pub unsupported: usize,
+    pub quarantined: usize,
+    /// Problems by the key prefix of the record, such as `m:`
+    pub problem_kinds: BTreeMap<String, usize>,
+}
+
+impl From<&IntegrityReport> for IntegritySummary {
+    fn from(report: &IntegrityReport) -> Self {
+        let mut summary = Self {
+            records_checked: report.records_checked,
+            quarantined: report.quarantined,
+            ..Default::default()
+        };
+        for problem in &report.problems {
+            match problem.problem {
+                Problem::Undecryptable => summary.undecryptable += 1,
+                Problem::Malformed { .. } => summary.malformed += 1,
+                Problem::Dangling { .. } => summary.dangling += 1,
+                Problem::UnsupportedFormat { .. } => summary.unsupported += 1,
+            }
+            let kind = match problem.key.find(':') {
+                Some(end) => &problem.key[..=end],
+                None => "other",
+            };
+            *summary.problem_kinds.entry(kind.to_string()).or_default() += 1;
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrity::RecordProblem;
+
+    #[test]
+    fn test_integrity_summary() {
+        let report = IntegrityReport {
+            records_checked: 10,
+            problems: vec![
+                RecordProblem { key: "m:conv:msg".to_string(), problem: Problem::Undecryptable },
+                RecordProblem { key: "m:conv:other".to_string(), problem: Problem::Dangling { missing: "cv:conv".to_string() } },
+                RecordProblem { key: "c:contact".to_string(), problem: Problem::Malformed { error: "bad".to_string() } },
+            ],
+            quarantined: 1,
+        };
+        let summary = IntegritySummary::from(&report);
+        assert_eq!((summary.undecryptable, summary.malformed, summary.dangling), (1, 1, 1));
+        assert_eq!(summary.problem_kinds.get("m:"), Some(&2));
+        assert_eq!(summary.problem_kinds.get("c:"), Some(&1));
+        assert!(!serde_json::to_string(&summary).unwrap().contains("contact"));
+    }
+}
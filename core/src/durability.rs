@@ -0,0 +1,138 @@
+//! Durability controls.
+//!
+//! sled keeps writes in memory until flushed. A background flusher owned
+//! by `SecureStorage` flushes every `flush_every_ms`; messages sent and
+//! received can also be flushed as they are written, before a send is
+//! reported or a receipt acknowledged. `SecureChat::flush` writes
+//! everything out at once, e.g. before the app is suspended.
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+/// Whether flushes made for a message wait for the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FsyncPolicy {
+    /// The write isn't reported until it is on disk
+    Always,
+    /// The background flusher is woken and the write reported at once;
+    /// a crash right after can lose it
+    Background,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Durability {
+    /// Interval of the background flusher; `None` leaves it to message
+    /// writes and explicit flushes
+    pub flush_every_ms: Option<u64>,
+    /// Flush whenever a message is sent or received
+    pub flush_on_message: bool,
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Self {
+            flush_every_ms: Some(500),
+            flush_on_message: true,
+            fsync: FsyncPolicy::Always,
+        }
+    }
+}
+
+impl Durability {
+    pub fn validate(&self) -> Result<()> {
+        if self.flush_every_ms == Some(0) {
+            return Err(anyhow::anyhow!("Flush interval must be positive"));
+        }
+        Ok(())
+    }
+    
+    fn interval(&self) -> Option<Duration> {
+        self.flush_every_ms.map(Duration::from_millis)
+    }
+}
+
+/// Flushes a database every interval and whenever woken, until dropped
+pub(crate) struct Flusher {
+    settings: Durability,
+    wake: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    /// When a flush last succeeded, by this flusher, one it replaced or
+    /// an explicit flush
+    last_flush: Arc<Mutex<Option<OffsetDateTime>>>,
+}
+
+impl Flusher {
+    pub(crate) fn start(db: sled::Db, settings: Durability) -> Result<Self> {
+        Self::spawn(db, settings, Arc::default())
+    }
+    
+    /// A flusher with new settings taking over from `previous`
+    pub(crate) fn replace(db: sled::Db, settings: Durability, previous: &Self) -> Result<Self> {
+        Self::spawn(db, settings, previous.last_flush.clone())
+    }
+    
+    fn spawn(db: sled::Db, settings: Durability, last_flush: Arc<Mutex<Option<OffsetDateTime>>>) -> Result<Self> {
+        let every = settings.interval();
+        let (wake, woken) = mpsc::channel();
+        let flushed = last_flush.clone();
+        let thread = std::thread::Builder::new()
+            .name("storage-flusher".to_string())
+            .spawn(move || loop {
+                let next = match every {
+                    Some(every) => woken.recv_timeout(every),
+                    None => woken.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                if next == Err(RecvTimeoutError::Disconnected) {
+                    break;
+                }
+                match db.flush() {
+                    Ok(_) => record(&flushed),
+                    Err(e) => tracing::warn!(error = %e, "Background flush failed"),
+                }
+            })
+            .context("Failed to start storage flusher")?;
+        Ok(Self { settings, wake: Some(wake), thread: Some(thread), last_flush })
+    }
+    
+    pub(crate) fn settings(&self) -> Durability {
+        self.settings
+    }
+    
+    /// Flush soon without waiting for it
+    pub(crate) fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            wake.send(()).ok();
+        }
+    }
+    
+    /// Note a flush made outside the flusher
+    pub(crate) fn flushed(&self) {
+        record(&self.last_flush);
+    }
+    
+    pub(crate) fn last_flush(&self) -> Option<OffsetDateTime> {
+        *self.last_flush.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn record(last_flush: &Mutex<Option<OffsetDateTime>>) {
+    *last_flush.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(OffsetDateTime::now_utc());
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread, which must let go of the
+        // database before it can be opened again
+        self.wake = None;
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
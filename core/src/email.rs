@@ -0,0 +1,223 @@
+//! Email transport, behind the `email` feature.
+//!
+//! For peers who can never hold a connection to each other: each frame
+//! is mailed to the peer over SMTP as a MIME attachment of type
+//! `application/vnd.securechat.frame`, and frames from the peer are
+//! fetched by polling an IMAP mailbox, then deleted there. The frames hold
+//! envelopes already sealed with our own keys, so mail servers only see
+//! who writes to whom and when, never what. Run it with
+//! `transport::MessageBridge`, with a poll interval mail servers won't
+//! mind.
+
+use std::time::Duration;
+
+use anyhow::{Result, Context};
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::{Serialize, Deserialize};
+
+use crate::transport::MessageTransport;
+
+/// MIME type of the part holding a frame
+pub const FRAME_CONTENT_TYPE: &str = "application/vnd.securechat.frame";
+/// Subject of every frame mail, used to find them in the mailbox
+pub const SUBJECT: &str = "SecureChat message";
+/// Most mails fetched by one poll
+const FETCH_LIMIT: usize = 100;
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EmailConfig {
+    /// Our address, which frames are sent from and arrive at
+    pub address: String,
+    /// The peer's address, which frames are sent to and accepted from
+    pub peer_address: String,
+    /// Submission server, reached with STARTTLS
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    /// Reached over TLS
+    pub imap_host: String,
+    pub imap_port: u16,
+    pub mailbox: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for EmailConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmailConfig")
+            .field("address", &self.address)
+            .field("peer_address", &self.peer_address)
+            .field("smtp_host", &self.smtp_host)
+            .field("smtp_port", &self.smtp_port)
+            .field("imap_host", &self.imap_host)
+            .field("imap_port", &self.imap_port)
+            .field("mailbox", &self.mailbox)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+impl EmailConfig {
+    /// Settings for a provider that uses the usual ports and an INBOX
+    pub fn new(address: &str, peer_address: &str, smtp_host: &str, imap_host: &str, username: &str, password: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            peer_address: peer_address.to_string(),
+            smtp_host: smtp_host.to_string(),
+            smtp_port: 587,
+            imap_host: imap_host.to_string(),
+            imap_port: 993,
+            mailbox: "INBOX".to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for address in [&self.address, &self.peer_address] {
+            address.parse::<Mailbox>()
+                .with_context(|| format!("Invalid email address: {}", address))?;
+        }
+        // Both end up quoted in IMAP commands
+        let quotable = |s: &str| !s.is_empty() && !s.chars().any(|c| c == '"' || c == '\\' || c.is_control());
+        if !quotable(&self.peer_address) || !quotable(&self.mailbox) {
+            return Err(anyhow::anyhow!("Peer address and mailbox can't hold quotes or control characters"));
+        }
+        Ok(())
+    }
+}
+
+/// Carries frames as mail between two addresses
+pub struct EmailTransport {
+    config: EmailConfig,
+    smtp: SmtpTransport,
+    from: Mailbox,
+    to: Mailbox,
+}
+
+impl EmailTransport {
+    pub fn new(config: EmailConfig) -> Result<Self> {
+        config.validate()?;
+        let smtp = SmtpTransport::starttls_relay(&config.smtp_host)
+            .context("Invalid SMTP server")?
+            .port(config.smtp_port)
+            .credentials(Credentials::new(config.username.clone(), config.password.clone()))
+            .timeout(Some(SMTP_TIMEOUT))
+            .build();
+        Ok(Self {
+            from: config.address.parse()?,
+            to: config.peer_address.parse()?,
+            smtp,
+            config,
+        })
+    }
+}
+
+impl MessageTransport for EmailTransport {
+    fn name(&self) -> &str {
+        "email"
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        let content_type = ContentType::parse(FRAME_CONTENT_TYPE)
+            .map_err(|e| anyhow::anyhow!("Invalid content type: {}", e))?;
+        let mail = Message::builder()
+            .from(self.from.clone())
+            .to(self.to.clone())
+            .subject(SUBJECT)
+            .singlepart(Attachment::new("frame.bin".to_string()).body(frame.to_vec(), content_type))
+            .context("Failed to build mail")?;
+        self.smtp.send(&mail).context("Failed to send mail")?;
+        Ok(())
+    }
+
+    fn receive(&self) -> Result<Vec<Vec<u8>>> {
+        let tls = native_tls::TlsConnector::new().context("Failed to set up TLS")?;
+        let client = imap::connect((self.config.imap_host.as_str(), self.config.imap_port), &self.config.imap_host, &tls)
+            .context("Failed to reach IMAP server")?;
+        let mut session = client.login(&self.config.username, &self.config.password)
+            .map_err(|(e, _)| e)
+            .context("IMAP login failed")?;
+        session.select(&self.config.mailbox)
+            .context("Failed to open mailbox")?;
+
+        let mut uids: Vec<u32> = session.uid_search(format!("FROM \"{}\" SUBJECT \"{}\"", self.config.peer_address, SUBJECT))
+            .context("IMAP search failed")?
+            .into_iter()
+            .collect();
+        uids.sort_unstable();
+        uids.truncate(FETCH_LIMIT);
+        if uids.is_empty() {
+            session.logout().ok();
+            return Ok(Vec::new());
+        }
+        let set = uids.iter().map(u32::to_string).collect::<Vec<_>>().join(",");
+
+        let fetched = session.uid_fetch(&set, "RFC822").context("IMAP fetch failed")?;
+        let mut mails: Vec<_> = fetched.iter()
+            .filter_map(|mail| Some((mail.uid?, mail.body()?)))
+            .collect();
+        mails.sort_by_key(|(uid, _)| *uid);
+        let frames = mails.into_iter()
+            .filter_map(|(uid, body)| match frame_of(body) {
+                Ok(frame) => Some(frame),
+                Err(e) => {
+                    tracing::debug!(uid, error = %e, "Ignoring mail without a frame");
+                    None
+                }
+            })
+            .collect();
+
+        // Only once read, so a failed poll leaves them for the next
+        session.uid_store(&set, "+FLAGS (\\Deleted)").context("Failed to mark mail deleted")?;
+        session.expunge().context("Failed to delete mail")?;
+        session.logout().ok();
+        Ok(frames)
+    }
+}
+
+/// The frame attached to a raw mail
+fn frame_of(raw: &[u8]) -> Result<Vec<u8>> {
+    fn find(part: &mailparse::ParsedMail) -> Option<Vec<u8>> {
+        if part.ctype.mimetype.eq_ignore_ascii_case(FRAME_CONTENT_TYPE) {
+            return part.get_body_raw().ok();
+        }
+        part.subparts.iter().find_map(find)
+    }
+    let mail = mailparse::parse_mail(raw).context("Malformed mail")?;
+    find(&mail).ok_or_else(|| anyhow::anyhow!("No frame attached"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> EmailConfig {
+        EmailConfig::new("alice@example.org", "bob@example.net", "smtp.example.org", "imap.example.org", "alice", "secret")
+    }
+
+    #[test]
+    fn test_config() {
+        assert!(config().validate().is_ok());
+        assert!(!format!("{:?}", config()).contains("secret"));
+        assert!(EmailConfig { peer_address: "not an address".to_string(), ..config() }.validate().is_err());
+        assert!(EmailConfig { mailbox: "IN\"BOX".to_string(), ..config() }.validate().is_err());
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let content_type = ContentType::parse(FRAME_CONTENT_TYPE).unwrap();
+        let mail = Message::builder()
+            .from("alice@example.org".parse().unwrap())
+            .to("bob@example.net".parse().unwrap())
+            .subject(SUBJECT)
+            .singlepart(Attachment::new("frame.bin".to_string()).body(vec![0u8, 1, 2, 255], content_type))
+            .unwrap();
+        assert_eq!(frame_of(&mail.formatted()).unwrap(), vec![0u8, 1, 2, 255]);
+        assert!(frame_of(b"Subject: hi\r\n\r\nplain text").is_err());
+    }
+}
@@ -0,0 +1,307 @@
+//! Handing the backup key to another of our own devices.
+//!
+//! Account backups are wrapped with the backup subkey of the account that
+//! made them, so another of our devices could only open them with that
+//! account's password. Instead, the device that made them offers its
+//! backup key in a QR code, and the device that scans it keeps the key as
+//! an `EscrowedBackupKey` to open those backups with.
+//!
+//! The key itself is never shown. The QR code carries it sealed under a
+//! random transfer key, which is wrapped with Argon2id under a
+//! confirmation code the offering device displays beside the QR code and
+//! the user types on the other one. A photo of the QR code alone is not
+//! enough: the code has 50 bits, too many to guess through Argon2id. The
+//! offer expires after a few minutes, and it is signed with the identity
+//! key so that only a device of the same account takes it, and no one can
+//! slip us a key of their own.
+//!
+//! Offers have the form `securechat://backup-key#<base64url(CBOR)>`.
+
+use std::time::Duration;
+
+use anyhow::{Result, Context};
+use base64::Engine;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use ed25519_dalek::{Signature, VerifyingKey};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+use zeroize::Zeroize;
+
+use crate::crypto::{IdentityKeyPair, MasterKey};
+use crate::protocol::wire;
+
+pub const LINK_PREFIX: &str = "securechat://backup-key#";
+
+/// How long an offer stays valid unless asked otherwise
+pub const DEFAULT_VALIDITY: Duration = Duration::from_secs(5 * 60);
+
+/// Longest an offer may stay valid
+pub const MAX_VALIDITY: Duration = Duration::from_secs(60 * 60);
+
+/// Characters in a confirmation code, 5 bits each
+pub const CODE_LEN: usize = 10;
+
+/// Longest device name carried in an offer
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Longest link `parse` accepts; real offers are a few hundred bytes
+pub const MAX_LINK_LEN: usize = 1024;
+
+/// No 0, 1, I or O, which are easily mistaken for one another
+const CODE_ALPHABET: &[u8; 32] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+const KEY_ID_CONTEXT: &str = "SecureChat backup key id v1";
+
+/// A backup key sealed for another of our devices, shown as a QR code
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupKeyOffer {
+    pub identity_key: [u8; 32],
+    /// Fingerprint of the key offered, see `key_id`
+    pub key_id: String,
+    /// Name of the offering device
+    pub device_name: String,
+    pub expires_at: OffsetDateTime,
+    /// The transfer key, wrapped with the confirmation code
+    transfer_key: MasterKey,
+    nonce: [u8; 24],
+    /// The backup key, sealed under the transfer key
+    sealed_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// What is shown about a backup key received from another device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupKeyInfo {
+    pub key_id: String,
+    /// Device the key came from
+    pub device_name: String,
+    pub received_at: OffsetDateTime,
+}
+
+/// A backup key received from another of our devices, which opens the
+/// account backups it made
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EscrowedBackupKey {
+    pub info: BackupKeyInfo,
+    key: [u8; 32],
+}
+
+impl Drop for EscrowedBackupKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl std::fmt::Debug for EscrowedBackupKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EscrowedBackupKey")
+            .field("info", &self.info)
+            .field("key", &"[REDACTED]")
+            .finish()
+    }
+}
+
+impl EscrowedBackupKey {
+    pub(crate) fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+}
+
+/// Fingerprint of a backup key, telling keys apart without revealing them
+pub fn key_id(backup_key: &[u8; 32]) -> String {
+    let hash = blake3::derive_key(KEY_ID_CONTEXT, backup_key);
+    hash[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl BackupKeyOffer {
+    /// Offer `backup_key`, valid for `valid_for`. Returns the offer with the
+    /// confirmation code to display beside it.
+    pub fn new(
+        identity: &IdentityKeyPair,
+        backup_key: &[u8; 32],
+        device_name: &str,
+        valid_for: Duration,
+    ) -> Result<(Self, String)> {
+        if valid_for.is_zero() || valid_for > MAX_VALIDITY {
+            return Err(anyhow::anyhow!("Backup key offers are valid for up to {} minutes", MAX_VALIDITY.as_secs() / 60));
+        }
+        let mut rng = rand::thread_rng();
+        let code: String = (0..CODE_LEN)
+            .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+            .collect();
+        let mut transfer_key: [u8; 32] = rng.gen();
+
+        let mut offer = Self {
+            identity_key: identity.public_key.to_bytes(),
+            key_id: key_id(backup_key),
+            device_name: device_name.chars().take(MAX_NAME_LEN).collect(),
+            expires_at: OffsetDateTime::now_utc() + valid_for,
+            transfer_key: MasterKey::wrap(&code, &transfer_key, &mut rng)
+                .context("Failed to wrap transfer key")?,
+            nonce: [0u8; 24],
+            sealed_key: Vec::new(),
+            signature: Vec::new(),
+        };
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = XChaCha20Poly1305::new((&transfer_key).into())
+            .encrypt(&nonce, Payload { msg: backup_key, aad: &offer.associated_data()? });
+        transfer_key.zeroize();
+        offer.sealed_key = sealed.map_err(|e| anyhow::anyhow!("Failed to seal backup key: {:?}", e))?;
+        offer.nonce = nonce.into();
+        offer.signature = identity.sign(&offer.signing_bytes()?).to_bytes().to_vec();
+
+        Ok((offer, format_code(&code)))
+    }
+
+    /// Binds the sealed key to the offer it was made for
+    fn associated_data(&self) -> Result<Vec<u8>> {
+        wire::encode(&(
+            b"SecureChat backup key v1",
+            &self.identity_key,
+            &self.key_id,
+            &self.device_name,
+            &self.expires_at,
+        ))
+    }
+
+    fn signing_bytes(&self) -> Result<Vec<u8>> {
+        wire::encode(&(
+            b"SecureChat backup key offer v1",
+            &self.associated_data()?,
+            &self.transfer_key,
+            &self.nonce,
+            &self.sealed_key,
+        ))
+    }
+
+    /// Check the signature and that the offer hasn't expired at `now`
+    pub fn verify(&self, now: OffsetDateTime) -> Result<()> {
+        if self.device_name.chars().count() > MAX_NAME_LEN {
+            return Err(anyhow::anyhow!("Device name too long"));
+        }
+        let key = VerifyingKey::from_bytes(&self.identity_key)
+            .context("Invalid identity key")?;
+        let signature = Signature::from_slice(&self.signature)
+            .context("Malformed signature")?;
+        IdentityKeyPair::verify(&key, &self.signing_bytes()?, &signature)
+            .context("Backup key offer signature is invalid")?;
+        if now >= self.expires_at {
+            return Err(anyhow::anyhow!("Backup key offer has expired"));
+        }
+        Ok(())
+    }
+
+    pub fn to_link(&self) -> Result<String> {
+        let payload = wire::encode(self)?;
+        Ok(format!("{}{}", LINK_PREFIX, base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(payload)))
+    }
+
+    /// Parse and verify a link, or the bare payload as read from a QR code
+    pub fn parse(link: &str, now: OffsetDateTime) -> Result<Self> {
+        let link = link.trim();
+        if link.len() > MAX_LINK_LEN {
+            return Err(anyhow::anyhow!("Backup key link too long"));
+        }
+        let encoded = link.strip_prefix(LINK_PREFIX).unwrap_or(link);
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(encoded)
+            .context("Not a backup key link")?;
+        let offer: Self = wire::decode(&payload).context("Backup key offer is corrupt")?;
+        offer.verify(now)?;
+        Ok(offer)
+    }
+
+    /// Open the offer with the confirmation code shown beside it
+    pub fn open(&self, code: &str) -> Result<EscrowedBackupKey> {
+        let code = normalize_code(code)?;
+        let mut transfer_key = self.transfer_key.unlock(&code)
+            .map_err(|_| anyhow::anyhow!("Wrong confirmation code"))?;
+        let opened = XChaCha20Poly1305::new((&transfer_key).into())
+            .decrypt(XNonce::from_slice(&self.nonce), Payload { msg: &self.sealed_key, aad: &self.associated_data()? });
+        transfer_key.zeroize();
+        let mut opened = opened.map_err(|_| anyhow::anyhow!("Backup key offer is corrupt"))?;
+        let key: Result<[u8; 32]> = opened.as_slice().try_into()
+            .map_err(|_| anyhow::anyhow!("Backup key has wrong length"));
+        opened.zeroize();
+
+        let escrowed = EscrowedBackupKey {
+            info: BackupKeyInfo {
+                key_id: self.key_id.clone(),
+                device_name: self.device_name.clone(),
+                received_at: OffsetDateTime::now_utc(),
+            },
+            key: key?,
+        };
+        if key_id(&escrowed.key) != self.key_id {
+            return Err(anyhow::anyhow!("Backup key doesn't match its fingerprint"));
+        }
+        Ok(escrowed)
+    }
+}
+
+/// `ABCDE-FGHJK`, easier to read off a screen
+fn format_code(code: &str) -> String {
+    let (first, second) = code.split_at(CODE_LEN / 2);
+    format!("{}-{}", first, second)
+}
+
+/// The code as generated, from what the user typed: case, dashes and
+/// spaces don't matter
+fn normalize_code(code: &str) -> Result<String> {
+    let code: String = code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if code.len() != CODE_LEN || !code.bytes().all(|b| CODE_ALPHABET.contains(&b)) {
+        return Err(anyhow::anyhow!("Confirmation codes are {} letters and digits", CODE_LEN));
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offer_roundtrip() {
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let backup_key = [7u8; 32];
+        let (offer, code) = BackupKeyOffer::new(&identity, &backup_key, "Desktop", DEFAULT_VALIDITY).unwrap();
+        let link = offer.to_link().unwrap();
+        // Neither the link nor the offer shows the key
+        assert!(!format!("{:?}", offer).contains(&format!("{:?}", backup_key)));
+
+        let now = OffsetDateTime::now_utc();
+        let parsed = BackupKeyOffer::parse(&link, now).unwrap();
+        assert_eq!(parsed.key_id, key_id(&backup_key));
+        assert!(parsed.open("23456-789AB").is_err());
+        assert!(parsed.open("12345").is_err());
+        let escrowed = parsed.open(&code.to_lowercase().replace('-', " ")).unwrap();
+        assert_eq!(escrowed.key(), &backup_key);
+        assert_eq!(escrowed.info.device_name, "Desktop");
+        assert!(!format!("{:?}", escrowed).contains("7, 7"));
+
+        assert!(BackupKeyOffer::parse(&link, offer.expires_at).is_err());
+    }
+
+    #[test]
+    fn test_tampered_offer_is_refused() {
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let (offer, _) = BackupKeyOffer::new(&identity, &[7u8; 32], "Desktop", DEFAULT_VALIDITY).unwrap();
+        let now = OffsetDateTime::now_utc();
+
+        let mut renamed = offer.clone();
+        renamed.device_name = "Someone else".to_string();
+        assert!(BackupKeyOffer::parse(&renamed.to_link().unwrap(), now).is_err());
+
+        // A key of someone else's can't pass as ours
+        let mut resigned = offer;
+        resigned.identity_key = [1u8; 32];
+        assert!(BackupKeyOffer::parse(&resigned.to_link().unwrap(), now).is_err());
+
+        assert!(BackupKeyOffer::new(&identity, &[7u8; 32], "Desktop", MAX_VALIDITY * 2).is_err());
+    }
+}
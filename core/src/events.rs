@@ -0,0 +1,499 @@
+//! The event bus.
+//!
+//! Every `ChatEvent` the core reports is numbered and kept in a replay
+//! buffer of the last `REPLAY_CAPACITY` events before it is handed out.
+//! A client that attaches late, such as a frontend whose listener comes up
+//! after unlock, catches up with `SecureChat::subscribe_from` or
+//! `SecureChat::replay_events` instead of missing what happened meanwhile.
+//!
+//! Reporting an event never waits for a subscriber. Each has a queue of
+//! up to `SUBSCRIPTION_CAPACITY` events; when a slow one's is full, the
+//! oldest event of the lowest `EventPriority` is dropped to make room,
+//! and the subscriber gets an `EventsDropped` with the count before its
+//! next event. Security events are never dropped. Peers can make us
+//! report them at will, so once a queue holds `SECURITY_QUEUE_LIMIT` the
+//! oldest are folded into counts by kind and contact instead, and the
+//! subscriber gets a `SecurityEventsCoalesced` for each count before its
+//! next event. Counts are kept for at most `MAX_COALESCED_SUBJECTS`
+//! contacts; past that a kind's events are counted for any contact.
+//!
+//! Delivery is at least once for a client that catches up after a drop:
+//! a subscription receives each event it isn't told it lost once and in
+//! order, and what it lost can be replayed from the sequence number after
+//! the last one it handled while still buffered. Replayed events may
+//! repeat ones already seen; sequence numbers tell them apart. Events
+//! older than the buffer are gone, which `missed` reports.
+//!
+//! The receiver from `SecureChat::start_network` is fed from a
+//! subscription the same way, without sequence numbers.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{mpsc, Notify};
+
+use crate::ChatEvent;
+
+/// Events kept for replay
+pub const REPLAY_CAPACITY: usize = 1024;
+/// Events a subscriber may fall behind by before some are dropped
+pub const SUBSCRIPTION_CAPACITY: usize = 256;
+/// Events a subscriber may fall behind by when all are security events;
+/// older ones are coalesced past it
+pub const SECURITY_QUEUE_LIMIT: usize = 4 * SUBSCRIPTION_CAPACITY;
+/// Kind and contact pairs coalesced security events are counted under
+/// separately, per subscriber
+pub const MAX_COALESCED_SUBJECTS: usize = 64;
+/// Events the `start_network` receiver holds on top of its subscription
+const LISTENER_CHANNEL: usize = 100;
+
+/// Sequence number of an `EventsDropped` notice, which is about one
+/// subscription rather than a bus event and is never replayed
+pub const NOTICE_SEQ: u64 = 0;
+
+/// What a full queue gives up first, lowest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EventPriority {
+    /// Progress and presence, soon out of date anyway
+    Low,
+    Normal,
+    /// Never dropped, only coalesced past `SECURITY_QUEUE_LIMIT`
+    Security,
+}
+
+impl ChatEvent {
+    pub fn priority(&self) -> EventPriority {
+        match self {
+            Self::DecryptionFailed { .. }
+            | Self::ContactRequestReceived { .. }
+            | Self::ContactDevicesChanged { .. }
+            | Self::PairingCompleted { .. }
+            | Self::PairingFailed { .. }
+            | Self::ConversationPolicyRequested { .. }
+            | Self::SessionDowngradeRefused { .. }
+            | Self::CapabilityMismatch { .. }
+            | Self::SecurityEventsCoalesced { .. }
+            | Self::EventsDropped { .. } => EventPriority::Security,
+            Self::MessageReceived { .. }
+            | Self::MessageStatusChanged { .. }
+            | Self::MessageRequestReceived { .. }
+            | Self::SyncCompleted
+            | Self::BackupCompleted { .. }
+            | Self::BackupFailed { .. }
+            | Self::OperationCancelled { .. }
+            | Self::Error { .. } => EventPriority::Normal,
+            Self::ContactOnline { .. }
+            | Self::ContactOffline { .. }
+            | Self::ContactUnreachable { .. }
+            | Self::NetworkStatusChanged { .. }
+            | Self::MessagesPruned { .. }
+            | Self::CompactionProgress { .. }
+            | Self::GarbageCollected { .. }
+            | Self::KeyMaterialCleaned { .. }
+            | Self::UnlockProgress { .. }
+            | Self::OperationProgress { .. } => EventPriority::Low,
+        }
+    }
+}
+
+impl ChatEvent {
+    /// Kind of a security event, and the contact or conversation it is
+    /// about, which coalesced events are counted under
+    fn subject(&self) -> (&'static str, Option<&str>) {
+        match self {
+            Self::DecryptionFailed { contact_id, .. } => ("decryption_failed", Some(contact_id)),
+            Self::ContactRequestReceived { contact_id, .. } => ("contact_request_received", Some(contact_id)),
+            Self::ContactDevicesChanged { contact_id } => ("contact_devices_changed", Some(contact_id)),
+            Self::PairingCompleted { contact } => ("pairing_completed", Some(&contact.id)),
+            Self::PairingFailed { .. } => ("pairing_failed", None),
+            Self::ConversationPolicyRequested { conversation_id, .. } => ("conversation_policy_requested", Some(conversation_id)),
+            Self::SessionDowngradeRefused { contact_id, .. } => ("session_downgrade_refused", Some(contact_id)),
+            Self::CapabilityMismatch { contact_id, .. } => ("capability_mismatch", Some(contact_id)),
+            _ => ("other", None),
+        }
+    }
+}
+
+/// An event with its place in the order of all events, counted from 1
+#[derive(Debug, Clone)]
+pub struct SequencedEvent {
+    pub seq: u64,
+    pub event: ChatEvent,
+}
+
+/// Events from a sequence number on, as far as still buffered
+#[derive(Debug, Clone)]
+pub struct EventReplay {
+    /// Events asked for that are no longer buffered
+    pub missed: u64,
+    pub events: Vec<SequencedEvent>,
+}
+
+/// Buffered events from the sequence number asked for, then each new one
+/// as it happens, until dropped
+#[derive(Debug)]
+pub struct EventSubscription {
+    /// Events asked for that were no longer buffered
+    pub missed: u64,
+    queue: Arc<Queue>,
+}
+
+impl EventSubscription {
+    /// The next event, or `None` once the subscription was closed. An
+    /// `EventsDropped` comes with `NOTICE_SEQ`.
+    pub async fn recv(&mut self) -> Option<SequencedEvent> {
+        self.queue.pop().await
+    }
+}
+
+impl Drop for EventSubscription {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+/// One subscriber's events, waiting to be received
+#[derive(Debug)]
+struct Queue {
+    state: Mutex<QueueState>,
+    ready: Notify,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    events: VecDeque<SequencedEvent>,
+    /// Dropped since the last notice
+    dropped: u64,
+    /// Security events taken out of the queue to be reported as counts,
+    /// oldest first; all of them older than those still queued
+    coalesced: Vec<Coalesced>,
+    closed: bool,
+}
+
+/// Security events of one kind about one subject, or any once there are
+/// too many subjects
+#[derive(Debug)]
+struct Coalesced {
+    kind: &'static str,
+    subject: Option<String>,
+    count: u64,
+    first_seq: u64,
+    last_seq: u64,
+}
+
+impl QueueState {
+    fn coalesce(&mut self, event: SequencedEvent) {
+        let (kind, subject) = event.event.subject();
+        let subject = subject.filter(|subject| {
+            self.coalesced.iter().any(|c| c.kind == kind && c.subject.as_deref() == Some(*subject))
+                || self.coalesced.len() < MAX_COALESCED_SUBJECTS
+        });
+        match self.coalesced.iter_mut().find(|c| c.kind == kind && c.subject.as_deref() == subject) {
+            Some(coalesced) => {
+                coalesced.count += 1;
+                coalesced.last_seq = event.seq;
+            }
+            None => self.coalesced.push(Coalesced {
+                kind,
+                subject: subject.map(str::to_string),
+                count: 1,
+                first_seq: event.seq,
+                last_seq: event.seq,
+            }),
+        }
+    }
+}
+
+impl Queue {
+    fn new(backlog: Vec<SequencedEvent>) -> Self {
+        Self {
+            state: Mutex::new(QueueState { events: backlog.into(), ..Default::default() }),
+            ready: Notify::new(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queue `event`, dropping one if full; false once closed
+    fn push(&self, event: SequencedEvent) -> bool {
+        let mut state = self.state();
+        if state.closed {
+            return false;
+        }
+        if state.events.len() >= SUBSCRIPTION_CAPACITY {
+            // The oldest of the lowest priority, the new event included
+            let priority = event.event.priority();
+            let victim = state.events.iter()
+                .enumerate()
+                .map(|(i, queued)| (queued.event.priority(), i))
+                .filter(|(priority, _)| *priority < EventPriority::Security)
+                .min();
+            match victim {
+                Some((lowest, i)) if lowest <= priority => {
+                    state.events.remove(i);
+                    state.dropped += 1;
+                }
+                _ if priority < EventPriority::Security => {
+                    state.dropped += 1;
+                    return true;
+                }
+                // Only security events left; they may go past the
+                // capacity, up to the limit, and are counted beyond it
+                _ if state.events.len() >= SECURITY_QUEUE_LIMIT => {
+                    let oldest = state.events.pop_front().expect("queue is full");
+                    state.coalesce(oldest);
+                }
+                _ => {}
+            }
+        }
+        state.events.push_back(event);
+        drop(state);
+        self.ready.notify_one();
+        true
+    }
+
+    async fn pop(&self) -> Option<SequencedEvent> {
+        loop {
+            {
+                let mut state = self.state();
+                if state.dropped > 0 {
+                    let count = std::mem::take(&mut state.dropped);
+                    return Some(SequencedEvent { seq: NOTICE_SEQ, event: ChatEvent::EventsDropped { count } });
+                }
+                if !state.coalesced.is_empty() {
+                    let coalesced = state.coalesced.remove(0);
+                    return Some(SequencedEvent {
+                        seq: NOTICE_SEQ,
+                        event: ChatEvent::SecurityEventsCoalesced {
+                            kind: coalesced.kind.to_string(),
+                            subject: coalesced.subject,
+                            count: coalesced.count,
+                            first_seq: coalesced.first_seq,
+                            last_seq: coalesced.last_seq,
+                        },
+                    });
+                }
+                if let Some(event) = state.events.pop_front() {
+                    return Some(event);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    fn close(&self) {
+        self.state().closed = true;
+        self.ready.notify_one();
+    }
+}
+
+/// Numbers, buffers and hands out the core's events
+#[derive(Default)]
+pub(crate) struct EventBus {
+    state: Mutex<BusState>,
+}
+
+#[derive(Default)]
+struct BusState {
+    last_seq: u64,
+    buffer: VecDeque<SequencedEvent>,
+    subscribers: Vec<Arc<Queue>>,
+    /// The subscription feeding the `start_network` receiver
+    listener: Option<Arc<Queue>>,
+}
+
+impl EventBus {
+    fn state(&self) -> std::sync::MutexGuard<'_, BusState> {
+        // Nothing is left half-done under the lock
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Report `events` to every subscriber
+    pub(crate) fn publish(&self, events: Vec<ChatEvent>) {
+        let mut state = self.state();
+        for event in events {
+            state.last_seq += 1;
+            let event = SequencedEvent { seq: state.last_seq, event };
+            state.subscribers.retain(|queue| queue.push(event.clone()));
+            if state.buffer.len() == REPLAY_CAPACITY {
+                state.buffer.pop_front();
+            }
+            state.buffer.push_back(event);
+        }
+    }
+
+    pub(crate) fn last_seq(&self) -> u64 {
+        self.state().last_seq
+    }
+
+    pub(crate) fn replay(&self, from: u64) -> EventReplay {
+        let state = self.state();
+        let (missed, events) = state.buffered_from(from);
+        EventReplay { missed, events: events.cloned().collect() }
+    }
+
+    pub(crate) fn subscribe_from(&self, from: u64) -> EventSubscription {
+        let mut state = self.state();
+        let (missed, backlog) = state.buffered_from(from);
+        let queue = Arc::new(Queue::new(backlog.cloned().collect()));
+        // Under the same lock, so nothing is published between the
+        // backlog and the first live event
+        state.subscribers.push(queue.clone());
+        EventSubscription { missed, queue }
+    }
+
+    /// A receiver of every event from `from` on, replacing the last one
+    pub(crate) fn attach_listener(&self, from: u64) -> mpsc::Receiver<ChatEvent> {
+        let mut subscription = self.subscribe_from(from);
+        if let Some(previous) = self.state().listener.replace(subscription.queue.clone()) {
+            previous.close();
+        }
+        let (sender, receiver) = mpsc::channel(LISTENER_CHANNEL);
+        tokio::spawn(async move {
+            while let Some(sequenced) = subscription.recv().await {
+                if sender.send(sequenced.event).await.is_err() {
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+
+    /// End the `start_network` receiver once it has what was queued
+    pub(crate) fn detach_listener(&self) {
+        if let Some(listener) = self.state().listener.take() {
+            listener.close();
+        }
+    }
+
+    pub(crate) fn has_listener(&self) -> bool {
+        self.state().listener.as_ref().is_some_and(|listener| !listener.state().closed)
+    }
+}
+
+impl BusState {
+    /// How many events from `from` on are gone, and those still here
+    fn buffered_from(&self, from: u64) -> (u64, impl Iterator<Item = &SequencedEvent>) {
+        let from = from.max(1);
+        let oldest = self.buffer.front().map_or(self.last_seq + 1, |event| event.seq);
+        (oldest.saturating_sub(from), self.buffer.iter().filter(move |event| event.seq >= from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(n: usize) -> ChatEvent {
+        ChatEvent::Error { message: n.to_string() }
+    }
+
+    fn seqs(replay: &EventReplay) -> Vec<u64> {
+        replay.events.iter().map(|event| event.seq).collect()
+    }
+
+    #[tokio::test]
+    async fn test_replay_and_subscribe() {
+        let bus = EventBus::default();
+        bus.publish((1..=3).map(error).collect());
+        assert_eq!(bus.last_seq(), 3);
+        assert_eq!(seqs(&bus.replay(2)), vec![2, 3]);
+        assert_eq!(seqs(&bus.replay(0)), vec![1, 2, 3]);
+
+        let mut subscription = bus.subscribe_from(3);
+        bus.publish(vec![error(4)]);
+        assert_eq!(subscription.recv().await.unwrap().seq, 3);
+        assert_eq!(subscription.recv().await.unwrap().seq, 4);
+        assert_eq!(subscription.missed, 0);
+
+        // The oldest events make room for new ones
+        bus.publish((5..=REPLAY_CAPACITY + 2).map(error).collect());
+        let replay = bus.replay(1);
+        assert_eq!(replay.missed, 2);
+        assert_eq!(replay.events.first().unwrap().seq, 3);
+    }
+
+    #[tokio::test]
+    async fn test_overflow_drops_lowest_priority() {
+        let bus = EventBus::default();
+        let mut subscription = bus.subscribe_from(1);
+        let security = || ChatEvent::PairingFailed { reason: "x".to_string() };
+        let low = || ChatEvent::ContactOnline { contact_id: "c".to_string() };
+        bus.publish(vec![security(), low()]);
+        bus.publish((2..SUBSCRIPTION_CAPACITY).map(error).collect());
+        // Full: the presence event goes first, then the oldest errors
+        bus.publish(vec![error(0), error(0), security()]);
+
+        let Some(SequencedEvent { seq: NOTICE_SEQ, event: ChatEvent::EventsDropped { count: 3 } }) = subscription.recv().await else {
+            panic!("expected a drop notice");
+        };
+        let mut received = Vec::new();
+        while let Ok(Some(event)) = tokio::time::timeout(std::time::Duration::from_millis(50), subscription.recv()).await {
+            received.push(event);
+        }
+        assert_eq!(received.len(), SUBSCRIPTION_CAPACITY);
+        assert!(matches!(received[0].event, ChatEvent::PairingFailed { .. }));
+        assert!(matches!(received.last().unwrap().event, ChatEvent::PairingFailed { .. }));
+        assert!(received.iter().all(|event| event.event.priority() != EventPriority::Low));
+        // Lost events are still there to replay
+        assert_eq!(received[1].seq, 5);
+    }
+
+    #[tokio::test]
+    async fn test_security_flood_is_coalesced() {
+        let bus = EventBus::default();
+        let mut subscription = bus.subscribe_from(1);
+        let failed = |contact: usize| ChatEvent::DecryptionFailed {
+            contact_id: contact.to_string(),
+            message_id: String::new(),
+            reason: String::new(),
+        };
+        // Two contacts past the limit, then more than are counted apart
+        let flood = SECURITY_QUEUE_LIMIT + 2 * MAX_COALESCED_SUBJECTS;
+        bus.publish((0..flood).map(|n| failed(n % 2)).collect());
+        bus.publish((0..flood).map(|n| failed(2 + n)).collect());
+
+        let mut queued = Vec::new();
+        let mut counts = Vec::new();
+        while let Ok(Some(event)) = tokio::time::timeout(std::time::Duration::from_millis(50), subscription.recv()).await {
+            match event.event {
+                ChatEvent::SecurityEventsCoalesced { kind, subject, count, first_seq, last_seq } => {
+                    assert_eq!(kind, "decryption_failed");
+                    // Coalesced events come before any still queued
+                    assert!(queued.is_empty());
+                    counts.push((subject, count, first_seq, last_seq));
+                }
+                ChatEvent::DecryptionFailed { .. } => queued.push(event.seq),
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+        // None lost: each is either received or counted
+        let total: u64 = counts.iter().map(|(_, count, _, _)| count).sum();
+        assert_eq!(queued.len(), SECURITY_QUEUE_LIMIT);
+        assert_eq!(total as usize + queued.len(), 2 * flood);
+        assert_eq!(queued[0], total + 1);
+        assert_eq!(counts.len(), MAX_COALESCED_SUBJECTS + 1);
+        assert_eq!(counts[0], (Some("0".to_string()), flood as u64 / 2, 1, flood as u64 - 1));
+        assert_eq!(counts[1].0.as_deref(), Some("1"));
+        assert!(counts.last().unwrap().0.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_listener() {
+        let bus = EventBus::default();
+        bus.publish(vec![error(1)]);
+        let mut receiver = bus.attach_listener(1);
+        assert!(bus.has_listener());
+        bus.publish(vec![error(2)]);
+        assert!(matches!(receiver.recv().await, Some(ChatEvent::Error { message }) if message == "1"));
+        assert!(matches!(receiver.recv().await, Some(ChatEvent::Error { message }) if message == "2"));
+        bus.detach_listener();
+        assert!(receiver.recv().await.is_none());
+        assert!(!bus.has_listener());
+    }
+}
@@ -0,0 +1,34 @@
+//! Orphaned record collection.
+//!
+//! Records hang off one another: messages off their conversation, a
+//! conversation off its contact, attachments off the messages and sticker
+//! packs using them. `SecureStorage::collect_garbage` finds records whose
+//! parent is gone, along with everything hanging off those, and deletes
+//! them or, in a dry run, only counts them.
+
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GcReport {
+    pub conversations: usize,
+    pub messages: usize,
+    /// Starred, attachment reference, envelope alias, conversation,
+    /// message status and message order index entries
+    pub index_entries: usize,
+    pub blobs: usize,
+    /// Settings, prekeys, audit logs, retention policies and thumbnails
+    pub other: usize,
+    /// Only counted, nothing was deleted
+    pub dry_run: bool,
+}
+
+impl GcReport {
+    /// Records found, counting each blob once
+    pub fn total(&self) -> usize {
+        self.conversations + self.messages + self.index_entries + self.blobs + self.other
+    }
+}
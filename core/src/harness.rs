@@ -0,0 +1,275 @@
+//! End-to-end tests with several nodes in one process.
+//!
+//! Each `TestNode` is a full `SecureChat` with in-memory storage, talking
+//! over libp2p's memory transport, so scenarios run the real protocol
+//! without sockets or files.
+
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use crate::network::{NetworkConfig, TransportKind};
+use crate::protocol::DeliveryStatus;
+use crate::storage::SecureStorage;
+use crate::{ChatEvent, SecureChat};
+
+/// Longest a scenario waits for an event
+pub const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TestNode {
+    pub chat: SecureChat,
+    /// Events since the last `start`
+    pub events: mpsc::Receiver<ChatEvent>,
+    /// Stays the same across restarts
+    pub address: String,
+    port: u64,
+}
+
+impl TestNode {
+    /// A node with a fresh account; its network isn't started
+    pub async fn new(name: &str) -> Self {
+        let chat = SecureChat::new(None);
+        *chat.storage.write().await = Some(SecureStorage::create_temporary("password").unwrap());
+        chat.init_account(name).await.unwrap();
+        let port = rand::random::<u64>().max(1);
+        Self {
+            chat,
+            events: mpsc::channel(1).1,
+            address: format!("/memory/{}", port),
+            port,
+        }
+    }
+    
+    /// Start the network, dialing `peers`
+    pub async fn start(&mut self, peers: &[&TestNode]) {
+        self.events = self.chat.start_network(NetworkConfig {
+            transport: TransportKind::Memory { port: self.port },
+            bootstrap_peers: peers.iter().map(|peer| peer.address.clone()).collect(),
+            enable_mdns: false,
+            ..Default::default()
+        }).await.unwrap();
+    }
+    
+    pub async fn stop(&mut self) {
+        self.chat.stop_network().await.unwrap();
+    }
+    
+    /// Wait for the first event `matcher` accepts, skipping others
+    pub async fn expect<T>(&mut self, what: &str, mut matcher: impl FnMut(&ChatEvent) -> Option<T>) -> T {
+        loop {
+            match tokio::time::timeout(EVENT_TIMEOUT, self.events.recv()).await {
+                Ok(Some(event)) => {
+                    if let Some(found) = matcher(&event) {
+                        return found;
+                    }
+                }
+                Ok(None) => panic!("Events ended waiting for {}", what),
+                Err(_) => panic!("Timed out waiting for {}", what),
+            }
+        }
+    }
+    
+    /// Wait until some peer is connected
+    pub async fn expect_online(&mut self) -> String {
+        self.expect("a peer to connect", |event| match event {
+            ChatEvent::ContactOnline { contact_id } => Some(contact_id.clone()),
+            _ => None,
+        }).await
+    }
+    
+    /// Wait for an incoming message, returning its conversation and text
+    pub async fn expect_message(&mut self) -> (String, String) {
+        self.expect("a message", |event| match event {
+            ChatEvent::MessageReceived { conversation_id, message } => match &message.content {
+                crate::protocol::MessageContent::Text { text } => Some((conversation_id.clone(), text.clone())),
+                _ => None,
+            },
+            _ => None,
+        }).await
+    }
+}
+
+/// Start every node, each dialing the first, and wait until they all
+/// have a peer
+pub async fn connect(nodes: &mut [TestNode]) {
+    let (first, rest) = nodes.split_first_mut().unwrap();
+    first.start(&[]).await;
+    for node in rest.iter_mut() {
+        node.start(&[first]).await;
+    }
+    for node in nodes.iter_mut() {
+        node.expect_online().await;
+    }
+}
+
+/// Have `from` accept an invite from `to`, returning `from`'s conversation
+/// with `to`
+pub async fn introduce(from: &TestNode, to: &TestNode) -> String {
+    let invite = to.chat.create_invite(Duration::from_secs(3600), false).await.unwrap();
+    let contact = from.chat.accept_invite(&invite.to_link().unwrap()).await.unwrap();
+    from.chat.get_or_create_conversation(&contact.id).await.unwrap().id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lifecycle::{ResumeReport, SuspendOptions};
+    
+    async fn nodes(names: &[&str]) -> Vec<TestNode> {
+        let mut nodes = Vec::new();
+        for name in names {
+            nodes.push(TestNode::new(name).await);
+        }
+        nodes
+    }
+    
+    #[tokio::test]
+    async fn test_invite_handshake() {
+        let mut nodes = nodes(&["Alice", "Bob", "Carol"]).await;
+        connect(&mut nodes).await;
+        let [alice, bob, carol] = &mut nodes[..] else { unreachable!() };
+        
+        let conversation = introduce(bob, alice).await;
+        bob.chat.send_text_message(&conversation, "Hi Alice").await.unwrap();
+        let (received_in, text) = alice.expect_message().await;
+        assert_eq!(text, "Hi Alice");
+        
+        // Alice now knows Bob by his key
+        let contacts = alice.chat.get_contacts().await.unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].display_name, "Bob");
+        assert_eq!(contacts[0].public_key, bob.chat.get_public_key().await.unwrap());
+        assert_eq!(alice.chat.get_messages(&received_in, 10).await.unwrap().len(), 1);
+        
+        // Carol saw the envelope go by but can't open it
+        carol.expect("the envelope to be rejected", |event| matches!(event, ChatEvent::Error { .. }).then_some(())).await;
+        assert!(carol.chat.get_contacts().await.unwrap().is_empty());
+        assert!(carol.chat.get_conversations().await.unwrap().is_empty());
+        
+        for node in nodes.iter_mut() {
+            node.stop().await;
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_offline_queue() {
+        let mut nodes = nodes(&["Alice", "Bob"]).await;
+        let [alice, bob] = &mut nodes[..] else { unreachable!() };
+        let conversation = introduce(bob, alice).await;
+        
+        // Sent before Bob's network runs, so kept in his outbox
+        bob.chat.send_text_message(&conversation, "before starting").await.unwrap();
+        alice.start(&[]).await;
+        bob.start(&[alice]).await;
+        assert_eq!(alice.expect_message().await.1, "before starting");
+        
+        // Sent while Alice is away, held by the network until she's back
+        alice.stop().await;
+        bob.expect("Alice to go offline", |event| matches!(event, ChatEvent::ContactOffline { .. }).then_some(())).await;
+        bob.chat.send_text_message(&conversation, "while you were out").await.unwrap();
+        alice.start(&[bob]).await;
+        assert_eq!(alice.expect_message().await.1, "while you were out");
+        assert_eq!(alice.chat.get_conversations().await.unwrap().len(), 1);
+        
+        alice.stop().await;
+        bob.stop().await;
+    }
+    
+    #[tokio::test]
+    async fn test_receipt_round_trip() {
+        let mut nodes = nodes(&["Alice", "Bob"]).await;
+        connect(&mut nodes).await;
+        let [alice, bob] = &mut nodes[..] else { unreachable!() };
+        
+        let conversation = introduce(bob, alice).await;
+        let message_id = bob.chat.send_text_message(&conversation, "Read me").await.unwrap();
+        let (received_in, _) = alice.expect_message().await;
+        
+        // Every step is reported, in order; Sending may lose the race with
+        // the network confirming the send
+        let mut seen = Vec::new();
+        while seen.last() != Some(&DeliveryStatus::Delivered) {
+            seen.push(bob.expect("a status change", |event| match event {
+                ChatEvent::MessageStatusChanged { message_id: id, status, .. } => (*id == message_id).then(|| status.clone()),
+                _ => None,
+            }).await);
+        }
+        assert!(seen.ends_with(&[DeliveryStatus::Sent, DeliveryStatus::Delivered]), "{:?}", seen);
+        
+        assert_eq!(alice.chat.mark_conversation_read(&received_in).await.unwrap(), 1);
+        bob.expect("a read receipt", |event| match event {
+            ChatEvent::MessageStatusChanged { message_id: id, status: DeliveryStatus::Read, .. } => (*id == message_id).then_some(()),
+            _ => None,
+        }).await;
+        let sent = bob.chat.get_messages(&conversation, 10).await.unwrap();
+        assert_eq!(sent[0].status, DeliveryStatus::Read);
+        assert_eq!(alice.chat.get_messages(&received_in, 10).await.unwrap()[0].status, DeliveryStatus::Read);
+        
+        alice.stop().await;
+        bob.stop().await;
+    }
+    
+    #[tokio::test]
+    async fn test_fan_out_to_every_device() {
+        let mut nodes = nodes(&["Alice", "Bob", "Bob"]).await;
+        // Bob's laptop shares his identity
+        let identity = nodes[1].chat.identity.read().await.clone();
+        *nodes[2].chat.identity.write().await = identity;
+        let alice_key = nodes[0].chat.get_public_key().await.unwrap();
+        let bob_key = nodes[1].chat.get_public_key().await.unwrap();
+        let contact = nodes[0].chat.add_contact(bob_key, "Bob").await.unwrap();
+        for bob in &nodes[1..] {
+            bob.chat.add_contact(alice_key, "Alice").await.unwrap();
+        }
+        connect(&mut nodes).await;
+        let [alice, bob, laptop] = &mut nodes[..] else { unreachable!() };
+        
+        bob.chat.announce_device().await.unwrap();
+        laptop.chat.announce_device().await.unwrap();
+        while alice.chat.get_contact_devices(&contact.id).await.unwrap().len() < 2 {
+            alice.expect("a device announcement", |event| matches!(event, ChatEvent::ContactDevicesChanged { .. }).then_some(())).await;
+        }
+        
+        let conversation = alice.chat.get_or_create_conversation(&contact.id).await.unwrap().id;
+        alice.chat.send_text_message(&conversation, "Hi Bob").await.unwrap();
+        for device in [bob, laptop] {
+            let (received_in, text) = device.expect_message().await;
+            assert_eq!(text, "Hi Bob");
+            assert_eq!(device.chat.get_messages(&received_in, 10).await.unwrap().len(), 1);
+        }
+        
+        for node in nodes.iter_mut() {
+            node.stop().await;
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_suspend_and_resume() {
+        let mut nodes = nodes(&["Alice", "Bob"]).await;
+        connect(&mut nodes).await;
+        let [alice, bob] = &mut nodes[..] else { unreachable!() };
+        let conversation = introduce(bob, alice).await;
+        
+        // Parked, so the message waits in the outbox
+        bob.chat.on_suspend(SuspendOptions { park_network: true }).await.unwrap();
+        assert!(bob.chat.is_suspended().await);
+        assert!(!bob.chat.is_network_running().await);
+        bob.chat.send_text_message(&conversation, "sent while asleep").await.unwrap();
+        
+        // Same settings and the same event channel as before
+        let report = bob.chat.on_resume().await.unwrap();
+        assert!(report.network_restarted);
+        assert!(!bob.chat.is_suspended().await);
+        bob.expect_online().await;
+        assert_eq!(alice.expect_message().await.1, "sent while asleep");
+        
+        // Kept running, so only announced again
+        alice.chat.on_suspend(SuspendOptions::default()).await.unwrap();
+        assert!(alice.chat.is_network_running().await);
+        assert!(!alice.chat.on_resume().await.unwrap().network_restarted);
+        assert_eq!(alice.chat.on_resume().await.unwrap(), ResumeReport::default());
+        
+        alice.stop().await;
+        bob.stop().await;
+    }
+}
@@ -0,0 +1,132 @@
+//! Health checks, for supervisors of a client that runs unattended.
+//!
+//! `SecureChat::health` reports on each part of the core: whether storage
+//! is open and still being flushed, whether the network runs and answers,
+//! how many peers are connected and how much is waiting to be sent. A
+//! network task that doesn't answer within `PROBE_TIMEOUT`, or a flusher
+//! that has stopped flushing, is what a wedged node looks like, and makes
+//! the report unhealthy. `HealthReport::http_status` maps the report onto
+//! the status code a `/healthz` endpoint of the host application returns.
+
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+/// Longest the network task may take to answer a health check
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Intervals of the background flusher that may pass without a flush
+/// before it counts as stuck
+pub const MISSED_FLUSHES: u32 = 20;
+/// Shortest time without a flush that counts as stuck
+pub const MIN_FLUSH_STALENESS: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Healthy,
+    /// Working, but can't deliver right now: the network is stopped or no
+    /// peer is connected
+    Degraded,
+    /// Storage is closed or a component stopped answering
+    Unhealthy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub checked_at: OffsetDateTime,
+    pub status: HealthStatus,
+    pub storage: StorageHealth,
+    pub network: NetworkHealth,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageHealth {
+    pub open: bool,
+    /// When changes last reached the disk this run
+    pub last_flush: Option<OffsetDateTime>,
+    /// The background flusher should have flushed by now and hasn't
+    pub flush_overdue: bool,
+    /// Messages kept to send once the network is back
+    pub outbox_depth: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkHealth {
+    /// The network task is running and listening
+    pub running: bool,
+    /// Whether it answered within `PROBE_TIMEOUT`; `None` when not running
+    pub responsive: Option<bool>,
+    pub connected_peers: usize,
+    /// Outgoing messages held back by network conditions
+    pub deferred_messages: usize,
+}
+
+impl HealthReport {
+    pub fn new(storage: StorageHealth, network: NetworkHealth) -> Self {
+        let status = if !storage.open || storage.flush_overdue || network.responsive == Some(false) {
+            HealthStatus::Unhealthy
+        } else if !network.running || network.connected_peers == 0 {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Healthy
+        };
+        Self { checked_at: OffsetDateTime::now_utc(), status, storage, network }
+    }
+
+    /// Status code for a `/healthz` response: only an unhealthy node fails
+    /// it, as a degraded one recovers by itself
+    pub fn http_status(&self) -> u16 {
+        match self.status {
+            HealthStatus::Healthy | HealthStatus::Degraded => 200,
+            HealthStatus::Unhealthy => 503,
+        }
+    }
+}
+
+/// Whether a flusher running every `flush_every` should have flushed
+/// since `last_flush`. Flushes made only for messages can't be overdue.
+pub fn flush_overdue(last_flush: Option<OffsetDateTime>, flush_every: Option<Duration>, now: OffsetDateTime) -> bool {
+    let (Some(last_flush), Some(every)) = (last_flush, flush_every) else {
+        return false;
+    };
+    let staleness = (every * MISSED_FLUSHES).max(MIN_FLUSH_STALENESS);
+    now - last_flush > staleness
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> StorageHealth {
+        StorageHealth { open: true, last_flush: None, flush_overdue: false, outbox_depth: 0 }
+    }
+
+    fn network() -> NetworkHealth {
+        NetworkHealth { running: true, responsive: Some(true), connected_peers: 2, deferred_messages: 0 }
+    }
+
+    #[test]
+    fn test_status() {
+        let report = HealthReport::new(storage(), network());
+        assert_eq!((report.status, report.http_status()), (HealthStatus::Healthy, 200));
+
+        let report = HealthReport::new(storage(), NetworkHealth { connected_peers: 0, ..network() });
+        assert_eq!((report.status, report.http_status()), (HealthStatus::Degraded, 200));
+
+        let report = HealthReport::new(storage(), NetworkHealth { responsive: Some(false), ..network() });
+        assert_eq!((report.status, report.http_status()), (HealthStatus::Unhealthy, 503));
+        let report = HealthReport::new(StorageHealth { open: false, ..storage() }, network());
+        assert_eq!(report.status, HealthStatus::Unhealthy);
+    }
+
+    #[test]
+    fn test_flush_overdue() {
+        let now = OffsetDateTime::now_utc();
+        let every = Some(Duration::from_millis(500));
+        assert!(!flush_overdue(Some(now - Duration::from_secs(10)), every, now));
+        assert!(flush_overdue(Some(now - Duration::from_secs(60)), every, now));
+        assert!(!flush_overdue(Some(now - Duration::from_secs(3600)), None, now));
+        assert!(!flush_overdue(None, every, now));
+    }
+}
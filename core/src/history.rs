@@ -0,0 +1,280 @@
+//! Message history sync between our own devices.
+//!
+//! A device asking for history sends a manifest: per conversation, its
+//! messages grouped into buckets of `BUCKET_SPAN` Lamport ticks, each with a
+//! count and a digest of the message ids. The device answering compares it
+//! with its own manifest and sends every bucket that differs, in batches
+//! encrypted to the requester. The answering side keeps no state, so an
+//! interrupted sync resumes by sending a fresh manifest, which no longer
+//! shows the buckets already filled.
+//!
+//! Requests are signed with their time and a random nonce. One older than
+//! `REQUEST_TOLERANCE`, or seen before, is refused, and each device is
+//! served one transfer at a time, so a captured request can't be used to
+//! make us send the history over and over.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::crypto::EncryptedMessage;
+use crate::protocol::{wire, LocalMessage};
+
+/// Lamport ticks covered by one manifest bucket
+pub const BUCKET_SPAN: u64 = 64;
+
+/// Most messages in one transfer batch. A batch never spans buckets, so
+/// whatever arrived is skipped when a sync resumes.
+pub const MAX_BATCH_MESSAGES: usize = 128;
+
+/// Pause between batches, so a large history doesn't flood the network
+pub const BATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How far a request's time may be from ours
+pub const REQUEST_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+/// Messages a device holds, per conversation
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryManifest {
+    pub conversations: Vec<ConversationManifest>,
+}
+
+/// Conversations are named by contact, since conversation ids differ
+/// between devices
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationManifest {
+    pub contact_id: String,
+    pub buckets: Vec<ManifestBucket>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestBucket {
+    /// Covers Lamport times `index * BUCKET_SPAN` up to the next bucket
+    pub index: u64,
+    pub count: u32,
+    /// Hash over the sorted message ids
+    pub digest: [u8; 32],
+}
+
+/// Decrypted contents of a `ProtocolMessage::HistoryBatch`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryBatchContent {
+    pub contact_id: String,
+    pub messages: Vec<LocalMessage>,
+    /// Batches still to come in this transfer
+    pub remaining: u32,
+}
+
+/// Outcome of applying one batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppliedBatch {
+    /// Messages this device didn't have yet
+    pub added: usize,
+    pub remaining: u32,
+}
+
+pub fn bucket_of(message: &LocalMessage) -> u64 {
+    message.lamport / BUCKET_SPAN
+}
+
+/// What the identity key signs in a `HistorySyncRequest`; covering the
+/// reply key keeps anyone else from redirecting the transfer to themselves,
+/// and the time and nonce from sending it again
+pub fn request_signing_bytes(
+    device_id: &str,
+    reply_key: &[u8; 32],
+    manifest: &HistoryManifest,
+    timestamp: i64,
+    nonce: &[u8; 16],
+) -> Result<Vec<u8>> {
+    wire::encode(&(device_id, reply_key, manifest, timestamp, nonce))
+}
+
+/// Requests accepted within the last `REQUEST_TOLERANCE`, and the devices
+/// being served
+#[derive(Debug, Default)]
+pub struct RequestGuard {
+    seen: Mutex<HashMap<[u8; 16], i64>>,
+    serving: Mutex<HashSet<String>>,
+}
+
+impl RequestGuard {
+    /// Fail unless a request made at `timestamp` is recent; checked before
+    /// the signature, as that is the costlier check
+    pub fn check_time(&self, timestamp: i64, now: OffsetDateTime) -> Result<()> {
+        if now.unix_timestamp().abs_diff(timestamp) > REQUEST_TOLERANCE.as_secs() {
+            return Err(anyhow::anyhow!("History sync request is too old or too far ahead"));
+        }
+        Ok(())
+    }
+    
+    /// Record the nonce of a verified request, failing if it was accepted
+    /// before
+    pub fn accept(&self, timestamp: i64, nonce: [u8; 16], now: OffsetDateTime) -> Result<()> {
+        self.check_time(timestamp, now)?;
+        let mut seen = self.seen.lock().map_err(|_| anyhow::anyhow!("Poisoned"))?;
+        // Anything older fails the time check anyway
+        seen.retain(|_, seen_at| now.unix_timestamp().abs_diff(*seen_at) <= REQUEST_TOLERANCE.as_secs());
+        if seen.contains_key(&nonce) {
+            return Err(anyhow::anyhow!("History sync request was already handled"));
+        }
+        seen.insert(nonce, timestamp);
+        Ok(())
+    }
+    
+    /// Claim serving `device_id` until the returned guard is dropped,
+    /// failing if it is being served already
+    pub fn serve(self: &Arc<Self>, device_id: &str) -> Result<Serving> {
+        let mut serving = self.serving.lock().map_err(|_| anyhow::anyhow!("Poisoned"))?;
+        if !serving.insert(device_id.to_string()) {
+            return Err(anyhow::anyhow!("History is already being sent to device {}", device_id));
+        }
+        Ok(Serving { guard: self.clone(), device_id: device_id.to_string() })
+    }
+}
+
+/// A transfer to one device in progress, see `RequestGuard::serve`
+#[derive(Debug)]
+pub struct Serving {
+    guard: Arc<RequestGuard>,
+    device_id: String,
+}
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        if let Ok(mut serving) = self.guard.serving.lock() {
+            serving.remove(&self.device_id);
+        }
+    }
+}
+
+/// What the identity key signs in a `HistoryBatch`
+pub fn batch_signing_bytes(device_id: &str, payload: &EncryptedMessage) -> Result<Vec<u8>> {
+    wire::encode(&(device_id, payload))
+}
+
+impl ConversationManifest {
+    pub fn build(contact_id: &str, messages: &[LocalMessage]) -> Self {
+        let mut ids: BTreeMap<u64, Vec<&str>> = BTreeMap::new();
+        for message in messages {
+            ids.entry(bucket_of(message)).or_default().push(&message.id);
+        }
+        
+        let buckets = ids.into_iter()
+            .map(|(index, mut ids)| {
+                ids.sort_unstable();
+                ids.dedup();
+                let mut hasher = blake3::Hasher::new_derive_key("SecureChat history manifest v1");
+                for id in &ids {
+                    hasher.update(&(id.len() as u64).to_be_bytes());
+                    hasher.update(id.as_bytes());
+                }
+                ManifestBucket {
+                    index,
+                    count: ids.len() as u32,
+                    digest: *hasher.finalize().as_bytes(),
+                }
+            })
+            .collect();
+        Self { contact_id: contact_id.to_string(), buckets }
+    }
+}
+
+impl HistoryManifest {
+    /// Buckets held here that `other` lacks or holds differently, as
+    /// (contact id, bucket index) pairs
+    pub fn gaps(&self, other: &HistoryManifest) -> Vec<(String, u64)> {
+        let mut gaps = Vec::new();
+        for conversation in &self.conversations {
+            let theirs = other.conversations.iter()
+                .find(|c| c.contact_id == conversation.contact_id);
+            for bucket in &conversation.buckets {
+                let held = theirs
+                    .and_then(|c| c.buckets.iter().find(|b| b.index == bucket.index))
+                    .is_some_and(|b| b == bucket);
+                if !held {
+                    gaps.push((conversation.contact_id.clone(), bucket.index));
+                }
+            }
+        }
+        gaps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{DeliveryStatus, MessageContent};
+    use time::OffsetDateTime;
+    
+    fn message(id: &str, lamport: u64) -> LocalMessage {
+        LocalMessage {
+            id: id.to_string(),
+            conversation_id: "conv".to_string(),
+            sender_id: "me".to_string(),
+            is_outgoing: true,
+            content: MessageContent::Text { text: id.to_string() },
+            timestamp: OffsetDateTime::UNIX_EPOCH,
+            status: DeliveryStatus::Sent,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport,
+        }
+    }
+    
+    fn manifest(contact_id: &str, messages: &[LocalMessage]) -> HistoryManifest {
+        HistoryManifest { conversations: vec![ConversationManifest::build(contact_id, messages)] }
+    }
+    
+    #[test]
+    fn test_gaps_cover_differing_buckets() {
+        let full: Vec<_> = (0..200).map(|i| message(&format!("m{}", i), i)).collect();
+        // Missing one message in the second bucket and all of the fourth
+        let partial: Vec<_> = full.iter()
+            .filter(|m| m.lamport != 100 && m.lamport < 192)
+            .cloned()
+            .collect();
+        
+        let ours = manifest("alice", &full);
+        assert_eq!(ours.conversations[0].buckets.len(), 4);
+        assert_eq!(ours.gaps(&manifest("alice", &partial)), vec![
+            ("alice".to_string(), 1),
+            ("alice".to_string(), 3),
+        ]);
+        assert!(ours.gaps(&ours).is_empty());
+        // Order of messages doesn't matter
+        let mut shuffled = full.clone();
+        shuffled.reverse();
+        assert!(ours.gaps(&manifest("alice", &shuffled)).is_empty());
+        
+        // Another conversation isn't a match
+        assert_eq!(ours.gaps(&manifest("bob", &full)).len(), 4);
+        assert_eq!(ours.gaps(&HistoryManifest::default()).len(), 4);
+    }
+    
+    #[test]
+    fn test_request_guard() {
+        let guard = Arc::new(RequestGuard::default());
+        let now = OffsetDateTime::now_utc();
+        let timestamp = now.unix_timestamp();
+        
+        guard.accept(timestamp, [1; 16], now).unwrap();
+        assert!(guard.accept(timestamp, [1; 16], now).is_err());
+        guard.accept(timestamp, [2; 16], now).unwrap();
+        let stale = timestamp - REQUEST_TOLERANCE.as_secs() as i64 - 1;
+        assert!(guard.accept(stale, [3; 16], now).is_err());
+        assert!(guard.check_time(timestamp + REQUEST_TOLERANCE.as_secs() as i64 + 1, now).is_err());
+        
+        let serving = guard.serve("phone").unwrap();
+        assert!(guard.serve("phone").is_err());
+        let other = guard.serve("tablet").unwrap();
+        drop(serving);
+        let _again = guard.serve("phone").unwrap();
+        drop(other);
+    }
+}
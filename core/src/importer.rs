@@ -0,0 +1,386 @@
+//! Importing contacts from other messengers.
+//!
+//! Three export formats are understood: vCard files, as most address
+//! books and messengers write them; the JSON `signal-cli listContacts
+//! --output json` prints; and the `joined_members` response a Matrix
+//! homeserver gives for a room. Only a vCard can carry a SecureChat
+//! identity key, in a `KEY` or `X-SECURECHAT-KEY` property; such entries
+//! become unverified contacts straight away. Signal and Matrix keys are
+//! of no use to us, so their entries, and cards without a key, are kept
+//! as `PendingContact`s, names pending verification, until the user
+//! learns their key. Each entry is parsed on its own, so one bad entry
+//! fails alone and is reported as such.
+
+use anyhow::Result;
+use base64::Engine;
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::text;
+
+/// Most entries accepted in one import
+pub const MAX_ENTRIES: usize = crate::contact_export::MAX_CONTACTS;
+/// Longer names are cut to this many grapheme clusters
+pub const MAX_NAME_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImportFormat {
+    Vcard,
+    Signal,
+    Matrix,
+}
+
+/// An entry read from an export
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedEntry {
+    pub display_name: String,
+    /// Phone number, Matrix id or similar the source knew them by
+    pub handle: Option<String>,
+    pub public_key: Option<[u8; 32]>,
+}
+
+/// Someone imported without a key, known by name until the user adds
+/// their key with `SecureChat::add_pending_contact`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingContact {
+    pub id: String,
+    pub display_name: String,
+    pub handle: Option<String>,
+    pub source: ImportFormat,
+    pub imported_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImportOutcome {
+    /// Added as an unverified contact
+    Added { contact_id: String },
+    /// Kept as a pending contact, for lack of a key
+    Pending { pending_id: String },
+    /// Already a contact, or already pending
+    Duplicate { id: String },
+    Failed { reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportResult {
+    /// Position of the entry in the export, from 0
+    pub index: usize,
+    /// The name read, if the entry got that far
+    pub name: Option<String>,
+    pub outcome: ImportOutcome,
+}
+
+/// Per-entry results of an import
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub results: Vec<ImportResult>,
+}
+
+impl ImportReport {
+    pub fn added(&self) -> usize {
+        self.count(|outcome| matches!(outcome, ImportOutcome::Added { .. }))
+    }
+
+    pub fn pending(&self) -> usize {
+        self.count(|outcome| matches!(outcome, ImportOutcome::Pending { .. }))
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, ImportOutcome::Failed { .. }))
+    }
+
+    fn count(&self, matches: impl Fn(&ImportOutcome) -> bool) -> usize {
+        self.results.iter().filter(|result| matches(&result.outcome)).count()
+    }
+}
+
+/// The entries of an export, each parsed or with why it couldn't be.
+/// Fails as a whole only if the export itself can't be read.
+pub fn parse(data: &[u8], format: ImportFormat) -> Result<Vec<Result<ImportedEntry, String>>> {
+    let entries = match format {
+        ImportFormat::Vcard => parse_vcards(std::str::from_utf8(data)
+            .map_err(|_| anyhow::anyhow!("vCard file is not UTF-8"))?),
+        ImportFormat::Signal => parse_signal(data)?,
+        ImportFormat::Matrix => parse_matrix(data)?,
+    };
+    if entries.len() > MAX_ENTRIES {
+        return Err(anyhow::anyhow!("Export holds more than {} entries", MAX_ENTRIES));
+    }
+    Ok(entries)
+}
+
+fn entry(name: &str, handle: Option<&str>, public_key: Option<[u8; 32]>) -> Result<ImportedEntry, String> {
+    let handle = handle.map(text::single_line).filter(|h| !h.is_empty());
+    let name = text::single_line(name);
+    let name = match (name.is_empty(), &handle) {
+        (false, _) => text::truncate(&name, MAX_NAME_LEN).to_string(),
+        (true, Some(handle)) => handle.clone(),
+        (true, None) => return Err("Entry has no name".to_string()),
+    };
+    Ok(ImportedEntry { display_name: name, handle, public_key })
+}
+
+// ===== vCard =====
+
+fn parse_vcards(data: &str) -> Vec<Result<ImportedEntry, String>> {
+    // Folded lines continue on lines starting with a space or tab
+    let unfolded = data.replace("\r\n", "\n").replace("\n ", "").replace("\n\t", "");
+
+    let mut entries = Vec::new();
+    let mut card: Option<Vec<(String, String)>> = None;
+    for line in unfolded.lines().map(str::trim_end).filter(|l| !l.is_empty()) {
+        let Some((name, value)) = property(line) else {
+            continue;
+        };
+        let vcard = value.eq_ignore_ascii_case("VCARD");
+        match name.as_str() {
+            "BEGIN" if vcard => {
+                if card.is_some() {
+                    entries.push(Err("Card not ended".to_string()));
+                }
+                card = Some(Vec::new());
+            }
+            "END" if vcard => {
+                if let Some(properties) = card.take() {
+                    entries.push(vcard_entry(&properties));
+                }
+            }
+            _ => {
+                if let Some(properties) = card.as_mut() {
+                    properties.push((name, value.to_string()));
+                }
+            }
+        }
+    }
+    if card.is_some() {
+        entries.push(Err("Card not ended".to_string()));
+    }
+    entries
+}
+
+/// Name, upper-cased and without group or parameters, and value of a
+/// content line
+fn property(line: &str) -> Option<(String, &str)> {
+    let (head, value) = line.split_once(':')?;
+    let name = head.split(';').next()?;
+    let name = name.rsplit('.').next()?;
+    Some((name.to_ascii_uppercase(), value))
+}
+
+fn vcard_entry(properties: &[(String, String)]) -> Result<ImportedEntry, String> {
+    let first = |wanted: &[&str]| first_value(properties, wanted);
+    let name = match (first(&["FN"]), first(&["N"])) {
+        (Some(full), _) => unescape(full),
+        // Family;Given;Additional;Prefixes;Suffixes
+        (None, Some(structured)) => {
+            let parts: Vec<String> = split_unescaped(structured).iter().map(|p| unescape(p)).collect();
+            [parts.get(1), parts.first()].into_iter().flatten()
+                .filter(|p| !p.is_empty())
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+        (None, None) => String::new(),
+    };
+    let handle = first(&["TEL", "IMPP", "EMAIL"]).map(unescape);
+    let public_key = match first(&["X-SECURECHAT-KEY", "KEY"]) {
+        Some(key) => Some(decode_key(key).ok_or_else(|| "Key is not a SecureChat identity key".to_string())?),
+        None => None,
+    };
+    entry(&name, handle.as_deref(), public_key)
+}
+
+/// Value of the first non-empty property named one of `wanted`
+fn first_value<'a>(properties: &'a [(String, String)], wanted: &[&str]) -> Option<&'a str> {
+    properties.iter()
+        .find(|(name, value)| wanted.contains(&name.as_str()) && !value.trim().is_empty())
+        .map(|(_, value)| value.as_str())
+}
+
+fn split_unescaped(value: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ';' => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => unescaped.push(' '),
+            Some(escaped) => unescaped.push(escaped),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+/// A 32 byte key in hex or base64, optionally as a `data:` URI
+fn decode_key(value: &str) -> Option<[u8; 32]> {
+    let value = value.trim();
+    let value = match value.strip_prefix("data:") {
+        Some(uri) => uri.split_once(',')?.1,
+        None => value,
+    };
+    let bytes = if value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        (0..32).map(|i| u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()).collect::<Option<Vec<u8>>>()?
+    } else {
+        base64::engine::general_purpose::STANDARD.decode(value)
+            .or_else(|_| base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(value))
+            .ok()?
+    };
+    bytes.try_into().ok()
+}
+
+// ===== Signal =====
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct SignalContact {
+    number: Option<String>,
+    username: Option<String>,
+    uuid: Option<String>,
+    name: Option<String>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+    profile: Option<SignalProfile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct SignalProfile {
+    given_name: Option<String>,
+    family_name: Option<String>,
+}
+
+fn parse_signal(data: &[u8]) -> Result<Vec<Result<ImportedEntry, String>>> {
+    let values: Vec<serde_json::Value> = serde_json::from_slice(data)
+        .map_err(|e| anyhow::anyhow!("Not a Signal contact list: {}", e))?;
+    Ok(values.into_iter()
+        .map(|value| {
+            let contact: SignalContact = serde_json::from_value(value)
+                .map_err(|e| format!("Malformed entry: {}", e))?;
+            let joined = |given: &Option<String>, family: &Option<String>| [given, family].into_iter()
+                .flatten()
+                .map(|part| part.trim())
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ");
+            let profile = contact.profile.as_ref()
+                .map(|p| joined(&p.given_name, &p.family_name))
+                .unwrap_or_default();
+            let name = [contact.name.clone().unwrap_or_default(), joined(&contact.given_name, &contact.family_name), profile]
+                .into_iter()
+                .find(|name| !name.trim().is_empty())
+                .unwrap_or_default();
+            let handle = contact.number.as_deref()
+                .or(contact.username.as_deref())
+                .or(contact.uuid.as_deref());
+            entry(&name, handle, None)
+        })
+        .collect())
+}
+
+// ===== Matrix =====
+
+#[derive(Debug, Deserialize)]
+struct JoinedMembers {
+    joined: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RoomMember {
+    display_name: Option<String>,
+}
+
+fn parse_matrix(data: &[u8]) -> Result<Vec<Result<ImportedEntry, String>>> {
+    let members: JoinedMembers = serde_json::from_slice(data)
+        .map_err(|e| anyhow::anyhow!("Not a Matrix member list: {}", e))?;
+    Ok(members.joined.into_iter()
+        .map(|(user_id, value)| {
+            let member: RoomMember = serde_json::from_value(value)
+                .map_err(|e| format!("Malformed entry for {}: {}", user_id, e))?;
+            if !user_id.starts_with('@') || !user_id.contains(':') {
+                return Err(format!("Not a Matrix user id: {}", user_id));
+            }
+            // @alice:example.org goes by alice without a display name
+            let localpart = user_id[1..].split(':').next().unwrap_or_default();
+            let name = member.display_name.unwrap_or_else(|| localpart.to_string());
+            entry(&name, Some(&user_id), None)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vcards() {
+        let key = base64::engine::general_purpose::STANDARD.encode([7u8; 32]);
+        let data = format!("BEGIN:VCARD\r\nVERSION:4.0\r\nFN:Alice\r\n  Smith\r\nTEL;TYPE=cell:+1 555 0100\r\n\
+            item1.X-SECURECHAT-KEY:{}\r\nEND:VCARD\r\n\
+            BEGIN:VCARD\nN:Jones;Bob;;;\nEND:VCARD\n\
+            BEGIN:VCARD\nFN:Carol\nKEY:data:application/octet-stream;base64,AAAA\nEND:VCARD\n\
+            BEGIN:VCARD\nNOTE:nameless\nEND:VCARD\n\
+            BEGIN:VCARD\nFN:Dave\\, Jr.\n", key);
+        let entries = parse(data.as_bytes(), ImportFormat::Vcard).unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0], Ok(ImportedEntry {
+            display_name: "Alice Smith".to_string(),
+            handle: Some("+1 555 0100".to_string()),
+            public_key: Some([7u8; 32]),
+        }));
+        assert_eq!(entries[1].as_ref().unwrap().display_name, "Bob Jones");
+        assert!(entries[1].as_ref().unwrap().public_key.is_none());
+        assert!(entries[2].is_err());
+        assert!(entries[3].is_err());
+        assert!(entries[4].is_err());
+        assert_eq!(decode_key(&"07".repeat(32)), Some([7u8; 32]));
+    }
+
+    #[test]
+    fn test_signal_and_matrix() {
+        let signal = br#"[
+            {"number": "+15550100", "name": "Alice", "profile": {"givenName": "Ally"}},
+            {"number": "+15550101", "name": "", "profile": {"givenName": "Bob", "familyName": "Jones"}},
+            {"uuid": "5a1c"},
+            {"name": 7}
+        ]"#;
+        let entries = parse(signal, ImportFormat::Signal).unwrap();
+        assert_eq!(entries[0].as_ref().unwrap().display_name, "Alice");
+        assert_eq!(entries[1].as_ref().unwrap().display_name, "Bob Jones");
+        assert_eq!(entries[2].as_ref().unwrap().display_name, "5a1c");
+        assert!(entries[3].is_err());
+        assert!(parse(b"{}", ImportFormat::Signal).is_err());
+
+        let matrix = br#"{"joined": {
+            "@alice:example.org": {"display_name": "Alice", "avatar_url": null},
+            "@bob:example.org": {},
+            "carol": {}
+        }}"#;
+        let entries = parse(matrix, ImportFormat::Matrix).unwrap();
+        assert_eq!(entries[0].as_ref().unwrap().handle.as_deref(), Some("@alice:example.org"));
+        assert_eq!(entries[1].as_ref().unwrap().display_name, "bob");
+        assert!(entries[2].is_err());
+    }
+}
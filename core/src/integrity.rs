@@ -0,0 +1,58 @@
+//! Database integrity checks.
+//!
+//! `SecureStorage::verify_integrity` reads every record of the profile,
+//! checking it decrypts, parses as what its key says it holds, and that
+//! messages and conversations point at records that exist. Records that
+//! can't be read at all can be moved aside with
+//! `SecureStorage::quarantine_records`, so one damaged record doesn't
+//! stop the account from opening; they are kept, as stored, for recovery.
+
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Problem {
+    /// Doesn't decrypt with the storage key
+    Undecryptable,
+    /// Decrypts but isn't a valid record of its kind
+    Malformed { error: String },
+    /// Refers to a record that doesn't exist
+    Dangling { missing: String },
+    /// Sealed in a record format this build doesn't know, presumably by a
+    /// newer one. Left where it is rather than treated as damaged.
+    UnsupportedFormat { version: u8 },
+}
+
+impl Problem {
+    /// Whether the record itself is unreadable, rather than just pointing
+    /// nowhere
+    pub fn is_corrupt(&self) -> bool {
+        !matches!(self, Self::Dangling { .. } | Self::UnsupportedFormat { .. })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordProblem {
+    pub key: String,
+    pub problem: Problem,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IntegrityReport {
+    pub records_checked: usize,
+    pub problems: Vec<RecordProblem>,
+    /// Records already moved aside by earlier checks
+    pub quarantined: usize,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+    
+    /// Keys of the unreadable records
+    pub fn corrupt_keys(&self) -> impl Iterator<Item = &str> {
+        self.problems.iter()
+            .filter(|p| p.problem.is_corrupt())
+            .map(|p| p.key.as_str())
+    }
+}
@@ -0,0 +1,265 @@
+//! Invite links.
+//!
+//! An invite carries the inviter's identity key, display name and a fresh
+//! prekey, signed by the identity key and valid until `expires_at`. Whoever
+//! scans it can send a first message right away, encrypted to the prekey,
+//! without the inviter being online for a handshake. The prekey's private
+//! half is kept by the inviter until the invite expires; a one-time invite
+//! also has a token, and only the first identity to present it is accepted.
+//!
+//! Links have the form `securechat://invite#<base64url(CBOR)>`; the
+//! payload sits in the fragment so it never reaches a web server.
+
+use std::time::Duration;
+
+use anyhow::{Result, Context};
+use base64::Engine;
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+use zeroize::Zeroize;
+
+use crate::crypto::{EncryptedMessage, IdentityKeyPair, MessageKeyPair};
+use crate::protocol::wire;
+
+pub const LINK_PREFIX: &str = "securechat://invite#";
+
+/// Longest an invite may stay valid
+pub const MAX_VALIDITY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Longest display name carried in an invite
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Longest link `parse` accepts; real invites are a few hundred bytes
+pub const MAX_LINK_LEN: usize = 2048;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invite {
+    pub identity_key: [u8; 32],
+    pub display_name: String,
+    /// X25519 key the first message is encrypted to
+    pub prekey: [u8; 32],
+    /// Present on one-time invites
+    pub token: Option<[u8; 16]>,
+    pub expires_at: OffsetDateTime,
+    pub signature: Vec<u8>,
+}
+
+/// Private half of an invite prekey, kept by the inviter
+#[derive(Clone, Serialize, Deserialize)]
+pub struct InvitePrekey {
+    secret: [u8; 32],
+    pub token: Option<[u8; 16]>,
+    pub expires_at: OffsetDateTime,
+    /// Identity that used a one-time invite
+    pub redeemed_by: Option<[u8; 32]>,
+}
+
+impl Drop for InvitePrekey {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+impl std::fmt::Debug for InvitePrekey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InvitePrekey")
+            .field("secret", &"[REDACTED]")
+            .field("expires_at", &self.expires_at)
+            .field("redeemed_by", &self.redeemed_by)
+            .finish()
+    }
+}
+
+/// Prekey from an accepted invite, used for messages to the inviter until
+/// a session exists
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContactPrekey {
+    pub prekey: [u8; 32],
+    pub token: Option<[u8; 16]>,
+    pub expires_at: OffsetDateTime,
+}
+
+/// Attached to messages sent through an invite, introducing the sender
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InviteRedemption {
+    pub prekey: [u8; 32],
+    pub token: Option<[u8; 16]>,
+    pub identity_key: [u8; 32],
+    pub display_name: String,
+    /// Identity signature binding all of the above to the message
+    pub signature: Vec<u8>,
+}
+
+impl Invite {
+    /// Sign a new invite. Returns it with the private half of its prekey.
+    pub fn new(identity: &IdentityKeyPair, display_name: &str, valid_for: Duration, one_time: bool) -> Result<(Self, InvitePrekey)> {
+        if valid_for.is_zero() || valid_for > MAX_VALIDITY {
+            return Err(anyhow::anyhow!("Invites are valid for up to {} days", MAX_VALIDITY.as_secs() / 86400));
+        }
+        let secret: [u8; 32] = rand::random();
+        let private = InvitePrekey {
+            secret,
+            token: one_time.then(rand::random),
+            expires_at: OffsetDateTime::now_utc() + valid_for,
+            redeemed_by: None,
+        };
+        
+        let mut invite = Self {
+            identity_key: identity.public_key.to_bytes(),
+            display_name: display_name.chars().take(MAX_NAME_LEN).collect(),
+            prekey: private.key_pair().public_key.to_bytes(),
+            token: private.token,
+            expires_at: private.expires_at,
+            signature: Vec::new(),
+        };
+        invite.signature = identity.sign(&invite.signing_bytes()?).to_bytes().to_vec();
+        Ok((invite, private))
+    }
+    
+    fn signing_bytes(&self) -> Result<Vec<u8>> {
+        wire::encode(&(
+            b"SecureChat invite v1",
+            &self.identity_key,
+            &self.display_name,
+            &self.prekey,
+            &self.token,
+            &self.expires_at,
+        ))
+    }
+    
+    /// Check the signature and that the invite hasn't expired at `now`
+    pub fn verify(&self, now: OffsetDateTime) -> Result<()> {
+        if self.display_name.chars().count() > MAX_NAME_LEN {
+            return Err(anyhow::anyhow!("Invite display name too long"));
+        }
+        verify_signature(&self.identity_key, &self.signing_bytes()?, &self.signature)
+            .context("Invite signature is invalid")?;
+        if now >= self.expires_at {
+            return Err(anyhow::anyhow!("Invite has expired"));
+        }
+        Ok(())
+    }
+    
+    pub fn to_link(&self) -> Result<String> {
+        let payload = wire::encode(self)?;
+        Ok(format!("{}{}", LINK_PREFIX, base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(payload)))
+    }
+    
+    /// Parse and verify a link, or the bare payload as read from a QR code
+    pub fn parse(link: &str, now: OffsetDateTime) -> Result<Self> {
+        let link = link.trim();
+        if link.len() > MAX_LINK_LEN {
+            return Err(anyhow::anyhow!("Invite link too long"));
+        }
+        let encoded = link.strip_prefix(LINK_PREFIX).unwrap_or(link);
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(encoded)
+            .context("Not an invite link")?;
+        let invite: Self = wire::decode(&payload).context("Invite is corrupt")?;
+        invite.verify(now)?;
+        Ok(invite)
+    }
+}
+
+impl InvitePrekey {
+    pub fn key_pair(&self) -> MessageKeyPair {
+        MessageKeyPair::from_secret_bytes(self.secret)
+    }
+    
+    /// Whether `identity_key` may use this invite at `now`
+    pub fn accepts(&self, identity_key: &[u8; 32], token: Option<&[u8; 16]>, now: OffsetDateTime) -> bool {
+        now < self.expires_at
+            && self.token.as_ref() == token
+            && self.redeemed_by.is_none_or(|by| &by == identity_key)
+    }
+}
+
+impl InviteRedemption {
+    pub fn new(
+        identity: &IdentityKeyPair,
+        display_name: &str,
+        prekey: &ContactPrekey,
+        message_id: &str,
+        encrypted: &EncryptedMessage,
+    ) -> Result<Self> {
+        let mut redemption = Self {
+            prekey: prekey.prekey,
+            token: prekey.token,
+            identity_key: identity.public_key.to_bytes(),
+            display_name: display_name.chars().take(MAX_NAME_LEN).collect(),
+            signature: Vec::new(),
+        };
+        redemption.signature = identity.sign(&redemption.signing_bytes(message_id, encrypted)?).to_bytes().to_vec();
+        Ok(redemption)
+    }
+    
+    fn signing_bytes(&self, message_id: &str, encrypted: &EncryptedMessage) -> Result<Vec<u8>> {
+        wire::encode(&(
+            b"SecureChat invite redemption v1",
+            &self.prekey,
+            &self.token,
+            &self.identity_key,
+            &self.display_name,
+            message_id,
+            encrypted,
+        ))
+    }
+    
+    /// Check the sender's signature over the redemption and the message
+    pub fn verify(&self, message_id: &str, encrypted: &EncryptedMessage) -> Result<()> {
+        if self.display_name.chars().count() > MAX_NAME_LEN {
+            return Err(anyhow::anyhow!("Display name too long"));
+        }
+        verify_signature(&self.identity_key, &self.signing_bytes(message_id, encrypted)?, &self.signature)
+            .context("Invite redemption signature is invalid")
+    }
+}
+
+fn verify_signature(key: &[u8; 32], message: &[u8], signature: &[u8]) -> Result<()> {
+    let key = VerifyingKey::from_bytes(key).context("Invalid identity key")?;
+    let signature = Signature::from_slice(signature).context("Malformed signature")?;
+    IdentityKeyPair::verify(&key, message, &signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    
+    #[test]
+    fn test_link_roundtrip_and_expiry() {
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let (invite, private) = Invite::new(&identity, "Alice", Duration::from_secs(3600), true).unwrap();
+        assert_eq!(private.key_pair().public_key.to_bytes(), invite.prekey);
+        assert!(invite.token.is_some());
+        
+        let link = invite.to_link().unwrap();
+        assert!(link.starts_with(LINK_PREFIX));
+        let now = OffsetDateTime::now_utc();
+        assert_eq!(Invite::parse(&link, now).unwrap(), invite);
+        assert_eq!(Invite::parse(&link[LINK_PREFIX.len()..], now).unwrap(), invite);
+        assert!(Invite::parse(&link, now + Duration::from_secs(7200)).is_err());
+        
+        // Edited fields break the signature
+        let mut forged = invite.clone();
+        forged.expires_at += Duration::from_secs(86400);
+        assert!(Invite::parse(&forged.to_link().unwrap(), now).is_err());
+        
+        assert!(Invite::new(&identity, "Alice", MAX_VALIDITY + Duration::from_secs(1), false).is_err());
+    }
+    
+    #[test]
+    fn test_one_time_invite_binds_to_first_identity() {
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let (invite, mut private) = Invite::new(&identity, "Alice", Duration::from_secs(3600), true).unwrap();
+        let now = OffsetDateTime::now_utc();
+        let (bob, carol) = ([1u8; 32], [2u8; 32]);
+        
+        assert!(!private.accepts(&bob, None, now));
+        assert!(private.accepts(&bob, invite.token.as_ref(), now));
+        private.redeemed_by = Some(bob);
+        assert!(private.accepts(&bob, invite.token.as_ref(), now));
+        assert!(!private.accepts(&carol, invite.token.as_ref(), now));
+        assert!(!private.accepts(&bob, invite.token.as_ref(), invite.expires_at));
+    }
+}
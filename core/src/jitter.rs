@@ -0,0 +1,172 @@
+//! Reordering of incoming messages.
+//!
+//! Gossip delivers messages in any order. Every message is stored as soon
+//! as it decrypts, but surfacing it is held back until the messages before
+//! it in the sender's ratchet order have been surfaced, or until it has
+//! waited `MAX_HOLD` for them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::crypto::RatchetHeader;
+
+/// Longest a message waits for the ones before it
+pub const MAX_HOLD: Duration = Duration::from_secs(2);
+
+/// Most messages held per sender; past that the gap is given up on
+pub const MAX_HELD_PER_SENDER: usize = 64;
+
+pub struct JitterBuffer<T> {
+    senders: HashMap<String, SenderQueue<T>>,
+}
+
+struct SenderQueue<T> {
+    /// Last header surfaced
+    last: Option<RatchetHeader>,
+    held: BTreeMap<(u32, u32), Held<T>>,
+}
+
+struct Held<T> {
+    since: Instant,
+    header: RatchetHeader,
+    item: T,
+}
+
+impl<T> Default for JitterBuffer<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> JitterBuffer<T> {
+    pub fn new() -> Self {
+        Self { senders: HashMap::new() }
+    }
+    
+    /// Add a message; returns the messages now ready, in order
+    pub fn push(&mut self, sender: &str, header: RatchetHeader, item: T, now: Instant) -> Vec<T> {
+        let queue = self.senders.entry(sender.to_string())
+            .or_insert_with(|| SenderQueue { last: None, held: BTreeMap::new() });
+        
+        let mut ready = Vec::new();
+        match queue.last {
+            // Nothing to wait for yet, or a straggler already overtaken
+            None => {
+                queue.last = Some(header);
+                ready.push(item);
+            }
+            Some(last) if order(&header) <= order(&last) => ready.push(item),
+            Some(last) if follows(&last, &header) => {
+                queue.last = Some(header);
+                ready.push(item);
+            }
+            Some(_) => {
+                queue.held.insert(order(&header), Held { since: now, header, item });
+                if queue.held.len() > MAX_HELD_PER_SENDER {
+                    let newest = *queue.held.keys().next_back().expect("held is not empty");
+                    queue.release_through(newest, &mut ready);
+                }
+            }
+        }
+        queue.release_following(&mut ready);
+        ready
+    }
+    
+    /// Release messages that have waited longer than `MAX_HOLD`, together
+    /// with anything held before them
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let mut ready = Vec::new();
+        for queue in self.senders.values_mut() {
+            let expired = queue.held.iter()
+                .filter(|(_, held)| now.duration_since(held.since) >= MAX_HOLD)
+                .map(|(key, _)| *key)
+                .next_back();
+            if let Some(key) = expired {
+                queue.release_through(key, &mut ready);
+                queue.release_following(&mut ready);
+            }
+        }
+        ready
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.senders.values().all(|queue| queue.held.is_empty())
+    }
+}
+
+impl<T> SenderQueue<T> {
+    /// Release every held message up to and including `key`
+    fn release_through(&mut self, key: (u32, u32), ready: &mut Vec<T>) {
+        let rest = self.held.split_off(&(key.0, key.1.saturating_add(1)));
+        for (_, held) in std::mem::replace(&mut self.held, rest) {
+            self.last = Some(held.header);
+            ready.push(held.item);
+        }
+    }
+    
+    /// Release held messages for as long as each follows the last one
+    fn release_following(&mut self, ready: &mut Vec<T>) {
+        while let Some(last) = self.last {
+            let next = match self.held.first_key_value() {
+                Some((key, held)) if follows(&last, &held.header) => *key,
+                _ => break,
+            };
+            let held = self.held.remove(&next).expect("key was just found");
+            self.last = Some(held.header);
+            ready.push(held.item);
+        }
+    }
+}
+
+fn order(header: &RatchetHeader) -> (u32, u32) {
+    (header.chain, header.message_number)
+}
+
+/// Whether `next` is the message right after `last` in the sender's order
+fn follows(last: &RatchetHeader, next: &RatchetHeader) -> bool {
+    (next.chain == last.chain && next.message_number == last.message_number + 1)
+        || (next.chain == last.chain + 1
+            && next.message_number == 0
+            && next.previous_chain_length == last.message_number + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn header(chain: u32, previous_chain_length: u32, message_number: u32) -> RatchetHeader {
+        RatchetHeader { chain, previous_chain_length, message_number }
+    }
+    
+    #[test]
+    fn test_reorders_by_ratchet_counter() {
+        let mut buffer = JitterBuffer::new();
+        let now = Instant::now();
+        
+        assert_eq!(buffer.push("a", header(1, 0, 0), 0, now), vec![0]);
+        assert!(buffer.push("a", header(1, 0, 2), 2, now).is_empty());
+        assert!(buffer.push("a", header(2, 3, 0), 3, now).is_empty());
+        // Other senders aren't held up
+        assert_eq!(buffer.push("b", header(1, 0, 5), 50, now), vec![50]);
+        assert_eq!(buffer.push("a", header(1, 0, 1), 1, now), vec![1, 2, 3]);
+        assert!(buffer.is_empty());
+        
+        // A duplicate of something already surfaced passes straight through
+        assert_eq!(buffer.push("a", header(1, 0, 1), 1, now), vec![1]);
+    }
+    
+    #[test]
+    fn test_gap_expires() {
+        let mut buffer = JitterBuffer::new();
+        let now = Instant::now();
+        
+        buffer.push("a", header(1, 0, 0), 0, now);
+        assert!(buffer.push("a", header(1, 0, 3), 3, now).is_empty());
+        assert!(buffer.push("a", header(1, 0, 4), 4, now + MAX_HOLD / 2).is_empty());
+        assert!(buffer.expire(now + MAX_HOLD / 2).is_empty());
+        
+        // Message 3 gives up on 1 and 2, and 4 follows it out
+        assert_eq!(buffer.expire(now + MAX_HOLD), vec![3, 4]);
+        assert_eq!(buffer.push("a", header(1, 0, 5), 5, now + MAX_HOLD), vec![5]);
+    }
+}
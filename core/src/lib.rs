@@ -11,42 +11,233 @@ pub mod crypto;
 pub mod protocol;
 pub mod storage;
 pub mod network;
+pub mod backup;
+pub mod backup_sink;
+pub mod escrow;
+pub mod stream;
+pub mod tempstore;
+pub mod richtext;
+pub mod voice;
+pub mod stickers;
+pub mod audit;
+pub mod transparency;
+pub mod jitter;
+pub mod history;
+pub mod contact_export;
+pub mod username;
+pub mod invite;
+pub mod pairing;
+pub mod conditions;
+pub mod reconnect;
+pub mod listen;
+pub mod bootstrap;
+pub mod retention;
+pub mod usage;
+pub mod stats;
+pub mod gc;
+pub mod integrity;
+pub mod durability;
+pub mod cache;
+pub mod cancel;
+pub mod thumbnail;
+pub mod push;
+pub mod devices;
+pub mod lifecycle;
+pub mod recovery;
+pub mod query;
+pub mod search;
+pub mod postings;
+pub mod activity;
+pub mod plugins;
+pub mod webhooks;
+pub mod broadcast;
+pub mod requests;
+pub mod startup;
+pub mod puzzle;
+pub mod maintenance;
+pub mod telemetry;
+pub mod diagnostics;
+pub mod events;
+pub mod operations;
+pub mod health;
+pub mod limits;
+pub mod text;
+pub mod names;
+pub mod importer;
+pub mod transport;
+#[cfg(feature = "matrix")]
+pub mod matrix;
+#[cfg(feature = "email")]
+pub mod email;
+#[cfg(feature = "webdav")]
+pub mod webdav;
+pub mod card;
+pub mod sim;
+#[cfg(test)]
+mod harness;
 
 use anyhow::{Result, Context};
-use crypto::{IdentityKeyPair, MessageKeyPair};
-use protocol::{Contact, Conversation, LocalMessage, MessageContent, UserProfile, DeviceInfo, Platform};
-use storage::SecureStorage;
-use network::{NetworkManager, NetworkConfig, NetworkCommand, NetworkEvent};
+use audit::{SecurityEvent, SecurityEventKind};
+use crypto::{Fingerprint, IdentityKeyPair, MessageKeyPair};
+use protocol::{AttachmentRef, ChatFolder, ColorTag, Contact, ContactLabel, ContactNote, ContactSettings, ConversationPolicies, ConversationPolicy, DeliveryStatus, PrivacySettings, ProtocolMessage, Conversation, ForwardedFrom, LocalMessage, MessageContent, MessageEnvelope, QuarantinedEnvelope, UserProfile, DeviceInfo, Platform};
+use query::{ConversationPage, ConversationQuery, FolderGroup};
+use recovery::RecoveryPhrase;
+use storage::{DuressPassword, SecureStorage};
+use network::{NetworkConfig, NetworkCommand, NetworkEvent, NetworkTask};
 use time::OffsetDateTime;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, Mutex, RwLock};
 use futures::channel::mpsc as futures_mpsc;
-use futures::{SinkExt, StreamExt};
+use futures::{SinkExt, Stream, StreamExt, TryStreamExt};
+
+/// Most undecryptable envelopes kept per contact
+const MAX_QUARANTINED_PER_CONTACT: usize = 100;
 
-/// Application state
+/// Application state. Clones share the same state.
+#[derive(Clone)]
 pub struct SecureChat {
     storage: Arc<RwLock<Option<SecureStorage>>>,
+    /// Encrypted staging files, next to the database
+    temp_store: Arc<RwLock<Option<tempstore::SecureTempStore>>>,
     identity: Arc<RwLock<Option<IdentityKeyPair>>>,
     message_keys: Arc<RwLock<Option<MessageKeyPair>>>,
-    network: Arc<RwLock<Option<NetworkManager>>>,
+    network_task: Arc<RwLock<Option<NetworkTask>>>,
     network_cmd_tx: Arc<RwLock<Option<futures_mpsc::Sender<NetworkCommand>>>>,
+    /// What `start_network` starts
+    transport: Arc<RwLock<Arc<dyn network::NetworkTransport>>>,
+    /// Settings `start_network` was last called with
+    network_config: Arc<RwLock<Option<NetworkConfig>>>,
+    /// Reachability and relays as last reported by the network
+    network_status: Arc<RwLock<network::NetworkStatus>>,
     profile: Arc<RwLock<Option<UserProfile>>>,
+    events: Arc<events::EventBus>,
+    backup_task: Arc<RwLock<Option<tokio::task::JoinHandle<()>>>>,
+    retention_task: Arc<RwLock<Option<tokio::task::JoinHandle<()>>>>,
+    gc_task: Arc<RwLock<Option<tokio::task::JoinHandle<()>>>>,
+    maintenance_task: Arc<RwLock<Option<tokio::task::JoinHandle<()>>>>,
+    warm_task: Arc<RwLock<Option<tokio::task::JoinHandle<()>>>>,
+    /// What to read into the record cache after unlocking
+    cache_warming: Arc<RwLock<Option<startup::CacheWarming>>>,
+    /// Parent of the tokens of background work, cancelled by `close`
+    shutdown: cancel::CancellationToken,
+    /// Applied to storage whenever it is opened
+    durability: Arc<RwLock<durability::Durability>>,
+    /// Bounds on what peers can make us decode
+    limits: Arc<RwLock<limits::ProtocolLimits>>,
+    /// Integrity check run at unlock
+    integrity: Arc<RwLock<Option<integrity::IntegrityReport>>>,
+    /// Held while reading and rewriting records that concurrent calls would
+    /// otherwise overwrite: conversation clocks and invite prekeys
+    record_updates: Arc<Mutex<()>>,
+    /// Held shared from storing an attachment until its message refers to
+    /// it, so compaction doesn't take it for an orphan
+    attachment_writes: Arc<RwLock<()>>,
+    /// Received messages waiting for earlier ones before being reported
+    jitter: Arc<RwLock<jitter::JitterBuffer<ChatEvent>>>,
+    username_registry: Arc<RwLock<Option<Arc<dyn username::UsernameRegistry>>>>,
+    thumbnailer: Arc<RwLock<Option<Arc<dyn thumbnail::Thumbnailer>>>>,
+    push_bridge: Arc<RwLock<Option<Arc<dyn push::PushBridge>>>>,
+    /// In priority order
+    plugins: Arc<RwLock<Vec<Arc<dyn plugins::Plugin>>>>,
+    webhook_sender: Arc<RwLock<Option<Arc<dyn webhooks::WebhookSender>>>>,
+    /// Inbound webhook signatures already acted on
+    inbound_replays: Arc<webhooks::ReplayGuard>,
+    /// History sync requests already answered, and transfers in progress
+    history_requests: Arc<history::RequestGuard>,
+    /// When each contact was last sent wake-up pings
+    push_wakes: Arc<RwLock<HashMap<String, std::time::Instant>>>,
+    /// Message request writes left to unknown senders
+    request_writes: Arc<RwLock<requests::WriteLimiter>>,
+    /// Local pairing in progress, if any
+    pairing: Arc<RwLock<Option<pairing::PairingSession>>>,
+    conditions: Arc<RwLock<conditions::NetworkConditions>>,
+    /// Outgoing messages held back by the network conditions
+    deferred: Arc<RwLock<conditions::DeferredQueue>>,
+    /// Set between `on_suspend` and `on_resume`
+    suspended: Arc<RwLock<Option<lifecycle::Suspension>>>,
     device_id: String,
 }
 
+/// Most peers from earlier runs dialed when the network starts
+const MAX_REDIALED_PEERS: usize = 64;
+
+/// Result of handling one pairing message
+#[derive(Debug, Default)]
+pub struct PairingStep {
+    /// Message to send to the other device
+    pub reply: Option<pairing::PairingMessage>,
+    /// The paired contact, once the exchange is done
+    pub contact: Option<Contact>,
+}
+
 /// Event types for UI updates
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum ChatEvent {
     MessageReceived { conversation_id: String, message: LocalMessage },
-    MessageSent { conversation_id: String, message_id: String },
-    MessageDelivered { conversation_id: String, message_id: String },
-    MessageRead { conversation_id: String, message_id: String },
+    /// One of our messages moved to `status`
+    MessageStatusChanged { conversation_id: String, message_id: String, status: DeliveryStatus },
     ContactOnline { contact_id: String },
     ContactOffline { contact_id: String },
-    ContactRequestReceived { contact_id: String, display_name: String, message: String },
+    /// `fingerprint_hint` is from the requester's identity key, and
+    /// `name_warnings` tell if the name imitates someone else's
+    ContactRequestReceived {
+        contact_id: String,
+        display_name: String,
+        message: String,
+        fingerprint_hint: String,
+        name_warnings: Vec<names::NameWarning>,
+    },
     SyncCompleted,
+    /// `path` is the file written, or its URL on a remote store
+    BackupCompleted { path: PathBuf, size: u64 },
+    BackupFailed { error: String },
     Error { message: String },
+    /// An incoming message could not be decrypted and was quarantined
+    DecryptionFailed { contact_id: String, message_id: String, reason: String },
+    /// Local pairing added or verified a contact
+    PairingCompleted { contact: Contact },
+    PairingFailed { reason: String },
+    /// Redialing gave up; `ContactOffline` alone means a redial is under way
+    ContactUnreachable { contact_id: String },
+    NetworkStatusChanged { status: network::NetworkStatus },
+    /// Retention deleted old messages
+    MessagesPruned { report: retention::PruneReport },
+    CompactionProgress { progress: usage::CompactionProgress },
+    /// A sweep found orphaned records, and deleted them unless a dry run
+    GarbageCollected { report: gc::GcReport },
+    /// A contact announced a device or a new key for one
+    ContactDevicesChanged { contact_id: String },
+    /// Expired or stale key material was deleted
+    KeyMaterialCleaned { report: maintenance::MaintenanceReport },
+    /// Someone who isn't a contact sent their first message, now waiting
+    /// in the message requests
+    MessageRequestReceived { sender_id: String },
+    /// A contact asked for new restrictions in their conversation with
+    /// us. They hold on top of ours from now on.
+    ConversationPolicyRequested { conversation_id: String, policy: ConversationPolicy },
+    /// This many events were dropped because the subscriber fell behind;
+    /// see `events`
+    EventsDropped { count: u64 },
+    /// `count` security events of `kind` about `subject`, a contact or
+    /// conversation, or any, that the subscriber fell too far behind on.
+    /// They can be replayed from `first_seq` while still buffered; see
+    /// `events`.
+    SecurityEventsCoalesced { kind: String, subject: Option<String>, count: u64, first_seq: u64, last_seq: u64 },
+    /// A step of `unlock_account` finished
+    UnlockProgress { phase: startup::UnlockPhase },
+    /// An operation stopped at its cancellation token; see `cancel`
+    OperationCancelled { operation: cancel::Operation },
+    /// How far a background operation has got; see `operations`
+    OperationProgress { progress: operations::OperationProgress },
+    /// A message to or from a contact fell short of their session
+    /// requirements and was refused
+    SessionDowngradeRefused { contact_id: String, downgrade: devices::Downgrade },
+    /// A message's capability transcript didn't match what was announced,
+    /// as when a relay strips features; it was quarantined
+    CapabilityMismatch { contact_id: String, message_id: String },
 }
 
 impl SecureChat {
@@ -54,14 +245,71 @@ impl SecureChat {
     pub fn new(device_id: Option<String>) -> Self {
         Self {
             storage: Arc::new(RwLock::new(None)),
+            temp_store: Arc::new(RwLock::new(None)),
             identity: Arc::new(RwLock::new(None)),
             message_keys: Arc::new(RwLock::new(None)),
-            network: Arc::new(RwLock::new(None)),
+            network_task: Arc::new(RwLock::new(None)),
             network_cmd_tx: Arc::new(RwLock::new(None)),
+            transport: Arc::new(RwLock::new(Arc::new(network::Libp2pTransport))),
+            network_config: Arc::new(RwLock::new(None)),
+            network_status: Arc::new(RwLock::new(network::NetworkStatus::default())),
             profile: Arc::new(RwLock::new(None)),
-            device_id: device_id.unwrap_or_else(|| protocol::generate_id()),
+            events: Arc::new(events::EventBus::default()),
+            backup_task: Arc::new(RwLock::new(None)),
+            retention_task: Arc::new(RwLock::new(None)),
+            gc_task: Arc::new(RwLock::new(None)),
+            maintenance_task: Arc::new(RwLock::new(None)),
+            warm_task: Arc::new(RwLock::new(None)),
+            cache_warming: Arc::new(RwLock::new(None)),
+            shutdown: cancel::CancellationToken::new(),
+            durability: Arc::new(RwLock::new(durability::Durability::default())),
+            limits: Arc::new(RwLock::new(limits::ProtocolLimits::default())),
+            integrity: Arc::new(RwLock::new(None)),
+            record_updates: Arc::new(Mutex::new(())),
+            attachment_writes: Arc::new(RwLock::new(())),
+            jitter: Arc::new(RwLock::new(jitter::JitterBuffer::new())),
+            username_registry: Arc::new(RwLock::new(None)),
+            thumbnailer: Arc::new(RwLock::new(thumbnail::default_thumbnailer())),
+            push_bridge: Arc::new(RwLock::new(None)),
+            plugins: Arc::new(RwLock::new(Vec::new())),
+            webhook_sender: Arc::new(RwLock::new(None)),
+            inbound_replays: Arc::new(webhooks::ReplayGuard::default()),
+            history_requests: Arc::new(history::RequestGuard::default()),
+            push_wakes: Arc::new(RwLock::new(HashMap::new())),
+            request_writes: Arc::new(RwLock::new(requests::WriteLimiter::default())),
+            pairing: Arc::new(RwLock::new(None)),
+            conditions: Arc::new(RwLock::new(conditions::NetworkConditions::default())),
+            deferred: Arc::new(RwLock::new(conditions::DeferredQueue::default())),
+            suspended: Arc::new(RwLock::new(None)),
+            device_id: device_id.unwrap_or_else(protocol::generate_id),
         }
     }
+
+    /// Write the core's tracing output to stderr, filtered by `config`,
+    /// with secrets and message content redacted. Once per process.
+    pub fn init_telemetry(config: &telemetry::TelemetryConfig) -> Result<()> {
+        telemetry::init(config)
+    }
+
+    /// A handle on the open storage. Handles share the database and work
+    /// concurrently, so the lock is only held to take one.
+    async fn storage(&self) -> Result<SecureStorage> {
+        self.storage.read().await.clone()
+            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))
+    }
+    
+    async fn temp_store(&self) -> Result<tempstore::SecureTempStore> {
+        self.temp_store.read().await.clone()
+            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))
+    }
+    
+    /// Open the staging store of the database at `db_path`, removing files
+    /// a crash left there
+    async fn open_temp_store(&self, db_path: &Path) -> Result<()> {
+        let store = tempstore::SecureTempStore::open(tempstore::SecureTempStore::dir_for(db_path))?;
+        *self.temp_store.write().await = Some(store);
+        Ok(())
+    }
     
     /// Initialize database with new password (first time setup)
     pub async fn create_account<P: AsRef<Path>>(
@@ -69,24 +317,107 @@ impl SecureChat {
         db_path: P,
         password: &str,
         display_name: &str,
+    ) -> Result<()> {
+        self.create_account_with_duress(db_path, password, display_name, None).await
+    }
+    
+    /// First time setup with an optional duress password. Unlocking with
+    /// it opens a decoy profile, see `DuressAction`.
+    pub async fn create_account_with_duress<P: AsRef<Path>>(
+        &self,
+        db_path: P,
+        password: &str,
+        display_name: &str,
+        duress: Option<&DuressPassword>,
     ) -> Result<()> {
         // Create storage
-        let storage = SecureStorage::create(db_path, password)
+        let db_path = db_path.as_ref();
+        let storage = SecureStorage::create_with_duress(db_path, password, duress)
+            .context("Failed to create database")?;
+        storage.set_durability(*self.durability.read().await)?;
+        self.open_temp_store(db_path).await?;
+        
+        *self.storage.write().await = Some(storage);
+        self.init_account(display_name).await
+    }
+    
+    /// First time setup with a recovery phrase, returned to be shown to
+    /// the user once. The identity key is derived from the phrase.
+    pub async fn create_account_with_recovery<P: AsRef<Path>>(
+        &self,
+        db_path: P,
+        password: &str,
+        display_name: &str,
+        duress: Option<&DuressPassword>,
+    ) -> Result<RecoveryPhrase> {
+        let phrase = RecoveryPhrase::generate(&mut rand::thread_rng());
+        let db_path = db_path.as_ref();
+        let storage = SecureStorage::create_with_recovery(db_path, password, duress, Some(&phrase))
             .context("Failed to create database")?;
+        storage.set_durability(*self.durability.read().await)?;
+        self.open_temp_store(db_path).await?;
         
         *self.storage.write().await = Some(storage);
+        self.init_account_with(display_name, phrase.identity()?).await?;
+        Ok(phrase)
+    }
+    
+    /// Whether `phrase` is the recovery phrase of the unlocked account,
+    /// for checking the user wrote it down right
+    pub async fn verify_recovery_phrase(&self, phrase: &str) -> Result<bool> {
+        let Ok(phrase) = RecoveryPhrase::parse(phrase) else {
+            return Ok(false);
+        };
+        let identity = self.identity.read().await;
+        let identity = identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Account not unlocked"))?;
+        Ok(phrase.identity()?.public_key == identity.public_key)
+    }
+    
+    /// Rebuild an account from its recovery phrase in a new database, e.g.
+    /// on a new device after the old one was lost. The identity is the
+    /// same, so contacts recognize it; contacts and history come back from
+    /// a backup or another device.
+    pub async fn recover_account<P: AsRef<Path>>(
+        &self,
+        db_path: P,
+        phrase: &str,
+        password: &str,
+        display_name: &str,
+    ) -> Result<()> {
+        let phrase = RecoveryPhrase::parse(phrase)?;
+        let db_path = db_path.as_ref();
+        let in_use = std::fs::read_dir(db_path)
+            .map(|mut entries| entries.next().is_some())
+            .unwrap_or(false);
+        if in_use {
+            return Err(anyhow::anyhow!("A database already exists at {}", db_path.display()));
+        }
+        
+        let storage = SecureStorage::create_with_recovery(db_path, password, None, Some(&phrase))
+            .context("Failed to create database")?;
+        storage.set_durability(*self.durability.read().await)?;
+        self.open_temp_store(db_path).await?;
         
-        // Generate identity keys
+        *self.storage.write().await = Some(storage);
+        self.init_account_with(display_name, phrase.identity()?).await
+    }
+    
+    /// Create identity, profile and device records in freshly created storage
+    async fn init_account(&self, display_name: &str) -> Result<()> {
+        let identity = IdentityKeyPair::generate(&mut rand::thread_rng());
+        self.init_account_with(display_name, identity).await
+    }
+    
+    async fn init_account_with(&self, display_name: &str, identity: IdentityKeyPair) -> Result<()> {
         let mut rng = rand::thread_rng();
-        let identity = IdentityKeyPair::generate(&mut rng);
-        let master_key = self.storage.read().await.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?
-            .master_key;
-        let encrypted_identity = identity.encrypt(&master_key, &mut rng)
+        let wrap_key = self.storage().await?
+            .keys()
+            .identity_wrap;
+        let encrypted_identity = identity.encrypt(&wrap_key, &mut rng)
             .context("Failed to encrypt identity")?;
         
-        self.storage.write().await.as_mut()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?
+        self.storage().await?
             .store_identity(&encrypted_identity)?;
         *self.identity.write().await = Some(identity);
         
@@ -101,8 +432,7 @@ impl SecureChat {
             avatar: None,
             created_at: OffsetDateTime::now_utc(),
         };
-        self.storage.write().await.as_mut()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?
+        self.storage().await?
             .store_profile(&profile)?;
         *self.profile.write().await = Some(profile);
         
@@ -114,8 +444,7 @@ impl SecureChat {
             last_seen: OffsetDateTime::now_utc(),
             identity_key: encrypted_identity,
         };
-        self.storage.write().await.as_mut()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?
+        self.storage().await?
             .store_device(&device)?;
         
         Ok(())
@@ -127,23 +456,145 @@ impl SecureChat {
         db_path: P,
         password: &str,
     ) -> Result<()> {
-        // Unlock storage
-        let storage = SecureStorage::unlock(db_path, password)
+        // Argon2 runs off the async runtime
+        let db_path = db_path.as_ref().to_path_buf();
+        let password = zeroize::Zeroizing::new(password.to_string());
+        let unlock_path = db_path.clone();
+        let storage = tokio::task::spawn_blocking(move || SecureStorage::unlock(unlock_path, &password))
+            .await?
             .context("Failed to unlock database")?;
+        storage.set_durability(*self.durability.read().await)?;
+        // Staging files of an earlier run went with its key; see `tempstore`
+        self.open_temp_store(&db_path).await?;
+        let fresh_profile = storage.fresh_profile_name().map(str::to_string);
+        self.events.publish(vec![ChatEvent::UnlockProgress { phase: startup::UnlockPhase::KeyDerived }]);
+        *self.storage.write().await = Some(storage.clone());
+        
+        // The rest only needs the key, so runs side by side; see `startup`
+        let integrity = {
+            let storage = storage.clone();
+            let events = self.events.clone();
+            tokio::task::spawn_blocking(move || {
+                // Unreadable records are moved aside rather than failing the unlock
+                let report = storage.verify_integrity().context("Failed to check database integrity")?;
+                let quarantined = storage.quarantine_records(report.corrupt_keys())?;
+                if quarantined > 0 {
+                    tracing::warn!("Moved {} unreadable records aside", quarantined);
+                }
+                events.publish(vec![ChatEvent::UnlockProgress { phase: startup::UnlockPhase::IntegrityChecked }]);
+                Ok::<_, anyhow::Error>(report)
+            })
+        };
+        let conversations = {
+            let events = self.events.clone();
+            tokio::task::spawn_blocking(move || {
+                // Only to fill the cache, so failing here fails nothing
+                if let Err(e) = storage.get_all_conversations() {
+                    tracing::warn!("Failed to prefetch conversations: {:#}", e);
+                }
+                events.publish(vec![ChatEvent::UnlockProgress { phase: startup::UnlockPhase::ConversationsLoaded }]);
+            })
+        };
+        let account = async {
+            match fresh_profile {
+                Some(display_name) => self.init_account(&display_name).await?,
+                None => self.load_account().await?,
+            }
+            self.events.publish(vec![ChatEvent::UnlockProgress { phase: startup::UnlockPhase::AccountLoaded }]);
+            Ok::<_, anyhow::Error>(())
+        };
+        let (integrity, account, _) = tokio::join!(integrity, account, conversations);
+        
+        let loaded = integrity.map_err(anyhow::Error::from)
+            .and_then(|report| report)
+            .and_then(|report| account.map(|()| report));
+        let report = match loaded {
+            Ok(report) => report,
+            Err(e) => {
+                // Nothing stays half unlocked
+                *self.storage.write().await = None;
+                *self.temp_store.write().await = None;
+                *self.identity.write().await = None;
+                *self.profile.write().await = None;
+                return Err(e);
+            }
+        };
+        *self.integrity.write().await = Some(report);
+        
+        let replayed = self.replay_ingest_journal().await?;
+        if replayed > 0 {
+            tracing::info!("Replayed {} messages received before the last shutdown", replayed);
+        }
+        self.start_cache_warming().await;
+        Ok(())
+    }
+    
+    /// Read recent messages into the record cache after each unlock, or
+    /// stop with `None`. Takes effect from the next unlock.
+    pub async fn set_cache_warming(&self, warming: Option<startup::CacheWarming>) {
+        *self.cache_warming.write().await = warming;
+    }
+    
+    /// Warm the cache in the background, as set with `set_cache_warming`
+    async fn start_cache_warming(&self) {
+        self.stop_cache_warming().await;
+        let Some(warming) = *self.cache_warming.read().await else {
+            return;
+        };
+        
+        let storage = self.storage.clone();
+        let task = tokio::spawn(async move {
+            match warm_cache(&storage, warming).await {
+                Ok(messages) => tracing::debug!("Warmed the cache with {} messages", messages),
+                Err(e) => tracing::warn!("Failed to warm the cache: {:#}", e),
+            }
+        });
+        *self.warm_task.write().await = Some(task);
+    }
+    
+    async fn stop_cache_warming(&self) {
+        if let Some(task) = self.warm_task.write().await.take() {
+            task.abort();
+        }
+    }
+    
+    /// Set a new password with the recovery phrase, for a forgotten one,
+    /// and unlock the account
+    pub async fn reset_password<P: AsRef<Path>>(
+        &self,
+        db_path: P,
+        phrase: &str,
+        new_password: &str,
+    ) -> Result<()> {
+        let phrase = RecoveryPhrase::parse(phrase)?;
+        let db_path = db_path.as_ref();
+        let storage = SecureStorage::reset_password(db_path, &phrase, new_password)
+            .context("Failed to reset password")?;
+        storage.set_durability(*self.durability.read().await)?;
+        self.open_temp_store(db_path).await?;
         
         *self.storage.write().await = Some(storage);
+        self.load_account().await?;
         
+        let replayed = self.replay_ingest_journal().await?;
+        if replayed > 0 {
+            tracing::info!("Replayed {} messages received before the last shutdown", replayed);
+        }
+        Ok(())
+    }
+    
+    /// Load identity and profile from freshly opened storage
+    async fn load_account(&self) -> Result<()> {
         // Decrypt identity
-        let encrypted_identity = self.storage.read().await.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?
+        let encrypted_identity = self.storage().await?
             .get_identity()
             .context("Failed to get identity")?
             .ok_or_else(|| anyhow::anyhow!("No identity found"))?;
         
-        let master_key = self.storage.read().await.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?
-            .master_key;
-        let identity = IdentityKeyPair::decrypt(&encrypted_identity, &master_key)
+        let wrap_key = self.storage().await?
+            .keys()
+            .identity_wrap;
+        let identity = IdentityKeyPair::decrypt(&encrypted_identity, &wrap_key)
             .context("Failed to decrypt identity")?;
         
         *self.identity.write().await = Some(identity);
@@ -153,8 +604,7 @@ impl SecureChat {
         *self.message_keys.write().await = Some(message_keys);
         
         // Load profile
-        let profile = self.storage.read().await.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?
+        let profile = self.storage().await?
             .get_profile()
             .context("Failed to get profile")?;
         *self.profile.write().await = profile;
@@ -164,359 +614,7622 @@ impl SecureChat {
     
     /// Start networking
     pub async fn start_network(&self, config: NetworkConfig) -> Result<mpsc::Receiver<ChatEvent>> {
-        let (manager, event_rx, cmd_tx) = NetworkManager::new(config)
-            .context("Failed to create network manager")?;
-        
-        *self.network.write().await = Some(manager);
-        *self.network_cmd_tx.write().await = Some(cmd_tx);
-        
-        // Spawn network task
-        let network = self.network.clone();
-        tokio::spawn(async move {
-            if let Some(manager) = network.write().await.take() {
-                if let Err(e) = manager.run().await {
-                    log::error!("Network error: {}", e);
-                }
-            }
-        });
-        
-        // Convert network events to chat events
-        let (chat_tx, chat_rx) = mpsc::channel(100);
-        tokio::spawn(Self::network_event_loop(event_rx, chat_tx));
-        
-        Ok(chat_rx)
+        // Including what starting it reported
+        let from = self.events.last_seq() + 1;
+        self.run_network(config).await?;
+        Ok(self.events.attach_listener(from))
     }
     
-    /// Stop networking
-    pub async fn stop_network(&self) -> Result<()> {
-        if let Some(tx) = self.network_cmd_tx.write().await.as_mut() {
-            tx.send(NetworkCommand::Shutdown).await.ok();
-        }
-        Ok(())
+    /// Every event from sequence number `from` on that is still buffered,
+    /// then each new one as it happens, whether or not the network runs.
+    /// See `events` for the delivery guarantees.
+    pub fn subscribe_from(&self, from: u64) -> events::EventSubscription {
+        self.events.subscribe_from(from)
     }
     
-    async fn network_event_loop(
-        mut event_rx: futures_mpsc::Receiver<NetworkEvent>,
-        chat_tx: mpsc::Sender<ChatEvent>,
-    ) {
-        while let Some(event) = event_rx.next().await {
-            let chat_event = match event {
-                NetworkEvent::MessageReceived { peer_id, message } => {
-                    // Handle protocol message
-                    Self::handle_protocol_message(peer_id, message).await
-                }
-                NetworkEvent::PeerConnected { peer_id } => {
-                    Some(ChatEvent::ContactOnline { contact_id: peer_id })
-                }
-                NetworkEvent::PeerDisconnected { peer_id } => {
-                    Some(ChatEvent::ContactOffline { contact_id: peer_id })
-                }
-                _ => None,
-            };
-            
-            if let Some(evt) = chat_event {
-                chat_tx.send(evt).await.ok();
-            }
-        }
+    /// Buffered events from sequence number `from` on, for a client to
+    /// catch up with before it listens
+    pub fn replay_events(&self, from: u64) -> events::EventReplay {
+        self.events.replay(from)
     }
     
-    async fn handle_protocol_message(peer_id: String, message: protocol::ProtocolMessage) -> Option<ChatEvent> {
-        match message {
-            protocol::ProtocolMessage::ContactRequest { display_name, message: msg, .. } => {
-                Some(ChatEvent::ContactRequestReceived {
-                    contact_id: peer_id,
-                    display_name,
-                    message: msg,
-                })
-            }
-            _ => None,
-        }
+    /// Sequence number of the latest event, 0 before the first
+    pub fn latest_event_seq(&self) -> u64 {
+        self.events.last_seq()
     }
     
-    /// Send text message
-    pub async fn send_text_message(&self, conversation_id: &str, text: &str) -> Result<String> {
-        let storage = self.storage.read().await;
-        let storage_ref = storage.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
+    /// Start the network, converting its events to chat events
+    async fn run_network(&self, mut config: NetworkConfig) -> Result<()> {
+        let mut task = self.network_task.write().await;
+        if task.as_ref().is_some_and(|t| !t.is_finished()) {
+            return Err(anyhow::anyhow!("Network is already running"));
+        }
         
-        let conversation = storage_ref
-            .get_conversation(conversation_id)?
-            .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?;
+        *self.network_config.write().await = Some(config.clone());
+        config.conditions = *self.conditions.read().await;
+        config.limits = *self.limits.read().await;
+        {
+            let storage = self.storage().await?;
+            config.known_peers = storage.get_known_peers(MAX_REDIALED_PEERS)?;
+            if config.bootstrap_peers.is_empty() {
+                config.bootstrap_peers = bootstrap::dial_order(&storage.get_bootstrap_nodes()?);
+            }
+        }
+        let handle = self.transport.read().await.start(config)?;
+        let event_rx = handle.events;
+        *self.network_status.write().await = network::NetworkStatus::default();
+        *task = Some(handle.task);
+        drop(task);
+        *self.network_cmd_tx.write().await = Some(handle.commands);
         
-        let _contact = storage_ref
-            .get_contact(&conversation.contact_id)?
-            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        tokio::spawn(self.clone().network_event_loop(event_rx));
         
-        let message_id = protocol::generate_id();
-        let timestamp = OffsetDateTime::now_utc();
+        let privacy = self.get_privacy_settings().await?;
+        self.send_protocol_message(ProtocolMessage::PrivacyUpdate { settings: privacy }).await?;
+        self.send_protocol_message(ProtocolMessage::Presence { online: true }).await?;
+        self.announce_device().await?;
         
-        // Create message
-        let content = MessageContent::Text { text: text.to_string() };
-        let local_message = LocalMessage {
-            id: message_id.clone(),
-            conversation_id: conversation_id.to_string(),
-            sender_id: "self".to_string(),
-            is_outgoing: true,
-            content,
-            timestamp,
-            sent: false,
-            delivered: false,
-            read: false,
-            reply_to: None,
+        // Whatever was left over from the last time the network ran
+        let outbox = {
+            let storage = self.storage().await?;
+            storage.take_outbox()?
         };
+        for data in outbox {
+            match ProtocolMessage::decode(&data) {
+                Ok(message) => { self.send_protocol_message(message).await?; }
+                Err(e) => tracing::warn!("Dropping unreadable outbox entry: {}", e),
+            }
+        }
         
-        // Store locally
-        storage_ref.store_message(&local_message)?;
-        
-        // Encrypt for network (placeholder - real implementation would use proper X3DH)
-        // self.encrypt_and_send(&contact, &local_message).await?;
-        
-        Ok(message_id)
+        Ok(())
     }
     
-    /// Get all conversations
-    pub async fn get_conversations(&self) -> Result<Vec<Conversation>> {
-        let storage = self.storage.read().await;
-        let storage_ref = storage.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
-        storage_ref.get_all_conversations()
+    /// Latency and liveness of the peers seen since the network started.
+    /// Empty if it isn't running.
+    pub async fn get_peer_stats(&self) -> Result<Vec<network::PeerStats>> {
+        let (reply, stats) = futures::channel::oneshot::channel();
+        match self.network_cmd_tx.write().await.as_mut() {
+            Some(tx) => tx.send(NetworkCommand::GetPeerStats { reply }).await
+                .context("Network is not running")?,
+            None => return Ok(Vec::new()),
+        }
+        let mut stats = stats.await.context("Network stopped")?;
+        stats.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        Ok(stats)
     }
     
-    /// Get messages for a conversation
-    pub async fn get_messages(&self, conversation_id: &str, limit: usize) -> Result<Vec<LocalMessage>> {
-        let storage = self.storage.read().await;
-        let storage_ref = storage.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
-        storage_ref.get_messages(conversation_id, limit)
+    async fn remember_peer(&self, peer: &network::PeerInfo) -> Result<()> {
+        let storage = self.storage().await?;
+        storage.store_known_peer(peer)
     }
     
-    /// Create or get conversation with contact
-    pub async fn get_or_create_conversation(&self, contact_id: &str) -> Result<Conversation> {
-        let storage = self.storage.read().await;
-        let storage_ref = storage.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
-        
-        if let Some(conv) = storage_ref.get_conversation_by_contact(contact_id)? {
-            return Ok(conv);
+    /// Whether the network runs, whether peers can reach us directly, and
+    /// which relays we listen through otherwise
+    pub async fn get_network_status(&self) -> network::NetworkStatus {
+        let status = self.network_status.read().await;
+        self.status_with_running(&status).await
+    }
+    
+    /// Stored bootstrap nodes, in the order they are dialed
+    pub async fn get_bootstrap_nodes(&self) -> Result<Vec<bootstrap::BootstrapNode>> {
+        let storage = self.storage().await?;
+        let mut nodes = storage.get_bootstrap_nodes()?;
+        let order = bootstrap::dial_order(&nodes);
+        nodes.sort_by_key(|node| order.iter().position(|a| *a == node.address));
+        Ok(nodes)
+    }
+    
+    /// Add a bootstrap node. Once any are stored the built-in defaults
+    /// are no longer used. A running network checks the node right away.
+    pub async fn add_bootstrap_node(&self, address: &str) -> Result<bootstrap::BootstrapNode> {
+        let node = bootstrap::BootstrapNode::new(address)?;
+        {
+            let storage = self.storage().await?;
+            if let Some(existing) = storage.get_bootstrap_node(&node.address)? {
+                return Ok(existing);
+            }
+            storage.store_bootstrap_node(&node)?;
         }
-        
-        drop(storage);  // Release read lock
-        
-        let conversation = Conversation::new(contact_id.to_string());
-        let mut storage = self.storage.write().await;
-        let storage_ref = storage.as_mut()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
-        storage_ref.store_conversation(&conversation)?;
-        
-        Ok(conversation)
+        if let Some(tx) = self.network_cmd_tx.write().await.as_mut() {
+            tx.send(NetworkCommand::AddBootstrap { address: node.address.clone() }).await.ok();
+        }
+        Ok(node)
     }
     
-    /// Add contact
-    pub async fn add_contact(&self, public_key: [u8; 32], display_name: &str) -> Result<Contact> {
-        let contact = Contact::new(
-            protocol::generate_id(),
-            display_name.to_string(),
-            public_key,
-        );
-        
-        let mut storage = self.storage.write().await;
-        let storage_ref = storage.as_mut()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
-        storage_ref.store_contact(&contact)?;
-        
-        Ok(contact)
+    pub async fn remove_bootstrap_node(&self, address: &str) -> Result<()> {
+        {
+            let storage = self.storage().await?;
+            storage.delete_bootstrap_node(address)?;
+        }
+        if let Some(tx) = self.network_cmd_tx.write().await.as_mut() {
+            tx.send(NetworkCommand::RemoveBootstrap { address: address.to_string() }).await.ok();
+        }
+        Ok(())
     }
     
-    /// Get all contacts
-    pub async fn get_contacts(&self) -> Result<Vec<Contact>> {
-        let storage = self.storage.read().await;
-        let storage_ref = storage.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
-        storage_ref.get_all_contacts()
+    /// Keep the result of a bootstrap health check, if the node is stored
+    async fn record_bootstrap_check(&self, address: &str, rtt: Option<std::time::Duration>) -> Result<()> {
+        let storage = self.storage().await?;
+        if let Some(mut node) = storage.get_bootstrap_node(address)? {
+            node.record_check(rtt, OffsetDateTime::now_utc());
+            storage.store_bootstrap_node(&node)?;
+        }
+        Ok(())
     }
     
-    /// Get user profile
-    pub async fn get_profile(&self) -> Result<Option<UserProfile>> {
-        let storage = self.storage.read().await;
-        let storage_ref = storage.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
-        storage_ref.get_profile()
+    async fn status_with_running(&self, status: &network::NetworkStatus) -> network::NetworkStatus {
+        network::NetworkStatus { running: self.is_network_running().await, ..status.clone() }
     }
     
-    /// Update profile
-    pub async fn update_profile(&self, display_name: Option<&str>, status_message: Option<&str>) -> Result<()> {
-        let storage = self.storage.read().await;
-        let storage_ref = storage.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
-        
-        let mut profile = storage_ref
-            .get_profile()?
-            .unwrap_or_else(|| UserProfile {
-                display_name: "Anonymous".to_string(),
-                status_message: None,
-                avatar: None,
-                created_at: OffsetDateTime::now_utc(),
-            });
-        
-        if let Some(name) = display_name {
-            profile.display_name = name.to_string();
+    /// Use another transport from the next `start_network`, e.g. the
+    /// simulator in `sim`
+    pub async fn set_network_transport(&self, transport: Arc<dyn network::NetworkTransport>) {
+        *self.transport.write().await = transport;
+    }
+    
+    pub async fn is_network_running(&self) -> bool {
+        self.network_task.read().await.as_ref().is_some_and(|t| !t.is_finished())
+    }
+    
+    /// Stop networking. Sends already queued are given to the network
+    /// first, and whatever it couldn't deliver is kept in the outbox for
+    /// the next `start_network`.
+    pub async fn stop_network(&self) -> Result<()> {
+        // Not to be started again on resume
+        if let Some(suspension) = self.suspended.write().await.as_mut() {
+            suspension.parked = None;
         }
-        if let Some(status) = status_message {
-            profile.status_message = Some(status.to_string());
+        self.halt_network().await?;
+        self.events.detach_listener();
+        Ok(())
+    }
+    
+    /// Stop the network, keeping the event channel for a later `run_network`
+    async fn halt_network(&self) -> Result<()> {
+        self.send_protocol_message(ProtocolMessage::Presence { online: false }).await.ok();
+        if let Some(mut tx) = self.network_cmd_tx.write().await.take() {
+            tx.send(NetworkCommand::Shutdown).await.ok();
         }
+        let Some(task) = self.network_task.write().await.take() else {
+            return Ok(());
+        };
+        let unsent = match task.await.context("Network task panicked")? {
+            Ok(unsent) => unsent,
+            Err(e) => {
+                tracing::error!("Network error: {}", e);
+                Vec::new()
+            }
+        };
         
-        drop(storage);
-        
-        let mut storage = self.storage.write().await;
-        let storage_ref = storage.as_mut()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
-        storage_ref.store_profile(&profile)?;
-        *self.profile.write().await = Some(profile);
+        let outbox = unsent.iter()
+            .filter(|message| !message.is_ephemeral())
+            .map(|message| message.encode())
+            .collect::<Result<Vec<_>>>()?;
+        self.storage().await?
+            .push_outbox(&outbox)?;
         
+        // Back in the outbox, so queued again
+        for message_id in unsent.iter().filter_map(ProtocolMessage::message_id) {
+            let events = self.set_delivery_status(message_id, DeliveryStatus::Queued).await?;
+            self.emit(events).await;
+        }
         Ok(())
     }
     
-    /// Get public identity key for sharing
-    pub async fn get_public_key(&self) -> Result<[u8; 32]> {
-        let identity = self.identity.read().await;
-        let identity = identity.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
-        Ok(identity.public_key.to_bytes())
+    /// Get ready to be suspended by the OS: flush storage, pause periodic
+    /// tasks and tell contacts we are offline, parking the network if
+    /// `options.park_network`. Does nothing if already suspended.
+    pub async fn on_suspend(&self, options: lifecycle::SuspendOptions) -> Result<()> {
+        let mut suspended = self.suspended.write().await;
+        if suspended.is_some() {
+            return Ok(());
+        }
+        let running = self.is_network_running().await;
+        let parked = if options.park_network && running {
+            self.halt_network().await?;
+            self.network_config.read().await.clone()
+        } else {
+            if running {
+                self.send_protocol_message(ProtocolMessage::Presence { online: false }).await.ok();
+            }
+            None
+        };
+        if let Some(storage) = self.storage.read().await.clone() {
+            storage.flush()?;
+        }
+        *suspended = Some(lifecycle::Suspension { since: std::time::Instant::now(), parked });
+        Ok(())
     }
     
-    /// Export encrypted backup
-    pub async fn export_backup(&self, password: &str) -> Result<Vec<u8>> {
-        let storage = self.storage.read().await;
-        let storage_ref = storage.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
-        
-        // Collect all data
-        let contacts = storage_ref.get_all_contacts()?;
-        let conversations = storage_ref.get_all_conversations()?;
-        let profile = storage_ref.get_profile()?;
-        
-        // Serialize
-        let backup_data = serde_json::json!({
-            "version": 1,
-            "contacts": contacts,
-            "conversations": conversations,
-            "profile": profile,
-        });
-        
-        let json_data = serde_json::to_vec(&backup_data)?;
-        
-        // Encrypt with password
-        use crypto::MasterKey;
-        use rand::RngCore;
-        
-        let mut rng = rand::thread_rng();
-        let (master_key_store, master_key) = MasterKey::from_password(password, &mut rng)?;
-        
-        use aes_gcm::{
-            aead::{Aead, AeadCore, KeyInit},
-            Aes256Gcm, Key, Nonce,
+    /// Carry on after `on_suspend`: start a parked network again, or
+    /// announce us and redial recent peers on one that kept running
+    pub async fn on_resume(&self) -> Result<lifecycle::ResumeReport> {
+        let Some(suspension) = self.suspended.write().await.take() else {
+            return Ok(lifecycle::ResumeReport::default());
+        };
+        let mut report = lifecycle::ResumeReport {
+            suspended_for: suspension.since.elapsed(),
+            ..Default::default()
         };
         
-        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&master_key));
-        let nonce = Aes256Gcm::generate_nonce(aes_gcm::aead::OsRng);
-        let encrypted = cipher.encrypt(&nonce, json_data.as_ref())
-            .map_err(|e| anyhow::anyhow!("Encryption failed: {:?}", e))?;
-        
-        // Format: [master_key_encrypted][nonce][encrypted_data]
-        let master_key_bytes = bincode::serialize(&master_key_store)?;
-        let mut result = Vec::new();
-        result.extend_from_slice(&(master_key_bytes.len() as u32).to_be_bytes());
-        result.extend_from_slice(&master_key_bytes);
-        result.extend_from_slice(&nonce);
-        result.extend_from_slice(&encrypted);
-        
-        Ok(result)
+        if let Some(config) = suspension.parked {
+            if self.events.has_listener() {
+                self.run_network(config).await?;
+                report.network_restarted = true;
+            }
+        } else if self.is_network_running().await {
+            self.send_protocol_message(ProtocolMessage::Presence { online: true }).await?;
+            // Connections may have died while suspended, unnoticed so far
+            let peers = self.storage().await?
+                .get_known_peers(lifecycle::RESUME_DIALS)?;
+            if let Some(tx) = self.network_cmd_tx.write().await.as_mut() {
+                for addr in peers.iter().filter_map(|peer| peer.addresses.first()) {
+                    if tx.send(NetworkCommand::ConnectPeer { addr: addr.clone() }).await.is_ok() {
+                        report.redialed += 1;
+                    }
+                }
+            }
+        }
+        Ok(report)
     }
     
-    /// Close and cleanup
-    pub async fn close(self) -> Result<()> {
-        self.stop_network().await.ok();
-        // Storage will be dropped
-        Ok(())
+    pub async fn is_suspended(&self) -> bool {
+        self.suspended.read().await.is_some()
     }
-}
-
-fn detect_platform() -> Platform {
-    #[cfg(target_os = "linux")]
-    return Platform::Linux;
-    
-    #[cfg(target_os = "windows")]
-    return Platform::Windows;
     
-    #[cfg(target_os = "android")]
-    return Platform::Android;
+    async fn network_event_loop(self, mut event_rx: futures_mpsc::Receiver<NetworkEvent>) {
+        // Messages held for reordering are checked for expiry on every tick
+        let mut jitter_tick = tokio::time::interval(jitter::MAX_HOLD / 4);
+        loop {
+            let chat_events = tokio::select! {
+                event = event_rx.next() => match event {
+                    Some(NetworkEvent::MessageReceived { peer_id, message }) => {
+                        // Handle protocol message
+                        self.handle_protocol_message(peer_id, message).await
+                    }
+                    Some(NetworkEvent::PeerConnected { peer_id }) => {
+                        vec![ChatEvent::ContactOnline { contact_id: peer_id }]
+                    }
+                    Some(NetworkEvent::PeerDisconnected { peer_id }) => {
+                        vec![ChatEvent::ContactOffline { contact_id: peer_id }]
+                    }
+                    Some(NetworkEvent::PeerUnreachable { peer_id }) => {
+                        if let Err(e) = self.wake_contact(&peer_id).await {
+                            tracing::warn!("Failed to wake {}: {:#}", peer_id, e);
+                        }
+                        vec![ChatEvent::ContactUnreachable { contact_id: peer_id }]
+                    }
+                    Some(NetworkEvent::ReachabilityChanged { reachability }) => {
+                        let mut status = self.network_status.write().await;
+                        status.reachability = reachability;
+                        if status.reachability != network::Reachability::Private {
+                            status.relays.clear();
+                        }
+                        vec![ChatEvent::NetworkStatusChanged { status: self.status_with_running(&status).await }]
+                    }
+                    Some(NetworkEvent::RelayReserved { relay_peer_id }) => {
+                        let mut status = self.network_status.write().await;
+                        if !status.relays.contains(&relay_peer_id) {
+                            status.relays.push(relay_peer_id);
+                        }
+                        vec![ChatEvent::NetworkStatusChanged { status: self.status_with_running(&status).await }]
+                    }
+                    Some(NetworkEvent::PeerAddressLearned { peer }) => {
+                        if let Err(e) = self.remember_peer(&peer).await {
+                            tracing::warn!("Failed to store peer address: {}", e);
+                        }
+                        Vec::new()
+                    }
+                    Some(NetworkEvent::MessageSent { message_id }) => {
+                        self.set_delivery_status(&message_id, DeliveryStatus::Sent).await
+                            .unwrap_or_else(|e| vec![ChatEvent::Error { message: e.to_string() }])
+                    }
+                    Some(NetworkEvent::MessageFailed { message_id, reason }) => {
+                        self.set_delivery_status(&message_id, DeliveryStatus::Failed { reason }).await
+                            .unwrap_or_else(|e| vec![ChatEvent::Error { message: e.to_string() }])
+                    }
+                    Some(NetworkEvent::BootstrapChecked { address, rtt }) => {
+                        if let Err(e) = self.record_bootstrap_check(&address, rtt).await {
+                            tracing::warn!("Failed to store bootstrap check: {}", e);
+                        }
+                        Vec::new()
+                    }
+                    Some(_) => Vec::new(),
+                    None => break,
+                },
+                _ = jitter_tick.tick() => {
+                    self.jitter.write().await.expire(std::time::Instant::now())
+                }
+            };
+            
+            self.notify_webhooks(&chat_events).await;
+            self.events.publish(chat_events);
+        }
+    }
     
-    #[cfg(target_os = "macos")]
+    #[tracing::instrument(level = "debug", skip(self, message))]
+    async fn handle_protocol_message(&self, peer_id: String, message: protocol::ProtocolMessage) -> Vec<ChatEvent> {
+        match message {
+            protocol::ProtocolMessage::Encrypted { envelope } => {
+                match self.receive_envelope(envelope).await {
+                    Ok(events) => events,
+                    Err(e) => vec![ChatEvent::Error { message: e.to_string() }],
+                }
+            }
+            protocol::ProtocolMessage::ContactRequest { display_name, message: msg, key_bundle, stamp } => {
+                match self.admit_contact_request(&key_bundle, &display_name, &msg, stamp.as_ref()).await {
+                    Ok(true) => {}
+                    Ok(false) => return Vec::new(),
+                    Err(e) => return vec![ChatEvent::Error { message: e.to_string() }],
+                }
+                let ProtocolMessage::KeyBundle { identity_key, .. } = key_bundle.as_ref() else {
+                    return Vec::new();
+                };
+                let requester_id = {
+                    use base64::Engine;
+                    base64::engine::general_purpose::STANDARD.encode(identity_key)
+                };
+                let request = plugins::ContactRequest {
+                    requester_id: requester_id.clone(),
+                    display_name: display_name.clone(),
+                    message: msg.clone(),
+                };
+                let hooks = self.plugins.read().await.clone();
+                if let Some(plugin) = plugins::run_contact_request(&hooks, &request).await.suppressed_by {
+                    tracing::debug!(plugin = %plugin, "Plugin suppressed contact request");
+                    return Vec::new();
+                }
+                match self.name_warnings(&display_name, &requester_id).await {
+                    Ok(name_warnings) => vec![ChatEvent::ContactRequestReceived {
+                        contact_id: peer_id,
+                        display_name,
+                        message: msg,
+                        fingerprint_hint: Fingerprint::of_key(identity_key).short_hint(),
+                        name_warnings,
+                    }],
+                    Err(e) => vec![ChatEvent::Error { message: e.to_string() }],
+                }
+            }
+            protocol::ProtocolMessage::DeliveryReceipt { message_id, .. } => {
+                self.set_delivery_status(&message_id, DeliveryStatus::Delivered).await
+                    .unwrap_or_else(|e| vec![ChatEvent::Error { message: e.to_string() }])
+            }
+            protocol::ProtocolMessage::ReadReceipt { message_id, .. } => {
+                self.set_delivery_status(&message_id, DeliveryStatus::Read).await
+                    .unwrap_or_else(|e| vec![ChatEvent::Error { message: e.to_string() }])
+            }
+            protocol::ProtocolMessage::Pairing { message } => {
+                match self.handle_pairing_message(&message).await {
+                    Ok(step) => {
+                        if let Some(reply) = step.reply {
+                            if let Err(e) = self.send_protocol_message(ProtocolMessage::Pairing { message: reply }).await {
+                                return vec![ChatEvent::PairingFailed { reason: e.to_string() }];
+                            }
+                        }
+                        step.contact.map(|contact| ChatEvent::PairingCompleted { contact }).into_iter().collect()
+                    }
+                    Err(e) => vec![ChatEvent::PairingFailed { reason: e.to_string() }],
+                }
+            }
+            request @ protocol::ProtocolMessage::HistorySyncRequest { .. } => {
+                // Paced, so served alongside the event loop
+                let serving = self.serve_history_sync(request);
+                let events = self.events.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serving.wait().await {
+                        if !cancel::is_cancelled(&e) {
+                            events.publish(vec![ChatEvent::Error { message: e.to_string() }]);
+                        }
+                    }
+                });
+                Vec::new()
+            }
+            protocol::ProtocolMessage::DeviceAnnouncement { announcement } => {
+                match self.register_contact_device(&announcement).await {
+                    Ok(Some(contact_id)) => {
+                        // Messages from the device that came before its
+                        // announcement can be opened now
+                        if let Err(e) = self.retry_quarantine(&contact_id).await {
+                            tracing::warn!("Failed to retry quarantined messages: {:#}", e);
+                        }
+                        vec![ChatEvent::ContactDevicesChanged { contact_id }]
+                    }
+                    Ok(None) => Vec::new(),
+                    Err(e) => {
+                        tracing::warn!("Ignoring device announcement: {:#}", e);
+                        Vec::new()
+                    }
+                }
+            }
+            batch @ protocol::ProtocolMessage::HistoryBatch { .. } => {
+                match self.apply_history_batch(&batch).await {
+                    Ok(Some(applied)) if applied.remaining == 0 => vec![ChatEvent::SyncCompleted],
+                    Ok(_) => Vec::new(),
+                    Err(e) => vec![ChatEvent::Error { message: e.to_string() }],
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+    
+    /// Move one of our messages to `status` if the state machine allows
+    /// it, returning the event announcing the change. Messages we didn't
+    /// send are left alone.
+    async fn set_delivery_status(&self, message_id: &str, status: DeliveryStatus) -> Result<Vec<ChatEvent>> {
+        let storage = self.storage().await?;
+        let message_id = &storage.resolve_envelope_id(message_id)?;
+        let mut message = match storage.find_message(message_id)? {
+            Some(message) if message.is_outgoing && message.status.can_become(&status) => message,
+            _ => return Ok(Vec::new()),
+        };
+        message.status = status.clone();
+        storage.store_message(&message)?;
+        Ok(vec![ChatEvent::MessageStatusChanged {
+            conversation_id: message.conversation_id,
+            message_id: message_id.to_string(),
+            status,
+        }])
+    }
+    
+    /// Report events that happen outside the network event loop
+    async fn emit(&self, events: Vec<ChatEvent>) {
+        self.notify_webhooks(&events).await;
+        self.events.publish(events);
+    }
+    
+    /// Hand a message to the network unless the privacy settings forbid it.
+    /// Returns whether it was sent; every outgoing signal goes through here.
+    async fn send_protocol_message(&self, message: ProtocolMessage) -> Result<bool> {
+        let privacy = self.get_privacy_settings().await?;
+        if !privacy.allows(&message) {
+            return Ok(false);
+        }
+        
+        match self.conditions.read().await.disposition(&message) {
+            conditions::Disposition::Send => {}
+            conditions::Disposition::Defer => {
+                self.deferred.write().await.push(message)?;
+                return Ok(false);
+            }
+            conditions::Disposition::Drop => return Ok(false),
+        }
+        
+        let message_id = message.message_id().map(str::to_string);
+        match self.network_cmd_tx.write().await.as_mut() {
+            Some(tx) => {
+                tx.send(NetworkCommand::SendMessage { peer_id: None, message }).await
+                    .context("Network is not running")?;
+            }
+            None => {
+                // Kept for the next time the network starts
+                if !message.is_ephemeral() {
+                    let storage = self.storage().await?;
+                    storage.push_outbox(&[message.encode()?])?;
+                }
+                return Ok(false);
+            }
+        }
+        if let Some(message_id) = message_id {
+            let events = self.set_delivery_status(&message_id, DeliveryStatus::Sending).await?;
+            self.emit(events).await;
+        }
+        Ok(true)
+    }
+    
+    pub async fn network_conditions(&self) -> conditions::NetworkConditions {
+        *self.conditions.read().await
+    }
+    
+    /// Report the platform's network conditions. Messages they no longer
+    /// hold back are sent, and privacy settings and presence are announced
+    /// again if they were held back. Returns how many deferred messages went out.
+    ///
+    /// The gossip heartbeat follows the conditions from the next
+    /// `start_network`.
+    pub async fn set_network_conditions(&self, conditions: conditions::NetworkConditions) -> Result<usize> {
+        let previous = std::mem::replace(&mut *self.conditions.write().await, conditions);
+        let ready = self.deferred.write().await.take_sendable(&conditions);
+        
+        let mut sent = 0;
+        for message in ready {
+            if self.send_protocol_message(message).await? {
+                sent += 1;
+            }
+        }
+        if previous.offline && !conditions.offline {
+            let privacy = self.get_privacy_settings().await?;
+            self.send_protocol_message(ProtocolMessage::PrivacyUpdate { settings: privacy }).await?;
+        }
+        if previous.pauses_presence() && !conditions.pauses_presence() {
+            self.send_protocol_message(ProtocolMessage::Presence { online: true }).await?;
+        }
+        Ok(sent)
+    }
+    
+    /// Outgoing messages waiting for better network conditions
+    pub async fn deferred_message_count(&self) -> usize {
+        self.deferred.read().await.len()
+    }
+    
+    /// Our privacy settings
+    pub async fn get_privacy_settings(&self) -> Result<PrivacySettings> {
+        let storage = self.storage().await?;
+        storage.get_privacy_settings()
+    }
+    
+    /// Change privacy settings and tell contacts about them
+    pub async fn set_privacy_settings(&self, settings: PrivacySettings) -> Result<()> {
+        {
+            let storage = self.storage().await?;
+            storage.store_privacy_settings(&settings)?;
+        }
+        self.send_protocol_message(ProtocolMessage::PrivacyUpdate { settings }).await?;
+        Ok(())
+    }
+    
+    /// Record the privacy preferences a contact advertised
+    pub async fn apply_contact_privacy(&self, contact_id: &str, settings: PrivacySettings) -> Result<()> {
+        let storage = self.storage().await?;
+        let mut contact = storage.get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        contact.privacy = Some(settings);
+        storage.store_contact(&contact)
+    }
+    
+    /// Tell the other side we're typing; does nothing if typing indicators
+    /// are off or in the note-to-self conversation
+    pub async fn send_typing(&self, conversation_id: &str, is_typing: bool) -> Result<bool> {
+        let conversation = self.storage().await?
+            .get_conversation(conversation_id)?;
+        if conversation.is_some_and(|conversation| conversation.is_self()) {
+            return Ok(false);
+        }
+        self.send_protocol_message(ProtocolMessage::Typing {
+            conversation_id: conversation_id.to_string(),
+            is_typing,
+        }).await
+    }
+    
+    /// Mark all incoming messages in a conversation read, sending read
+    /// receipts only if they are enabled. Returns how many were marked.
+    pub async fn mark_conversation_read(&self, conversation_id: &str) -> Result<usize> {
+        let (unread, note_to_self) = {
+            let storage = self.storage().await?;
+            
+            let mut conversation = storage.get_conversation(conversation_id)?
+                .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?;
+            let mut unread = Vec::new();
+            for message in storage.messages(conversation_id) {
+                let mut message = message?;
+                if !message.is_outgoing && message.status != DeliveryStatus::Read {
+                    message.status = DeliveryStatus::Read;
+                    storage.store_message(&message)?;
+                    unread.push(message.id);
+                }
+            }
+            conversation.unread_count = 0;
+            storage.store_conversation(&conversation)?;
+            (unread, conversation.is_self())
+        };
+        if note_to_self {
+            return Ok(unread.len());
+        }
+        
+        let timestamp = OffsetDateTime::now_utc();
+        for message_id in &unread {
+            self.send_protocol_message(ProtocolMessage::ReadReceipt {
+                message_id: message_id.clone(),
+                timestamp,
+            }).await?;
+        }
+        Ok(unread.len())
+    }
+    
+    /// Send text message
+    pub async fn send_text_message(&self, conversation_id: &str, text: &str) -> Result<String> {
+        let content = MessageContent::Text { text: text.to_string() };
+        self.send_content(conversation_id, content, None).await
+    }
+    
+    /// Send formatted text written in Markdown (see `richtext`)
+    pub async fn send_rich_text(&self, conversation_id: &str, markdown: &str) -> Result<String> {
+        let content = MessageContent::RichText(richtext::RichText::from_markdown(markdown));
+        self.send_content(conversation_id, content, None).await
+    }
+    
+    /// Send a file. The bytes go to the blob store and the message refers to them.
+    pub async fn send_file(
+        &self,
+        conversation_id: &str,
+        data: &[u8],
+        filename: &str,
+        mime_type: &str,
+    ) -> Result<String> {
+        let _writing = self.attachment_writes.read().await;
+        let attachment = self.storage().await?
+            .store_blob(data)?;
+        
+        let content = MessageContent::File {
+            attachment,
+            filename: filename.to_string(),
+            mime_type: mime_type.to_string(),
+        };
+        self.send_content(conversation_id, content, None).await
+    }
+    
+    /// Send a file read from `reader`, which may be larger than memory:
+    /// it is streamed into the blob store a chunk at a time. Cancelling
+    /// `cancel` before it is stored removes what was, and sends nothing.
+    pub async fn send_file_from<R: std::io::Read>(
+        &self,
+        conversation_id: &str,
+        reader: R,
+        filename: &str,
+        mime_type: &str,
+        cancel: &cancel::CancellationToken,
+    ) -> Result<String> {
+        let _writing = self.attachment_writes.read().await;
+        let stored = self.storage().await
+            .and_then(|storage| storage.store_blob_from(reader, cancel));
+        let attachment = self.report_cancelled(cancel::Operation::AttachmentUpload, stored)?;
+        
+        let content = MessageContent::File {
+            attachment,
+            filename: filename.to_string(),
+            mime_type: mime_type.to_string(),
+        };
+        self.send_content(conversation_id, content, None).await
+    }
+    
+    /// Send the file at `path` in the background, reporting the bytes
+    /// read; the message is sent once all are stored. The file is staged
+    /// encrypted first, so a slow source doesn't hold off garbage
+    /// collection while it is read.
+    pub async fn start_file_upload(
+        &self,
+        conversation_id: &str,
+        path: PathBuf,
+        mime_type: &str,
+    ) -> Result<operations::OperationHandle<String>> {
+        let file = std::fs::File::open(&path).context("Failed to open file")?;
+        let total = file.metadata()?.len();
+        let filename = path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let (conversation_id, mime_type) = (conversation_id.to_string(), mime_type.to_string());
+        let temp = self.temp_store().await?;
+        
+        let chat = self.clone();
+        Ok(self.start_operation(cancel::Operation::AttachmentUpload, operations::ProgressUnit::Bytes, Some(total), move |mut tracker, cancel| async move {
+            let reader = operations::ProgressReader::new(std::io::BufReader::new(file), &mut tracker);
+            let staged = temp.stage_from(reader, &cancel);
+            let staged = chat.report_cancelled(cancel::Operation::AttachmentUpload, staged)?;
+            chat.send_file_from(&conversation_id, staged.reader()?, &filename, &mime_type, &cancel).await
+        }))
+    }
+    
+    /// Send an image or video, with a thumbnail if a thumbnailer is set
+    pub async fn send_image(
+        &self,
+        conversation_id: &str,
+        data: &[u8],
+        mime_type: &str,
+        caption: Option<&str>,
+    ) -> Result<String> {
+        if self.effective_policy(conversation_id).await?.view_once_media {
+            if caption.is_some() {
+                return Err(anyhow::anyhow!("This conversation only allows view-once media, which has no caption"));
+            }
+            return self.send_view_once_image(conversation_id, data, mime_type).await;
+        }
+        let _writing = self.attachment_writes.read().await;
+        let attachment = self.storage().await?
+            .store_blob(data)?;
+        
+        let content = MessageContent::Image {
+            attachment,
+            mime_type: mime_type.to_string(),
+            caption: caption.map(str::to_string),
+        };
+        self.send_content(conversation_id, content, None).await
+    }
+    
+    /// Whether `content` has an attachment sent or received view-once
+    async fn is_view_once(&self, content: &MessageContent) -> Result<bool> {
+        match content.attachment() {
+            Some(attachment) => Ok(self.storage().await?.get_view_once(attachment)?.is_some()),
+            None => Ok(false),
+        }
+    }
+    
+    /// Application code that makes thumbnails of images and videos, in
+    /// place of the built-in one if the `image` feature is on
+    pub async fn set_thumbnailer(&self, thumbnailer: Arc<dyn thumbnail::Thumbnailer>) {
+        *self.thumbnailer.write().await = Some(thumbnailer);
+    }
+    
+    /// Thumbnail of a message's attachment, sent or received with it
+    pub async fn get_thumbnail(&self, message_id: &str) -> Result<Option<thumbnail::Thumbnail>> {
+        let storage = self.storage().await?;
+        let message = storage.find_message(message_id)?
+            .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+        storage.get_thumbnail(&message.conversation_id, &message.id)
+    }
+    
+    /// Add a plugin, called on messages from now on; see `plugins`
+    pub async fn register_plugin(&self, plugin: Arc<dyn plugins::Plugin>) -> Result<()> {
+        plugins::register(&mut *self.plugins.write().await, plugin)
+    }
+    
+    /// Returns false if no plugin has the name
+    pub async fn unregister_plugin(&self, name: &str) -> bool {
+        let mut plugins = self.plugins.write().await;
+        let before = plugins.len();
+        plugins.retain(|plugin| plugin.name() != name);
+        plugins.len() != before
+    }
+    
+    /// Notes plugins made on a message
+    pub async fn get_message_annotations(&self, message_id: &str) -> Result<Vec<plugins::Annotation>> {
+        let storage = self.storage().await?;
+        let message = storage.find_message(message_id)?
+            .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+        storage.get_annotations(&message.conversation_id, &message.id)
+    }
+    
+    /// Thumbnail of `content`'s attachment if it is an image or video
+    /// stored here and a thumbnailer is set. Failing to make one never
+    /// fails the message.
+    async fn make_thumbnail(&self, content: &MessageContent) -> Option<thumbnail::Thumbnail> {
+        let (attachment, mime_type) = match content {
+            MessageContent::Image { attachment, mime_type, .. }
+            | MessageContent::File { attachment, mime_type, .. } => (attachment, mime_type),
+            _ => return None,
+        };
+        if !thumbnail::wants_thumbnail(mime_type) || self.is_view_once(content).await.unwrap_or(true) {
+            return None;
+        }
+        let thumbnailer = self.thumbnailer.read().await.clone()?;
+        let made = async {
+            let Some(data) = self.storage().await?.get_blob(attachment)? else {
+                return Ok(None);
+            };
+            let thumbnail = thumbnailer.thumbnail(&data, mime_type, thumbnail::THUMBNAIL_EDGE)?;
+            if let Some(thumbnail) = &thumbnail {
+                thumbnail.validate()?;
+            }
+            anyhow::Ok(thumbnail)
+        };
+        made.await.unwrap_or_else(|e| {
+            tracing::warn!("No thumbnail for {} attachment: {:#}", mime_type, e);
+            None
+        })
+    }
+    
+    /// Send a voice note, streaming the encoded audio into the attachment
+    /// store; `metadata` comes from a `voice::VoiceAnalyzer` run while recording
+    pub async fn send_voice_note<R: std::io::Read>(
+        &self,
+        conversation_id: &str,
+        audio: R,
+        metadata: voice::VoiceMetadata,
+    ) -> Result<String> {
+        if self.effective_policy(conversation_id).await?.view_once_media {
+            return self.send_view_once_voice_note(conversation_id, audio, metadata).await;
+        }
+        let _writing = self.attachment_writes.read().await;
+        let attachment = self.store_voice_note(audio).await?;
+        
+        let content = MessageContent::Voice {
+            attachment,
+            duration_secs: metadata.duration_secs,
+            waveform: metadata.waveform,
+        };
+        self.send_content(conversation_id, content, None).await
+    }
+    
+    /// Stream encoded audio into the attachment store
+    async fn store_voice_note<R: std::io::Read>(&self, audio: R) -> Result<AttachmentRef> {
+        self.storage().await?
+            .store_blob_from(audio, &cancel::CancellationToken::new())
+            .context("Failed to store voice note")
+    }
+    
+    /// Send an image the recipient can open once, after which their
+    /// device deletes it. Unlike `send_image` it has no caption or
+    /// thumbnail, which would outlive it.
+    pub async fn send_view_once_image(&self, conversation_id: &str, data: &[u8], mime_type: &str) -> Result<String> {
+        let _writing = self.attachment_writes.read().await;
+        let attachment = {
+            let storage = self.storage().await?;
+            let attachment = storage.store_blob(data)?;
+            storage.mark_view_once(&attachment)?;
+            attachment
+        };
+        
+        let content = MessageContent::Image {
+            attachment,
+            mime_type: mime_type.to_string(),
+            caption: None,
+        };
+        self.send_content(conversation_id, content, None).await
+    }
+    
+    /// Send a voice note the recipient can play once, see
+    /// `send_view_once_image`
+    pub async fn send_view_once_voice_note<R: std::io::Read>(
+        &self,
+        conversation_id: &str,
+        audio: R,
+        metadata: voice::VoiceMetadata,
+    ) -> Result<String> {
+        let _writing = self.attachment_writes.read().await;
+        let attachment = self.store_voice_note(audio).await?;
+        self.storage().await?.mark_view_once(&attachment)?;
+        
+        let content = MessageContent::Voice {
+            attachment,
+            duration_secs: metadata.duration_secs,
+            waveform: metadata.waveform,
+        };
+        self.send_content(conversation_id, content, None).await
+    }
+    
+    /// Open a view-once image or voice note we received. Its bytes are
+    /// returned this one time: the media is then deleted, won't be stored
+    /// if it arrives again, and a system message in the conversation
+    /// records the opening. Media still downloading is left unopened.
+    pub async fn open_view_once(&self, message_id: &str) -> Result<Vec<u8>> {
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        let message = storage.find_message(message_id)?
+            .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+        let attachment = message.content.attachment()
+            .ok_or_else(|| anyhow::anyhow!("Message is not view-once"))?;
+        let view_once = storage.get_view_once(attachment)?
+            .ok_or_else(|| anyhow::anyhow!("Message is not view-once"))?;
+        if message.is_outgoing {
+            return Err(anyhow::anyhow!("Sent view-once media can't be opened"));
+        }
+        if view_once.viewed_at.is_some() {
+            return Err(anyhow::anyhow!("View-once media was already opened"));
+        }
+        let data = storage.get_blob(attachment)?
+            .ok_or_else(|| anyhow::anyhow!("View-once media has not finished downloading"))?;
+        
+        let now = OffsetDateTime::now_utc();
+        storage.consume_view_once(attachment, now)?;
+        let mut conversation = storage.get_conversation(&message.conversation_id)?
+            .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?;
+        let lamport = conversation.tick();
+        storage.store_conversation(&conversation)?;
+        storage.store_message(&LocalMessage {
+            id: protocol::generate_id(),
+            conversation_id: conversation.id.clone(),
+            sender_id: "self".to_string(),
+            is_outgoing: false,
+            content: MessageContent::System {
+                notice: protocol::SystemNotice::ViewOnceOpened { message_id: message.id.clone() },
+            },
+            timestamp: now,
+            status: DeliveryStatus::Read,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport,
+        })?;
+        Ok(data)
+    }
+    
+    /// Read one chunk of an attachment so playback can start before the
+    /// download completes. None means the chunk hasn't arrived yet.
+    pub async fn read_attachment_chunk(&self, attachment: &AttachmentRef, index: u32) -> Result<Option<Vec<u8>>> {
+        let storage = self.storage().await?;
+        if storage.get_view_once(attachment)?.is_some() {
+            return Err(anyhow::anyhow!("View-once media can only be opened with open_view_once"));
+        }
+        storage.get_blob_chunk(attachment, index)
+    }
+    
+    /// Write a complete attachment to `writer` a chunk at a time, returning
+    /// its size. Fails if it isn't fully downloaded, doesn't match its
+    /// digest or `cancel` is cancelled, in which case what was written
+    /// must be discarded.
+    pub async fn save_attachment<W: std::io::Write>(
+        &self,
+        attachment: &AttachmentRef,
+        mut writer: W,
+        cancel: &cancel::CancellationToken,
+    ) -> Result<u64> {
+        let storage = self.storage().await?;
+        if storage.get_view_once(attachment)?.is_some() {
+            return Err(anyhow::anyhow!("View-once media can only be opened with open_view_once"));
+        }
+        let mut reader = storage.blob_reader(attachment)?
+            .ok_or_else(|| anyhow::anyhow!("Attachment has not finished downloading"))?;
+        
+        let copied = (|| -> Result<u64> {
+            use std::io::Read;
+            let mut buffer = vec![0u8; protocol::ATTACHMENT_CHUNK_SIZE];
+            let mut written = 0u64;
+            loop {
+                cancel.check()?;
+                let read = match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e).context("Failed to save attachment"),
+                };
+                writer.write_all(&buffer[..read])?;
+                written += read as u64;
+            }
+            writer.flush()?;
+            Ok(written)
+        })();
+        self.report_cancelled(cancel::Operation::AttachmentDownload, copied)
+    }
+    
+    /// Save a complete attachment to the file at `path` in the background,
+    /// reporting the bytes read from the blob store. It is staged
+    /// encrypted until its digest has checked out, and the file only
+    /// appears once complete.
+    pub async fn start_attachment_save(&self, attachment: &AttachmentRef, path: PathBuf) -> Result<operations::OperationHandle<u64>> {
+        if !self.storage().await?.has_blob(attachment)? {
+            return Err(anyhow::anyhow!("Attachment has not finished downloading"));
+        }
+        let attachment = attachment.clone();
+        let temp = self.temp_store().await?;
+        
+        let chat = self.clone();
+        Ok(self.start_operation(cancel::Operation::AttachmentDownload, operations::ProgressUnit::Bytes, Some(attachment.size), move |mut tracker, cancel| async move {
+            let mut staged = temp.create()?;
+            let size = chat.save_attachment(&attachment, operations::ProgressWriter::new(&mut staged, &mut tracker), &cancel).await?;
+            let staged = staged.finish()?;
+            
+            let partial = partial_path(&path);
+            let saved = (|| -> Result<u64> {
+                let file = std::fs::File::create(&partial).context("Failed to create file")?;
+                staged.copy_to(std::io::BufWriter::new(&file), &cancel)?;
+                file.sync_all()?;
+                std::fs::rename(&partial, &path)?;
+                Ok(size)
+            })();
+            if saved.is_err() {
+                std::fs::remove_file(&partial).ok();
+            }
+            chat.report_cancelled(cancel::Operation::AttachmentDownload, saved)
+        }))
+    }
+    
+    /// Install a sticker pack from images and their optional emoji
+    pub async fn install_sticker_pack(
+        &self,
+        title: &str,
+        author: &str,
+        images: Vec<(Vec<u8>, Option<String>)>,
+    ) -> Result<stickers::StickerPack> {
+        let storage = self.storage().await?;
+        
+        if images.iter().any(|(data, _)| data.len() as u64 > stickers::MAX_STICKER_SIZE) {
+            return Err(anyhow::anyhow!("Sticker image too large"));
+        }
+        let mut pack_stickers = Vec::with_capacity(images.len());
+        for (data, emoji) in images {
+            let image = storage.store_blob(&data)?;
+            pack_stickers.push(stickers::Sticker { emoji, image });
+        }
+        
+        let pack = stickers::StickerPack::new(title, author, pack_stickers)?;
+        storage.store_sticker_pack(&pack)?;
+        Ok(pack)
+    }
+    
+    /// Install a pack a contact shared, once its manifest has downloaded
+    pub async fn install_shared_sticker_pack(&self, message_id: &str) -> Result<stickers::StickerPack> {
+        let storage = self.storage().await?;
+        
+        let message = storage.find_message(message_id)?
+            .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+        let MessageContent::StickerPack { pack_id, manifest, .. } = &message.content else {
+            return Err(anyhow::anyhow!("Message is not a sticker pack"));
+        };
+        let manifest = storage.get_blob(manifest)?
+            .ok_or_else(|| anyhow::anyhow!("Sticker pack has not finished downloading"))?;
+        
+        let pack = stickers::StickerPack::from_manifest(&manifest)?;
+        if &pack.id != pack_id {
+            return Err(anyhow::anyhow!("Sticker pack does not match its id"));
+        }
+        storage.store_sticker_pack(&pack)?;
+        Ok(pack)
+    }
+    
+    /// Remove an installed sticker pack
+    pub async fn remove_sticker_pack(&self, pack_id: &[u8; 32]) -> Result<()> {
+        let storage = self.storage().await?;
+        storage.delete_sticker_pack(pack_id)
+    }
+    
+    /// Installed sticker packs, by title
+    pub async fn get_sticker_packs(&self) -> Result<Vec<stickers::StickerPack>> {
+        let storage = self.storage().await?;
+        storage.get_all_sticker_packs()
+    }
+    
+    /// Send a sticker from an installed pack
+    pub async fn send_sticker(&self, conversation_id: &str, pack_id: &[u8; 32], sticker_digest: &[u8; 32]) -> Result<String> {
+        let content = {
+            let storage = self.storage().await?;
+            let pack = storage.get_sticker_pack(pack_id)?
+                .ok_or_else(|| anyhow::anyhow!("Sticker pack not installed"))?;
+            let sticker = pack.sticker(sticker_digest)
+                .ok_or_else(|| anyhow::anyhow!("Sticker not in pack"))?;
+            MessageContent::Sticker {
+                pack_id: pack.id,
+                image: sticker.image.clone(),
+                emoji: sticker.emoji.clone(),
+            }
+        };
+        self.send_content(conversation_id, content, None).await
+    }
+    
+    /// Share an installed sticker pack with a conversation
+    pub async fn share_sticker_pack(&self, conversation_id: &str, pack_id: &[u8; 32]) -> Result<String> {
+        let content = {
+            let storage = self.storage().await?;
+            let pack = storage.get_sticker_pack(pack_id)?
+                .ok_or_else(|| anyhow::anyhow!("Sticker pack not installed"))?;
+            MessageContent::StickerPack {
+                pack_id: pack.id,
+                title: pack.title.clone(),
+                manifest: storage.store_blob(&pack.to_manifest()?)?,
+            }
+        };
+        self.send_content(conversation_id, content, None).await
+    }
+    
+    /// Get the bytes of an attachment
+    pub async fn get_attachment(&self, attachment: &AttachmentRef) -> Result<Vec<u8>> {
+        let storage = self.storage().await?;
+        if storage.get_view_once(attachment)?.is_some() {
+            return Err(anyhow::anyhow!("View-once media can only be opened with open_view_once"));
+        }
+        storage.get_blob(attachment)?
+            .ok_or_else(|| anyhow::anyhow!("Attachment not found"))
+    }
+    
+    /// Bookmark a message
+    pub async fn star_message(&self, message_id: &str) -> Result<()> {
+        self.set_message_starred(message_id, true).await
+    }
+    
+    /// Remove a bookmark
+    pub async fn unstar_message(&self, message_id: &str) -> Result<()> {
+        self.set_message_starred(message_id, false).await
+    }
+    
+    async fn set_message_starred(&self, message_id: &str, starred: bool) -> Result<()> {
+        let storage = self.storage().await?;
+        let message = storage.find_message(message_id)?
+            .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+        storage.set_message_starred(&message.conversation_id, message_id, starred)
+    }
+    
+    /// All starred messages, newest first
+    pub async fn get_starred_messages(&self) -> Result<Vec<LocalMessage>> {
+        let storage = self.storage().await?;
+        storage.get_starred_messages()
+    }
+    
+    /// Sent messages not yet delivered, and those that failed, across all
+    /// conversations, oldest first
+    pub async fn get_undelivered_messages(&self) -> Result<Vec<LocalMessage>> {
+        let storage = self.storage().await?;
+        storage.get_undelivered_messages()
+    }
+    
+    /// Forward a message into other conversations. Each copy is a new message
+    /// sent over the target's own session; attachments are shared, not copied.
+    /// With `strip_provenance` the copies carry no forwarded-from marker.
+    pub async fn forward_message(
+        &self,
+        message_id: &str,
+        target_conversation_ids: &[String],
+        strip_provenance: bool,
+    ) -> Result<Vec<String>> {
+        let original = {
+            let storage = self.storage().await?;
+            
+            // Check every target first so a bad id doesn't leave a partial forward
+            for conversation_id in target_conversation_ids {
+                storage.get_conversation(conversation_id)?
+                    .ok_or_else(|| anyhow::anyhow!("Conversation not found: {}", conversation_id))?;
+            }
+            
+            let original = storage.find_message(message_id)?
+                .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+            if let Some(attachment) = original.content.attachment() {
+                if storage.get_view_once(attachment)?.is_some() {
+                    return Err(anyhow::anyhow!("View-once messages can't be forwarded"));
+                }
+            }
+            let policy = storage.get_conversation_policy(&original.conversation_id)?.unwrap_or_default();
+            if policy.effective().no_forwarding {
+                return Err(anyhow::anyhow!("This conversation doesn't allow forwarding"));
+            }
+            original
+        };
+        
+        // Forwarding a forward keeps pointing at the original author
+        let forwarded_from = if strip_provenance {
+            None
+        } else {
+            Some(original.forwarded_from.clone().unwrap_or(ForwardedFrom {
+                sender_id: original.sender_id.clone(),
+                timestamp: original.timestamp,
+            }))
+        };
+        
+        let mut message_ids = Vec::with_capacity(target_conversation_ids.len());
+        for conversation_id in target_conversation_ids {
+            let id = self.send_content(conversation_id, original.content.clone(), forwarded_from.clone()).await?;
+            message_ids.push(id);
+        }
+        Ok(message_ids)
+    }
+    
+    /// Ask for restrictions in a conversation. They hold here at once, and
+    /// on the contact's side from our next message, which carries them.
+    pub async fn set_conversation_policy(&self, conversation_id: &str, policy: ConversationPolicy) -> Result<()> {
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        storage.get_conversation(conversation_id)?
+            .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?;
+        let mut policies = storage.get_conversation_policy(conversation_id)?.unwrap_or_default();
+        policies.ours = policy;
+        storage.store_conversation_policy(conversation_id, &policies)
+    }
+    
+    /// Choose how our messages in a conversation are authenticated, see
+    /// `devices::Authentication`
+    pub async fn set_message_authentication(&self, conversation_id: &str, authentication: devices::Authentication) -> Result<()> {
+        let storage = self.storage().await?;
+        storage.get_conversation(conversation_id)?
+            .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?;
+        storage.store_authentication(conversation_id, authentication)
+    }
+    
+    pub async fn get_message_authentication(&self, conversation_id: &str) -> Result<devices::Authentication> {
+        Ok(self.storage().await?
+            .get_authentication(conversation_id)?
+            .unwrap_or_default())
+    }
+    
+    /// Both sides' restrictions in a conversation
+    pub async fn get_conversation_policy(&self, conversation_id: &str) -> Result<ConversationPolicies> {
+        Ok(self.storage().await?
+            .get_conversation_policy(conversation_id)?
+            .unwrap_or_default())
+    }
+    
+    async fn effective_policy(&self, conversation_id: &str) -> Result<ConversationPolicy> {
+        Ok(self.get_conversation_policy(conversation_id).await?.effective())
+    }
+    
+    /// A conversation as plain text, one message per line, oldest first.
+    /// Where it doesn't allow forwarding, the contact's messages are
+    /// redacted. Attachments are only named.
+    pub async fn export_conversation(&self, conversation_id: &str) -> Result<String> {
+        use std::fmt::Write;
+        use time::format_description::well_known::Rfc3339;
+        
+        let storage = self.storage().await?;
+        let conversation = storage.get_conversation(conversation_id)?
+            .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?;
+        let contact_name = match storage.get_contact(&conversation.contact_id)? {
+            Some(contact) => contact.display_name,
+            None => "Me".to_string(),
+        };
+        let redact = storage.get_conversation_policy(conversation_id)?
+            .unwrap_or_default()
+            .effective()
+            .no_forwarding;
+        
+        let mut transcript = String::new();
+        for message in storage.messages(conversation_id) {
+            let message = message?;
+            let (sender, text) = match &message.content {
+                MessageContent::System { .. } => ("*", message.preview_text()),
+                _ if message.is_outgoing => ("Me", export_text(&message)),
+                _ if redact => (contact_name.as_str(), "[redacted]".to_string()),
+                _ => (contact_name.as_str(), export_text(&message)),
+            };
+            writeln!(transcript, "[{}] {}: {}", message.timestamp.format(&Rfc3339)?, sender, text)?;
+        }
+        Ok(transcript)
+    }
+    
+    /// Store an outgoing message and hand it to the conversation's session
+    #[tracing::instrument(level = "debug", skip(self, content, forwarded_from))]
+    async fn send_content(
+        &self,
+        conversation_id: &str,
+        content: MessageContent,
+        forwarded_from: Option<ForwardedFrom>,
+    ) -> Result<String> {
+        if matches!(content, MessageContent::System { .. }) {
+            return Err(anyhow::anyhow!("System messages are never sent"));
+        }
+        let mut outgoing = plugins::OutgoingMessage { conversation_id: conversation_id.to_string(), content };
+        let hooks = self.plugins.read().await.clone();
+        let outcome = plugins::run_outgoing(&hooks, &mut outgoing).await;
+        if let Some(plugin) = outcome.suppressed_by {
+            return Err(anyhow::anyhow!("Suppressed by plugin {}", plugin));
+        }
+        let content = outgoing.content;
+        let thumbnail = self.make_thumbnail(&content).await;
+        // So concurrent sends each get their own clock value
+        let updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        
+        let mut conversation = storage
+            .get_conversation(conversation_id)?
+            .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?;
+        
+        // Notes to self go nowhere; history sync carries them to our
+        // other devices
+        let note_to_self = conversation.is_self();
+        if !note_to_self {
+            storage
+                .get_contact(&conversation.contact_id)?
+                .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        }
+        
+        let message_id = protocol::generate_id();
+        let timestamp = OffsetDateTime::now_utc();
+        let lamport = conversation.tick();
+        storage.store_conversation(&conversation)?;
+        
+        // Create message
+        let local_message = LocalMessage {
+            id: message_id.clone(),
+            conversation_id: conversation_id.to_string(),
+            sender_id: "self".to_string(),
+            is_outgoing: true,
+            content,
+            timestamp,
+            status: if note_to_self { DeliveryStatus::Read } else { DeliveryStatus::Queued },
+            reply_to: None,
+            forwarded_from,
+            starred: false,
+            lamport,
+        };
+        
+        // Store locally
+        storage.store_message(&local_message)?;
+        if let Some(thumbnail) = &thumbnail {
+            storage.store_thumbnail(conversation_id, &message_id, thumbnail)?;
+        }
+        if !outcome.annotations.is_empty() {
+            storage.add_annotations(conversation_id, &message_id, &outcome.annotations)?;
+        }
+        storage.flush_message_writes()?;
+        drop(updates);
+        
+        if !note_to_self {
+            self.dispatch_message(&conversation.contact_id, &local_message, &message_id).await?;
+        }
+        Ok(message_id)
+    }
+    
+    /// Seal a stored message for its contact and hand it to the network
+    /// as envelope `envelope_id`
+    #[tracing::instrument(level = "debug", skip(self, message))]
+    async fn dispatch_message(&self, contact_id: &str, message: &LocalMessage, envelope_id: &str) -> Result<()> {
+        let (contact, contact_prekey, requirements, devices) = {
+            let storage = self.storage().await?;
+            let contact = storage.get_contact(contact_id)?
+                .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+            (
+                contact,
+                storage.get_contact_prekey(contact_id)?,
+                storage.get_session_requirements(contact_id)?.unwrap_or_default(),
+                storage.get_contact_devices(contact_id)?,
+            )
+        };
+        
+        let downgrade = if requirements.verified && !contact.verified {
+            Some(devices::Downgrade::Unverified)
+        } else if requirements.device_sessions && devices.is_empty() && contact_prekey.is_some() {
+            Some(devices::Downgrade::InvitePrekey)
+        } else {
+            None
+        };
+        if let Some(downgrade) = downgrade {
+            let mut events = vec![self.refuse_downgrade(contact_id, downgrade).await?];
+            events.extend(self.set_delivery_status(&message.id, DeliveryStatus::Failed {
+                reason: "Refused by the contact's session requirements".to_string(),
+            }).await?);
+            self.emit(events).await;
+            return Err(anyhow::anyhow!("Message refused by the contact's session requirements"));
+        }
+        
+        // Until a session exists, a contact added through an invite is
+        // reached through the invite's prekey
+        if let Some(prekey) = contact_prekey.filter(|_| !requirements.device_sessions) {
+            if prekey.expires_at > OffsetDateTime::now_utc() {
+                let envelope = self.seal_invite_message(contact_id, &prekey, message, envelope_id).await?;
+                self.send_protocol_message(ProtocolMessage::Encrypted { envelope }).await?;
+            } else {
+                self.storage().await?
+                    .delete_contact_prekey(contact_id)?;
+            }
+        }
+        
+        // One envelope per device the contact announced
+        for device in &devices {
+            let envelope = self.seal_for_device(contact_id, device, message).await?;
+            self.send_protocol_message(ProtocolMessage::Encrypted { envelope }).await?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Envelope for one of a contact's devices, sealed to its message key
+    /// and numbered in our session with it
+    async fn seal_for_device(&self, contact_id: &str, device: &devices::RemoteDevice, message: &LocalMessage) -> Result<MessageEnvelope> {
+        use base64::Engine;
+        
+        let envelope_id = protocol::generate_id();
+        let (header, device_capabilities) = {
+            let _updates = self.record_updates.lock().await;
+            let storage = self.storage().await?;
+            let mut session = storage.get_device_session(contact_id, &device.device_id)?
+                .unwrap_or_else(|| devices::DeviceSession::new(device.message_key));
+            let header = session.next_header(device.message_key);
+            storage.store_device_session(contact_id, &device.device_id, &session)?;
+            storage.store_envelope_alias(&envelope_id, &message.id)?;
+            (header, storage.get_device_capabilities(contact_id, &device.device_id)?.unwrap_or_default())
+        };
+        // Only devices that check the transcript can open content sealed
+        // with it
+        let transcript = device_capabilities.contains(devices::Capabilities::TRANSCRIPT)
+            .then_some(devices::CapabilityTranscript {
+                sender: devices::Capabilities::SUPPORTED,
+                recipient: device_capabilities,
+            });
+        let sender_key = self.get_public_key().await?;
+        
+        let mut envelope = MessageEnvelope {
+            id: envelope_id.clone(),
+            // Contacts know us by our key, under whatever id they gave us
+            sender_id: base64::engine::general_purpose::STANDARD.encode(sender_key),
+            recipient_id: contact_id.to_string(),
+            timestamp: message.timestamp,
+            // Sealed below, with the rest as associated data
+            encrypted_content: Default::default(),
+            signature: Vec::new(),
+            reply_to: message.reply_to.clone(),
+            ratchet_header: Some(header),
+            lamport: Some(message.lamport),
+            invite: None,
+            thumbnail: self.sealed_thumbnail(message, &envelope_id).await?,
+            sender_device: Some(self.device_id.clone()),
+            recipient_device: Some(device.device_id.clone()),
+            message_id: Some(message.id.clone()),
+            view_once: self.is_view_once(&message.content).await?,
+            policy: self.storage().await?.get_conversation_policy(&message.conversation_id)?.map(|p| p.ours),
+            capabilities: transcript,
+        };
+        envelope.encrypted_content = self.message_keys.read().await.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Message keys not loaded"))?
+            .encrypt_message_with_aad(&x25519_dalek::PublicKey::from(device.message_key), &protocol::wire::encode(&message.content)?, &envelope.content_aad()?)?;
+        let authentication = self.storage().await?
+            .get_authentication(&message.conversation_id)?
+            .unwrap_or_default();
+        if authentication == devices::Authentication::Signed {
+            let identity = self.identity.read().await;
+            let identity = identity.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+            envelope.signature = identity.sign(&envelope.signing_bytes()?).to_bytes().to_vec();
+        }
+        Ok(envelope)
+    }
+    
+    /// A message's stored thumbnail, sealed for envelope `envelope_id`.
+    /// View-once media goes without, as a thumbnail would outlive it.
+    async fn sealed_thumbnail(&self, message: &LocalMessage, envelope_id: &str) -> Result<Option<Vec<u8>>> {
+        let Some(attachment) = message.content.attachment() else {
+            return Ok(None);
+        };
+        let storage = self.storage().await?;
+        if storage.get_view_once(attachment)?.is_some() {
+            return Ok(None);
+        }
+        storage
+            .get_thumbnail(&message.conversation_id, &message.id)?
+            .map(|thumbnail| thumbnail::seal(&thumbnail, attachment, envelope_id))
+            .transpose()
+    }
+    
+    /// Tell contacts this device can be reached under our current message
+    /// key. Sent whenever the network starts, as the key is new each unlock.
+    pub async fn announce_device(&self) -> Result<()> {
+        let message_key = self.message_keys.read().await.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Message keys not loaded"))?
+            .public_key
+            .to_bytes();
+        self.log_device_key(message_key).await?;
+        let announcement = {
+            let identity = self.identity.read().await;
+            let identity = identity.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+            devices::DeviceAnnouncement::new(identity, &self.device_id, message_key)?
+        };
+        self.send_protocol_message(ProtocolMessage::DeviceAnnouncement { announcement }).await?;
+        Ok(())
+    }
+    
+    /// Send a contact request with our key bundle. It carries a stamp at
+    /// our own difficulty, which takes a moment to mint.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn send_contact_request(&self, message: &str) -> Result<()> {
+        let message_key = self.message_keys.read().await.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Message keys not loaded"))?
+            .public_key
+            .to_bytes();
+        let (identity_key, signature) = {
+            let identity = self.identity.read().await;
+            let identity = identity.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+            (identity.public_key.to_bytes(), identity.sign(&message_key).to_bytes().to_vec())
+        };
+        let key_bundle = ProtocolMessage::KeyBundle {
+            identity_key,
+            signed_prekey: message_key,
+            signed_prekey_signature: signature,
+            one_time_prekeys: Vec::new(),
+        };
+        let display_name = self.get_profile().await?
+            .map(|profile| profile.display_name)
+            .unwrap_or_default();
+        let difficulty = self.get_contact_request_difficulty().await?;
+        let digest = puzzle::request_digest(&key_bundle, &display_name, message)?;
+        let now = OffsetDateTime::now_utc();
+        let stamp = tokio::task::spawn_blocking(move || puzzle::Stamp::mint(&identity_key, &digest, difficulty, now))
+            .await
+            .context("Minting the request stamp failed")??;
+        
+        self.send_protocol_message(ProtocolMessage::ContactRequest {
+            display_name,
+            message: message.to_string(),
+            key_bundle: Box::new(key_bundle),
+            stamp: Some(stamp),
+        }).await?;
+        Ok(())
+    }
+    
+    /// Whether a contact request should be shown. Requests from contacts
+    /// always are; others need a fresh stamp at our difficulty for this
+    /// request and are dropped silently without one, as are those from
+    /// blocked senders.
+    async fn admit_contact_request(&self, key_bundle: &ProtocolMessage, display_name: &str, message: &str, stamp: Option<&puzzle::Stamp>) -> Result<bool> {
+        use base64::Engine;
+        
+        let ProtocolMessage::KeyBundle { identity_key, .. } = key_bundle else {
+            return Ok(false);
+        };
+        let storage = self.storage().await?;
+        let known = storage.contacts()
+            .find(|c| c.as_ref().map_or(true, |c| c.public_key == *identity_key))
+            .transpose()?
+            .is_some();
+        if known {
+            return Ok(true);
+        }
+        if storage.is_sender_blocked(&base64::engine::general_purpose::STANDARD.encode(identity_key))? {
+            return Ok(false);
+        }
+        
+        let required = storage.get_contact_request_difficulty()?;
+        let Some(stamp) = stamp else {
+            tracing::debug!("Dropping contact request without a stamp");
+            return Ok(false);
+        };
+        let digest = puzzle::request_digest(key_bundle, display_name, message)?;
+        if let Err(e) = stamp.verify(identity_key, &digest, required, OffsetDateTime::now_utc()) {
+            tracing::debug!("Dropping contact request: {:#}", e);
+            return Ok(false);
+        }
+        if !storage.use_stamp(&stamp.hash(identity_key, &digest), stamp.expires_at())? {
+            tracing::debug!("Dropping contact request with a stamp already used");
+            return Ok(false);
+        }
+        Ok(true)
+    }
+    
+    /// Proof of work asked of contact requests from strangers, and put
+    /// into our own, as leading zero bits
+    pub async fn get_contact_request_difficulty(&self) -> Result<u8> {
+        self.storage().await?
+            .get_contact_request_difficulty()
+    }
+    
+    pub async fn set_contact_request_difficulty(&self, difficulty: u8) -> Result<()> {
+        puzzle::check_difficulty(difficulty)?;
+        self.storage().await?
+            .store_contact_request_difficulty(difficulty)
+    }
+    
+    /// Add an announced device to its contact's registry. Returns the
+    /// contact if the registry changed; announcements from strangers and
+    /// our own devices are ignored.
+    async fn register_contact_device(&self, announcement: &devices::DeviceAnnouncement) -> Result<Option<String>> {
+        announcement.verify()?;
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        let contact = storage.contacts()
+            .find(|c| c.as_ref().map_or(true, |c| c.public_key == announcement.identity_key))
+            .transpose()?;
+        let Some(contact) = contact else {
+            return Ok(None);
+        };
+        let mut devices = storage.get_contact_devices(&contact.id)?;
+        if !devices::register(&mut devices, announcement.device.clone()) {
+            return Ok(None);
+        }
+        storage.set_contact_devices(&contact.id, &devices)?;
+        storage.store_device_capabilities(&contact.id, &announcement.device.device_id, announcement.capabilities)?;
+        Ok(Some(contact.id))
+    }
+    
+    /// Devices a contact announced, most recently announced first
+    pub async fn get_contact_devices(&self, contact_id: &str) -> Result<Vec<devices::RemoteDevice>> {
+        self.storage().await?
+            .get_contact_devices(contact_id)
+    }
+    
+    /// Send a failed message again. It goes out under a fresh envelope id,
+    /// so recipients that saw the first attempt don't drop it as a
+    /// duplicate; receipts for either id update the same message.
+    pub async fn resend_message(&self, message_id: &str) -> Result<()> {
+        let envelope_id = protocol::generate_id();
+        let (message, contact_id) = {
+            let storage = self.storage().await?;
+            let message = storage.find_message(message_id)?
+                .filter(|message| message.is_outgoing)
+                .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+            if !matches!(message.status, DeliveryStatus::Failed { .. }) {
+                return Err(anyhow::anyhow!("Only failed messages can be resent"));
+            }
+            let conversation = storage.get_conversation(&message.conversation_id)?
+                .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?;
+            storage.store_envelope_alias(&envelope_id, message_id)?;
+            (message, conversation.contact_id)
+        };
+        
+        let events = self.set_delivery_status(message_id, DeliveryStatus::Queued).await?;
+        self.emit(events).await;
+        self.dispatch_message(&contact_id, &message, &envelope_id).await
+    }
+    
+    /// Stop a message that hasn't gone out yet, taking it out of the
+    /// outbox and the network's queues. It is marked failed, so it can
+    /// still be resent.
+    pub async fn cancel_pending_message(&self, message_id: &str) -> Result<()> {
+        let envelope_ids = {
+            let storage = self.storage().await?;
+            let message = storage.find_message(message_id)?
+                .filter(|message| message.is_outgoing)
+                .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+            if !matches!(message.status, DeliveryStatus::Queued | DeliveryStatus::Sending) {
+                return Err(anyhow::anyhow!("Message is no longer pending"));
+            }
+            let envelope_ids = storage.envelope_ids(message_id)?;
+            storage.remove_from_outbox(&envelope_ids)?;
+            envelope_ids
+        };
+        self.deferred.write().await.remove(&envelope_ids);
+        if let Some(tx) = self.network_cmd_tx.write().await.as_mut() {
+            for message_id in envelope_ids {
+                tx.send(NetworkCommand::CancelMessage { message_id }).await.ok();
+            }
+        }
+        
+        let events = self.set_delivery_status(message_id, DeliveryStatus::Failed { reason: "Cancelled".to_string() }).await?;
+        self.emit(events).await;
+        Ok(())
+    }
+    
+    /// Envelope for a message to a contact added through their invite,
+    /// encrypted to the invite prekey and introducing us
+    async fn seal_invite_message(
+        &self,
+        contact_id: &str,
+        prekey: &invite::ContactPrekey,
+        message: &LocalMessage,
+        envelope_id: &str,
+    ) -> Result<MessageEnvelope> {
+        use base64::Engine;
+        
+        let display_name = self.get_profile().await?
+            .map(|profile| profile.display_name)
+            .unwrap_or_default();
+        let encrypted_content = self.message_keys.read().await.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Message keys not loaded"))?
+            .encrypt_message(&x25519_dalek::PublicKey::from(prekey.prekey), &protocol::wire::encode(&message.content)?)?;
+        
+        let identity = self.identity.read().await;
+        let identity = identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+        let redemption = invite::InviteRedemption::new(identity, &display_name, prekey, envelope_id, &encrypted_content)?;
+        let thumbnail = self.sealed_thumbnail(message, envelope_id).await?;
+        Ok(MessageEnvelope {
+            id: envelope_id.to_string(),
+            // The inviter doesn't know us yet and names us by our key
+            sender_id: base64::engine::general_purpose::STANDARD.encode(identity.public_key.to_bytes()),
+            recipient_id: contact_id.to_string(),
+            timestamp: message.timestamp,
+            encrypted_content,
+            signature: Vec::new(),
+            reply_to: message.reply_to.clone(),
+            ratchet_header: None,
+            lamport: Some(message.lamport),
+            invite: Some(redemption),
+            thumbnail,
+            sender_device: None,
+            recipient_device: None,
+            message_id: None,
+            view_once: self.is_view_once(&message.content).await?,
+            policy: self.storage().await?.get_conversation_policy(&message.conversation_id)?.map(|p| p.ours),
+            capabilities: None,
+        })
+    }
+    
+    /// Decrypt an incoming envelope and store it in the sender's
+    /// conversation. If decryption fails the envelope is quarantined, the
+    /// failure is logged for the contact, and `DecryptionFailed` is returned;
+    /// it is retried after a session reset or key update.
+    ///
+    /// `MessageReceived` follows the sender's ratchet order: a message that
+    /// overtook earlier ones is stored right away but only reported once
+    /// they arrive or `jitter::MAX_HOLD` passes, so this may return several
+    /// events or none.
+    ///
+    /// The envelope is journaled before anything else, and the message
+    /// flushed before it is acknowledged, so a crash in between leaves it
+    /// to be replayed at the next unlock rather than lost. Messages from
+    /// unknown senders are never acknowledged, so they skip the journal.
+    pub async fn receive_envelope(&self, envelope: MessageEnvelope) -> Result<Vec<ChatEvent>> {
+        if let Some(key) = self.unknown_sender_key(&envelope).await {
+            return self.receive_message_request(key, envelope).await;
+        }
+        let entry = self.storage().await?
+            .journal_envelope(&envelope)?;
+        let result = self.process_envelope(envelope).await;
+        // Only a crash should leave an entry behind; an envelope that
+        // failed here would fail the same way when replayed
+        self.storage().await?
+            .clear_journal(entry)?;
+        result
+    }
+    
+    /// Process envelopes journaled before a crash. Those stored before it
+    /// are only acknowledged again. Returns how many were replayed.
+    async fn replay_ingest_journal(&self) -> Result<usize> {
+        let entries = self.storage().await?
+            .journaled_envelopes()?;
+        for (entry, envelope) in &entries {
+            if self.received_before(envelope).await? {
+                self.send_protocol_message(ProtocolMessage::DeliveryReceipt {
+                    message_id: envelope.id.clone(),
+                    timestamp: OffsetDateTime::now_utc(),
+                }).await?;
+            } else {
+                match self.process_envelope(envelope.clone()).await {
+                    Ok(events) => self.emit(events).await,
+                    Err(e) => tracing::warn!("Dropping journaled message {}: {}", envelope.id, e),
+                }
+            }
+            self.storage().await?
+                .clear_journal(*entry)?;
+        }
+        Ok(entries.len())
+    }
+    
+    #[tracing::instrument(level = "debug", skip_all, fields(envelope_id = %envelope.id))]
+    async fn process_envelope(&self, mut envelope: MessageEnvelope) -> Result<Vec<ChatEvent>> {
+        // A copy fanned out to another of our devices
+        if envelope.recipient_device.as_ref().is_some_and(|device| *device != self.device_id) {
+            return Ok(Vec::new());
+        }
+        if envelope.invite.is_none() {
+            match self.resolve_sender(&envelope.sender_id).await {
+                Ok(sender_id) => envelope.sender_id = sender_id,
+                Err(e) => match identity_key_from_id(&envelope.sender_id) {
+                    Some(key) => return self.receive_message_request(key, envelope).await,
+                    None => return Err(e),
+                },
+            }
+        }
+        let contact = self.storage().await?
+            .get_contact(&envelope.sender_id)?;
+        if let Some(contact) = contact {
+            let downgrade = self.storage().await?
+                .get_session_requirements(&contact.id)?
+                .and_then(|requirements| requirements.check_envelope(&envelope, contact.verified));
+            if let Some(downgrade) = downgrade {
+                return Ok(vec![self.refuse_downgrade(&contact.id, downgrade).await?]);
+            }
+        }
+        
+        // Several copies of a fanned out message can reach us, and a
+        // message sent again may have arrived the first time after all
+        if self.received_before(&envelope).await? {
+            self.send_protocol_message(ProtocolMessage::DeliveryReceipt {
+                message_id: envelope.id.clone(),
+                timestamp: OffsetDateTime::now_utc(),
+            }).await?;
+            return Ok(Vec::new());
+        }
+        
+        let opened = match &envelope.invite {
+            // Sent through one of our invites, possibly by someone new. A
+            // bad invite can't become valid later, so it isn't quarantined.
+            Some(redemption) => Ok(self.open_invite_envelope(&envelope, redemption).await?),
+            None => self.open_envelope(&envelope).await,
+        };
+        
+        match opened {
+            Ok(content) => {
+                // Authentic now, so its number in the sender's session can
+                // be taken. Stored with the message, so one replayed from
+                // the journal isn't refused.
+                let received = self.take_session_number(&envelope).await?;
+                let mut incoming = plugins::IncomingMessage {
+                    sender_id: envelope.sender_id.clone(),
+                    message_id: logical_message_id(&envelope).to_string(),
+                    content,
+                    received_at: OffsetDateTime::now_utc(),
+                };
+                let hooks = self.plugins.read().await.clone();
+                let outcome = plugins::run_incoming(&hooks, &mut incoming).await;
+                if let Some(plugin) = outcome.suppressed_by {
+                    tracing::debug!(plugin = %plugin, "Plugin suppressed incoming message");
+                    self.store_session_number(&envelope, received).await?;
+                    // Acknowledged all the same, so it isn't sent again
+                    self.send_protocol_message(ProtocolMessage::DeliveryReceipt {
+                        message_id: envelope.id.clone(),
+                        timestamp: OffsetDateTime::now_utc(),
+                    }).await?;
+                    return Ok(Vec::new());
+                }
+                
+                // Before storing, so the message is held to it
+                let policy_event = self.receive_policy(&envelope).await?;
+                let (conversation_id, message) = self.store_received(&envelope, incoming.content).await?;
+                let storage = self.storage().await?;
+                if !outcome.annotations.is_empty() {
+                    storage.add_annotations(&conversation_id, &message.id, &outcome.annotations)?;
+                }
+                self.store_session_number(&envelope, received).await?;
+                storage.flush_message_writes()?;
+                self.send_protocol_message(ProtocolMessage::DeliveryReceipt {
+                    message_id: message.id.clone(),
+                    timestamp: OffsetDateTime::now_utc(),
+                }).await?;
+                let event = ChatEvent::MessageReceived { conversation_id, message };
+                // Each of the sender's devices numbers its messages itself
+                let stream = match &envelope.sender_device {
+                    Some(device) => format!("{}/{}", envelope.sender_id, device),
+                    None => envelope.sender_id.clone(),
+                };
+                let mut events: Vec<_> = policy_event.into_iter().collect();
+                events.extend(match envelope.ratchet_header {
+                    Some(header) => self.jitter.write().await
+                        .push(&stream, header, event, std::time::Instant::now()),
+                    None => vec![event],
+                });
+                Ok(events)
+            }
+            Err(e) => {
+                let reason = format!("{:#}", e);
+                let storage = self.storage().await?;
+                
+                if storage.get_quarantined(Some(&envelope.sender_id))?.len() < MAX_QUARANTINED_PER_CONTACT {
+                    storage.quarantine_envelope(&QuarantinedEnvelope {
+                        envelope: envelope.clone(),
+                        reason: reason.clone(),
+                        received_at: OffsetDateTime::now_utc(),
+                        attempts: 1,
+                    })?;
+                } else {
+                    tracing::warn!("Quarantine full for {}, dropping message {}", envelope.sender_id, envelope.id);
+                }
+                // Held like any other failure, as an announcement still on
+                // its way explains a mismatch as well as an attack does
+                if let Some(mismatch) = e.downcast_ref::<devices::CapabilityMismatch>() {
+                    storage.append_security_event(
+                        &envelope.sender_id,
+                        SecurityEventKind::CapabilityMismatch { transcript: mismatch.transcript },
+                    )?;
+                    return Ok(vec![ChatEvent::CapabilityMismatch {
+                        contact_id: envelope.sender_id,
+                        message_id: envelope.id,
+                    }]);
+                }
+                storage.append_security_event(
+                    &envelope.sender_id,
+                    SecurityEventKind::DecryptFailed { reason: reason.clone() },
+                )?;
+                
+                Ok(vec![ChatEvent::DecryptionFailed {
+                    contact_id: envelope.sender_id,
+                    message_id: envelope.id,
+                    reason,
+                }])
+            }
+        }
+    }
+    
+    /// Id of the contact an envelope names as its sender. Senders may name
+    /// themselves by their identity key, which maps to whatever id we gave
+    /// the contact.
+    async fn resolve_sender(&self, sender_id: &str) -> Result<String> {
+        let storage = self.storage().await?;
+        if storage.get_contact(sender_id)?.is_some() {
+            return Ok(sender_id.to_string());
+        }
+        if let Some(key) = identity_key_from_id(sender_id) {
+            let contact = storage.contacts()
+                .find(|c| c.as_ref().map_or(true, |c| c.public_key == key))
+                .transpose()?;
+            if let Some(contact) = contact {
+                return Ok(contact.id);
+            }
+        }
+        Err(anyhow::anyhow!("Message from unknown contact"))
+    }
+    
+    /// Whether the message in `envelope` is stored already, from the same
+    /// sender. Stored messages name the sender by contact id, which an
+    /// envelope may still name by key.
+    async fn received_before(&self, envelope: &MessageEnvelope) -> Result<bool> {
+        let Some(message) = self.storage().await?.find_message(logical_message_id(envelope))? else {
+            return Ok(false);
+        };
+        // Nothing is stored from senders who aren't contacts
+        Ok(self.resolve_sender(&envelope.sender_id).await
+            .is_ok_and(|sender_id| message.sender_id == sender_id))
+    }
+    
+    /// The identity key of the sender of `envelope` if it is for this
+    /// device and they aren't a contact, so it is a message request
+    async fn unknown_sender_key(&self, envelope: &MessageEnvelope) -> Option<[u8; 32]> {
+        if envelope.invite.is_some() || envelope.recipient_device.as_ref().is_some_and(|device| *device != self.device_id) {
+            return None;
+        }
+        match self.resolve_sender(&envelope.sender_id).await {
+            Ok(_) => None,
+            Err(_) => identity_key_from_id(&envelope.sender_id),
+        }
+    }
+    
+    /// Keep a message from an unknown sender as a message request. It is
+    /// neither acknowledged nor reported as received, and is dropped if
+    /// the sender is blocked, it can't be decrypted, requests are full or
+    /// unknown senders are writing too fast; see `requests`. Nothing is
+    /// flushed for it, the background flusher writes it out.
+    async fn receive_message_request(&self, sender_key: [u8; 32], envelope: MessageEnvelope) -> Result<Vec<ChatEvent>> {
+        let storage = self.storage().await?;
+        if storage.is_sender_blocked(&envelope.sender_id)? {
+            return Ok(Vec::new());
+        }
+        let content = match self.open_envelope(&envelope).await {
+            Ok(content) => content,
+            Err(e) => {
+                tracing::debug!("Dropping unreadable message from unknown sender: {:#}", e);
+                return Ok(Vec::new());
+            }
+        };
+        
+        let _updates = self.record_updates.lock().await;
+        let mut request = match storage.get_message_request(&envelope.sender_id)? {
+            Some(request) => request,
+            None if storage.count_message_requests() >= requests::MAX_PENDING_REQUESTS => {
+                tracing::warn!("Message requests full, dropping message {}", envelope.id);
+                return Ok(Vec::new());
+            }
+            None => requests::MessageRequest::new(envelope.sender_id.clone(), sender_key),
+        };
+        let message_id = logical_message_id(&envelope);
+        let known = request.messages.iter()
+            .any(|message| logical_message_id(&message.envelope) == message_id);
+        if known || request.messages.len() >= requests::MAX_REQUEST_MESSAGES {
+            return Ok(Vec::new());
+        }
+        if !self.request_writes.write().await.try_take(std::time::Instant::now()) {
+            tracing::debug!("Message requests arriving too fast, dropping message {}", envelope.id);
+            return Ok(Vec::new());
+        }
+        
+        let first = request.messages.is_empty();
+        request.messages.push(requests::RequestedMessage {
+            envelope,
+            content,
+            received_at: OffsetDateTime::now_utc(),
+        });
+        storage.store_message_request(&request)?;
+        Ok(if first {
+            vec![ChatEvent::MessageRequestReceived { sender_id: request.sender_id }]
+        } else {
+            Vec::new()
+        })
+    }
+    
+    /// Messages waiting from people who aren't contacts, most recent first
+    pub async fn get_message_requests(&self) -> Result<Vec<requests::MessageRequest>> {
+        let mut requests = self.storage().await?
+            .get_message_requests()?;
+        requests.sort_by_key(|request| std::cmp::Reverse(request.last_received_at()));
+        Ok(requests)
+    }
+    
+    /// Make the sender of a message request a contact and move their
+    /// messages into the conversation with them, acknowledging them only
+    /// now
+    pub async fn accept_message_request(&self, sender_id: &str, display_name: &str) -> Result<Contact> {
+        let request = self.storage().await?
+            .get_message_request(sender_id)?
+            .ok_or_else(|| anyhow::anyhow!("Message request not found"))?;
+        let contact = self.add_contact(request.sender_key, display_name).await?;
+        
+        for mut message in request.messages {
+            message.envelope.sender_id = contact.id.clone();
+            let (_, stored) = self.store_received(&message.envelope, message.content).await?;
+            self.send_protocol_message(ProtocolMessage::DeliveryReceipt {
+                message_id: stored.id,
+                timestamp: OffsetDateTime::now_utc(),
+            }).await?;
+        }
+        let storage = self.storage().await?;
+        storage.flush_message_writes()?;
+        storage.delete_message_request(sender_id)?;
+        Ok(contact)
+    }
+    
+    /// Drop a message request without telling the sender. A blocked
+    /// sender's later messages are dropped on arrival.
+    pub async fn decline_message_request(&self, sender_id: &str, block: bool) -> Result<()> {
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        storage.get_message_request(sender_id)?
+            .ok_or_else(|| anyhow::anyhow!("Message request not found"))?;
+        if block {
+            storage.block_sender(sender_id, OffsetDateTime::now_utc())?;
+        }
+        storage.delete_message_request(sender_id)
+    }
+    
+    /// Unknown senders blocked when declining their requests, and when
+    pub async fn get_blocked_senders(&self) -> Result<Vec<(String, OffsetDateTime)>> {
+        self.storage().await?
+            .get_blocked_senders()
+    }
+    
+    /// Let a blocked sender's messages through as requests again
+    pub async fn unblock_sender(&self, sender_id: &str) -> Result<()> {
+        self.storage().await?
+            .unblock_sender(sender_id)
+    }
+    
+    /// Envelopes that failed to decrypt, from one contact or everyone
+    pub async fn get_quarantine(&self, contact_id: Option<&str>) -> Result<Vec<QuarantinedEnvelope>> {
+        let storage = self.storage().await?;
+        storage.get_quarantined(contact_id)
+    }
+    
+    /// Give up on quarantined envelopes. Returns how many were removed.
+    pub async fn purge_quarantine(&self, contact_id: Option<&str>) -> Result<usize> {
+        let storage = self.storage().await?;
+        let entries = storage.get_quarantined(contact_id)?;
+        for entry in &entries {
+            storage.remove_quarantined(&entry.envelope.sender_id, &entry.envelope.id)?;
+        }
+        Ok(entries.len())
+    }
+    
+    /// Try to decrypt a contact's quarantined envelopes again. Recovered
+    /// messages are stored and reported as `MessageReceived`; returns how
+    /// many were recovered.
+    pub async fn retry_quarantine(&self, contact_id: &str) -> Result<usize> {
+        let entries = self.get_quarantine(Some(contact_id)).await?;
+        let mut recovered = 0;
+        for mut entry in entries {
+            match self.open_envelope(&entry.envelope).await {
+                Ok(content) => {
+                    let (conversation_id, message) = self.store_received(&entry.envelope, content).await?;
+                    self.storage().await?
+                        .remove_quarantined(contact_id, &entry.envelope.id)?;
+                    self.emit(vec![ChatEvent::MessageReceived { conversation_id, message }]).await;
+                    recovered += 1;
+                }
+                Err(e) => {
+                    entry.reason = format!("{:#}", e);
+                    entry.attempts += 1;
+                    self.storage().await?
+                        .quarantine_envelope(&entry)?;
+                }
+            }
+        }
+        Ok(recovered)
+    }
+    
+    /// Start a contact's session over, then retry anything quarantined
+    /// under the old one. Returns how many messages were recovered.
+    pub async fn reset_session(&self, contact_id: &str) -> Result<usize> {
+        self.storage().await?
+            .append_security_event(contact_id, SecurityEventKind::SessionReset)?;
+        self.retry_quarantine(contact_id).await
+    }
+    
+    /// Our receiving session with the device that sent `envelope`, with
+    /// the envelope's number taken, for `store_session_number` once the
+    /// message is stored. Refuses a number already taken or one too far
+    /// ahead; envelopes outside a device session have none.
+    async fn take_session_number(&self, envelope: &MessageEnvelope) -> Result<Option<(String, devices::ReceivingSession)>> {
+        let (Some(header), Some(device)) = (&envelope.ratchet_header, &envelope.sender_device) else {
+            return Ok(None);
+        };
+        let max_skip = self.limits.read().await.max_skipped_keys;
+        let mut session = self.storage().await?
+            .get_receiving_session(&envelope.sender_id, device)?
+            .unwrap_or_else(|| devices::ReceivingSession::starting_at(header, max_skip));
+        session.accept(header, max_skip)?;
+        Ok(Some((device.clone(), session)))
+    }
+    
+    async fn store_session_number(&self, envelope: &MessageEnvelope, received: Option<(String, devices::ReceivingSession)>) -> Result<()> {
+        if let Some((device, session)) = received {
+            self.storage().await?
+                .store_receiving_session(&envelope.sender_id, &device, &session)?;
+        }
+        Ok(())
+    }
+    
+    async fn open_envelope(&self, envelope: &MessageEnvelope) -> Result<MessageContent> {
+        if let Some(device) = &envelope.sender_device {
+            let storage = self.storage().await?;
+            let identity_key = match storage.get_contact(&envelope.sender_id)? {
+                Some(contact) => contact.public_key,
+                None => identity_key_from_id(&envelope.sender_id)
+                    .ok_or_else(|| anyhow::anyhow!("Message from unknown contact"))?,
+            };
+            let registered = storage.get_contact_devices(&envelope.sender_id)?
+                .into_iter()
+                .find(|registered| registered.device_id == *device);
+            devices::Authentication::check(envelope, &identity_key, registered.as_ref())?;
+        }
+        let plaintext = self.message_keys.read().await.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Message keys not loaded"))?
+            .decrypt_message_with_aad(&envelope.encrypted_content, &envelope.content_aad()?)?;
+        // Authentic now, as it was sealed with the content
+        if let (Some(transcript), Some(device)) = (&envelope.capabilities, &envelope.sender_device) {
+            let registered = self.storage().await?
+                .get_device_capabilities(&envelope.sender_id, device)?;
+            transcript.check(devices::Capabilities::SUPPORTED, registered)?;
+        }
+        let mut content: MessageContent = protocol::wire::decode(&plaintext)
+            .context("Malformed message content")?;
+        content.check_bounds(&*self.limits.read().await)?;
+        content.sanitize();
+        Ok(content)
+    }
+    
+    /// Decrypt a message sent through one of our invites. The first one
+    /// from a new identity adds it as a contact, named by its key.
+    #[tracing::instrument(level = "debug", skip_all, fields(envelope_id = %envelope.id))]
+    async fn open_invite_envelope(&self, envelope: &MessageEnvelope, redemption: &invite::InviteRedemption) -> Result<MessageContent> {
+        use base64::Engine;
+        
+        redemption.verify(&envelope.id, &envelope.encrypted_content)?;
+        if envelope.sender_id != base64::engine::general_purpose::STANDARD.encode(redemption.identity_key) {
+            return Err(anyhow::anyhow!("Invite message sender does not match its identity"));
+        }
+        
+        // A one-time invite must not be redeemed twice at once
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        let mut private = storage.get_invite_prekey(&redemption.prekey)?
+            .filter(|p| p.accepts(&redemption.identity_key, redemption.token.as_ref(), OffsetDateTime::now_utc()))
+            .ok_or_else(|| anyhow::anyhow!("Invite is unknown, expired or already used"))?;
+        let contact = storage.get_contact(&envelope.sender_id)?;
+        if contact.as_ref().is_some_and(|c| c.public_key != redemption.identity_key) {
+            return Err(anyhow::anyhow!("Invite message sender does not match its identity"));
+        }
+        
+        let plaintext = private.key_pair().decrypt_message(&envelope.encrypted_content)?;
+        let mut content: MessageContent = protocol::wire::decode(&plaintext)
+            .context("Malformed message content")?;
+        content.check_bounds(&*self.limits.read().await)?;
+        content.sanitize();
+        
+        if private.token.is_some() && private.redeemed_by.is_none() {
+            private.redeemed_by = Some(redemption.identity_key);
+            storage.store_invite_prekey(&redemption.prekey, &private)?;
+        }
+        if contact.is_none() {
+            let contact = Contact::new(envelope.sender_id.clone(), redemption.display_name.clone(), redemption.identity_key);
+            storage.store_contact(&contact)?;
+        }
+        Ok(content)
+    }
+    
+    /// Take up the policy a contact's envelope asks for in our
+    /// conversation. Returns an event if it changed.
+    async fn receive_policy(&self, envelope: &MessageEnvelope) -> Result<Option<ChatEvent>> {
+        let Some(requested) = envelope.policy else {
+            return Ok(None);
+        };
+        let conversation_id = self.get_or_create_conversation(&envelope.sender_id).await?.id;
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        let mut policies = storage.get_conversation_policy(&conversation_id)?.unwrap_or_default();
+        if policies.theirs == requested {
+            return Ok(None);
+        }
+        policies.theirs = requested;
+        storage.store_conversation_policy(&conversation_id, &policies)?;
+        Ok(Some(ChatEvent::ConversationPolicyRequested { conversation_id, policy: requested }))
+    }
+    
+    /// Store decrypted incoming content in the sender's conversation
+    async fn store_received(&self, envelope: &MessageEnvelope, content: MessageContent) -> Result<(String, LocalMessage)> {
+        let conversation_id = self.get_or_create_conversation(&envelope.sender_id).await?.id;
+        
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        let mut conversation = storage
+            .get_conversation(&conversation_id)?
+            .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?;
+        // Older peers send no clock; treat their messages as arriving now
+        let lamport = match envelope.lamport {
+            Some(lamport) => {
+                conversation.observe(lamport);
+                lamport
+            }
+            None => conversation.tick(),
+        };
+        storage.store_conversation(&conversation)?;
+        
+        let message = LocalMessage {
+            id: logical_message_id(envelope).to_string(),
+            conversation_id: conversation.id.clone(),
+            sender_id: envelope.sender_id.clone(),
+            is_outgoing: false,
+            content,
+            timestamp: envelope.timestamp,
+            status: DeliveryStatus::Delivered,
+            reply_to: envelope.reply_to.clone(),
+            forwarded_from: None,
+            starred: false,
+            lamport,
+        };
+        storage.store_message(&message)?;
+        let view_once = envelope.view_once || storage.get_conversation_policy(&conversation.id)?
+            .is_some_and(|policies| policies.effective().view_once_media);
+        if view_once && message.content.allows_view_once() {
+            if let Some(attachment) = message.content.attachment() {
+                storage.mark_view_once(attachment)?;
+            }
+            return Ok((conversation.id, message));
+        }
+        // A bad thumbnail doesn't cost the message
+        if let (Some(sealed), Some(attachment)) = (&envelope.thumbnail, message.content.attachment()) {
+            match thumbnail::open(sealed, attachment, &envelope.id) {
+                Ok(thumbnail) => storage.store_thumbnail(&conversation.id, &message.id, &thumbnail)?,
+                Err(e) => tracing::warn!("Dropping thumbnail of message {}: {:#}", message.id, e),
+            }
+        }
+        Ok((conversation.id, message))
+    }
+    
+    /// Get all conversations
+    pub async fn get_conversations(&self) -> Result<Vec<Conversation>> {
+        let storage = self.storage().await?;
+        storage.get_all_conversations()
+    }
+    
+    /// Conversations decrypted as the stream is read, in no particular
+    /// order
+    pub async fn stream_conversations(&self) -> Result<impl Stream<Item = Result<Conversation>> + Send> {
+        Ok(futures::stream::iter(self.storage().await?.conversations()))
+    }
+    
+    /// One page of the conversations matching a query, for lists too long
+    /// to load at once
+    pub async fn query_conversations(&self, query: &ConversationQuery) -> Result<ConversationPage> {
+        let storage = self.storage().await?;
+        storage.query_conversations(query)
+    }
+    
+    /// The newest messages across conversations and waiting message
+    /// requests, newest first
+    pub async fn get_recent_activity(&self, limit: usize) -> Result<Vec<activity::ActivityItem>> {
+        if limit == 0 || limit > activity::MAX_FEED_LEN {
+            return Err(anyhow::anyhow!("The feed holds 1 to {} items", activity::MAX_FEED_LEN));
+        }
+        let storage = self.storage().await?;
+        
+        let mut contacts = HashMap::new();
+        let mut feed = Vec::new();
+        for message in storage.activity().rev().take(limit) {
+            let message = message?;
+            if !contacts.contains_key(&message.conversation_id) {
+                let contact_id = storage.get_conversation(&message.conversation_id)?
+                    .map(|conversation| conversation.contact_id);
+                contacts.insert(message.conversation_id.clone(), contact_id);
+            }
+            // Messages of a conversation gone from under them are skipped
+            if let Some(contact_id) = &contacts[&message.conversation_id] {
+                feed.push(activity::ActivityItem::Message { contact_id: contact_id.clone(), message: Box::new(message) });
+            }
+        }
+        for request in storage.get_message_requests()? {
+            if let Some(received_at) = request.last_received_at() {
+                feed.push(activity::ActivityItem::MessageRequest {
+                    sender_id: request.sender_id,
+                    messages: request.messages.len(),
+                    received_at,
+                });
+            }
+        }
+        feed.sort_by_key(|item| std::cmp::Reverse(item.timestamp()));
+        feed.truncate(limit);
+        Ok(feed)
+    }
+    
+    /// Get messages for a conversation
+    pub async fn get_messages(&self, conversation_id: &str, limit: usize) -> Result<Vec<LocalMessage>> {
+        let storage = self.storage().await?;
+        storage.get_messages(conversation_id, limit)
+    }
+    
+    /// Message counts, activity and response times of a conversation. The
+    /// first call for a conversation reads its history, later ones a single
+    /// record.
+    pub async fn get_conversation_stats(&self, conversation_id: &str) -> Result<stats::ConversationStats> {
+        let storage = self.storage().await?;
+        if storage.get_conversation(conversation_id)?.is_none() {
+            return Err(anyhow::anyhow!("Conversation not found"));
+        }
+        let conversation_id = conversation_id.to_string();
+        tokio::task::spawn_blocking(move || storage.get_conversation_stats(&conversation_id)).await?
+    }
+    
+    /// How many messages a conversation has on each day it has any, oldest
+    /// first, for an activity heatmap. Days are in UTC.
+    pub async fn get_message_count_by_day(&self, conversation_id: &str) -> Result<Vec<stats::DayCount>> {
+        let storage = self.storage().await?;
+        if storage.get_conversation(conversation_id)?.is_none() {
+            return Err(anyhow::anyhow!("Conversation not found"));
+        }
+        storage.get_message_count_by_day(conversation_id)
+    }
+    
+    /// The first message of a conversation sent at or after `date`, to jump
+    /// to that point in its history, or `None` if there is none after it
+    pub async fn get_message_at_date(&self, conversation_id: &str, date: OffsetDateTime) -> Result<Option<LocalMessage>> {
+        let storage = self.storage().await?;
+        if storage.get_conversation(conversation_id)?.is_none() {
+            return Err(anyhow::anyhow!("Conversation not found"));
+        }
+        storage.get_message_at_date(conversation_id, date)
+    }
+    
+    /// A conversation's messages newest first, starting before `before_id`
+    /// if given, decrypted as the stream is read
+    pub async fn stream_messages(&self, conversation_id: &str, before_id: Option<&str>) -> Result<impl Stream<Item = Result<LocalMessage>> + Send> {
+        let storage = self.storage().await?;
+        let messages = match before_id {
+            Some(before_id) => storage.messages_before(conversation_id, before_id)?,
+            None => storage.messages(conversation_id),
+        };
+        Ok(futures::stream::iter(messages.rev()))
+    }
+    
+    /// Up to `limit` messages before `before_id`, or the newest if none is
+    /// given, in conversation order. Pass the first message of a page to
+    /// get the one before it.
+    pub async fn get_messages_page(&self, conversation_id: &str, before_id: Option<&str>, limit: usize) -> Result<Vec<LocalMessage>> {
+        let mut page: Vec<LocalMessage> = self.stream_messages(conversation_id, before_id).await?
+            .take(limit)
+            .try_collect()
+            .await?;
+        page.reverse();
+        Ok(page)
+    }
+    
+    /// Create or get conversation with contact
+    pub async fn get_or_create_conversation(&self, contact_id: &str) -> Result<Conversation> {
+        let storage = self.storage().await?;
+        
+        if let Some(conv) = storage.get_conversation_by_contact(contact_id)? {
+            return Ok(conv);
+        }
+        
+        let conversation = Conversation::new(contact_id.to_string());
+        storage.store_conversation(&conversation)?;
+        
+        Ok(conversation)
+    }
+    
+    /// The note-to-self conversation, created on first use. Its messages
+    /// stay on our own devices and need no contact or network.
+    pub async fn get_self_conversation(&self) -> Result<Conversation> {
+        self.get_or_create_conversation(protocol::SELF_CONTACT_ID).await
+    }
+    
+    /// Add contact
+    pub async fn add_contact(&self, public_key: [u8; 32], display_name: &str) -> Result<Contact> {
+        let contact = Contact::new(
+            protocol::generate_id(),
+            display_name.to_string(),
+            public_key,
+        );
+        
+        let storage = self.storage().await?;
+        storage.store_contact(&contact)?;
+        
+        Ok(contact)
+    }
+    
+    /// Import contacts exported from another messenger. Entries with a
+    /// SecureChat key become unverified contacts, the rest pending contacts
+    /// for the user to complete; see `importer`.
+    pub async fn import_external_contacts(&self, data: &[u8], format: importer::ImportFormat) -> Result<importer::ImportReport> {
+        use importer::{ImportOutcome, ImportResult};
+        
+        let entries = importer::parse(data, format)?;
+        let storage = self.storage().await?;
+        let mut contacts = storage.get_all_contacts()?;
+        let mut pending = storage.get_pending_contacts()?;
+        
+        let mut report = importer::ImportReport::default();
+        for (index, entry) in entries.into_iter().enumerate() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(reason) => {
+                    report.results.push(ImportResult { index, name: None, outcome: ImportOutcome::Failed { reason } });
+                    continue;
+                }
+            };
+            let outcome = match entry.public_key {
+                Some(public_key) => match contacts.iter().find(|c| c.public_key == public_key) {
+                    Some(contact) => ImportOutcome::Duplicate { id: contact.id.clone() },
+                    None => {
+                        let contact = Contact::new(protocol::generate_id(), entry.display_name.clone(), public_key);
+                        storage.store_contact(&contact)?;
+                        let contact_id = contact.id.clone();
+                        contacts.push(contact);
+                        ImportOutcome::Added { contact_id }
+                    }
+                },
+                None => {
+                    let known = pending.iter().find(|p| p.source == format && match (&p.handle, &entry.handle) {
+                        (Some(known), Some(handle)) => known == handle,
+                        _ => p.display_name == entry.display_name,
+                    });
+                    match known {
+                        Some(known) => ImportOutcome::Duplicate { id: known.id.clone() },
+                        None => {
+                            let contact = importer::PendingContact {
+                                id: protocol::generate_id(),
+                                display_name: entry.display_name.clone(),
+                                handle: entry.handle,
+                                source: format,
+                                imported_at: OffsetDateTime::now_utc(),
+                            };
+                            storage.store_pending_contact(&contact)?;
+                            let pending_id = contact.id.clone();
+                            pending.push(contact);
+                            ImportOutcome::Pending { pending_id }
+                        }
+                    }
+                }
+            };
+            report.results.push(ImportResult { index, name: Some(entry.display_name), outcome });
+        }
+        Ok(report)
+    }
+    
+    /// Contacts imported without a key, by name
+    pub async fn get_pending_contacts(&self) -> Result<Vec<importer::PendingContact>> {
+        let mut pending = self.storage().await?
+            .get_pending_contacts()?;
+        pending.sort_by_key(|p| p.display_name.to_lowercase());
+        Ok(pending)
+    }
+    
+    /// Complete a pending contact with the key the user got from them.
+    /// The contact starts out unverified, like any other.
+    pub async fn add_pending_contact(&self, pending_id: &str, public_key: [u8; 32]) -> Result<Contact> {
+        let storage = self.storage().await?;
+        let pending = storage.get_pending_contact(pending_id)?
+            .ok_or_else(|| anyhow::anyhow!("Pending contact not found"))?;
+        if storage.get_all_contacts()?.iter().any(|c| c.public_key == public_key) {
+            return Err(anyhow::anyhow!("Already a contact"));
+        }
+        let contact = Contact::new(protocol::generate_id(), pending.display_name, public_key);
+        storage.store_contact(&contact)?;
+        storage.delete_pending_contact(pending_id)?;
+        Ok(contact)
+    }
+    
+    pub async fn delete_pending_contact(&self, pending_id: &str) -> Result<()> {
+        self.storage().await?
+            .delete_pending_contact(pending_id)
+    }
+    
+    /// Create an invite link other people can add us from and message us
+    /// through straight away, valid for `valid_for`. A one-time invite is
+    /// only accepted from the first person who uses it.
+    pub async fn create_invite(&self, valid_for: std::time::Duration, one_time: bool) -> Result<invite::Invite> {
+        let display_name = self.get_profile().await?
+            .map(|profile| profile.display_name)
+            .unwrap_or_default();
+        let (invite, private) = {
+            let identity = self.identity.read().await;
+            let identity = identity.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+            invite::Invite::new(identity, &display_name, valid_for, one_time)?
+        };
+        
+        self.log_own_key(transparency::KeyLogEventKind::InvitePrekey { prekey: invite.prekey }).await?;
+        let storage = self.storage().await?;
+        storage.prune_invite_prekeys(OffsetDateTime::now_utc())?;
+        storage.store_invite_prekey(&invite.prekey, &private)?;
+        Ok(invite)
+    }
+    
+    // ===== Key Transparency =====
+    
+    /// Log this device's message key before it is handed out
+    async fn log_device_key(&self, message_key: [u8; 32]) -> Result<()> {
+        self.log_own_key(transparency::KeyLogEventKind::DeviceKey {
+            device_id: self.device_id.clone(),
+            message_key,
+        }).await
+    }
+    
+    /// Append to our key transparency log, unless the key is in it already
+    async fn log_own_key(&self, kind: transparency::KeyLogEventKind) -> Result<()> {
+        let _updates = self.record_updates.lock().await;
+        let identity = self.identity.read().await;
+        let identity = identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+        let storage = self.storage().await?;
+        if storage.get_key_log(&identity.public_key.to_bytes())?.iter().any(|entry| entry.kind == kind) {
+            return Ok(());
+        }
+        storage.append_key_log(identity, kind)?;
+        Ok(())
+    }
+    
+    /// Our key transparency log, as a file contacts can check the keys
+    /// they were shown for us against
+    pub async fn export_key_log(&self) -> Result<Vec<u8>> {
+        let identity_key = self.get_public_key().await?;
+        let entries = self.storage().await?.get_key_log(&identity_key)?;
+        transparency::KeyLog { identity_key, entries }.to_bytes()
+    }
+    
+    /// Check the keys we were shown for a contact against a log they
+    /// exported. Keys missing from it are logged as a security event.
+    pub async fn verify_contact_key_log(&self, contact_id: &str, data: &[u8]) -> Result<transparency::KeyLogReport> {
+        let log = transparency::KeyLog::parse(data)?;
+        let storage = self.storage().await?;
+        let contact = storage.get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        let report = log.check(&transparency::ObservedKeys {
+            identity_key: contact.public_key,
+            devices: storage.get_contact_devices(contact_id)?,
+            invite_prekey: storage.get_contact_prekey(contact_id)?.map(|prekey| prekey.prekey),
+        });
+        if !report.inconsistencies.is_empty() {
+            storage.append_security_event(contact_id, SecurityEventKind::KeyLogMismatch {
+                inconsistencies: report.inconsistencies.clone(),
+            })?;
+        }
+        Ok(report)
+    }
+    
+    /// Add the contact behind an invite link or QR code. Messages to them
+    /// use the invite's prekey, so they can be sent before any handshake.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn accept_invite(&self, link: &str) -> Result<Contact> {
+        let invite = invite::Invite::parse(link, OffsetDateTime::now_utc())?;
+        if invite.identity_key == self.get_public_key().await? {
+            return Err(anyhow::anyhow!("This is your own invite"));
+        }
+        
+        let known = self.get_contacts().await?.into_iter()
+            .find(|c| c.public_key == invite.identity_key);
+        let contact = match known {
+            Some(contact) => contact,
+            None => self.add_contact(invite.identity_key, &invite.display_name).await?,
+        };
+        
+        let storage = self.storage().await?;
+        storage.store_contact_prekey(&contact.id, &invite::ContactPrekey {
+            prekey: invite.prekey,
+            token: invite.token,
+            expires_at: invite.expires_at,
+        })?;
+        Ok(contact)
+    }
+    
+    /// Create a contact card others can add us from, valid for
+    /// `valid_for`. It carries this device's current message key, so the
+    /// first messages to us can be sealed before any handshake.
+    pub async fn create_contact_card(&self, valid_for: std::time::Duration) -> Result<card::ContactCard> {
+        let profile = self.get_profile().await?;
+        let message_key = self.message_keys.read().await.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Message keys not loaded"))?
+            .public_key
+            .to_bytes();
+        self.log_device_key(message_key).await?;
+        let card = {
+            let identity = self.identity.read().await;
+            let identity = identity.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+            let device = devices::DeviceAnnouncement::new(identity, &self.device_id, message_key)?;
+            card::ContactCard::new(
+                identity,
+                profile.as_ref().map_or("", |p| p.display_name.as_str()),
+                profile.as_ref().and_then(|p| p.avatar.as_deref()),
+                device,
+                valid_for,
+            )?
+        };
+        
+        self.storage().await?.store_issued_card(&card::IssuedCard {
+            id: card.id.clone(),
+            issued_at: card.issued_at,
+            expires_at: card.expires_at,
+            revoked_at: None,
+        })?;
+        Ok(card)
+    }
+    
+    /// Cards we handed out, newest first
+    pub async fn get_issued_cards(&self) -> Result<Vec<card::IssuedCard>> {
+        let mut cards = self.storage().await?.get_issued_cards()?;
+        cards.sort_by_key(|card| std::cmp::Reverse(card.issued_at));
+        Ok(cards)
+    }
+    
+    /// Withdraw one of our cards. Hand the revocation to whoever the card
+    /// went to; contacts already added from it are unaffected.
+    pub async fn revoke_contact_card(&self, card_id: &str) -> Result<card::CardRevocation> {
+        let storage = self.storage().await?;
+        let mut issued = storage.get_issued_card(card_id)?
+            .ok_or_else(|| anyhow::anyhow!("Card not found"))?;
+        let revocation = {
+            let identity = self.identity.read().await;
+            let identity = identity.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+            card::CardRevocation::new(identity, card_id)?
+        };
+        issued.revoked_at.get_or_insert(revocation.revoked_at);
+        storage.store_issued_card(&issued)?;
+        Ok(revocation)
+    }
+    
+    /// Add the contact behind a card file or link, registering the device
+    /// it announces. Importing a card for someone already known updates
+    /// their devices and returns the existing contact.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn import_contact_card(&self, data: &[u8]) -> Result<Contact> {
+        let card = card::ContactCard::parse(data, OffsetDateTime::now_utc())?;
+        if card.identity_key == self.get_public_key().await? {
+            return Err(anyhow::anyhow!("This is your own card"));
+        }
+        if self.storage().await?.get_card_revocation(&card.id)?.is_some_and(|r| r.revokes(&card)) {
+            return Err(anyhow::anyhow!("This card has been revoked"));
+        }
+        
+        let known = self.get_contacts().await?.into_iter()
+            .find(|c| c.public_key == card.identity_key);
+        let contact = match known {
+            Some(contact) => contact,
+            None => self.add_contact(card.identity_key, &card.display_name).await?,
+        };
+        self.register_contact_device(&card.device).await?;
+        Ok(contact)
+    }
+    
+    /// Record a revocation of someone's card, so the card is refused from
+    /// now on
+    pub async fn import_card_revocation(&self, data: &[u8]) -> Result<card::CardRevocation> {
+        let revocation = card::CardRevocation::parse(data)?;
+        self.storage().await?.store_card_revocation(&revocation)?;
+        Ok(revocation)
+    }
+    
+    /// Show a code for someone on the same network to pair with. Replaces
+    /// any pairing in progress.
+    pub async fn start_pairing(&self) -> Result<String> {
+        let (session, code) = pairing::PairingSession::display();
+        *self.pairing.write().await = Some(session);
+        Ok(code)
+    }
+    
+    /// Pair using the code shown on the other device
+    pub async fn join_pairing(&self, code: &str) -> Result<pairing::PairingMessage> {
+        let (session, start) = pairing::PairingSession::join(code)?;
+        *self.pairing.write().await = Some(session);
+        self.send_protocol_message(ProtocolMessage::Pairing { message: start.clone() }).await?;
+        Ok(start)
+    }
+    
+    pub async fn cancel_pairing(&self) {
+        *self.pairing.write().await = None;
+    }
+    
+    /// Advance the pairing in progress. Messages for other sessions are
+    /// ignored; any failure ends the session, so each code gets one guess.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn handle_pairing_message(&self, message: &pairing::PairingMessage) -> Result<PairingStep> {
+        let mut pairing = self.pairing.write().await;
+        let Some(session) = pairing.as_mut().filter(|s| s.wants(message)) else {
+            return Ok(PairingStep::default());
+        };
+        if session.is_expired() {
+            *pairing = None;
+            return Err(anyhow::anyhow!("Pairing code expired"));
+        }
+        
+        let ours = pairing::PairingIdentity {
+            identity_key: self.get_public_key().await?,
+            display_name: self.get_profile().await?
+                .map(|profile| profile.display_name)
+                .unwrap_or_default(),
+        };
+        let outcome = match message {
+            pairing::PairingMessage::Start { .. } => session.respond(message, &ours)
+                .map(|reply| (Some(reply), None)),
+            pairing::PairingMessage::Reply { .. } => session.finish(message, &ours)
+                .map(|(theirs, finish)| (Some(finish), Some(theirs))),
+            pairing::PairingMessage::Finish { .. } => session.complete(message)
+                .map(|theirs| (None, Some(theirs))),
+        };
+        let (reply, theirs) = match outcome {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                *pairing = None;
+                return Err(e);
+            }
+        };
+        if theirs.is_some() {
+            *pairing = None;
+        }
+        drop(pairing);
+        
+        let contact = match theirs {
+            Some(theirs) => Some(self.add_verified_contact(theirs.identity_key, &theirs.display_name).await?),
+            None => None,
+        };
+        Ok(PairingStep { reply, contact })
+    }
+    
+    /// Add a contact whose key was authenticated in person, or mark the
+    /// existing contact with that key verified
+    async fn add_verified_contact(&self, public_key: [u8; 32], display_name: &str) -> Result<Contact> {
+        if public_key == self.get_public_key().await? {
+            return Err(anyhow::anyhow!("Cannot pair with yourself"));
+        }
+        let storage = self.storage().await?;
+        
+        let known = storage.contacts()
+            .find(|c| c.as_ref().map_or(true, |c| c.public_key == public_key))
+            .transpose()?;
+        let mut contact = match known {
+            Some(contact) if contact.verified => return Ok(contact),
+            Some(contact) => contact,
+            None => Contact::new(protocol::generate_id(), display_name.to_string(), public_key),
+        };
+        contact.verified = true;
+        storage.store_contact(&contact)?;
+        storage.append_security_event(&contact.id, SecurityEventKind::VerificationChanged { verified: true })?;
+        Ok(contact)
+    }
+    
+    /// Get all contacts
+    pub async fn get_contacts(&self) -> Result<Vec<Contact>> {
+        let storage = self.storage().await?;
+        storage.get_all_contacts()
+    }
+    
+    /// Contacts decrypted as the stream is read, in no particular order
+    pub async fn stream_contacts(&self) -> Result<impl Stream<Item = Result<Contact>> + Send> {
+        Ok(futures::stream::iter(self.storage().await?.contacts()))
+    }
+    
+    /// Per-contact preferences; defaults if none were set
+    /// Delete a contact. With `cascade` its conversation, messages and
+    /// attachments go too; without, a contact with a conversation is kept
+    /// and an error returned.
+    pub async fn delete_contact(&self, contact_id: &str, cascade: bool) -> Result<gc::GcReport> {
+        let _writes = self.attachment_writes.write().await;
+        let storage = self.storage().await?;
+        storage.get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        if !cascade && storage.get_conversation_by_contact(contact_id)?.is_some() {
+            return Err(anyhow::anyhow!("Contact has a conversation; delete with cascade to remove it too"));
+        }
+        storage.delete_contact(contact_id)?;
+        storage.collect_garbage(false)
+    }
+    
+    pub async fn get_contact_settings(&self, contact_id: &str) -> Result<ContactSettings> {
+        let storage = self.storage().await?;
+        Ok(storage.get_contact_settings(contact_id)?
+            .unwrap_or_else(|| ContactSettings::new(contact_id)))
+    }
+    
+    /// How to show a contact: their nickname or else display name, a
+    /// hint of their key, and whether their own name imitates another
+    /// contact's or ours. Naming them with a nickname clears the warnings.
+    pub async fn get_contact_name(&self, contact_id: &str) -> Result<names::ContactName> {
+        let storage = self.storage().await?;
+        let contact = storage.get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        let nickname = storage.get_contact_settings(contact_id)?.and_then(|s| s.nickname);
+        let warnings = match nickname {
+            Some(_) => Vec::new(),
+            None => self.name_warnings(&contact.display_name, contact_id).await?,
+        };
+        Ok(names::ContactName {
+            contact_id: contact.id,
+            name: nickname.clone().unwrap_or_else(|| contact.display_name.clone()),
+            display_name: contact.display_name,
+            nickname,
+            fingerprint_hint: Fingerprint::of_key(&contact.public_key).short_hint(),
+            warnings,
+        })
+    }
+    
+    /// Warnings for `name` among the names of every contact but `except`
+    /// and our own
+    async fn name_warnings(&self, name: &str, except: &str) -> Result<Vec<names::NameWarning>> {
+        let storage = self.storage().await?;
+        let mut others: Vec<(String, String)> = storage.shown_names()?.into_iter()
+            .filter(|(contact_id, _)| contact_id != except)
+            .collect();
+        if let Some(profile) = storage.get_profile()? {
+            others.push((protocol::SELF_CONTACT_ID.to_string(), profile.display_name));
+        }
+        Ok(names::check(name, others.iter().map(|(contact_id, name)| (contact_id.as_str(), name.as_str()))))
+    }
+    
+    /// Set or clear the nickname shown for a contact
+    pub async fn set_contact_nickname(&self, contact_id: &str, nickname: Option<&str>) -> Result<()> {
+        let nickname = nickname.map(str::trim).filter(|n| !n.is_empty());
+        if nickname.is_some_and(|n| n.chars().count() > ContactSettings::MAX_NICKNAME_LEN) {
+            return Err(anyhow::anyhow!("Nickname too long"));
+        }
+        self.update_contact_settings(contact_id, |s| s.nickname = nickname.map(String::from)).await
+    }
+    
+    /// Set or clear a contact's color tag
+    pub async fn set_contact_color(&self, contact_id: &str, color: Option<ColorTag>) -> Result<()> {
+        self.update_contact_settings(contact_id, |s| s.color = color).await
+    }
+    
+    /// Set or clear a contact's notification sound
+    pub async fn set_contact_notification_sound(&self, contact_id: &str, sound_id: Option<&str>) -> Result<()> {
+        self.update_contact_settings(contact_id, |s| s.notification_sound = sound_id.map(String::from)).await
+    }
+    
+    async fn update_contact_settings(&self, contact_id: &str, update: impl FnOnce(&mut ContactSettings)) -> Result<()> {
+        let storage = self.storage().await?;
+        storage.get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        
+        let mut settings = storage.get_contact_settings(contact_id)?
+            .unwrap_or_else(|| ContactSettings::new(contact_id));
+        update(&mut settings);
+        settings.updated_at = OffsetDateTime::now_utc();
+        storage.store_contact_settings(&settings)
+    }
+    
+    /// Our private note on a contact, if there is one
+    pub async fn get_contact_note(&self, contact_id: &str) -> Result<Option<ContactNote>> {
+        Ok(self.storage().await?
+            .get_contact_note(contact_id)?
+            .filter(|note| !note.text.is_empty()))
+    }
+    
+    /// Write a private note on a contact, replacing any earlier one. It's
+    /// kept encrypted with the rest of storage and only ever leaves this
+    /// device in backups and sync with our own devices.
+    pub async fn set_contact_note(&self, contact_id: &str, text: &str) -> Result<()> {
+        let text = text.trim();
+        if text.chars().count() > ContactNote::MAX_LEN {
+            return Err(anyhow::anyhow!("Note too long"));
+        }
+        let storage = self.storage().await?;
+        storage.get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        storage.store_contact_note(&ContactNote {
+            contact_id: contact_id.to_string(),
+            text: text.to_string(),
+            updated_at: OffsetDateTime::now_utc(),
+        })
+    }
+    
+    pub async fn delete_contact_note(&self, contact_id: &str) -> Result<()> {
+        self.set_contact_note(contact_id, "").await
+    }
+    
+    /// Notes containing `query`, ignoring case, most recently edited
+    /// first
+    pub async fn search_contact_notes(&self, query: &str) -> Result<Vec<ContactNote>> {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut notes = self.storage().await?
+            .get_all_contact_notes()?;
+        notes.retain(|note| note.text.to_lowercase().contains(&query));
+        notes.sort_by_key(|note| std::cmp::Reverse(note.updated_at));
+        Ok(notes)
+    }
+    
+    /// Contacts, conversations, notes and messages matching `query`, best
+    /// first. Every word of the query may be just the start of a word, as
+    /// while it is being typed; see `search`.
+    pub async fn search(&self, query: &str, limit: usize) -> Result<Vec<search::SearchResult>> {
+        use search::{SearchHit, SearchResult};
+        if limit == 0 || limit > search::MAX_LIMIT {
+            return Err(anyhow::anyhow!("Searches return 1 to {} results", search::MAX_LIMIT));
+        }
+        let words = search::words(query);
+        if words.is_empty() {
+            return Ok(Vec::new());
+        }
+        let storage = self.storage().await?;
+        tokio::task::spawn_blocking(move || -> Result<Vec<SearchResult>> {
+            let mut names = storage.shown_names()?;
+            if let Some(profile) = storage.get_profile()? {
+                names.insert(protocol::SELF_CONTACT_ID.to_string(), profile.display_name);
+            }
+            let name = |contact_id: &str| names.get(contact_id).cloned().unwrap_or_default();
+            let nicknames: HashMap<String, String> = storage.get_all_contact_settings()?.into_iter()
+                .filter_map(|settings| Some((settings.contact_id, settings.nickname?)))
+                .collect();
+            let mut results = Vec::new();
+            
+            for contact in storage.contacts() {
+                let contact = contact?;
+                let nickname = nicknames.get(&contact.id).and_then(|nickname| search::score(nickname, &words));
+                if let Some(score) = search::score(&contact.display_name, &words).max(nickname) {
+                    results.push(SearchResult { hit: SearchHit::Contact { name: name(&contact.id), contact_id: contact.id }, score });
+                }
+            }
+            let mut titles = HashMap::new();
+            for conversation in storage.conversations() {
+                let conversation = conversation?;
+                let title = name(&conversation.contact_id);
+                if let Some(score) = search::score(&title, &words) {
+                    results.push(SearchResult {
+                        hit: SearchHit::Conversation {
+                            conversation_id: conversation.id.clone(),
+                            contact_id: conversation.contact_id,
+                            title: title.clone(),
+                            updated_at: conversation.updated_at,
+                        },
+                        score,
+                    });
+                }
+                titles.insert(conversation.id, title);
+            }
+            for note in storage.get_all_contact_notes()? {
+                if let Some(score) = search::score(&note.text, &words) {
+                    results.push(SearchResult {
+                        hit: SearchHit::Note {
+                            name: name(&note.contact_id),
+                            contact_id: note.contact_id,
+                            preview: text::preview(&note.text),
+                            updated_at: note.updated_at,
+                        },
+                        score,
+                    });
+                }
+            }
+            for (message, score) in storage.search_messages(&words)? {
+                results.push(SearchResult {
+                    hit: SearchHit::Message {
+                        title: titles.get(&message.conversation_id).cloned().unwrap_or_default(),
+                        preview: message.preview_text(),
+                        conversation_id: message.conversation_id,
+                        message_id: message.id,
+                        timestamp: message.timestamp,
+                    },
+                    score,
+                });
+            }
+            
+            search::rank(&mut results, limit);
+            Ok(results)
+        }).await?
+    }
+    
+    /// Labels, by name
+    pub async fn get_labels(&self) -> Result<Vec<ContactLabel>> {
+        let mut labels = self.storage().await?
+            .get_all_labels()?;
+        labels.retain(|label| !label.deleted);
+        labels.sort_by_key(|label| label.name.to_lowercase());
+        Ok(labels)
+    }
+    
+    /// Create a label; names are unique, ignoring case
+    pub async fn create_label(&self, name: &str, color: Option<ColorTag>) -> Result<ContactLabel> {
+        let label = ContactLabel::new(name, color)?;
+        let _updates = self.record_updates.lock().await;
+        self.check_label_name(&label.id, &label.name).await?;
+        self.storage().await?
+            .store_label(&label)?;
+        Ok(label)
+    }
+    
+    pub async fn rename_label(&self, label_id: &str, name: &str) -> Result<()> {
+        let name = ContactLabel::check_name(name)?;
+        let _updates = self.record_updates.lock().await;
+        self.check_label_name(label_id, &name).await?;
+        self.update_label(label_id, |label| label.name = name).await
+    }
+    
+    pub async fn set_label_color(&self, label_id: &str, color: Option<ColorTag>) -> Result<()> {
+        let _updates = self.record_updates.lock().await;
+        self.update_label(label_id, |label| label.color = color).await
+    }
+    
+    /// Delete a label. Its contacts are kept.
+    pub async fn delete_label(&self, label_id: &str) -> Result<()> {
+        let _updates = self.record_updates.lock().await;
+        self.update_label(label_id, |label| {
+            label.deleted = true;
+            label.members.clear();
+        }).await
+    }
+    
+    /// Returns false if the contact already had the label
+    pub async fn add_contact_to_label(&self, label_id: &str, contact_id: &str) -> Result<bool> {
+        let _updates = self.record_updates.lock().await;
+        self.storage().await?
+            .get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        let label = self.get_label(label_id).await?;
+        if label.members.iter().any(|member| member == contact_id) {
+            return Ok(false);
+        }
+        self.update_label(label_id, |label| label.members.push(contact_id.to_string())).await?;
+        Ok(true)
+    }
+    
+    /// Returns false if the contact didn't have the label
+    pub async fn remove_contact_from_label(&self, label_id: &str, contact_id: &str) -> Result<bool> {
+        let _updates = self.record_updates.lock().await;
+        let label = self.get_label(label_id).await?;
+        if !label.members.iter().any(|member| member == contact_id) {
+            return Ok(false);
+        }
+        self.update_label(label_id, |label| label.members.retain(|member| member != contact_id)).await?;
+        Ok(true)
+    }
+    
+    /// Contacts with a label
+    pub async fn get_contacts_with_label(&self, label_id: &str) -> Result<Vec<Contact>> {
+        let label = self.get_label(label_id).await?;
+        let mut contacts = self.get_contacts().await?;
+        contacts.retain(|contact| label.members.contains(&contact.id));
+        Ok(contacts)
+    }
+    
+    /// Conversations with contacts that have a label
+    pub async fn get_conversations_with_label(&self, label_id: &str) -> Result<Vec<Conversation>> {
+        let label = self.get_label(label_id).await?;
+        let mut conversations = self.get_conversations().await?;
+        conversations.retain(|conversation| label.members.contains(&conversation.contact_id));
+        Ok(conversations)
+    }
+    
+    async fn get_label(&self, label_id: &str) -> Result<ContactLabel> {
+        self.storage().await?
+            .get_label(label_id)?
+            .filter(|label| !label.deleted)
+            .ok_or_else(|| anyhow::anyhow!("Label not found"))
+    }
+    
+    async fn check_label_name(&self, label_id: &str, name: &str) -> Result<()> {
+        let taken = self.get_labels().await?
+            .iter()
+            .any(|label| label.id != label_id && label.name.to_lowercase() == name.to_lowercase());
+        if taken {
+            return Err(anyhow::anyhow!("A label named {:?} already exists", name));
+        }
+        Ok(())
+    }
+    
+    /// Change a label; callers hold `record_updates`
+    async fn update_label(&self, label_id: &str, update: impl FnOnce(&mut ContactLabel)) -> Result<()> {
+        let mut label = self.get_label(label_id).await?;
+        update(&mut label);
+        label.updated_at = OffsetDateTime::now_utc();
+        self.storage().await?
+            .store_label(&label)
+    }
+    
+    /// Folders, in order
+    pub async fn get_folders(&self) -> Result<Vec<ChatFolder>> {
+        let mut folders = self.storage().await?
+            .get_all_folders()?;
+        folders.retain(|folder| !folder.deleted);
+        folders.sort_by(|a, b| a.position.cmp(&b.position)
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase())));
+        Ok(folders)
+    }
+    
+    /// Create a folder after the others; names are unique, ignoring case
+    pub async fn create_folder(&self, name: &str) -> Result<ChatFolder> {
+        let _updates = self.record_updates.lock().await;
+        let position = self.get_folders().await?
+            .last()
+            .map_or(0, |folder| folder.position.saturating_add(1));
+        let folder = ChatFolder::new(name, position)?;
+        self.check_folder_name(&folder.id, &folder.name).await?;
+        self.storage().await?
+            .store_folder(&folder)?;
+        Ok(folder)
+    }
+    
+    pub async fn rename_folder(&self, folder_id: &str, name: &str) -> Result<()> {
+        let name = ChatFolder::check_name(name)?;
+        let _updates = self.record_updates.lock().await;
+        self.check_folder_name(folder_id, &name).await?;
+        self.update_folder(folder_id, |folder| folder.name = name).await
+    }
+    
+    /// Delete a folder. Its conversations are kept, in no folder.
+    pub async fn delete_folder(&self, folder_id: &str) -> Result<()> {
+        let _updates = self.record_updates.lock().await;
+        self.update_folder(folder_id, |folder| {
+            folder.deleted = true;
+            folder.members.clear();
+        }).await
+    }
+    
+    /// Put the folders in the order given, which must name each of them
+    /// once
+    pub async fn reorder_folders(&self, folder_ids: &[String]) -> Result<()> {
+        let _updates = self.record_updates.lock().await;
+        let folders = self.get_folders().await?;
+        let mut given: Vec<&str> = folder_ids.iter().map(String::as_str).collect();
+        let mut current: Vec<&str> = folders.iter().map(|folder| folder.id.as_str()).collect();
+        given.sort_unstable();
+        current.sort_unstable();
+        if given != current {
+            return Err(anyhow::anyhow!("Reordering must list every folder once"));
+        }
+        for (position, folder_id) in folder_ids.iter().enumerate() {
+            let position = position as u32;
+            if folders.iter().any(|folder| &folder.id == folder_id && folder.position != position) {
+                self.update_folder(folder_id, |folder| folder.position = position).await?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Move a conversation into a folder, or out of any with None
+    pub async fn set_conversation_folder(&self, conversation_id: &str, folder_id: Option<&str>) -> Result<()> {
+        let _updates = self.record_updates.lock().await;
+        let contact_id = self.storage().await?
+            .get_conversation(conversation_id)?
+            .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?
+            .contact_id;
+        if let Some(folder_id) = folder_id {
+            self.get_folder(folder_id).await?;
+        }
+        for folder in self.get_folders().await? {
+            let member = folder.members.contains(&contact_id);
+            let target = folder_id == Some(folder.id.as_str());
+            if member && !target {
+                self.update_folder(&folder.id, |folder| folder.members.retain(|member| *member != contact_id)).await?;
+            } else if target && !member {
+                self.update_folder(&folder.id, |folder| folder.members.push(contact_id.clone())).await?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Conversations in a folder, most recently active first
+    pub async fn get_conversations_in_folder(&self, folder_id: &str) -> Result<Vec<Conversation>> {
+        let folder = self.get_folder(folder_id).await?;
+        let mut conversations = self.get_conversations().await?;
+        conversations.retain(|conversation| folder.members.contains(&conversation.contact_id));
+        Ok(conversations)
+    }
+    
+    /// Every conversation grouped by folder with each folder's unread
+    /// count, folders in order and conversations in none last
+    pub async fn get_conversations_by_folder(&self) -> Result<Vec<FolderGroup>> {
+        self.storage().await?
+            .conversations_by_folder()
+    }
+    
+    async fn get_folder(&self, folder_id: &str) -> Result<ChatFolder> {
+        self.storage().await?
+            .get_folder(folder_id)?
+            .filter(|folder| !folder.deleted)
+            .ok_or_else(|| anyhow::anyhow!("Folder not found"))
+    }
+    
+    async fn check_folder_name(&self, folder_id: &str, name: &str) -> Result<()> {
+        let taken = self.get_folders().await?
+            .iter()
+            .any(|folder| folder.id != folder_id && folder.name.to_lowercase() == name.to_lowercase());
+        if taken {
+            return Err(anyhow::anyhow!("A folder named {:?} already exists", name));
+        }
+        Ok(())
+    }
+    
+    /// Change a folder; callers hold `record_updates`
+    async fn update_folder(&self, folder_id: &str, update: impl FnOnce(&mut ChatFolder)) -> Result<()> {
+        let mut folder = self.get_folder(folder_id).await?;
+        update(&mut folder);
+        folder.updated_at = OffsetDateTime::now_utc();
+        self.storage().await?
+            .store_folder(&folder)
+    }
+    
+    /// Broadcast lists, by name
+    pub async fn get_broadcast_lists(&self) -> Result<Vec<broadcast::BroadcastList>> {
+        let mut lists = self.storage().await?
+            .get_broadcast_lists()?;
+        lists.sort_by_key(|list| list.name.to_lowercase());
+        Ok(lists)
+    }
+    
+    /// Create a list to broadcast to `contact_ids`
+    pub async fn create_broadcast_list(&self, name: &str, contact_ids: &[String]) -> Result<broadcast::BroadcastList> {
+        let mut list = broadcast::BroadcastList::new(name)?;
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        for contact_id in contact_ids {
+            storage.get_contact(contact_id)?
+                .ok_or_else(|| anyhow::anyhow!("Contact not found: {}", contact_id))?;
+            if !list.members.contains(contact_id) {
+                list.members.push(contact_id.clone());
+            }
+        }
+        if list.members.len() > broadcast::BroadcastList::MAX_MEMBERS {
+            return Err(anyhow::anyhow!("Broadcast lists hold at most {} contacts", broadcast::BroadcastList::MAX_MEMBERS));
+        }
+        storage.store_broadcast_list(&list)?;
+        Ok(list)
+    }
+    
+    pub async fn rename_broadcast_list(&self, list_id: &str, name: &str) -> Result<()> {
+        let name = broadcast::BroadcastList::check_name(name)?;
+        let _updates = self.record_updates.lock().await;
+        let mut list = self.get_broadcast_list(list_id).await?;
+        list.name = name;
+        self.storage().await?
+            .store_broadcast_list(&list)
+    }
+    
+    /// Delete a list and its broadcast records; the messages sent stay
+    pub async fn delete_broadcast_list(&self, list_id: &str) -> Result<()> {
+        let _updates = self.record_updates.lock().await;
+        self.get_broadcast_list(list_id).await?;
+        self.storage().await?
+            .delete_broadcast_list(list_id)
+    }
+    
+    /// Returns false if the contact was already on the list
+    pub async fn add_broadcast_member(&self, list_id: &str, contact_id: &str) -> Result<bool> {
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        storage.get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        let mut list = self.get_broadcast_list(list_id).await?;
+        if list.members.iter().any(|member| member == contact_id) {
+            return Ok(false);
+        }
+        if list.members.len() >= broadcast::BroadcastList::MAX_MEMBERS {
+            return Err(anyhow::anyhow!("Broadcast lists hold at most {} contacts", broadcast::BroadcastList::MAX_MEMBERS));
+        }
+        list.members.push(contact_id.to_string());
+        storage.store_broadcast_list(&list)?;
+        Ok(true)
+    }
+    
+    /// Returns false if the contact wasn't on the list
+    pub async fn remove_broadcast_member(&self, list_id: &str, contact_id: &str) -> Result<bool> {
+        let _updates = self.record_updates.lock().await;
+        let mut list = self.get_broadcast_list(list_id).await?;
+        if !list.members.iter().any(|member| member == contact_id) {
+            return Ok(false);
+        }
+        list.members.retain(|member| member != contact_id);
+        self.storage().await?
+            .store_broadcast_list(&list)?;
+        Ok(true)
+    }
+    
+    /// Send `content` to every member of a list as a separate message in
+    /// their own conversation. A member the message can't be sent to is
+    /// reported in the result rather than failing the whole broadcast.
+    pub async fn send_broadcast(&self, list_id: &str, content: MessageContent) -> Result<broadcast::Broadcast> {
+        let list = self.get_broadcast_list(list_id).await?;
+        if list.members.is_empty() {
+            return Err(anyhow::anyhow!("Broadcast list has no members"));
+        }
+        
+        let mut recipients = Vec::with_capacity(list.members.len());
+        for contact_id in list.members {
+            let sent = async {
+                self.storage().await?
+                    .get_contact(&contact_id)?
+                    .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+                let conversation = self.get_or_create_conversation(&contact_id).await?;
+                let message_id = self.send_content(&conversation.id, content.clone(), None).await?;
+                Ok::<_, anyhow::Error>((conversation.id, message_id))
+            }.await;
+            let outcome = match sent {
+                Ok((conversation_id, message_id)) => broadcast::RecipientOutcome::Sent { conversation_id, message_id },
+                Err(e) => {
+                    tracing::warn!("Broadcast to {} failed: {:#}", contact_id, e);
+                    broadcast::RecipientOutcome::Failed { reason: e.to_string() }
+                }
+            };
+            recipients.push(broadcast::BroadcastRecipient { contact_id, outcome });
+        }
+        
+        let broadcast = broadcast::Broadcast {
+            id: protocol::generate_id(),
+            list_id: list_id.to_string(),
+            sent_at: OffsetDateTime::now_utc(),
+            recipients,
+        };
+        self.storage().await?
+            .store_broadcast(&broadcast)?;
+        Ok(broadcast)
+    }
+    
+    /// Broadcasts sent to a list, newest first
+    pub async fn get_broadcasts(&self, list_id: &str) -> Result<Vec<broadcast::Broadcast>> {
+        let mut broadcasts = self.storage().await?
+            .get_broadcasts(list_id)?;
+        broadcasts.reverse();
+        Ok(broadcasts)
+    }
+    
+    /// Where each recipient's copy of a broadcast has got to
+    pub async fn get_broadcast_status(&self, broadcast_id: &str) -> Result<broadcast::BroadcastStatus> {
+        let storage = self.storage().await?;
+        let broadcast = storage.get_broadcast(broadcast_id)?
+            .ok_or_else(|| anyhow::anyhow!("Broadcast not found"))?;
+        
+        let mut status = broadcast::BroadcastStatus::default();
+        for recipient in &broadcast.recipients {
+            match &recipient.outcome {
+                broadcast::RecipientOutcome::Sent { conversation_id, message_id } => {
+                    let message = storage.get_message(conversation_id, message_id)?;
+                    status.add(&recipient.contact_id, message.as_ref().map(|message| &message.status));
+                }
+                broadcast::RecipientOutcome::Failed { reason } => {
+                    status.failed.push((recipient.contact_id.clone(), reason.clone()));
+                }
+            }
+        }
+        Ok(status)
+    }
+    
+    async fn get_broadcast_list(&self, list_id: &str) -> Result<broadcast::BroadcastList> {
+        self.storage().await?
+            .get_broadcast_list(list_id)?
+            .ok_or_else(|| anyhow::anyhow!("Broadcast list not found"))
+    }
+    
+    /// Snapshot for syncing to another of our own devices
+    pub async fn sync_data(&self) -> Result<protocol::ProtocolMessage> {
+        let storage = self.storage().await?;
+        Ok(protocol::ProtocolMessage::SyncData {
+            conversations: storage.get_all_conversations()?,
+            contacts: storage.get_all_contacts()?,
+            settings: std::collections::HashMap::new(),
+            contact_settings: storage.get_all_contact_settings()?,
+            labels: storage.get_all_labels()?,
+            contact_notes: storage.get_all_contact_notes()?,
+            folders: storage.get_all_folders()?,
+        })
+    }
+    
+    /// Merge sync data from another of our devices: unknown contacts are
+    /// added and the newer of two contact settings, note, label or folder
+    /// records wins
+    pub async fn apply_sync_data(&self, data: protocol::ProtocolMessage) -> Result<()> {
+        let protocol::ProtocolMessage::SyncData { contacts, contact_settings, labels, contact_notes, folders, .. } = data else {
+            return Err(anyhow::anyhow!("Not sync data"));
+        };
+        
+        let storage = self.storage().await?;
+        
+        for contact in contacts {
+            if storage.get_contact(&contact.id)?.is_none() {
+                storage.store_contact(&contact)?;
+            }
+        }
+        for settings in contact_settings {
+            if storage.get_contact(&settings.contact_id)?.is_none() {
+                continue;
+            }
+            let newer = match storage.get_contact_settings(&settings.contact_id)? {
+                Some(local) => settings.updated_at > local.updated_at,
+                None => true,
+            };
+            if newer {
+                storage.store_contact_settings(&settings)?;
+            }
+        }
+        for note in contact_notes {
+            if storage.get_contact(&note.contact_id)?.is_none() {
+                continue;
+            }
+            let newer = match storage.get_contact_note(&note.contact_id)? {
+                Some(local) => note.updated_at > local.updated_at,
+                None => true,
+            };
+            if newer {
+                storage.store_contact_note(&note)?;
+            }
+        }
+        for mut label in labels {
+            let newer = match storage.get_label(&label.id)? {
+                Some(local) => label.updated_at > local.updated_at,
+                None => true,
+            };
+            if newer {
+                // Contacts only the other device knows of stay there
+                let mut members = Vec::new();
+                for member in label.members {
+                    if storage.get_contact(&member)?.is_some() {
+                        members.push(member);
+                    }
+                }
+                label.members = members;
+                storage.store_label(&label)?;
+            }
+        }
+        for mut folder in folders {
+            let newer = match storage.get_folder(&folder.id)? {
+                Some(local) => folder.updated_at > local.updated_at,
+                None => true,
+            };
+            if newer {
+                let mut members = Vec::new();
+                for member in folder.members {
+                    if storage.get_contact(&member)?.is_some() {
+                        members.push(member);
+                    }
+                }
+                folder.members = members;
+                storage.store_folder(&folder)?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Which messages this device holds, for history sync
+    pub async fn history_manifest(&self) -> Result<history::HistoryManifest> {
+        let storage = self.storage().await?;
+        
+        let mut manifest = history::HistoryManifest::default();
+        for conversation in storage.conversations() {
+            let conversation = conversation?;
+            let messages = storage.messages(&conversation.id).collect::<Result<Vec<_>>>()?;
+            if !messages.is_empty() {
+                manifest.conversations.push(
+                    history::ConversationManifest::build(&conversation.contact_id, &messages));
+            }
+        }
+        Ok(manifest)
+    }
+    
+    /// Request for the history our other devices hold and this one lacks.
+    /// Sending it again after an interruption picks up where it stopped.
+    pub async fn history_sync_request(&self) -> Result<ProtocolMessage> {
+        let manifest = self.history_manifest().await?;
+        let reply_key = self.message_keys.read().await.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?
+            .public_key.to_bytes();
+        let timestamp = OffsetDateTime::now_utc().unix_timestamp();
+        let nonce: [u8; 16] = rand::random();
+        let signed = history::request_signing_bytes(&self.device_id, &reply_key, &manifest, timestamp, &nonce)?;
+        
+        let identity = self.identity.read().await;
+        let identity = identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+        Ok(ProtocolMessage::HistorySyncRequest {
+            device_id: self.device_id.clone(),
+            reply_key,
+            signature: identity.sign(&signed).to_bytes().to_vec(),
+            manifest,
+            timestamp,
+            nonce,
+        })
+    }
+    
+    /// Ask our other devices for missing history
+    pub async fn start_history_sync(&self) -> Result<bool> {
+        let request = self.history_sync_request().await?;
+        self.send_protocol_message(request).await
+    }
+    
+    /// Batches answering a history sync request from another of our
+    /// devices. A request is answered once, and only while recent.
+    pub async fn history_batches(&self, request: &ProtocolMessage) -> Result<Vec<ProtocolMessage>> {
+        let ProtocolMessage::HistorySyncRequest { device_id, reply_key, manifest, timestamp, nonce, signature } = request else {
+            return Err(anyhow::anyhow!("Not a history sync request"));
+        };
+        if *device_id == self.device_id {
+            return Ok(Vec::new());
+        }
+        self.history_requests.check_time(*timestamp, OffsetDateTime::now_utc())?;
+        let signed = history::request_signing_bytes(device_id, reply_key, manifest, *timestamp, nonce)?;
+        self.verify_own_signature(&signed, signature).await
+            .context("History sync request is not from one of our devices")?;
+        self.history_requests.accept(*timestamp, *nonce, OffsetDateTime::now_utc())?;
+        
+        let gaps = self.history_manifest().await?.gaps(manifest);
+        let mut contents = Vec::new();
+        {
+            let storage = self.storage().await?;
+            for conversation in storage.conversations() {
+                let conversation = conversation?;
+                let wanted: Vec<u64> = gaps.iter()
+                    .filter(|(contact_id, _)| *contact_id == conversation.contact_id)
+                    .map(|(_, bucket)| *bucket)
+                    .collect();
+                if wanted.is_empty() {
+                    continue;
+                }
+                let messages: Vec<LocalMessage> = storage.messages(&conversation.id)
+                    .filter(|m| m.as_ref().map_or(true, |m| wanted.contains(&history::bucket_of(m))))
+                    .collect::<Result<_>>()?;
+                for bucket in wanted {
+                    let messages: Vec<LocalMessage> = messages.iter()
+                        .filter(|m| history::bucket_of(m) == bucket)
+                        .cloned()
+                        .collect();
+                    for chunk in messages.chunks(history::MAX_BATCH_MESSAGES) {
+                        contents.push(history::HistoryBatchContent {
+                            contact_id: conversation.contact_id.clone(),
+                            messages: chunk.to_vec(),
+                            remaining: 0,
+                        });
+                    }
+                }
+            }
+        }
+        
+        let message_keys = self.message_keys.read().await;
+        let message_keys = message_keys.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+        let identity = self.identity.read().await;
+        let identity = identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+        let recipient = x25519_dalek::PublicKey::from(*reply_key);
+        
+        let total = contents.len();
+        let mut batches = Vec::with_capacity(total);
+        for (i, mut content) in contents.into_iter().enumerate() {
+            content.remaining = (total - i - 1) as u32;
+            let payload = message_keys.encrypt_message(&recipient, &protocol::wire::encode(&content)?)?;
+            let signature = identity.sign(&history::batch_signing_bytes(device_id, &payload)?);
+            batches.push(ProtocolMessage::HistoryBatch {
+                device_id: device_id.clone(),
+                payload,
+                signature: signature.to_bytes().to_vec(),
+            });
+        }
+        Ok(batches)
+    }
+    
+    /// Answer a history sync request over the network in the background,
+    /// pacing the batches by `history::BATCH_INTERVAL`, with the number of
+    /// batches sent as its result. Once cancelled no further batch is
+    /// sent; the requester keeps the ones it got and asks again for the
+    /// rest. A device already being served gets nothing more until that
+    /// transfer ends.
+    pub fn serve_history_sync(&self, request: ProtocolMessage) -> operations::OperationHandle<usize> {
+        let chat = self.clone();
+        self.start_operation(cancel::Operation::HistorySync, operations::ProgressUnit::Items, None, move |mut tracker, cancel| async move {
+            let served = chat.send_history_batches(&request, &cancel, &mut tracker).await;
+            chat.report_cancelled(cancel::Operation::HistorySync, served)
+        })
+    }
+    
+    async fn send_history_batches(
+        &self,
+        request: &ProtocolMessage,
+        cancel: &cancel::CancellationToken,
+        tracker: &mut operations::ProgressTracker,
+    ) -> Result<usize> {
+        let ProtocolMessage::HistorySyncRequest { device_id, .. } = request else {
+            return Err(anyhow::anyhow!("Not a history sync request"));
+        };
+        let _serving = self.history_requests.serve(device_id)?;
+        let batches = self.history_batches(request).await?;
+        let count = batches.len();
+        tracker.set_total(count as u64);
+        for (i, batch) in batches.into_iter().enumerate() {
+            if i > 0 {
+                tokio::select! {
+                    _ = tokio::time::sleep(history::BATCH_INTERVAL) => {}
+                    _ = cancel.cancelled() => {}
+                }
+            }
+            cancel.check()?;
+            if !self.send_protocol_message(batch).await? {
+                return Err(anyhow::anyhow!("Network is not running"));
+            }
+            tracker.advance(1);
+        }
+        Ok(count)
+    }
+    
+    /// Run `run` in the background as `operation`; see `operations`
+    fn start_operation<T, F, Fut>(
+        &self,
+        operation: cancel::Operation,
+        unit: operations::ProgressUnit,
+        total: Option<u64>,
+        run: F,
+    ) -> operations::OperationHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(operations::ProgressTracker, cancel::CancellationToken) -> Fut,
+        Fut: std::future::Future<Output = Result<T>> + Send + 'static,
+    {
+        operations::spawn(self.events.clone(), operation, unit, total, self.shutdown.child_token(), run)
+    }
+    
+    /// Pass `result` on, reporting it if the operation was cancelled
+    fn report_cancelled<T>(&self, operation: cancel::Operation, result: Result<T>) -> Result<T> {
+        if result.as_ref().is_err_and(cancel::is_cancelled) {
+            self.events.publish(vec![ChatEvent::OperationCancelled { operation }]);
+        }
+        result
+    }
+    
+    /// Store the messages from a history batch that this device lacks.
+    /// Batches for our other devices are ignored and give None.
+    pub async fn apply_history_batch(&self, batch: &ProtocolMessage) -> Result<Option<history::AppliedBatch>> {
+        let ProtocolMessage::HistoryBatch { device_id, payload, signature } = batch else {
+            return Err(anyhow::anyhow!("Not a history batch"));
+        };
+        if *device_id != self.device_id {
+            return Ok(None);
+        }
+        self.verify_own_signature(&history::batch_signing_bytes(device_id, payload)?, signature).await
+            .context("History batch is not from one of our devices")?;
+        
+        let plaintext = self.message_keys.read().await.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?
+            .decrypt_message(payload)?;
+        let content: history::HistoryBatchContent = protocol::wire::decode(&plaintext)?;
+        let mut applied = history::AppliedBatch { added: 0, remaining: content.remaining };
+        
+        // Contacts come over with the regular sync data
+        let known = content.contact_id == protocol::SELF_CONTACT_ID
+            || self.storage().await?.get_contact(&content.contact_id)?.is_some();
+        if !known {
+            return Ok(Some(applied));
+        }
+        let conversation_id = self.get_or_create_conversation(&content.contact_id).await?.id;
+        
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        let mut conversation = storage
+            .get_conversation(&conversation_id)?
+            .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?;
+        
+        let messages: Vec<LocalMessage> = content.messages.into_iter()
+            .map(|message| LocalMessage { conversation_id: conversation_id.clone(), ..message })
+            .collect();
+        applied.added = storage.store_messages_batch(&mut conversation, &messages)?;
+        Ok(Some(applied))
+    }
+    
+    /// Check a signature made by our own identity key, i.e. by one of our devices
+    async fn verify_own_signature(&self, message: &[u8], signature: &[u8]) -> Result<()> {
+        let signature = ed25519_dalek::Signature::from_slice(signature)
+            .context("Malformed signature")?;
+        let identity = self.identity.read().await;
+        let identity = identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+        IdentityKeyPair::verify(&identity.public_key, message, &signature)
+    }
+    
+    /// Export the contact list, signed with our identity key and encrypted
+    /// with `password`, for import on another device or account
+    pub async fn export_contacts(&self, password: &str) -> Result<Vec<u8>> {
+        let contacts = self.get_contacts().await?;
+        let identity = self.identity.read().await;
+        let identity = identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+        contact_export::seal(contacts.iter().map(Into::into).collect(), identity, password)
+    }
+    
+    /// Merge an exported contact list. Contacts are matched by identity
+    /// key: unknown ones are added, known ones keep their local name and
+    /// take on the verified and blocked flags from the export.
+    pub async fn import_contacts(&self, data: &[u8], password: &str) -> Result<contact_export::ContactImport> {
+        let list = contact_export::open(data, password)?;
+        
+        let storage = self.storage().await?;
+        let mut local = storage.get_all_contacts()?;
+        
+        let mut result = contact_export::ContactImport { signer: list.signer, added: 0, updated: 0, unchanged: 0 };
+        for exported in list.contacts {
+            match local.iter_mut().find(|c| c.public_key == exported.public_key) {
+                Some(contact) => {
+                    let newly_verified = exported.verified && !contact.verified;
+                    let newly_blocked = exported.blocked && !contact.blocked;
+                    if !newly_verified && !newly_blocked {
+                        result.unchanged += 1;
+                        continue;
+                    }
+                    contact.verified |= exported.verified;
+                    contact.blocked |= exported.blocked;
+                    storage.store_contact(contact)?;
+                    if newly_verified {
+                        storage.append_security_event(&contact.id, SecurityEventKind::VerificationChanged { verified: true })?;
+                    }
+                    result.updated += 1;
+                }
+                None => {
+                    // Keep the id, so importing into the same account lines
+                    // up with its conversations, unless it is taken
+                    let id = if local.iter().any(|c| c.id == exported.id) {
+                        protocol::generate_id()
+                    } else {
+                        exported.id
+                    };
+                    let mut contact = Contact::new(id, exported.display_name, exported.public_key);
+                    contact.added_at = exported.added_at;
+                    contact.verified = exported.verified;
+                    contact.blocked = exported.blocked;
+                    storage.store_contact(&contact)?;
+                    if contact.verified {
+                        storage.append_security_event(&contact.id, SecurityEventKind::VerificationChanged { verified: true })?;
+                    }
+                    local.push(contact);
+                    result.added += 1;
+                }
+            }
+        }
+        Ok(result)
+    }
+    
+    /// Session details for a contact, for a session screen or support.
+    /// Holds counters and times only, never key material.
+    pub async fn get_session_info(&self, contact_id: &str) -> Result<devices::SessionInfo> {
+        let storage = self.storage().await?;
+        let contact = storage.get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        
+        let mut devices = Vec::new();
+        for device in storage.get_contact_devices(contact_id)? {
+            let session = storage.get_device_session(contact_id, &device.device_id)?;
+            devices.push(devices::DeviceSessionInfo {
+                device_id: device.device_id,
+                announced_at: device.announced_at,
+                session: session.as_ref().map(devices::SessionCounters::from),
+            });
+        }
+        let conversation = storage.get_conversation_by_contact(contact_id)?;
+        let authentication = match &conversation {
+            Some(conversation) => storage.get_authentication(&conversation.id)?.unwrap_or_default(),
+            None => devices::Authentication::default(),
+        };
+        let ratchet = conversation
+            .and_then(|conversation| conversation.ratchet_state)
+            .map(|ratchet| devices::RatchetInfo::from(&ratchet));
+        let invite_prekey = storage.get_contact_prekey(contact_id)?
+            .is_some_and(|prekey| prekey.expires_at > OffsetDateTime::now_utc());
+        let requirements = storage.get_session_requirements(contact_id)?.unwrap_or_default();
+        // Mirrors `dispatch_message`
+        let mode = if invite_prekey && !requirements.device_sessions {
+            devices::SessionMode::InvitePrekey
+        } else if !devices.is_empty() {
+            devices::SessionMode::DeviceSessions
+        } else {
+            devices::SessionMode::Unestablished
+        };
+        
+        Ok(devices::SessionInfo {
+            contact_id: contact.id,
+            protocol_version: protocol::wire::VERSION,
+            cipher_suite: crypto::MESSAGE_CIPHER_SUITE.to_string(),
+            mode,
+            requirements,
+            authentication,
+            verified: contact.verified,
+            invite_prekey,
+            last_rekey_at: devices.iter().map(|device| device.announced_at).max(),
+            devices,
+            ratchet,
+        })
+    }
+    
+    /// Safety number for a contact, derived from both identity keys
+    pub async fn get_safety_number(&self, contact_id: &str) -> Result<Fingerprint> {
+        let local_key = self.get_public_key().await?;
+        
+        let storage = self.storage().await?;
+        let contact = storage
+            .get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        
+        Ok(Fingerprint::for_conversation(&local_key, &contact.public_key))
+    }
+    
+    /// Mark a contact verified if the safety number compared out of band matches
+    pub async fn verify_contact(&self, contact_id: &str, safety_number: &str) -> Result<bool> {
+        let expected = self.get_safety_number(contact_id).await?;
+        if !expected.matches_safety_number(safety_number) {
+            return Ok(false);
+        }
+        
+        let storage = self.storage().await?;
+        let mut contact = storage
+            .get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        if !contact.verified {
+            contact.verified = true;
+            storage.store_contact(&contact)?;
+            storage.append_security_event(contact_id, SecurityEventKind::VerificationChanged { verified: true })?;
+        }
+        
+        Ok(true)
+    }
+    
+    /// Hold sessions with a contact to `requirements` from now on. Messages
+    /// falling short are refused both ways with
+    /// `ChatEvent::SessionDowngradeRefused`, never sent or accepted weaker.
+    pub async fn set_session_requirements(&self, contact_id: &str, requirements: devices::SessionRequirements) -> Result<()> {
+        let storage = self.storage().await?;
+        storage.get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        if storage.get_session_requirements(contact_id)?.unwrap_or_default() == requirements {
+            return Ok(());
+        }
+        storage.store_session_requirements(contact_id, &requirements)?;
+        storage.append_security_event(contact_id, SecurityEventKind::RequirementsChanged { requirements })?;
+        Ok(())
+    }
+    
+    pub async fn get_session_requirements(&self, contact_id: &str) -> Result<devices::SessionRequirements> {
+        Ok(self.storage().await?
+            .get_session_requirements(contact_id)?
+            .unwrap_or_default())
+    }
+    
+    /// Log and report a message refused under a contact's requirements
+    async fn refuse_downgrade(&self, contact_id: &str, downgrade: devices::Downgrade) -> Result<ChatEvent> {
+        tracing::warn!(?downgrade, "Refused a message below the contact's session requirements");
+        self.storage().await?
+            .append_security_event(contact_id, SecurityEventKind::DowngradeRefused { downgrade })?;
+        Ok(ChatEvent::SessionDowngradeRefused { contact_id: contact_id.to_string(), downgrade })
+    }
+    
+    /// Replace a contact's identity key, e.g. after they reinstalled. The
+    /// contact is no longer verified and both changes are logged.
+    pub async fn update_contact_key(&self, contact_id: &str, public_key: [u8; 32]) -> Result<()> {
+        let storage = self.storage().await?;
+        let mut contact = storage
+            .get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        if contact.public_key == public_key {
+            return Ok(());
+        }
+        
+        let old_key = std::mem::replace(&mut contact.public_key, public_key);
+        let was_verified = std::mem::replace(&mut contact.verified, false);
+        storage.store_contact(&contact)?;
+        
+        storage.append_security_event(contact_id, SecurityEventKind::KeyChanged { old_key, new_key: public_key })?;
+        if was_verified {
+            storage.append_security_event(contact_id, SecurityEventKind::VerificationChanged { verified: false })?;
+        }
+        
+        self.retry_quarantine(contact_id).await?;
+        Ok(())
+    }
+    
+    /// Security audit log for a contact, oldest first. Fails if entries
+    /// were removed, reordered or altered.
+    pub async fn get_security_events(&self, contact_id: &str) -> Result<Vec<SecurityEvent>> {
+        let storage = self.storage().await?;
+        storage.get_security_events(contact_id)
+    }
+    
+    /// Relay that delivers wake-up pings to push endpoints
+    pub async fn set_push_bridge(&self, bridge: Arc<dyn push::PushBridge>) {
+        *self.push_bridge.write().await = Some(bridge);
+    }
+    
+    /// Id of this device, to register its push endpoint under
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+    
+    /// Register the push endpoint one of our devices can be woken at,
+    /// replacing its previous one. Contacts learn it from us.
+    pub async fn register_push_endpoint(&self, device_id: &str, url: &str) -> Result<push::PushEndpoint> {
+        let endpoint = push::PushEndpoint::new(device_id, url)?;
+        self.storage().await?
+            .store_push_endpoint(&endpoint)?;
+        Ok(endpoint)
+    }
+    
+    /// Stop offering a device's push endpoint. Returns whether it had one.
+    pub async fn unregister_push_endpoint(&self, device_id: &str) -> Result<bool> {
+        self.storage().await?
+            .remove_push_endpoint(device_id)
+    }
+    
+    /// Push endpoints of our devices, to share with contacts
+    pub async fn get_push_endpoints(&self) -> Result<Vec<push::PushEndpoint>> {
+        self.storage().await?
+            .get_push_endpoints()
+    }
+    
+    /// Endpoints a contact shared for waking their devices, replacing
+    /// those known before
+    pub async fn set_contact_push_endpoints(&self, contact_id: &str, endpoints: Vec<push::PushEndpoint>) -> Result<()> {
+        if endpoints.len() > push::MAX_ENDPOINTS_PER_CONTACT {
+            return Err(anyhow::anyhow!("A contact can share at most {} push endpoints", push::MAX_ENDPOINTS_PER_CONTACT));
+        }
+        for endpoint in &endpoints {
+            endpoint.validate()?;
+        }
+        let storage = self.storage().await?;
+        storage.get_contact(contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        storage.set_contact_push_endpoints(contact_id, &endpoints)
+    }
+    
+    /// Ping a contact's push endpoints so their devices come online.
+    /// Done when redialing them gives up; at most once per
+    /// `push::MIN_WAKE_INTERVAL`. Returns how many endpoints were pinged.
+    pub async fn wake_contact(&self, contact_id: &str) -> Result<usize> {
+        let Some(bridge) = self.push_bridge.read().await.clone() else {
+            return Ok(0);
+        };
+        let endpoints = self.storage().await?
+            .get_contact_push_endpoints(contact_id)?;
+        if endpoints.is_empty() {
+            return Ok(0);
+        }
+        {
+            let now = std::time::Instant::now();
+            let mut wakes = self.push_wakes.write().await;
+            if wakes.get(contact_id).is_some_and(|last| now.duration_since(*last) < push::MIN_WAKE_INTERVAL) {
+                return Ok(0);
+            }
+            wakes.insert(contact_id.to_string(), now);
+        }
+        
+        let mut woken = 0;
+        for endpoint in endpoints {
+            match bridge.wake(&endpoint.url, &push::wake_ping()) {
+                Ok(()) => woken += 1,
+                Err(e) => tracing::warn!("Push to device {} of {} failed: {:#}", endpoint.device_id, contact_id, e),
+            }
+        }
+        Ok(woken)
+    }
+    
+    /// Application code that posts webhook payloads; until one is set,
+    /// webhooks are kept but not called
+    pub async fn set_webhook_sender(&self, sender: Arc<dyn webhooks::WebhookSender>) {
+        *self.webhook_sender.write().await = Some(sender);
+    }
+    
+    /// Register a webhook for `events`, about `contact_ids` only unless
+    /// empty. The returned webhook holds the secret its payloads are
+    /// signed with.
+    pub async fn add_webhook(&self, url: &str, events: Vec<webhooks::WebhookEvent>, contact_ids: Vec<String>) -> Result<webhooks::Webhook> {
+        let webhook = webhooks::Webhook::new(url, events, contact_ids)?;
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        if storage.get_webhooks()?.len() >= webhooks::MAX_WEBHOOKS {
+            return Err(anyhow::anyhow!("At most {} webhooks can be registered", webhooks::MAX_WEBHOOKS));
+        }
+        storage.store_webhook(&webhook)?;
+        Ok(webhook)
+    }
+    
+    pub async fn get_webhooks(&self) -> Result<Vec<webhooks::Webhook>> {
+        let mut webhooks = self.storage().await?
+            .get_webhooks()?;
+        webhooks.sort_by_key(|webhook| webhook.created_at);
+        Ok(webhooks)
+    }
+    
+    pub async fn remove_webhook(&self, webhook_id: &str) -> Result<()> {
+        self.storage().await?
+            .delete_webhook(webhook_id)
+    }
+    
+    /// Secret inbound requests are signed with, made on first use
+    pub async fn get_inbound_webhook_secret(&self) -> Result<String> {
+        let _updates = self.record_updates.lock().await;
+        let storage = self.storage().await?;
+        if let Some(secret) = storage.get_inbound_webhook_secret()? {
+            return Ok(secret);
+        }
+        let secret = webhooks::generate_secret();
+        storage.store_inbound_webhook_secret(&secret)?;
+        Ok(secret)
+    }
+    
+    /// Replace the inbound secret; requests signed with the old one fail
+    pub async fn rotate_inbound_webhook_secret(&self) -> Result<String> {
+        let secret = webhooks::generate_secret();
+        self.storage().await?
+            .store_inbound_webhook_secret(&secret)?;
+        Ok(secret)
+    }
+    
+    /// Send the text message an inbound request asks for, given the
+    /// request's body and signature header. Returns the message id; a
+    /// request replayed with the same signature is refused.
+    pub async fn handle_inbound_webhook(&self, signature: &str, body: &[u8]) -> Result<String> {
+        let secret = self.get_inbound_webhook_secret().await?;
+        let request = webhooks::parse_inbound(&secret, signature, body, OffsetDateTime::now_utc(), &self.inbound_replays)?;
+        self.storage().await?
+            .get_contact(&request.contact_id)?
+            .ok_or_else(|| anyhow::anyhow!("Contact not found"))?;
+        let conversation = self.get_or_create_conversation(&request.contact_id).await?;
+        self.send_text_message(&conversation.id, &request.text).await
+    }
+    
+    /// Post events to the webhooks that asked for them, off the event loop
+    async fn notify_webhooks(&self, events: &[ChatEvent]) {
+        let Some(sender) = self.webhook_sender.read().await.clone() else {
+            return;
+        };
+        let payloads: Vec<_> = events.iter().filter_map(webhooks::WebhookPayload::from_event).collect();
+        if payloads.is_empty() {
+            return;
+        }
+        let registered = match self.get_webhooks().await {
+            Ok(registered) => registered,
+            Err(e) => {
+                tracing::warn!("Failed to read webhooks: {:#}", e);
+                return;
+            }
+        };
+        for payload in payloads {
+            for webhook in registered.iter().filter(|webhook| webhook.wants(&payload)) {
+                let (sender, webhook, payload) = (sender.clone(), webhook.clone(), payload.clone());
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = webhooks::deliver(sender.as_ref(), &webhook, &payload) {
+                        tracing::warn!("Webhook {} failed: {:#}", webhook.id, e);
+                    }
+                });
+            }
+        }
+    }
+    
+    /// Registry used to publish and look up usernames
+    pub async fn set_username_registry(&self, registry: Arc<dyn username::UsernameRegistry>) {
+        *self.username_registry.write().await = Some(registry);
+    }
+    
+    /// Claim `username` for our identity key. Fails if someone else holds
+    /// it; claiming a name we already hold republishes the original claim.
+    pub async fn claim_username(&self, name: &str) -> Result<username::UsernameClaim> {
+        let name = username::normalize(name)?;
+        let registry = self.username_registry.read().await.clone()
+            .ok_or_else(|| anyhow::anyhow!("No username registry configured"))?;
+        let our_key = self.get_public_key().await?;
+        
+        let claim = match username::winning_claim(&name, registry.lookup(&name)?) {
+            Some(holder) if holder.identity_key == our_key => holder,
+            Some(_) => return Err(anyhow::anyhow!("Username '{}' is taken", name)),
+            None => {
+                let identity = self.identity.read().await;
+                let identity = identity.as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+                username::UsernameClaim::new(&name, identity)?
+            }
+        };
+        registry.publish(&claim)?;
+        
+        // Someone may have claimed it at the same time
+        match username::winning_claim(&name, registry.lookup(&name)?) {
+            Some(holder) if holder.identity_key == our_key => {}
+            _ => return Err(anyhow::anyhow!("Username '{}' is taken", name)),
+        }
+        
+        let storage = self.storage().await?;
+        storage.store_username_claim(&claim)?;
+        Ok(claim)
+    }
+    
+    /// Our own username claim, if any
+    pub async fn get_username(&self) -> Result<Option<username::UsernameClaim>> {
+        let storage = self.storage().await?;
+        storage.get_username_claim()
+    }
+    
+    /// Look up the identity key behind a username. The first answer is
+    /// pinned; a different key later is reported, not silently accepted.
+    pub async fn resolve_username(&self, name: &str) -> Result<username::UsernameResolution> {
+        let name = username::normalize(name)?;
+        let registry = self.username_registry.read().await.clone()
+            .ok_or_else(|| anyhow::anyhow!("No username registry configured"))?;
+        let claim = username::winning_claim(&name, registry.lookup(&name)?)
+            .ok_or_else(|| anyhow::anyhow!("Username '{}' not found", name))?;
+        
+        let storage = self.storage().await?;
+        Ok(match storage.get_username_pin(&name)? {
+            Some(pinned_key) if pinned_key == claim.identity_key => username::UsernameResolution::Pinned(claim),
+            Some(pinned_key) => username::UsernameResolution::KeyChanged { pinned_key, claim },
+            None => {
+                storage.pin_username(&name, &claim.identity_key)?;
+                username::UsernameResolution::FirstUse(claim)
+            }
+        })
+    }
+    
+    /// Pin a username to a new key after the user accepted the change
+    pub async fn accept_username_key(&self, name: &str, identity_key: [u8; 32]) -> Result<()> {
+        let name = username::normalize(name)?;
+        let storage = self.storage().await?;
+        storage.pin_username(&name, &identity_key)
+    }
+    
+    /// Get user profile
+    pub async fn get_profile(&self) -> Result<Option<UserProfile>> {
+        let storage = self.storage().await?;
+        storage.get_profile()
+    }
+    
+    /// Update profile
+    pub async fn update_profile(&self, display_name: Option<&str>, status_message: Option<&str>) -> Result<()> {
+        let storage = self.storage().await?;
+        
+        let mut profile = storage
+            .get_profile()?
+            .unwrap_or_else(|| UserProfile {
+                display_name: "Anonymous".to_string(),
+                status_message: None,
+                avatar: None,
+                created_at: OffsetDateTime::now_utc(),
+            });
+        
+        if let Some(name) = display_name {
+            profile.display_name = name.to_string();
+        }
+        if let Some(status) = status_message {
+            profile.status_message = Some(status.to_string());
+        }
+        
+        storage.store_profile(&profile)?;
+        *self.profile.write().await = Some(profile);
+        
+        Ok(())
+    }
+    
+    /// Set our avatar from an image, scaled down by the thumbnailer, or
+    /// remove it with `None`
+    pub async fn set_avatar(&self, image: Option<(&[u8], &str)>) -> Result<()> {
+        let avatar = match image {
+            Some((data, mime_type)) => {
+                let thumbnailer = self.thumbnailer.read().await.clone()
+                    .ok_or_else(|| anyhow::anyhow!("No thumbnailer set"))?;
+                let thumbnail = thumbnailer.thumbnail(data, mime_type, thumbnail::AVATAR_EDGE)?
+                    .ok_or_else(|| anyhow::anyhow!("Unsupported avatar format: {}", mime_type))?;
+                thumbnail.validate()?;
+                Some(thumbnail.data)
+            }
+            None => None,
+        };
+        
+        let storage = self.storage().await?;
+        let mut profile = storage.get_profile()?
+            .ok_or_else(|| anyhow::anyhow!("No profile"))?;
+        profile.avatar = avatar;
+        storage.store_profile(&profile)?;
+        *self.profile.write().await = Some(profile);
+        Ok(())
+    }
+    
+    /// Get public identity key for sharing
+    pub async fn get_public_key(&self) -> Result<[u8; 32]> {
+        let identity = self.identity.read().await;
+        let identity = identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+        Ok(identity.public_key.to_bytes())
+    }
+    
+    /// Our identity key and display name as a signed, ASCII-armored
+    /// block for pasting into email or posts, see `protocol::armor`
+    pub async fn export_identity_armor(&self) -> Result<String> {
+        let display_name = self.get_profile().await?
+            .map(|profile| profile.display_name)
+            .unwrap_or_default();
+        let identity = self.identity.read().await;
+        let identity = identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+        protocol::armor::generate(identity, &display_name)
+    }
+    
+    /// Add the contact in an armored identity block someone pasted. They
+    /// start out unverified; a block only proves the name came with the
+    /// key, not whose key it is.
+    pub async fn add_contact_from_armor(&self, text: &str) -> Result<Contact> {
+        let block = protocol::armor::parse(text)?;
+        if block.identity_key == self.get_public_key().await? {
+            return Err(anyhow::anyhow!("That is our own identity"));
+        }
+        let storage = self.storage().await?;
+        if let Some(contact) = storage.get_all_contacts()?.into_iter().find(|c| c.public_key == block.identity_key) {
+            return Ok(contact);
+        }
+        let contact = Contact::new(protocol::generate_id(), block.display_name, block.identity_key);
+        storage.store_contact(&contact)?;
+        Ok(contact)
+    }
+    
+    /// Export encrypted backup
+    pub async fn export_backup(&self, password: &str) -> Result<Vec<u8>> {
+        self.export_backup_to(Vec::new(), password, &cancel::CancellationToken::new()).await
+    }
+    
+    /// Stream a full encrypted backup (format version 3) into `writer`:
+    /// every record, including messages, identity and session state. A
+    /// backup cancelled with `cancel` is unfinished and must be discarded.
+    pub async fn export_backup_to<W: std::io::Write>(&self, writer: W, password: &str, cancel: &cancel::CancellationToken) -> Result<W> {
+        let storage = self.storage().await?;
+        
+        let backup = backup::BackupWriter::new(writer, password)?;
+        let exported = backup::export_storage(&storage, backup, cancel, |_| {});
+        self.report_cancelled(cancel::Operation::BackupExport, exported)
+    }
+    
+    /// Export a full backup to the file at `path` in the background,
+    /// reporting the records written. The file only appears once complete.
+    pub async fn start_backup_export(&self, path: PathBuf, password: &str) -> Result<operations::OperationHandle<u64>> {
+        let storage = self.storage().await?;
+        let password = zeroize::Zeroizing::new(password.to_string());
+        let total = storage.record_count() as u64;
+        
+        let chat = self.clone();
+        Ok(self.start_operation(cancel::Operation::BackupExport, operations::ProgressUnit::Items, Some(total), move |mut tracker, cancel| async move {
+            let exported = tokio::task::spawn_blocking(move || {
+                let partial = partial_path(&path);
+                let exported = (|| -> Result<u64> {
+                    let file = std::fs::File::create(&partial).context("Failed to create backup file")?;
+                    let backup = backup::BackupWriter::new(std::io::BufWriter::new(file), &password)?;
+                    let file = backup::export_storage(&storage, backup, &cancel, |written| tracker.set_done(written as u64))?
+                        .into_inner()
+                        .map_err(|e| anyhow::anyhow!("Failed to write backup: {}", e))?;
+                    file.sync_all()?;
+                    std::fs::rename(&partial, &path)?;
+                    tracker.set_done(total);
+                    Ok(std::fs::metadata(&path)?.len())
+                })();
+                if exported.is_err() {
+                    std::fs::remove_file(&partial).ok();
+                }
+                exported
+            }).await?;
+            chat.report_cancelled(cancel::Operation::BackupExport, exported)
+        }))
+    }
+    
+    /// Start writing automatic backups every `schedule.interval`, replacing
+    /// any running schedule. Backups are restorable with the account
+    /// password; results are reported on the network event channel. A
+    /// failed run is tried again `schedule.retries` times, with backoff,
+    /// before it is reported.
+    pub async fn start_backup_scheduler(&self, schedule: backup::BackupSchedule) -> Result<()> {
+        if schedule.keep == 0 {
+            return Err(anyhow::anyhow!("Backup retention must keep at least one backup"));
+        }
+        self.stop_backup_scheduler().await;
+        
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        let suspended = self.suspended.clone();
+        let cancel = self.shutdown.child_token();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(schedule.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut last_checksum = None;
+            
+            loop {
+                interval.tick().await;
+                if suspended.read().await.is_some() {
+                    continue;
+                }
+                
+                let mut attempt = 0;
+                let result = loop {
+                    let Some(current) = storage.read().await.clone() else {
+                        break Err(anyhow::anyhow!("Storage not initialized"));
+                    };
+                    // Stores may block on the network
+                    let run = tokio::task::spawn_blocking({
+                        let (schedule, cancel) = (schedule.clone(), cancel.clone());
+                        let mut checksum = last_checksum;
+                        move || {
+                            let result = backup::run_scheduled_backup(&current, &schedule, &mut checksum, &cancel);
+                            (result, checksum)
+                        }
+                    });
+                    let result = match run.await {
+                        Ok((result, checksum)) => {
+                            last_checksum = checksum;
+                            result
+                        }
+                        Err(e) => Err(e.into()),
+                    };
+                    match result {
+                        Err(e) if attempt < schedule.retries && !cancel::is_cancelled(&e) => {
+                            let delay = reconnect::backoff_delay(attempt, rand::random());
+                            tracing::warn!("Scheduled backup to {} failed, retrying in {:?}: {:#}", schedule.store.name(), delay, e);
+                            attempt += 1;
+                            tokio::select! {
+                                _ = tokio::time::sleep(delay) => {}
+                                _ = cancel.cancelled() => break Err(cancel::Cancelled.into()),
+                            }
+                        }
+                        result => break result,
+                    }
+                };
+                let event = match result {
+                    Ok(backup::ScheduledBackup::Written { location, size, .. }) => {
+                        ChatEvent::BackupCompleted { path: PathBuf::from(location), size }
+                    }
+                    Ok(backup::ScheduledBackup::Unchanged) => continue,
+                    Err(e) if cancel::is_cancelled(&e) => {
+                        events.publish(vec![ChatEvent::OperationCancelled { operation: cancel::Operation::BackupExport }]);
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::error!("Scheduled backup failed: {:#}", e);
+                        ChatEvent::BackupFailed { error: format!("{:#}", e) }
+                    }
+                };
+                
+                events.publish(vec![event]);
+            }
+        });
+        
+        *self.backup_task.write().await = Some(task);
+        Ok(())
+    }
+    
+    /// Stop automatic backups
+    pub async fn stop_backup_scheduler(&self) {
+        if let Some(task) = self.backup_task.write().await.take() {
+            task.abort();
+        }
+    }
+    
+    pub async fn get_default_retention(&self) -> Result<retention::RetentionPolicy> {
+        self.storage().await?
+            .get_default_retention()
+    }
+    
+    /// Retention for every conversation without a policy of its own
+    pub async fn set_default_retention(&self, policy: retention::RetentionPolicy) -> Result<()> {
+        policy.validate()?;
+        self.storage().await?
+            .store_default_retention(&policy)
+    }
+    
+    /// A conversation's own retention policy, `None` if it follows the
+    /// default
+    pub async fn get_conversation_retention(&self, conversation_id: &str) -> Result<Option<retention::RetentionPolicy>> {
+        self.storage().await?
+            .get_conversation_retention(conversation_id)
+    }
+    
+    pub async fn set_conversation_retention(&self, conversation_id: &str, policy: Option<retention::RetentionPolicy>) -> Result<()> {
+        if let Some(policy) = &policy {
+            policy.validate()?;
+        }
+        let storage = self.storage().await?;
+        storage.get_conversation(conversation_id)?
+            .ok_or_else(|| anyhow::anyhow!("Conversation not found"))?;
+        storage.store_conversation_retention(conversation_id, policy.as_ref())
+    }
+    
+    /// Apply every conversation's retention policy now
+    pub async fn prune_messages(&self) -> Result<retention::PruneReport> {
+        prune_storage(&self.storage).await
+    }
+    
+    /// Prune messages every `retention::PRUNE_INTERVAL`, starting now,
+    /// reporting each pass that deleted something
+    pub async fn start_retention_task(&self) {
+        self.stop_retention_task().await;
+        self.stop_gc_sweep().await;
+        
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        let suspended = self.suspended.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(retention::PRUNE_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if suspended.read().await.is_some() {
+                    continue;
+                }
+                
+                let event = match prune_storage(&storage).await {
+                    Ok(report) if report.messages == 0 => continue,
+                    Ok(report) => ChatEvent::MessagesPruned { report },
+                    Err(e) => {
+                        tracing::error!("Pruning messages failed: {:#}", e);
+                        ChatEvent::Error { message: format!("Pruning messages failed: {:#}", e) }
+                    }
+                };
+                events.publish(vec![event]);
+            }
+        });
+        *self.retention_task.write().await = Some(task);
+    }
+    
+    pub async fn stop_retention_task(&self) {
+        if let Some(task) = self.retention_task.write().await.take() {
+            task.abort();
+        }
+    }
+    
+    pub async fn storage_usage(&self) -> Result<usage::UsageReport> {
+        self.storage().await?
+            .usage_report()
+    }
+    
+    /// Delete orphaned attachments and reclaim free space, reporting
+    /// progress as `CompactionProgress` events. Attachments can't be sent
+    /// until it finishes.
+    pub async fn compact_storage(&self) -> Result<usage::CompactionReport> {
+        let _writes = self.attachment_writes.write().await;
+        self.storage().await?
+            .compact(|progress| {
+                self.events.publish(vec![ChatEvent::CompactionProgress { progress }]);
+            })
+    }
+    
+    /// Rebuild the conversation and message indexes in the background,
+    /// reporting the records read. Best run while idle: a message whose
+    /// status changes meanwhile may be indexed with the old one.
+    pub async fn start_reindex(&self) -> Result<operations::OperationHandle<()>> {
+        let storage = self.storage().await?;
+        let chat = self.clone();
+        Ok(self.start_operation(cancel::Operation::Reindex, operations::ProgressUnit::Items, None, move |mut tracker, cancel| async move {
+            let rebuilt = tokio::task::spawn_blocking(move || {
+                storage.rebuild_indexes_with(&cancel, |done, total| {
+                    tracker.set_total(total as u64);
+                    tracker.set_done(done as u64);
+                })
+            }).await?;
+            chat.report_cancelled(cancel::Operation::Reindex, rebuilt)
+        }))
+    }
+    
+    pub async fn durability(&self) -> durability::Durability {
+        *self.durability.read().await
+    }
+    
+    /// Change how writes reach the disk, now and whenever storage is
+    /// opened again
+    pub async fn set_durability(&self, settings: durability::Durability) -> Result<()> {
+        settings.validate()?;
+        if let Some(storage) = self.storage.read().await.as_ref() {
+            storage.set_durability(settings)?;
+        }
+        *self.durability.write().await = settings;
+        Ok(())
+    }
+    
+    pub async fn protocol_limits(&self) -> limits::ProtocolLimits {
+        *self.limits.read().await
+    }
+    
+    /// Change the bounds on what peers can send. Content is checked
+    /// against them right away; frames from the network once it is next
+    /// started.
+    pub async fn set_protocol_limits(&self, limits: limits::ProtocolLimits) -> Result<()> {
+        limits.validate()?;
+        *self.limits.write().await = limits;
+        Ok(())
+    }
+    
+    /// Write everything to disk now, e.g. before the app is suspended
+    pub async fn flush(&self) -> Result<()> {
+        self.storage().await?
+            .flush()
+    }
+    
+    /// Hit rate and size of the cache of decrypted records
+    pub async fn cache_stats(&self) -> Result<cache::CacheStats> {
+        Ok(self.storage().await?.cache_stats())
+    }
+    
+    /// What the integrity check at unlock found; records it reports as
+    /// corrupt were quarantined
+    pub async fn integrity_report(&self) -> Option<integrity::IntegrityReport> {
+        self.integrity.read().await.clone()
+    }
+    
+    /// Check every record now, quarantining unreadable ones if `quarantine`
+    pub async fn verify_integrity(&self, quarantine: bool) -> Result<integrity::IntegrityReport> {
+        let storage = self.storage().await?;
+        let report = storage.verify_integrity()?;
+        if quarantine {
+            storage.quarantine_records(report.corrupt_keys())?;
+        }
+        Ok(report)
+    }
+    
+    /// Status of storage and the network, for supervisors to tell a
+    /// wedged node from a working one. Never fails: a part that can't be
+    /// checked is reported as down.
+    pub async fn health(&self) -> health::HealthReport {
+        let storage = self.storage.read().await.clone();
+        let last_flush = storage.as_ref().and_then(|s| s.last_flush());
+        let flush_every = storage.as_ref()
+            .and_then(|s| s.durability().flush_every_ms)
+            .map(std::time::Duration::from_millis);
+        let storage_health = health::StorageHealth {
+            open: storage.is_some(),
+            last_flush,
+            flush_overdue: health::flush_overdue(last_flush, flush_every, OffsetDateTime::now_utc()),
+            outbox_depth: storage.as_ref().map_or(0, |s| s.outbox_len()),
+        };
+        
+        let running = self.is_network_running().await;
+        let (responsive, connected_peers) = if running {
+            match tokio::time::timeout(health::PROBE_TIMEOUT, self.get_peer_stats()).await {
+                Ok(Ok(peers)) => (Some(true), peers.iter().filter(|p| p.connected).count()),
+                // Stopped while we asked
+                Ok(Err(_)) if !self.is_network_running().await => (None, 0),
+                Ok(Err(_)) | Err(_) => (Some(false), 0),
+            }
+        } else {
+            (None, 0)
+        };
+        let network_health = health::NetworkHealth {
+            running,
+            responsive,
+            connected_peers,
+            deferred_messages: self.deferred_message_count().await,
+        };
+        health::HealthReport::new(storage_health, network_health)
+    }
+    
+    /// A description of this setup for bug reports, naming no contact or
+    /// peer and holding no message content or key. Integrity comes from
+    /// the check at unlock, or a fresh check if there was none.
+    pub async fn diagnostics(&self) -> Result<diagnostics::DiagnosticBundle> {
+        let storage = self.storage().await?;
+        let integrity = match self.integrity_report().await {
+            Some(report) => report,
+            None => storage.verify_integrity()?,
+        };
+        let peers = self.get_peer_stats().await.unwrap_or_default();
+        Ok(diagnostics::DiagnosticBundle {
+            generated_at: OffsetDateTime::now_utc(),
+            version: diagnostics::VersionInfo::current(),
+            settings: diagnostics::DiagnosticSettings {
+                privacy: storage.get_privacy_settings()?,
+                durability: self.durability().await,
+                conditions: self.network_conditions().await,
+                default_retention: storage.get_default_retention()?,
+                contact_request_difficulty: storage.get_contact_request_difficulty()?,
+                bootstrap_nodes: storage.get_bootstrap_nodes()?.len(),
+                push_endpoints: storage.get_push_endpoints()?.len(),
+            },
+            network: diagnostics::NetworkDiagnostics {
+                status: self.get_network_status().await,
+                peers: peers.into_iter().map(Into::into).collect(),
+                deferred_messages: self.deferred_message_count().await,
+            },
+            storage: diagnostics::StorageDiagnostics {
+                contacts: storage.get_all_contacts()?.len(),
+                conversations: storage.get_all_conversations()?.len(),
+                message_requests: storage.count_message_requests(),
+                integrity: (&integrity).into(),
+            },
+            logs: telemetry::recent_logs(),
+        })
+    }
+    
+    /// `diagnostics` as a JSON file to attach to an issue
+    pub async fn export_diagnostics(&self) -> Result<Vec<u8>> {
+        let bundle = self.diagnostics().await?;
+        Ok(serde_json::to_vec_pretty(&bundle)?)
+    }
+    
+    /// Delete records whose parent is gone, or with `dry_run` only count
+    /// them
+    pub async fn collect_garbage(&self, dry_run: bool) -> Result<gc::GcReport> {
+        collect_garbage_in(&self.storage, &self.attachment_writes, dry_run).await
+    }
+    
+    /// Sweep for orphaned records every `gc::SWEEP_INTERVAL`, starting now,
+    /// reporting each sweep that found something. A dry run only reports.
+    pub async fn start_gc_sweep(&self, dry_run: bool) {
+        self.stop_gc_sweep().await;
+        
+        let storage = self.storage.clone();
+        let attachment_writes = self.attachment_writes.clone();
+        let events = self.events.clone();
+        let suspended = self.suspended.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(gc::SWEEP_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if suspended.read().await.is_some() {
+                    continue;
+                }
+                
+                let event = match collect_garbage_in(&storage, &attachment_writes, dry_run).await {
+                    Ok(report) if report.total() == 0 => continue,
+                    Ok(report) => ChatEvent::GarbageCollected { report },
+                    Err(e) => {
+                        tracing::error!("Garbage collection failed: {:#}", e);
+                        ChatEvent::Error { message: format!("Garbage collection failed: {:#}", e) }
+                    }
+                };
+                events.publish(vec![event]);
+            }
+        });
+        *self.gc_task.write().await = Some(task);
+    }
+    
+    pub async fn stop_gc_sweep(&self) {
+        if let Some(task) = self.gc_task.write().await.take() {
+            task.abort();
+        }
+    }
+    
+    /// Delete expired prekeys, old skipped message keys and stale sessions
+    pub async fn clean_key_material(&self) -> Result<maintenance::MaintenanceReport> {
+        let _updates = self.record_updates.lock().await;
+        self.storage().await?
+            .clean_key_material(OffsetDateTime::now_utc())
+    }
+    
+    /// Clean up key material every `maintenance::MAINTENANCE_INTERVAL`,
+    /// starting now, reporting each pass that deleted something
+    pub async fn start_key_maintenance(&self) {
+        self.stop_key_maintenance().await;
+        
+        let chat = self.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(maintenance::MAINTENANCE_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if chat.suspended.read().await.is_some() {
+                    continue;
+                }
+                
+                let event = match chat.clean_key_material().await {
+                    Ok(report) if report.total() == 0 => continue,
+                    Ok(report) => ChatEvent::KeyMaterialCleaned { report },
+                    Err(e) => {
+                        tracing::error!("Key maintenance failed: {:#}", e);
+                        ChatEvent::Error { message: format!("Key maintenance failed: {:#}", e) }
+                    }
+                };
+                chat.emit(vec![event]).await;
+            }
+        });
+        *self.maintenance_task.write().await = Some(task);
+    }
+    
+    pub async fn stop_key_maintenance(&self) {
+        if let Some(task) = self.maintenance_task.write().await.take() {
+            task.abort();
+        }
+    }
+    
+    /// Restore a backup into a new database protected by `password`, then
+    /// unlock it. On error the partially written database should be removed.
+    pub async fn restore_backup<R: std::io::Read, P: AsRef<Path>>(
+        &self,
+        reader: R,
+        backup_password: &str,
+        db_path: P,
+        password: &str,
+    ) -> Result<()> {
+        self.restore_backup_with(reader, backup_password, db_path.as_ref(), password, &cancel::CancellationToken::new()).await
+    }
+    
+    /// Restore the backup file at `backup_path` in the background, like
+    /// `restore_backup`, reporting the bytes read
+    pub async fn start_backup_restore(
+        &self,
+        backup_path: PathBuf,
+        backup_password: &str,
+        db_path: PathBuf,
+        password: &str,
+    ) -> Result<operations::OperationHandle<()>> {
+        let file = std::fs::File::open(&backup_path).context("Failed to open backup")?;
+        let total = file.metadata()?.len();
+        let backup_password = zeroize::Zeroizing::new(backup_password.to_string());
+        let password = zeroize::Zeroizing::new(password.to_string());
+        
+        let chat = self.clone();
+        Ok(self.start_operation(cancel::Operation::BackupRestore, operations::ProgressUnit::Bytes, Some(total), move |mut tracker, cancel| async move {
+            let reader = operations::ProgressReader::new(std::io::BufReader::new(file), &mut tracker);
+            let restored = chat.restore_backup_with(reader, &backup_password, &db_path, &password, &cancel).await;
+            chat.report_cancelled(cancel::Operation::BackupRestore, restored)
+        }))
+    }
+    
+    /// A restore that fails or is cancelled removes the database it
+    /// created
+    async fn restore_backup_with<R: std::io::Read>(
+        &self,
+        reader: R,
+        backup_password: &str,
+        db_path: &Path,
+        password: &str,
+        cancel: &cancel::CancellationToken,
+    ) -> Result<()> {
+        let created = !db_path.exists();
+        let backup_keys = self.escrowed_backup_keys().await?;
+        let storage = match import_backup(reader, backup_password, &backup_keys, db_path, password, cancel) {
+            Ok(storage) => storage,
+            Err(e) => {
+                if created {
+                    std::fs::remove_dir_all(db_path).ok();
+                }
+                return Err(e);
+            }
+        };
+        
+        self.open_temp_store(db_path).await?;
+        *self.storage.write().await = Some(storage);
+        self.load_account().await
+    }
+    
+    /// What the backup file at `path` holds, from its manifest, without
+    /// decrypting its records
+    pub async fn inspect_backup(&self, path: &Path, backup_password: &str) -> Result<backup::BackupManifest> {
+        let file = std::fs::File::open(path).context("Failed to open backup")?;
+        let backup_keys = self.escrowed_backup_keys().await?;
+        let archive = backup::BackupArchive::open_with(std::io::BufReader::new(file), backup_password, &backup_keys)?;
+        Ok(archive.manifest().clone())
+    }
+    
+    /// Check every chunk of the backup file at `path` against its
+    /// manifest, returning the numbers of the damaged ones
+    pub async fn verify_backup(&self, path: &Path, backup_password: &str) -> Result<Vec<usize>> {
+        let file = std::fs::File::open(path).context("Failed to open backup")?;
+        let backup_keys = self.escrowed_backup_keys().await?;
+        let mut archive = backup::BackupArchive::open_with(std::io::BufReader::new(file), backup_password, &backup_keys)?;
+        Ok(archive.verify())
+    }
+    
+    /// Restore part of the backup file at `path` into this account,
+    /// reading only the chunks that hold it. Records already here are kept
+    /// as they are. Returns the number of records restored.
+    pub async fn restore_backup_partial(
+        &self,
+        path: &Path,
+        backup_password: &str,
+        selection: &backup::RestoreSelection,
+    ) -> Result<usize> {
+        let storage = self.storage().await?;
+        let file = std::fs::File::open(path).context("Failed to open backup")?;
+        let backup_keys = storage.get_escrowed_backup_keys()?;
+        let mut archive = backup::BackupArchive::open_with(std::io::BufReader::new(file), backup_password, &backup_keys)?;
+        let transport_key = *archive.content_key();
+        storage.import_selected(selection, &transport_key, |prefix| archive.records_with_prefix(prefix))
+    }
+    
+    /// Offer this account's backup key to another of our devices, as a QR
+    /// code valid for `valid_for`. Returns the offer with the confirmation
+    /// code to show beside it; the key itself is never shown.
+    pub async fn offer_backup_key(&self, valid_for: std::time::Duration) -> Result<(escrow::BackupKeyOffer, String)> {
+        let storage = self.storage().await?;
+        let device_name = storage.get_device(&self.device_id)?
+            .map(|device| device.device_name)
+            .unwrap_or_default();
+        let identity = self.identity.read().await;
+        let identity = identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+        escrow::BackupKeyOffer::new(identity, &storage.keys().backup, &device_name, valid_for)
+    }
+    
+    /// Keep the backup key another of our devices offers in a QR code,
+    /// opened with the confirmation code it shows. Account backups that
+    /// device made then open here without its password.
+    pub async fn accept_backup_key(&self, link: &str, code: &str) -> Result<escrow::BackupKeyInfo> {
+        let offer = escrow::BackupKeyOffer::parse(link, OffsetDateTime::now_utc())?;
+        if offer.identity_key != self.get_public_key().await? {
+            return Err(anyhow::anyhow!("This backup key belongs to another account"));
+        }
+        let storage = self.storage().await?;
+        if offer.key_id == escrow::key_id(&storage.keys().backup) {
+            return Err(anyhow::anyhow!("This is this device's own backup key"));
+        }
+        let escrowed = offer.open(code)?;
+        storage.store_escrowed_backup_key(&escrowed)?;
+        Ok(escrowed.info.clone())
+    }
+    
+    /// Backup keys received from our other devices
+    pub async fn get_escrowed_backup_keys(&self) -> Result<Vec<escrow::BackupKeyInfo>> {
+        let mut keys: Vec<_> = self.storage().await?
+            .get_escrowed_backup_keys()?
+            .iter()
+            .map(|key| key.info.clone())
+            .collect();
+        keys.sort_by_key(|key| key.received_at);
+        Ok(keys)
+    }
+    
+    pub async fn remove_escrowed_backup_key(&self, key_id: &str) -> Result<()> {
+        self.storage().await?
+            .delete_escrowed_backup_key(key_id)
+    }
+    
+    /// Backup keys to try on account backups; none before an account is
+    /// open, as when restoring onto a new device
+    async fn escrowed_backup_keys(&self) -> Result<Vec<escrow::EscrowedBackupKey>> {
+        match self.storage.read().await.clone() {
+            Some(storage) => storage.get_escrowed_backup_keys(),
+            None => Ok(Vec::new()),
+        }
+    }
+    
+    /// Close and cleanup
+    pub async fn close(self) -> Result<()> {
+        self.shutdown.cancel();
+        self.stop_backup_scheduler().await;
+        self.stop_retention_task().await;
+        self.stop_gc_sweep().await;
+        self.stop_key_maintenance().await;
+        self.stop_cache_warming().await;
+        self.stop_network().await.ok();
+        // Storage will be dropped; handles still held elsewhere mustn't
+        // keep decrypted records around
+        if let Some(storage) = self.storage.write().await.take() {
+            storage.clear_cache();
+        }
+        self.temp_store.write().await.take();
+        Ok(())
+    }
+}
+
+/// Create a database at `db_path` holding the records of a backup
+fn import_backup<R: std::io::Read>(
+    reader: R,
+    backup_password: &str,
+    backup_keys: &[escrow::EscrowedBackupKey],
+    db_path: &Path,
+    password: &str,
+    cancel: &cancel::CancellationToken,
+) -> Result<SecureStorage> {
+    let backup = backup::BackupReader::open_with(reader, backup_password, backup_keys)?;
+    let transport_key = *backup.content_key();
+    
+    let storage = SecureStorage::create(db_path, password)
+        .context("Failed to create database")?;
+    // Backups carry their message schema; older ones have none
+    storage.set_message_schema(1)?;
+    // Message records stay as they are in the backup, which may
+    // predate the current message schema, and are indexed afterwards
+    let mut records = Vec::with_capacity(backup::RESTORE_BATCH);
+    for record in backup {
+        cancel.check()?;
+        let record = record.context("Backup is corrupt")?;
+        records.push((record.key, record.value));
+        if records.len() == backup::RESTORE_BATCH {
+            storage.import_records(&records, &transport_key)?;
+            records.clear();
+        }
+    }
+    storage.import_records(&records, &transport_key)?;
+    storage.migrate_message_schema()?;
+    storage.rebuild_indexes_with(cancel, |_, _| {})?;
+    storage.flush()?;
+    Ok(storage)
+}
+
+/// Where a file is written until it is complete
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+/// Read the newest messages of the top conversations into the cache,
+/// taking a new storage handle for each so it stops once storage is
+/// closed. Returns the number of messages read.
+async fn warm_cache(storage: &RwLock<Option<SecureStorage>>, warming: startup::CacheWarming) -> Result<usize> {
+    let Some(handle) = storage.read().await.clone() else {
+        return Ok(0);
+    };
+    let mut conversations = tokio::task::spawn_blocking(move || handle.get_all_conversations()).await??;
+    conversations.retain(|conversation| !conversation.archived);
+    // Stable, so the most recent come first among pinned and unpinned alike
+    conversations.sort_by_key(|conversation| !conversation.pinned);
+    
+    let mut warmed = 0;
+    for conversation in conversations.into_iter().take(warming.conversations) {
+        let Some(handle) = storage.read().await.clone() else {
+            break;
+        };
+        warmed += tokio::task::spawn_blocking(move || handle.get_messages(&conversation.id, warming.messages))
+            .await??
+            .len();
+    }
+    Ok(warmed)
+}
+
+/// One retention pass over every conversation
+async fn prune_storage(storage: &RwLock<Option<SecureStorage>>) -> Result<retention::PruneReport> {
+    let storage = storage.read().await.clone()
+        .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?;
+    let now = OffsetDateTime::now_utc();
+    let mut report = retention::PruneReport::default();
+    for conversation in storage.conversations() {
+        let conversation = conversation?;
+        let policy = storage.retention_policy(&conversation.id)?;
+        report.add(storage.prune_conversation(&conversation.id, &policy, now)?);
+    }
+    Ok(report)
+}
+
+async fn collect_garbage_in(
+    storage: &RwLock<Option<SecureStorage>>,
+    attachment_writes: &RwLock<()>,
+    dry_run: bool,
+) -> Result<gc::GcReport> {
+    let _writes = attachment_writes.write().await;
+    storage.read().await.clone()
+        .ok_or_else(|| anyhow::anyhow!("Storage not initialized"))?
+        .collect_garbage(dry_run)
+}
+
+/// Id the message in an envelope is stored under: the same for every
+/// envelope a message was fanned out in
+fn logical_message_id(envelope: &MessageEnvelope) -> &str {
+    envelope.message_id.as_deref().unwrap_or(&envelope.id)
+}
+
+/// A message's full text for exports; other content as in previews
+fn export_text(message: &LocalMessage) -> String {
+    match &message.content {
+        MessageContent::Text { text } => text.clone(),
+        MessageContent::RichText(rich) => rich.text.clone(),
+        _ => message.preview_text(),
+    }
+}
+
+/// The identity key a sender named by its key stands for
+fn identity_key_from_id(sender_id: &str) -> Option<[u8; 32]> {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD.decode(sender_id).ok()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+}
+
+fn detect_platform() -> Platform {
+    #[cfg(target_os = "linux")]
+    return Platform::Linux;
+    
+    #[cfg(target_os = "windows")]
+    return Platform::Windows;
+    
+    #[cfg(target_os = "android")]
+    return Platform::Android;
+    
+    #[cfg(target_os = "macos")]
     return Platform::MacOS;
     
-    #[cfg(target_os = "ios")]
-    return Platform::IOS;
+    #[cfg(target_os = "ios")]
+    return Platform::IOS;
+    
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "windows",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios"
+    )))]
+    return Platform::Unknown;
+}
+
+// Re-exports
+pub use crypto::{hash_password, verify_password};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use query::{ConversationFilter, ConversationSort};
+    use backup_sink::{BackupSink, BackupSource};
+    
+    #[tokio::test]
+    async fn test_create_and_unlock() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        
+        // Create account and drop the instance to release the database lock
+        {
+            let chat = SecureChat::new(None);
+            chat.create_account(&db_path, "password123", "Test User").await.unwrap();
+        }
+        
+        // Unlock should work now that the database is closed
+        {
+            let chat2 = SecureChat::new(None);
+            chat2.unlock_account(&db_path, "password123").await.unwrap();
+        }
+        
+        // Wrong password should fail
+        let chat3 = SecureChat::new(None);
+        assert!(chat3.unlock_account(&db_path, "wrong_password").await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_unlock_reports_progress_and_warms_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        {
+            let chat = SecureChat::new(None);
+            chat.create_account(&db_path, "password", "User").await.unwrap();
+            let alice = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+            let conversation = chat.get_or_create_conversation(&alice.id).await.unwrap();
+            for i in 0..5 {
+                chat.send_text_message(&conversation.id, &i.to_string()).await.unwrap();
+            }
+            chat.close().await.unwrap();
+        }
+        
+        let chat = SecureChat::new(None);
+        chat.set_cache_warming(Some(startup::CacheWarming { conversations: 1, messages: 3 })).await;
+        chat.unlock_account(&db_path, "password").await.unwrap();
+        
+        let mut phases: Vec<_> = chat.replay_events(1).events.into_iter()
+            .filter_map(|sequenced| match sequenced.event {
+                ChatEvent::UnlockProgress { phase } => Some(phase),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(phases.len(), startup::UnlockPhase::COUNT);
+        assert_eq!(phases.remove(0), startup::UnlockPhase::KeyDerived);
+        
+        let task = chat.warm_task.write().await.take().unwrap();
+        task.await.unwrap();
+        let before = chat.cache_stats().await.unwrap();
+        let conversation = &chat.get_conversations().await.unwrap()[0];
+        chat.get_messages(&conversation.id, 3).await.unwrap();
+        let after = chat.cache_stats().await.unwrap();
+        assert_eq!(after.misses, before.misses);
+        assert!(after.hits > before.hits);
+    }
+    
+    #[tokio::test]
+    async fn test_recovery_phrase_resets_password_and_recovers_identity() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        
+        let (phrase, public_key) = {
+            let chat = SecureChat::new(None);
+            let phrase = chat.create_account_with_recovery(&db_path, "forgotten", "Test User", None).await.unwrap();
+            assert!(chat.verify_recovery_phrase(&phrase.phrase()).await.unwrap());
+            assert!(!chat.verify_recovery_phrase("not the phrase").await.unwrap());
+            (phrase, chat.get_public_key().await.unwrap())
+        };
+        
+        // A wrong phrase doesn't reset anything
+        let other = RecoveryPhrase::generate(&mut rand::thread_rng());
+        assert!(SecureChat::new(None).reset_password(&db_path, &other.phrase(), "new").await.is_err());
+        
+        {
+            let chat = SecureChat::new(None);
+            chat.reset_password(&db_path, &phrase.phrase(), "new password").await.unwrap();
+            assert_eq!(chat.get_public_key().await.unwrap(), public_key);
+        }
+        assert!(SecureChat::new(None).unlock_account(&db_path, "forgotten").await.is_err());
+        SecureChat::new(None).unlock_account(&db_path, "new password").await.unwrap();
+        
+        // A fresh database on another device gets the same identity back
+        let recovered = SecureChat::new(None);
+        assert!(recovered.recover_account(&db_path, &phrase.phrase(), "pw", "Test User").await.is_err());
+        recovered.recover_account(temp_dir.path().join("new.db"), &phrase.phrase(), "pw", "Test User").await.unwrap();
+        assert_eq!(recovered.get_public_key().await.unwrap(), public_key);
+    }
+    
+    #[tokio::test]
+    async fn test_duress_unlock_sets_up_decoy_account() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let duress = DuressPassword {
+            password: "duress".to_string(),
+            action: storage::DuressAction::OpenDecoy,
+            display_name: "Sam".to_string(),
+        };
+        
+        let real_identity = {
+            let chat = SecureChat::new(None);
+            chat.create_account_with_duress(&db_path, "password", "Alex", Some(&duress)).await.unwrap();
+            chat.get_public_key().await.unwrap()
+        };
+        
+        let decoy_identity = {
+            let chat = SecureChat::new(None);
+            chat.unlock_account(&db_path, "duress").await.unwrap();
+            assert_eq!(chat.get_profile().await.unwrap().unwrap().display_name, "Sam");
+            chat.get_public_key().await.unwrap()
+        };
+        assert_ne!(decoy_identity, real_identity);
+        
+        // Later unlocks load the decoy account instead of creating another
+        let chat = SecureChat::new(None);
+        chat.unlock_account(&db_path, "duress").await.unwrap();
+        assert_eq!(chat.get_public_key().await.unwrap(), decoy_identity);
+    }
+    
+    #[tokio::test]
+    async fn test_contacts_and_conversations() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(&db_path, "password", "User").await.unwrap();
+        
+        // Add contact
+        let public_key = [1u8; 32];
+        let contact = chat.add_contact(public_key, "Alice").await.unwrap();
+        
+        // Get conversation
+        let _conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+        
+        // Verify
+        let conversations = chat.get_conversations().await.unwrap();
+        assert_eq!(conversations.len(), 1);
+        
+        let contacts = chat.get_contacts().await.unwrap();
+        assert_eq!(contacts.len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_forward_message() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(&db_path, "password", "User").await.unwrap();
+        let alice = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        let bob = chat.add_contact([2u8; 32], "Bob").await.unwrap();
+        let carol = chat.add_contact([3u8; 32], "Carol").await.unwrap();
+        let to_alice = chat.get_or_create_conversation(&alice.id).await.unwrap();
+        let to_bob = chat.get_or_create_conversation(&bob.id).await.unwrap();
+        let to_carol = chat.get_or_create_conversation(&carol.id).await.unwrap();
+        
+        let original_id = chat.send_file(&to_alice.id, b"report", "report.pdf", "application/pdf").await.unwrap();
+        let forwarded = chat.forward_message(&original_id, std::slice::from_ref(&to_bob.id), false).await.unwrap();
+        let stripped = chat.forward_message(&forwarded[0], std::slice::from_ref(&to_carol.id), true).await.unwrap();
+        
+        let original = &chat.get_messages(&to_alice.id, 10).await.unwrap()[0];
+        let copy = &chat.get_messages(&to_bob.id, 10).await.unwrap()[0];
+        assert_eq!(copy.id, forwarded[0]);
+        assert_eq!(copy.forwarded_from.as_ref().unwrap().timestamp, original.timestamp);
+        assert!(chat.get_messages(&to_carol.id, 10).await.unwrap()[0].forwarded_from.is_none());
+        assert_eq!(stripped.len(), 1);
+        
+        // All three messages share one stored blob
+        let attachment = copy.content.attachment().unwrap();
+        assert_eq!(chat.get_attachment(attachment).await.unwrap(), b"report");
+        let storage = chat.storage.read().await;
+        assert_eq!(storage.as_ref().unwrap().blob_ref_count(attachment).unwrap(), 3);
+        drop(storage);
+        
+        assert!(chat.forward_message(&original_id, &["missing".to_string()], false).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_streamed_file_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let alice = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        let conversation = chat.get_or_create_conversation(&alice.id).await.unwrap();
+        
+        // Spans several blob chunks, with a partial one at the end
+        let data: Vec<u8> = (0..protocol::ATTACHMENT_CHUNK_SIZE * 3 + 100).map(|i| i as u8).collect();
+        let cancel = cancel::CancellationToken::new();
+        chat.send_file_from(&conversation.id, data.as_slice(), "video.mp4", "video/mp4", &cancel).await.unwrap();
+        
+        let message = &chat.get_messages(&conversation.id, 10).await.unwrap()[0];
+        let attachment = message.content.attachment().unwrap();
+        let mut saved = Vec::new();
+        assert_eq!(chat.save_attachment(attachment, &mut saved, &cancel).await.unwrap(), data.len() as u64);
+        assert_eq!(saved, data);
+        
+        // Cancelled uploads leave no staged chunks and send nothing
+        cancel.cancel();
+        let error = chat.send_file_from(&conversation.id, data.as_slice(), "other.mp4", "video/mp4", &cancel).await.unwrap_err();
+        assert!(cancel::is_cancelled(&error));
+        assert!(chat.save_attachment(attachment, Vec::new(), &cancel).await.is_err());
+        assert_eq!(chat.get_messages(&conversation.id, 10).await.unwrap().len(), 1);
+        
+        let cancelled: Vec<_> = chat.replay_events(1).events.into_iter()
+            .filter_map(|sequenced| match sequenced.event {
+                ChatEvent::OperationCancelled { operation } => Some(operation),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(cancelled, [cancel::Operation::AttachmentUpload, cancel::Operation::AttachmentDownload]);
+    }
+    
+    #[tokio::test]
+    async fn test_attachments_staged_encrypted() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let staging = tempstore::SecureTempStore::dir_for(&db_path);
+        let chat = SecureChat::new(None);
+        chat.create_account(&db_path, "password", "User").await.unwrap();
+        let alice = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        let conversation = chat.get_or_create_conversation(&alice.id).await.unwrap();
+        
+        let data: Vec<u8> = (0..protocol::ATTACHMENT_CHUNK_SIZE * 2 + 7).map(|i| i as u8).collect();
+        let source = temp_dir.path().join("clip.mp4");
+        std::fs::write(&source, &data).unwrap();
+        chat.start_file_upload(&conversation.id, source, "video/mp4").await.unwrap().wait().await.unwrap();
+        
+        let message = &chat.get_messages(&conversation.id, 10).await.unwrap()[0];
+        let saved = temp_dir.path().join("saved.mp4");
+        chat.start_attachment_save(message.content.attachment().unwrap(), saved.clone()).await.unwrap().wait().await.unwrap();
+        assert_eq!(std::fs::read(&saved).unwrap(), data);
+        assert_eq!(std::fs::read_dir(&staging).unwrap().count(), 0);
+        
+        // What a crash leaves behind is gone after the next unlock
+        std::fs::write(staging.join("left.stage"), b"sealed under a lost key").unwrap();
+        chat.close().await.unwrap();
+        let chat = SecureChat::new(None);
+        chat.unlock_account(&db_path, "password").await.unwrap();
+        assert_eq!(std::fs::read_dir(&staging).unwrap().count(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_retention_prunes_history() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let alice = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        let bob = chat.add_contact([2u8; 32], "Bob").await.unwrap();
+        let to_alice = chat.get_or_create_conversation(&alice.id).await.unwrap();
+        let to_bob = chat.get_or_create_conversation(&bob.id).await.unwrap();
+        
+        let file = chat.send_file(&to_alice.id, b"old report", "report.pdf", "application/pdf").await.unwrap();
+        let starred = chat.send_text_message(&to_alice.id, "keep me").await.unwrap();
+        chat.star_message(&starred).await.unwrap();
+        for i in 0..3 {
+            chat.send_text_message(&to_alice.id, &i.to_string()).await.unwrap();
+            chat.send_text_message(&to_bob.id, &i.to_string()).await.unwrap();
+        }
+        assert_eq!(chat.prune_messages().await.unwrap(), retention::PruneReport::default());
+        
+        // Alice's conversation keeps two, Bob's follows the default
+        let keep = |n| retention::RetentionPolicy { max_messages: Some(n), max_age: None };
+        chat.set_conversation_retention(&to_alice.id, Some(keep(2))).await.unwrap();
+        chat.set_default_retention(keep(1)).await.unwrap();
+        assert!(chat.set_default_retention(keep(0)).await.is_err());
+        assert!(chat.set_conversation_retention("missing", Some(keep(1))).await.is_err());
+        let report = chat.prune_messages().await.unwrap();
+        assert_eq!(report, retention::PruneReport { messages: 4, attachments: 1, bytes_freed: 10 });
+        
+        let texts = |messages: Vec<LocalMessage>| messages.iter().map(|m| m.preview_text()).collect::<Vec<_>>();
+        assert_eq!(texts(chat.get_messages(&to_alice.id, 10).await.unwrap()), ["keep me", "1", "2"]);
+        assert_eq!(texts(chat.get_messages(&to_bob.id, 10).await.unwrap()), ["2"]);
+        assert!(chat.storage.read().await.as_ref().unwrap().find_message(&file).unwrap().is_none());
+        
+        chat.set_conversation_retention(&to_alice.id, None).await.unwrap();
+        assert_eq!(chat.get_conversation_retention(&to_alice.id).await.unwrap(), None);
+        assert_eq!(chat.prune_messages().await.unwrap().messages, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_message_paging() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let alice = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        let conversation = chat.get_or_create_conversation(&alice.id).await.unwrap().id;
+        for i in 0..5 {
+            chat.send_text_message(&conversation, &format!("m{}", i)).await.unwrap();
+        }
+        
+        let texts = |page: Vec<LocalMessage>| -> Vec<String> {
+            page.into_iter().map(|m| m.preview_text()).collect()
+        };
+        let mut pages = Vec::new();
+        let mut before = None;
+        loop {
+            let page = chat.get_messages_page(&conversation, before.as_deref(), 2).await.unwrap();
+            let Some(first) = page.first() else { break };
+            before = Some(first.id.clone());
+            pages.push(texts(page));
+        }
+        assert_eq!(pages, [vec!["m3", "m4"], vec!["m1", "m2"], vec!["m0"]]);
+        
+        let contacts: Vec<Contact> = chat.stream_contacts().await.unwrap().try_collect().await.unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(chat.stream_conversations().await.unwrap().count().await, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_orphaned_records_are_collected() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let alice = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        let bob = chat.add_contact([2u8; 32], "Bob").await.unwrap();
+        let to_alice = chat.get_or_create_conversation(&alice.id).await.unwrap();
+        let to_bob = chat.get_or_create_conversation(&bob.id).await.unwrap();
+        let file = chat.send_file(&to_alice.id, b"report", "report.pdf", "application/pdf").await.unwrap();
+        chat.star_message(&file).await.unwrap();
+        chat.send_text_message(&to_alice.id, "hi").await.unwrap();
+        chat.set_contact_nickname(&alice.id, Some("Al")).await.unwrap();
+        chat.set_conversation_retention(&to_alice.id, Some(retention::RetentionPolicy { max_messages: Some(5), max_age: None })).await.unwrap();
+        chat.send_text_message(&to_bob.id, "hi").await.unwrap();
+        assert_eq!(chat.collect_garbage(false).await.unwrap().total(), 0);
+        
+        // Deleted the old way, leaving Bob's conversation behind
+        chat.storage.read().await.as_ref().unwrap().delete_contact(&bob.id).unwrap();
+        let found = chat.collect_garbage(true).await.unwrap();
+        assert_eq!((found.conversations, found.messages, found.dry_run), (1, 1, true));
+        assert_eq!(chat.get_conversations().await.unwrap().len(), 2);
+        assert_eq!(chat.collect_garbage(false).await.unwrap().total(), found.total());
+        assert_eq!(chat.get_conversations().await.unwrap().len(), 1);
+        
+        assert!(chat.delete_contact(&alice.id, false).await.is_err());
+        assert_eq!(chat.get_contacts().await.unwrap().len(), 1);
+        let report = chat.delete_contact(&alice.id, true).await.unwrap();
+        assert_eq!(report, gc::GcReport { conversations: 1, messages: 2, index_entries: 19, blobs: 1, other: 2, dry_run: false });
+        assert!(chat.get_conversations().await.unwrap().is_empty());
+        assert!(chat.get_starred_messages().await.unwrap().is_empty());
+        assert_eq!(chat.collect_garbage(true).await.unwrap().total(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_sticker_packs() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(&db_path, "password", "User").await.unwrap();
+        let contact = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+        
+        let pack = chat.install_sticker_pack("Cats", "Ann", vec![
+            (b"cat-1".to_vec(), Some("😺".to_string())),
+            (b"cat-2".to_vec(), None),
+        ]).await.unwrap();
+        assert_eq!(chat.get_sticker_packs().await.unwrap(), vec![pack.clone()]);
+        
+        let sticker = &pack.stickers[0];
+        chat.send_sticker(&conversation.id, &pack.id, &sticker.image.digest).await.unwrap();
+        let share_id = chat.share_sticker_pack(&conversation.id, &pack.id).await.unwrap();
+        
+        // Removing the pack keeps stickers that were already sent
+        chat.remove_sticker_pack(&pack.id).await.unwrap();
+        assert!(chat.get_sticker_packs().await.unwrap().is_empty());
+        assert_eq!(chat.get_attachment(&sticker.image).await.unwrap(), b"cat-1");
+        assert!(chat.get_attachment(&pack.stickers[1].image).await.is_err());
+        
+        let reinstalled = chat.install_shared_sticker_pack(&share_id).await.unwrap();
+        assert_eq!(reinstalled.id, pack.id);
+        assert_eq!(chat.get_sticker_packs().await.unwrap().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_contact_settings_sync() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let laptop = SecureChat::new(None);
+        laptop.create_account(temp_dir.path().join("laptop.db"), "password", "User").await.unwrap();
+        let contact = laptop.add_contact([1u8; 32], "Alice").await.unwrap();
+        laptop.set_contact_nickname(&contact.id, Some("  Al ")).await.unwrap();
+        laptop.set_contact_color(&contact.id, Some(ColorTag::Green)).await.unwrap();
+        assert!(laptop.set_contact_nickname(&contact.id, Some(&"x".repeat(65))).await.is_err());
+        assert!(laptop.set_contact_color("unknown", None).await.is_err());
+        
+        let settings = laptop.get_contact_settings(&contact.id).await.unwrap();
+        assert_eq!(settings.nickname.as_deref(), Some("Al"));
+        assert_eq!(settings.color, Some(ColorTag::Green));
+        
+        let phone = SecureChat::new(None);
+        phone.create_account(temp_dir.path().join("phone.db"), "password", "User").await.unwrap();
+        phone.apply_sync_data(laptop.sync_data().await.unwrap()).await.unwrap();
+        assert_eq!(phone.get_contact_settings(&contact.id).await.unwrap(), settings);
+        
+        // A later local change is not overwritten by the older synced copy
+        phone.set_contact_notification_sound(&contact.id, Some("chime")).await.unwrap();
+        phone.apply_sync_data(laptop.sync_data().await.unwrap()).await.unwrap();
+        assert_eq!(phone.get_contact_settings(&contact.id).await.unwrap().notification_sound.as_deref(), Some("chime"));
+    }
+    
+    #[tokio::test]
+    async fn test_contact_notes() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let laptop = SecureChat::new(None);
+        laptop.create_account(temp_dir.path().join("laptop.db"), "password", "User").await.unwrap();
+        let alice = laptop.add_contact([1u8; 32], "Alice").await.unwrap();
+        let bob = laptop.add_contact([2u8; 32], "Bob").await.unwrap();
+        laptop.set_contact_note(&alice.id, " Met at the climbing gym ").await.unwrap();
+        laptop.set_contact_note(&bob.id, "Verified keys in person").await.unwrap();
+        assert!(laptop.set_contact_note("unknown", "note").await.is_err());
+        assert!(laptop.set_contact_note(&alice.id, &"x".repeat(ContactNote::MAX_LEN + 1)).await.is_err());
+        assert_eq!(laptop.get_contact_note(&alice.id).await.unwrap().unwrap().text, "Met at the climbing gym");
+        
+        let found = laptop.search_contact_notes("GYM").await.unwrap();
+        assert_eq!(found.iter().map(|n| &n.contact_id).collect::<Vec<_>>(), vec![&alice.id]);
+        assert!(laptop.search_contact_notes(" ").await.unwrap().is_empty());
+        
+        // Backups carry notes
+        let backup = laptop.export_backup("backup-pw").await.unwrap();
+        let restored = SecureChat::new(None);
+        restored.restore_backup(backup.as_slice(), "backup-pw", temp_dir.path().join("restored.db"), "password")
+            .await.unwrap();
+        assert_eq!(restored.get_contact_note(&bob.id).await.unwrap().unwrap().text, "Verified keys in person");
+        
+        // So does sync, clearings included
+        let phone = SecureChat::new(None);
+        phone.create_account(temp_dir.path().join("phone.db"), "password", "User").await.unwrap();
+        phone.apply_sync_data(laptop.sync_data().await.unwrap()).await.unwrap();
+        assert_eq!(phone.get_contact_note(&alice.id).await.unwrap(), laptop.get_contact_note(&alice.id).await.unwrap());
+        laptop.delete_contact_note(&alice.id).await.unwrap();
+        assert!(laptop.get_contact_note(&alice.id).await.unwrap().is_none());
+        phone.apply_sync_data(laptop.sync_data().await.unwrap()).await.unwrap();
+        assert!(phone.get_contact_note(&alice.id).await.unwrap().is_none());
+        assert!(phone.search_contact_notes("gym").await.unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_contact_labels() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let laptop = SecureChat::new(None);
+        laptop.create_account(temp_dir.path().join("laptop.db"), "password", "User").await.unwrap();
+        let alice = laptop.add_contact([1u8; 32], "Alice").await.unwrap();
+        let bob = laptop.add_contact([2u8; 32], "Bob").await.unwrap();
+        laptop.get_or_create_conversation(&alice.id).await.unwrap();
+        laptop.get_or_create_conversation(&bob.id).await.unwrap();
+        
+        let family = laptop.create_label(" Family ", Some(ColorTag::Green)).await.unwrap();
+        let work = laptop.create_label("Work", None).await.unwrap();
+        assert_eq!(family.name, "Family");
+        assert!(laptop.create_label("family", None).await.is_err());
+        assert!(laptop.rename_label(&work.id, "FAMILY").await.is_err());
+        assert!(laptop.create_label("", None).await.is_err());
+        
+        assert!(laptop.add_contact_to_label(&family.id, &alice.id).await.unwrap());
+        assert!(!laptop.add_contact_to_label(&family.id, &alice.id).await.unwrap());
+        assert!(laptop.add_contact_to_label(&work.id, &bob.id).await.unwrap());
+        assert!(laptop.add_contact_to_label(&work.id, "unknown").await.is_err());
+        let contacts = laptop.get_contacts_with_label(&family.id).await.unwrap();
+        assert_eq!(contacts.iter().map(|c| &c.id).collect::<Vec<_>>(), vec![&alice.id]);
+        let conversations = laptop.get_conversations_with_label(&work.id).await.unwrap();
+        assert_eq!(conversations.iter().map(|c| &c.contact_id).collect::<Vec<_>>(), vec![&bob.id]);
+        
+        let phone = SecureChat::new(None);
+        phone.create_account(temp_dir.path().join("phone.db"), "password", "User").await.unwrap();
+        phone.apply_sync_data(laptop.sync_data().await.unwrap()).await.unwrap();
+        assert_eq!(phone.get_labels().await.unwrap(), laptop.get_labels().await.unwrap());
+        
+        // Deleting syncs too, and leaves the contacts alone
+        laptop.delete_label(&work.id).await.unwrap();
+        phone.apply_sync_data(laptop.sync_data().await.unwrap()).await.unwrap();
+        let labels = phone.get_labels().await.unwrap();
+        assert_eq!(labels.iter().map(|l| l.name.as_str()).collect::<Vec<_>>(), vec!["Family"]);
+        assert!(phone.get_contacts_with_label(&work.id).await.is_err());
+        assert_eq!(phone.get_contacts().await.unwrap().len(), 2);
+        
+        laptop.delete_contact(&alice.id, true).await.unwrap();
+        assert!(laptop.get_contacts_with_label(&family.id).await.unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_chat_folders() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let laptop = SecureChat::new(None);
+        laptop.create_account(temp_dir.path().join("laptop.db"), "password", "User").await.unwrap();
+        let alice = laptop.add_contact([1u8; 32], "Alice").await.unwrap();
+        let bob = laptop.add_contact([2u8; 32], "Bob").await.unwrap();
+        let carol = laptop.add_contact([3u8; 32], "Carol").await.unwrap();
+        let storage = laptop.storage().await.unwrap();
+        let mut conversations = Vec::new();
+        for (contact, unread) in [(&alice, 2), (&bob, 3), (&carol, 1)] {
+            let mut conversation = laptop.get_or_create_conversation(&contact.id).await.unwrap();
+            conversation.unread_count = unread;
+            storage.store_conversation(&conversation).unwrap();
+            conversations.push(conversation);
+        }
+        
+        let work = laptop.create_folder("Work").await.unwrap();
+        let friends = laptop.create_folder(" Friends ").await.unwrap();
+        assert_eq!(friends.name, "Friends");
+        assert!(laptop.create_folder("work").await.is_err());
+        assert!(laptop.rename_folder(&friends.id, "WORK").await.is_err());
+        
+        laptop.set_conversation_folder(&conversations[0].id, Some(&work.id)).await.unwrap();
+        laptop.set_conversation_folder(&conversations[1].id, Some(&friends.id)).await.unwrap();
+        laptop.set_conversation_folder(&conversations[2].id, Some(&friends.id)).await.unwrap();
+        // A conversation is in one folder at a time
+        laptop.set_conversation_folder(&conversations[0].id, Some(&friends.id)).await.unwrap();
+        assert!(laptop.get_conversations_in_folder(&work.id).await.unwrap().is_empty());
+        laptop.set_conversation_folder(&conversations[2].id, None).await.unwrap();
+        assert!(laptop.set_conversation_folder(&conversations[2].id, Some("unknown")).await.is_err());
+        
+        laptop.reorder_folders(&[friends.id.clone(), work.id.clone()]).await.unwrap();
+        assert!(laptop.reorder_folders(std::slice::from_ref(&friends.id)).await.is_err());
+        let groups = laptop.get_conversations_by_folder().await.unwrap();
+        let summary = groups.iter()
+            .map(|group| (group.folder.as_ref().map(|folder| folder.name.as_str()), group.conversations.len(), group.unread_count))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![(Some("Friends"), 2, 5), (Some("Work"), 0, 0), (None, 1, 1)]);
+        
+        let phone = SecureChat::new(None);
+        phone.create_account(temp_dir.path().join("phone.db"), "password", "User").await.unwrap();
+        phone.apply_sync_data(laptop.sync_data().await.unwrap()).await.unwrap();
+        assert_eq!(phone.get_folders().await.unwrap(), laptop.get_folders().await.unwrap());
+        
+        // Deleting syncs too, and leaves the conversations in no folder
+        laptop.delete_folder(&friends.id).await.unwrap();
+        phone.apply_sync_data(laptop.sync_data().await.unwrap()).await.unwrap();
+        let folders = phone.get_folders().await.unwrap();
+        assert_eq!(folders.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), vec!["Work"]);
+        let groups = laptop.get_conversations_by_folder().await.unwrap();
+        assert_eq!(groups.last().unwrap().conversations.len(), 3);
+    }
+    
+    #[tokio::test]
+    async fn test_broadcast_lists() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let alice = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        let bob = chat.add_contact([2u8; 32], "Bob").await.unwrap();
+        
+        let list = chat.create_broadcast_list("Neighbours", &[alice.id.clone(), bob.id.clone(), alice.id.clone()]).await.unwrap();
+        assert_eq!(list.members, vec![alice.id.clone(), bob.id.clone()]);
+        assert!(chat.create_broadcast_list("Others", &["unknown".to_string()]).await.is_err());
+        assert!(!chat.add_broadcast_member(&list.id, &bob.id).await.unwrap());
+        
+        // A member gone from under the list fails alone
+        let storage = chat.storage().await.unwrap();
+        let mut stale = storage.get_broadcast_list(&list.id).unwrap().unwrap();
+        stale.members.push("gone".to_string());
+        storage.store_broadcast_list(&stale).unwrap();
+        
+        let content = MessageContent::Text { text: "Street party on Saturday".to_string() };
+        let sent = chat.send_broadcast(&list.id, content).await.unwrap();
+        assert_eq!(sent.recipients.len(), 3);
+        assert_eq!(sent.failures().map(|(contact_id, _)| contact_id).collect::<Vec<_>>(), vec!["gone"]);
+        
+        // Each copy is its own message in the member's own conversation
+        let conversation = chat.get_or_create_conversation(&alice.id).await.unwrap();
+        let messages = chat.get_messages(&conversation.id, 10).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        let broadcast::RecipientOutcome::Sent { message_id, .. } = &sent.recipients[0].outcome else {
+            panic!("Alice's copy wasn't sent");
+        };
+        assert_eq!(&messages[0].id, message_id);
+        
+        chat.set_delivery_status(message_id, DeliveryStatus::Delivered).await.unwrap();
+        let status = chat.get_broadcast_status(&sent.id).await.unwrap();
+        assert_eq!((status.delivered, status.pending, status.failed.len()), (1, 1, 1));
+        assert!(!status.all_delivered());
+        
+        chat.delete_contact(&bob.id, true).await.unwrap();
+        assert!(!chat.remove_broadcast_member(&list.id, &bob.id).await.unwrap());
+        assert_eq!(chat.get_broadcasts(&list.id).await.unwrap(), vec![sent]);
+        chat.delete_broadcast_list(&list.id).await.unwrap();
+        assert!(chat.get_broadcast_lists().await.unwrap().is_empty());
+        assert!(chat.get_broadcasts(&list.id).await.unwrap().is_empty());
+        assert_eq!(chat.get_messages(&conversation.id, 10).await.unwrap().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_query_conversations() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let storage = chat.storage().await.unwrap();
+        let mut ids = Vec::new();
+        for (i, name) in ["Dave", "alice", "Carol", "Bob"].into_iter().enumerate() {
+            let contact = chat.add_contact([i as u8 + 1; 32], name).await.unwrap();
+            let mut conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+            conversation.updated_at += std::time::Duration::from_secs(i as u64);
+            conversation.unread_count = i as u32 % 2;
+            conversation.archived = name == "Carol";
+            storage.store_conversation(&conversation).unwrap();
+            ids.push(contact.id);
+        }
+        chat.set_contact_nickname(&ids[0], Some("Aaron")).await.unwrap();
+        let contacts = |page: ConversationPage| page.conversations.into_iter().map(|c| c.contact_id).collect::<Vec<_>>();
+        
+        let page = chat.query_conversations(&ConversationQuery { limit: 2, ..Default::default() }).await.unwrap();
+        assert_eq!(page.total, 4);
+        assert_eq!(contacts(page), vec![ids[3].clone(), ids[2].clone()]);
+        let query = ConversationQuery { sort: ConversationSort::Name, offset: 1, limit: 2, ..Default::default() };
+        assert_eq!(contacts(chat.query_conversations(&query).await.unwrap()), vec![ids[1].clone(), ids[3].clone()]);
+        
+        let filter = ConversationFilter { archived: Some(false), unread: true, ..Default::default() };
+        let query = ConversationQuery { filter, sort: ConversationSort::Unread, ..Default::default() };
+        assert_eq!(contacts(chat.query_conversations(&query).await.unwrap()), vec![ids[3].clone(), ids[1].clone()]);
+        
+        let family = chat.create_label("Family", None).await.unwrap();
+        chat.add_contact_to_label(&family.id, &ids[2]).await.unwrap();
+        let filter = ConversationFilter { label_id: Some(family.id), ..Default::default() };
+        let page = chat.query_conversations(&ConversationQuery { filter, ..Default::default() }).await.unwrap();
+        assert_eq!((page.total, contacts(page)), (1, vec![ids[2].clone()]));
+        let filter = ConversationFilter { label_id: Some("unknown".to_string()), ..Default::default() };
+        assert!(chat.query_conversations(&ConversationQuery { filter, ..Default::default() }).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_history_sync_fills_gaps_and_resumes() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let laptop = SecureChat::new(None);
+        laptop.create_account(temp_dir.path().join("laptop.db"), "password", "User").await.unwrap();
+        let contact = laptop.add_contact([5u8; 32], "Carol").await.unwrap();
+        let conversation = laptop.get_or_create_conversation(&contact.id).await.unwrap();
+        laptop.send_text_message(&conversation.id, "before linking").await.unwrap();
+        
+        // The phone starts from a backup, then falls behind
+        let backup = laptop.export_backup("backup-pw").await.unwrap();
+        let phone = SecureChat::new(None);
+        phone.restore_backup(backup.as_slice(), "backup-pw", temp_dir.path().join("phone.db"), "password")
+            .await
+            .unwrap();
+        for i in 0..120 {
+            laptop.send_text_message(&conversation.id, &format!("message {}", i)).await.unwrap();
+        }
+        
+        let request = phone.history_sync_request().await.unwrap();
+        // One batch per manifest bucket
+        let batches = laptop.history_batches(&request).await.unwrap();
+        assert_eq!(batches.len(), 2);
+        // Addressed to the phone only
+        assert_eq!(laptop.apply_history_batch(&batches[0]).await.unwrap(), None);
+        
+        // Interrupted after one batch; a fresh request skips what arrived
+        let applied = phone.apply_history_batch(&batches[0]).await.unwrap().unwrap();
+        assert_eq!(applied, history::AppliedBatch { added: 62, remaining: 1 });
+        let resumed = laptop.history_batches(&phone.history_sync_request().await.unwrap()).await.unwrap();
+        assert_eq!(resumed.len(), 1);
+        for batch in &resumed {
+            phone.apply_history_batch(batch).await.unwrap();
+        }
+        
+        assert_eq!(phone.history_manifest().await.unwrap(), laptop.history_manifest().await.unwrap());
+        let ids = |messages: Vec<LocalMessage>| messages.into_iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(phone.get_messages(&conversation.id, 200).await.unwrap()),
+            ids(laptop.get_messages(&conversation.id, 200).await.unwrap()),
+        );
+        assert!(laptop.history_batches(&phone.history_sync_request().await.unwrap()).await.unwrap().is_empty());
+        
+        // A request is answered once, and only while recent
+        let request = phone.history_sync_request().await.unwrap();
+        laptop.history_batches(&request).await.unwrap();
+        assert!(laptop.history_batches(&request).await.is_err());
+        let ProtocolMessage::HistorySyncRequest { device_id, reply_key, manifest, nonce, .. } = request else {
+            unreachable!();
+        };
+        let timestamp = OffsetDateTime::now_utc().unix_timestamp() - history::REQUEST_TOLERANCE.as_secs() as i64 - 1;
+        let signed = history::request_signing_bytes(&device_id, &reply_key, &manifest, timestamp, &nonce).unwrap();
+        let signature = phone.identity.read().await.as_ref().unwrap().sign(&signed).to_bytes().to_vec();
+        let stale = ProtocolMessage::HistorySyncRequest { device_id, reply_key, manifest, timestamp, nonce, signature };
+        assert!(laptop.history_batches(&stale).await.is_err());
+        
+        // Another account can't ask for our history
+        let stranger = SecureChat::new(None);
+        stranger.create_account(temp_dir.path().join("stranger.db"), "password", "User").await.unwrap();
+        assert!(laptop.history_batches(&stranger.history_sync_request().await.unwrap()).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_note_to_self() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let laptop = SecureChat::new(None);
+        laptop.create_account(temp_dir.path().join("laptop.db"), "password", "User").await.unwrap();
+        let backup = laptop.export_backup("backup-pw").await.unwrap();
+        let phone = SecureChat::new(None);
+        phone.restore_backup(backup.as_slice(), "backup-pw", temp_dir.path().join("phone.db"), "password")
+            .await
+            .unwrap();
+        
+        let notes = laptop.get_self_conversation().await.unwrap();
+        assert!(notes.is_self());
+        assert_eq!(laptop.get_self_conversation().await.unwrap().id, notes.id);
+        let text_id = laptop.send_text_message(&notes.id, "buy milk").await.unwrap();
+        laptop.send_file(&notes.id, b"receipt", "receipt.txt", "text/plain").await.unwrap();
+        let messages = laptop.get_messages(&notes.id, 10).await.unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.status == DeliveryStatus::Read));
+        assert!(laptop.get_undelivered_messages().await.unwrap().is_empty());
+        assert!(!laptop.send_typing(&notes.id, true).await.unwrap());
+        
+        // No contact holds it, yet it isn't garbage
+        assert_eq!(laptop.collect_garbage(false).await.unwrap().total(), 0);
+        
+        for batch in laptop.history_batches(&phone.history_sync_request().await.unwrap()).await.unwrap() {
+            phone.apply_history_batch(&batch).await.unwrap();
+        }
+        let synced = phone.get_self_conversation().await.unwrap();
+        let synced = phone.get_messages(&synced.id, 10).await.unwrap();
+        assert_eq!(synced.len(), 2);
+        assert_eq!(synced[0].id, text_id);
+    }
+    
+    #[tokio::test]
+    async fn test_privacy_settings() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(&db_path, "password", "User").await.unwrap();
+        assert_eq!(chat.get_privacy_settings().await.unwrap(), PrivacySettings::default());
+        
+        let quiet = PrivacySettings { send_read_receipts: false, send_typing: false, share_presence: true };
+        chat.set_privacy_settings(quiet).await.unwrap();
+        assert_eq!(chat.get_privacy_settings().await.unwrap(), quiet);
+        assert!(!chat.send_typing("c", true).await.unwrap());
+        
+        let contact = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        chat.apply_contact_privacy(&contact.id, quiet).await.unwrap();
+        assert_eq!(chat.get_contacts().await.unwrap()[0].privacy, Some(quiet));
+    }
+    
+    #[tokio::test]
+    async fn test_verify_contact() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(&db_path, "password", "User").await.unwrap();
+        let contact = chat.add_contact([9u8; 32], "Bob").await.unwrap();
+        
+        let safety_number = chat.get_safety_number(&contact.id).await.unwrap();
+        assert!(!chat.verify_contact(&contact.id, "00000 00000").await.unwrap());
+        assert!(chat.verify_contact(&contact.id, &safety_number.safety_number()).await.unwrap());
+        
+        let contacts = chat.get_contacts().await.unwrap();
+        assert!(contacts[0].verified);
+    }
+    
+    fn envelope_for(recipient: &MessageKeyPair, sender_id: &str, text: &str) -> MessageEnvelope {
+        let sender = MessageKeyPair::generate();
+        let content = protocol::wire::encode(&MessageContent::Text { text: text.to_string() }).unwrap();
+        MessageEnvelope {
+            id: protocol::generate_id(),
+            sender_id: sender_id.to_string(),
+            recipient_id: "self".to_string(),
+            timestamp: OffsetDateTime::now_utc(),
+            encrypted_content: sender.encrypt_message(&recipient.public_key, &content).unwrap(),
+            signature: Vec::new(),
+            reply_to: None,
+            ratchet_header: None,
+            lamport: None,
+            invite: None,
+            thumbnail: None,
+            sender_device: None,
+            recipient_device: None,
+            message_id: None,
+            view_once: false,
+            policy: None,
+            capabilities: None,
+        }
+    }
+    
+    /// Contact request from `identity_key`, stamped at `difficulty`
+    fn contact_request(identity_key: [u8; 32], display_name: &str, message: &str, difficulty: Option<u8>) -> ProtocolMessage {
+        let key_bundle = ProtocolMessage::KeyBundle {
+            identity_key,
+            signed_prekey: [0u8; 32],
+            signed_prekey_signature: Vec::new(),
+            one_time_prekeys: Vec::new(),
+        };
+        let digest = puzzle::request_digest(&key_bundle, display_name, message).unwrap();
+        ProtocolMessage::ContactRequest {
+            display_name: display_name.to_string(),
+            message: message.to_string(),
+            key_bundle: Box::new(key_bundle),
+            stamp: difficulty.map(|d| puzzle::Stamp::mint(&identity_key, &digest, d, OffsetDateTime::now_utc()).unwrap()),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_message_requests() {
+        use base64::Engine;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let keys = chat.message_keys.read().await.clone().unwrap();
+        let stranger = base64::engine::general_purpose::STANDARD.encode([7u8; 32]);
+        let spammer = base64::engine::general_purpose::STANDARD.encode([8u8; 32]);
+        
+        // Only the first message announces the request, and none is
+        // reported as received
+        let first = envelope_for(&keys, &stranger, "hello?");
+        let events = chat.receive_envelope(first.clone()).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::MessageRequestReceived { sender_id }] if *sender_id == stranger));
+        assert!(chat.receive_envelope(first).await.unwrap().is_empty());
+        assert!(chat.receive_envelope(envelope_for(&keys, &stranger, "it's Dave")).await.unwrap().is_empty());
+        chat.receive_envelope(envelope_for(&keys, &spammer, "cheap watches")).await.unwrap();
+        assert!(chat.get_conversations().await.unwrap().is_empty());
+        assert_eq!(chat.get_message_requests().await.unwrap().len(), 2);
+        // Not journaled, so a flood isn't synced to disk envelope by envelope
+        assert!(chat.storage().await.unwrap().journaled_envelopes().unwrap().is_empty());
+        
+        let dave = chat.accept_message_request(&stranger, "Dave").await.unwrap();
+        assert_eq!(dave.public_key, [7u8; 32]);
+        let conversation = chat.get_or_create_conversation(&dave.id).await.unwrap();
+        let messages = chat.get_messages(&conversation.id, 10).await.unwrap();
+        assert_eq!(messages.iter().map(|m| m.preview_text()).collect::<Vec<_>>(), vec!["hello?", "it's Dave"]);
+        
+        // Blocked senders are dropped on arrival
+        chat.decline_message_request(&spammer, true).await.unwrap();
+        assert!(chat.receive_envelope(envelope_for(&keys, &spammer, "last chance")).await.unwrap().is_empty());
+        assert!(chat.get_message_requests().await.unwrap().is_empty());
+        assert_eq!(chat.get_blocked_senders().await.unwrap()[0].0, spammer);
+        
+        // Dave is a contact now
+        let events = chat.receive_envelope(envelope_for(&keys, &stranger, "thanks")).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::MessageReceived { .. }]));
+    }
+    
+    #[tokio::test]
+    async fn test_message_request_flood_is_limited() {
+        use base64::Engine;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let keys = chat.message_keys.read().await.clone().unwrap();
+        
+        for i in 0..requests::MAX_PENDING_REQUESTS {
+            let sender = base64::engine::general_purpose::STANDARD.encode([i as u8; 32]);
+            chat.receive_envelope(envelope_for(&keys, &sender, "hi")).await.unwrap();
+        }
+        let stored = chat.get_message_requests().await.unwrap().len();
+        assert_eq!(stored, requests::REQUEST_WRITE_BURST as usize);
+        
+        // Writes are allowed again over time
+        let mut limiter = requests::WriteLimiter::default();
+        let start = std::time::Instant::now();
+        assert!((0..requests::REQUEST_WRITE_BURST).all(|_| limiter.try_take(start)));
+        assert!(!limiter.try_take(start));
+        let later = start + requests::REQUEST_WRITE_INTERVAL * 2;
+        assert!(limiter.try_take(later) && limiter.try_take(later));
+        assert!(!limiter.try_take(later));
+        assert!((0..requests::REQUEST_WRITE_BURST).all(|_| limiter.try_take(later + requests::REQUEST_WRITE_INTERVAL * 100)));
+    }
+    
+    #[tokio::test]
+    async fn test_recent_activity() {
+        use base64::Engine;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let alice = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        let bob = chat.add_contact([2u8; 32], "Bob").await.unwrap();
+        let to_alice = chat.get_or_create_conversation(&alice.id).await.unwrap();
+        let to_bob = chat.get_or_create_conversation(&bob.id).await.unwrap();
+        chat.send_text_message(&to_alice.id, "one").await.unwrap();
+        chat.send_text_message(&to_bob.id, "two").await.unwrap();
+        let three = chat.send_text_message(&to_alice.id, "three").await.unwrap();
+        let keys = chat.message_keys.read().await.clone().unwrap();
+        let stranger = base64::engine::general_purpose::STANDARD.encode([7u8; 32]);
+        chat.receive_envelope(envelope_for(&keys, &stranger, "hello?")).await.unwrap();
+        
+        let describe = |item: &activity::ActivityItem| match item {
+            activity::ActivityItem::Message { contact_id, message } => (contact_id.clone(), message.preview_text()),
+            activity::ActivityItem::MessageRequest { sender_id, messages, .. } => (sender_id.clone(), messages.to_string()),
+        };
+        let feed = chat.get_recent_activity(3).await.unwrap();
+        assert_eq!(feed.iter().map(describe).collect::<Vec<_>>(), vec![
+            (stranger.clone(), "1".to_string()),
+            (alice.id.clone(), "three".to_string()),
+            (bob.id.clone(), "two".to_string()),
+        ]);
+        
+        // The index follows deletions
+        chat.storage().await.unwrap().delete_message(&to_alice.id, &three).unwrap();
+        let feed = chat.get_recent_activity(10).await.unwrap();
+        assert_eq!(feed.iter().map(describe).map(|(_, text)| text).collect::<Vec<_>>(), vec!["1", "two", "one"]);
+        assert!(chat.get_recent_activity(0).await.is_err());
+    }
+    
+    /// Drops spam, marks everything else it read and signs what we send
+    struct SpamFilter;
+    
+    impl plugins::Plugin for SpamFilter {
+        fn name(&self) -> &str {
+            "spam-filter"
+        }
+        
+        fn on_incoming_message<'a>(&'a self, message: &'a plugins::IncomingMessage) -> futures::future::BoxFuture<'a, Result<plugins::HookAction>> {
+            Box::pin(async move {
+                Ok(match &message.content {
+                    MessageContent::Text { text } if text.contains("spam") => plugins::HookAction::Suppress,
+                    _ => plugins::HookAction::Annotate("no spam".to_string()),
+                })
+            })
+        }
+        
+        fn on_outgoing_message<'a>(&'a self, message: &'a plugins::OutgoingMessage) -> futures::future::BoxFuture<'a, Result<plugins::HookAction>> {
+            Box::pin(async move {
+                Ok(match &message.content {
+                    MessageContent::Text { text } => plugins::HookAction::Modify(MessageContent::Text { text: format!("{} (filtered)", text) }),
+                    _ => plugins::HookAction::Continue,
+                })
+            })
+        }
+        
+        fn on_contact_request<'a>(&'a self, _request: &'a plugins::ContactRequest) -> futures::future::BoxFuture<'a, Result<plugins::HookAction>> {
+            Box::pin(async { panic!("contact requests break this plugin") })
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_plugins() {
+        use base64::Engine;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        chat.register_plugin(Arc::new(SpamFilter)).await.unwrap();
+        assert!(chat.register_plugin(Arc::new(SpamFilter)).await.is_err());
+        let keys = chat.message_keys.read().await.clone().unwrap();
+        let dave = chat.add_contact([7u8; 32], "Dave").await.unwrap();
+        let dave_id = base64::engine::general_purpose::STANDARD.encode([7u8; 32]);
+        
+        assert!(chat.receive_envelope(envelope_for(&keys, &dave_id, "cheap spam")).await.unwrap().is_empty());
+        let events = chat.receive_envelope(envelope_for(&keys, &dave_id, "hi")).await.unwrap();
+        let [ChatEvent::MessageReceived { message, .. }] = &events[..] else {
+            panic!("Expected the message, got {:?}", events);
+        };
+        let annotations = chat.get_message_annotations(&message.id).await.unwrap();
+        assert_eq!(annotations, vec![plugins::Annotation { plugin: "spam-filter".to_string(), text: "no spam".to_string() }]);
+        
+        let conversation = chat.get_or_create_conversation(&dave.id).await.unwrap();
+        chat.send_text_message(&conversation.id, "hello").await.unwrap();
+        let messages = chat.get_messages(&conversation.id, 10).await.unwrap();
+        assert_eq!(messages.iter().map(|m| m.preview_text()).collect::<Vec<_>>(), vec!["hi", "hello (filtered)"]);
+        
+        // A panicking hook lets the request through
+        chat.set_contact_request_difficulty(0).await.unwrap();
+        let identity_key = [9u8; 32];
+        let request = contact_request(identity_key, "Erin", "", Some(0));
+        let events = chat.handle_protocol_message("peer".to_string(), request).await;
+        assert!(matches!(&events[..], [ChatEvent::ContactRequestReceived { .. }]));
+        
+        assert!(chat.unregister_plugin("spam-filter").await);
+        assert!(!chat.unregister_plugin("spam-filter").await);
+    }
+    
+    #[tokio::test]
+    async fn test_webhooks() {
+        use base64::Engine;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let sender = Arc::new(webhooks::MemoryWebhookSender::default());
+        chat.set_webhook_sender(sender.clone()).await;
+        let keys = chat.message_keys.read().await.clone().unwrap();
+        let dave = chat.add_contact([7u8; 32], "Dave").await.unwrap();
+        let erin = chat.add_contact([8u8; 32], "Erin").await.unwrap();
+        let encode = |key: [u8; 32]| base64::engine::general_purpose::STANDARD.encode(key);
+        
+        let all = chat.add_webhook("https://bot.example.org/all", vec![webhooks::WebhookEvent::MessageReceived], Vec::new()).await.unwrap();
+        chat.add_webhook("https://bot.example.org/erin", vec![webhooks::WebhookEvent::MessageReceived], vec![erin.id.clone()]).await.unwrap();
+        chat.add_webhook("https://bot.example.org/requests", vec![webhooks::WebhookEvent::MessageRequest], Vec::new()).await.unwrap();
+        
+        let events = chat.receive_envelope(envelope_for(&keys, &encode([7u8; 32]), "ping")).await.unwrap();
+        chat.notify_webhooks(&events).await;
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while sender.posts().is_empty() && std::time::Instant::now() < deadline {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let posts = sender.posts();
+        assert_eq!(posts.len(), 1);
+        let (url, signature, body) = &posts[0];
+        assert_eq!(url, &all.url);
+        webhooks::verify(&all.secret, signature, body, OffsetDateTime::now_utc()).unwrap();
+        let payload: webhooks::WebhookPayload = serde_json::from_slice(body).unwrap();
+        assert_eq!((payload.event, payload.contact_id.as_str()), (webhooks::WebhookEvent::MessageReceived, dave.id.as_str()));
+        assert_eq!(payload.data["text"], "ping");
+        
+        // Programs send with the inbound secret
+        let body = serde_json::to_vec(&webhooks::InboundMessage { contact_id: dave.id.clone(), text: "pong".to_string() }).unwrap();
+        let secret = chat.get_inbound_webhook_secret().await.unwrap();
+        assert_eq!(chat.get_inbound_webhook_secret().await.unwrap(), secret);
+        let signature = webhooks::sign(&secret, OffsetDateTime::now_utc().unix_timestamp(), &body);
+        chat.handle_inbound_webhook(&signature, &body).await.unwrap();
+        let conversation = chat.get_or_create_conversation(&dave.id).await.unwrap();
+        let messages = chat.get_messages(&conversation.id, 10).await.unwrap();
+        assert_eq!(messages.last().unwrap().preview_text(), "pong");
+        assert!(chat.handle_inbound_webhook(&signature, &body).await.is_err());
+        assert_eq!(chat.get_messages(&conversation.id, 10).await.unwrap().len(), messages.len());
+        
+        chat.rotate_inbound_webhook_secret().await.unwrap();
+        assert!(chat.handle_inbound_webhook(&signature, &body).await.is_err());
+        chat.remove_webhook(&all.id).await.unwrap();
+        assert_eq!(chat.get_webhooks().await.unwrap().len(), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_contact_request_stamps() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        assert_eq!(chat.get_contact_request_difficulty().await.unwrap(), puzzle::DEFAULT_DIFFICULTY);
+        assert!(chat.set_contact_request_difficulty(puzzle::MAX_DIFFICULTY + 1).await.is_err());
+        chat.set_contact_request_difficulty(6).await.unwrap();
+        
+        let request = |identity_key: [u8; 32], difficulty: Option<u8>| contact_request(identity_key, "Eve", "hi", difficulty);
+        let admitted = |message| {
+            let chat = chat.clone();
+            async move {
+                let events = chat.handle_protocol_message("peer".to_string(), message).await;
+                matches!(&events[..], [ChatEvent::ContactRequestReceived { .. }])
+            }
+        };
+        
+        let stamped = request([1u8; 32], Some(6));
+        assert!(admitted(stamped.clone()).await);
+        assert!(!admitted(request([1u8; 32], None)).await);
+        assert!(!admitted(request([1u8; 32], Some(2))).await);
+        
+        // Each stamp buys one request
+        assert!(!admitted(stamped).await);
+        
+        // Known keys need no stamp
+        chat.add_contact([2u8; 32], "Bob").await.unwrap();
+        assert!(admitted(request([2u8; 32], None)).await);
+    }
+
+    #[tokio::test]
+    async fn test_contact_names() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let alice = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        let impostor = chat.add_contact([2u8; 32], "\u{410}lice").await.unwrap();
+        let me = chat.add_contact([3u8; 32], "user").await.unwrap();
+        
+        let name = chat.get_contact_name(&impostor.id).await.unwrap();
+        assert_eq!(name.warnings, vec![names::NameWarning::Confusable { contact_id: alice.id.clone() }]);
+        assert_eq!(name.fingerprint_hint, Fingerprint::of_key(&[2u8; 32]).short_hint());
+        assert_ne!(name.fingerprint_hint, chat.get_contact_name(&alice.id).await.unwrap().fingerprint_hint);
+        let name = chat.get_contact_name(&me.id).await.unwrap();
+        assert_eq!(name.warnings, vec![names::NameWarning::Duplicate { contact_id: protocol::SELF_CONTACT_ID.to_string() }]);
+        
+        // A nickname is the user's own choice, so it clears the warnings
+        chat.set_contact_nickname(&impostor.id, Some("Work Alice")).await.unwrap();
+        let name = chat.get_contact_name(&impostor.id).await.unwrap();
+        assert_eq!((name.name.as_str(), name.warnings.len()), ("Work Alice", 0));
+        
+        chat.set_contact_request_difficulty(0).await.unwrap();
+        let identity_key = [4u8; 32];
+        let request = contact_request(identity_key, "Alice", "", Some(0));
+        let events = chat.handle_protocol_message("peer".to_string(), request).await;
+        assert!(matches!(&events[..], [ChatEvent::ContactRequestReceived { name_warnings, fingerprint_hint, .. }]
+            if *name_warnings == vec![names::NameWarning::Duplicate { contact_id: alice.id.clone() }]
+                && *fingerprint_hint == Fingerprint::of_key(&identity_key).short_hint()));
+    }
+    
+    #[tokio::test]
+    async fn test_session_info() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let bob = chat.add_contact([2u8; 32], "Bob").await.unwrap();
+        let info = chat.get_session_info(&bob.id).await.unwrap();
+        assert!(info.devices.is_empty() && info.ratchet.is_none() && info.last_rekey_at.is_none());
+        
+        let announced_at = OffsetDateTime::now_utc();
+        let device = devices::RemoteDevice { device_id: "phone".to_string(), message_key: [3u8; 32], announced_at };
+        chat.storage().await.unwrap().set_contact_devices(&bob.id, &[device]).unwrap();
+        let conversation = chat.get_or_create_conversation(&bob.id).await.unwrap();
+        chat.send_text_message(&conversation.id, "one").await.unwrap();
+        chat.send_text_message(&conversation.id, "two").await.unwrap();
+        
+        let info = chat.get_session_info(&bob.id).await.unwrap();
+        assert_eq!(info.protocol_version, protocol::wire::VERSION);
+        assert_eq!(info.last_rekey_at, Some(announced_at));
+        assert_eq!(info.devices[0].session, Some(devices::SessionCounters { chain: 0, messages_sent: 2, previous_chain_length: 0 }));
+        assert!(chat.get_session_info("unknown").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics() {
+        use base64::Engine;
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let bob = chat.add_contact([2u8; 32], "Bob").await.unwrap();
+        let conversation = chat.get_or_create_conversation(&bob.id).await.unwrap();
+        chat.send_text_message(&conversation.id, "meet me at the usual place").await.unwrap();
+
+        let bundle = chat.diagnostics().await.unwrap();
+        assert_eq!(bundle.version.wire_protocol, protocol::wire::VERSION);
+        assert_eq!((bundle.storage.contacts, bundle.storage.conversations), (1, 1));
+        assert!(bundle.storage.integrity.records_checked > 0);
+
+        let exported = String::from_utf8(chat.export_diagnostics().await.unwrap()).unwrap();
+        let public_key = base64::engine::general_purpose::STANDARD.encode(chat.get_public_key().await.unwrap());
+        for secret in [bob.id.as_str(), "Bob", "usual place", conversation.id.as_str(), public_key.as_str()] {
+            assert!(!exported.contains(secret), "{} in diagnostics", secret);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        let report = chat.health().await;
+        assert_eq!(report.status, health::HealthStatus::Unhealthy);
+        assert!(!report.storage.open);
+        
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        chat.storage().await.unwrap().push_outbox(&[vec![1u8], vec![2u8]]).unwrap();
+        chat.flush().await.unwrap();
+        let report = chat.health().await;
+        // Usable, but with nowhere to deliver to
+        assert_eq!(report.status, health::HealthStatus::Degraded);
+        assert_eq!(report.http_status(), 200);
+        assert_eq!(report.storage.outbox_depth, 2);
+        assert!(report.storage.last_flush.is_some());
+        assert!(!report.storage.flush_overdue);
+        assert_eq!((report.network.running, report.network.responsive), (false, None));
+    }
+    
+    #[tokio::test]
+    async fn test_event_replay() {
+        let chat = SecureChat::new(None);
+        // Reported before anyone listens, as during unlock
+        chat.emit(vec![ChatEvent::SyncCompleted, ChatEvent::Error { message: "early".to_string() }]).await;
+        assert_eq!(chat.latest_event_seq(), 2);
+        let replay = chat.replay_events(2);
+        assert_eq!(replay.missed, 0);
+        assert!(matches!(&replay.events[..], [events::SequencedEvent { seq: 2, event: ChatEvent::Error { .. } }]));
+        
+        let mut subscription = chat.subscribe_from(1);
+        chat.emit(vec![ChatEvent::SyncCompleted]).await;
+        let mut seqs = Vec::new();
+        for _ in 0..3 {
+            seqs.push(subscription.recv().await.unwrap().seq);
+        }
+        assert_eq!(seqs, vec![1, 2, 3]);
+    }
+    
+    #[tokio::test]
+    async fn test_key_maintenance() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let storage = chat.storage().await.unwrap();
+        let bob = chat.add_contact([2u8; 32], "Bob").await.unwrap();
+        let device = devices::RemoteDevice { device_id: "phone".to_string(), message_key: [3u8; 32], announced_at: OffsetDateTime::now_utc() };
+        storage.set_contact_devices(&bob.id, &[device]).unwrap();
+        for device_id in ["phone", "old tablet"] {
+            storage.store_device_session(&bob.id, device_id, &devices::DeviceSession::new([3u8; 32])).unwrap();
+        }
+        storage.store_device_session("removed", "phone", &devices::DeviceSession::new([4u8; 32])).unwrap();
+        storage.store_contact_prekey(&bob.id, &invite::ContactPrekey {
+            prekey: [5u8; 32],
+            token: None,
+            expires_at: OffsetDateTime::now_utc() - time::Duration::hours(1),
+        }).unwrap();
+        let invite = chat.create_invite(std::time::Duration::from_secs(3600), false).await.unwrap();
+        
+        let report = chat.clean_key_material().await.unwrap();
+        assert_eq!(report, maintenance::MaintenanceReport { invite_prekeys: 0, contact_prekeys: 1, skipped_keys: 0, sessions: 2, stamps: 0 });
+        assert!(storage.get_device_session(&bob.id, "phone").unwrap().is_some());
+        assert!(storage.get_invite_prekey(&invite.prekey).unwrap().is_some());
+        
+        let report = storage.clean_key_material(OffsetDateTime::now_utc() + time::Duration::hours(2)).unwrap();
+        assert_eq!(report.total(), 1);
+        assert!(storage.get_invite_prekey(&invite.prekey).unwrap().is_none());
+    }
+    
+    #[tokio::test]
+    async fn test_ingest_journal_replay() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let contact = chat.add_contact([9u8; 32], "Bob").await.unwrap();
+        let keys = chat.message_keys.read().await.clone().unwrap();
+        let journal = |envelope: &MessageEnvelope| {
+            let chat = chat.clone();
+            let envelope = envelope.clone();
+            async move { chat.storage.read().await.as_ref().unwrap().journal_envelope(&envelope).unwrap() }
+        };
+        
+        // One crash right after journaling, one after storing but before
+        // the entry was cleared
+        let lost = envelope_for(&keys, &contact.id, "lost");
+        journal(&lost).await;
+        let stored = envelope_for(&keys, &contact.id, "stored");
+        chat.receive_envelope(stored.clone()).await.unwrap();
+        journal(&stored).await;
+        
+        assert_eq!(chat.replay_ingest_journal().await.unwrap(), 2);
+        let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+        let messages = chat.get_messages(&conversation.id, 10).await.unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().any(|m| m.id == lost.id));
+        
+        let storage = chat.storage.read().await;
+        let storage_ref = storage.as_ref().unwrap();
+        assert!(storage_ref.journaled_envelopes().unwrap().is_empty());
+        // Every message is acknowledged, the stored one twice
+        let receipts: Vec<_> = storage_ref.take_outbox().unwrap().iter()
+            .filter_map(|data| match ProtocolMessage::decode(data).unwrap() {
+                ProtocolMessage::DeliveryReceipt { message_id, .. } => Some(message_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(receipts, [stored.id.clone(), lost.id, stored.id]);
+    }
+    
+    #[tokio::test]
+    async fn test_fanned_out_message_received_once() {
+        use base64::Engine;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let contact = chat.add_contact([9u8; 32], "Bob").await.unwrap();
+        let keys = chat.message_keys.read().await.clone().unwrap();
+        
+        // Two copies of one message, naming Bob by his key as real
+        // senders do
+        let sender_id = base64::engine::general_purpose::STANDARD.encode([9u8; 32]);
+        let message_id = protocol::generate_id();
+        let copy = || MessageEnvelope {
+            message_id: Some(message_id.clone()),
+            recipient_device: Some(chat.device_id.clone()),
+            ..envelope_for(&keys, &sender_id, "hi")
+        };
+        let (first, second) = (copy(), copy());
+        assert_eq!(chat.receive_envelope(first).await.unwrap().len(), 1);
+        assert!(chat.receive_envelope(second.clone()).await.unwrap().is_empty());
+        
+        // Nor is it stored again when replayed after a crash
+        chat.storage().await.unwrap().journal_envelope(&second).unwrap();
+        assert_eq!(chat.replay_ingest_journal().await.unwrap(), 1);
+        let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+        let messages = chat.get_messages(&conversation.id, 10).await.unwrap();
+        assert_eq!(messages.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), [message_id.as_str()]);
+    }
+    
+    #[tokio::test]
+    async fn test_undecryptable_message_is_quarantined() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let contact = chat.add_contact([9u8; 32], "Bob").await.unwrap();
+        
+        let current = chat.message_keys.read().await.clone().unwrap();
+        let events = chat.receive_envelope(envelope_for(&current, &contact.id, "hello")).await.unwrap();
+        assert!(matches!(events[..], [ChatEvent::MessageReceived { .. }]));
+        
+        // Encrypted to keys this device no longer has loaded
+        let newer = MessageKeyPair::generate();
+        let lost = envelope_for(&newer, &contact.id, "recover me");
+        let events = chat.receive_envelope(lost.clone()).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::DecryptionFailed { message_id, .. }] if *message_id == lost.id));
+        assert_eq!(chat.get_quarantine(Some(&contact.id)).await.unwrap().len(), 1);
+        
+        // A reset that doesn't help bumps the attempt count
+        assert_eq!(chat.reset_session(&contact.id).await.unwrap(), 0);
+        assert_eq!(chat.get_quarantine(None).await.unwrap()[0].attempts, 2);
+        
+        *chat.message_keys.write().await = Some(newer);
+        assert_eq!(chat.reset_session(&contact.id).await.unwrap(), 1);
+        assert!(chat.get_quarantine(None).await.unwrap().is_empty());
+        
+        let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+        assert!(chat.get_messages(&conversation.id, 10).await.unwrap().iter().any(|m| m.id == lost.id));
+        
+        let events = chat.get_security_events(&contact.id).await.unwrap();
+        assert!(matches!(events[0].kind, SecurityEventKind::DecryptFailed { .. }));
+        assert_eq!(events[1].kind, SecurityEventKind::SessionReset);
+        assert_eq!(events.len(), 3);
+        
+        chat.receive_envelope(envelope_for(&current, &contact.id, "again")).await.unwrap();
+        assert_eq!(chat.purge_quarantine(Some(&contact.id)).await.unwrap(), 1);
+        assert!(chat.get_quarantine(None).await.unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_out_of_order_messages_reported_in_order() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let contact = chat.add_contact([9u8; 32], "Bob").await.unwrap();
+        let keys = chat.message_keys.read().await.clone().unwrap();
+        
+        let envelopes: Vec<_> = (0..3).map(|n| MessageEnvelope {
+            ratchet_header: Some(crypto::RatchetHeader { chain: 1, previous_chain_length: 0, message_number: n }),
+            ..envelope_for(&keys, &contact.id, "hi")
+        }).collect();
+        let received_ids = |events: Vec<ChatEvent>| -> Vec<String> {
+            events.into_iter().map(|event| match event {
+                ChatEvent::MessageReceived { message, .. } => message.id,
+                other => panic!("unexpected event {:?}", other),
+            }).collect()
+        };
+        
+        assert_eq!(received_ids(chat.receive_envelope(envelopes[0].clone()).await.unwrap()), [envelopes[0].id.clone()]);
+        assert!(chat.receive_envelope(envelopes[2].clone()).await.unwrap().is_empty());
+        
+        // Held back but already stored
+        let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+        assert_eq!(chat.get_messages(&conversation.id, 10).await.unwrap().len(), 2);
+        
+        assert_eq!(
+            received_ids(chat.receive_envelope(envelopes[1].clone()).await.unwrap()),
+            [envelopes[1].id.clone(), envelopes[2].id.clone()]
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_session_numbers_are_taken_once() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let bob = IdentityKeyPair::generate(&mut rand::thread_rng());
+        let contact = chat.add_contact(bob.public_key.to_bytes(), "Bob").await.unwrap();
+        let keys = chat.message_keys.read().await.clone().unwrap();
+        let from_phone = |chain, message_number| {
+            let mut envelope = MessageEnvelope {
+                ratchet_header: Some(crypto::RatchetHeader { chain, previous_chain_length: 0, message_number }),
+                sender_device: Some("phone".to_string()),
+                ..envelope_for(&keys, &contact.id, "hi")
+            };
+            envelope.signature = bob.sign(&envelope.signing_bytes().unwrap()).to_bytes().to_vec();
+            envelope
+        };
+        
+        chat.receive_envelope(from_phone(0, 2)).await.unwrap();
+        // A new message under a number already taken is refused, while one
+        // passed over is still let in once
+        assert!(chat.receive_envelope(from_phone(0, 2)).await.is_err());
+        chat.receive_envelope(from_phone(0, 1)).await.unwrap();
+        assert!(chat.receive_envelope(from_phone(0, 1)).await.is_err());
+        
+        let max_skip = chat.protocol_limits().await.max_skipped_keys;
+        let error = chat.receive_envelope(from_phone(0, 3 + max_skip + 1)).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(limits::LimitError::TooManySkipped { .. })));
+        let error = chat.receive_envelope(from_phone(u32::MAX, 0)).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(limits::LimitError::TooManyChains { .. })));
+        chat.receive_envelope(from_phone(0, 3)).await.unwrap();
+        
+        let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+        assert_eq!(chat.get_messages(&conversation.id, 10).await.unwrap().len(), 3);
+        let session = chat.storage().await.unwrap().get_receiving_session(&contact.id, "phone").unwrap().unwrap();
+        assert_eq!((session.next_message, session.skipped.len()), (4, 1));
+    }
+    
+    #[tokio::test]
+    async fn test_messages_follow_logical_order() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let contact = chat.add_contact([9u8; 32], "Bob").await.unwrap();
+        let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+        let keys = chat.message_keys.read().await.clone().unwrap();
+        
+        // Bob's clock runs an hour fast and he has sent a few messages already
+        let skewed = MessageEnvelope {
+            timestamp: OffsetDateTime::now_utc() + time::Duration::hours(1),
+            lamport: Some(10),
+            ..envelope_for(&keys, &contact.id, "from the future")
+        };
+        chat.receive_envelope(skewed.clone()).await.unwrap();
+        let reply = chat.send_text_message(&conversation.id, "reply").await.unwrap();
+        
+        let messages = chat.get_messages(&conversation.id, 10).await.unwrap();
+        let ids: Vec<_> = messages.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, [skewed.id.as_str(), reply.as_str()]);
+        assert_eq!(messages[1].lamport, 11);
+        
+        // Limits keep the newest messages
+        let latest = chat.get_messages(&conversation.id, 1).await.unwrap();
+        assert_eq!(latest[0].id, reply);
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_sends_and_reads() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let contact = chat.add_contact([9u8; 32], "Bob").await.unwrap();
+        let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap().id;
+        
+        let mut tasks = Vec::new();
+        for i in 0..16 {
+            let chat = chat.clone();
+            let conversation = conversation.clone();
+            tasks.push(tokio::spawn(async move {
+                chat.send_text_message(&conversation, &i.to_string()).await.unwrap();
+                chat.get_messages(&conversation, usize::MAX).await.unwrap().len()
+            }));
+        }
+        for task in tasks {
+            assert!(task.await.unwrap() >= 1);
+        }
+        
+        // Each send still got its own clock value
+        let mut clocks: Vec<_> = chat.get_messages(&conversation, usize::MAX).await.unwrap()
+            .iter().map(|m| m.lamport).collect();
+        clocks.dedup();
+        assert_eq!(clocks, (1..=16).collect::<Vec<_>>());
+    }
+    
+    #[tokio::test]
+    async fn test_key_change_is_audited() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let contact = chat.add_contact([9u8; 32], "Bob").await.unwrap();
+        
+        let safety_number = chat.get_safety_number(&contact.id).await.unwrap();
+        assert!(chat.verify_contact(&contact.id, &safety_number.safety_number()).await.unwrap());
+        chat.update_contact_key(&contact.id, [8u8; 32]).await.unwrap();
+        assert!(!chat.get_contacts().await.unwrap()[0].verified);
+        
+        let kinds: Vec<_> = chat.get_security_events(&contact.id).await.unwrap()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(kinds, vec![
+            SecurityEventKind::VerificationChanged { verified: true },
+            SecurityEventKind::KeyChanged { old_key: [9u8; 32], new_key: [8u8; 32] },
+            SecurityEventKind::VerificationChanged { verified: false },
+        ]);
+    }
+    
+    #[tokio::test]
+    async fn test_contact_export_merges_on_import() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        let laptop = SecureChat::new(None);
+        laptop.create_account(temp_dir.path().join("laptop.db"), "password", "User").await.unwrap();
+        let alice = laptop.add_contact([1u8; 32], "Alice").await.unwrap();
+        laptop.add_contact([2u8; 32], "Bob").await.unwrap();
+        let safety_number = laptop.get_safety_number(&alice.id).await.unwrap();
+        assert!(laptop.verify_contact(&alice.id, &safety_number.safety_number()).await.unwrap());
+        let export = laptop.export_contacts("export-pw").await.unwrap();
+        
+        // The other account already knows Alice, under its own name
+        let phone = SecureChat::new(None);
+        phone.create_account(temp_dir.path().join("phone.db"), "password", "User").await.unwrap();
+        let known = phone.add_contact([1u8; 32], "Ally").await.unwrap();
+        assert!(phone.import_contacts(&export, "wrong").await.is_err());
+        
+        let result = phone.import_contacts(&export, "export-pw").await.unwrap();
+        assert_eq!(result.signer, laptop.get_public_key().await.unwrap());
+        assert_eq!((result.added, result.updated, result.unchanged), (1, 1, 0));
+        
+        let contacts = phone.get_contacts().await.unwrap();
+        assert_eq!(contacts.len(), 2);
+        let ally = contacts.iter().find(|c| c.id == known.id).unwrap();
+        assert_eq!(ally.display_name, "Ally");
+        assert!(ally.verified);
+        assert_eq!(phone.get_security_events(&known.id).await.unwrap().len(), 1);
+        let bob = contacts.iter().find(|c| c.public_key == [2u8; 32]).unwrap();
+        assert!(!bob.verified);
+        
+        // Importing again changes nothing
+        let again = phone.import_contacts(&export, "export-pw").await.unwrap();
+        assert_eq!((again.added, again.updated, again.unchanged), (0, 0, 2));
+    }
+    
+    #[tokio::test]
+    async fn test_import_external_contacts() {
+        use base64::Engine;
+        use importer::{ImportFormat, ImportOutcome};
+        
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let known = chat.add_contact([1u8; 32], "Alice").await.unwrap();
+        
+        let key = |byte: u8| base64::engine::general_purpose::STANDARD.encode([byte; 32]);
+        let vcards = format!("BEGIN:VCARD\nFN:Alice\nKEY:{}\nEND:VCARD\n\
+            BEGIN:VCARD\nFN:Bob\nX-SECURECHAT-KEY:{}\nEND:VCARD\n\
+            BEGIN:VCARD\nFN:Carol\nTEL:+15550100\nEND:VCARD\n\
+            BEGIN:VCARD\nNOTE:nameless\nEND:VCARD\n", key(1), key(2));
+        let report = chat.import_external_contacts(vcards.as_bytes(), ImportFormat::Vcard).await.unwrap();
+        assert_eq!(report.results[0].outcome, ImportOutcome::Duplicate { id: known.id.clone() });
+        assert!(matches!(report.results[1].outcome, ImportOutcome::Added { .. }));
+        assert!(matches!(report.results[3].outcome, ImportOutcome::Failed { .. }));
+        assert_eq!((report.added(), report.pending(), report.failed()), (1, 1, 1));
+        
+        let bob = chat.get_contacts().await.unwrap().into_iter().find(|c| c.public_key == [2u8; 32]).unwrap();
+        assert!(!bob.verified && bob.last_seen.is_none());
+        
+        // Already pending from the first import
+        let signal = br#"[{"number": "+15550100", "name": "Carol"}, {"number": "+15550101", "name": "Dave"}]"#;
+        let report = chat.import_external_contacts(signal, ImportFormat::Signal).await.unwrap();
+        assert_eq!(report.pending(), 2);
+        let report = chat.import_external_contacts(signal, ImportFormat::Signal).await.unwrap();
+        assert!(report.results.iter().all(|r| matches!(r.outcome, ImportOutcome::Duplicate { .. })));
+        
+        let pending = chat.get_pending_contacts().await.unwrap();
+        assert_eq!(pending.iter().map(|p| p.display_name.as_str()).collect::<Vec<_>>(), vec!["Carol", "Carol", "Dave"]);
+        assert!(chat.add_pending_contact(&pending[2].id, [1u8; 32]).await.is_err());
+        let dave = chat.add_pending_contact(&pending[2].id, [4u8; 32]).await.unwrap();
+        assert_eq!(dave.display_name, "Dave");
+        assert!(!dave.verified);
+        chat.delete_pending_contact(&pending[0].id).await.unwrap();
+        assert_eq!(chat.get_pending_contacts().await.unwrap().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_identity_armor_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let alice = SecureChat::new(None);
+        alice.create_account(temp_dir.path().join("alice.db"), "password", "Alice").await.unwrap();
+        let bob = SecureChat::new(None);
+        bob.create_account(temp_dir.path().join("bob.db"), "password", "Bob").await.unwrap();
+        
+        let armored = alice.export_identity_armor().await.unwrap();
+        assert!(alice.add_contact_from_armor(&armored).await.is_err());
+        let contact = bob.add_contact_from_armor(&armored).await.unwrap();
+        assert_eq!(contact.display_name, "Alice");
+        assert_eq!(contact.public_key, alice.get_public_key().await.unwrap());
+        assert!(!contact.verified);
+        assert_eq!(bob.add_contact_from_armor(&armored).await.unwrap().id, contact.id);
+        assert_eq!(bob.get_contacts().await.unwrap().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_contact_cards() {
+        let temp_dir = TempDir::new().unwrap();
+        let alice = SecureChat::new(None);
+        alice.create_account(temp_dir.path().join("alice.db"), "password", "Alice").await.unwrap();
+        let bob = SecureChat::new(None);
+        bob.create_account(temp_dir.path().join("bob.db"), "password", "Bob").await.unwrap();
+        
+        let card = alice.create_contact_card(std::time::Duration::from_secs(3600)).await.unwrap();
+        assert!(alice.import_contact_card(&card.to_bytes().unwrap()).await.is_err());
+        let contact = bob.import_contact_card(card.to_link().unwrap().as_bytes()).await.unwrap();
+        assert_eq!(contact.display_name, "Alice");
+        assert_eq!(contact.public_key, alice.get_public_key().await.unwrap());
+        assert_eq!(bob.get_contact_devices(&contact.id).await.unwrap(), vec![card.device.device.clone()]);
+        
+        // A revoked card is refused, but the contact stays
+        let revocation = alice.revoke_contact_card(&card.id).await.unwrap();
+        assert!(alice.get_issued_cards().await.unwrap()[0].revoked_at.is_some());
+        bob.import_card_revocation(&revocation.to_bytes().unwrap()).await.unwrap();
+        assert!(bob.import_contact_card(&card.to_bytes().unwrap()).await.is_err());
+        assert_eq!(bob.get_contacts().await.unwrap().len(), 1);
+        
+        let second = alice.create_contact_card(std::time::Duration::from_secs(3600)).await.unwrap();
+        assert_eq!(bob.import_contact_card(&second.to_bytes().unwrap()).await.unwrap().id, contact.id);
+    }
+    
+    #[tokio::test]
+    async fn test_username_claims_and_pinning() {
+        let temp_dir = TempDir::new().unwrap();
+        let registry: Arc<dyn username::UsernameRegistry> = Arc::new(username::MemoryRegistry::default());
+        let mut chats = Vec::new();
+        for name in ["mallory", "alice", "bob"] {
+            let chat = SecureChat::new(None);
+            chat.create_account(temp_dir.path().join(format!("{}.db", name)), "password", name).await.unwrap();
+            chats.push(chat);
+        }
+        let [mallory, alice, bob] = chats.try_into().ok().unwrap();
+        
+        // Mallory's claim is older but only published elsewhere for now
+        mallory.set_username_registry(Arc::new(username::MemoryRegistry::default())).await;
+        let squatted = mallory.claim_username("alice").await.unwrap();
+        alice.set_username_registry(registry.clone()).await;
+        bob.set_username_registry(registry.clone()).await;
+        
+        let claim = alice.claim_username("Alice").await.unwrap();
+        assert_eq!(claim.username, "alice");
+        assert_eq!(alice.get_username().await.unwrap(), Some(claim.clone()));
+        assert!(bob.claim_username("alice").await.is_err());
+        assert!(bob.resolve_username("nobody").await.is_err());
+        
+        assert_eq!(bob.resolve_username("alice").await.unwrap(), username::UsernameResolution::FirstUse(claim.clone()));
+        assert_eq!(bob.resolve_username("ALICE").await.unwrap(), username::UsernameResolution::Pinned(claim.clone()));
+        
+        // The older claim now wins the name, but not against Bob's pin
+        registry.publish(&squatted).unwrap();
+        assert_eq!(bob.resolve_username("alice").await.unwrap(), username::UsernameResolution::KeyChanged {
+            pinned_key: alice.get_public_key().await.unwrap(),
+            claim: squatted.clone(),
+        });
+        bob.accept_username_key("alice", squatted.identity_key).await.unwrap();
+        assert_eq!(bob.resolve_username("alice").await.unwrap(), username::UsernameResolution::Pinned(squatted));
+    }
+    
+    /// Send `text` to `contact_id` and return the envelope that went out
+    async fn send_through_invite(chat: &SecureChat, contact_id: &str, text: &str) -> MessageEnvelope {
+        let conversation = chat.get_or_create_conversation(contact_id).await.unwrap();
+        let message_id = chat.send_text_message(&conversation.id, text).await.unwrap();
+        let message = chat.storage.read().await.as_ref().unwrap()
+            .get_message(&conversation.id, &message_id).unwrap().unwrap();
+        let prekey = chat.storage.read().await.as_ref().unwrap()
+            .get_contact_prekey(contact_id).unwrap().unwrap();
+        chat.seal_invite_message(contact_id, &prekey, &message, &message.id).await.unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_one_time_invite_first_message() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut chats = Vec::new();
+        for name in ["Alice", "Bob", "Carol"] {
+            let chat = SecureChat::new(None);
+            chat.create_account(temp_dir.path().join(format!("{}.db", name)), "password", name).await.unwrap();
+            chats.push(chat);
+        }
+        let [alice, bob, carol] = chats.try_into().ok().unwrap();
+        
+        let link = alice.create_invite(std::time::Duration::from_secs(3600), true).await.unwrap().to_link().unwrap();
+        assert!(alice.accept_invite(&link).await.is_err());
+        let contact = bob.accept_invite(&link).await.unwrap();
+        assert_eq!(contact.display_name, "Alice");
+        assert_eq!(contact.public_key, alice.get_public_key().await.unwrap());
+        
+        // Alice learns about Bob from his first message
+        let events = alice.receive_envelope(send_through_invite(&bob, &contact.id, "hi Alice").await).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::MessageReceived { message, .. }] if message.preview_text() == "hi Alice"));
+        let contacts = alice.get_contacts().await.unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].display_name, "Bob");
+        assert_eq!(contacts[0].public_key, bob.get_public_key().await.unwrap());
+        alice.receive_envelope(send_through_invite(&bob, &contact.id, "again").await).await.unwrap();
+        
+        // The token is spent on Bob
+        let carols = carol.accept_invite(&link).await.unwrap();
+        assert!(alice.receive_envelope(send_through_invite(&carol, &carols.id, "me too").await).await.is_err());
+        assert_eq!(alice.get_contacts().await.unwrap().len(), 1);
+        
+        // A tampered message is rejected
+        let mut envelope = send_through_invite(&bob, &contact.id, "edited").await;
+        envelope.invite.as_mut().unwrap().display_name = "Mallory".to_string();
+        assert!(alice.receive_envelope(envelope).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_view_once_media() {
+        let temp_dir = TempDir::new().unwrap();
+        let alice = SecureChat::new(None);
+        alice.create_account(temp_dir.path().join("alice.db"), "password", "Alice").await.unwrap();
+        let bob = SecureChat::new(None);
+        bob.create_account(temp_dir.path().join("bob.db"), "password", "Bob").await.unwrap();
+        bob.set_thumbnailer(Arc::new(PrefixThumbnailer)).await;
+        
+        let link = alice.create_invite(std::time::Duration::from_secs(3600), false).await.unwrap().to_link().unwrap();
+        let contact = bob.accept_invite(&link).await.unwrap();
+        let conversation = bob.get_or_create_conversation(&contact.id).await.unwrap();
+        let message_id = bob.send_view_once_image(&conversation.id, b"secret picture", "image/png").await.unwrap();
+        let sent = bob.storage.read().await.as_ref().unwrap()
+            .get_message(&conversation.id, &message_id).unwrap().unwrap();
+        let prekey = bob.storage.read().await.as_ref().unwrap()
+            .get_contact_prekey(&contact.id).unwrap().unwrap();
+        let envelope = bob.seal_invite_message(&contact.id, &prekey, &sent, &sent.id).await.unwrap();
+        assert!(envelope.view_once);
+        assert!(envelope.thumbnail.is_none());
+        let attachment = sent.content.attachment().unwrap().clone();
+        assert!(bob.get_attachment(&attachment).await.is_err());
+        assert!(bob.open_view_once(&message_id).await.is_err());
+        assert!(bob.forward_message(&message_id, std::slice::from_ref(&conversation.id), false).await.is_err());
+        
+        let events = alice.receive_envelope(envelope).await.unwrap();
+        let [ChatEvent::MessageReceived { conversation_id, message }] = &events[..] else {
+            panic!("Expected the message, got {:?}", events);
+        };
+        assert!(alice.open_view_once(&message.id).await.is_err());
+        alice.storage.read().await.as_ref().unwrap()
+            .store_blob_chunk(&attachment, 0, b"secret picture").unwrap();
+        assert!(alice.get_attachment(&attachment).await.is_err());
+        assert!(alice.read_attachment_chunk(&attachment, 0).await.is_err());
+        
+        // Opened once, then gone for good
+        assert_eq!(alice.open_view_once(&message.id).await.unwrap(), b"secret picture");
+        assert!(alice.open_view_once(&message.id).await.is_err());
+        assert!(alice.storage.read().await.as_ref().unwrap()
+            .store_blob_chunk(&attachment, 0, b"secret picture").is_err());
+        let messages = alice.get_messages(conversation_id, 10).await.unwrap();
+        assert!(matches!(
+            &messages.last().unwrap().content,
+            MessageContent::System { notice: protocol::SystemNotice::ViewOnceOpened { message_id } } if *message_id == message.id
+        ));
+    }
     
-    #[cfg(not(any(
-        target_os = "linux",
-        target_os = "windows",
-        target_os = "android",
-        target_os = "macos",
-        target_os = "ios"
-    )))]
-    return Platform::Unknown;
-}
-
-// Re-exports
-pub use crypto::{hash_password, verify_password};
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+    #[tokio::test]
+    async fn test_conversation_policy() {
+        let temp_dir = TempDir::new().unwrap();
+        let alice = SecureChat::new(None);
+        alice.create_account(temp_dir.path().join("alice.db"), "password", "Alice").await.unwrap();
+        let bob = SecureChat::new(None);
+        bob.create_account(temp_dir.path().join("bob.db"), "password", "Bob").await.unwrap();
+        
+        let link = alice.create_invite(std::time::Duration::from_secs(3600), false).await.unwrap().to_link().unwrap();
+        let contact = bob.accept_invite(&link).await.unwrap();
+        let bobs = bob.get_or_create_conversation(&contact.id).await.unwrap().id;
+        let policy = ConversationPolicy { no_forwarding: true, view_once_media: true };
+        bob.set_conversation_policy(&bobs, policy).await.unwrap();
+        assert_eq!(bob.get_conversation_policy(&bobs).await.unwrap().effective(), policy);
+        
+        let envelope = send_through_invite(&bob, &contact.id, "between us").await;
+        assert_eq!(envelope.policy, Some(policy));
+        let events = alice.receive_envelope(envelope).await.unwrap();
+        let [ChatEvent::ConversationPolicyRequested { conversation_id, policy: requested }, ChatEvent::MessageReceived { message, .. }] = &events[..] else {
+            panic!("Expected the policy and the message, got {:?}", events);
+        };
+        assert_eq!(*requested, policy);
+        let policies = alice.get_conversation_policy(conversation_id).await.unwrap();
+        assert_eq!((policies.ours, policies.theirs), (ConversationPolicy::default(), policy));
+        
+        // Held on Alice's side too
+        assert!(alice.forward_message(&message.id, std::slice::from_ref(conversation_id), false).await.is_err());
+        let transcript = alice.export_conversation(conversation_id).await.unwrap();
+        assert!(transcript.contains("Bob: [redacted]"));
+        assert!(!transcript.contains("between us"));
+        assert!(alice.send_image(conversation_id, b"picture", "image/png", Some("caption")).await.is_err());
+        let image_id = alice.send_image(conversation_id, b"picture", "image/png", None).await.unwrap();
+        let image = alice.storage.read().await.as_ref().unwrap()
+            .get_message(conversation_id, &image_id).unwrap().unwrap();
+        assert!(alice.get_attachment(image.content.attachment().unwrap()).await.is_err());
+        
+        // The same envelope policy again is no news
+        let events = alice.receive_envelope(send_through_invite(&bob, &contact.id, "again").await).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::MessageReceived { .. }]));
+        
+        // Bob's own side still exports in full
+        assert!(bob.export_conversation(&bobs).await.unwrap().contains("Me: between us"));
+    }
     
     #[tokio::test]
-    async fn test_create_and_unlock() {
+    async fn test_session_requirements_refuse_downgrades() {
         let temp_dir = TempDir::new().unwrap();
-        let db_path = temp_dir.path().join("test.db");
+        let alice = SecureChat::new(None);
+        alice.create_account(temp_dir.path().join("alice.db"), "password", "Alice").await.unwrap();
+        let bob = SecureChat::new(None);
+        bob.create_account(temp_dir.path().join("bob.db"), "password", "Bob").await.unwrap();
         
-        // Create account and drop the instance to release the database lock
-        {
+        let link = alice.create_invite(std::time::Duration::from_secs(3600), false).await.unwrap().to_link().unwrap();
+        let contact = bob.accept_invite(&link).await.unwrap();
+        alice.receive_envelope(send_through_invite(&bob, &contact.id, "hi").await).await.unwrap();
+        let bob_id = alice.get_contacts().await.unwrap()[0].id.clone();
+        
+        // Alice takes nothing more through her invite's prekey
+        let required = devices::SessionRequirements { verified: false, device_sessions: true };
+        alice.set_session_requirements(&bob_id, required).await.unwrap();
+        let events = alice.receive_envelope(send_through_invite(&bob, &contact.id, "still the invite").await).await.unwrap();
+        assert!(matches!(
+            &events[..],
+            [ChatEvent::SessionDowngradeRefused { contact_id, downgrade: devices::Downgrade::InvitePrekey }] if *contact_id == bob_id
+        ));
+        let conversation = alice.get_or_create_conversation(&bob_id).await.unwrap();
+        assert_eq!(alice.get_messages(&conversation.id, 10).await.unwrap().len(), 1);
+        let logged: Vec<_> = alice.storage().await.unwrap().get_security_events(&bob_id).unwrap()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(logged, [
+            SecurityEventKind::RequirementsChanged { requirements: required },
+            SecurityEventKind::DowngradeRefused { downgrade: devices::Downgrade::InvitePrekey },
+        ]);
+        
+        // Bob won't send to Alice before verifying her
+        let info = bob.get_session_info(&contact.id).await.unwrap();
+        assert_eq!(info.mode, devices::SessionMode::InvitePrekey);
+        bob.set_session_requirements(&contact.id, devices::SessionRequirements { verified: true, device_sessions: false }).await.unwrap();
+        let conversation = bob.get_or_create_conversation(&contact.id).await.unwrap();
+        assert!(bob.send_text_message(&conversation.id, "unverified").await.is_err());
+        let messages = bob.get_messages(&conversation.id, 10).await.unwrap();
+        assert!(matches!(messages.last().unwrap().status, DeliveryStatus::Failed { .. }));
+        assert!(bob.replay_events(1).events.iter().any(|sequenced| matches!(
+            sequenced.event,
+            ChatEvent::SessionDowngradeRefused { downgrade: devices::Downgrade::Unverified, .. }
+        )));
+        
+        // With per-device sessions required and no device announced yet,
+        // there is no way to reach her
+        bob.set_session_requirements(&contact.id, required).await.unwrap();
+        let info = bob.get_session_info(&contact.id).await.unwrap();
+        assert_eq!((info.mode, info.requirements), (devices::SessionMode::Unestablished, required));
+    }
+    
+    /// What `announce_device` would send for `chat`
+    async fn device_announcement(chat: &SecureChat) -> devices::DeviceAnnouncement {
+        let message_key = chat.message_keys.read().await.as_ref().unwrap().public_key.to_bytes();
+        devices::DeviceAnnouncement::new(chat.identity.read().await.as_ref().unwrap(), &chat.device_id, message_key).unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_capability_mismatch_quarantined() {
+        let temp_dir = TempDir::new().unwrap();
+        let alice = SecureChat::new(None);
+        alice.create_account(temp_dir.path().join("alice.db"), "password", "Alice").await.unwrap();
+        let bob = SecureChat::new(None);
+        bob.create_account(temp_dir.path().join("bob.db"), "password", "Bob").await.unwrap();
+        
+        let link = alice.create_invite(std::time::Duration::from_secs(3600), false).await.unwrap().to_link().unwrap();
+        let contact = bob.accept_invite(&link).await.unwrap();
+        alice.receive_envelope(send_through_invite(&bob, &contact.id, "hi").await).await.unwrap();
+        let bob_id = alice.get_contacts().await.unwrap()[0].id.clone();
+        let announcement = protocol::ProtocolMessage::DeviceAnnouncement { announcement: device_announcement(&alice).await };
+        bob.handle_protocol_message("peer".to_string(), announcement).await;
+        let bobs_device = device_announcement(&bob).await;
+        alice.handle_protocol_message("peer".to_string(), protocol::ProtocolMessage::DeviceAnnouncement { announcement: bobs_device.clone() }).await;
+        
+        let seal = |text: &'static str| {
+            let bob = &bob;
+            let contact_id = &contact.id;
+            async move {
+                let conversation = bob.get_or_create_conversation(contact_id).await.unwrap();
+                let message_id = bob.send_text_message(&conversation.id, text).await.unwrap();
+                let message = bob.storage().await.unwrap().get_message(&conversation.id, &message_id).unwrap().unwrap();
+                let device = bob.get_contact_devices(contact_id).await.unwrap().remove(0);
+                bob.seal_for_device(contact_id, &device, &message).await.unwrap()
+            }
+        };
+        let envelope = seal("sealed with the transcript").await;
+        assert!(envelope.capabilities.is_some());
+        let events = alice.receive_envelope(envelope).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::MessageReceived { .. }]));
+        
+        // Stripping the transcript, or changing it, breaks the seal
+        let mut stripped = seal("stripped").await;
+        stripped.capabilities = None;
+        let events = alice.receive_envelope(stripped).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::DecryptionFailed { .. }]));
+        
+        // Alice holds an older announcement of Bob's with fewer features
+        let older = devices::Capabilities(devices::Capabilities::DEVICE_SESSIONS.0 | devices::Capabilities::TRANSCRIPT.0);
+        alice.storage().await.unwrap().store_device_capabilities(&bob_id, &bob.device_id, older).unwrap();
+        let events = alice.receive_envelope(seal("newer features").await).await.unwrap();
+        assert!(matches!(
+            &events[..],
+            [ChatEvent::CapabilityMismatch { contact_id, .. }] if *contact_id == bob_id
+        ));
+        assert!(alice.storage().await.unwrap().get_security_events(&bob_id).unwrap().iter().any(|event| matches!(
+            event.kind,
+            SecurityEventKind::CapabilityMismatch { transcript } if transcript.sender == devices::Capabilities::SUPPORTED
+        )));
+        
+        // His next announcement explains it and the message opens
+        let announcement = protocol::ProtocolMessage::DeviceAnnouncement { announcement: device_announcement(&bob).await };
+        alice.handle_protocol_message("peer".to_string(), announcement).await;
+        let conversation = alice.get_or_create_conversation(&bob_id).await.unwrap();
+        let messages = alice.get_messages(&conversation.id, 10).await.unwrap();
+        assert!(matches!(&messages.last().unwrap().content, MessageContent::Text { text } if text == "newer features"));
+    }
+    
+    #[tokio::test]
+    async fn test_key_log_catches_unlogged_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let alice = SecureChat::new(None);
+        alice.create_account(temp_dir.path().join("alice.db"), "password", "Alice").await.unwrap();
+        let bob = SecureChat::new(None);
+        bob.create_account(temp_dir.path().join("bob.db"), "password", "Bob").await.unwrap();
+        
+        let link = alice.create_invite(std::time::Duration::from_secs(3600), false).await.unwrap().to_link().unwrap();
+        let contact = bob.accept_invite(&link).await.unwrap();
+        alice.create_contact_card(std::time::Duration::from_secs(3600)).await.unwrap();
+        // Handing out the same key again logs nothing new
+        alice.create_contact_card(std::time::Duration::from_secs(3600)).await.unwrap();
+        let announcement = protocol::ProtocolMessage::DeviceAnnouncement { announcement: device_announcement(&alice).await };
+        bob.handle_protocol_message("peer".to_string(), announcement).await;
+        
+        let report = bob.verify_contact_key_log(&contact.id, &alice.export_key_log().await.unwrap()).await.unwrap();
+        assert_eq!((report.entries, report.newer_than_log), (2, 0));
+        assert!(report.inconsistencies.is_empty());
+        
+        // A device Alice never had, announced under her stolen key
+        let rogue = {
+            let identity = alice.identity.read().await;
+            devices::DeviceAnnouncement::new(identity.as_ref().unwrap(), "rogue", [7; 32]).unwrap()
+        };
+        bob.handle_protocol_message("peer".to_string(), protocol::ProtocolMessage::DeviceAnnouncement { announcement: rogue }).await;
+        let stale = alice.export_key_log().await.unwrap();
+        let report = bob.verify_contact_key_log(&contact.id, &stale).await.unwrap();
+        assert_eq!(report.newer_than_log, 1);
+        assert!(report.inconsistencies.is_empty());
+        
+        // Her log moves past it without it
+        alice.create_invite(std::time::Duration::from_secs(3600), false).await.unwrap();
+        let report = bob.verify_contact_key_log(&contact.id, &alice.export_key_log().await.unwrap()).await.unwrap();
+        let expected = [transparency::Inconsistency::DeviceKey { device_id: "rogue".to_string(), message_key: [7; 32] }];
+        assert_eq!(report.inconsistencies, expected);
+        let logged = bob.storage().await.unwrap().get_security_events(&contact.id).unwrap();
+        assert_eq!(logged.last().unwrap().kind, SecurityEventKind::KeyLogMismatch { inconsistencies: expected.to_vec() });
+        
+        // Someone else's log doesn't pass for hers
+        let report = bob.verify_contact_key_log(&contact.id, &bob.export_key_log().await.unwrap()).await.unwrap();
+        assert!(matches!(&report.inconsistencies[..], [transparency::Inconsistency::IdentityKey { .. }]));
+    }
+    
+    #[tokio::test]
+    async fn test_deniable_authentication() {
+        let temp_dir = TempDir::new().unwrap();
+        let alice = SecureChat::new(None);
+        alice.create_account(temp_dir.path().join("alice.db"), "password", "Alice").await.unwrap();
+        let bob = SecureChat::new(None);
+        bob.create_account(temp_dir.path().join("bob.db"), "password", "Bob").await.unwrap();
+        
+        let link = alice.create_invite(std::time::Duration::from_secs(3600), false).await.unwrap().to_link().unwrap();
+        let contact = bob.accept_invite(&link).await.unwrap();
+        alice.receive_envelope(send_through_invite(&bob, &contact.id, "hi").await).await.unwrap();
+        let bob_id = alice.get_contacts().await.unwrap()[0].id.clone();
+        let announcement = protocol::ProtocolMessage::DeviceAnnouncement { announcement: device_announcement(&alice).await };
+        bob.handle_protocol_message("peer".to_string(), announcement).await;
+        
+        let conversation = bob.get_or_create_conversation(&contact.id).await.unwrap();
+        let seal = |text: &'static str| {
+            let bob = &bob;
+            let contact_id = &contact.id;
+            let conversation_id = &conversation.id;
+            async move {
+                let message_id = bob.send_text_message(conversation_id, text).await.unwrap();
+                let message = bob.storage().await.unwrap().get_message(conversation_id, &message_id).unwrap().unwrap();
+                let device = bob.get_contact_devices(contact_id).await.unwrap().remove(0);
+                bob.seal_for_device(contact_id, &device, &message).await.unwrap()
+            }
+        };
+        
+        // Signed by default, which Alice can check without knowing Bob's
+        // device, and which covers the whole envelope
+        let signed = seal("signed").await;
+        assert!(!signed.signature.is_empty());
+        let mut tampered = signed.clone();
+        tampered.view_once = true;
+        let events = alice.receive_envelope(tampered).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::DecryptionFailed { .. }]));
+        let events = alice.receive_envelope(signed).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::MessageReceived { .. }]));
+        
+        bob.set_message_authentication(&conversation.id, devices::Authentication::Deniable).await.unwrap();
+        let info = bob.get_session_info(&contact.id).await.unwrap();
+        assert_eq!(info.authentication, devices::Authentication::Deniable);
+        let deniable = seal("deniable").await;
+        assert!(deniable.signature.is_empty());
+        
+        // Unsigned, it waits until Alice knows the key of Bob's device
+        let events = alice.receive_envelope(deniable.clone()).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::DecryptionFailed { .. }]));
+        let announcement = protocol::ProtocolMessage::DeviceAnnouncement { announcement: device_announcement(&bob).await };
+        alice.handle_protocol_message("peer".to_string(), announcement).await;
+        let alices = alice.get_or_create_conversation(&bob_id).await.unwrap();
+        let messages = alice.get_messages(&alices.id, 10).await.unwrap();
+        assert!(matches!(&messages.last().unwrap().content, MessageContent::Text { text } if text == "deniable"));
+        
+        // Sealed by a key Bob's device never announced
+        let mut forged = seal("forged").await;
+        let mallory = crypto::MessageKeyPair::generate();
+        let alices_key = alice.message_keys.read().await.as_ref().unwrap().public_key;
+        forged.encrypted_content = mallory.encrypt_message(&alices_key, &protocol::wire::encode(&MessageContent::Text { text: "forged".into() }).unwrap()).unwrap();
+        let events = alice.receive_envelope(forged).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::DecryptionFailed { reason, .. }] if reason.contains("didn't announce")));
+    }
+    
+    /// Takes the first bytes of any image for its thumbnail
+    struct PrefixThumbnailer;
+    
+    impl thumbnail::Thumbnailer for PrefixThumbnailer {
+        fn thumbnail(&self, data: &[u8], mime_type: &str, max_edge: u32) -> Result<Option<thumbnail::Thumbnail>> {
+            Ok(mime_type.starts_with("image/").then(|| thumbnail::Thumbnail {
+                mime_type: "image/png".to_string(),
+                width: max_edge,
+                height: max_edge / 2,
+                data: data[..4].to_vec(),
+            }))
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_thumbnail_travels_with_message() {
+        let temp_dir = TempDir::new().unwrap();
+        let alice = SecureChat::new(None);
+        alice.create_account(temp_dir.path().join("alice.db"), "password", "Alice").await.unwrap();
+        let bob = SecureChat::new(None);
+        bob.create_account(temp_dir.path().join("bob.db"), "password", "Bob").await.unwrap();
+        bob.set_thumbnailer(Arc::new(PrefixThumbnailer)).await;
+        
+        let link = alice.create_invite(std::time::Duration::from_secs(3600), false).await.unwrap().to_link().unwrap();
+        let contact = bob.accept_invite(&link).await.unwrap();
+        let conversation = bob.get_or_create_conversation(&contact.id).await.unwrap();
+        let image_id = bob.send_image(&conversation.id, b"\x89PNG and the rest", "image/png", Some("look")).await.unwrap();
+        let document_id = bob.send_file(&conversation.id, b"%PDF", "notes.pdf", "application/pdf").await.unwrap();
+        let thumbnail = bob.get_thumbnail(&image_id).await.unwrap().unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height, &thumbnail.data[..]), (256, 128, &b"\x89PNG"[..]));
+        assert!(bob.get_thumbnail(&document_id).await.unwrap().is_none());
+        
+        // Alice can show it before the image itself arrives
+        let storage = bob.storage().await.unwrap();
+        let message = storage.get_message(&conversation.id, &image_id).unwrap().unwrap();
+        let prekey = storage.get_contact_prekey(&contact.id).unwrap().unwrap();
+        let envelope = bob.seal_invite_message(&contact.id, &prekey, &message, &message.id).await.unwrap();
+        alice.receive_envelope(envelope.clone()).await.unwrap();
+        assert_eq!(alice.get_thumbnail(&image_id).await.unwrap(), Some(thumbnail));
+        
+        // One that doesn't open is dropped, not the message
+        let mut tampered = bob.seal_invite_message(&contact.id, &prekey, &message, "resent").await.unwrap();
+        tampered.thumbnail.as_mut().unwrap()[0] ^= 1;
+        let events = alice.receive_envelope(tampered).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::MessageReceived { .. }]));
+        assert!(alice.get_thumbnail("resent").await.unwrap().is_none());
+        
+        bob.set_avatar(Some((b"avatar image", "image/jpeg"))).await.unwrap();
+        assert_eq!(bob.get_profile().await.unwrap().unwrap().avatar.as_deref(), Some(&b"avat"[..]));
+        assert!(alice.set_avatar(Some((b"avatar image", "image/jpeg"))).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_push_wakes_unreachable_contact() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(Some("laptop".to_string()));
+        chat.create_account(temp_dir.path().join("test.db"), "password", "User").await.unwrap();
+        let contact = chat.add_contact([9u8; 32], "Bob").await.unwrap();
+        
+        let endpoint = chat.register_push_endpoint(chat.device_id(), "https://push.example.org/up/laptop").await.unwrap();
+        assert_eq!(chat.get_push_endpoints().await.unwrap(), [endpoint]);
+        assert!(chat.unregister_push_endpoint("laptop").await.unwrap());
+        assert!(!chat.unregister_push_endpoint("laptop").await.unwrap());
+        
+        let bobs = vec![
+            push::PushEndpoint::new("phone", "https://push.example.org/up/phone").unwrap(),
+            push::PushEndpoint::new("tablet", "https://push.example.org/up/tablet").unwrap(),
+        ];
+        chat.set_contact_push_endpoints(&contact.id, bobs).await.unwrap();
+        assert!(chat.set_contact_push_endpoints("nobody", Vec::new()).await.is_err());
+        // No bridge set, nothing to send through
+        assert_eq!(chat.wake_contact(&contact.id).await.unwrap(), 0);
+        
+        let bridge = Arc::new(push::MemoryPushBridge::default());
+        chat.set_push_bridge(bridge.clone()).await;
+        assert_eq!(chat.wake_contact(&contact.id).await.unwrap(), 2);
+        // Too soon for another round
+        assert_eq!(chat.wake_contact(&contact.id).await.unwrap(), 0);
+        let pings = bridge.pings();
+        assert_eq!(pings.len(), 2);
+        assert_eq!(pings[0].0, "https://push.example.org/up/phone");
+        assert_eq!(pings[0].1.len(), push::WAKE_PING_LEN);
+        assert_ne!(pings[0].1, pings[1].1);
+    }
+    
+    #[tokio::test]
+    async fn test_cancel_and_resend() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut chats = Vec::new();
+        for name in ["Alice", "Bob"] {
             let chat = SecureChat::new(None);
-            chat.create_account(&db_path, "password123", "Test User").await.unwrap();
+            chat.create_account(temp_dir.path().join(format!("{}.db", name)), "password", name).await.unwrap();
+            chats.push(chat);
         }
+        let [alice, bob] = chats.try_into().ok().unwrap();
+        let link = alice.create_invite(std::time::Duration::from_secs(3600), false).await.unwrap().to_link().unwrap();
+        let contact = bob.accept_invite(&link).await.unwrap();
+        let conversation = bob.get_or_create_conversation(&contact.id).await.unwrap();
+        let status = |message_id: String| {
+            let bob = bob.clone();
+            async move { bob.storage.read().await.as_ref().unwrap().find_message(&message_id).unwrap().unwrap().status }
+        };
+        let outbox = || async { bob.storage.read().await.as_ref().unwrap().take_outbox().unwrap() };
         
-        // Unlock should work now that the database is closed
-        {
-            let chat2 = SecureChat::new(None);
-            chat2.unlock_account(&db_path, "password123").await.unwrap();
+        // The network is down, so the message waits in the outbox
+        let message_id = bob.send_text_message(&conversation.id, "hello").await.unwrap();
+        assert!(bob.resend_message(&message_id).await.is_err());
+        bob.cancel_pending_message(&message_id).await.unwrap();
+        assert!(outbox().await.is_empty());
+        assert_eq!(status(message_id.clone()).await, DeliveryStatus::Failed { reason: "Cancelled".to_string() });
+        assert!(bob.cancel_pending_message(&message_id).await.is_err());
+        
+        // Resent under a new envelope id that Alice accepts
+        bob.resend_message(&message_id).await.unwrap();
+        assert_eq!(status(message_id.clone()).await, DeliveryStatus::Queued);
+        let queued = outbox().await;
+        let Ok(ProtocolMessage::Encrypted { envelope }) = ProtocolMessage::decode(&queued[0]) else { panic!("expected an envelope") };
+        assert_ne!(envelope.id, message_id);
+        let events = alice.receive_envelope(envelope.clone()).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::MessageReceived { message, .. }] if message.preview_text() == "hello"));
+        
+        // Her receipt names the new envelope but updates the original
+        let events = bob.set_delivery_status(&envelope.id, DeliveryStatus::Delivered).await.unwrap();
+        assert!(matches!(&events[..], [ChatEvent::MessageStatusChanged { message_id: id, .. }] if *id == message_id));
+        assert_eq!(status(message_id.clone()).await, DeliveryStatus::Delivered);
+        assert!(bob.resend_message(&message_id).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_network_conditions_defer_and_release() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "Alice").await.unwrap();
+        let chunk = || ProtocolMessage::AttachmentChunk { transfer_id: [0; 32], index: 0, ciphertext: Vec::new() };
+        
+        chat.set_network_conditions(conditions::NetworkConditions { metered: true, ..Default::default() }).await.unwrap();
+        assert!(!chat.send_protocol_message(chunk()).await.unwrap());
+        assert!(!chat.send_protocol_message(ProtocolMessage::Presence { online: true }).await.unwrap());
+        assert_eq!(chat.deferred_message_count().await, 1);
+        
+        chat.set_network_conditions(conditions::NetworkConditions { offline: true, ..Default::default() }).await.unwrap();
+        chat.send_protocol_message(ProtocolMessage::Presence { online: true }).await.unwrap();
+        chat.send_protocol_message(ProtocolMessage::DeliveryReceipt { message_id: "m".to_string(), timestamp: OffsetDateTime::now_utc() }).await.unwrap();
+        assert_eq!(chat.deferred_message_count().await, 2);
+        
+        // Queued work resumes; nothing counts as sent without a network
+        chat.set_network_conditions(conditions::NetworkConditions::default()).await.unwrap();
+        assert_eq!(chat.deferred_message_count().await, 0);
+        
+        // Delivered once the network is up
+        let (tx, mut rx) = futures_mpsc::channel(8);
+        *chat.network_cmd_tx.write().await = Some(tx);
+        chat.set_network_conditions(conditions::NetworkConditions { low_power: true, ..Default::default() }).await.unwrap();
+        chat.send_protocol_message(chunk()).await.unwrap();
+        assert_eq!(chat.set_network_conditions(conditions::NetworkConditions::default()).await.unwrap(), 1);
+        assert!(matches!(rx.try_recv(), Ok(NetworkCommand::SendMessage { message: ProtocolMessage::AttachmentChunk { .. }, .. })));
+        assert!(matches!(rx.try_recv(), Ok(NetworkCommand::SendMessage { message: ProtocolMessage::Presence { online: true }, .. })));
+    }
+    
+    #[tokio::test]
+    async fn test_network_restart_keeps_unsent_messages() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "Alice").await.unwrap();
+        let config = || NetworkConfig {
+            listen: listen::ListenConfig { bind: "127.0.0.1".parse().unwrap(), quic_port: None, ..Default::default() },
+            enable_mdns: false,
+            ..Default::default()
+        };
+        let outbox = |chat: &SecureChat| {
+            let chat = chat.clone();
+            async move { chat.storage.read().await.as_ref().unwrap().take_outbox().unwrap() }
+        };
+        
+        chat.start_network(config()).await.unwrap();
+        assert!(chat.start_network(config()).await.is_err());
+        let receipt = ProtocolMessage::DeliveryReceipt { message_id: "m".to_string(), timestamp: OffsetDateTime::now_utc() };
+        assert!(chat.send_protocol_message(receipt).await.unwrap());
+        
+        // Nobody was listening, so the receipt is kept; presence is not
+        chat.stop_network().await.unwrap();
+        let kept = outbox(&chat).await;
+        assert!(matches!(&kept[..], [data] if matches!(ProtocolMessage::decode(data), Ok(ProtocolMessage::DeliveryReceipt { .. }))));
+        
+        // Sends while stopped wait in the outbox too, and go out on restart
+        assert!(!chat.send_protocol_message(ProtocolMessage::decode(&kept[0]).unwrap()).await.unwrap());
+        chat.start_network(config()).await.unwrap();
+        assert!(outbox(&chat).await.is_empty());
+        chat.stop_network().await.unwrap();
+        assert_eq!(outbox(&chat).await.len(), 1);
+        chat.stop_network().await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_peer_liveness_and_redial() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut chats = Vec::new();
+        for name in ["Alice", "Bob"] {
+            let chat = SecureChat::new(None);
+            chat.create_account(temp_dir.path().join(format!("{}.db", name)), "password", name).await.unwrap();
+            chats.push(chat);
         }
+        let [alice, bob] = chats.try_into().ok().unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = format!("/ip4/127.0.0.1/tcp/{}", port);
         
-        // Wrong password should fail
-        let chat3 = SecureChat::new(None);
-        assert!(chat3.unlock_account(&db_path, "wrong_password").await.is_err());
+        alice.start_network(NetworkConfig {
+            listen: listen::ListenConfig { bind: "127.0.0.1".parse().unwrap(), tcp_port: port, quic_port: None, ..Default::default() },
+            enable_mdns: false,
+            ..Default::default()
+        }).await.unwrap();
+        // Stored bootstrap nodes replace the built-in ones
+        bob.add_bootstrap_node(&addr).await.unwrap();
+        let mut events = bob.start_network(NetworkConfig {
+            listen: listen::ListenConfig { bind: "127.0.0.1".parse().unwrap(), quic_port: None, ..Default::default() },
+            enable_mdns: false,
+            ..Default::default()
+        }).await.unwrap();
+        
+        let wait = std::time::Duration::from_secs(10);
+        let alice_peer = match tokio::time::timeout(wait, events.recv()).await.unwrap() {
+            Some(ChatEvent::ContactOnline { contact_id }) => contact_id,
+            other => panic!("Expected Alice online, got {:?}", other),
+        };
+        let known = bob.storage.read().await.as_ref().unwrap().get_known_peers(10).unwrap();
+        assert_eq!(known.len(), 1);
+        assert_eq!((known[0].peer_id.as_str(), known[0].addresses.as_slice()), (alice_peer.as_str(), [addr].as_slice()));
+        
+        // Pings measure latency, identify names the client
+        let stats = loop {
+            let stats = bob.get_peer_stats().await.unwrap();
+            if stats.iter().any(|s| s.rtt.is_some() && s.agent_version.is_some()) {
+                break stats;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        };
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].peer_id, alice_peer);
+        assert!(stats[0].connected);
+        assert!(stats[0].agent_version.as_ref().unwrap().starts_with("securechat/"));
+        let nodes = bob.get_bootstrap_nodes().await.unwrap();
+        assert!(matches!(&nodes[..], [node] if node.rtt.is_some() && node.failures == 0));
+        
+        // A dropped connection is transient while Bob redials
+        alice.stop_network().await.unwrap();
+        loop {
+            match tokio::time::timeout(wait, events.recv()).await.unwrap() {
+                Some(ChatEvent::ContactOffline { contact_id }) => {
+                    assert_eq!(contact_id, alice_peer);
+                    break;
+                }
+                Some(ChatEvent::ContactUnreachable { .. }) | None => panic!("Alice should be redialed"),
+                Some(_) => {}
+            }
+        }
+        bob.stop_network().await.unwrap();
     }
     
     #[tokio::test]
-    async fn test_contacts_and_conversations() {
+    async fn test_network_status_follows_reachability() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("test.db"), "password", "Alice").await.unwrap();
+        assert_eq!(chat.get_network_status().await, network::NetworkStatus::default());
+        
+        let (mut net_tx, net_rx) = futures_mpsc::channel(8);
+        let mut chat_rx = chat.events.attach_listener(1);
+        tokio::spawn(chat.clone().network_event_loop(net_rx));
+        
+        net_tx.send(NetworkEvent::ReachabilityChanged { reachability: network::Reachability::Private }).await.unwrap();
+        net_tx.send(NetworkEvent::RelayReserved { relay_peer_id: "relay".to_string() }).await.unwrap();
+        chat_rx.recv().await.unwrap();
+        let Some(ChatEvent::NetworkStatusChanged { status }) = chat_rx.recv().await else { panic!() };
+        assert_eq!(status.reachability, network::Reachability::Private);
+        assert_eq!(status.relays, vec!["relay".to_string()]);
+        assert_eq!(chat.get_network_status().await, status);
+        
+        // Reachable again: the relays are given up
+        let address = "/ip4/203.0.113.5/tcp/4001".to_string();
+        net_tx.send(NetworkEvent::ReachabilityChanged { reachability: network::Reachability::Public { address: address.clone() } }).await.unwrap();
+        let Some(ChatEvent::NetworkStatusChanged { status }) = chat_rx.recv().await else { panic!() };
+        assert_eq!(status.reachability, network::Reachability::Public { address });
+        assert!(status.relays.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_pairing_adds_verified_contacts() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut chats = Vec::new();
+        for name in ["Alice", "Bob", "Carol"] {
+            let chat = SecureChat::new(None);
+            chat.create_account(temp_dir.path().join(format!("{}.db", name)), "password", name).await.unwrap();
+            chats.push(chat);
+        }
+        let [alice, bob, carol] = chats.try_into().ok().unwrap();
+        
+        let code = alice.start_pairing().await.unwrap();
+        let start = bob.join_pairing(&code).await.unwrap();
+        // Carol isn't pairing and ignores the exchange
+        assert!(carol.handle_pairing_message(&start).await.unwrap().reply.is_none());
+        let reply = alice.handle_pairing_message(&start).await.unwrap().reply.unwrap();
+        let step = bob.handle_pairing_message(&reply).await.unwrap();
+        let bobs = step.contact.unwrap();
+        let alices = alice.handle_pairing_message(&step.reply.unwrap()).await.unwrap().contact.unwrap();
+        
+        assert_eq!((bobs.display_name.as_str(), bobs.verified), ("Alice", true));
+        assert_eq!(bobs.public_key, alice.get_public_key().await.unwrap());
+        assert_eq!((alices.display_name.as_str(), alices.verified), ("Bob", true));
+        assert_eq!(alices.public_key, bob.get_public_key().await.unwrap());
+        
+        // A wrong code fails and uses up the session
+        let code = alice.start_pairing().await.unwrap();
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        let start = carol.join_pairing(wrong).await.unwrap();
+        let reply = alice.handle_pairing_message(&start).await.unwrap().reply.unwrap();
+        assert!(carol.handle_pairing_message(&reply).await.is_err());
+        assert!(carol.handle_pairing_message(&reply).await.unwrap().contact.is_none());
+        // Alice answers only the first start, so there's no second guess
+        let (_, retry) = pairing::PairingSession::join(&code).unwrap();
+        assert!(alice.handle_pairing_message(&retry).await.unwrap().reply.is_none());
+        assert_eq!(alice.get_contacts().await.unwrap().len(), 1);
+        assert!(carol.get_contacts().await.unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_backup_restores_messages_and_identity() {
         let temp_dir = TempDir::new().unwrap();
-        let db_path = temp_dir.path().join("test.db");
         
         let chat = SecureChat::new(None);
-        chat.create_account(&db_path, "password", "User").await.unwrap();
+        chat.create_account(temp_dir.path().join("a.db"), "password", "User").await.unwrap();
+        let contact = chat.add_contact([5u8; 32], "Carol").await.unwrap();
+        let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+        chat.send_text_message(&conversation.id, "kept in backup").await.unwrap();
         
-        // Add contact
-        let public_key = [1u8; 32];
-        let contact = chat.add_contact(public_key, "Alice").await.unwrap();
+        let backup = chat.export_backup("backup-pw").await.unwrap();
         
-        // Get conversation
+        let restored = SecureChat::new(None);
+        restored.restore_backup(backup.as_slice(), "backup-pw", temp_dir.path().join("b.db"), "new-pw")
+            .await
+            .unwrap();
+        
+        assert_eq!(restored.get_public_key().await.unwrap(), chat.get_public_key().await.unwrap());
+        let messages = restored.get_messages(&conversation.id, 10).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].preview_text(), "kept in backup");
+    }
+    
+    #[tokio::test]
+    async fn test_background_backup_and_reindex() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("a.db"), "password", "User").await.unwrap();
+        let contact = chat.add_contact([5u8; 32], "Carol").await.unwrap();
         let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+        chat.send_text_message(&conversation.id, "kept in backup").await.unwrap();
         
-        // Verify
-        let conversations = chat.get_conversations().await.unwrap();
-        assert_eq!(conversations.len(), 1);
+        let path = temp_dir.path().join("backup.scb");
+        let export = chat.start_backup_export(path.clone(), "backup-pw").await.unwrap();
+        let id = export.id();
+        let size = export.wait().await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        assert!(!partial_path(&path).exists());
+        let last = chat.replay_events(1).events.into_iter()
+            .rev()
+            .find_map(|sequenced| match sequenced.event {
+                ChatEvent::OperationProgress { progress } if progress.id == id => Some(progress),
+                _ => None,
+            })
+            .unwrap();
+        assert!(last.finished);
+        assert_eq!(last.total, Some(last.done));
         
-        let contacts = chat.get_contacts().await.unwrap();
-        assert_eq!(contacts.len(), 1);
+        // Cancelled before it starts, a restore leaves no database behind
+        let restored = SecureChat::new(None);
+        let db_path = temp_dir.path().join("b.db");
+        let restore = restored.start_backup_restore(path.clone(), "backup-pw", db_path.clone(), "new-pw").await.unwrap();
+        restore.cancel();
+        assert!(cancel::is_cancelled(&restore.wait().await.unwrap_err()));
+        assert!(!db_path.exists());
+        
+        let restore = restored.start_backup_restore(path, "backup-pw", db_path, "new-pw").await.unwrap();
+        restore.wait().await.unwrap();
+        assert_eq!(restored.get_messages(&conversation.id, 10).await.unwrap().len(), 1);
+        
+        restored.start_reindex().await.unwrap().wait().await.unwrap();
+        assert_eq!(restored.get_messages(&conversation.id, 10).await.unwrap()[0].preview_text(), "kept in backup");
+    }
+    
+    /// Fails the first `failures` backups, then keeps them in a directory
+    struct FlakyStore {
+        directory: backup_sink::DirectorySink,
+        failures: std::sync::atomic::AtomicU32,
+    }
+    
+    impl BackupSink for FlakyStore {
+        fn name(&self) -> String {
+            "flaky".to_string()
+        }
+        
+        fn create(&self, name: &str) -> Result<Box<dyn backup_sink::BackupUpload>> {
+            use std::sync::atomic::Ordering;
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err(anyhow::anyhow!("Server unavailable"));
+            }
+            self.directory.create(name)
+        }
+        
+        fn remove(&self, name: &str) -> Result<()> {
+            self.directory.remove(name)
+        }
+    }
+    
+    impl BackupSource for FlakyStore {
+        fn list(&self) -> Result<Vec<String>> {
+            self.directory.list()
+        }
+        
+        fn read(&self, name: &str) -> Result<Box<dyn std::io::Read + Send>> {
+            self.directory.read(name)
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_scheduled_backup_retries() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("a.db"), "password", "User").await.unwrap();
+        let store = Arc::new(FlakyStore {
+            directory: backup_sink::DirectorySink::new(temp_dir.path().join("backups")),
+            failures: std::sync::atomic::AtomicU32::new(1),
+        });
+        let mut schedule = backup::BackupSchedule::in_directory(std::time::Duration::from_secs(3600), temp_dir.path(), 1);
+        schedule.store = store.clone();
+        chat.start_backup_scheduler(schedule).await.unwrap();
+        
+        let completed = |chat: &SecureChat| chat.replay_events(1).events.iter()
+            .any(|sequenced| matches!(sequenced.event, ChatEvent::BackupCompleted { .. }));
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            while !completed(&chat) {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        }).await.unwrap();
+        chat.stop_backup_scheduler().await;
+        
+        // The failed first attempt was retried rather than reported
+        assert!(!chat.replay_events(1).events.iter()
+            .any(|sequenced| matches!(sequenced.event, ChatEvent::BackupFailed { .. })));
+        assert_eq!(store.list().unwrap().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_partial_restore_from_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("a.db"), "password", "User").await.unwrap();
+        let carol = chat.add_contact([5u8; 32], "Carol").await.unwrap();
+        let dave = chat.add_contact([6u8; 32], "Dave").await.unwrap();
+        let with_carol = chat.get_or_create_conversation(&carol.id).await.unwrap();
+        let with_dave = chat.get_or_create_conversation(&dave.id).await.unwrap();
+        chat.send_text_message(&with_carol.id, "first").await.unwrap();
+        chat.send_text_message(&with_carol.id, "second").await.unwrap();
+        chat.send_text_message(&with_dave.id, "hi").await.unwrap();
+        
+        let path = temp_dir.path().join("backup.scbk");
+        chat.start_backup_export(path.clone(), "backup-pw").await.unwrap().wait().await.unwrap();
+        let manifest = chat.inspect_backup(&path, "backup-pw").await.unwrap();
+        assert_eq!(manifest.conversations[&with_carol.id].records, 2);
+        assert_eq!(manifest.conversations[&with_dave.id].records, 1);
+        assert!(chat.verify_backup(&path, "backup-pw").await.unwrap().is_empty());
+        assert!(chat.inspect_backup(&path, "wrong").await.is_err());
+        
+        chat.delete_contact(&carol.id, true).await.unwrap();
+        assert!(chat.get_messages(&with_carol.id, 10).await.unwrap().is_empty());
+        
+        let selection = backup::RestoreSelection::Conversation { conversation_id: with_carol.id.clone() };
+        // The conversation, its contact and two messages
+        assert!(chat.restore_backup_partial(&path, "backup-pw", &selection).await.unwrap() >= 4);
+        let messages = chat.get_messages(&with_carol.id, 10).await.unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].preview_text(), "second");
+        assert!(chat.get_contacts().await.unwrap().iter().any(|contact| contact.id == carol.id));
+        
+        // Nothing is left to add, and the other conversation was untouched
+        assert_eq!(chat.restore_backup_partial(&path, "backup-pw", &selection).await.unwrap(), 0);
+        assert_eq!(chat.get_messages(&with_dave.id, 10).await.unwrap().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_conversation_stats_follow_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("a.db"), "password", "User").await.unwrap();
+        let contact = chat.add_contact([5u8; 32], "Carol").await.unwrap();
+        let conversation = chat.get_or_create_conversation(&contact.id).await.unwrap();
+        let storage = chat.storage().await.unwrap();
+        let first = chat.send_text_message(&conversation.id, "one").await.unwrap();
+        let first = storage.get_message(&conversation.id, &first).unwrap().unwrap();
+        
+        let stats = chat.get_conversation_stats(&conversation.id).await.unwrap();
+        assert_eq!(stats.messages.sent, 1);
+        assert_eq!(stats.first_activity, Some(first.timestamp));
+        
+        // Added to the kept stats
+        let second = chat.send_text_message(&conversation.id, "two").await.unwrap();
+        let second = storage.get_message(&conversation.id, &second).unwrap().unwrap();
+        let stats = chat.get_conversation_stats(&conversation.id).await.unwrap();
+        assert_eq!(stats.messages.sent, 2);
+        assert_eq!(stats.by_kind[&stats::MessageKind::Text].sent, 2);
+        assert_eq!(stats.last_activity, Some(second.timestamp));
+        
+        // Computed again after a deletion
+        storage.delete_message(&conversation.id, &first.id).unwrap();
+        let stats = chat.get_conversation_stats(&conversation.id).await.unwrap();
+        assert_eq!(stats.messages.sent, 1);
+        assert_eq!(stats.first_activity, Some(second.timestamp));
+        
+        assert!(chat.get_conversation_stats("missing").await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_jump_to_date_and_counts_by_day() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("a.db"), "password", "User").await.unwrap();
+        let carol = chat.add_contact([5u8; 32], "Carol").await.unwrap();
+        let dave = chat.add_contact([6u8; 32], "Dave").await.unwrap();
+        let with_carol = chat.get_or_create_conversation(&carol.id).await.unwrap();
+        let with_dave = chat.get_or_create_conversation(&dave.id).await.unwrap();
+        let storage = chat.storage().await.unwrap();
+        let day = time::Date::from_calendar_date(2024, time::Month::March, 10).unwrap();
+        let at = |day: time::Date, hour: u8| day.with_hms(hour, 0, 0).unwrap().assume_utc();
+        let message = |conversation_id: &str, id: &str, timestamp: OffsetDateTime| LocalMessage {
+            id: id.to_string(),
+            conversation_id: conversation_id.to_string(),
+            sender_id: carol.id.clone(),
+            is_outgoing: false,
+            content: MessageContent::Text { text: id.to_string() },
+            timestamp,
+            status: DeliveryStatus::Delivered,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport: 0,
+        };
+        storage.store_message(&message(&with_carol.id, "morning", at(day, 9))).unwrap();
+        storage.store_message(&message(&with_carol.id, "evening", at(day, 20))).unwrap();
+        storage.store_message(&message(&with_dave.id, "dave", at(day, 21))).unwrap();
+        let later = day + time::Duration::days(3);
+        storage.store_message(&message(&with_carol.id, "later", at(later, 8))).unwrap();
+        
+        let counts = chat.get_message_count_by_day(&with_carol.id).await.unwrap();
+        assert_eq!(counts, [stats::DayCount { date: day, messages: 2 }, stats::DayCount { date: later, messages: 1 }]);
+        
+        let jump = |date: OffsetDateTime| chat.get_message_at_date(&with_carol.id, date);
+        assert_eq!(jump(at(day, 0)).await.unwrap().unwrap().id, "morning");
+        assert_eq!(jump(at(day, 10)).await.unwrap().unwrap().id, "evening");
+        // Past the day's last message, on to the next day with any; Dave's
+        // message in between isn't Carol's
+        assert_eq!(jump(at(day, 20) + time::Duration::minutes(1)).await.unwrap().unwrap().id, "later");
+        assert!(jump(at(later, 9)).await.unwrap().is_none());
+        
+        // Moved to another day and deleted, the counts follow
+        storage.store_message(&message(&with_carol.id, "evening", at(later, 7))).unwrap();
+        storage.delete_message(&with_carol.id, "later").unwrap();
+        let counts = chat.get_message_count_by_day(&with_carol.id).await.unwrap();
+        assert_eq!(counts, [stats::DayCount { date: day, messages: 1 }, stats::DayCount { date: later, messages: 1 }]);
+        assert_eq!(jump(at(day, 10)).await.unwrap().unwrap().id, "evening");
+        storage.rebuild_indexes().unwrap();
+        assert_eq!(chat.get_message_count_by_day(&with_carol.id).await.unwrap(), counts);
+        assert!(chat.get_message_count_by_day("missing").await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_search_across_contacts_notes_and_messages() {
+        let temp_dir = TempDir::new().unwrap();
+        let chat = SecureChat::new(None);
+        chat.create_account(temp_dir.path().join("a.db"), "password", "User").await.unwrap();
+        let alice = chat.add_contact([1u8; 32], "Alice Parker").await.unwrap();
+        let bob = chat.add_contact([2u8; 32], "Bob").await.unwrap();
+        chat.set_contact_nickname(&bob.id, Some("Robert")).await.unwrap();
+        chat.set_contact_note(&bob.id, "Met him at parkrun").await.unwrap();
+        let to_alice = chat.get_or_create_conversation(&alice.id).await.unwrap();
+        chat.send_text_message(&to_alice.id, "Shall we meet in the park?").await.unwrap();
+        let parking = chat.send_text_message(&to_alice.id, "Parking is free").await.unwrap();
+        
+        let kinds = |results: &[search::SearchResult]| results.iter()
+            .map(|result| match &result.hit {
+                search::SearchHit::Contact { name, .. } => format!("contact {}", name),
+                search::SearchHit::Conversation { title, .. } => format!("conversation {}", title),
+                search::SearchHit::Note { preview, .. } => format!("note {}", preview),
+                search::SearchHit::Message { preview, .. } => format!("message {}", preview),
+            })
+            .collect::<Vec<_>>();
+        // As typed: the message starting with it first, then by kind
+        let results = chat.search("par", 10).await.unwrap();
+        assert_eq!(kinds(&results), [
+            "message Parking is free",
+            "contact Alice Parker",
+            "conversation Alice Parker",
+            "note Met him at parkrun",
+            "message Shall we meet in the park?",
+        ]);
+        let results = chat.search("Sha PARK", 10).await.unwrap();
+        assert_eq!(kinds(&results), ["message Shall we meet in the park?"]);
+        if let search::SearchHit::Message { title, .. } = &results[0].hit {
+            assert_eq!(title, "Alice Parker");
+        }
+        assert_eq!(kinds(&chat.search("rob", 10).await.unwrap()), ["contact Robert"]);
+        assert!(chat.search("lice", 10).await.unwrap().is_empty());
+        assert!(chat.search("  ", 10).await.unwrap().is_empty());
+        assert!(chat.search("par", 0).await.is_err());
+        
+        // The index follows deletions, and is built again with the others
+        let storage = chat.storage().await.unwrap();
+        storage.delete_message(&to_alice.id, &parking).unwrap();
+        assert!(chat.search("parking", 10).await.unwrap().is_empty());
+        storage.rebuild_indexes().unwrap();
+        assert_eq!(kinds(&chat.search("park meet", 10).await.unwrap()), ["message Shall we meet in the park?"]);
+    }
+    
+    #[tokio::test]
+    async fn test_backup_key_offer_opens_account_backups() {
+        let temp_dir = TempDir::new().unwrap();
+        let desktop = SecureChat::new(None);
+        desktop.create_account(temp_dir.path().join("desktop.db"), "password", "User").await.unwrap();
+        let backup = desktop.export_backup("backup-pw").await.unwrap();
+        let phone = SecureChat::new(None);
+        phone.restore_backup(backup.as_slice(), "backup-pw", temp_dir.path().join("phone.db"), "phone-pw")
+            .await
+            .unwrap();
+        
+        // A scheduled backup made on the desktop takes its password
+        let schedule = backup::BackupSchedule::in_directory(std::time::Duration::from_secs(3600), temp_dir.path().join("backups"), 5);
+        let written = backup::run_scheduled_backup(&desktop.storage().await.unwrap(), &schedule, &mut None, &cancel::CancellationToken::new())
+            .unwrap();
+        let backup::ScheduledBackup::Written { location, .. } = written else {
+            panic!("Nothing was backed up");
+        };
+        let path = PathBuf::from(location);
+        assert!(phone.inspect_backup(&path, "phone-pw").await.is_err());
+        
+        let (offer, code) = desktop.offer_backup_key(escrow::DEFAULT_VALIDITY).await.unwrap();
+        let link = offer.to_link().unwrap();
+        assert!(desktop.accept_backup_key(&link, &code).await.is_err());
+        assert!(phone.accept_backup_key(&link, "23456-789AB").await.is_err());
+        let info = phone.accept_backup_key(&link, &code).await.unwrap();
+        assert_eq!(phone.get_escrowed_backup_keys().await.unwrap(), std::slice::from_ref(&info));
+        
+        let manifest = phone.inspect_backup(&path, "").await.unwrap();
+        assert!(manifest.records > 0);
+        assert!(phone.verify_backup(&path, "").await.unwrap().is_empty());
+        
+        // Only devices of the same account take it
+        let stranger = SecureChat::new(None);
+        stranger.create_account(temp_dir.path().join("stranger.db"), "password", "Eve").await.unwrap();
+        assert!(stranger.accept_backup_key(&link, &code).await.is_err());
+        
+        phone.remove_escrowed_backup_key(&info.key_id).await.unwrap();
+        assert!(phone.inspect_backup(&path, "").await.is_err());
     }
 }
@@ -0,0 +1,42 @@
+//! Suspending and resuming with the host app.
+//!
+//! Mobile and desktop systems suspend apps without warning them much.
+//! `SecureChat::on_suspend` gets ready for that: storage is flushed,
+//! periodic backups, pruning and sweeps skip their runs, contacts are told
+//! we went offline, and with `park_network` the network is stopped
+//! altogether, its unsent messages kept in the outbox. `on_resume` undoes
+//! it: a parked network is started again with the same settings, feeding
+//! the same event channel, and a running one redials recently seen peers
+//! whose connections may not have survived the suspension.
+
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+
+use crate::network::NetworkConfig;
+
+/// Peers redialed on resume when the network kept running
+pub const RESUME_DIALS: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SuspendOptions {
+    /// Stop the network, e.g. where the OS would kill its sockets anyway
+    pub park_network: bool,
+}
+
+/// What `on_resume` found
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeReport {
+    /// How long the app was suspended
+    pub suspended_for: Duration,
+    /// The network was parked and has been started again
+    pub network_restarted: bool,
+    /// Addresses redialed on a network that kept running
+    pub redialed: usize,
+}
+
+pub(crate) struct Suspension {
+    pub(crate) since: Instant,
+    /// Settings of the network parked at suspension
+    pub(crate) parked: Option<NetworkConfig>,
+}
@@ -0,0 +1,112 @@
+//! Bounds on what a peer can make us decode.
+//!
+//! Everything from the network is checked against `ProtocolLimits` as it
+//! is decoded: frames before parsing, then the fields of the message, and
+//! the content of an envelope once it decrypts. Anything out of bounds is
+//! rejected with a `LimitError` before it is stored or acted on, so a peer
+//! can't announce a 2 GB image or a key bundle with a million prekeys.
+//! `SecureChat::set_protocol_limits` tunes them.
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::crypto::DoubleRatchet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolLimits {
+    /// Largest frame accepted from the network, in bytes
+    pub max_frame_len: usize,
+    /// Longest text field, in bytes: message text, captions, file names,
+    /// display names and contact request messages
+    pub max_text_len: usize,
+    /// Most bytes carried inside a message rather than as an attachment,
+    /// such as a voice message's waveform
+    pub max_inline_bytes: usize,
+    /// Largest attachment a message may announce, in bytes
+    pub max_attachment_size: u64,
+    /// Most one-time prekeys in a key bundle
+    pub max_prekeys: usize,
+    /// Most message keys one ratchet step may skip over
+    pub max_skipped_keys: u32,
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_len: crate::protocol::MAX_FRAME_LEN,
+            max_text_len: 64 * 1024,
+            max_inline_bytes: 16 * 1024,
+            max_attachment_size: 100 * 1024 * 1024,
+            max_prekeys: 100,
+            max_skipped_keys: DoubleRatchet::MAX_SKIP,
+        }
+    }
+}
+
+impl ProtocolLimits {
+    /// Frames must still fit an attachment chunk and a thumbnail, and a
+    /// bundle at least one prekey
+    pub fn validate(&self) -> Result<()> {
+        let smallest_frame = crate::protocol::ATTACHMENT_CHUNK_SIZE + crate::thumbnail::MAX_SEALED_THUMBNAIL_LEN;
+        if self.max_frame_len < smallest_frame {
+            return Err(anyhow::anyhow!("Frames must be allowed at least {} bytes", smallest_frame));
+        }
+        if self.max_text_len == 0 || self.max_prekeys == 0 {
+            return Err(anyhow::anyhow!("Text and prekey limits must be above zero"));
+        }
+        Ok(())
+    }
+
+    pub fn check_frame(&self, len: usize) -> Result<(), LimitError> {
+        if len > self.max_frame_len {
+            return Err(LimitError::FrameTooLarge { len, max: self.max_frame_len });
+        }
+        Ok(())
+    }
+
+    pub fn check_text(&self, what: &'static str, text: &str) -> Result<(), LimitError> {
+        check_len(what, text.len(), self.max_text_len)
+    }
+
+    pub fn check_inline(&self, what: &'static str, len: usize) -> Result<(), LimitError> {
+        check_len(what, len, self.max_inline_bytes)
+    }
+
+    pub fn check_attachment(&self, size: u64) -> Result<(), LimitError> {
+        if size > self.max_attachment_size {
+            return Err(LimitError::AttachmentTooLarge { size, max: self.max_attachment_size });
+        }
+        Ok(())
+    }
+
+    pub fn check_prekeys(&self, count: usize) -> Result<(), LimitError> {
+        if count > self.max_prekeys {
+            return Err(LimitError::TooManyPrekeys { count, max: self.max_prekeys });
+        }
+        Ok(())
+    }
+}
+
+/// A field over a fixed bound, such as the length of an id
+pub fn check_len(what: &'static str, len: usize, max: usize) -> Result<(), LimitError> {
+    if len > max {
+        return Err(LimitError::TooLong { what, len, max });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LimitError {
+    #[error("Frame of {len} bytes exceeds the {max} byte limit")]
+    FrameTooLarge { len: usize, max: usize },
+    #[error("Too long {what}: {len} > {max}")]
+    TooLong { what: &'static str, len: usize, max: usize },
+    #[error("Attachment of {size} bytes exceeds the {max} byte limit")]
+    AttachmentTooLarge { size: u64, max: u64 },
+    #[error("Too many one-time prekeys: {count} > {max}")]
+    TooManyPrekeys { count: usize, max: usize },
+    #[error("Too many skipped messages: {count} > {max}")]
+    TooManySkipped { count: u32, max: u32 },
+    #[error("Too many ratchet steps skipped: {count} > {max}")]
+    TooManyChains { count: u32, max: u32 },
+}
@@ -0,0 +1,243 @@
+//! Listen addresses and what we tell peers about them.
+//!
+//! By default the network binds every interface on ephemeral ports. A
+//! `ListenConfig` can pin the ports, bind one interface, name external
+//! addresses for port-forwarded setups, and keep private (LAN, loopback,
+//! link-local) addresses from being advertised to peers.
+
+use std::net::{IpAddr, Ipv4Addr, TcpListener, UdpSocket};
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+use libp2p::core::{transport::PortUse, Endpoint, Multiaddr};
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{
+    ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
+    THandlerOutEvent, ToSwarm,
+};
+use libp2p::PeerId;
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListenConfig {
+    /// Interface to bind; the unspecified address binds all of them
+    pub bind: IpAddr,
+    /// 0 picks a free port
+    pub tcp_port: u16,
+    /// UDP port for QUIC, 0 picks a free one; `None` disables QUIC
+    pub quic_port: Option<u16>,
+    /// Addresses peers should dial us at, e.g. a port forwarded on the
+    /// router
+    pub external_addrs: Vec<String>,
+    /// Tell peers about private addresses too
+    pub advertise_private: bool,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            tcp_port: 0,
+            quic_port: Some(0),
+            external_addrs: Vec::new(),
+            advertise_private: true,
+        }
+    }
+}
+
+impl ListenConfig {
+    pub fn listen_addrs(&self) -> Vec<Multiaddr> {
+        let ip = match self.bind {
+            IpAddr::V4(ip) => Protocol::Ip4(ip),
+            IpAddr::V6(ip) => Protocol::Ip6(ip),
+        };
+        let mut addrs = vec![Multiaddr::empty().with(ip.clone()).with(Protocol::Tcp(self.tcp_port))];
+        if let Some(port) = self.quic_port {
+            addrs.push(Multiaddr::empty().with(ip).with(Protocol::Udp(port)).with(Protocol::QuicV1));
+        }
+        addrs
+    }
+    
+    pub fn external_addrs(&self) -> Result<Vec<Multiaddr>> {
+        self.external_addrs.iter().map(|addr| parse_external(addr)).collect()
+    }
+    
+    /// Check the interface exists and fixed ports are free, so a bad
+    /// config fails at startup with a useful message
+    pub fn validate(&self) -> Result<()> {
+        self.external_addrs()?;
+        TcpListener::bind((self.bind, 0)).map_err(|e| {
+            anyhow::anyhow!("Cannot bind to {}: {} (is it an address of this machine?)", self.bind, e)
+        })?;
+        if self.tcp_port != 0 {
+            TcpListener::bind((self.bind, self.tcp_port)).map_err(|e| {
+                anyhow::anyhow!("TCP port {} on {} is unavailable: {}", self.tcp_port, self.bind, e)
+            })?;
+        }
+        if let Some(port) = self.quic_port.filter(|port| *port != 0) {
+            UdpSocket::bind((self.bind, port)).map_err(|e| {
+                anyhow::anyhow!("UDP port {} on {} is unavailable for QUIC: {}", port, self.bind, e)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_external(addr: &str) -> Result<Multiaddr> {
+    let parsed: Multiaddr = addr.parse()
+        .map_err(|e| anyhow::anyhow!("Invalid external address '{}': {}", addr, e))?;
+    if !matches!(parsed.iter().next(), Some(Protocol::Ip4(_) | Protocol::Ip6(_) | Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_))) {
+        return Err(anyhow::anyhow!("External address '{}' must start with /ip4, /ip6 or /dns", addr));
+    }
+    if !parsed.iter().any(|p| matches!(p, Protocol::Tcp(_) | Protocol::Udp(_))) {
+        return Err(anyhow::anyhow!("External address '{}' has no /tcp or /udp port", addr));
+    }
+    if parsed.iter().any(|p| matches!(p, Protocol::P2pCircuit | Protocol::P2p(_))) {
+        return Err(anyhow::anyhow!("External address '{}' must be our own, without /p2p", addr));
+    }
+    Ok(parsed)
+}
+
+/// Whether `addr` is only reachable from this machine or network
+pub fn is_private(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        Some(Protocol::Ip6(ip)) => {
+            let first = ip.segments()[0];
+            ip.is_loopback() || ip.is_unspecified()
+                || (first & 0xfe00) == 0xfc00 // unique local
+                || (first & 0xffc0) == 0xfe80 // link-local
+        }
+        _ => false,
+    }
+}
+
+/// Wraps a behaviour so it never learns of private listen or external
+/// addresses, and so can't advertise them
+pub struct PublicOnly<B> {
+    inner: B,
+    enabled: bool,
+}
+
+impl<B> PublicOnly<B> {
+    /// Filter only if `enabled`, otherwise pass everything through
+    pub fn new(inner: B, enabled: bool) -> Self {
+        Self { inner, enabled }
+    }
+}
+
+impl<B: NetworkBehaviour> NetworkBehaviour for PublicOnly<B> {
+    type ConnectionHandler = B::ConnectionHandler;
+    type ToSwarm = B::ToSwarm;
+    
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner.handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+    
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_inbound_connection(connection_id, peer, local_addr, remote_addr)
+    }
+    
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(connection_id, maybe_peer, addresses, effective_role)
+    }
+    
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+        port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_outbound_connection(connection_id, peer, addr, role_override, port_use)
+    }
+    
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        let hidden = match &event {
+            FromSwarm::NewListenAddr(e) => is_private(e.addr),
+            FromSwarm::ExpiredListenAddr(e) => is_private(e.addr),
+            FromSwarm::ExternalAddrConfirmed(e) => is_private(e.addr),
+            FromSwarm::ExternalAddrExpired(e) => is_private(e.addr),
+            _ => false,
+        };
+        if !(self.enabled && hidden) {
+            self.inner.on_swarm_event(event);
+        }
+    }
+    
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner.on_connection_handler_event(peer_id, connection_id, event)
+    }
+    
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        self.inner.poll(cx)
+    }
+}
+
+impl<B> std::ops::Deref for PublicOnly<B> {
+    type Target = B;
+    
+    fn deref(&self) -> &B {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_listen_config_validation() {
+        let config = ListenConfig { tcp_port: 4001, quic_port: None, ..Default::default() };
+        assert_eq!(config.listen_addrs(), vec!["/ip4/0.0.0.0/tcp/4001".parse::<Multiaddr>().unwrap()]);
+        assert_eq!(ListenConfig::default().listen_addrs().len(), 2);
+        
+        for bad in ["not an address", "/tcp/4001", "/ip4/203.0.113.5", "/ip4/203.0.113.5/tcp/4001/p2p-circuit"] {
+            let config = ListenConfig { external_addrs: vec![bad.to_string()], ..Default::default() };
+            assert!(config.validate().unwrap_err().to_string().contains(bad));
+        }
+        let config = ListenConfig { external_addrs: vec!["/dns4/chat.example.org/tcp/4001".to_string()], ..Default::default() };
+        assert!(config.validate().is_ok());
+        
+        // A port already taken is reported by number
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let config = ListenConfig { bind: "127.0.0.1".parse().unwrap(), tcp_port: port, ..Default::default() };
+        assert!(config.validate().unwrap_err().to_string().contains(&port.to_string()));
+        let config = ListenConfig { bind: "192.0.2.1".parse().unwrap(), ..Default::default() };
+        assert!(config.validate().is_err());
+    }
+    
+    #[test]
+    fn test_private_addresses() {
+        for private in ["/ip4/192.168.1.5/tcp/1", "/ip4/127.0.0.1/tcp/1", "/ip4/169.254.0.1/udp/1/quic-v1", "/ip6/fd00::1/tcp/1", "/ip6/fe80::1/tcp/1"] {
+            assert!(is_private(&private.parse().unwrap()), "{}", private);
+        }
+        for public in ["/ip4/203.0.113.5/tcp/1", "/ip6/2001:db8::1/tcp/1", "/dns4/example.org/tcp/1"] {
+            assert!(!is_private(&public.parse().unwrap()), "{}", public);
+        }
+    }
+}
@@ -0,0 +1,36 @@
+//! Key material cleanup.
+//!
+//! Secrets linger after their use: prekeys of invites that expired, keys
+//! kept for skipped messages that never arrived, sessions with devices a
+//! contact no longer has or with contacts since removed.
+//! `SecureStorage::clean_key_material` deletes them, and secret key bytes
+//! are zeroized as they are dropped from memory.
+
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+/// How often `SecureChat::start_key_maintenance` cleans up
+pub const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Skipped message keys older than this are dropped
+pub const SKIPPED_KEY_MAX_AGE: time::Duration = time::Duration::days(30);
+/// Most skipped message keys kept per conversation after a cleanup
+pub const SKIPPED_KEYS_KEPT: usize = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceReport {
+    /// Private halves of our invites' prekeys
+    pub invite_prekeys: usize,
+    /// Prekeys from contacts' invites
+    pub contact_prekeys: usize,
+    pub skipped_keys: usize,
+    pub sessions: usize,
+    /// Contact request stamps past their expiry
+    pub stamps: usize,
+}
+
+impl MaintenanceReport {
+    pub fn total(&self) -> usize {
+        self.invite_prekeys + self.contact_prekeys + self.skipped_keys + self.sessions + self.stamps
+    }
+}
@@ -0,0 +1,232 @@
+//! Matrix bridge, behind the `matrix` feature.
+//!
+//! Two SecureChat users who share a Matrix room can talk through it while
+//! they move over: frames go into the room as `org.securechat.frame`
+//! events and are read back with `/sync`. The frames hold envelopes
+//! already sealed with our own keys, so the homeserver, and Matrix's own
+//! encryption if the room has it, only ever carry ciphertext. Run it with
+//! `transport::MessageBridge`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Result, Context};
+use base64::Engine;
+use serde::{Serialize, Deserialize};
+
+use crate::transport::MessageTransport;
+
+/// Event type frames are sent as
+pub const EVENT_TYPE: &str = "org.securechat.frame";
+/// Most events one sync returns
+const SYNC_LIMIT: usize = 100;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MatrixConfig {
+    /// e.g. `https://matrix.example.org`
+    pub homeserver: String,
+    /// Our Matrix user, e.g. `@alice:example.org`; frames we sent
+    /// ourselves are skipped when reading the room
+    pub user_id: String,
+    pub access_token: String,
+    /// The room both users are in
+    pub room_id: String,
+    /// Sync token to resume from, see `MatrixTransport::sync_token`.
+    /// Without one, the room's latest events are read again; envelopes
+    /// already received are recognized and dropped by the core.
+    pub since: Option<String>,
+}
+
+impl std::fmt::Debug for MatrixConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MatrixConfig")
+            .field("homeserver", &self.homeserver)
+            .field("user_id", &self.user_id)
+            .field("access_token", &"<redacted>")
+            .field("room_id", &self.room_id)
+            .field("since", &self.since)
+            .finish()
+    }
+}
+
+impl MatrixConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.homeserver.starts_with("https://") {
+            return Err(anyhow::anyhow!("Homeserver must be an https URL"));
+        }
+        if !self.user_id.starts_with('@') || !self.room_id.starts_with('!') {
+            return Err(anyhow::anyhow!("Expected a Matrix user id and room id"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncResponse {
+    next_batch: String,
+    #[serde(default)]
+    rooms: Rooms,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Rooms {
+    #[serde(default)]
+    join: HashMap<String, JoinedRoom>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JoinedRoom {
+    #[serde(default)]
+    timeline: Timeline,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Timeline {
+    #[serde(default)]
+    events: Vec<RoomEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoomEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    sender: String,
+    #[serde(default)]
+    content: FrameContent,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FrameContent {
+    #[serde(default)]
+    frame: String,
+}
+
+/// Carries frames through a Matrix room
+pub struct MatrixTransport {
+    config: MatrixConfig,
+    agent: ureq::Agent,
+    since: Mutex<Option<String>>,
+}
+
+impl MatrixTransport {
+    pub fn new(config: MatrixConfig) -> Result<Self> {
+        config.validate()?;
+        let agent = ureq::AgentBuilder::new()
+            .timeout(REQUEST_TIMEOUT)
+            .build();
+        Ok(Self { since: Mutex::new(config.since.clone()), config, agent })
+    }
+
+    /// Where the next sync resumes, to keep for `MatrixConfig::since`
+    pub fn sync_token(&self) -> Option<String> {
+        self.since.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/_matrix/client/v3{}", self.config.homeserver.trim_end_matches('/'), path)
+    }
+
+    fn authorization(&self) -> String {
+        format!("Bearer {}", self.config.access_token)
+    }
+
+    fn filter(&self) -> String {
+        serde_json::json!({
+            "room": {
+                "rooms": [self.config.room_id],
+                "timeline": { "types": [EVENT_TYPE], "limit": SYNC_LIMIT },
+                "state": { "types": [] },
+                "ephemeral": { "types": [] },
+                "account_data": { "types": [] },
+            },
+            "presence": { "types": [] },
+            "account_data": { "types": [] },
+        }).to_string()
+    }
+}
+
+impl MessageTransport for MatrixTransport {
+    fn name(&self) -> &str {
+        "matrix"
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        let txn_id = crate::protocol::generate_id();
+        let path = format!(
+            "/rooms/{}/send/{}/{}",
+            urlencoding::encode(&self.config.room_id),
+            EVENT_TYPE,
+            urlencoding::encode(&txn_id),
+        );
+        let content = FrameContent { frame: base64::engine::general_purpose::STANDARD.encode(frame) };
+        self.agent.put(&self.url(&path))
+            .set("Authorization", &self.authorization())
+            .send_json(&content)
+            .context("Failed to send to the Matrix room")?;
+        Ok(())
+    }
+
+    fn receive(&self) -> Result<Vec<Vec<u8>>> {
+        let filter = self.filter();
+        let since = self.sync_token();
+        let mut request = self.agent.get(&self.url("/sync"))
+            .set("Authorization", &self.authorization())
+            .query("filter", &filter)
+            .query("timeout", "0");
+        if let Some(since) = &since {
+            request = request.query("since", since);
+        }
+        let response: SyncResponse = request.call()
+            .context("Failed to sync with the homeserver")?
+            .into_json()
+            .context("Malformed sync response")?;
+
+        let frames = response.rooms.join.get(&self.config.room_id)
+            .map(|room| room.timeline.events.iter()
+                .filter(|event| event.kind == EVENT_TYPE && event.sender != self.config.user_id)
+                .filter_map(|event| base64::engine::general_purpose::STANDARD.decode(&event.content.frame).ok())
+                .collect())
+            .unwrap_or_default();
+        *self.since.lock().unwrap_or_else(|e| e.into_inner()) = Some(response.next_batch);
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MatrixConfig {
+        MatrixConfig {
+            homeserver: "https://matrix.example.org".to_string(),
+            user_id: "@alice:example.org".to_string(),
+            access_token: "secret".to_string(),
+            room_id: "!room:example.org".to_string(),
+            since: None,
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        assert!(config().validate().is_ok());
+        assert!(!format!("{:?}", config()).contains("secret"));
+        assert!(MatrixConfig { homeserver: "http://matrix.example.org".to_string(), ..config() }.validate().is_err());
+        assert!(MatrixConfig { room_id: "#room:example.org".to_string(), ..config() }.validate().is_err());
+    }
+
+    #[test]
+    fn test_sync_response() {
+        let response: SyncResponse = serde_json::from_str(r#"{
+            "next_batch": "s2",
+            "rooms": {"join": {"!room:example.org": {"timeline": {"events": [
+                {"type": "org.securechat.frame", "sender": "@bob:example.org", "content": {"frame": "AQI="}},
+                {"type": "m.room.message", "sender": "@bob:example.org", "content": {"body": "hi"}}
+            ]}}}}
+        }"#).unwrap();
+        let events = &response.rooms.join["!room:example.org"].timeline.events;
+        assert_eq!(events[0].content.frame, "AQI=");
+        assert_eq!(events[1].content.frame, "");
+    }
+}
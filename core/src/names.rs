@@ -0,0 +1,145 @@
+//! Display name integrity.
+//!
+//! A display name is whatever the peer chose, so anyone can call
+//! themselves "Alice", or "Аlice" with a Cyrillic А. Names are compared by
+//! their skeleton, a folding of lookalike characters, case, diacritics and
+//! punctuation, against the names of every other contact and our own; a
+//! match gets a `NameWarning`. Names are shown with a short fingerprint
+//! hint of the key behind them, and a nickname the user gave a contact
+//! takes precedence over the name they gave themselves.
+
+use serde::{Serialize, Deserialize};
+
+use crate::text;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NameWarning {
+    /// Another contact, or we ourselves, go by the same name
+    Duplicate { contact_id: String },
+    /// Another name looks the same, e.g. with Cyrillic letters for Latin
+    /// ones or an added accent
+    Confusable { contact_id: String },
+}
+
+/// How to show a contact
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContactName {
+    pub contact_id: String,
+    /// The nickname if there is one, else the display name
+    pub name: String,
+    /// As the contact named themselves
+    pub display_name: String,
+    pub nickname: Option<String>,
+    /// Start of the contact's key fingerprint, see
+    /// `Fingerprint::short_hint`
+    pub fingerprint_hint: String,
+    /// Empty when the user chose the name with a nickname
+    pub warnings: Vec<NameWarning>,
+}
+
+/// Warnings for `name` among `others`, given as contact id and the name
+/// each is shown under
+pub fn check<'a>(name: &str, others: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<NameWarning> {
+    let plain = normalize(name);
+    let folded = skeleton(name);
+    others.into_iter()
+        .filter_map(|(contact_id, other)| {
+            let contact_id = contact_id.to_string();
+            if normalize(other) == plain {
+                Some(NameWarning::Duplicate { contact_id })
+            } else if !folded.is_empty() && skeleton(other) == folded {
+                Some(NameWarning::Confusable { contact_id })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// On one line, without direction controls, and in lower case
+fn normalize(name: &str) -> String {
+    text::single_line(name).chars()
+        .filter(|c| !text::is_bidi_control(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// `name` with lookalikes folded together: letters that look alike in
+/// other scripts become Latin ones, then case, diacritics, invisible
+/// characters and everything but letters and digits are dropped
+pub fn skeleton(name: &str) -> String {
+    let mut folded = String::with_capacity(name.len());
+    for c in name.chars() {
+        let c = match c {
+            // Fullwidth forms of ASCII
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            _ => c,
+        };
+        // Before lower-casing, while I and l still look alike
+        let c = match c {
+            'I' | '1' | '|' => 'l',
+            '0' => 'o',
+            _ => c,
+        };
+        for c in c.to_lowercase() {
+            if let Some(latin) = latin_lookalike(c) {
+                folded.push(latin);
+            } else if c.is_alphanumeric() && !is_combining_mark(c) {
+                folded.push(c);
+            }
+        }
+    }
+    folded.replace("rn", "m").replace("vv", "w")
+}
+
+fn is_combining_mark(c: char) -> bool {
+    matches!(c, '\u{0300}'..='\u{036F}' | '\u{1AB0}'..='\u{1AFF}' | '\u{1DC0}'..='\u{1DFF}' | '\u{20D0}'..='\u{20FF}')
+}
+
+/// Latin letter a lower-case letter is commonly mistaken for
+fn latin_lookalike(c: char) -> Option<char> {
+    let latin = match c {
+        // Cyrillic
+        'а' => 'a', 'в' => 'b', 'е' | 'ё' => 'e', 'к' => 'k', 'м' => 'm',
+        'н' | 'һ' => 'h', 'о' => 'o', 'р' => 'p', 'с' => 'c', 'т' => 't',
+        'у' | 'ү' => 'y', 'х' => 'x', 'ѕ' => 's', 'і' | 'ї' | 'ӏ' => 'l',
+        'ј' => 'j', 'ԁ' => 'd', 'ԛ' => 'q', 'ԝ' | 'ѡ' => 'w',
+        // Greek
+        'α' => 'a', 'β' => 'b', 'ε' => 'e', 'η' => 'n', 'ι' => 'l',
+        'κ' => 'k', 'ν' => 'v', 'ο' => 'o', 'ρ' => 'p', 'τ' => 't',
+        'υ' => 'u', 'χ' => 'x', 'γ' => 'y', 'ω' => 'w',
+        // Latin letters with lookalikes among the plain ones
+        'ı' | 'ɩ' | 'i' => 'l', 'ɡ' => 'g', 'ß' => 'b',
+        // Precomposed letters, whose accent is what sets them apart
+        'à'..='å' => 'a', 'ç' => 'c', 'è'..='ë' => 'e', 'ì'..='ï' => 'l',
+        'ñ' => 'n', 'ò'..='ö' | 'ø' => 'o', 'ù'..='ü' => 'u', 'ý' | 'ÿ' => 'y',
+        _ => return None,
+    };
+    Some(latin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skeleton() {
+        assert_eq!(skeleton("Alice"), skeleton("Аlice"));
+        assert_eq!(skeleton("Alice"), skeleton("alice."));
+        assert_eq!(skeleton("Bill"), skeleton("BiII"));
+        assert_eq!(skeleton("Martin"), skeleton("Mártin"));
+        assert_eq!(skeleton("mom"), skeleton("rnom"));
+        assert_eq!(skeleton("Bob"), skeleton("Ｂｏｂ"));
+        assert_ne!(skeleton("Alice"), skeleton("Alicia"));
+    }
+
+    #[test]
+    fn test_check() {
+        let others = [("1", "Alice"), ("2", "Bob"), ("3", "Carol")];
+        assert_eq!(check("alice ", others), vec![NameWarning::Duplicate { contact_id: "1".to_string() }]);
+        assert_eq!(check("Аlice", others), vec![NameWarning::Confusable { contact_id: "1".to_string() }]);
+        assert_eq!(check("B\u{202E}ob", others), vec![NameWarning::Duplicate { contact_id: "2".to_string() }]);
+        assert!(check("Dave", others).is_empty());
+        assert!(check("🙂", [("4", "🙃")]).is_empty());
+    }
+}
@@ -1,20 +1,34 @@
 use futures::channel::mpsc;
 use futures::{SinkExt, StreamExt};
 use libp2p::{
+    core::{transport::MemoryTransport, Transport},
     gossipsub::{self, IdentTopic, MessageAuthenticity},
+    autonat, identify,
     identity::Keypair,
-    noise,
-    swarm::{NetworkBehaviour, SwarmEvent},
+    mdns, multiaddr::Protocol, noise, ping, relay,
+    swarm::{
+        behaviour::toggle::Toggle,
+        dial_opts::{DialOpts, PeerCondition},
+        ConnectionId, NetworkBehaviour, SwarmEvent,
+    },
     PeerId, SwarmBuilder,
 };
 use anyhow::{Result, Context};
-use std::collections::HashMap;
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
 
+use crate::bootstrap;
+use crate::conditions::NetworkConditions;
+use crate::limits::ProtocolLimits;
+use crate::listen::{ListenConfig, PublicOnly};
 use crate::protocol::ProtocolMessage;
+use crate::reconnect::{Reconnector, RedialOutcome};
 
 /// Network event types
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum NetworkEvent {
     /// New message received
     MessageReceived {
@@ -30,7 +44,7 @@ pub enum NetworkEvent {
     PeerConnected {
         peer_id: String,
     },
-    /// Peer disconnected
+    /// Peer disconnected; it is being redialed if we know where to reach it
     PeerDisconnected {
         peer_id: String,
     },
@@ -38,35 +52,267 @@ pub enum NetworkEvent {
     Connected,
     /// Connection lost
     Disconnected,
+    /// Redialing a peer failed for good, see `reconnect`
+    PeerUnreachable {
+        peer_id: String,
+    },
+    /// A peer was reached at a new address; worth keeping for next time
+    PeerAddressLearned {
+        peer: PeerInfo,
+    },
+    /// AutoNAT changed its mind about whether we are reachable
+    ReachabilityChanged {
+        reachability: Reachability,
+    },
+    /// A relay accepted our reservation and now listens for us
+    RelayReserved {
+        relay_peer_id: String,
+    },
+    /// A bootstrap node was dialed; `rtt` is the time to connect, `None`
+    /// if it couldn't be reached
+    BootstrapChecked {
+        address: String,
+        rtt: Option<Duration>,
+    },
+    /// A chat message went out to peers
+    MessageSent {
+        message_id: String,
+    },
+    /// A chat message was given up on
+    MessageFailed {
+        message_id: String,
+        reason: String,
+    },
+}
+
+/// Whether other peers can dial us directly
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reachability {
+    /// Not probed yet
+    #[default]
+    Unknown,
+    Public { address: String },
+    /// Behind NAT or a firewall; we listen through relays
+    Private,
+}
+
+impl From<&autonat::NatStatus> for Reachability {
+    fn from(status: &autonat::NatStatus) -> Self {
+        match status {
+            autonat::NatStatus::Public(address) => Self::Public { address: address.to_string() },
+            autonat::NatStatus::Private => Self::Private,
+            autonat::NatStatus::Unknown => Self::Unknown,
+        }
+    }
+}
+
+/// What the core knows about its network
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkStatus {
+    pub running: bool,
+    pub reachability: Reachability,
+    /// Relays holding a reservation for us
+    pub relays: Vec<String>,
+}
+
+/// The peer id at the end of `/.../p2p/<id>`
+fn remote_peer_id(addr: &libp2p::Multiaddr) -> Option<PeerId> {
+    match addr.iter().last()? {
+        Protocol::P2p(peer_id) => Some(peer_id),
+        _ => None,
+    }
+}
+
+/// Most messages held while no peer is subscribed to the topic
+pub const MAX_UNSENT: usize = 1024;
+
+/// How long a stopping network keeps running so published messages reach
+/// the wire
+pub const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+
+/// A ping unanswered this long marks the connection dead
+pub const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const IDENTIFY_PROTOCOL: &str = "/securechat/id/1.0.0";
+
+/// Start of the data in a contact QR code
+pub const CONTACT_QR_PREFIX: &str = "securechat://contact?";
+
+/// How often peers due for a redial are dialed
+pub const REDIAL_TICK: Duration = Duration::from_millis(500);
+
+/// How the network reaches peers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportKind {
+    /// TCP and QUIC sockets, per `NetworkConfig::listen`
+    #[default]
+    Sockets,
+    /// libp2p's in-process memory transport, listening on
+    /// `/memory/<port>`; lets tests run several nodes without sockets
+    Memory { port: u64 },
 }
 
 /// Network configuration
 #[derive(Debug, Clone)]
 pub struct NetworkConfig {
-    pub listen_addrs: Vec<String>,
+    pub transport: TransportKind,
+    /// Ignored by the memory transport
+    pub listen: ListenConfig,
+    /// Fastest first; the first `bootstrap::STARTUP_DIALS` are dialed at
+    /// start, the rest only if those fail
     pub bootstrap_peers: Vec<String>,
     pub enable_mdns: bool,
     pub topic: String,
+    /// Set from the core's conditions when the network starts
+    pub conditions: NetworkConditions,
+    /// Set from the core's limits when the network starts
+    pub limits: ProtocolLimits,
+    /// Peers from earlier runs, dialed at start and redialed when lost
+    pub known_peers: Vec<PeerInfo>,
+    /// Relays to listen through when AutoNAT finds us unreachable, as
+    /// addresses ending in `/p2p/<peer id>`
+    pub relays: Vec<String>,
+}
+
+impl NetworkConfig {
+    /// Check addresses parse and listen ports are usable, with errors
+    /// naming the offending setting
+    pub fn validate(&self) -> Result<()> {
+        if self.transport == TransportKind::Sockets {
+            self.listen.validate()?;
+        }
+        for addr in &self.bootstrap_peers {
+            addr.parse::<libp2p::Multiaddr>()
+                .map_err(|e| anyhow::anyhow!("Invalid bootstrap peer '{}': {}", addr, e))?;
+        }
+        for addr in &self.relays {
+            let parsed: libp2p::Multiaddr = addr.parse()
+                .map_err(|e| anyhow::anyhow!("Invalid relay address '{}': {}", addr, e))?;
+            if remote_peer_id(&parsed).is_none() {
+                return Err(anyhow::anyhow!("Relay address '{}' must end with /p2p/<peer id>", addr));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
-            listen_addrs: vec![
-                "/ip4/0.0.0.0/tcp/0".to_string(),
-                "/ip4/0.0.0.0/udp/0/quic-v1".to_string(),
-            ],
+            transport: TransportKind::Sockets,
+            listen: ListenConfig::default(),
             bootstrap_peers: vec![],
             enable_mdns: true,
             topic: "securechat-v1".to_string(),
+            conditions: NetworkConditions::default(),
+            limits: ProtocolLimits::default(),
+            known_peers: Vec::new(),
+            relays: Vec::new(),
         }
     }
 }
 
+/// Running network; yields the messages it couldn't send
+pub type NetworkTask = tokio::task::JoinHandle<Result<Vec<ProtocolMessage>>>;
+
+/// A started network: its events, its commands, and the task that ends
+/// once it has shut down
+pub struct NetworkHandle {
+    pub events: mpsc::Receiver<NetworkEvent>,
+    pub commands: mpsc::Sender<NetworkCommand>,
+    pub task: NetworkTask,
+}
+
+/// Carries protocol messages between peers. `Libp2pTransport` is the real
+/// network, `sim::SimTransport` a deterministic stand-in for tests.
+pub trait NetworkTransport: Send + Sync {
+    /// Start networking; must be called within a tokio runtime
+    fn start(&self, config: NetworkConfig) -> Result<NetworkHandle>;
+}
+
+#[derive(Debug, Default)]
+pub struct Libp2pTransport;
+
+impl NetworkTransport for Libp2pTransport {
+    fn start(&self, config: NetworkConfig) -> Result<NetworkHandle> {
+        let (manager, events, commands) = NetworkManager::new(config)
+            .context("Failed to create network manager")?;
+        Ok(NetworkHandle { events, commands, task: tokio::spawn(manager.run()) })
+    }
+}
+
 /// Network behaviour combining all protocols
 #[derive(NetworkBehaviour)]
 struct SecureChatBehaviour {
     gossipsub: gossipsub::Behaviour,
+    /// Finds peers on the local network, used for pairing
+    mdns: Toggle<mdns::async_io::Behaviour>,
+    /// Liveness checks and round trip times
+    ping: ping::Behaviour,
+    /// Learns the addresses peers listen on, and tells them ours
+    identify: PublicOnly<identify::Behaviour>,
+    /// Finds out whether we are reachable from outside
+    autonat: autonat::Behaviour,
+    /// Listens through a relay while we aren't
+    relay_client: relay::client::Behaviour,
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+fn build_behaviour(
+    config: &NetworkConfig,
+    keypair: &Keypair,
+    relay_client: relay::client::Behaviour,
+) -> Result<SecureChatBehaviour, BoxError> {
+    // Gossipsub configuration
+    let gossipsub_config = gossipsub::ConfigBuilder::default()
+        .heartbeat_interval(config.conditions.heartbeat_interval())
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        // At most half of mesh_n, or the config is rejected
+        .mesh_outbound_min(3)
+        .mesh_n_low(4)
+        .mesh_n(6)
+        .mesh_n_high(12)
+        .gossip_lazy(6)
+        .history_length(10)
+        .history_gossip(3)
+        .build()
+        .expect("Valid gossipsub config");
+    
+    let gossipsub = gossipsub::Behaviour::new(
+        MessageAuthenticity::Signed(keypair.clone()),
+        gossipsub_config,
+    ).expect("Valid gossipsub behaviour");
+    
+    let mdns = if config.enable_mdns {
+        Some(mdns::async_io::Behaviour::new(mdns::Config::default(), keypair.public().to_peer_id())?)
+    } else {
+        None
+    };
+    
+    let ping = ping::Behaviour::new(ping::Config::new()
+        .with_interval(config.conditions.ping_interval())
+        .with_timeout(PING_TIMEOUT));
+    let identify = identify::Behaviour::new(identify::Config::new(
+        IDENTIFY_PROTOCOL.to_string(),
+        keypair.public(),
+    ).with_agent_version(format!("securechat/{}", env!("CARGO_PKG_VERSION"))));
+    
+    let mut autonat = autonat::Behaviour::new(keypair.public().to_peer_id(), autonat::Config::default());
+    for addr in config.relays.iter().chain(&config.bootstrap_peers) {
+        if let Some((peer_id, addr)) = addr.parse().ok().and_then(|addr| Some((remote_peer_id(&addr)?, addr))) {
+            autonat.add_server(peer_id, Some(addr));
+        }
+    }
+    
+    Ok(SecureChatBehaviour {
+        gossipsub,
+        mdns: mdns.into(),
+        ping,
+        identify: PublicOnly::new(identify, !config.listen.advertise_private),
+        autonat,
+        relay_client,
+    })
 }
 
 /// P2P Network manager
@@ -75,10 +321,23 @@ pub struct NetworkManager {
     event_sender: mpsc::Sender<NetworkEvent>,
     command_receiver: mpsc::Receiver<NetworkCommand>,
     config: NetworkConfig,
+    /// Published while nobody was listening, retried when a peer subscribes
+    unsent: VecDeque<ProtocolMessage>,
+    peers: PeerManager,
+    reconnector: Reconnector,
+    /// Circuit listeners through relays, while we are unreachable
+    relay_listeners: Vec<libp2p::core::transport::ListenerId>,
+    /// Bootstrap dials in flight, with when they started
+    bootstrap_checks: HashMap<ConnectionId, (String, Instant)>,
+    /// Bootstrap nodes not dialed yet this run, tried while no node
+    /// has answered
+    bootstrap_fallbacks: VecDeque<String>,
+    bootstrap_connected: bool,
 }
 
 /// Commands that can be sent to the network manager
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum NetworkCommand {
     SendMessage {
         peer_id: Option<String>, // None = broadcast
@@ -90,7 +349,22 @@ pub enum NetworkCommand {
     DisconnectPeer {
         peer_id: String,
     },
+    /// Reply with statistics for every peer seen this run
+    GetPeerStats {
+        reply: futures::channel::oneshot::Sender<Vec<PeerStats>>,
+    },
+    /// Start using a bootstrap node, checking it right away
+    AddBootstrap {
+        address: String,
+    },
+    RemoveBootstrap {
+        address: String,
+    },
     Shutdown,
+    /// Forget a message still waiting for peers
+    CancelMessage {
+        message_id: String,
+    },
 }
 
 impl NetworkManager {
@@ -98,6 +372,7 @@ impl NetworkManager {
     pub fn new(
         config: NetworkConfig,
     ) -> Result<(Self, mpsc::Receiver<NetworkEvent>, mpsc::Sender<NetworkCommand>)> {
+        config.validate()?;
         let (event_sender, event_receiver) = mpsc::channel(100);
         let (command_sender, command_receiver) = mpsc::channel(100);
         
@@ -106,58 +381,56 @@ impl NetworkManager {
         let local_key = Keypair::generate_ed25519();
         let local_peer_id = PeerId::from(local_key.public());
         
-        log::info!("Local peer ID: {}", local_peer_id);
+        tracing::info!(%local_peer_id, "Local peer ID");
         
         let manager = Self {
             local_peer_id,
             event_sender,
             command_receiver,
             config,
+            unsent: VecDeque::new(),
+            peers: PeerManager::new(),
+            reconnector: Reconnector::default(),
+            relay_listeners: Vec::new(),
+            bootstrap_checks: HashMap::new(),
+            bootstrap_fallbacks: VecDeque::new(),
+            bootstrap_connected: false,
         };
         
         Ok((manager, event_receiver, command_sender))
     }
     
-    /// Start the network event loop
-    pub async fn run(mut self) -> Result<()> {
+    /// Run the network until `Shutdown` or until the command channel
+    /// closes. Commands already queued are handled before stopping, and
+    /// messages no peer received are returned so the caller can keep them.
+    pub async fn run(mut self) -> Result<Vec<ProtocolMessage>> {
         // Generate keypair for swarm
         let local_key = Keypair::generate_ed25519();
-        let local_peer_id = PeerId::from(local_key.public());
         
         // Build swarm using new libp2p 0.54+ API
-        let mut swarm = SwarmBuilder::with_existing_identity(local_key)
-            .with_async_std()
-            .with_tcp(
-                libp2p::tcp::Config::default(),
-                noise::Config::new,
-                libp2p::yamux::Config::default,
-            )?
-            .with_quic()
-            .with_behaviour(|keypair| {
-                // Gossipsub configuration
-                let gossipsub_config = gossipsub::ConfigBuilder::default()
-                    .heartbeat_interval(Duration::from_secs(10))
-                    .validation_mode(gossipsub::ValidationMode::Strict)
-                    .mesh_outbound_min(4)
-                    .mesh_n_low(4)
-                    .mesh_n(6)
-                    .mesh_n_high(12)
-                    .gossip_lazy(6)
-                    .history_length(10)
-                    .history_gossip(3)
-                    .build()
-                    .expect("Valid gossipsub config");
-                
-                let gossipsub = gossipsub::Behaviour::new(
-                    MessageAuthenticity::Signed(keypair.clone()),
-                    gossipsub_config,
-                ).expect("Valid gossipsub behaviour");
-                
-                SecureChatBehaviour {
-                    gossipsub,
-                }
-            })?
-            .build();
+        let builder = SwarmBuilder::with_existing_identity(local_key).with_async_std();
+        let mut swarm = match self.config.transport {
+            TransportKind::Sockets => builder
+                .with_tcp(
+                    libp2p::tcp::Config::default(),
+                    noise::Config::new,
+                    libp2p::yamux::Config::default,
+                )?
+                .with_quic()
+                .with_relay_client(noise::Config::new, libp2p::yamux::Config::default)?
+                .with_behaviour(|keypair, relay_client| build_behaviour(&self.config, keypair, relay_client))?
+                .build(),
+            TransportKind::Memory { .. } => builder
+                .with_other_transport(|keypair| -> Result<_, BoxError> {
+                    Ok(MemoryTransport::default()
+                        .upgrade(libp2p::core::upgrade::Version::V1)
+                        .authenticate(noise::Config::new(keypair)?)
+                        .multiplex(libp2p::yamux::Config::default()))
+                })?
+                .with_relay_client(noise::Config::new, libp2p::yamux::Config::default)?
+                .with_behaviour(|keypair, relay_client| build_behaviour(&self.config, keypair, relay_client))?
+                .build(),
+        };
         
         // Subscribe to topic
         let topic = IdentTopic::new(&self.config.topic);
@@ -165,26 +438,54 @@ impl NetworkManager {
             .context("Failed to subscribe to topic")?;
         
         // Listen on addresses
-        for addr in &self.config.listen_addrs {
-            swarm.listen_on(addr.parse()?)
-                .context("Failed to listen on address")?;
+        let listen_addrs = match self.config.transport {
+            TransportKind::Sockets => self.config.listen.listen_addrs(),
+            TransportKind::Memory { port } => vec![libp2p::Multiaddr::empty().with(Protocol::Memory(port))],
+        };
+        let mut listeners = Vec::new();
+        for addr in listen_addrs {
+            listeners.push(swarm.listen_on(addr.clone())
+                .with_context(|| format!("Failed to listen on {}", addr))?);
+        }
+        for addr in self.config.listen.external_addrs()? {
+            swarm.add_external_address(addr);
         }
         
-        // Dial bootstrap peers
-        for addr in &self.config.bootstrap_peers {
-            let multiaddr: libp2p::Multiaddr = addr.parse()?;
-            swarm.dial(multiaddr)
-                .context("Failed to dial bootstrap peer")?;
+        // Dial the fastest bootstrap nodes, keeping the rest in reserve
+        self.bootstrap_fallbacks = self.config.bootstrap_peers.iter().cloned().collect();
+        for address in self.bootstrap_fallbacks.drain(..bootstrap::STARTUP_DIALS.min(self.bootstrap_fallbacks.len())).collect::<Vec<_>>() {
+            self.check_bootstrap(&mut swarm, address).await;
         }
         
-        log::info!("Network started");
+        // Peers from earlier runs
+        let now = Instant::now();
+        for peer in std::mem::take(&mut self.config.known_peers) {
+            self.reconnector.dial_now(&peer.peer_id, now);
+            self.peers.add_peer(peer);
+        }
+        
+        tracing::info!("Network started");
         
         // Event loop
+        let mut redial_tick = Box::pin(futures::FutureExt::fuse(async_std::task::sleep(REDIAL_TICK)));
+        let mut health_tick = Box::pin(futures::FutureExt::fuse(async_std::task::sleep(bootstrap::HEALTH_CHECK_INTERVAL)));
         loop {
             futures::select! {
                 event = swarm.select_next_some() => {
                     self.handle_swarm_event(&mut swarm, event, &topic).await?;
                 }
+                _ = redial_tick => {
+                    self.redial_due(&mut swarm).await;
+                    redial_tick.set(futures::FutureExt::fuse(async_std::task::sleep(REDIAL_TICK)));
+                }
+                _ = health_tick => {
+                    for address in self.config.bootstrap_peers.clone() {
+                        if !self.bootstrap_checks.values().any(|(a, _)| *a == address) {
+                            self.check_bootstrap(&mut swarm, address).await;
+                        }
+                    }
+                    health_tick.set(futures::FutureExt::fuse(async_std::task::sleep(bootstrap::HEALTH_CHECK_INTERVAL)));
+                }
                 command = self.command_receiver.next() => {
                     if let Some(cmd) = command {
                         if self.handle_command(&mut swarm, cmd, &topic).await? {
@@ -197,7 +498,166 @@ impl NetworkManager {
             }
         }
         
-        log::info!("Network stopped");
+        // Send what was queued behind the shutdown, then give the swarm a
+        // moment to write it out
+        self.command_receiver.close();
+        while let Ok(cmd) = self.command_receiver.try_recv() {
+            match cmd {
+                NetworkCommand::SendMessage { message, .. } => self.publish(&mut swarm, message, &topic).await?,
+                NetworkCommand::CancelMessage { message_id } => self.cancel(&message_id),
+                _ => {}
+            }
+        }
+        let mut grace = Box::pin(futures::FutureExt::fuse(async_std::task::sleep(SHUTDOWN_GRACE)));
+        loop {
+            futures::select! {
+                event = swarm.select_next_some() => {
+                    self.handle_swarm_event(&mut swarm, event, &topic).await?;
+                }
+                _ = grace => break,
+            }
+        }
+        // Memory transport ports are only freed this way
+        for listener in listeners {
+            swarm.remove_listener(listener);
+        }
+        
+        tracing::info!(unsent = self.unsent.len(), "Network stopped");
+        Ok(self.unsent.into())
+    }
+    
+    /// Reserve a slot on every configured relay while we are unreachable,
+    /// and give the slots up once we are reachable again
+    fn update_relay_listeners(&mut self, swarm: &mut libp2p::Swarm<SecureChatBehaviour>, status: &autonat::NatStatus) {
+        match status {
+            autonat::NatStatus::Private if self.relay_listeners.is_empty() => {
+                for addr in &self.config.relays {
+                    let Ok(addr) = addr.parse::<libp2p::Multiaddr>() else {
+                        tracing::warn!(%addr, "Invalid relay address");
+                        continue;
+                    };
+                    match swarm.listen_on(addr.with(Protocol::P2pCircuit)) {
+                        Ok(id) => self.relay_listeners.push(id),
+                        Err(e) => tracing::warn!(error = %e, "Failed to listen through relay"),
+                    }
+                }
+            }
+            autonat::NatStatus::Public(_) => {
+                for id in self.relay_listeners.drain(..) {
+                    swarm.remove_listener(id);
+                }
+            }
+            _ => {}
+        }
+    }
+    
+    /// Dial a bootstrap node to see whether and how fast it answers
+    async fn check_bootstrap(&mut self, swarm: &mut libp2p::Swarm<SecureChatBehaviour>, address: String) {
+        let dialed = address.parse::<libp2p::Multiaddr>()
+            .map_err(|e| e.to_string())
+            .and_then(|addr| {
+                let opts = DialOpts::from(addr);
+                let connection_id = opts.connection_id();
+                swarm.dial(opts).map(|()| connection_id).map_err(|e| e.to_string())
+            });
+        match dialed {
+            Ok(connection_id) => {
+                self.bootstrap_checks.insert(connection_id, (address, Instant::now()));
+            }
+            Err(e) => {
+                tracing::warn!(%address, error = %e, "Failed to dial bootstrap node");
+                Box::pin(self.bootstrap_checked(swarm, address, None)).await;
+            }
+        }
+    }
+    
+    async fn bootstrap_checked(
+        &mut self,
+        swarm: &mut libp2p::Swarm<SecureChatBehaviour>,
+        address: String,
+        rtt: Option<Duration>,
+    ) {
+        self.bootstrap_connected |= rtt.is_some();
+        self.event_sender.send(NetworkEvent::BootstrapChecked { address, rtt }).await.ok();
+        if rtt.is_none() && !self.bootstrap_connected {
+            if let Some(next) = self.bootstrap_fallbacks.pop_front() {
+                self.check_bootstrap(swarm, next).await;
+            }
+        }
+    }
+    
+    /// Dial the peers whose backoff has run out
+    async fn redial_due(&mut self, swarm: &mut libp2p::Swarm<SecureChatBehaviour>) {
+        let now = Instant::now();
+        for peer_id in self.reconnector.due(now) {
+            let addresses: Vec<libp2p::Multiaddr> = self.peers.get_peer(&peer_id)
+                .map(|peer| peer.addresses.iter().filter_map(|a| a.parse().ok()).collect())
+                .unwrap_or_default();
+            let dialed = match peer_id.parse::<PeerId>() {
+                Ok(pid) if !addresses.is_empty() => swarm.dial(
+                    DialOpts::peer_id(pid)
+                        .addresses(addresses)
+                        .condition(PeerCondition::DisconnectedAndNotDialing)
+                        .build(),
+                ).map_err(|e| e.to_string()),
+                _ => Err("no usable address".to_string()),
+            };
+            if let Err(e) = dialed {
+                tracing::debug!(%peer_id, error = %e, "Failed to redial");
+                self.redial_failed(&peer_id, now).await;
+            }
+        }
+    }
+    
+    async fn redial_failed(&mut self, peer_id: &str, now: Instant) {
+        match self.reconnector.dial_failed(peer_id, now) {
+            Some(RedialOutcome::Retry(delay)) => {
+                tracing::debug!(%peer_id, ?delay, "Redialing");
+            }
+            Some(RedialOutcome::Unreachable) => {
+                tracing::info!(%peer_id, "Giving up on peer");
+                self.event_sender.send(NetworkEvent::PeerUnreachable {
+                    peer_id: peer_id.to_string(),
+                }).await.ok();
+            }
+            None => {}
+        }
+    }
+    
+    /// Publish to the topic, holding the message if no peer is subscribed
+    async fn publish(
+        &mut self,
+        swarm: &mut libp2p::Swarm<SecureChatBehaviour>,
+        message: ProtocolMessage,
+        topic: &IdentTopic,
+    ) -> Result<()> {
+        let data = message.encode()?;
+        let message_id = message.message_id().map(str::to_string);
+        let failed = match swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+            Ok(_) | Err(gossipsub::PublishError::Duplicate) => {
+                if let Some(message_id) = message_id {
+                    self.event_sender.send(NetworkEvent::MessageSent { message_id }).await.ok();
+                }
+                None
+            }
+            Err(gossipsub::PublishError::InsufficientPeers) => {
+                self.unsent.push_back(message);
+                if self.unsent.len() > MAX_UNSENT {
+                    tracing::warn!("Unsent queue full, dropping the oldest message");
+                    self.unsent.pop_front()
+                        .and_then(|dropped| dropped.message_id().map(|id| (id.to_string(), "Too many unsent messages".to_string())))
+                } else {
+                    None
+                }
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to publish message");
+                message_id.map(|id| (id, e.to_string()))
+            }
+        };
+        if let Some((message_id, reason)) = failed {
+            self.event_sender.send(NetworkEvent::MessageFailed { message_id, reason }).await.ok();
+        }
         Ok(())
     }
     
@@ -209,26 +669,117 @@ impl NetworkManager {
     ) -> Result<()> {
         match event {
             SwarmEvent::NewListenAddr { address, .. } => {
-                log::info!("Listening on {}", address);
+                tracing::info!(%address, "Listening");
             }
-            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
-                log::info!("Connected to {}", peer_id);
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, num_established, .. } => {
+                tracing::info!(%peer_id, "Connected");
+                if let Some((address, started)) = self.bootstrap_checks.remove(&connection_id) {
+                    self.bootstrap_checked(swarm, address, Some(started.elapsed())).await;
+                    // A health check of a node we were already connected to
+                    if num_established.get() > 1 {
+                        swarm.close_connection(connection_id);
+                        return Ok(());
+                    }
+                }
+                let peer = peer_id.to_string();
+                self.reconnector.connected(&peer);
+                self.peers.record_connected(&peer, true);
+                // Only addresses we dialed can be dialed again
+                if endpoint.is_dialer() {
+                    let addr = endpoint.get_remote_address().to_string();
+                    if let Some(info) = self.peers.add_address(&peer, &addr) {
+                        self.event_sender.send(NetworkEvent::PeerAddressLearned {
+                            peer: info.clone(),
+                        }).await.ok();
+                    }
+                }
                 self.event_sender.send(NetworkEvent::PeerConnected {
-                    peer_id: peer_id.to_string(),
+                    peer_id: peer,
                 }).await.ok();
             }
-            SwarmEvent::ConnectionClosed { peer_id, .. } => {
-                log::info!("Disconnected from {}", peer_id);
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                tracing::info!(%peer_id, "Disconnected");
+                let peer = peer_id.to_string();
+                self.peers.record_connected(&peer, false);
+                if self.peers.get_peer(&peer).is_some_and(|p| !p.addresses.is_empty()) {
+                    self.reconnector.disconnected(&peer, Instant::now());
+                }
                 self.event_sender.send(NetworkEvent::PeerDisconnected {
-                    peer_id: peer_id.to_string(),
+                    peer_id: peer,
                 }).await.ok();
             }
+            SwarmEvent::OutgoingConnectionError { connection_id, peer_id, error } => {
+                if let Some((address, _)) = self.bootstrap_checks.remove(&connection_id) {
+                    tracing::info!(%address, %error, "Bootstrap node is unreachable");
+                    self.bootstrap_checked(swarm, address, None).await;
+                } else if let Some(peer) = peer_id.map(|p| p.to_string()).filter(|p| self.reconnector.is_pending(p)) {
+                    tracing::debug!(%peer, %error, "Failed to reach peer");
+                    self.redial_failed(&peer, Instant::now()).await;
+                }
+            }
+            SwarmEvent::Behaviour(SecureChatBehaviourEvent::Ping(ping::Event { peer, connection, result })) => {
+                match result {
+                    Ok(rtt) => self.peers.record_rtt(&peer.to_string(), rtt),
+                    Err(e) => {
+                        // Ping no longer closes the connection itself
+                        tracing::info!(%peer, error = %e, "Ping failed, closing connection");
+                        swarm.close_connection(connection);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(SecureChatBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
+                self.peers.record_agent(&peer_id.to_string(), info.agent_version);
+            }
+            SwarmEvent::Behaviour(SecureChatBehaviourEvent::Autonat(autonat::Event::StatusChanged { new, .. })) => {
+                tracing::info!(reachability = ?new, "Reachability changed");
+                self.update_relay_listeners(swarm, &new);
+                self.event_sender.send(NetworkEvent::ReachabilityChanged {
+                    reachability: Reachability::from(&new),
+                }).await.ok();
+            }
+            SwarmEvent::Behaviour(SecureChatBehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal: false, .. },
+            )) => {
+                tracing::info!(%relay_peer_id, "Listening through relay");
+                self.event_sender.send(NetworkEvent::RelayReserved {
+                    relay_peer_id: relay_peer_id.to_string(),
+                }).await.ok();
+            }
+            SwarmEvent::Behaviour(SecureChatBehaviourEvent::Mdns(mdns::Event::Discovered(peers))) => {
+                let mut addrs: HashMap<PeerId, Vec<String>> = HashMap::new();
+                for (peer_id, addr) in peers {
+                    swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                    if let Err(e) = swarm.dial(addr.clone()) {
+                        tracing::debug!(%peer_id, %addr, error = %e, "Failed to dial");
+                    }
+                    addrs.entry(peer_id).or_default().push(addr.to_string());
+                }
+                for (peer_id, addrs) in addrs {
+                    tracing::info!(%peer_id, "Discovered on the local network");
+                    self.event_sender.send(NetworkEvent::PeerDiscovered {
+                        peer_id: peer_id.to_string(),
+                        addrs,
+                    }).await.ok();
+                }
+            }
+            SwarmEvent::Behaviour(SecureChatBehaviourEvent::Mdns(mdns::Event::Expired(peers))) => {
+                for (peer_id, _) in peers {
+                    swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                }
+            }
+            SwarmEvent::Behaviour(SecureChatBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { topic: subscribed, .. }))
+                if subscribed == topic.hash() =>
+            {
+                for message in std::mem::take(&mut self.unsent) {
+                    self.publish(swarm, message, topic).await?;
+                }
+            }
             SwarmEvent::Behaviour(SecureChatBehaviourEvent::Gossipsub(gossipsub::Event::Message {
                 propagation_source,
                 message_id: _,
                 message,
             })) => {
-                match bincode::deserialize::<ProtocolMessage>(&message.data) {
+                match ProtocolMessage::decode_within(&message.data, &self.config.limits) {
                     Ok(protocol_msg) => {
                         self.event_sender.send(NetworkEvent::MessageReceived {
                             peer_id: propagation_source.to_string(),
@@ -236,7 +787,7 @@ impl NetworkManager {
                         }).await.ok();
                     }
                     Err(e) => {
-                        log::warn!("Failed to deserialize message: {}", e);
+                        tracing::warn!(error = %e, "Failed to deserialize message");
                     }
                 }
             }
@@ -252,23 +803,10 @@ impl NetworkManager {
         topic: &IdentTopic,
     ) -> Result<bool> {
         match command {
-            NetworkCommand::SendMessage { peer_id, message } => {
-                let data = bincode::serialize(&message)
-                    .context("Failed to serialize message")?;
-                
-                if let Some(_target) = peer_id {
-                    // Direct message (requires established connection)
-                    swarm.behaviour_mut().gossipsub.publish(
-                        topic.clone(),
-                        data,
-                    ).ok();
-                } else {
-                    // Broadcast
-                    swarm.behaviour_mut().gossipsub.publish(
-                        topic.clone(),
-                        data,
-                    ).ok();
-                }
+            NetworkCommand::SendMessage { peer_id: _, message } => {
+                // Direct messages go over the topic too until peers have
+                // direct streams
+                self.publish(swarm, message, topic).await?;
             }
             NetworkCommand::ConnectPeer { addr } => {
                 let multiaddr: libp2p::Multiaddr = addr.parse()?;
@@ -280,13 +818,33 @@ impl NetworkManager {
                     swarm.disconnect_peer_id(pid).ok();
                 }
             }
+            NetworkCommand::GetPeerStats { reply } => {
+                reply.send(self.peers.stats()).ok();
+            }
+            NetworkCommand::AddBootstrap { address } => {
+                if !self.config.bootstrap_peers.contains(&address) {
+                    self.config.bootstrap_peers.push(address.clone());
+                    self.check_bootstrap(swarm, address).await;
+                }
+            }
+            NetworkCommand::RemoveBootstrap { address } => {
+                self.config.bootstrap_peers.retain(|a| *a != address);
+                self.bootstrap_fallbacks.retain(|a| *a != address);
+            }
             NetworkCommand::Shutdown => {
                 return Ok(true);
             }
+            NetworkCommand::CancelMessage { message_id } => {
+                self.cancel(&message_id);
+            }
         }
         Ok(false)
     }
     
+    fn cancel(&mut self, message_id: &str) {
+        self.unsent.retain(|message| message.message_id() != Some(message_id));
+    }
+    
     pub fn local_peer_id(&self) -> &PeerId {
         &self.local_peer_id
     }
@@ -295,22 +853,61 @@ impl NetworkManager {
 /// Peer connection manager for direct connections
 pub struct PeerManager {
     known_peers: HashMap<String, PeerInfo>,
+    stats: HashMap<String, PeerStats>,
 }
 
-#[derive(Debug, Clone)]
+/// A peer and the addresses it was reached at, kept across runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PeerInfo {
     pub peer_id: String,
-    pub public_key: [u8; 32],
+    /// Identity key, once the peer is matched to a contact
+    pub public_key: Option<[u8; 32]>,
     pub display_name: Option<String>,
-    pub last_seen: std::time::Instant,
+    pub last_seen: OffsetDateTime,
+    /// Most recent first, at most `MAX_PEER_ADDRESSES`
     pub addresses: Vec<String>,
     pub trusted: bool,
 }
 
+/// Connection statistics for one peer, for this run only
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerStats {
+    pub peer_id: String,
+    pub connected: bool,
+    /// Round trip time of the last successful ping
+    pub rtt: Option<Duration>,
+    pub last_seen: OffsetDateTime,
+    /// As reported by the peer
+    pub agent_version: Option<String>,
+}
+
+/// Addresses remembered per peer
+pub const MAX_PEER_ADDRESSES: usize = 8;
+
+impl PeerInfo {
+    pub fn new(peer_id: String) -> Self {
+        Self {
+            peer_id,
+            public_key: None,
+            display_name: None,
+            last_seen: OffsetDateTime::now_utc(),
+            addresses: Vec::new(),
+            trusted: false,
+        }
+    }
+}
+
+impl Default for PeerManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PeerManager {
     pub fn new() -> Self {
         Self {
             known_peers: HashMap::new(),
+            stats: HashMap::new(),
         }
     }
     
@@ -324,8 +921,54 @@ impl PeerManager {
     
     pub fn update_last_seen(&mut self, peer_id: &str) {
         if let Some(peer) = self.known_peers.get_mut(peer_id) {
-            peer.last_seen = std::time::Instant::now();
+            peer.last_seen = OffsetDateTime::now_utc();
+        }
+    }
+    
+    /// Remember an address `peer_id` was reached at. Returns the updated
+    /// peer if the address is new to it.
+    pub fn add_address(&mut self, peer_id: &str, addr: &str) -> Option<&PeerInfo> {
+        let peer = self.known_peers.entry(peer_id.to_string())
+            .or_insert_with(|| PeerInfo::new(peer_id.to_string()));
+        peer.last_seen = OffsetDateTime::now_utc();
+        if peer.addresses.first().is_some_and(|first| first == addr) {
+            return None;
         }
+        peer.addresses.retain(|a| a != addr);
+        peer.addresses.insert(0, addr.to_string());
+        peer.addresses.truncate(MAX_PEER_ADDRESSES);
+        Some(peer)
+    }
+    
+    pub fn record_connected(&mut self, peer_id: &str, connected: bool) {
+        self.stats_mut(peer_id).connected = connected;
+        self.update_last_seen(peer_id);
+    }
+    
+    /// A ping was answered, so the peer is alive
+    pub fn record_rtt(&mut self, peer_id: &str, rtt: Duration) {
+        self.stats_mut(peer_id).rtt = Some(rtt);
+        self.update_last_seen(peer_id);
+    }
+    
+    pub fn record_agent(&mut self, peer_id: &str, agent_version: String) {
+        self.stats_mut(peer_id).agent_version = Some(agent_version);
+    }
+    
+    pub fn stats(&self) -> Vec<PeerStats> {
+        self.stats.values().cloned().collect()
+    }
+    
+    fn stats_mut(&mut self, peer_id: &str) -> &mut PeerStats {
+        let stats = self.stats.entry(peer_id.to_string()).or_insert_with(|| PeerStats {
+            peer_id: peer_id.to_string(),
+            connected: false,
+            rtt: None,
+            last_seen: OffsetDateTime::now_utc(),
+            agent_version: None,
+        });
+        stats.last_seen = OffsetDateTime::now_utc();
+        stats
     }
     
     pub fn get_trusted_peers(&self) -> Vec<&PeerInfo> {
@@ -346,16 +989,52 @@ pub mod utils {
     /// Generate QR code data for sharing contact
     pub fn generate_contact_qr(public_key: &[u8; 32], display_name: &str) -> String {
         use base64::Engine;
-        format!("securechat://contact?key={}&name={}",
+        format!("{}key={}&name={}",
+            CONTACT_QR_PREFIX,
             base64::engine::general_purpose::STANDARD.encode(public_key),
-            display_name
+            urlencoding::encode(display_name)
         )
     }
     
-    /// Parse contact from QR code
-    pub fn parse_contact_qr(_qr: &str) -> Result<(String, [u8; 32])> {
-        // Implementation would parse the QR data
-        // For now, return error
-        Err(anyhow::anyhow!("QR parsing not implemented"))
+    /// Parse contact from QR code, returning the display name and key
+    pub fn parse_contact_qr(qr: &str) -> Result<(String, [u8; 32])> {
+        use base64::Engine;
+        let qr = qr.trim();
+        if qr.len() > crate::invite::MAX_LINK_LEN {
+            return Err(anyhow::anyhow!("Contact code too long"));
+        }
+        let query = qr.strip_prefix(CONTACT_QR_PREFIX)
+            .ok_or_else(|| anyhow::anyhow!("Not a SecureChat contact code"))?;
+        let (mut key, mut name) = (None, "");
+        for pair in query.split('&') {
+            match pair.split_once('=') {
+                Some(("key", value)) => key = Some(value),
+                Some(("name", value)) => name = value,
+                _ => {}
+            }
+        }
+        let key = key.ok_or_else(|| anyhow::anyhow!("Contact code has no key"))?;
+        let key: [u8; 32] = base64::engine::general_purpose::STANDARD.decode(key)
+            .context("Invalid contact key")?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Contact key has wrong length"))?;
+        let name = urlencoding::decode(name).context("Invalid contact name")?;
+        Ok((name.chars().take(crate::invite::MAX_NAME_LEN).collect(), key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_contact_qr_round_trip() {
+        let qr = utils::generate_contact_qr(&[9u8; 32], "Ann & Bo=b");
+        assert_eq!(utils::parse_contact_qr(&qr).unwrap(), ("Ann & Bo=b".to_string(), [9u8; 32]));
+        
+        for bad in ["", "securechat://contact?name=x", "securechat://contact?key=AAAA", "https://example.org/?key=x"] {
+            assert!(utils::parse_contact_qr(bad).is_err(), "{}", bad);
+        }
+        assert!(utils::parse_contact_qr(&format!("{}&pad={}", qr, "x".repeat(4096))).is_err());
     }
 }
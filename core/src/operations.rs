@@ -0,0 +1,260 @@
+//! Long operations run in the background, with progress.
+//!
+//! Exporting and restoring backups, moving attachments between files and
+//! the blob store, serving history sync and rebuilding indexes can take
+//! minutes. Their `start_` methods on `SecureChat` return an
+//! `OperationHandle` at once, which has the operation's id and latest
+//! `OperationProgress`, streams its updates, cancels it (see `cancel`)
+//! and waits for its result. Clients without the handle follow the same
+//! progress as `ChatEvent::OperationProgress`, sent at most every
+//! `PROGRESS_INTERVAL` besides the final one.
+
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::Stream;
+use serde::{Serialize, Deserialize};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::cancel::{CancellationToken, Operation};
+use crate::events::EventBus;
+use crate::ChatEvent;
+
+/// Shortest time between two progress events of an operation
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressUnit {
+    Bytes,
+    /// Records, messages or batches, depending on the operation
+    Items,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationProgress {
+    pub id: String,
+    pub operation: Operation,
+    pub unit: ProgressUnit,
+    pub done: u64,
+    /// None until known
+    pub total: Option<u64>,
+    /// The operation ended, successfully or not
+    pub finished: bool,
+}
+
+impl OperationProgress {
+    /// Share done, once the total is known
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(0) => Some(1.0),
+            Some(total) => Some((self.done as f64 / total as f64).min(1.0)),
+            None => None,
+        }
+    }
+}
+
+/// A running operation. Dropping the handle leaves it running.
+pub struct OperationHandle<T> {
+    progress: watch::Receiver<OperationProgress>,
+    cancel: CancellationToken,
+    task: JoinHandle<Result<T>>,
+}
+
+impl<T> OperationHandle<T> {
+    pub fn id(&self) -> String {
+        self.progress.borrow().id.clone()
+    }
+
+    pub fn progress(&self) -> OperationProgress {
+        self.progress.borrow().clone()
+    }
+
+    /// Each change of progress from now on, ending with the finished one
+    pub fn updates(&self) -> impl Stream<Item = OperationProgress> + Send + 'static {
+        futures::stream::unfold(Some(self.progress.clone()), |progress| async move {
+            let mut progress = progress?;
+            progress.changed().await.ok()?;
+            let latest = progress.borrow_and_update().clone();
+            let next = (!latest.finished).then_some(progress);
+            Some((latest, next))
+        })
+    }
+
+    /// Stop the operation at its next check; `wait` then gives `Cancelled`
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// A token that cancels the operation, to keep without the handle
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub async fn wait(self) -> Result<T> {
+        self.task.await?
+    }
+}
+
+/// Reports the progress of one operation. Dropping it, when the
+/// operation ends, reports it finished.
+pub(crate) struct ProgressTracker {
+    progress: watch::Sender<OperationProgress>,
+    events: Arc<EventBus>,
+    last_event: Option<Instant>,
+}
+
+impl ProgressTracker {
+    pub(crate) fn set_total(&mut self, total: u64) {
+        self.progress.send_modify(|progress| progress.total = Some(total));
+        self.report(false);
+    }
+
+    pub(crate) fn set_done(&mut self, done: u64) {
+        self.progress.send_modify(|progress| progress.done = done);
+        self.report(false);
+    }
+
+    pub(crate) fn advance(&mut self, by: u64) {
+        self.progress.send_modify(|progress| progress.done += by);
+        self.report(false);
+    }
+
+    fn report(&mut self, always: bool) {
+        let now = Instant::now();
+        if !always && self.last_event.is_some_and(|last| now - last < PROGRESS_INTERVAL) {
+            return;
+        }
+        self.last_event = Some(now);
+        let progress = self.progress.borrow().clone();
+        self.events.publish(vec![ChatEvent::OperationProgress { progress }]);
+    }
+}
+
+impl Drop for ProgressTracker {
+    fn drop(&mut self) {
+        self.progress.send_modify(|progress| progress.finished = true);
+        self.report(true);
+    }
+}
+
+/// Run `run` as the operation `operation`, cancelled by `cancel`
+pub(crate) fn spawn<T, F, Fut>(
+    events: Arc<EventBus>,
+    operation: Operation,
+    unit: ProgressUnit,
+    total: Option<u64>,
+    cancel: CancellationToken,
+    run: F,
+) -> OperationHandle<T>
+where
+    T: Send + 'static,
+    F: FnOnce(ProgressTracker, CancellationToken) -> Fut,
+    Fut: Future<Output = Result<T>> + Send + 'static,
+{
+    let (sender, receiver) = watch::channel(OperationProgress {
+        id: crate::protocol::generate_id(),
+        operation,
+        unit,
+        done: 0,
+        total,
+        finished: false,
+    });
+    let mut tracker = ProgressTracker { progress: sender, events, last_event: None };
+    tracker.report(true);
+    let task = tokio::spawn(run(tracker, cancel.clone()));
+    OperationHandle { progress: receiver, cancel, task }
+}
+
+/// Counts the bytes read through it as progress
+pub(crate) struct ProgressReader<'a, R> {
+    inner: R,
+    tracker: &'a mut ProgressTracker,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub(crate) fn new(inner: R, tracker: &'a mut ProgressTracker) -> Self {
+        Self { inner, tracker }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.tracker.advance(read as u64);
+        Ok(read)
+    }
+}
+
+/// Counts the bytes written through it as progress
+pub(crate) struct ProgressWriter<'a, W> {
+    inner: W,
+    tracker: &'a mut ProgressTracker,
+}
+
+impl<'a, W: Write> ProgressWriter<'a, W> {
+    pub(crate) fn new(inner: W, tracker: &'a mut ProgressTracker) -> Self {
+        Self { inner, tracker }
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(data)?;
+        self.tracker.advance(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_progress_and_result() {
+        let events = Arc::new(EventBus::default());
+        let handle = spawn(events.clone(), Operation::Reindex, ProgressUnit::Items, None, CancellationToken::new(), |mut tracker, _| async move {
+            tokio::task::yield_now().await;
+            tracker.set_total(4);
+            for _ in 0..4 {
+                tracker.advance(1);
+            }
+            Ok(7)
+        });
+        let updates = handle.updates();
+        assert_eq!(handle.wait().await.unwrap(), 7);
+
+        let last = updates.collect::<Vec<_>>().await.pop().unwrap();
+        assert!(last.finished);
+        assert_eq!((last.done, last.total, last.fraction()), (4, Some(4), Some(1.0)));
+        // The first and last go out as events whatever the interval
+        let reported: Vec<_> = events.replay(1).events.into_iter()
+            .filter_map(|sequenced| match sequenced.event {
+                ChatEvent::OperationProgress { progress } => Some(progress),
+                _ => None,
+            })
+            .collect();
+        assert!(!reported[0].finished);
+        assert_eq!(reported.last(), Some(&last));
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let handle = spawn(Arc::new(EventBus::default()), Operation::Reindex, ProgressUnit::Items, None, CancellationToken::new(), |_, cancel| async move {
+            cancel.cancelled().await;
+            cancel.check()?;
+            Ok(())
+        });
+        handle.cancel();
+        assert!(crate::cancel::is_cancelled(&handle.wait().await.unwrap_err()));
+    }
+}
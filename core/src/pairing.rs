@@ -0,0 +1,291 @@
+//! Pairing with someone on the same local network.
+//!
+//! One device shows a short numeric code and the other person types it in.
+//! The two devices run SPAKE2 over Ristretto with the code as password: a
+//! device that doesn't know the code learns nothing it could test guesses
+//! against offline, and gets a single online guess before the session is
+//! dropped. The shared key then protects the exchange of identity keys, so
+//! both sides end up with a verified contact without comparing keys.
+//!
+//! ```text
+//! joiner                                 displayer
+//!   Start  { X = x·G + w·M }        ->
+//!                                   <-   Reply  { Y = y·G + w·N, confirm, sealed identity }
+//!   Finish { confirm, sealed identity } ->
+//! ```
+//!
+//! Messages travel as `ProtocolMessage::Pairing` over the local network,
+//! where peers are found through mDNS.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, Context};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_POINT,
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+    traits::Identity,
+};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use sha2::Sha512;
+use subtle::ConstantTimeEq;
+
+use crate::protocol::wire;
+
+pub const CODE_DIGITS: usize = 6;
+
+/// How long a pairing code stays usable
+pub const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PairingMessage {
+    Start { pairing_id: [u8; 16], element: [u8; 32] },
+    Reply { pairing_id: [u8; 16], element: [u8; 32], confirm: [u8; 32], sealed: Vec<u8> },
+    Finish { pairing_id: [u8; 16], confirm: [u8; 32], sealed: Vec<u8> },
+}
+
+/// Who the other side says they are, sent under the pairing key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairingIdentity {
+    pub identity_key: [u8; 32],
+    pub display_name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingRole {
+    /// Shows the code and answers the first `Start`
+    Displayer,
+    /// Typed in the code
+    Joiner,
+}
+
+/// One side of a pairing in progress
+pub struct PairingSession {
+    role: PairingRole,
+    started: Instant,
+    password: Scalar,
+    secret: Scalar,
+    element: [u8; 32],
+    /// Set once the other side's element arrived
+    pairing_id: Option<[u8; 16]>,
+    keys: Option<SessionKeys>,
+}
+
+struct SessionKeys {
+    confirm_displayer: [u8; 32],
+    confirm_joiner: [u8; 32],
+    seal_displayer: [u8; 32],
+    seal_joiner: [u8; 32],
+}
+
+impl std::fmt::Debug for PairingSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PairingSession")
+            .field("role", &self.role)
+            .field("pairing_id", &self.pairing_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PairingSession {
+    /// Start a session that shows a code. Returns it with the code.
+    pub fn display() -> (Self, String) {
+        let code: String = (0..CODE_DIGITS)
+            .map(|_| char::from(b'0' + rand::thread_rng().gen_range(0..10)))
+            .collect();
+        let session = Self::new(PairingRole::Displayer, &code);
+        (session, code)
+    }
+    
+    /// Start a session from a code typed in by the user
+    pub fn join(code: &str) -> Result<(Self, PairingMessage)> {
+        let code: String = code.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+        if code.len() != CODE_DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(anyhow::anyhow!("Pairing codes are {} digits", CODE_DIGITS));
+        }
+        let mut session = Self::new(PairingRole::Joiner, &code);
+        let pairing_id: [u8; 16] = rand::random();
+        session.pairing_id = Some(pairing_id);
+        let start = PairingMessage::Start { pairing_id, element: session.element };
+        Ok((session, start))
+    }
+    
+    fn new(role: PairingRole, code: &str) -> Self {
+        let password = Scalar::hash_from_bytes::<Sha512>(format!("SecureChat pairing code {}", code).as_bytes());
+        let secret = Scalar::random(&mut OsRng);
+        let blind = match role {
+            PairingRole::Joiner => blinding_point(b"M"),
+            PairingRole::Displayer => blinding_point(b"N"),
+        };
+        let element = (RISTRETTO_BASEPOINT_POINT * secret + blind * password).compress().to_bytes();
+        Self { role, started: Instant::now(), password, secret, element, pairing_id: None, keys: None }
+    }
+    
+    pub fn role(&self) -> PairingRole {
+        self.role
+    }
+    
+    pub fn is_expired(&self) -> bool {
+        self.started.elapsed() >= PAIRING_TIMEOUT
+    }
+    
+    /// Whether `message` belongs to this session. A displayer takes up the
+    /// first `Start` it sees.
+    pub fn wants(&self, message: &PairingMessage) -> bool {
+        match (self.role, message, self.pairing_id) {
+            (PairingRole::Displayer, PairingMessage::Start { .. }, None) => true,
+            (PairingRole::Displayer, PairingMessage::Finish { pairing_id, .. }, Some(id)) => *pairing_id == id,
+            (PairingRole::Joiner, PairingMessage::Reply { pairing_id, .. }, Some(id)) => *pairing_id == id,
+            _ => false,
+        }
+    }
+    
+    /// Displayer: answer a `Start` with our element and identity
+    pub fn respond(&mut self, start: &PairingMessage, ours: &PairingIdentity) -> Result<PairingMessage> {
+        let PairingMessage::Start { pairing_id, element } = start else {
+            return Err(anyhow::anyhow!("Expected a pairing start"));
+        };
+        let keys = self.derive_keys(pairing_id, element, &self.element.clone())?;
+        let confirm = keys.confirm_displayer;
+        let sealed = seal(&keys.seal_displayer, ours)?;
+        self.pairing_id = Some(*pairing_id);
+        self.keys = Some(keys);
+        Ok(PairingMessage::Reply { pairing_id: *pairing_id, element: self.element, confirm, sealed })
+    }
+    
+    /// Joiner: check the `Reply`, learn the displayer's identity and send ours
+    pub fn finish(&self, reply: &PairingMessage, ours: &PairingIdentity) -> Result<(PairingIdentity, PairingMessage)> {
+        let PairingMessage::Reply { pairing_id, element, confirm, sealed } = reply else {
+            return Err(anyhow::anyhow!("Expected a pairing reply"));
+        };
+        let keys = self.derive_keys(pairing_id, &self.element.clone(), element)?;
+        if !bool::from(confirm.ct_eq(&keys.confirm_displayer)) {
+            return Err(anyhow::anyhow!("Pairing failed - wrong code?"));
+        }
+        let theirs = open(&keys.seal_displayer, sealed)?;
+        let finish = PairingMessage::Finish {
+            pairing_id: *pairing_id,
+            confirm: keys.confirm_joiner,
+            sealed: seal(&keys.seal_joiner, ours)?,
+        };
+        Ok((theirs, finish))
+    }
+    
+    /// Displayer: check the `Finish` and learn the joiner's identity
+    pub fn complete(&self, finish: &PairingMessage) -> Result<PairingIdentity> {
+        let PairingMessage::Finish { confirm, sealed, .. } = finish else {
+            return Err(anyhow::anyhow!("Expected a pairing finish"));
+        };
+        let keys = self.keys.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Pairing has not started"))?;
+        if !bool::from(confirm.ct_eq(&keys.confirm_joiner)) {
+            return Err(anyhow::anyhow!("Pairing failed - wrong code?"));
+        }
+        open(&keys.seal_joiner, sealed)
+    }
+    
+    fn derive_keys(&self, pairing_id: &[u8; 16], joiner: &[u8; 32], displayer: &[u8; 32]) -> Result<SessionKeys> {
+        // Remove the other side's blinding to get their x·G or y·G
+        let (theirs, blind) = match self.role {
+            PairingRole::Displayer => (joiner, blinding_point(b"M")),
+            PairingRole::Joiner => (displayer, blinding_point(b"N")),
+        };
+        let point = CompressedRistretto(*theirs).decompress()
+            .ok_or_else(|| anyhow::anyhow!("Invalid pairing element"))?;
+        let shared = (point - blind * self.password) * self.secret;
+        if shared == RistrettoPoint::identity() {
+            return Err(anyhow::anyhow!("Invalid pairing element"));
+        }
+        
+        let mut hasher = blake3::Hasher::new_derive_key("SecureChat pairing v1");
+        hasher.update(pairing_id);
+        hasher.update(joiner);
+        hasher.update(displayer);
+        hasher.update(shared.compress().as_bytes());
+        hasher.update(self.password.as_bytes());
+        let transcript = *hasher.finalize().as_bytes();
+        
+        Ok(SessionKeys {
+            confirm_displayer: blake3::derive_key("SecureChat pairing v1 confirm displayer", &transcript),
+            confirm_joiner: blake3::derive_key("SecureChat pairing v1 confirm joiner", &transcript),
+            seal_displayer: blake3::derive_key("SecureChat pairing v1 seal displayer", &transcript),
+            seal_joiner: blake3::derive_key("SecureChat pairing v1 seal joiner", &transcript),
+        })
+    }
+}
+
+/// SPAKE2's M and N, hashed to the group so nobody knows their discrete log
+fn blinding_point(name: &[u8]) -> RistrettoPoint {
+    RistrettoPoint::hash_from_bytes::<Sha512>(&[b"SecureChat SPAKE2 ".as_slice(), name].concat())
+}
+
+fn seal(key: &[u8; 32], identity: &PairingIdentity) -> Result<Vec<u8>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(&nonce, wire::encode(identity)?.as_slice())
+        .map_err(|_| anyhow::anyhow!("Failed to seal pairing identity"))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn open(key: &[u8; 32], sealed: &[u8]) -> Result<PairingIdentity> {
+    if sealed.len() < NONCE_LEN {
+        return Err(anyhow::anyhow!("Pairing message too short"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let plaintext = XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("Pairing message is corrupt"))?;
+    wire::decode(&plaintext).context("Pairing identity is malformed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn identity(byte: u8, name: &str) -> PairingIdentity {
+        PairingIdentity { identity_key: [byte; 32], display_name: name.to_string() }
+    }
+    
+    #[test]
+    fn test_pairing_exchanges_identities() {
+        let (mut displayer, code) = PairingSession::display();
+        assert_eq!(code.len(), CODE_DIGITS);
+        let (joiner, start) = PairingSession::join(&format!(" {}-{} ", &code[..3], &code[3..])).unwrap();
+        
+        assert!(displayer.wants(&start));
+        let reply = displayer.respond(&start, &identity(1, "Alice")).unwrap();
+        assert!(!displayer.wants(&start));
+        assert!(joiner.wants(&reply));
+        
+        let (theirs, finish) = joiner.finish(&reply, &identity(2, "Bob")).unwrap();
+        assert_eq!(theirs, identity(1, "Alice"));
+        assert!(displayer.wants(&finish));
+        assert_eq!(displayer.complete(&finish).unwrap(), identity(2, "Bob"));
+    }
+    
+    #[test]
+    fn test_wrong_code_fails_on_both_sides() {
+        let (mut displayer, code) = PairingSession::display();
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        let (joiner, start) = PairingSession::join(wrong).unwrap();
+        
+        let reply = displayer.respond(&start, &identity(1, "Alice")).unwrap();
+        assert!(joiner.finish(&reply, &identity(2, "Bob")).is_err());
+        
+        // A joiner pushing on regardless is caught by the displayer
+        let PairingMessage::Start { pairing_id, .. } = start else { unreachable!() };
+        let forged = PairingMessage::Finish { pairing_id, confirm: [0u8; 32], sealed: vec![0u8; 64] };
+        assert!(displayer.wants(&forged));
+        assert!(displayer.complete(&forged).is_err());
+        
+        assert!(PairingSession::join("12345").is_err());
+        assert!(PairingSession::join("abcdef").is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//! Plugins that act on messages as they pass through the core.
+//!
+//! A `Plugin` registered with `SecureChat::register_plugin` is called at
+//! three points: a message arrived and is about to be stored, we are about
+//! to send one, or someone asked to become a contact. At each it can let
+//! the message through, change its content, annotate it, or suppress it,
+//! which is enough for auto-responders and filters. Plugins run in order
+//! of priority, lowest first, each seeing what those before it changed;
+//! the first to suppress ends the run.
+//!
+//! Plugins are trusted local code but not trusted to be correct: a hook
+//! that fails, panics or takes longer than `HOOK_TIMEOUT` is logged and
+//! treated as letting the message through. Hooks run with no locks held,
+//! so a plugin may call back into `SecureChat`, e.g. to send a reply.
+
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::protocol::MessageContent;
+
+/// Longest one hook may take
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest annotation kept, in characters
+pub const MAX_ANNOTATION_LEN: usize = 256;
+
+/// What a hook decided
+#[derive(Debug, Clone)]
+pub enum HookAction {
+    Continue,
+    /// Replace the message's content. Ignored for contact requests.
+    Modify(MessageContent),
+    /// Attach a note to the message, kept with it. Ignored for contact
+    /// requests.
+    Annotate(String),
+    /// Drop the message; nothing after this plugin sees it
+    Suppress,
+}
+
+/// A message from a contact, before it is stored
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    /// The sending contact, or for a first message through one of our
+    /// invites the id the sender gave
+    pub sender_id: String,
+    pub message_id: String,
+    pub content: MessageContent,
+    pub received_at: OffsetDateTime,
+}
+
+/// A message of ours, before it is stored and sent
+#[derive(Debug, Clone)]
+pub struct OutgoingMessage {
+    pub conversation_id: String,
+    pub content: MessageContent,
+}
+
+/// Someone asking to become a contact
+#[derive(Debug, Clone)]
+pub struct ContactRequest {
+    /// The requester's identity key, base64 encoded
+    pub requester_id: String,
+    pub display_name: String,
+    pub message: String,
+}
+
+/// A plugin's note on a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Annotation {
+    pub plugin: String,
+    pub text: String,
+}
+
+/// What running the hooks came to
+#[derive(Debug, Clone, Default)]
+pub struct HookOutcome {
+    /// Name of the plugin that suppressed the message, if one did
+    pub suppressed_by: Option<String>,
+    pub annotations: Vec<Annotation>,
+}
+
+/// Hooks a plugin implements; those it doesn't let everything through
+pub trait Plugin: Send + Sync {
+    /// Unique among registered plugins; shown in logs and annotations
+    fn name(&self) -> &str;
+
+    /// Lower runs first
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    fn on_incoming_message<'a>(&'a self, _message: &'a IncomingMessage) -> BoxFuture<'a, Result<HookAction>> {
+        Box::pin(async { Ok(HookAction::Continue) })
+    }
+
+    fn on_outgoing_message<'a>(&'a self, _message: &'a OutgoingMessage) -> BoxFuture<'a, Result<HookAction>> {
+        Box::pin(async { Ok(HookAction::Continue) })
+    }
+
+    fn on_contact_request<'a>(&'a self, _request: &'a ContactRequest) -> BoxFuture<'a, Result<HookAction>> {
+        Box::pin(async { Ok(HookAction::Continue) })
+    }
+}
+
+/// Add `plugin` to `plugins`, keeping them in priority order
+pub fn register(plugins: &mut Vec<Arc<dyn Plugin>>, plugin: Arc<dyn Plugin>) -> Result<()> {
+    if plugin.name().is_empty() || plugins.iter().any(|p| p.name() == plugin.name()) {
+        return Err(anyhow::anyhow!("A plugin needs a name no other plugin has"));
+    }
+    plugins.push(plugin);
+    // Stable, so equal priorities run in the order registered
+    plugins.sort_by_key(|p| p.priority());
+    Ok(())
+}
+
+pub async fn run_incoming(plugins: &[Arc<dyn Plugin>], message: &mut IncomingMessage) -> HookOutcome {
+    let mut outcome = HookOutcome::default();
+    for plugin in plugins {
+        let action = call(plugin.as_ref(), "on_incoming_message", plugin.on_incoming_message(message)).await;
+        if !apply(&mut outcome, plugin.as_ref(), action, &mut message.content) {
+            break;
+        }
+    }
+    outcome
+}
+
+pub async fn run_outgoing(plugins: &[Arc<dyn Plugin>], message: &mut OutgoingMessage) -> HookOutcome {
+    let mut outcome = HookOutcome::default();
+    for plugin in plugins {
+        let action = call(plugin.as_ref(), "on_outgoing_message", plugin.on_outgoing_message(message)).await;
+        if !apply(&mut outcome, plugin.as_ref(), action, &mut message.content) {
+            break;
+        }
+    }
+    outcome
+}
+
+pub async fn run_contact_request(plugins: &[Arc<dyn Plugin>], request: &ContactRequest) -> HookOutcome {
+    let mut outcome = HookOutcome::default();
+    for plugin in plugins {
+        let action = call(plugin.as_ref(), "on_contact_request", plugin.on_contact_request(request)).await;
+        if matches!(action, HookAction::Suppress) {
+            outcome.suppressed_by = Some(plugin.name().to_string());
+            break;
+        }
+    }
+    outcome
+}
+
+/// Await one hook, taking failures, panics and timeouts for `Continue`
+async fn call(plugin: &dyn Plugin, hook: &str, future: BoxFuture<'_, Result<HookAction>>) -> HookAction {
+    match tokio::time::timeout(HOOK_TIMEOUT, AssertUnwindSafe(future).catch_unwind()).await {
+        Ok(Ok(Ok(action))) => action,
+        Ok(Ok(Err(e))) => {
+            tracing::warn!(plugin = plugin.name(), hook, error = %e, "Plugin hook failed");
+            HookAction::Continue
+        }
+        Ok(Err(_)) => {
+            tracing::warn!(plugin = plugin.name(), hook, "Plugin hook panicked");
+            HookAction::Continue
+        }
+        Err(_) => {
+            tracing::warn!(plugin = plugin.name(), hook, "Plugin hook timed out");
+            HookAction::Continue
+        }
+    }
+}
+
+/// Fold one message hook's action into `outcome`; false once suppressed
+fn apply(outcome: &mut HookOutcome, plugin: &dyn Plugin, action: HookAction, content: &mut MessageContent) -> bool {
+    match action {
+        HookAction::Continue => {}
+        HookAction::Modify(modified) => {
+            // Plugins can't make messages the core only writes itself
+            if matches!(modified, MessageContent::System { .. }) {
+                tracing::warn!(plugin = plugin.name(), "Plugin tried to make a system message");
+            } else {
+                *content = modified;
+            }
+        }
+        HookAction::Annotate(text) => outcome.annotations.push(Annotation {
+            plugin: plugin.name().to_string(),
+            text: text.chars().take(MAX_ANNOTATION_LEN).collect(),
+        }),
+        HookAction::Suppress => {
+            outcome.suppressed_by = Some(plugin.name().to_string());
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Rule {
+        name: &'static str,
+        priority: i32,
+        act: fn(&str) -> HookAction,
+    }
+
+    impl Plugin for Rule {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn priority(&self) -> i32 {
+            self.priority
+        }
+
+        fn on_outgoing_message<'a>(&'a self, message: &'a OutgoingMessage) -> BoxFuture<'a, Result<HookAction>> {
+            Box::pin(async move {
+                match &message.content {
+                    MessageContent::Text { text } => Ok((self.act)(text)),
+                    _ => Ok(HookAction::Continue),
+                }
+            })
+        }
+    }
+
+    fn outgoing(text: &str) -> OutgoingMessage {
+        OutgoingMessage {
+            conversation_id: "c".to_string(),
+            content: MessageContent::Text { text: text.to_string() },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks_run_in_priority_order() {
+        let mut plugins: Vec<Arc<dyn Plugin>> = Vec::new();
+        let shout = |text: &str| HookAction::Modify(MessageContent::Text { text: text.to_uppercase() });
+        let tag = |text: &str| HookAction::Annotate(format!("saw {}", text));
+        let block = |text: &str| if text.contains("SPAM") { HookAction::Suppress } else { HookAction::Continue };
+        register(&mut plugins, Arc::new(Rule { name: "tag", priority: 5, act: tag })).unwrap();
+        register(&mut plugins, Arc::new(Rule { name: "shout", priority: -1, act: shout })).unwrap();
+        register(&mut plugins, Arc::new(Rule { name: "block", priority: 9, act: block })).unwrap();
+        assert!(register(&mut plugins, Arc::new(Rule { name: "tag", priority: 0, act: tag })).is_err());
+
+        let mut message = outgoing("hello");
+        let outcome = run_outgoing(&plugins, &mut message).await;
+        assert!(matches!(&message.content, MessageContent::Text { text } if text == "HELLO"));
+        assert_eq!(outcome.annotations, vec![Annotation { plugin: "tag".to_string(), text: "saw HELLO".to_string() }]);
+        assert!(outcome.suppressed_by.is_none());
+
+        let outcome = run_outgoing(&plugins, &mut outgoing("spam")).await;
+        assert_eq!(outcome.suppressed_by.as_deref(), Some("block"));
+    }
+
+    #[tokio::test]
+    async fn test_panicking_hook_is_contained() {
+        let mut plugins: Vec<Arc<dyn Plugin>> = Vec::new();
+        register(&mut plugins, Arc::new(Rule { name: "broken", priority: 0, act: |_| panic!("bad plugin") })).unwrap();
+        register(&mut plugins, Arc::new(Rule { name: "tag", priority: 1, act: |_| HookAction::Annotate("ok".to_string()) })).unwrap();
+
+        let mut message = outgoing("hello");
+        let outcome = run_outgoing(&plugins, &mut message).await;
+        assert_eq!(outcome.annotations.len(), 1);
+        assert!(matches!(&message.content, MessageContent::Text { text } if text == "hello"));
+    }
+}
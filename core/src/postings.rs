@@ -0,0 +1,258 @@
+//! Index lists kept in sealed blocks.
+//!
+//! `SecureStorage` indexes messages by conversation order, by time and by
+//! search term. Index keys used to spell out what they sort by, so the
+//! database file alone told when each message was sent and which messages
+//! share a word. Each list is now a `Directory` record under an opaque
+//! token, naming blocks of at most `BLOCK_LEN` postings stored beside it.
+//! A key holds only the token and a block number; what entries sort by
+//! and the messages they point to are sealed in the values.
+//!
+//! Reading a range decrypts the directory and the blocks that can hold
+//! it. A write reads and rewrites the one block a posting belongs in,
+//! splitting it in two once it grows past `BLOCK_LEN`.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+/// Most postings in a block; a fuller one is split in two
+pub const BLOCK_LEN: usize = 128;
+
+/// One entry of a list. Lists are ordered by `sort`, then by `path`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Posting {
+    /// Big-endian bytes of what the list sorts by
+    pub sort: Vec<u8>,
+    /// Path of the message the entry points to
+    pub path: String,
+}
+
+impl Posting {
+    /// The first place `sort` could be in a list, before any posting
+    /// that sorts the same
+    pub fn at(sort: Vec<u8>) -> Self {
+        Self { sort, path: String::new() }
+    }
+}
+
+/// A list's blocks, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Directory {
+    pub blocks: Vec<BlockInfo>,
+    /// Number the next block made gets
+    pub next_block: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockInfo {
+    pub id: u64,
+    /// The block's first posting; every posting from it up to the next
+    /// block's first is in this block
+    pub first: Posting,
+}
+
+impl Directory {
+    /// A list of `postings`, which must be sorted and distinct, with its
+    /// blocks half full so the first writes don't split them
+    pub fn build(postings: Vec<Posting>) -> (Directory, Vec<(u64, Vec<Posting>)>) {
+        let mut directory = Directory::default();
+        let mut blocks = Vec::new();
+        for chunk in postings.chunks(BLOCK_LEN / 2) {
+            let id = directory.new_block();
+            directory.blocks.push(BlockInfo { id, first: chunk[0].clone() });
+            blocks.push((id, chunk.to_vec()));
+        }
+        (directory, blocks)
+    }
+
+    /// Blocks that can hold postings from `from` up to, not including,
+    /// `before`, in order
+    pub fn blocks_between(&self, from: Option<&Posting>, before: Option<&Posting>) -> Vec<u64> {
+        let start = from.map_or(0, |from| self.block_for(from));
+        let end = before.map_or(self.blocks.len(), |before| self.blocks.partition_point(|block| block.first < *before));
+        self.blocks[start..end.max(start)].iter()
+            .map(|block| block.id)
+            .collect()
+    }
+
+    /// Index of the block `posting` belongs in: the last starting at or
+    /// before it, or the first
+    fn block_for(&self, posting: &Posting) -> usize {
+        self.blocks.partition_point(|block| block.first <= *posting).saturating_sub(1)
+    }
+
+    fn new_block(&mut self) -> u64 {
+        let id = self.next_block;
+        self.next_block += 1;
+        id
+    }
+}
+
+/// Changes to one list. Blocks are read through the caller as they are
+/// first needed and kept until the changes are written.
+#[derive(Debug, Default)]
+pub struct ListEdit {
+    directory: Directory,
+    blocks: HashMap<u64, Vec<Posting>>,
+    /// Whether anything changed
+    dirty: bool,
+}
+
+impl ListEdit {
+    pub fn new(directory: Directory) -> Self {
+        Self { directory, blocks: HashMap::new(), dirty: false }
+    }
+
+    /// Add `posting` unless the list has it, reading a block with `read`
+    /// if it isn't loaded
+    pub fn insert(&mut self, posting: Posting, read: impl FnOnce(u64) -> Result<Vec<Posting>>) -> Result<()> {
+        if self.directory.blocks.is_empty() {
+            let id = self.directory.new_block();
+            self.directory.blocks.push(BlockInfo { id, first: posting.clone() });
+            self.blocks.insert(id, vec![posting]);
+            self.dirty = true;
+            return Ok(());
+        }
+        let index = self.directory.block_for(&posting);
+        let id = self.directory.blocks[index].id;
+        let block = self.block(id, read)?;
+        let Err(at) = block.binary_search(&posting) else {
+            return Ok(());
+        };
+        block.insert(at, posting);
+        let first = block[0].clone();
+        let rest = (block.len() > BLOCK_LEN).then(|| block.split_off(block.len() / 2));
+        self.directory.blocks[index].first = first;
+        if let Some(rest) = rest {
+            let new = self.directory.new_block();
+            self.directory.blocks.insert(index + 1, BlockInfo { id: new, first: rest[0].clone() });
+            self.blocks.insert(new, rest);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Remove `posting` if the list has it, reading a block with `read`
+    /// if it isn't loaded
+    pub fn remove(&mut self, posting: &Posting, read: impl FnOnce(u64) -> Result<Vec<Posting>>) -> Result<()> {
+        if self.directory.blocks.is_empty() {
+            return Ok(());
+        }
+        let index = self.directory.block_for(posting);
+        let id = self.directory.blocks[index].id;
+        let block = self.block(id, read)?;
+        let Ok(at) = block.binary_search(posting) else {
+            return Ok(());
+        };
+        block.remove(at);
+        match block.first() {
+            Some(first) => self.directory.blocks[index].first = first.clone(),
+            // Kept as loaded and empty, so it is deleted on writing
+            None => {
+                self.directory.blocks.remove(index);
+            }
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// What to write if anything changed: the directory and the loaded
+    /// blocks, those left empty to be deleted
+    pub fn into_changes(self) -> Option<(Directory, HashMap<u64, Vec<Posting>>)> {
+        self.dirty.then_some((self.directory, self.blocks))
+    }
+
+    fn block(&mut self, id: u64, read: impl FnOnce(u64) -> Result<Vec<Posting>>) -> Result<&mut Vec<Posting>> {
+        Ok(match self.blocks.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(read(id)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn posting(n: u32) -> Posting {
+        Posting { sort: n.to_be_bytes().to_vec(), path: format!("c/{}", n) }
+    }
+
+    /// Apply `edit` to `blocks` as storage would, returning the list's
+    /// postings in order
+    fn apply(edit: ListEdit, blocks: &mut HashMap<u64, Vec<Posting>>) -> (Directory, Vec<Posting>) {
+        let (directory, changed) = edit.into_changes().unwrap();
+        for (id, block) in changed {
+            if block.is_empty() {
+                blocks.remove(&id);
+            } else {
+                blocks.insert(id, block);
+            }
+        }
+        let postings = directory.blocks.iter()
+            .flat_map(|block| blocks[&block.id].clone())
+            .collect();
+        (directory, postings)
+    }
+
+    #[test]
+    fn test_postings_stay_sorted_across_splits() {
+        let mut blocks = HashMap::new();
+        let mut directory = Directory::default();
+        // Interleaved so inserts land in the middle of blocks
+        let order: Vec<u32> = (0..1000).map(|n| (n * 7919) % 1000).collect();
+        for chunk in order.chunks(100) {
+            let mut edit = ListEdit::new(directory);
+            for &n in chunk {
+                edit.insert(posting(n), |id| Ok(blocks.get(&id).cloned().unwrap_or_default())).unwrap();
+            }
+            directory = apply(edit, &mut blocks).0;
+        }
+
+        let (_, postings) = apply({
+            let mut edit = ListEdit::new(directory.clone());
+            edit.insert(posting(0), |id| Ok(blocks[&id].clone())).unwrap();
+            edit.insert(posting(1000), |id| Ok(blocks[&id].clone())).unwrap();
+            edit
+        }, &mut blocks.clone());
+        assert_eq!(postings, (0..=1000).map(posting).collect::<Vec<_>>());
+        assert!(directory.blocks.len() > 1000 / BLOCK_LEN);
+        assert!(blocks.values().all(|block| block.len() <= BLOCK_LEN));
+
+        // A range reads only the blocks that can hold it
+        let ids = directory.blocks_between(Some(&Posting::at(500u32.to_be_bytes().to_vec())), Some(&Posting::at(510u32.to_be_bytes().to_vec())));
+        let found: Vec<_> = ids.iter()
+            .flat_map(|id| blocks[id].clone())
+            .filter(|p| (500..510).contains(&u32::from_be_bytes(p.sort[..].try_into().unwrap())))
+            .collect();
+        assert!(ids.len() <= 2);
+        assert_eq!(found, (500..510).map(posting).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_removing_postings_drops_empty_blocks() {
+        let (directory, built) = Directory::build((0..200).map(posting).collect());
+        let mut blocks: HashMap<_, _> = built.into_iter().collect();
+        let mut edit = ListEdit::new(directory);
+        for n in 0..150 {
+            edit.remove(&posting(n), |id| Ok(blocks[&id].clone())).unwrap();
+        }
+        // Already gone
+        edit.remove(&posting(3), |id| Ok(blocks[&id].clone())).unwrap();
+        let (directory, postings) = apply(edit, &mut blocks);
+
+        assert_eq!(postings, (150..200).map(posting).collect::<Vec<_>>());
+        assert_eq!(directory.blocks.len(), blocks.len());
+        assert_eq!(directory.blocks[0].first, posting(150));
+
+        let mut edit = ListEdit::new(directory);
+        for n in 150..200 {
+            edit.remove(&posting(n), |id| Ok(blocks[&id].clone())).unwrap();
+        }
+        let (directory, postings) = apply(edit, &mut blocks);
+        assert!(postings.is_empty() && directory.blocks.is_empty() && blocks.is_empty());
+    }
+}
@@ -2,7 +2,15 @@ use serde::{Serialize, Deserialize};
 use anyhow::{Result, Context};
 use std::collections::HashMap;
 use time::OffsetDateTime;
-use crate::crypto::{EncryptedMessage, EncryptedIdentityKeys, DoubleRatchet};
+use crate::crypto::{EncryptedMessage, EncryptedIdentityKeys, DoubleRatchet, Fingerprint, RatchetHeader};
+use crate::devices::{Capabilities, CapabilityTranscript, DeviceAnnouncement};
+use crate::history::HistoryManifest;
+use crate::invite::InviteRedemption;
+use crate::limits::{check_len, ProtocolLimits};
+use crate::pairing::PairingMessage;
+use crate::puzzle::Stamp;
+use crate::richtext::RichText;
+use crate::text;
 
 /// Contact information
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,18 +22,186 @@ pub struct Contact {
     pub last_seen: Option<OffsetDateTime>,
     pub verified: bool,
     pub blocked: bool,
+    /// Privacy preferences the contact advertised, if any
+    pub privacy: Option<PrivacySettings>,
+}
+
+/// What this device tells contacts about its user. Enforced in the core:
+/// a disabled signal is never sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrivacySettings {
+    pub send_read_receipts: bool,
+    pub send_typing: bool,
+    pub share_presence: bool,
+}
+
+/// Local per-contact preferences, synced between own devices
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContactSettings {
+    pub contact_id: String,
+    /// Shown instead of the contact's own display name
+    pub nickname: Option<String>,
+    pub color: Option<ColorTag>,
+    /// Id of the notification sound; None uses the default
+    pub notification_sound: Option<String>,
+    /// Newest change wins when devices sync
+    pub updated_at: OffsetDateTime,
+}
+
+/// Private notes on a contact, e.g. how we met or why we trust them.
+/// Never sent to the contact; synced between own devices like
+/// `ContactSettings`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContactNote {
+    pub contact_id: String,
+    /// Empty once cleared; the record is kept so the clearing syncs too
+    pub text: String,
+    /// Newest change wins when devices sync
+    pub updated_at: OffsetDateTime,
+}
+
+/// A label contacts are filed under, e.g. Family or Work. Synced between
+/// own devices like `ContactSettings`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContactLabel {
+    pub id: String,
+    pub name: String,
+    pub color: Option<ColorTag>,
+    /// Ids of the contacts with this label
+    pub members: Vec<String>,
+    /// Newest change wins when devices sync
+    pub updated_at: OffsetDateTime,
+    /// Deleted labels are kept, empty, so the deletion syncs too
+    pub deleted: bool,
+}
+
+/// A folder conversations are grouped in, e.g. Work or Friends. Each
+/// conversation is in at most one. Synced between own devices like
+/// `ContactLabel`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatFolder {
+    pub id: String,
+    pub name: String,
+    /// Folders are listed by position, lowest first
+    pub position: u32,
+    /// Ids of the contacts whose conversations are in the folder;
+    /// conversation ids differ between devices
+    pub members: Vec<String>,
+    /// Newest change wins when devices sync
+    pub updated_at: OffsetDateTime,
+    /// Deleted folders are kept, empty, so the deletion syncs too
+    pub deleted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColorTag {
+    Red,
+    Orange,
+    Yellow,
+    Green,
+    Blue,
+    Purple,
+    Pink,
+    Gray,
 }
 
 /// Message types
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
 pub enum MessageContent {
     Text { text: String },
-    Image { data: Vec<u8>, mime_type: String, caption: Option<String> },
-    File { data: Vec<u8>, filename: String, mime_type: String },
-    Voice { data: Vec<u8>, duration_secs: u32 },
+    Image { attachment: AttachmentRef, mime_type: String, caption: Option<String> },
+    File { attachment: AttachmentRef, filename: String, mime_type: String },
+    Voice { attachment: AttachmentRef, duration_secs: u32, waveform: Vec<u8> },
     Location { latitude: f64, longitude: f64, accuracy: Option<f32> },
     Contact { name: String, public_key: [u8; 32] },
+    RichText(RichText),
+    Sticker { pack_id: [u8; 32], image: AttachmentRef, emoji: Option<String> },
+    /// Offer to install a sticker pack; `manifest` holds the encoded pack
+    StickerPack { pack_id: [u8; 32], title: String, manifest: AttachmentRef },
+    /// Written into a conversation by this device; never sent, and
+    /// refused from peers
+    System { notice: SystemNotice },
+}
+
+/// An attachment sent or received view-once. Kept after the media is
+/// deleted, so it can't be downloaded again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ViewOnce {
+    /// When the recipient opened it
+    pub viewed_at: Option<OffsetDateTime>,
+}
+
+/// Restrictions either side of a conversation can ask for. They are
+/// cooperative: both cores enforce them, but nothing stops a modified
+/// client, or a camera pointed at the screen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationPolicy {
+    /// Messages can't be forwarded, and exports leave the other side's out
+    #[serde(default)]
+    pub no_forwarding: bool,
+    /// Images and voice notes are always sent and received view-once
+    #[serde(default)]
+    pub view_once_media: bool,
+}
+
+impl ConversationPolicy {
+    /// The restrictions of both
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            no_forwarding: self.no_forwarding || other.no_forwarding,
+            view_once_media: self.view_once_media || other.view_once_media,
+        }
+    }
+}
+
+/// The policies of both sides of a conversation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationPolicies {
+    /// Set here, and sent with every message in the conversation
+    pub ours: ConversationPolicy,
+    /// The contact's, as of their latest message
+    pub theirs: ConversationPolicy,
+}
+
+impl ConversationPolicies {
+    /// What both cores enforce
+    pub fn effective(&self) -> ConversationPolicy {
+        self.ours.union(&self.theirs)
+    }
+}
+
+/// What a system message records
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SystemNotice {
+    /// A view-once message was opened and its media deleted
+    ViewOnceOpened { message_id: String },
+}
+
+/// Attachments are stored and transferred in chunks of this many bytes
+pub const ATTACHMENT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest frame `ProtocolMessage::decode_untrusted` accepts by default
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Longest id (message, conversation, device) accepted from a peer
+pub const MAX_ID_LEN: usize = 256;
+
+/// Contact id of the note-to-self conversation. No contact record has
+/// it; generated ids are longer.
+pub const SELF_CONTACT_ID: &str = "self";
+
+/// Ed25519 signatures are 64 bytes; leave room for other schemes
+const MAX_SIGNATURE_LEN: usize = 128;
+
+/// Reference to attachment bytes held in the blob store. Messages that share
+/// an attachment share one stored copy.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttachmentRef {
+    /// BLAKE3 digest of the plaintext
+    pub digest: [u8; 32],
+    pub size: u64,
+    /// Random key encrypting the chunks in transit
+    pub key: [u8; 32],
 }
 
 /// Message envelope - encrypted content + metadata
@@ -38,6 +214,53 @@ pub struct MessageEnvelope {
     pub encrypted_content: EncryptedMessage,
     pub signature: Vec<u8>,
     pub reply_to: Option<String>,
+    /// Position in the sender's ratchet, used to put messages back in order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ratchet_header: Option<RatchetHeader>,
+    /// Sender's logical clock for the conversation; absent from older peers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lamport: Option<u64>,
+    /// Set on messages sent through one of the recipient's invites, which
+    /// are encrypted to the invite prekey
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite: Option<InviteRedemption>,
+    /// Thumbnail of the message's attachment, sealed by `thumbnail::seal`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<Vec<u8>>,
+    /// Device of the sender that sealed this envelope
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_device: Option<String>,
+    /// The one device this envelope is sealed to; absent when it's for
+    /// any of the recipient's devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient_device: Option<String>,
+    /// Id of the message when it was fanned out to several devices, each
+    /// envelope under an id of its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// The attached image or voice note may be opened once, after which
+    /// the recipient deletes it; see `SecureChat::open_view_once`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub view_once: bool,
+    /// The policy the sender asks for in this conversation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<ConversationPolicy>,
+    /// Capabilities of both devices as the sender saw them, sealed with
+    /// the content; see `devices`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<CapabilityTranscript>,
+}
+
+/// An incoming envelope that could not be decrypted, kept so it can be
+/// retried once the session or the sender's key changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedEnvelope {
+    #[serde(with = "wire::framed")]
+    pub envelope: MessageEnvelope,
+    /// Error from the most recent attempt
+    pub reason: String,
+    pub received_at: OffsetDateTime,
+    pub attempts: u32,
 }
 
 /// Message as stored locally (decrypted)
@@ -49,10 +272,41 @@ pub struct LocalMessage {
     pub is_outgoing: bool,
     pub content: MessageContent,
     pub timestamp: OffsetDateTime,
-    pub sent: bool,
-    pub delivered: bool,
-    pub read: bool,
+    pub status: DeliveryStatus,
     pub reply_to: Option<String>,
+    /// Set on forwarded copies unless provenance was stripped
+    pub forwarded_from: Option<ForwardedFrom>,
+    /// Bookmarked by the local user; never sent
+    pub starred: bool,
+    /// Conversation's logical clock when the message was sent. Ordering
+    /// by it keeps histories the same on both ends despite clock skew.
+    pub lamport: u64,
+}
+
+/// Where a message is on its way to the recipient. Outgoing messages move
+/// from `Queued` towards `Read`; incoming ones are stored as `Delivered` and
+/// become `Read` once the user has seen them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryStatus {
+    /// Stored, waiting for the network
+    Queued,
+    /// Handed to the network, not yet out to any peer
+    Sending,
+    /// Published to peers
+    Sent,
+    /// The recipient's device confirmed it
+    Delivered,
+    /// The recipient read it
+    Read,
+    /// Gave up; can be queued again
+    Failed { reason: String },
+}
+
+/// Origin of a forwarded message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardedFrom {
+    pub sender_id: String,
+    pub timestamp: OffsetDateTime,
 }
 
 /// Conversation/session state
@@ -67,6 +321,8 @@ pub struct Conversation {
     pub archived: bool,
     pub pinned: bool,
     pub ratchet_state: Option<DoubleRatchet>,
+    /// Lamport clock over the messages sent and seen in this conversation
+    pub lamport: u64,
 }
 
 /// User profile
@@ -101,6 +357,7 @@ pub enum Platform {
 /// Protocol message types for P2P communication
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
+#[allow(clippy::large_enum_variant)]
 pub enum ProtocolMessage {
     /// Initial handshake - X3DH key bundle
     KeyBundle {
@@ -133,6 +390,16 @@ pub enum ProtocolMessage {
         is_typing: bool,
     },
     
+    /// Online/offline announcement
+    Presence {
+        online: bool,
+    },
+    
+    /// The sender's privacy preferences, so peers can show "receipts off"
+    PrivacyUpdate {
+        settings: PrivacySettings,
+    },
+    
     /// Profile update
     ProfileUpdate {
         display_name: Option<String>,
@@ -145,6 +412,9 @@ pub enum ProtocolMessage {
         display_name: String,
         message: String,
         key_bundle: Box<ProtocolMessage>, // KeyBundle
+        /// Proof of work for the bundle's identity key, see `puzzle`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stamp: Option<Stamp>,
     },
     
     /// Contact response
@@ -153,6 +423,13 @@ pub enum ProtocolMessage {
         key_bundle: Option<Box<ProtocolMessage>>, // KeyBundle if accepted
     },
     
+    /// One encrypted chunk of an attachment, see `AttachmentRef::seal_chunk`
+    AttachmentChunk {
+        transfer_id: [u8; 32],
+        index: u32,
+        ciphertext: Vec<u8>,
+    },
+    
     /// Sync request for multi-device
     SyncRequest {
         device_id: String,
@@ -164,6 +441,46 @@ pub enum ProtocolMessage {
         conversations: Vec<Conversation>,
         contacts: Vec<Contact>,
         settings: HashMap<String, String>,
+        #[serde(default)]
+        contact_settings: Vec<ContactSettings>,
+        #[serde(default)]
+        labels: Vec<ContactLabel>,
+        #[serde(default)]
+        contact_notes: Vec<ContactNote>,
+        #[serde(default)]
+        folders: Vec<ChatFolder>,
+    },
+    
+    /// Ask another of our devices for the history this one lacks
+    HistorySyncRequest {
+        device_id: String,
+        /// Key the history batches are encrypted to
+        reply_key: [u8; 32],
+        manifest: HistoryManifest,
+        /// Unix time in seconds the request was made
+        timestamp: i64,
+        /// Random, so each request is signed differently
+        nonce: [u8; 16],
+        /// Identity signature, see `history::request_signing_bytes`
+        signature: Vec<u8>,
+    },
+    
+    /// Part of a history transfer to `device_id`; the payload decrypts to
+    /// a `history::HistoryBatchContent`
+    HistoryBatch {
+        device_id: String,
+        payload: EncryptedMessage,
+        signature: Vec<u8>,
+    },
+    
+    /// Local pairing by code, see `pairing`
+    Pairing {
+        message: PairingMessage,
+    },
+    
+    /// A device introducing itself to contacts, see `devices`
+    DeviceAnnouncement {
+        announcement: DeviceAnnouncement,
     },
 }
 
@@ -186,30 +503,176 @@ impl Contact {
             last_seen: None,
             verified: false,
             blocked: false,
+            privacy: None,
+        }
+    }
+    
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of_key(&self.public_key)
+    }
+}
+
+impl AttachmentRef {
+    /// Number of chunks; an empty attachment still has one (empty) chunk
+    pub fn chunk_count(&self) -> u32 {
+        self.size.div_ceil(ATTACHMENT_CHUNK_SIZE as u64).max(1) as u32
+    }
+    
+    /// Expected plaintext length of chunk `index`
+    pub fn chunk_len(&self, index: u32) -> Option<usize> {
+        let count = self.chunk_count();
+        if index >= count {
+            return None;
+        }
+        if index + 1 < count {
+            return Some(ATTACHMENT_CHUNK_SIZE);
         }
+        Some((self.size - u64::from(index) * ATTACHMENT_CHUNK_SIZE as u64) as usize)
+    }
+    
+    /// Identifies the transfer on the wire without revealing the digest
+    pub fn transfer_id(&self) -> [u8; 32] {
+        *blake3::keyed_hash(&self.key, &self.digest).as_bytes()
+    }
+    
+    /// Encrypt chunk `index` for sending
+    pub fn seal_chunk(&self, index: u32, plaintext: &[u8]) -> Result<ProtocolMessage> {
+        let transfer_id = self.transfer_id();
+        Ok(ProtocolMessage::AttachmentChunk {
+            transfer_id,
+            index,
+            ciphertext: crate::crypto::seal_attachment_chunk(&self.key, &transfer_id, index, plaintext)?,
+        })
     }
     
-    pub fn fingerprint(&self) -> String {
-        let hash = blake3::hash(&self.public_key);
-        format!("{}", hash.to_hex())[..32].to_string()
+    /// Decrypt a received chunk and check it has the expected length
+    pub fn open_chunk(&self, index: u32, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let expected = self.chunk_len(index)
+            .ok_or_else(|| anyhow::anyhow!("Attachment chunk index out of range"))?;
+        let plaintext = crate::crypto::open_attachment_chunk(&self.key, &self.transfer_id(), index, ciphertext)?;
+        if plaintext.len() != expected {
+            return Err(anyhow::anyhow!("Attachment chunk has wrong length"));
+        }
+        Ok(plaintext)
+    }
+}
+
+impl std::fmt::Debug for AttachmentRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AttachmentRef")
+            .field("size", &self.size)
+            .field("key", &"[REDACTED]")
+            .finish()
+    }
+}
+
+impl MessageContent {
+    /// Normalize untrusted content from the network before it is stored
+    pub fn sanitize(&mut self) {
+        if let MessageContent::RichText(rich) = self {
+            rich.sanitize();
+        }
+    }
+    
+    /// Check decrypted content from a peer against `limits`
+    pub fn check_bounds(&self, limits: &ProtocolLimits) -> Result<()> {
+        match self {
+            MessageContent::Text { text } => limits.check_text("text", text)?,
+            MessageContent::Image { caption, mime_type, .. } => {
+                limits.check_text("MIME type", mime_type)?;
+                limits.check_text("caption", caption.as_deref().unwrap_or_default())?;
+            }
+            MessageContent::File { filename, mime_type, .. } => {
+                limits.check_text("MIME type", mime_type)?;
+                limits.check_text("file name", filename)?;
+            }
+            MessageContent::Voice { waveform, .. } => limits.check_inline("waveform", waveform.len())?,
+            MessageContent::Contact { name, .. } => limits.check_text("contact name", name)?,
+            MessageContent::RichText(rich) => limits.check_text("text", &rich.text)?,
+            MessageContent::Sticker { emoji, .. } => limits.check_text("emoji", emoji.as_deref().unwrap_or_default())?,
+            MessageContent::StickerPack { title, .. } => limits.check_text("sticker pack title", title)?,
+            MessageContent::Location { .. } => {}
+            MessageContent::System { .. } => return Err(anyhow::anyhow!("System messages can't come from peers")),
+        }
+        if let Some(attachment) = self.attachment() {
+            limits.check_attachment(attachment.size)?;
+        }
+        Ok(())
+    }
+    
+    /// Whether the content can be sent view-once
+    pub fn allows_view_once(&self) -> bool {
+        matches!(self, MessageContent::Image { .. } | MessageContent::Voice { .. })
+    }
+    
+    /// The attachment this content refers to, if any
+    pub fn attachment(&self) -> Option<&AttachmentRef> {
+        match self {
+            MessageContent::Image { attachment, .. }
+            | MessageContent::File { attachment, .. }
+            | MessageContent::Voice { attachment, .. } => Some(attachment),
+            MessageContent::Sticker { image, .. } => Some(image),
+            MessageContent::StickerPack { manifest, .. } => Some(manifest),
+            _ => None,
+        }
+    }
+}
+
+impl DeliveryStatus {
+    /// Whether a message in this status may move to `next`. Statuses only
+    /// move forward, except that a message handed back by a stopping
+    /// network is queued again and a failed one can be retried. A receipt
+    /// proves delivery whatever we thought before.
+    pub fn can_become(&self, next: &Self) -> bool {
+        use DeliveryStatus::*;
+        match (self, next) {
+            (Read, _) => false,
+            (Delivered, next) => *next == Read,
+            (Sending, Queued) => true,
+            (Failed { .. }, next) => matches!(next, Queued | Delivered | Read),
+            (_, Failed { .. }) => true,
+            (current, next) => next.rank() > current.rank(),
+        }
+    }
+    
+    /// Delivered or read
+    pub fn is_delivered(&self) -> bool {
+        matches!(self, Self::Delivered | Self::Read)
+    }
+    
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Queued => 0,
+            Self::Sending => 1,
+            Self::Sent => 2,
+            Self::Delivered => 3,
+            Self::Read => 4,
+            Self::Failed { .. } => 0,
+        }
     }
 }
 
 impl LocalMessage {
+    /// Conversation order: logical clock, then wall clock for concurrent
+    /// messages, then id so every device breaks full ties alike
+    pub fn causal_cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.lamport.cmp(&other.lamport)
+            .then(self.timestamp.cmp(&other.timestamp))
+            .then_with(|| self.id.cmp(&other.id))
+    }
+    
+    /// One line of text standing for the message in lists and
+    /// notifications. Names from peers are bidi isolated.
     pub fn preview_text(&self) -> String {
         match &self.content {
-            MessageContent::Text { text } => {
-                if text.len() > 100 {
-                    format!("{}...", &text[..100])
-                } else {
-                    text.clone()
-                }
-            }
-            MessageContent::Image { caption, .. } => {
-                caption.clone().unwrap_or_else(|| "📷 Image".to_string())
-            }
+            MessageContent::Text { text } => text::preview(text),
+            MessageContent::RichText(rich) => text::preview(&rich.text),
+            MessageContent::Image { caption, .. } => match caption {
+                Some(caption) => text::preview(caption),
+                None => "📷 Image".to_string(),
+            },
             MessageContent::File { filename, .. } => {
-                format!("📎 {}", filename)
+                format!("📎 {}", text::isolate(text::truncate(filename, text::PREVIEW_LEN)))
             }
             MessageContent::Voice { .. } => {
                 "🎤 Voice message".to_string()
@@ -218,9 +681,111 @@ impl LocalMessage {
                 "📍 Location".to_string()
             }
             MessageContent::Contact { name, .. } => {
-                format!("👤 Contact: {}", name)
+                format!("👤 Contact: {}", text::isolate(text::truncate(name, text::PREVIEW_LEN)))
             }
+            MessageContent::Sticker { emoji, .. } => match emoji {
+                Some(emoji) => text::truncate(&text::single_line(emoji), 1).to_string(),
+                None => "Sticker".to_string(),
+            },
+            MessageContent::StickerPack { title, .. } => {
+                format!("🎨 Sticker pack: {}", text::isolate(text::truncate(title, text::PREVIEW_LEN)))
+            }
+            MessageContent::System { notice: SystemNotice::ViewOnceOpened { .. } } => {
+                "👁 View-once media opened".to_string()
+            }
+        }
+    }
+}
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        Self {
+            send_read_receipts: true,
+            send_typing: true,
+            share_presence: true,
+        }
+    }
+}
+
+impl PrivacySettings {
+    /// Whether `message` may leave this device under these settings
+    pub fn allows(&self, message: &ProtocolMessage) -> bool {
+        match message {
+            ProtocolMessage::ReadReceipt { .. } => self.send_read_receipts,
+            ProtocolMessage::Typing { .. } => self.send_typing,
+            ProtocolMessage::Presence { .. } => self.share_presence,
+            _ => true,
+        }
+    }
+}
+
+impl ContactSettings {
+    /// Longest nickname accepted, in characters
+    pub const MAX_NICKNAME_LEN: usize = 64;
+    
+    pub fn new(contact_id: &str) -> Self {
+        Self {
+            contact_id: contact_id.to_string(),
+            nickname: None,
+            color: None,
+            notification_sound: None,
+            updated_at: OffsetDateTime::now_utc(),
+        }
+    }
+}
+
+impl ContactNote {
+    /// Longest note accepted, in characters
+    pub const MAX_LEN: usize = 4096;
+}
+
+impl ContactLabel {
+    /// Longest label name accepted, in characters
+    pub const MAX_NAME_LEN: usize = 32;
+    
+    pub fn new(name: &str, color: Option<ColorTag>) -> Result<Self> {
+        Ok(Self {
+            id: generate_id(),
+            name: Self::check_name(name)?,
+            color,
+            members: Vec::new(),
+            updated_at: OffsetDateTime::now_utc(),
+            deleted: false,
+        })
+    }
+    
+    /// The name trimmed, if it is one a label can have
+    pub fn check_name(name: &str) -> Result<String> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > Self::MAX_NAME_LEN {
+            return Err(anyhow::anyhow!("Label names are 1 to {} characters", Self::MAX_NAME_LEN));
         }
+        Ok(name.to_string())
+    }
+}
+
+impl ChatFolder {
+    /// Longest folder name accepted, in characters
+    pub const MAX_NAME_LEN: usize = 32;
+    
+    pub fn new(name: &str, position: u32) -> Result<Self> {
+        Ok(Self {
+            id: generate_id(),
+            name: Self::check_name(name)?,
+            position,
+            members: Vec::new(),
+            updated_at: OffsetDateTime::now_utc(),
+            deleted: false,
+        })
+    }
+    
+    /// The name trimmed, if it is one a folder can have
+    pub fn check_name(name: &str) -> Result<String> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > Self::MAX_NAME_LEN {
+            return Err(anyhow::anyhow!("Folder names are 1 to {} characters", Self::MAX_NAME_LEN));
+        }
+        Ok(name.to_string())
     }
 }
 
@@ -237,23 +802,806 @@ impl Conversation {
             archived: false,
             pinned: false,
             ratchet_state: None,
+            lamport: 0,
         }
     }
+    
+    /// Advance the clock for a message sent now and return its value
+    pub fn tick(&mut self) -> u64 {
+        self.lamport += 1;
+        self.lamport
+    }
+    
+    /// Merge the clock value carried by a received message
+    pub fn observe(&mut self, lamport: u64) {
+        self.lamport = self.lamport.max(lamport);
+    }
+    
+    /// Whether this is the note-to-self conversation
+    pub fn is_self(&self) -> bool {
+        self.contact_id == SELF_CONTACT_ID
+    }
 }
 
 impl MessageEnvelope {
     pub fn serialize(&self) -> Result<Vec<u8>> {
-        bincode::serialize(self)
+        wire::encode(self)
             .context("Failed to serialize message envelope")
     }
     
     pub fn deserialize(data: &[u8]) -> Result<Self> {
-        bincode::deserialize(data)
+        wire::decode(data)
             .context("Failed to deserialize message envelope")
     }
+    
+    /// What an identity signature of the envelope covers: all of it but
+    /// the signature and the sender id, which receivers replace with their
+    /// own id for the sender
+    pub fn signing_bytes(&self) -> Result<Vec<u8>> {
+        let unsigned = Self {
+            sender_id: String::new(),
+            signature: Vec::new(),
+            ..self.clone()
+        };
+        wire::encode(&(b"SecureChat envelope v1", &unsigned))
+    }
+    
+    /// Associated data the content is sealed with. Devices that take
+    /// `Capabilities::ENVELOPE_AAD` get the header bound to the content,
+    /// so neither can be moved onto another envelope; others get the
+    /// transcript alone, and envelopes without one nothing.
+    pub fn content_aad(&self) -> Result<Vec<u8>> {
+        match &self.capabilities {
+            Some(transcript) if transcript.recipient.contains(Capabilities::ENVELOPE_AAD) => self.header_aad(),
+            Some(transcript) => transcript.aad(),
+            None => Ok(Vec::new()),
+        }
+    }
+    
+    /// The header fields a receiver acts on. Left out are the ids
+    /// receivers replace, the signature, the content itself and the
+    /// thumbnail, which is sealed for the envelope id on its own.
+    fn header_aad(&self) -> Result<Vec<u8>> {
+        wire::encode(&(
+            b"SecureChat envelope header v1",
+            &self.id,
+            &self.timestamp,
+            &self.reply_to,
+            &self.ratchet_header,
+            &self.lamport,
+            &self.sender_device,
+            &self.recipient_device,
+            &self.message_id,
+            &self.view_once,
+            &self.policy,
+            &self.capabilities,
+        ))
+    }
+    
+    /// `deserialize` with the size checks of `ProtocolMessage::decode_untrusted`
+    pub fn deserialize_untrusted(data: &[u8]) -> Result<Self> {
+        Self::deserialize_within(data, &ProtocolLimits::default())
+    }
+    
+    /// `deserialize_untrusted` with the given limits
+    pub fn deserialize_within(data: &[u8], limits: &ProtocolLimits) -> Result<Self> {
+        limits.check_frame(data.len())?;
+        let envelope = Self::deserialize(data)?;
+        envelope.check_bounds(limits)?;
+        Ok(envelope)
+    }
+    
+    fn check_bounds(&self, limits: &ProtocolLimits) -> Result<()> {
+        for (what, id) in [("message id", &self.id), ("sender id", &self.sender_id), ("recipient id", &self.recipient_id)] {
+            check_len(what, id.len(), MAX_ID_LEN)?;
+        }
+        for (what, id) in [("reply id", &self.reply_to), ("sender device", &self.sender_device), ("recipient device", &self.recipient_device), ("message id", &self.message_id)] {
+            if let Some(id) = id {
+                check_len(what, id.len(), MAX_ID_LEN)?;
+            }
+        }
+        if let Some(thumbnail) = &self.thumbnail {
+            check_len("thumbnail", thumbnail.len(), crate::thumbnail::MAX_SEALED_THUMBNAIL_LEN)?;
+        }
+        if let Some(invite) = &self.invite {
+            limits.check_text("display name", &invite.display_name)?;
+        }
+        Ok(check_len("signature", self.signature.len(), MAX_SIGNATURE_LEN)?)
+    }
+}
+
+/// Contact requests and responses carry a key bundle, never another kind
+/// of message, so they can't nest
+fn check_key_bundle(message: &ProtocolMessage, limits: &ProtocolLimits) -> Result<()> {
+    match message {
+        ProtocolMessage::KeyBundle { .. } => message.check_bounds(limits),
+        _ => Err(anyhow::anyhow!("Expected a key bundle")),
+    }
+}
+
+impl ProtocolMessage {
+    /// Encode for transmission over the network
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        wire::encode(self)
+            .context("Failed to encode protocol message")
+    }
+    
+    /// Decode a frame received from the network
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        wire::decode(data)
+            .context("Failed to decode protocol message")
+    }
+    
+    /// Decode a frame from a peer, rejecting oversized frames and messages
+    /// whose fields are out of bounds. Never panics on any input.
+    pub fn decode_untrusted(data: &[u8]) -> Result<Self> {
+        Self::decode_within(data, &ProtocolLimits::default())
+    }
+    
+    /// `decode_untrusted` with the given limits. Errors for anything out of
+    /// bounds carry a `LimitError`.
+    pub fn decode_within(data: &[u8], limits: &ProtocolLimits) -> Result<Self> {
+        limits.check_frame(data.len())?;
+        let message = Self::decode(data)?;
+        message.check_bounds(limits)?;
+        Ok(message)
+    }
+    
+    fn check_bounds(&self, limits: &ProtocolLimits) -> Result<()> {
+        match self {
+            Self::KeyBundle { signed_prekey_signature, one_time_prekeys, .. } => {
+                check_len("signature", signed_prekey_signature.len(), MAX_SIGNATURE_LEN)?;
+                limits.check_prekeys(one_time_prekeys.len())?;
+            }
+            Self::Encrypted { envelope } => envelope.check_bounds(limits)?,
+            Self::DeliveryReceipt { message_id, .. } | Self::ReadReceipt { message_id, .. } => {
+                check_len("message id", message_id.len(), MAX_ID_LEN)?;
+            }
+            Self::Typing { conversation_id, .. } => check_len("conversation id", conversation_id.len(), MAX_ID_LEN)?,
+            Self::ProfileUpdate { display_name, status_message, avatar_hash } => {
+                for (what, text) in [("display name", display_name), ("status message", status_message), ("avatar hash", avatar_hash)] {
+                    limits.check_text(what, text.as_deref().unwrap_or_default())?;
+                }
+            }
+            Self::ContactRequest { display_name, message, key_bundle, .. } => {
+                limits.check_text("display name", display_name)?;
+                limits.check_text("contact request message", message)?;
+                check_key_bundle(key_bundle, limits)?;
+            }
+            Self::ContactResponse { key_bundle: Some(key_bundle), .. } => {
+                check_key_bundle(key_bundle, limits)?;
+            }
+            Self::AttachmentChunk { ciphertext, .. } => {
+                // The chunk plus the AEAD tag
+                check_len("attachment chunk", ciphertext.len(), ATTACHMENT_CHUNK_SIZE + 16)?;
+            }
+            Self::SyncRequest { device_id, .. } => check_len("device id", device_id.len(), MAX_ID_LEN)?,
+            Self::HistorySyncRequest { device_id, signature, .. } | Self::HistoryBatch { device_id, signature, .. } => {
+                check_len("device id", device_id.len(), MAX_ID_LEN)?;
+                check_len("signature", signature.len(), MAX_SIGNATURE_LEN)?;
+            }
+            Self::DeviceAnnouncement { announcement } => {
+                check_len("device id", announcement.device.device_id.len(), MAX_ID_LEN)?;
+                check_len("signature", announcement.signature.len(), MAX_SIGNATURE_LEN)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    
+    /// Id of the chat message this carries, if any
+    pub fn message_id(&self) -> Option<&str> {
+        match self {
+            Self::Encrypted { envelope } => Some(&envelope.id),
+            _ => None,
+        }
+    }
+    
+    /// Signals that are meaningless once stale, or announced afresh when
+    /// the network comes up, so never queued for later
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(self, Self::Presence { .. } | Self::Typing { .. } | Self::PrivacyUpdate { .. } | Self::Pairing { .. } | Self::DeviceAnnouncement { .. })
+    }
+}
+
+/// Wire encoding for everything that leaves the device.
+///
+/// Frames are `[magic:2][version:1][CBOR payload]`. CBOR is self-describing:
+/// enum variants are tagged by name and struct fields by key, so reordering
+/// variants or adding `#[serde(default)]` fields does not break older peers.
+/// bincode is still used for local storage, where both ends share a build.
+pub mod wire {
+    use anyhow::{Result, Context};
+    use serde::{Serialize, de::DeserializeOwned};
+    
+    /// Frame magic ("SC")
+    pub const MAGIC: [u8; 2] = *b"SC";
+    
+    /// Current wire format version
+    pub const VERSION: u8 = 1;
+    
+    /// Length of the frame header preceding the CBOR payload
+    pub const HEADER_LEN: usize = 3;
+    
+    pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        let mut frame = Vec::with_capacity(64);
+        frame.extend_from_slice(&MAGIC);
+        frame.push(VERSION);
+        ciborium::into_writer(value, &mut frame)
+            .map_err(|e| anyhow::anyhow!("CBOR encoding failed: {}", e))?;
+        Ok(frame)
+    }
+    
+    pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+        let version = frame_version(data)?;
+        if version != VERSION {
+            return Err(anyhow::anyhow!("Unsupported wire version {}", version));
+        }
+        ciborium::from_reader(&data[HEADER_LEN..])
+            .map_err(|e| anyhow::anyhow!("CBOR decoding failed: {}", e))
+            .context("Malformed frame payload")
+    }
+    
+    /// Read the version byte of a frame without decoding the payload
+    pub fn frame_version(data: &[u8]) -> Result<u8> {
+        if data.len() < HEADER_LEN || data[..2] != MAGIC {
+            return Err(anyhow::anyhow!("Not a SecureChat frame"));
+        }
+        Ok(data[2])
+    }
+    
+    /// Serde adapter that keeps a wire type as a frame inside a bincode
+    /// record. Wire types skip absent optional fields, which bincode can't
+    /// read back. Use with `#[serde(with = "wire::framed")]`.
+    pub mod framed {
+        use serde::{Deserialize, Deserializer, Serialize, Serializer, de::DeserializeOwned};
+        
+        pub fn serialize<T: Serialize, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+            let frame = super::encode(value).map_err(serde::ser::Error::custom)?;
+            serializer.serialize_bytes(&frame)
+        }
+        
+        pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+            let frame = Vec::<u8>::deserialize(deserializer)?;
+            super::decode(&frame).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+/// ASCII-armored identities, for pasting into email or a forum post.
+///
+/// ```text
+/// -----BEGIN SECURECHAT IDENTITY-----
+/// Version: 1
+///
+/// <base64 of a wire-encoded IdentityBlock, 64 columns>
+/// =<base64 of the first 3 bytes of the payload's BLAKE3 hash>
+/// -----END SECURECHAT IDENTITY-----
+/// ```
+///
+/// The block is signed with the identity key it carries, so a pasted
+/// name can't be swapped for another; the checksum only catches text
+/// mangled on the way, before the signature is looked at. Lines may be
+/// quoted with `>` and surrounded by other text.
+pub mod armor {
+    use anyhow::{Result, Context};
+    use base64::Engine;
+    use ed25519_dalek::{Signature, VerifyingKey};
+    use serde::{Serialize, Deserialize};
+    use time::OffsetDateTime;
+    
+    use crate::crypto::{Fingerprint, IdentityKeyPair};
+    use super::wire;
+    
+    pub const BEGIN: &str = "-----BEGIN SECURECHAT IDENTITY-----";
+    pub const END: &str = "-----END SECURECHAT IDENTITY-----";
+    pub const VERSION: u8 = 1;
+    const LINE_LEN: usize = 64;
+    /// Longest armored block read, in bytes
+    const MAX_ARMOR_LEN: usize = 16 * 1024;
+    
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct IdentityBlock {
+        pub identity_key: [u8; 32],
+        pub display_name: String,
+        pub created_at: OffsetDateTime,
+        signature: Vec<u8>,
+    }
+    
+    impl IdentityBlock {
+        pub fn fingerprint(&self) -> Fingerprint {
+            Fingerprint::of_key(&self.identity_key)
+        }
+        
+        fn signing_bytes(identity_key: &[u8; 32], display_name: &str, created_at: &OffsetDateTime) -> Result<Vec<u8>> {
+            wire::encode(&(b"SecureChat identity v1", identity_key, display_name, created_at))
+        }
+        
+        fn verify(&self) -> Result<()> {
+            let key = VerifyingKey::from_bytes(&self.identity_key)
+                .context("Invalid identity key")?;
+            let signature = Signature::from_slice(&self.signature)
+                .context("Malformed signature")?;
+            IdentityKeyPair::verify(&key, &Self::signing_bytes(&self.identity_key, &self.display_name, &self.created_at)?, &signature)
+                .context("Identity block has been tampered with")
+        }
+    }
+    
+    /// `identity` and our display name as an armored block
+    pub fn generate(identity: &IdentityKeyPair, display_name: &str) -> Result<String> {
+        let identity_key = identity.public_key.to_bytes();
+        let created_at = OffsetDateTime::now_utc();
+        let signature = identity.sign(&IdentityBlock::signing_bytes(&identity_key, display_name, &created_at)?);
+        let payload = wire::encode(&IdentityBlock {
+            identity_key,
+            display_name: display_name.to_string(),
+            created_at,
+            signature: signature.to_bytes().to_vec(),
+        })?;
+        
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&payload);
+        let mut armored = format!("{}\nVersion: {}\n\n", BEGIN, VERSION);
+        for line in encoded.as_bytes().chunks(LINE_LEN) {
+            armored.push_str(std::str::from_utf8(line)?);
+            armored.push('\n');
+        }
+        armored.push('=');
+        armored.push_str(&checksum(&payload));
+        armored.push('\n');
+        armored.push_str(END);
+        armored.push('\n');
+        Ok(armored)
+    }
+    
+    /// The first armored block in `text`, checked and verified
+    pub fn parse(text: &str) -> Result<IdentityBlock> {
+        if text.len() > MAX_ARMOR_LEN {
+            return Err(anyhow::anyhow!("Too much text for an identity block"));
+        }
+        let lines = text.lines()
+            .map(|line| line.trim_start_matches(|c: char| c == '>' || c.is_whitespace()).trim_end())
+            .skip_while(|line| *line != BEGIN)
+            .skip(1);
+        
+        let mut version = None;
+        let mut body = String::new();
+        let mut sum = None;
+        let mut ended = false;
+        for line in lines {
+            if line == END {
+                ended = true;
+                break;
+            }
+            if let Some((name, value)) = line.split_once(": ") {
+                if name.eq_ignore_ascii_case("Version") {
+                    version = Some(value.trim().parse::<u8>().context("Malformed version")?);
+                }
+            } else if let Some(value) = line.strip_prefix('=') {
+                sum = Some(value.to_string());
+            } else {
+                body.push_str(line);
+            }
+        }
+        if !ended {
+            return Err(anyhow::anyhow!("No complete SecureChat identity block found"));
+        }
+        match version {
+            Some(VERSION) => {}
+            Some(other) => return Err(anyhow::anyhow!("Unsupported identity block version {}", other)),
+            None => return Err(anyhow::anyhow!("Identity block has no version")),
+        }
+        
+        let payload = base64::engine::general_purpose::STANDARD.decode(&body)
+            .context("Identity block is corrupt")?;
+        if sum.as_deref() != Some(checksum(&payload).as_str()) {
+            return Err(anyhow::anyhow!("Identity block checksum mismatch; was it copied in full?"));
+        }
+        let block: IdentityBlock = wire::decode(&payload)?;
+        super::check_len("display name", block.display_name.len(), crate::limits::ProtocolLimits::default().max_text_len)?;
+        block.verify()?;
+        Ok(block)
+    }
+    
+    fn checksum(payload: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(&blake3::hash(payload).as_bytes()[..3])
+    }
 }
 
 use base64;
 
 // blake3 re-export for fingerprinting
 pub use blake3;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    
+    fn fixed_time() -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap()
+    }
+    
+    fn sample_key_bundle() -> ProtocolMessage {
+        ProtocolMessage::KeyBundle {
+            identity_key: [1u8; 32],
+            signed_prekey: [2u8; 32],
+            signed_prekey_signature: vec![3u8; 4],
+            one_time_prekeys: vec![[4u8; 32]],
+        }
+    }
+    
+    fn sample_envelope() -> MessageEnvelope {
+        MessageEnvelope {
+            id: "msg-1".to_string(),
+            sender_id: "alice".to_string(),
+            recipient_id: "bob".to_string(),
+            timestamp: fixed_time(),
+            encrypted_content: EncryptedMessage {
+                ciphertext: vec![0xAA; 8],
+                nonce: [5u8; 12],
+                sender_pubkey: [6u8; 32],
+                ephemeral_pubkey: [7u8; 32],
+            },
+            signature: vec![8u8; 4],
+            reply_to: None,
+            ratchet_header: None,
+            lamport: None,
+            invite: None,
+            thumbnail: None,
+            sender_device: None,
+            recipient_device: None,
+            message_id: None,
+            view_once: false,
+            policy: None,
+            capabilities: None,
+        }
+    }
+    
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+    
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+    
+    // Golden vectors for wire version 1. Changing these means older peers
+    // can no longer talk to us, so only regenerate on a deliberate bump.
+    const GOLDEN_RECEIPT: &str = "534301a364747970656f44656c6976657279526563656970746a6d6573736167655f6964656d73672d316974696d657374616d70891907e719013e160d1400000000";
+    const GOLDEN_TYPING: &str = "534301a3647479706566547970696e676f636f6e766572736174696f6e5f696461636969735f747970696e67f5";
+    const GOLDEN_KEY_BUNDLE: &str = "534301a56474797065694b657942756e646c656c6964656e746974795f6b6579982001010101010101010101010101010101010101010101010101010101010101016d7369676e65645f7072656b657998200202020202020202020202020202020202020202020202020202020202020202777369676e65645f7072656b65795f7369676e61747572658403030303706f6e655f74696d655f7072656b6579738198200404040404040404040404040404040404040404040404040404040404040404";
+    const GOLDEN_ENVELOPE: &str = "534301a7626964656d73672d316973656e6465725f696465616c6963656c726563697069656e745f696463626f626974696d657374616d70891907e719013e160d140000000071656e637279707465645f636f6e74656e74a46a636970686572746578748818aa18aa18aa18aa18aa18aa18aa18aa656e6f6e63658c0505050505050505050505056d73656e6465725f7075626b65799820060606060606060606060606060606060606060606060606060606060606060670657068656d6572616c5f7075626b657998200707070707070707070707070707070707070707070707070707070707070707697369676e61747572658408080808687265706c795f746ff6";
+    
+    #[test]
+    fn test_protocol_golden_vectors() {
+        let receipt = ProtocolMessage::DeliveryReceipt {
+            message_id: "msg-1".to_string(),
+            timestamp: fixed_time(),
+        };
+        let typing = ProtocolMessage::Typing {
+            conversation_id: "c".to_string(),
+            is_typing: true,
+        };
+        
+        for (message, golden) in [
+            (receipt, GOLDEN_RECEIPT),
+            (typing, GOLDEN_TYPING),
+            (sample_key_bundle(), GOLDEN_KEY_BUNDLE),
+        ] {
+            assert_eq!(hex(&message.encode().unwrap()), golden);
+            let decoded = ProtocolMessage::decode(&unhex(golden)).unwrap();
+            assert_eq!(hex(&decoded.encode().unwrap()), golden);
+        }
+        
+        match ProtocolMessage::decode(&unhex(GOLDEN_RECEIPT)).unwrap() {
+            ProtocolMessage::DeliveryReceipt { message_id, timestamp } => {
+                assert_eq!(message_id, "msg-1");
+                assert_eq!(timestamp, fixed_time());
+            }
+            other => panic!("unexpected variant: {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn test_envelope_golden_vector() {
+        assert_eq!(hex(&sample_envelope().serialize().unwrap()), GOLDEN_ENVELOPE);
+        
+        let decoded = MessageEnvelope::deserialize(&unhex(GOLDEN_ENVELOPE)).unwrap();
+        assert_eq!(decoded.id, "msg-1");
+        assert_eq!(decoded.timestamp, fixed_time());
+        assert_eq!(decoded.encrypted_content.ephemeral_pubkey, [7u8; 32]);
+        assert_eq!(hex(&decoded.serialize().unwrap()), GOLDEN_ENVELOPE);
+    }
+    
+    #[test]
+    fn test_content_aad_binds_header() {
+        let mut envelope = sample_envelope();
+        assert!(envelope.content_aad().unwrap().is_empty());
+        
+        let transcript = CapabilityTranscript { sender: Capabilities::SUPPORTED, recipient: Capabilities::TRANSCRIPT };
+        envelope.capabilities = Some(transcript);
+        assert_eq!(envelope.content_aad().unwrap(), transcript.aad().unwrap());
+        
+        envelope.capabilities = Some(CapabilityTranscript { recipient: Capabilities::SUPPORTED, ..transcript });
+        let bound = envelope.content_aad().unwrap();
+        let mut moved = envelope.clone();
+        moved.id = "msg-2".to_string();
+        assert_ne!(moved.content_aad().unwrap(), bound);
+        // Receivers put their own id for the sender in
+        let mut resolved = envelope.clone();
+        resolved.sender_id = "contact-7".to_string();
+        assert_eq!(resolved.content_aad().unwrap(), bound);
+    }
+    
+    #[test]
+    fn test_wire_tolerates_unknown_fields() {
+        // A newer peer may add fields; older decoders must ignore them
+        let mut value: ciborium::Value = ciborium::from_reader(
+            &unhex(GOLDEN_TYPING)[wire::HEADER_LEN..]
+        ).unwrap();
+        if let ciborium::Value::Map(entries) = &mut value {
+            entries.push(("added_in_v2".into(), 42.into()));
+        }
+        let mut frame = vec![wire::MAGIC[0], wire::MAGIC[1], wire::VERSION];
+        ciborium::into_writer(&value, &mut frame).unwrap();
+        
+        match ProtocolMessage::decode(&frame).unwrap() {
+            ProtocolMessage::Typing { conversation_id, is_typing } => {
+                assert_eq!(conversation_id, "c");
+                assert!(is_typing);
+            }
+            other => panic!("unexpected variant: {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn test_wire_rejects_bad_frames() {
+        let mut frame = unhex(GOLDEN_TYPING);
+        frame[2] = wire::VERSION + 1;
+        assert!(ProtocolMessage::decode(&frame).is_err());
+        
+        assert!(ProtocolMessage::decode(b"XX\x01").is_err());
+        assert!(ProtocolMessage::decode(&[]).is_err());
+        assert!(ProtocolMessage::decode(&unhex(GOLDEN_TYPING)[..10]).is_err());
+    }
+    
+    #[test]
+    fn test_decode_untrusted_enforces_bounds() {
+        assert!(ProtocolMessage::decode_untrusted(&unhex(GOLDEN_KEY_BUNDLE)).is_ok());
+        assert!(ProtocolMessage::decode_untrusted(&vec![0u8; MAX_FRAME_LEN + 1]).unwrap_err().to_string().contains("limit"));
+        
+        let long_id = ProtocolMessage::DeliveryReceipt { message_id: "m".repeat(MAX_ID_LEN + 1), timestamp: fixed_time() };
+        assert!(ProtocolMessage::decode(&long_id.encode().unwrap()).is_ok());
+        assert!(ProtocolMessage::decode_untrusted(&long_id.encode().unwrap()).is_err());
+        
+        // A contact request may only carry a key bundle, not another request
+        let request = |key_bundle| ProtocolMessage::ContactRequest {
+            display_name: "Bob".to_string(),
+            message: String::new(),
+            key_bundle: Box::new(key_bundle),
+            stamp: None,
+        };
+        assert!(ProtocolMessage::decode_untrusted(&request(sample_key_bundle()).encode().unwrap()).is_ok());
+        let nested = request(request(sample_key_bundle()));
+        assert!(ProtocolMessage::decode_untrusted(&nested.encode().unwrap()).is_err());
+        
+        let mut envelope = sample_envelope();
+        envelope.signature = vec![0; 4096];
+        assert!(MessageEnvelope::deserialize_untrusted(&envelope.serialize().unwrap()).is_err());
+        assert!(MessageEnvelope::deserialize_untrusted(&unhex(GOLDEN_ENVELOPE)).is_ok());
+    }
+    
+    #[test]
+    fn test_protocol_limits() {
+        use crate::limits::LimitError;
+        let limits = ProtocolLimits { max_text_len: 8, max_prekeys: 1, ..Default::default() };
+        assert!(ProtocolMessage::decode_within(&sample_key_bundle().encode().unwrap(), &limits).is_ok());
+        let mut bundle = sample_key_bundle();
+        if let ProtocolMessage::KeyBundle { one_time_prekeys, .. } = &mut bundle {
+            one_time_prekeys.push([5u8; 32]);
+        }
+        let error = ProtocolMessage::decode_within(&bundle.encode().unwrap(), &limits).unwrap_err();
+        assert!(matches!(error.downcast_ref::<LimitError>(), Some(LimitError::TooManyPrekeys { .. })));
+        let frame = vec![0u8; 100];
+        let error = ProtocolMessage::decode_within(&frame, &ProtocolLimits { max_frame_len: 99, ..limits }).unwrap_err();
+        assert_eq!(error.downcast_ref::<LimitError>(), Some(&LimitError::FrameTooLarge { len: 100, max: 99 }));
+        
+        assert!(MessageContent::Text { text: "short".to_string() }.check_bounds(&limits).is_ok());
+        let error = MessageContent::Text { text: "far too long".to_string() }.check_bounds(&limits).unwrap_err();
+        assert!(matches!(error.downcast_ref::<LimitError>(), Some(LimitError::TooLong { what: "text", .. })));
+        let image = MessageContent::Image {
+            attachment: AttachmentRef { digest: [0; 32], size: 2 << 30, key: [0; 32] },
+            mime_type: "image/png".to_string(),
+            caption: None,
+        };
+        let error = image.check_bounds(&ProtocolLimits::default()).unwrap_err();
+        assert!(matches!(error.downcast_ref::<LimitError>(), Some(LimitError::AttachmentTooLarge { .. })));
+    }
+    
+    #[test]
+    fn test_preview_text() {
+        let preview = |content| LocalMessage {
+            id: "m".to_string(),
+            conversation_id: "c".to_string(),
+            sender_id: "s".to_string(),
+            is_outgoing: false,
+            content,
+            timestamp: fixed_time(),
+            status: DeliveryStatus::Delivered,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport: 0,
+        }.preview_text();
+        // 150 two-byte characters: byte 100 is inside the 51st
+        let long = "é".repeat(150);
+        assert_eq!(preview(MessageContent::Text { text: long }), format!("{}...", "é".repeat(100)));
+        assert_eq!(preview(MessageContent::Text { text: "line one\nline two".to_string() }), "line one line two");
+        let contact = MessageContent::Contact { name: "Eve\u{202E}".to_string(), public_key: [0; 32] };
+        assert_eq!(preview(contact), "👤 Contact: \u{2068}Eve\u{2069}");
+    }
+    
+    #[test]
+    fn test_local_message_storage_roundtrip() {
+        let message = LocalMessage {
+            id: "m".to_string(),
+            conversation_id: "c".to_string(),
+            sender_id: "self".to_string(),
+            is_outgoing: true,
+            content: MessageContent::Text { text: "hi".to_string() },
+            timestamp: fixed_time(),
+            status: DeliveryStatus::Queued,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport: 0,
+        };
+        
+        let bytes = bincode::serialize(&message).unwrap();
+        let decoded: LocalMessage = bincode::deserialize(&bytes).unwrap();
+        assert!(matches!(decoded.content, MessageContent::Text { ref text } if text == "hi"));
+    }
+    
+    #[test]
+    fn test_delivery_status_transitions() {
+        use DeliveryStatus::*;
+        let failed = Failed { reason: "no peers".to_string() };
+        
+        assert!(Queued.can_become(&Sending) && Sending.can_become(&Sent) && Sent.can_become(&Delivered));
+        assert!(Queued.can_become(&Read));
+        assert!(!Sent.can_become(&Sending) && !Delivered.can_become(&Sent) && !Read.can_become(&Delivered));
+        assert!(!Sent.can_become(&Sent));
+        assert!(Sending.can_become(&Queued) && !Sent.can_become(&Queued));
+        
+        assert!(Sent.can_become(&failed) && !Delivered.can_become(&failed));
+        assert!(failed.can_become(&Queued) && failed.can_become(&Delivered));
+        assert!(!failed.can_become(&Sent) && !failed.can_become(&failed));
+    }
+    
+    #[test]
+    fn test_privacy_settings_gate_signals() {
+        let receipt = ProtocolMessage::ReadReceipt { message_id: "m".to_string(), timestamp: fixed_time() };
+        let typing = ProtocolMessage::Typing { conversation_id: "c".to_string(), is_typing: true };
+        let presence = ProtocolMessage::Presence { online: true };
+        let delivery = ProtocolMessage::DeliveryReceipt { message_id: "m".to_string(), timestamp: fixed_time() };
+        
+        let open = PrivacySettings::default();
+        assert!([&receipt, &typing, &presence, &delivery].iter().all(|m| open.allows(m)));
+        
+        let closed = PrivacySettings { send_read_receipts: false, send_typing: false, share_presence: false };
+        assert!([&receipt, &typing, &presence].iter().all(|m| !closed.allows(m)));
+        assert!(closed.allows(&delivery));
+    }
+    
+    #[test]
+    fn test_identity_armor() {
+        let identity = crate::crypto::IdentityKeyPair::generate(&mut rand::rngs::OsRng);
+        let armored = armor::generate(&identity, "Alice").unwrap();
+        assert!(armored.starts_with(armor::BEGIN));
+        assert!(armored.lines().all(|line| line.len() <= 64));
+        
+        // Quoted in a reply, amid other text
+        let quoted: String = armored.lines().map(|line| format!("> {}\r\n", line)).collect();
+        let block = armor::parse(&format!("My key:\n{}\nCheers", quoted)).unwrap();
+        assert_eq!(block.identity_key, identity.public_key.to_bytes());
+        assert_eq!(block.display_name, "Alice");
+        
+        // Truncated, of an unknown version, or corrupted
+        let lines: Vec<&str> = armored.lines().collect();
+        assert!(armor::parse(&lines[..lines.len() - 1].join("\n")).is_err());
+        assert!(armor::parse(&armored.replacen("Version: 1", "Version: 2", 1)).is_err());
+        let body = lines[3];
+        let flipped = if body.starts_with('A') { body.replacen('A', "B", 1) } else { format!("A{}", &body[1..]) };
+        assert!(armor::parse(&armored.replacen(body, &flipped, 1)).is_err());
+    }
+    
+    fn arb_time() -> impl Strategy<Value = OffsetDateTime> {
+        (0i128..4_000_000_000_000_000_000).prop_map(|nanos| OffsetDateTime::from_unix_timestamp_nanos(nanos).unwrap())
+    }
+    
+    fn arb_key_bundle() -> impl Strategy<Value = ProtocolMessage> {
+        (any::<[u8; 32]>(), any::<[u8; 32]>(), prop::collection::vec(any::<u8>(), 0..80), prop::collection::vec(any::<[u8; 32]>(), 0..4))
+            .prop_map(|(identity_key, signed_prekey, signed_prekey_signature, one_time_prekeys)| ProtocolMessage::KeyBundle {
+                identity_key,
+                signed_prekey,
+                signed_prekey_signature,
+                one_time_prekeys,
+            })
+    }
+    
+    fn arb_envelope() -> impl Strategy<Value = MessageEnvelope> {
+        let header = (any::<u32>(), any::<u32>(), any::<u32>())
+            .prop_map(|(chain, previous_chain_length, message_number)| RatchetHeader { chain, previous_chain_length, message_number });
+        (
+            (".{0,24}", ".{0,24}", ".{0,24}", arb_time()),
+            (prop::collection::vec(any::<u8>(), 0..256), any::<[u8; 12]>(), any::<[u8; 32]>(), any::<[u8; 32]>()),
+            (prop::collection::vec(any::<u8>(), 0..80), proptest::option::of(".{0,24}"), proptest::option::of(header), proptest::option::of(any::<u64>())),
+        ).prop_map(|((id, sender_id, recipient_id, timestamp), (ciphertext, nonce, sender_pubkey, ephemeral_pubkey), (signature, reply_to, ratchet_header, lamport))| {
+            MessageEnvelope {
+                id,
+                sender_id,
+                recipient_id,
+                timestamp,
+                encrypted_content: EncryptedMessage { ciphertext, nonce, sender_pubkey, ephemeral_pubkey },
+                signature,
+                reply_to,
+                ratchet_header,
+                lamport,
+                invite: None,
+                thumbnail: None,
+                sender_device: None,
+                recipient_device: None,
+                message_id: None,
+                view_once: false,
+                policy: None,
+                capabilities: None,
+            }
+        })
+    }
+    
+    fn arb_message() -> impl Strategy<Value = ProtocolMessage> {
+        prop_oneof![
+            arb_key_bundle(),
+            arb_envelope().prop_map(|envelope| ProtocolMessage::Encrypted { envelope }),
+            (".{0,24}", arb_time()).prop_map(|(message_id, timestamp)| ProtocolMessage::DeliveryReceipt { message_id, timestamp }),
+            (".{0,24}", arb_time()).prop_map(|(message_id, timestamp)| ProtocolMessage::ReadReceipt { message_id, timestamp }),
+            (".{0,24}", any::<bool>()).prop_map(|(conversation_id, is_typing)| ProtocolMessage::Typing { conversation_id, is_typing }),
+            any::<bool>().prop_map(|online| ProtocolMessage::Presence { online }),
+            (any::<bool>(), any::<bool>(), any::<bool>()).prop_map(|(send_read_receipts, send_typing, share_presence)| {
+                ProtocolMessage::PrivacyUpdate { settings: PrivacySettings { send_read_receipts, send_typing, share_presence } }
+            }),
+            (proptest::option::of(".{0,24}"), proptest::option::of(".{0,24}"), proptest::option::of("[0-9a-f]{64}"))
+                .prop_map(|(display_name, status_message, avatar_hash)| ProtocolMessage::ProfileUpdate { display_name, status_message, avatar_hash }),
+            (".{0,24}", ".{0,64}", arb_key_bundle()).prop_map(|(display_name, message, key_bundle)| {
+                ProtocolMessage::ContactRequest { display_name, message, key_bundle: Box::new(key_bundle), stamp: None }
+            }),
+            (any::<bool>(), proptest::option::of(arb_key_bundle()))
+                .prop_map(|(accepted, key_bundle)| ProtocolMessage::ContactResponse { accepted, key_bundle: key_bundle.map(Box::new) }),
+            (any::<[u8; 32]>(), any::<u32>(), prop::collection::vec(any::<u8>(), 0..512))
+                .prop_map(|(transfer_id, index, ciphertext)| ProtocolMessage::AttachmentChunk { transfer_id, index, ciphertext }),
+            (".{0,24}", any::<[u8; 32]>()).prop_map(|(device_id, nonce)| ProtocolMessage::SyncRequest { device_id, nonce }),
+        ]
+    }
+    
+    proptest! {
+        /// Anything we send decodes to the same message, and re-encodes to
+        /// the same bytes
+        #[test]
+        fn prop_wire_round_trip(message in arb_message()) {
+            let frame = message.encode().unwrap();
+            let decoded = ProtocolMessage::decode(&frame).unwrap();
+            prop_assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+            prop_assert_eq!(decoded.encode().unwrap(), frame);
+        }
+        
+        #[test]
+        fn prop_envelope_round_trip(envelope in arb_envelope()) {
+            let frame = envelope.serialize().unwrap();
+            let decoded = MessageEnvelope::deserialize(&frame).unwrap();
+            prop_assert_eq!(format!("{:?}", decoded), format!("{:?}", envelope));
+            prop_assert_eq!(decoded.serialize().unwrap(), frame);
+        }
+    }
+}
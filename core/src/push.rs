@@ -0,0 +1,111 @@
+//! Wake-up pings through a push relay.
+//!
+//! Mobile apps can't keep the network running in the background. A device
+//! registers a push endpoint, e.g. a UnifiedPush URL, and shares it with
+//! its contacts. A contact that can't reach the device directly sends a
+//! wake-up ping to the endpoint through a `PushBridge`; the app wakes,
+//! starts the network and fetches its messages the usual way. A ping is
+//! random bytes: the relay learns that someone wanted the device awake,
+//! nothing about who or why.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::protocol::MAX_ID_LEN;
+
+pub const MAX_ENDPOINT_URL_LEN: usize = 1024;
+/// Most endpoints kept for one contact
+pub const MAX_ENDPOINTS_PER_CONTACT: usize = 16;
+/// Shortest time between pings to the same contact
+pub const MIN_WAKE_INTERVAL: Duration = Duration::from_secs(60);
+pub const WAKE_PING_LEN: usize = 16;
+
+/// Where one device can be woken
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushEndpoint {
+    pub device_id: String,
+    pub url: String,
+    pub registered_at: OffsetDateTime,
+}
+
+impl PushEndpoint {
+    pub fn new(device_id: &str, url: &str) -> Result<Self> {
+        let endpoint = Self {
+            device_id: device_id.to_string(),
+            url: url.trim().to_string(),
+            registered_at: OffsetDateTime::now_utc(),
+        };
+        endpoint.validate()?;
+        Ok(endpoint)
+    }
+    
+    pub fn validate(&self) -> Result<()> {
+        if self.device_id.is_empty() || self.device_id.len() > MAX_ID_LEN {
+            return Err(anyhow::anyhow!("Invalid device id"));
+        }
+        if self.url.len() > MAX_ENDPOINT_URL_LEN {
+            return Err(anyhow::anyhow!("Push endpoint is longer than {} bytes", MAX_ENDPOINT_URL_LEN));
+        }
+        // Pings must not go out in the clear to just anyone
+        if !self.url.starts_with("https://") || self.url.len() == "https://".len() {
+            return Err(anyhow::anyhow!("Push endpoints must be https URLs"));
+        }
+        Ok(())
+    }
+}
+
+/// Delivers wake-up pings to push endpoints
+pub trait PushBridge: Send + Sync {
+    /// Post `ping` to the endpoint at `url`
+    fn wake(&self, url: &str, ping: &[u8]) -> Result<()>;
+}
+
+/// A fresh ping, unlinkable to any other
+pub fn wake_ping() -> [u8; WAKE_PING_LEN] {
+    let mut ping = [0u8; WAKE_PING_LEN];
+    rand::thread_rng().fill_bytes(&mut ping);
+    ping
+}
+
+/// Bridge that only records pings, for tests
+#[derive(Debug, Default)]
+pub struct MemoryPushBridge {
+    pings: Mutex<Vec<(String, Vec<u8>)>>,
+}
+
+impl MemoryPushBridge {
+    /// Every ping so far, with the URL it went to
+    pub fn pings(&self) -> Vec<(String, Vec<u8>)> {
+        self.pings.lock().map(|pings| pings.clone()).unwrap_or_default()
+    }
+}
+
+impl PushBridge for MemoryPushBridge {
+    fn wake(&self, url: &str, ping: &[u8]) -> Result<()> {
+        self.pings.lock()
+            .map_err(|_| anyhow::anyhow!("Push bridge lock poisoned"))?
+            .push((url.to_string(), ping.to_vec()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_push_endpoint_validation() {
+        let endpoint = PushEndpoint::new("phone", " https://push.example.org/up/abc ").unwrap();
+        assert_eq!(endpoint.url, "https://push.example.org/up/abc");
+        assert!(PushEndpoint::new("phone", "http://push.example.org/up/abc").is_err());
+        assert!(PushEndpoint::new("phone", "https://").is_err());
+        assert!(PushEndpoint::new("", "https://push.example.org").is_err());
+        assert!(PushEndpoint::new("phone", &format!("https://{}", "a".repeat(MAX_ENDPOINT_URL_LEN))).is_err());
+        assert_ne!(wake_ping(), wake_ping());
+    }
+}
@@ -0,0 +1,145 @@
+//! Client puzzles for contact requests.
+//!
+//! Contact requests go out over gossipsub to anyone, so sending thousands
+//! would cost a spammer nothing. Each request carries a hashcash-style
+//! `Stamp` instead: a nonce found by brute force such that a BLAKE3 hash
+//! over the sender's identity key, a digest of the request, the time and
+//! the nonce starts with `difficulty` zero bits. Checking takes one hash;
+//! minting takes about `2^difficulty`. Requests from keys we already know
+//! need no stamp.
+//!
+//! A stamp only fits the request it was minted for, and the receiver keeps
+//! the hashes of those it accepted until they expire, so each stamp buys
+//! one request.
+
+use crate::protocol::ProtocolMessage;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use time::{Duration, OffsetDateTime};
+
+/// About a quarter of a million hashes, well under a second on a phone
+pub const DEFAULT_DIFFICULTY: u8 = 18;
+/// Highest difficulty that can be required, so requests stay sendable
+pub const MAX_DIFFICULTY: u8 = 28;
+/// Stamps older than this are refused, so they can't be stockpiled
+pub const MAX_STAMP_AGE: Duration = Duration::hours(24);
+/// Clock skew tolerated for stamps from the future
+const MAX_CLOCK_SKEW: Duration = Duration::minutes(5);
+
+const DOMAIN: &[u8] = b"securechat contact request stamp v2";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    pub difficulty: u8,
+    /// Unix time the stamp was minted, in seconds
+    pub minted_at: i64,
+    pub nonce: u64,
+}
+
+impl Stamp {
+    /// Find a stamp for `identity_key` and the request with `digest`, see
+    /// `request_digest`. Takes about `2^difficulty` hashes, so run it off
+    /// the async executor.
+    pub fn mint(identity_key: &[u8; 32], digest: &[u8; 32], difficulty: u8, now: OffsetDateTime) -> Result<Self> {
+        check_difficulty(difficulty)?;
+        let mut stamp = Self { difficulty, minted_at: now.unix_timestamp(), nonce: 0 };
+        while !stamp.solves(identity_key, digest) {
+            stamp.nonce += 1;
+        }
+        Ok(stamp)
+    }
+
+    /// Check the stamp is recent, for `identity_key` and the request with
+    /// `digest`, and at least as hard as `required`
+    pub fn verify(&self, identity_key: &[u8; 32], digest: &[u8; 32], required: u8, now: OffsetDateTime) -> Result<()> {
+        if self.difficulty < required {
+            return Err(anyhow::anyhow!("Stamp difficulty {} is below the required {}", self.difficulty, required));
+        }
+        let age = now.unix_timestamp() - self.minted_at;
+        if age > MAX_STAMP_AGE.whole_seconds() || -age > MAX_CLOCK_SKEW.whole_seconds() {
+            return Err(anyhow::anyhow!("Stamp has expired"));
+        }
+        if !self.solves(identity_key, digest) {
+            return Err(anyhow::anyhow!("Stamp does not solve its puzzle"));
+        }
+        Ok(())
+    }
+
+    /// When the stamp stops being accepted, as a Unix time in seconds
+    pub fn expires_at(&self) -> i64 {
+        self.minted_at + MAX_STAMP_AGE.whole_seconds()
+    }
+
+    /// The puzzle's hash, which names the stamp among those already used
+    pub fn hash(&self, identity_key: &[u8; 32], digest: &[u8; 32]) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(DOMAIN);
+        hasher.update(identity_key);
+        hasher.update(digest);
+        hasher.update(&[self.difficulty]);
+        hasher.update(&self.minted_at.to_be_bytes());
+        hasher.update(&self.nonce.to_be_bytes());
+        *hasher.finalize().as_bytes()
+    }
+
+    fn solves(&self, identity_key: &[u8; 32], digest: &[u8; 32]) -> bool {
+        leading_zero_bits(&self.hash(identity_key, digest)) >= u32::from(self.difficulty)
+    }
+}
+
+/// Digest of what a contact request says, which its stamp is bound to so
+/// it can't be moved onto another request
+pub fn request_digest(key_bundle: &ProtocolMessage, display_name: &str, message: &str) -> Result<[u8; 32]> {
+    let key_bundle = key_bundle.encode()?;
+    let mut hasher = blake3::Hasher::new();
+    for part in [&key_bundle[..], display_name.as_bytes(), message.as_bytes()] {
+        hasher.update(&(part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    Ok(*hasher.finalize().as_bytes())
+}
+
+pub fn check_difficulty(difficulty: u8) -> Result<()> {
+    if difficulty > MAX_DIFFICULTY {
+        return Err(anyhow::anyhow!("Puzzle difficulty is at most {}", MAX_DIFFICULTY));
+    }
+    Ok(())
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mint_and_verify() {
+        // A fixed time, so a wrong key or digest can't pass by luck
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let digest = [5u8; 32];
+        let stamp = Stamp::mint(&[1u8; 32], &digest, 8, now).unwrap();
+        stamp.verify(&[1u8; 32], &digest, 8, now).unwrap();
+        assert!(stamp.verify(&[1u8; 32], &digest, 9, now).is_err());
+        assert!(stamp.verify(&[2u8; 32], &digest, 8, now).is_err());
+        assert!(stamp.verify(&[1u8; 32], &[6u8; 32], 8, now).is_err());
+        assert!(stamp.verify(&[1u8; 32], &digest, 8, now + MAX_STAMP_AGE + Duration::seconds(1)).is_err());
+        assert!(stamp.verify(&[1u8; 32], &digest, 8, now - Duration::hours(1)).is_err());
+        assert!(Stamp::mint(&[1u8; 32], &digest, MAX_DIFFICULTY + 1, now).is_err());
+    }
+
+    #[test]
+    fn test_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0, 0x10, 0xff]), 11);
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+    }
+}
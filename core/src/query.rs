@@ -0,0 +1,146 @@
+//! Querying the conversation list.
+//!
+//! A `ConversationQuery` is evaluated in storage: conversations are
+//! filtered as they are read, sorted, and only the requested page is
+//! returned along with how many matched, so a large account doesn't hand
+//! the UI every conversation at once.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashSet;
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::protocol::{ChatFolder, Conversation};
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationFilter {
+    /// Only archived conversations, or only those not archived
+    pub archived: Option<bool>,
+    /// Only pinned conversations, or only those not pinned
+    pub pinned: Option<bool>,
+    /// Only conversations with unread messages
+    pub unread: bool,
+    /// Only conversations with contacts that have this label
+    pub label_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConversationSort {
+    /// Most recently active first
+    #[default]
+    Activity,
+    /// By the contact's nickname, or display name without one
+    Name,
+    /// Most unread messages first
+    Unread,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationQuery {
+    pub filter: ConversationFilter,
+    pub sort: ConversationSort,
+    /// Matching conversations skipped, in sort order
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// One page of a query's results
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationPage {
+    pub conversations: Vec<Conversation>,
+    /// Conversations matching the filter, across all pages
+    pub total: usize,
+}
+
+/// The conversations in one folder, most recently active first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderGroup {
+    /// None for conversations in no folder
+    pub folder: Option<ChatFolder>,
+    pub conversations: Vec<Conversation>,
+    /// Unread messages across the folder's conversations
+    pub unread_count: u32,
+}
+
+impl Default for ConversationQuery {
+    fn default() -> Self {
+        Self {
+            filter: ConversationFilter::default(),
+            sort: ConversationSort::default(),
+            offset: 0,
+            limit: DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+impl ConversationQuery {
+    pub fn validate(&self) -> Result<()> {
+        if self.limit == 0 || self.limit > MAX_PAGE_SIZE {
+            return Err(anyhow::anyhow!("Pages hold 1 to {} conversations", MAX_PAGE_SIZE));
+        }
+        Ok(())
+    }
+}
+
+impl ConversationFilter {
+    /// Whether `conversation` passes; `labelled` holds the contacts with
+    /// the filter's label
+    pub fn matches(&self, conversation: &Conversation, labelled: Option<&HashSet<String>>) -> bool {
+        self.archived.is_none_or(|archived| conversation.archived == archived)
+            && self.pinned.is_none_or(|pinned| conversation.pinned == pinned)
+            && (!self.unread || conversation.unread_count > 0)
+            && labelled.is_none_or(|contacts| contacts.contains(&conversation.contact_id))
+    }
+}
+
+impl ConversationSort {
+    /// Order of two conversations, given their names for `Name`. Ties go
+    /// to the most recently active, then by id, so pages don't shift.
+    pub fn compare(&self, a: (&Conversation, &str), b: (&Conversation, &str)) -> Ordering {
+        let (a, a_name) = a;
+        let (b, b_name) = b;
+        let primary = match self {
+            Self::Activity => Ordering::Equal,
+            Self::Name => a_name.to_lowercase().cmp(&b_name.to_lowercase()),
+            Self::Unread => b.unread_count.cmp(&a.unread_count),
+        };
+        primary
+            .then_with(|| Reverse(a.updated_at).cmp(&Reverse(b.updated_at)))
+            .then_with(|| a.id.cmp(&b.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_filter_and_sort() {
+        let mut quiet = Conversation::new("alice".to_string());
+        let mut busy = Conversation::new("bob".to_string());
+        busy.unread_count = 3;
+        busy.pinned = true;
+        quiet.updated_at = busy.updated_at + std::time::Duration::from_secs(1);
+        
+        let unread = ConversationFilter { unread: true, ..Default::default() };
+        assert!(unread.matches(&busy, None));
+        assert!(!unread.matches(&quiet, None));
+        let pinned = ConversationFilter { pinned: Some(false), ..Default::default() };
+        assert!(pinned.matches(&quiet, None));
+        let labelled = HashSet::from(["alice".to_string()]);
+        let label = ConversationFilter { label_id: Some("family".to_string()), ..Default::default() };
+        assert!(label.matches(&quiet, Some(&labelled)));
+        assert!(!label.matches(&busy, Some(&labelled)));
+        
+        let (quiet, busy) = ((&quiet, "Zed"), (&busy, "amy"));
+        assert_eq!(ConversationSort::Activity.compare(quiet, busy), Ordering::Less);
+        assert_eq!(ConversationSort::Name.compare(quiet, busy), Ordering::Greater);
+        assert_eq!(ConversationSort::Unread.compare(quiet, busy), Ordering::Greater);
+        
+        assert!(ConversationQuery { limit: 0, ..Default::default() }.validate().is_err());
+    }
+}
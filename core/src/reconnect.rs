@@ -0,0 +1,155 @@
+//! Redialing peers after a connection drops.
+//!
+//! A peer we lost is redialed with exponential backoff: the delay doubles
+//! with every failed attempt up to `MAX_DELAY`, with random jitter so peers
+//! that dropped together don't all redial at once. After `MAX_ATTEMPTS`
+//! failures the peer is given up on as unreachable until it connects again.
+//! At most `MAX_CONCURRENT_DIALS` redials run at a time.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Delay before the first redial
+pub const INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between redials
+pub const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Failed redials before a peer counts as unreachable
+pub const MAX_ATTEMPTS: u32 = 10;
+
+pub const MAX_CONCURRENT_DIALS: usize = 4;
+
+/// What became of a failed redial
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedialOutcome {
+    /// Tried again after this long
+    Retry(Duration),
+    /// Out of attempts
+    Unreachable,
+}
+
+#[derive(Debug)]
+struct Redial {
+    /// Failed attempts so far
+    attempt: u32,
+    due: Instant,
+    dialing: bool,
+}
+
+/// Backoff state for the peers being redialed
+#[derive(Debug, Default)]
+pub struct Reconnector {
+    peers: HashMap<String, Redial>,
+}
+
+/// Delay before redial number `attempt`, with `jitter` in [0, 1) picking a
+/// point in the upper half of the backoff window
+pub fn backoff_delay(attempt: u32, jitter: f64) -> Duration {
+    let base = INITIAL_DELAY.saturating_mul(1 << attempt.min(16)).min(MAX_DELAY);
+    base / 2 + base.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+impl Reconnector {
+    /// Start redialing a peer that disconnected
+    pub fn disconnected(&mut self, peer_id: &str, now: Instant) {
+        self.peers.entry(peer_id.to_string()).or_insert(Redial {
+            attempt: 0,
+            due: now + backoff_delay(0, rand::random()),
+            dialing: false,
+        });
+    }
+    
+    /// Redial right away, e.g. a peer known from an earlier run
+    pub fn dial_now(&mut self, peer_id: &str, now: Instant) {
+        self.peers.entry(peer_id.to_string()).or_insert(Redial { attempt: 0, due: now, dialing: false });
+    }
+    
+    /// Peers to dial now, within the concurrency cap. They count as dialing
+    /// until `connected` or `dial_failed`.
+    pub fn due(&mut self, now: Instant) -> Vec<String> {
+        let slots = MAX_CONCURRENT_DIALS.saturating_sub(self.dialing());
+        let mut due: Vec<_> = self.peers.iter_mut()
+            .filter(|(_, r)| !r.dialing && r.due <= now)
+            .collect();
+        due.sort_by_key(|(_, r)| r.due);
+        due.into_iter()
+            .take(slots)
+            .map(|(peer_id, redial)| {
+                redial.dialing = true;
+                peer_id.clone()
+            })
+            .collect()
+    }
+    
+    pub fn dial_failed(&mut self, peer_id: &str, now: Instant) -> Option<RedialOutcome> {
+        let redial = self.peers.get_mut(peer_id)?;
+        redial.attempt += 1;
+        if redial.attempt >= MAX_ATTEMPTS {
+            self.peers.remove(peer_id);
+            return Some(RedialOutcome::Unreachable);
+        }
+        let delay = backoff_delay(redial.attempt, rand::random());
+        redial.due = now + delay;
+        redial.dialing = false;
+        Some(RedialOutcome::Retry(delay))
+    }
+    
+    pub fn connected(&mut self, peer_id: &str) {
+        self.peers.remove(peer_id);
+    }
+    
+    /// Whether `peer_id` is being redialed
+    pub fn is_pending(&self, peer_id: &str) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+    
+    fn dialing(&self) -> usize {
+        self.peers.values().filter(|r| r.dialing).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_backoff_grows_and_gives_up() {
+        assert_eq!(backoff_delay(0, 0.0), INITIAL_DELAY / 2);
+        assert_eq!(backoff_delay(3, 0.999_999).as_secs(), 7);
+        assert_eq!(backoff_delay(30, 1.0), MAX_DELAY);
+        
+        let now = Instant::now();
+        let mut reconnector = Reconnector::default();
+        for i in 0..6 {
+            reconnector.disconnected(&format!("peer{}", i), now);
+        }
+        assert!(reconnector.due(now).is_empty());
+        let later = now + INITIAL_DELAY;
+        let dialing = reconnector.due(later);
+        assert_eq!(dialing.len(), MAX_CONCURRENT_DIALS);
+        assert!(reconnector.due(later).is_empty());
+        
+        // A success frees a slot for the next peer
+        reconnector.connected(&dialing[0]);
+        assert_eq!(reconnector.due(later).len(), 1);
+        
+        let mut reconnector = Reconnector::default();
+        let peer = "peer";
+        reconnector.dial_now(peer, now);
+        assert_eq!(reconnector.due(now), vec![peer.to_string()]);
+        let mut outcomes = Vec::new();
+        let mut at = now;
+        while let Some(outcome) = reconnector.dial_failed(peer, at) {
+            if let RedialOutcome::Retry(delay) = outcome {
+                at += delay;
+                assert!(reconnector.due(at - delay).is_empty());
+                assert_eq!(reconnector.due(at), vec![peer.to_string()]);
+            }
+            outcomes.push(outcome);
+        }
+        assert_eq!(outcomes.len() as u32, MAX_ATTEMPTS);
+        assert_eq!(outcomes.last(), Some(&RedialOutcome::Unreachable));
+        assert!(!reconnector.is_pending(peer));
+    }
+}
@@ -0,0 +1,153 @@
+//! Account recovery phrase.
+//!
+//! An account can be created with a 24-word BIP39 phrase, shown once and
+//! kept by the user. The identity key is derived from the phrase, so a
+//! new database set up with `recover_account` has the same identity and
+//! contacts still recognize it. The database also keeps a recovery slot:
+//! the master key wrapped under a key derived from the phrase, with which
+//! `reset_password` opens the database again after the password is
+//! forgotten. The phrase carries 256 bits of entropy, so keys are derived
+//! from it with HKDF rather than a password hash.
+
+use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Key, Nonce};
+use anyhow::{Result, Context};
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+
+use crate::crypto::IdentityKeyPair;
+
+pub const PHRASE_WORDS: usize = 24;
+const ENTROPY_LEN: usize = 32;
+const INFO_IDENTITY: &[u8] = b"SecureChat v1 recovery-identity";
+const INFO_WRAP: &[u8] = b"SecureChat v1 recovery-wrap";
+
+/// A recovery phrase; keep it out of logs and storage
+#[derive(Clone)]
+pub struct RecoveryPhrase {
+    mnemonic: bip39::Mnemonic,
+}
+
+impl RecoveryPhrase {
+    pub fn generate(rng: &mut impl RngCore) -> Self {
+        let mut entropy = [0u8; ENTROPY_LEN];
+        rng.fill_bytes(&mut entropy);
+        let mnemonic = bip39::Mnemonic::from_entropy(&entropy)
+            .expect("32 bytes is a valid entropy length");
+        Self { mnemonic }
+    }
+    
+    /// Parse a phrase as the user typed it: any case and spacing, checked
+    /// against the word list and checksum
+    pub fn parse(phrase: &str) -> Result<Self> {
+        let normalized = phrase.split_whitespace()
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mnemonic = bip39::Mnemonic::parse(normalized)
+            .map_err(|e| anyhow::anyhow!("Invalid recovery phrase: {}", e))?;
+        if mnemonic.word_count() != PHRASE_WORDS {
+            return Err(anyhow::anyhow!("Recovery phrases have {} words", PHRASE_WORDS));
+        }
+        Ok(Self { mnemonic })
+    }
+    
+    pub fn words(&self) -> Vec<&'static str> {
+        self.mnemonic.words().collect()
+    }
+    
+    /// The words separated by single spaces
+    pub fn phrase(&self) -> String {
+        self.mnemonic.to_string()
+    }
+    
+    fn derive(&self, info: &[u8]) -> Result<[u8; 32]> {
+        let (entropy, len) = self.mnemonic.to_entropy_array();
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, &entropy[..len])
+            .expand(info, &mut key)
+            .map_err(|e| anyhow::anyhow!("Recovery key derivation failed: {:?}", e))?;
+        Ok(key)
+    }
+    
+    /// The identity an account created with this phrase has
+    pub fn identity(&self) -> Result<IdentityKeyPair> {
+        Ok(IdentityKeyPair::from_seed(&self.derive(INFO_IDENTITY)?))
+    }
+}
+
+impl std::fmt::Debug for RecoveryPhrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RecoveryPhrase([REDACTED])")
+    }
+}
+
+/// The master key and its key slot, wrapped under a recovery phrase
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoverySlot {
+    pub encrypted_key: Vec<u8>,
+    pub nonce: [u8; 12],
+}
+
+impl RecoverySlot {
+    pub fn seal(phrase: &RecoveryPhrase, master_key: &[u8; 32], slot: usize, rng: &mut impl RngCore) -> Result<Self> {
+        let mut nonce = [0u8; 12];
+        rng.fill_bytes(&mut nonce);
+        let mut plaintext = master_key.to_vec();
+        plaintext.push(u8::try_from(slot).context("Invalid key slot")?);
+        let encrypted_key = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&phrase.derive(INFO_WRAP)?))
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
+            .map_err(|e| anyhow::anyhow!("Failed to wrap master key: {:?}", e))?;
+        Ok(Self { encrypted_key, nonce })
+    }
+    
+    /// A slot shaped like a sealed one that no phrase opens, stored when
+    /// the account has no phrase so its presence gives nothing away
+    pub fn stand_in(rng: &mut impl RngCore) -> Self {
+        // The key, its slot byte and the 16-byte GCM tag
+        let mut encrypted_key = vec![0u8; 32 + 1 + 16];
+        rng.fill_bytes(&mut encrypted_key);
+        let mut nonce = [0u8; 12];
+        rng.fill_bytes(&mut nonce);
+        Self { encrypted_key, nonce }
+    }
+    
+    /// The master key and the slot it belongs in
+    pub fn open(&self, phrase: &RecoveryPhrase) -> Result<([u8; 32], usize)> {
+        let plaintext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&phrase.derive(INFO_WRAP)?))
+            .decrypt(Nonce::from_slice(&self.nonce), self.encrypted_key.as_ref())
+            .map_err(|_| anyhow::anyhow!("Recovery phrase does not match this account"))?;
+        let (slot, master_key) = plaintext.split_last()
+            .ok_or_else(|| anyhow::anyhow!("Recovery slot is empty"))?;
+        let master_key = master_key.try_into()
+            .map_err(|_| anyhow::anyhow!("Master key has wrong length"))?;
+        Ok((master_key, *slot as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_recovery_phrase_round_trip() {
+        let mut rng = rand::thread_rng();
+        let phrase = RecoveryPhrase::generate(&mut rng);
+        assert_eq!(phrase.words().len(), PHRASE_WORDS);
+        
+        // Typed back sloppily, it's the same phrase with the same identity
+        let typed = format!("  {}  ", phrase.phrase().to_uppercase().replace(' ', "   "));
+        let parsed = RecoveryPhrase::parse(&typed).unwrap();
+        assert_eq!(parsed.identity().unwrap().public_key, phrase.identity().unwrap().public_key);
+        
+        let mut words = phrase.words();
+        words[3] = "securechat";
+        assert!(RecoveryPhrase::parse(&words.join(" ")).is_err());
+        assert!(RecoveryPhrase::parse(&phrase.words()[..12].join(" ")).is_err());
+        
+        let slot = RecoverySlot::seal(&phrase, &[7; 32], 1, &mut rng).unwrap();
+        assert_eq!(slot.open(&parsed).unwrap(), ([7; 32], 1));
+        assert!(slot.open(&RecoveryPhrase::generate(&mut rng)).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! Message requests from people who aren't contacts.
+//!
+//! A message from an identity key we don't know is kept aside, per sender,
+//! instead of landing in a conversation. Nothing is acknowledged and no
+//! `MessageReceived` is reported, so an unknown sender can't tell whether
+//! anyone is there and can't set off notifications. Accepting a request
+//! makes the sender a contact and moves their messages into a
+//! conversation; declining drops them, and may block the key so later
+//! messages are dropped on arrival.
+//!
+//! Anyone can send us such messages, so they are neither journaled nor
+//! flushed on arrival, and their writes are rate limited by a
+//! `WriteLimiter`. One dropped for going over is not lost to the sender,
+//! who sees no receipt and sends it again.
+
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::protocol::{wire, MessageContent, MessageEnvelope};
+
+/// Most senders with a request waiting; messages from further unknown
+/// senders are dropped until some are accepted or declined
+pub const MAX_PENDING_REQUESTS: usize = 100;
+/// Most messages kept from one unknown sender
+pub const MAX_REQUEST_MESSAGES: usize = 20;
+/// Message request writes allowed in a burst
+pub const REQUEST_WRITE_BURST: u32 = 20;
+/// Time for one more write to be allowed after a burst
+pub const REQUEST_WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageRequest {
+    /// The sender's identity key, base64 encoded as they named themselves
+    pub sender_id: String,
+    pub sender_key: [u8; 32],
+    /// Oldest first
+    pub messages: Vec<RequestedMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestedMessage {
+    #[serde(with = "wire::framed")]
+    pub envelope: MessageEnvelope,
+    /// Decrypted on arrival, as our message keys change with each unlock
+    pub content: MessageContent,
+    pub received_at: OffsetDateTime,
+}
+
+impl MessageRequest {
+    pub fn new(sender_id: String, sender_key: [u8; 32]) -> Self {
+        Self { sender_id, sender_key, messages: Vec::new() }
+    }
+
+    pub fn last_received_at(&self) -> Option<OffsetDateTime> {
+        self.messages.last().map(|message| message.received_at)
+    }
+}
+
+/// Writes of message requests, across all unknown senders
+#[derive(Debug)]
+pub struct WriteLimiter {
+    allowed: u32,
+    refilled_at: Instant,
+}
+
+impl Default for WriteLimiter {
+    fn default() -> Self {
+        Self { allowed: REQUEST_WRITE_BURST, refilled_at: Instant::now() }
+    }
+}
+
+impl WriteLimiter {
+    /// Take one write, or `false` if there is none left for now
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        let refills = (elapsed.as_millis() / REQUEST_WRITE_INTERVAL.as_millis())
+            .min(u128::from(REQUEST_WRITE_BURST)) as u32;
+        self.allowed = (self.allowed + refills).min(REQUEST_WRITE_BURST);
+        if self.allowed == REQUEST_WRITE_BURST {
+            self.refilled_at = now;
+        } else {
+            self.refilled_at += REQUEST_WRITE_INTERVAL * refills;
+        }
+        
+        if self.allowed == 0 {
+            return false;
+        }
+        self.allowed -= 1;
+        true
+    }
+}
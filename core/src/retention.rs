@@ -0,0 +1,108 @@
+//! Message retention.
+//!
+//! A retention policy caps a conversation's history by count, by age, or
+//! both. Conversations without a policy of their own follow the default
+//! one, which keeps everything until changed. Starred messages are never
+//! pruned. Pruning runs every `PRUNE_INTERVAL` once started, and deletes
+//! the attachments no remaining message refers to.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::protocol::LocalMessage;
+
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Newest messages kept; `None` keeps any number
+    pub max_messages: Option<usize>,
+    /// Messages older than this are pruned; `None` keeps them
+    pub max_age: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_messages == Some(0) {
+            return Err(anyhow::anyhow!("Retention must keep at least one message"));
+        }
+        if self.max_age == Some(Duration::ZERO) {
+            return Err(anyhow::anyhow!("Retention age must be positive"));
+        }
+        Ok(())
+    }
+    
+    pub fn keeps_everything(&self) -> bool {
+        self.max_messages.is_none() && self.max_age.is_none()
+    }
+    
+    /// Messages the policy prunes from `messages`, which are in
+    /// conversation order
+    pub fn expired<'a>(&self, messages: &'a [LocalMessage], now: OffsetDateTime) -> Vec<&'a LocalMessage> {
+        let keep_from = self.max_messages.map_or(0, |max| messages.len().saturating_sub(max));
+        messages.iter().enumerate()
+            .filter(|(i, message)| {
+                !message.starred
+                    && (*i < keep_from || self.max_age.is_some_and(|age| now - message.timestamp > age))
+            })
+            .map(|(_, message)| message)
+            .collect()
+    }
+}
+
+/// What a pruning pass removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PruneReport {
+    pub messages: usize,
+    /// Attachments deleted with their last message
+    pub attachments: usize,
+    /// Plaintext size of those attachments
+    pub bytes_freed: u64,
+}
+
+impl PruneReport {
+    pub fn add(&mut self, other: PruneReport) {
+        self.messages += other.messages;
+        self.attachments += other.attachments;
+        self.bytes_freed += other.bytes_freed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{DeliveryStatus, MessageContent};
+    
+    #[test]
+    fn test_policy_picks_old_unstarred_messages() {
+        let now = OffsetDateTime::now_utc();
+        let messages: Vec<_> = (0..6u64).map(|i| LocalMessage {
+            id: format!("m{}", i),
+            conversation_id: "c".to_string(),
+            sender_id: "self".to_string(),
+            is_outgoing: true,
+            content: MessageContent::Text { text: i.to_string() },
+            timestamp: now - time::Duration::days(6 - i as i64),
+            status: DeliveryStatus::Sent,
+            reply_to: None,
+            forwarded_from: None,
+            starred: i == 1,
+            lamport: i,
+        }).collect();
+        let ids = |policy: RetentionPolicy| -> Vec<_> {
+            policy.expired(&messages, now).into_iter().map(|m| m.id.as_str()).collect()
+        };
+        
+        assert!(ids(RetentionPolicy::default()).is_empty());
+        assert_eq!(ids(RetentionPolicy { max_messages: Some(3), max_age: None }), ["m0", "m2"]);
+        let three_days = Some(Duration::from_secs(3 * 24 * 60 * 60) + Duration::from_secs(60));
+        assert_eq!(ids(RetentionPolicy { max_messages: None, max_age: three_days }), ["m0", "m2"]);
+        assert_eq!(ids(RetentionPolicy { max_messages: Some(2), max_age: three_days }), ["m0", "m2", "m3"]);
+        
+        assert!(RetentionPolicy { max_messages: Some(0), max_age: None }.validate().is_err());
+        assert!(RetentionPolicy { max_messages: None, max_age: Some(Duration::ZERO) }.validate().is_err());
+    }
+}
@@ -0,0 +1,300 @@
+//! Structured rich text for message content.
+//!
+//! A `RichText` is plain text plus style spans given as byte ranges into
+//! that text. Spans may nest. Markdown is only an input and display
+//! convenience; the span form is what gets sent and stored:
+//!
+//! ```text
+//! **bold**  *italic*  _italic_  `code`  [label](https://example.com)
+//! [@Alice](contact:<contact id>)
+//! ```
+
+use serde::{Serialize, Deserialize};
+
+/// Most spans accepted in one message; the rest are dropped
+pub const MAX_SPANS: usize = 256;
+
+/// Link schemes that survive sanitization
+const ALLOWED_SCHEMES: [&str; 3] = ["https://", "http://", "mailto:"];
+
+/// Markdown link target that marks a mention
+const MENTION_SCHEME: &str = "contact:";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RichText {
+    pub text: String,
+    pub spans: Vec<TextSpan>,
+}
+
+/// A style applied to `text[start..end]`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextSpan {
+    pub start: u32,
+    pub end: u32,
+    pub style: SpanStyle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpanStyle {
+    Bold,
+    Italic,
+    Code,
+    Link { url: String },
+    Mention { contact_id: String },
+}
+
+impl RichText {
+    /// Unstyled text
+    pub fn plain(text: &str) -> Self {
+        Self { text: text.to_string(), spans: Vec::new() }
+    }
+    
+    /// Parse the Markdown subset described in the module docs. Anything that
+    /// isn't well-formed markup is kept as literal text.
+    pub fn from_markdown(markdown: &str) -> Self {
+        let mut rich = Self::plain("");
+        parse_inline(markdown, &mut rich);
+        rich.sanitize();
+        rich
+    }
+    
+    /// Render back to Markdown, escaping literal markup characters
+    pub fn to_markdown(&self) -> String {
+        // Opening and closing markers per byte offset. At equal offsets,
+        // closes go first, inner spans close before outer ones and outer
+        // spans open before inner ones.
+        let mut markers: Vec<(usize, bool, usize, String)> = Vec::new();
+        for (i, span) in self.spans.iter().enumerate() {
+            let (open, close) = match &span.style {
+                SpanStyle::Bold => ("**".to_string(), "**".to_string()),
+                SpanStyle::Italic => ("*".to_string(), "*".to_string()),
+                SpanStyle::Code => ("`".to_string(), "`".to_string()),
+                SpanStyle::Link { url } => ("[".to_string(), format!("]({})", url)),
+                SpanStyle::Mention { contact_id } => {
+                    ("[".to_string(), format!("]({}{})", MENTION_SCHEME, contact_id))
+                }
+            };
+            markers.push((span.start as usize, true, i, open));
+            markers.push((span.end as usize, false, i, close));
+        }
+        let spans = &self.spans;
+        markers.sort_by(|a, b| {
+            a.0.cmp(&b.0)
+                .then(a.1.cmp(&b.1))
+                .then_with(|| if a.1 {
+                    spans[b.2].end.cmp(&spans[a.2].end)
+                } else {
+                    spans[b.2].start.cmp(&spans[a.2].start)
+                })
+        });
+        
+        let mut out = String::with_capacity(self.text.len() + markers.len() * 2);
+        let mut markers = markers.into_iter().peekable();
+        let mut in_code = 0usize;
+        for (offset, c) in self.text.char_indices() {
+            while let Some((_, open, i, marker)) = markers.next_if(|m| m.0 == offset) {
+                if self.spans[i].style == SpanStyle::Code {
+                    if open { in_code += 1 } else { in_code -= 1 }
+                }
+                out.push_str(&marker);
+            }
+            if in_code == 0 && matches!(c, '\\' | '*' | '_' | '`' | '[' | ']') {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+        for (_, _, _, marker) in markers {
+            out.push_str(&marker);
+        }
+        out
+    }
+    
+    /// Drop spans that are empty, out of bounds, split a character, use a
+    /// disallowed link scheme or name no contact, and cap their number.
+    /// Run on every received message before it is stored or shown.
+    pub fn sanitize(&mut self) {
+        let text = &self.text;
+        self.spans.retain(|span| {
+            let (start, end) = (span.start as usize, span.end as usize);
+            let in_bounds = start < end
+                && end <= text.len()
+                && text.is_char_boundary(start)
+                && text.is_char_boundary(end);
+            in_bounds && match &span.style {
+                SpanStyle::Link { url } => is_allowed_url(url),
+                SpanStyle::Mention { contact_id } => !contact_id.is_empty(),
+                _ => true,
+            }
+        });
+        self.spans.truncate(MAX_SPANS);
+    }
+    
+    /// Contact ids mentioned in this text, without duplicates
+    pub fn mentions(&self) -> Vec<&str> {
+        let mut mentions = Vec::new();
+        for span in &self.spans {
+            if let SpanStyle::Mention { contact_id } = &span.style {
+                if !mentions.contains(&contact_id.as_str()) {
+                    mentions.push(contact_id.as_str());
+                }
+            }
+        }
+        mentions
+    }
+    
+    /// Whether `contact_id` is mentioned, e.g. to notify even when muted
+    pub fn mentions_contact(&self, contact_id: &str) -> bool {
+        self.mentions().contains(&contact_id)
+    }
+}
+
+fn is_allowed_url(url: &str) -> bool {
+    let lower = url.to_ascii_lowercase();
+    ALLOWED_SCHEMES.iter().any(|scheme| lower.starts_with(scheme))
+        && !url.chars().any(|c| c.is_control() || c.is_whitespace())
+}
+
+fn parse_inline(mut rest: &str, rich: &mut RichText) {
+    while let Some(c) = rest.chars().next() {
+        // Backslash escapes the next character
+        if c == '\\' {
+            if let Some(escaped) = rest[1..].chars().next() {
+                rich.text.push(escaped);
+                rest = &rest[1 + escaped.len_utf8()..];
+                continue;
+            }
+        }
+        
+        if c == '`' {
+            if let Some(end) = rest[1..].find('`').filter(|end| *end > 0) {
+                let start = rich.text.len();
+                rich.text.push_str(&rest[1..1 + end]);
+                push_span(rich, start, SpanStyle::Code);
+                rest = &rest[end + 2..];
+                continue;
+            }
+        }
+        
+        if rest.starts_with("**") {
+            if let Some(mut end) = rest[2..].find("**").filter(|end| *end > 0) {
+                // In "***" the last two stars close the bold text
+                while rest[2 + end + 2..].starts_with('*') {
+                    end += 1;
+                }
+                let start = rich.text.len();
+                parse_inline(&rest[2..2 + end], rich);
+                push_span(rich, start, SpanStyle::Bold);
+                rest = &rest[end + 4..];
+                continue;
+            }
+        }
+        
+        // An underscore inside a word, as in snake_case, is literal
+        let in_word = c == '_' && rich.text.chars().last().is_some_and(char::is_alphanumeric);
+        if (c == '*' || c == '_') && !in_word {
+            if let Some(end) = rest[1..].find(c).filter(|end| *end > 0) {
+                let start = rich.text.len();
+                parse_inline(&rest[1..1 + end], rich);
+                push_span(rich, start, SpanStyle::Italic);
+                rest = &rest[end + 2..];
+                continue;
+            }
+        }
+        
+        if c == '[' {
+            if let Some((label, target, consumed)) = split_link(rest) {
+                let start = rich.text.len();
+                parse_inline(label, rich);
+                let style = match target.strip_prefix(MENTION_SCHEME) {
+                    Some(contact_id) => SpanStyle::Mention { contact_id: contact_id.to_string() },
+                    None => SpanStyle::Link { url: target.to_string() },
+                };
+                push_span(rich, start, style);
+                rest = &rest[consumed..];
+                continue;
+            }
+        }
+        
+        rich.text.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+}
+
+/// Split `[label](target)` off the front of `s`
+fn split_link(s: &str) -> Option<(&str, &str, usize)> {
+    let label_end = s.find("](")?;
+    let target_end = label_end + 2 + s[label_end + 2..].find(')')?;
+    let label = &s[1..label_end];
+    let target = &s[label_end + 2..target_end];
+    if label.is_empty() || label.contains('[') || target.is_empty() {
+        return None;
+    }
+    Some((label, target, target_end + 1))
+}
+
+fn push_span(rich: &mut RichText, start: usize, style: SpanStyle) {
+    let end = rich.text.len();
+    if end > start {
+        rich.spans.push(TextSpan { start: start as u32, end: end as u32, style });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn span(start: u32, end: u32, style: SpanStyle) -> TextSpan {
+        TextSpan { start, end, style }
+    }
+    
+    #[test]
+    fn test_markdown_parsing() {
+        let rich = RichText::from_markdown(
+            "hi **bold *both***, `a*b` [docs](https://x.org) [@Al](contact:c1) 2*3"
+        );
+        assert_eq!(rich.text, "hi bold both, a*b docs @Al 2*3");
+        assert_eq!(rich.spans, vec![
+            span(8, 12, SpanStyle::Italic),
+            span(3, 12, SpanStyle::Bold),
+            span(14, 17, SpanStyle::Code),
+            span(18, 22, SpanStyle::Link { url: "https://x.org".to_string() }),
+            span(23, 26, SpanStyle::Mention { contact_id: "c1".to_string() }),
+        ]);
+        assert_eq!(rich.mentions(), ["c1"]);
+        assert!(rich.mentions_contact("c1"));
+    }
+    
+    #[test]
+    fn test_markdown_roundtrip() {
+        for markdown in [
+            "plain \\*not italic\\* \\[x\\]",
+            "**bold *both***",
+            "`code` and [@Bob](contact:b/2+=) and [site](http://a.b/c)",
+            "émoji **😀 ok**",
+        ] {
+            let rich = RichText::from_markdown(markdown);
+            assert_eq!(RichText::from_markdown(&rich.to_markdown()), rich, "{}", markdown);
+        }
+    }
+    
+    #[test]
+    fn test_sanitize_drops_bad_spans() {
+        let mut rich = RichText {
+            text: "é link".to_string(),
+            spans: vec![
+                span(0, 1, SpanStyle::Bold),
+                span(3, 99, SpanStyle::Italic),
+                span(2, 2, SpanStyle::Code),
+                span(3, 7, SpanStyle::Link { url: "javascript:alert(1)".to_string() }),
+                span(3, 7, SpanStyle::Link { url: "https://ok.example".to_string() }),
+                span(0, 2, SpanStyle::Mention { contact_id: String::new() }),
+            ],
+        };
+        rich.sanitize();
+        assert_eq!(rich.spans, vec![span(3, 7, SpanStyle::Link { url: "https://ok.example".to_string() })]);
+        
+        // Unsafe links in Markdown stay as text
+        let rich = RichText::from_markdown("[x](javascript:alert(1))");
+        assert!(rich.spans.is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+//! Searching contacts, conversations and messages at once.
+//!
+//! A query is split into words, compared in lower case, and each of its
+//! words has to start a word of what it matches, so results narrow down
+//! as the user types. Contacts match by name or nickname, conversations by
+//! the title they are shown under, notes and messages by their text.
+//! Whole words score above prefixes, and a match at the start above one
+//! further in; ties go to contacts, then conversations, notes and
+//! messages, the most recent first.
+//!
+//! Messages are found through an index in `SecureStorage` kept as they are
+//! written: an entry for every prefix of `MIN_TERM_LEN` to `MAX_TERM_LEN`
+//! characters of every word, in a list of sealed postings named by a hash
+//! of the prefix under the search index key, so the stored keys give away
+//! neither the words nor which messages hold them. The index only narrows
+//! down the messages to read; each is checked against the whole query
+//! before it is returned.
+
+use std::collections::BTreeSet;
+
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::protocol::MessageContent;
+
+pub const DEFAULT_LIMIT: usize = 50;
+pub const MAX_LIMIT: usize = 500;
+
+/// Shortest word prefix messages are indexed under; shorter query words
+/// only narrow down the messages found by the others
+pub const MIN_TERM_LEN: usize = 2;
+
+/// Longest word prefix messages are indexed under
+pub const MAX_TERM_LEN: usize = 16;
+
+/// Most messages read for one query, newest first
+pub const MAX_MESSAGE_CANDIDATES: usize = 1000;
+
+const WHOLE_WORD: u32 = 3;
+const WORD_PREFIX: u32 = 2;
+const AT_START: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SearchHit {
+    /// A contact whose name or nickname matched
+    Contact { contact_id: String, name: String },
+    /// A conversation whose title, the contact's name, matched
+    Conversation { conversation_id: String, contact_id: String, title: String, updated_at: OffsetDateTime },
+    /// A private note on a contact
+    Note { contact_id: String, name: String, preview: String, updated_at: OffsetDateTime },
+    Message {
+        conversation_id: String,
+        message_id: String,
+        /// Title of the message's conversation
+        title: String,
+        preview: String,
+        timestamp: OffsetDateTime,
+    },
+}
+
+impl SearchHit {
+    /// Which kind goes first among equal scores
+    fn order(&self) -> u8 {
+        match self {
+            SearchHit::Contact { .. } => 0,
+            SearchHit::Conversation { .. } => 1,
+            SearchHit::Note { .. } => 2,
+            SearchHit::Message { .. } => 3,
+        }
+    }
+
+    fn time(&self) -> Option<OffsetDateTime> {
+        match self {
+            SearchHit::Contact { .. } => None,
+            SearchHit::Conversation { updated_at, .. } | SearchHit::Note { updated_at, .. } => Some(*updated_at),
+            SearchHit::Message { timestamp, .. } => Some(*timestamp),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub hit: SearchHit,
+    /// Higher is a better match
+    pub score: u32,
+}
+
+/// The words of `text`, in lower case
+pub fn words(text: &str) -> Vec<String> {
+    text.unicode_words().map(str::to_lowercase).collect()
+}
+
+/// How well `text` matches the query `words`, or `None` if some query word
+/// starts none of its words
+pub fn score(text: &str, query: &[String]) -> Option<u32> {
+    if query.is_empty() {
+        return None;
+    }
+    let words = words(text);
+    let mut score = 0;
+    for query_word in query {
+        score += words.iter()
+            .map(|word| match word.strip_prefix(query_word.as_str()) {
+                Some("") => WHOLE_WORD,
+                Some(_) => WORD_PREFIX,
+                None => 0,
+            })
+            .max()
+            .filter(|best| *best > 0)?;
+    }
+    if words.first().is_some_and(|first| first.starts_with(query[0].as_str())) {
+        score += AT_START;
+    }
+    Some(score)
+}
+
+/// Best first, at most `limit`
+pub(crate) fn rank(results: &mut Vec<SearchResult>, limit: usize) {
+    results.sort_by(|a, b| b.score.cmp(&a.score)
+        .then(a.hit.order().cmp(&b.hit.order()))
+        .then(b.hit.time().cmp(&a.hit.time())));
+    results.truncate(limit);
+}
+
+/// The part of a message that is searched: its text or caption, a file's
+/// name, or the name of a shared contact or sticker pack
+pub fn message_text(content: &MessageContent) -> Option<&str> {
+    match content {
+        MessageContent::Text { text } => Some(text),
+        MessageContent::RichText(rich) => Some(&rich.text),
+        MessageContent::Image { caption, .. } => caption.as_deref(),
+        MessageContent::File { filename, .. } => Some(filename),
+        MessageContent::Contact { name, .. } => Some(name),
+        MessageContent::StickerPack { title, .. } => Some(title),
+        MessageContent::Voice { .. } | MessageContent::Location { .. }
+            | MessageContent::Sticker { .. } | MessageContent::System { .. } => None,
+    }
+}
+
+/// What a message with `text` is indexed under
+pub(crate) fn index_terms(text: &str) -> BTreeSet<String> {
+    let mut terms = BTreeSet::new();
+    for word in words(text) {
+        let ends = word.char_indices().map(|(start, c)| start + c.len_utf8());
+        for end in ends.take(MAX_TERM_LEN).skip(MIN_TERM_LEN - 1) {
+            terms.insert(word[..end].to_string());
+        }
+    }
+    terms
+}
+
+/// The term a query word is looked up under, if it isn't too short to be
+/// indexed
+pub(crate) fn lookup_term(word: &str) -> Option<String> {
+    (word.chars().count() >= MIN_TERM_LEN).then(|| word.chars().take(MAX_TERM_LEN).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(text: &str) -> Vec<String> {
+        words(text)
+    }
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score("Alice Smith", &query("alice")), Some(WHOLE_WORD + AT_START));
+        assert_eq!(score("Alice Smith", &query("Smi")), Some(WORD_PREFIX));
+        assert_eq!(score("Alice Smith", &query("smith al")), Some(WHOLE_WORD + WORD_PREFIX));
+        assert_eq!(score("Alice Smith", &query("alice x")), None);
+        // Inside a word isn't a match
+        assert_eq!(score("Alice Smith", &query("lice")), None);
+        assert_eq!(score("Alice", &[]), None);
+        assert_eq!(score("Grüße aus Köln", &query("KÖLN")), Some(WHOLE_WORD));
+    }
+
+    #[test]
+    fn test_index_terms() {
+        let terms = index_terms("Hi, Zoë!");
+        assert_eq!(terms.into_iter().collect::<Vec<_>>(), ["hi", "zo", "zoë"]);
+        let long = "a".repeat(MAX_TERM_LEN + 4);
+        assert_eq!(index_terms(&long).len(), MAX_TERM_LEN - 1);
+        // Query words longer than what is indexed are looked up by the
+        // longest indexed prefix
+        assert!(index_terms(&long).contains(&lookup_term(&long).unwrap()));
+        assert_eq!(lookup_term("a"), None);
+    }
+}
@@ -0,0 +1,502 @@
+//! Deterministic network simulator.
+//!
+//! `SimNetwork` stands in for the real network so protocol logic can be
+//! tested without sockets. Nodes join through `SimTransport`s handed to
+//! `SecureChat::set_network_transport`, and every published message goes
+//! to all other running nodes, as gossip would. Nothing moves until the
+//! test calls `advance`: messages then arrive after a latency drawn from a
+//! seeded RNG, may be dropped, may overtake each other within the jitter
+//! window, and never cross a partition. A message published with no peer
+//! to reach is held and sent once one appears.
+//!
+//! The same seed and the same calls give the same run, provided the test
+//! runs on a current-thread runtime (the `#[tokio::test]` default).
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use time::OffsetDateTime;
+
+use crate::network::{NetworkCommand, NetworkConfig, NetworkEvent, NetworkHandle, NetworkTransport, PeerStats};
+use crate::protocol::ProtocolMessage;
+
+/// Yields given to node tasks before the simulator looks at what they
+/// sent, so commands and replies already under way are picked up
+const SETTLE_YIELDS: usize = 32;
+
+/// Most `advance` steps `run_until_idle` takes
+const MAX_IDLE_STEPS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimConfig {
+    pub seed: u64,
+    /// Shortest time a message takes
+    pub latency: Duration,
+    /// Most extra time a message may take, picked at random; messages
+    /// sent closer together than this can arrive out of order
+    pub jitter: Duration,
+    /// Chance in [0, 1] that a message to one peer is lost
+    pub drop_rate: f64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            latency: Duration::from_millis(50),
+            jitter: Duration::ZERO,
+            drop_rate: 0.0,
+        }
+    }
+}
+
+/// Messages handed to and lost by the simulator, counted per recipient
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimStats {
+    pub sent: usize,
+    pub delivered: usize,
+    pub dropped: usize,
+}
+
+struct SimNode {
+    name: String,
+    /// Set while the node's network runs
+    events: Option<mpsc::Sender<NetworkEvent>>,
+    /// Published since the last step
+    outgoing: VecDeque<ProtocolMessage>,
+    /// Published while no peer was reachable
+    unsent: Vec<ProtocolMessage>,
+}
+
+struct InFlight {
+    deliver_at: Duration,
+    /// Breaks ties in the order messages were sent
+    seq: u64,
+    from: usize,
+    to: usize,
+    message: ProtocolMessage,
+}
+
+impl InFlight {
+    fn key(&self) -> (Duration, u64) {
+        (self.deliver_at, self.seq)
+    }
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+struct SimState {
+    config: SimConfig,
+    rng: StdRng,
+    now: Duration,
+    nodes: Vec<SimNode>,
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    /// Node pairs that can't reach each other, both ways round
+    partitioned: HashSet<(usize, usize)>,
+    seq: u64,
+    stats: SimStats,
+}
+
+/// A simulated network shared by several nodes. Clones share the network.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimState>>,
+}
+
+/// One node's way onto a `SimNetwork`
+pub struct SimTransport {
+    network: SimNetwork,
+    node: usize,
+}
+
+impl SimState {
+    fn is_running(&self, node: usize) -> bool {
+        self.nodes[node].events.is_some()
+    }
+    
+    fn reachable(&self, from: usize, to: usize) -> bool {
+        from != to && self.is_running(to) && !self.partitioned.contains(&(from, to))
+    }
+    
+    fn emit(&mut self, node: usize, event: NetworkEvent) -> bool {
+        match self.nodes[node].events.as_mut() {
+            Some(events) => events.try_send(event).is_ok(),
+            None => false,
+        }
+    }
+    
+    /// Tell both ends of a link that opened or closed
+    fn link_changed(&mut self, a: usize, b: usize, up: bool) {
+        for (node, peer) in [(a, b), (b, a)] {
+            let peer_id = self.nodes[peer].name.clone();
+            self.emit(node, if up {
+                NetworkEvent::PeerConnected { peer_id }
+            } else {
+                NetworkEvent::PeerDisconnected { peer_id }
+            });
+        }
+        if up {
+            for node in [a, b] {
+                let unsent = std::mem::take(&mut self.nodes[node].unsent);
+                self.nodes[node].outgoing.extend(unsent);
+            }
+        }
+    }
+    
+    /// Put everything published since the last step on the wire
+    fn schedule(&mut self) {
+        for from in 0..self.nodes.len() {
+            while let Some(message) = self.nodes[from].outgoing.pop_front() {
+                let targets: Vec<_> = (0..self.nodes.len()).filter(|&to| self.reachable(from, to)).collect();
+                if targets.is_empty() {
+                    self.nodes[from].unsent.push(message);
+                    continue;
+                }
+                if let Some(message_id) = message.message_id() {
+                    self.emit(from, NetworkEvent::MessageSent { message_id: message_id.to_string() });
+                }
+                for to in targets {
+                    self.stats.sent += 1;
+                    if self.rng.gen_bool(self.config.drop_rate.clamp(0.0, 1.0)) {
+                        self.stats.dropped += 1;
+                        continue;
+                    }
+                    let jitter = self.config.jitter.mul_f64(self.rng.gen::<f64>());
+                    self.seq += 1;
+                    self.in_flight.push(Reverse(InFlight {
+                        deliver_at: self.now + self.config.latency + jitter,
+                        seq: self.seq,
+                        from,
+                        to,
+                        message: message.clone(),
+                    }));
+                }
+            }
+        }
+    }
+    
+    /// Hand over every message due by now, in arrival order
+    fn deliver(&mut self) -> usize {
+        let mut delivered = 0;
+        while self.in_flight.peek().is_some_and(|Reverse(m)| m.deliver_at <= self.now) {
+            let Reverse(InFlight { from, to, message, .. }) = self.in_flight.pop().unwrap();
+            let peer_id = self.nodes[from].name.clone();
+            if self.reachable(from, to) && self.emit(to, NetworkEvent::MessageReceived { peer_id, message }) {
+                delivered += 1;
+            } else {
+                self.stats.dropped += 1;
+            }
+        }
+        self.stats.delivered += delivered;
+        delivered
+    }
+    
+    fn indices(&self, names: &[&str]) -> Vec<usize> {
+        names.iter()
+            .filter_map(|name| self.nodes.iter().position(|node| node.name == *name))
+            .collect()
+    }
+    
+    fn is_idle(&self) -> bool {
+        self.in_flight.is_empty() && self.nodes.iter().all(|node| node.outgoing.is_empty())
+    }
+}
+
+impl SimNetwork {
+    pub fn new(config: SimConfig) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SimState {
+                config,
+                rng: StdRng::seed_from_u64(config.seed),
+                now: Duration::ZERO,
+                nodes: Vec::new(),
+                in_flight: BinaryHeap::new(),
+                partitioned: HashSet::new(),
+                seq: 0,
+                stats: SimStats::default(),
+            })),
+        }
+    }
+    
+    /// Add a node, known to its peers as `name`
+    pub fn transport(&self, name: &str) -> Arc<SimTransport> {
+        let mut state = self.state();
+        state.nodes.push(SimNode {
+            name: name.to_string(),
+            events: None,
+            outgoing: VecDeque::new(),
+            unsent: Vec::new(),
+        });
+        Arc::new(SimTransport { network: self.clone(), node: state.nodes.len() - 1 })
+    }
+    
+    /// Simulated time since the network was created
+    pub fn now(&self) -> Duration {
+        self.state().now
+    }
+    
+    pub fn stats(&self) -> SimStats {
+        self.state().stats
+    }
+    
+    /// Move simulated time forward by `by`, delivering what arrives in
+    /// the meantime. Returns how many messages were delivered.
+    pub async fn advance(&self, by: Duration) -> usize {
+        settle().await;
+        let delivered = {
+            let mut state = self.state();
+            state.schedule();
+            state.now += by;
+            state.deliver()
+        };
+        settle().await;
+        delivered
+    }
+    
+    /// Advance until nothing is in flight, including the replies to what
+    /// was delivered. Returns how many messages were delivered.
+    pub async fn run_until_idle(&self) -> usize {
+        let mut delivered = 0;
+        for _ in 0..MAX_IDLE_STEPS {
+            settle().await;
+            let next = {
+                let mut state = self.state();
+                state.schedule();
+                if state.is_idle() {
+                    return delivered;
+                }
+                state.in_flight.peek().map(|Reverse(m)| m.deliver_at.saturating_sub(state.now))
+            };
+            delivered += self.advance(next.unwrap_or_default()).await;
+        }
+        delivered
+    }
+    
+    /// Cut every node named in `a` off from every node named in `b`
+    pub fn partition(&self, a: &[&str], b: &[&str]) {
+        let mut state = self.state();
+        let (a, b) = (state.indices(a), state.indices(b));
+        for &x in &a {
+            for &y in &b {
+                if x != y && state.partitioned.insert((x, y)) {
+                    state.partitioned.insert((y, x));
+                    if state.is_running(x) && state.is_running(y) {
+                        state.link_changed(x, y, false);
+                    }
+                }
+            }
+        }
+    }
+    
+    /// Undo every partition
+    pub fn heal(&self) {
+        let mut state = self.state();
+        let links: Vec<_> = state.partitioned.drain().filter(|(x, y)| x < y).collect();
+        for (x, y) in links {
+            if state.is_running(x) && state.is_running(y) {
+                state.link_changed(x, y, true);
+            }
+        }
+    }
+    
+    fn state(&self) -> std::sync::MutexGuard<'_, SimState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+async fn settle() {
+    for _ in 0..SETTLE_YIELDS {
+        tokio::task::yield_now().await;
+    }
+}
+
+impl NetworkTransport for SimTransport {
+    fn start(&self, _config: NetworkConfig) -> Result<NetworkHandle> {
+        let (event_tx, events) = mpsc::channel(1024);
+        let (commands, mut command_rx) = mpsc::channel(100);
+        {
+            let mut state = self.network.state();
+            if state.is_running(self.node) {
+                return Err(anyhow::anyhow!("Simulated node is already running"));
+            }
+            state.nodes[self.node].events = Some(event_tx);
+            for peer in 0..state.nodes.len() {
+                if state.reachable(self.node, peer) {
+                    state.link_changed(self.node, peer, true);
+                }
+            }
+        }
+        
+        let network = self.network.clone();
+        let node = self.node;
+        let task = tokio::spawn(async move {
+            let handle = |command| {
+                let mut state = network.state();
+                match command {
+                    NetworkCommand::SendMessage { message, .. } => state.nodes[node].outgoing.push_back(message),
+                    NetworkCommand::CancelMessage { message_id } => {
+                        state.nodes[node].outgoing.retain(|message| message.message_id() != Some(message_id.as_str()));
+                    }
+                    NetworkCommand::GetPeerStats { reply } => {
+                        let latency = state.config.latency;
+                        let stats = (0..state.nodes.len())
+                            .filter(|&peer| state.reachable(node, peer))
+                            .map(|peer| PeerStats {
+                                peer_id: state.nodes[peer].name.clone(),
+                                connected: true,
+                                rtt: Some(latency * 2),
+                                last_seen: OffsetDateTime::now_utc(),
+                                agent_version: None,
+                            })
+                            .collect();
+                        reply.send(stats).ok();
+                    }
+                    _ => {}
+                }
+            };
+            while let Some(command) = command_rx.next().await {
+                if matches!(command, NetworkCommand::Shutdown) {
+                    break;
+                }
+                handle(command);
+            }
+            command_rx.close();
+            while let Ok(command) = command_rx.try_recv() {
+                handle(command);
+            }
+            
+            let mut state = network.state();
+            state.nodes[node].events = None;
+            for peer in 0..state.nodes.len() {
+                if state.reachable(node, peer) {
+                    let peer_id = state.nodes[node].name.clone();
+                    state.emit(peer, NetworkEvent::PeerDisconnected { peer_id });
+                }
+            }
+            Ok::<_, anyhow::Error>(std::mem::take(&mut state.nodes[node].unsent))
+        });
+        Ok(NetworkHandle { events, commands, task })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+    use crate::harness::{introduce, TestNode};
+    use crate::protocol::DeliveryStatus;
+    use crate::ChatEvent;
+    
+    fn receipt(i: usize) -> NetworkCommand {
+        NetworkCommand::SendMessage {
+            peer_id: None,
+            message: ProtocolMessage::DeliveryReceipt { message_id: i.to_string(), timestamp: OffsetDateTime::UNIX_EPOCH },
+        }
+    }
+    
+    fn received(events: &mut mpsc::Receiver<NetworkEvent>) -> Vec<String> {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                NetworkEvent::MessageReceived { message: ProtocolMessage::DeliveryReceipt { message_id, .. }, .. } => Some(message_id),
+                _ => None,
+            })
+            .collect()
+    }
+    
+    async fn lossy_run(seed: u64) -> (Vec<String>, Vec<String>, SimStats) {
+        let sim = SimNetwork::new(SimConfig {
+            seed,
+            jitter: Duration::from_millis(100),
+            drop_rate: 0.3,
+            ..Default::default()
+        });
+        let mut handles = Vec::new();
+        for name in ["a", "b", "c"] {
+            handles.push(sim.transport(name).start(NetworkConfig::default()).unwrap());
+        }
+        for i in 0..20 {
+            handles[0].commands.send(receipt(i)).await.unwrap();
+        }
+        sim.run_until_idle().await;
+        
+        // Nothing reaches across a partition
+        sim.partition(&["a"], &["c"]);
+        handles[0].commands.send(receipt(100)).await.unwrap();
+        sim.run_until_idle().await;
+        sim.heal();
+        
+        let [_, b, c] = &mut handles[..] else { unreachable!() };
+        (received(&mut b.events), received(&mut c.events), sim.stats())
+    }
+    
+    #[tokio::test]
+    async fn test_simulation_is_deterministic() {
+        let (b, c, stats) = lossy_run(7).await;
+        assert_eq!(lossy_run(7).await, (b.clone(), c.clone(), stats));
+        assert_ne!(lossy_run(8).await, (b.clone(), c.clone(), stats));
+        
+        assert_eq!(stats.sent, 41);
+        assert!(stats.dropped > 0);
+        assert_eq!(stats.delivered + stats.dropped, stats.sent);
+        assert_eq!(b.len() + c.len(), stats.delivered);
+        assert!(!c.contains(&"100".to_string()));
+        // Jitter let later messages overtake earlier ones
+        assert!(b.windows(2).any(|w| w[0].parse::<usize>().unwrap() > w[1].parse::<usize>().unwrap()));
+    }
+    
+    #[tokio::test]
+    async fn test_partitioned_chat_catches_up_after_healing() {
+        let sim = SimNetwork::new(SimConfig::default());
+        let mut alice = TestNode::new("Alice").await;
+        let mut bob = TestNode::new("Bob").await;
+        for (name, node) in [("alice", &mut alice), ("bob", &mut bob)] {
+            let transport = sim.transport(name);
+            node.chat.set_network_transport(transport).await;
+            node.events = node.chat.start_network(NetworkConfig::default()).await.unwrap();
+        }
+        let conversation = introduce(&bob, &alice).await;
+        sim.run_until_idle().await;
+        
+        sim.partition(&["alice"], &["bob"]);
+        let message_id = bob.chat.send_text_message(&conversation, "across the gap").await.unwrap();
+        sim.advance(Duration::from_secs(60)).await;
+        assert!(alice.chat.get_conversations().await.unwrap().is_empty());
+        
+        sim.heal();
+        sim.run_until_idle().await;
+        assert_eq!(alice.expect_message().await.1, "across the gap");
+        bob.expect("a delivery receipt", |event| match event {
+            ChatEvent::MessageStatusChanged { message_id: id, status: DeliveryStatus::Delivered, .. } => (*id == message_id).then_some(()),
+            _ => None,
+        }).await;
+        assert!(sim.now() >= Duration::from_secs(60));
+        
+        alice.stop().await;
+        bob.stop().await;
+    }
+}
@@ -0,0 +1,45 @@
+//! Unlocking, and what it reports while it runs.
+//!
+//! Deriving the master key is most of the wait: Argon2 runs once for each
+//! key slot, and the slots are tried side by side. Once the key is known,
+//! checking integrity, decrypting the identity and profile and reading
+//! the conversation list run concurrently, the last filling the record
+//! cache so the first screen the client shows doesn't decrypt it again.
+//! Each step is reported with `ChatEvent::UnlockProgress` as it finishes.
+//!
+//! With `CacheWarming` set, unlocking also starts reading the recent
+//! messages of the top conversations into the cache in the background.
+//! It stops when storage is closed and never delays the unlock.
+
+use serde::{Serialize, Deserialize};
+
+/// Steps of an unlock, reported as each finishes. Everything after
+/// `KeyDerived` runs concurrently, so those finish in any order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnlockPhase {
+    KeyDerived,
+    IntegrityChecked,
+    AccountLoaded,
+    ConversationsLoaded,
+}
+
+impl UnlockPhase {
+    /// Number of steps, for a progress bar
+    pub const COUNT: usize = 4;
+}
+
+/// What to read into the record cache after unlocking
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheWarming {
+    /// Conversations to warm, pinned ones first, then the most recent
+    pub conversations: usize,
+    /// Newest messages to read in each
+    pub messages: usize,
+}
+
+impl Default for CacheWarming {
+    fn default() -> Self {
+        Self { conversations: 10, messages: 50 }
+    }
+}
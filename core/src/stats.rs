@@ -0,0 +1,276 @@
+//! Per-conversation statistics.
+//!
+//! `SecureStorage` keeps a `StatsRecord` per conversation and updates it
+//! in the same write as each message added, so reading the statistics of
+//! even a huge history is a single record. It is computed from the
+//! messages the first time it is asked for. Changes that can't be applied
+//! to it incrementally, deleting a message or adding one before the
+//! newest, mark it stale, and the next read computes it again.
+//!
+//! Response times are measured in conversation order: from the first of a
+//! run of messages from one side to the other side's next message.
+//!
+//! Storage also counts each conversation's messages per day as they are
+//! written, for activity heatmaps and jumping to a date. Days are in UTC.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+use time::{Date, OffsetDateTime, UtcOffset};
+
+use crate::protocol::{LocalMessage, MessageContent};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    /// Plain and rich text
+    Text,
+    Image,
+    File,
+    Voice,
+    Location,
+    Contact,
+    Sticker,
+    StickerPack,
+    System,
+}
+
+impl MessageKind {
+    pub fn of(content: &MessageContent) -> Self {
+        match content {
+            MessageContent::Text { .. } | MessageContent::RichText(_) => MessageKind::Text,
+            MessageContent::Image { .. } => MessageKind::Image,
+            MessageContent::File { .. } => MessageKind::File,
+            MessageContent::Voice { .. } => MessageKind::Voice,
+            MessageContent::Location { .. } => MessageKind::Location,
+            MessageContent::Contact { .. } => MessageKind::Contact,
+            MessageContent::Sticker { .. } => MessageKind::Sticker,
+            MessageContent::StickerPack { .. } => MessageKind::StickerPack,
+            MessageContent::System { .. } => MessageKind::System,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectionCounts {
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl DirectionCounts {
+    pub fn total(&self) -> u64 {
+        self.sent + self.received
+    }
+
+    fn add(&mut self, outgoing: bool) {
+        if outgoing {
+            self.sent += 1;
+        } else {
+            self.received += 1;
+        }
+    }
+}
+
+/// How quickly one side replied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResponseTimes {
+    pub replies: u64,
+    pub total_secs: u64,
+}
+
+impl ResponseTimes {
+    pub fn average(&self) -> Option<Duration> {
+        (self.replies > 0).then(|| Duration::from_secs(self.total_secs / self.replies))
+    }
+
+    fn add(&mut self, waited: time::Duration) {
+        self.replies += 1;
+        // Clock skew can put a reply before what it answers
+        self.total_secs += waited.whole_seconds().max(0) as u64;
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationStats {
+    pub messages: DirectionCounts,
+    pub by_kind: BTreeMap<MessageKind, DirectionCounts>,
+    /// Time of the oldest and newest message
+    pub first_activity: Option<OffsetDateTime>,
+    pub last_activity: Option<OffsetDateTime>,
+    /// Size of the attachments sent and received, shared ones counted once
+    /// per message
+    pub attachment_bytes: u64,
+    /// From the contact's messages to our replies
+    pub our_response: ResponseTimes,
+    /// From our messages to the contact's replies
+    pub their_response: ResponseTimes,
+}
+
+/// A conversation's messages on one day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DayCount {
+    pub date: Date,
+    pub messages: u64,
+}
+
+/// The day, in UTC, a message sent at `timestamp` is counted on
+pub fn day_of(timestamp: OffsetDateTime) -> Date {
+    timestamp.to_offset(UtcOffset::UTC).date()
+}
+
+/// `ConversationStats` as stored, with what it takes to add the next
+/// message
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct StatsRecord {
+    pub stats: ConversationStats,
+    /// Lamport time and timestamp of the newest message in conversation
+    /// order
+    newest: Option<(u64, OffsetDateTime)>,
+    /// Direction and start of the run of messages the next reply answers
+    waiting: Option<(bool, OffsetDateTime)>,
+    stale: bool,
+}
+
+/// What a message adds to the statistics
+#[derive(PartialEq)]
+struct Contribution {
+    kind: MessageKind,
+    outgoing: bool,
+    attachment_bytes: u64,
+    position: (u64, OffsetDateTime),
+}
+
+impl Contribution {
+    fn of(message: &LocalMessage) -> Self {
+        Self {
+            kind: MessageKind::of(&message.content),
+            outgoing: message.is_outgoing,
+            attachment_bytes: message.content.attachment().map_or(0, |attachment| attachment.size),
+            position: (message.lamport, message.timestamp),
+        }
+    }
+}
+
+impl StatsRecord {
+    /// Statistics of `messages`, in conversation order
+    pub fn compute<'a>(messages: impl IntoIterator<Item = &'a LocalMessage>) -> Self {
+        let mut record = Self::default();
+        for message in messages {
+            record.add(message);
+        }
+        record
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    pub fn add(&mut self, message: &LocalMessage) {
+        let contribution = Contribution::of(message);
+        let stats = &mut self.stats;
+        stats.messages.add(contribution.outgoing);
+        stats.by_kind.entry(contribution.kind).or_default().add(contribution.outgoing);
+        stats.attachment_bytes += contribution.attachment_bytes;
+        let (_, timestamp) = contribution.position;
+        stats.first_activity = Some(stats.first_activity.map_or(timestamp, |first| first.min(timestamp)));
+        stats.last_activity = Some(stats.last_activity.map_or(timestamp, |last| last.max(timestamp)));
+
+        if self.newest.is_some_and(|newest| contribution.position < newest) {
+            self.stale = true;
+        }
+        if self.stale {
+            return;
+        }
+        self.newest = Some(contribution.position);
+        // Notices aren't written by either side
+        if contribution.kind == MessageKind::System {
+            return;
+        }
+        match self.waiting {
+            Some((outgoing, _)) if outgoing == contribution.outgoing => {}
+            Some((_, since)) => {
+                let response = if contribution.outgoing { &mut stats.our_response } else { &mut stats.their_response };
+                response.add(timestamp - since);
+                self.waiting = Some((contribution.outgoing, timestamp));
+            }
+            None => self.waiting = Some((contribution.outgoing, timestamp)),
+        }
+    }
+
+    /// `old` was stored again as `new`, e.g. with a new status
+    pub fn replace(&mut self, old: &LocalMessage, new: &LocalMessage) {
+        if Contribution::of(old) != Contribution::of(new) {
+            self.stale = true;
+        }
+    }
+
+    /// A message was deleted
+    pub fn remove(&mut self) {
+        self.stale = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::DeliveryStatus;
+
+    fn message(id: &str, outgoing: bool, minutes: i64, content: MessageContent) -> LocalMessage {
+        LocalMessage {
+            id: id.to_string(),
+            conversation_id: "c".to_string(),
+            sender_id: if outgoing { "me" } else { "them" }.to_string(),
+            is_outgoing: outgoing,
+            content,
+            timestamp: OffsetDateTime::UNIX_EPOCH + time::Duration::minutes(minutes),
+            status: DeliveryStatus::Delivered,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport: minutes as u64,
+        }
+    }
+
+    fn text(id: &str, outgoing: bool, minutes: i64) -> LocalMessage {
+        message(id, outgoing, minutes, MessageContent::Text { text: id.to_string() })
+    }
+
+    #[test]
+    fn test_counts_and_response_times() {
+        let messages = [
+            text("a", false, 0),
+            text("b", false, 1),
+            // We answer 10 minutes after their first message
+            text("c", true, 10),
+            message("d", true, 11, MessageContent::Location { latitude: 0.0, longitude: 0.0, accuracy: None }),
+            // They answer 4 minutes after ours
+            text("e", false, 14),
+        ];
+        let record = StatsRecord::compute(&messages);
+        let stats = &record.stats;
+        assert_eq!(stats.messages, DirectionCounts { sent: 2, received: 3 });
+        assert_eq!(stats.by_kind[&MessageKind::Text], DirectionCounts { sent: 1, received: 3 });
+        assert_eq!(stats.by_kind[&MessageKind::Location].total(), 1);
+        assert_eq!(stats.first_activity, Some(messages[0].timestamp));
+        assert_eq!(stats.last_activity, Some(messages[4].timestamp));
+        assert_eq!(stats.our_response.average(), Some(Duration::from_secs(600)));
+        assert_eq!(stats.their_response.average(), Some(Duration::from_secs(240)));
+        assert!(!record.is_stale());
+    }
+
+    #[test]
+    fn test_changes_out_of_order_go_stale() {
+        let mut record = StatsRecord::compute(&[text("a", false, 0), text("b", true, 5)]);
+        let mut updated = text("b", true, 5);
+        updated.status = DeliveryStatus::Read;
+        record.replace(&text("b", true, 5), &updated);
+        assert!(!record.is_stale());
+
+        record.add(&text("early", true, 2));
+        assert!(record.is_stale());
+        let mut record = StatsRecord::compute(&[text("a", false, 0)]);
+        record.remove();
+        assert!(record.is_stale());
+    }
+}
@@ -0,0 +1,132 @@
+//! Sticker packs.
+//!
+//! A pack is a manifest of sticker images held in the attachment store. Its
+//! id is a hash over the title, author and sticker digests, so the same
+//! pack has the same id on every device no matter who shared it. To share a
+//! pack, the manifest itself is stored as an attachment and sent in a
+//! `MessageContent::StickerPack`; recipients fetch it and the sticker
+//! images over the usual attachment chunk transfer.
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::protocol::{wire, AttachmentRef};
+
+/// Most stickers in one pack
+pub const MAX_STICKERS: usize = 200;
+
+/// Largest sticker image accepted
+pub const MAX_STICKER_SIZE: u64 = 512 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StickerPack {
+    pub id: [u8; 32],
+    pub title: String,
+    pub author: String,
+    pub stickers: Vec<Sticker>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sticker {
+    /// Emoji the sticker stands for, used for suggestions
+    pub emoji: Option<String>,
+    pub image: AttachmentRef,
+}
+
+impl StickerPack {
+    pub fn new(title: &str, author: &str, stickers: Vec<Sticker>) -> Result<Self> {
+        let mut pack = Self {
+            id: [0u8; 32],
+            title: title.to_string(),
+            author: author.to_string(),
+            stickers,
+        };
+        pack.validate_contents()?;
+        pack.id = pack.compute_id();
+        Ok(pack)
+    }
+    
+    /// Content address of the pack; keys used for transfer are left out
+    pub fn compute_id(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new_derive_key("SecureChat sticker pack v1");
+        for field in [&self.title, &self.author] {
+            hasher.update(&(field.len() as u64).to_be_bytes());
+            hasher.update(field.as_bytes());
+        }
+        for sticker in &self.stickers {
+            hasher.update(&sticker.image.digest);
+        }
+        *hasher.finalize().as_bytes()
+    }
+    
+    /// Hex form of the id, used in storage keys
+    pub fn id_hex(&self) -> String {
+        blake3::Hash::from_bytes(self.id).to_hex().to_string()
+    }
+    
+    pub fn sticker(&self, digest: &[u8; 32]) -> Option<&Sticker> {
+        self.stickers.iter().find(|s| &s.image.digest == digest)
+    }
+    
+    /// Encode the manifest for sharing
+    pub fn to_manifest(&self) -> Result<Vec<u8>> {
+        wire::encode(self)
+    }
+    
+    /// Decode a shared manifest, checking it matches its claimed id
+    pub fn from_manifest(data: &[u8]) -> Result<Self> {
+        let pack: Self = wire::decode(data)?;
+        pack.validate_contents()?;
+        if pack.compute_id() != pack.id {
+            return Err(anyhow::anyhow!("Sticker pack does not match its id"));
+        }
+        Ok(pack)
+    }
+    
+    fn validate_contents(&self) -> Result<()> {
+        if self.stickers.is_empty() || self.stickers.len() > MAX_STICKERS {
+            return Err(anyhow::anyhow!("A sticker pack holds 1 to {} stickers", MAX_STICKERS));
+        }
+        if self.stickers.iter().any(|s| s.image.size > MAX_STICKER_SIZE) {
+            return Err(anyhow::anyhow!("Sticker image too large"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn image(byte: u8) -> AttachmentRef {
+        AttachmentRef { digest: [byte; 32], size: 100, key: rand::random() }
+    }
+    
+    #[test]
+    fn test_pack_id_ignores_transfer_keys() {
+        let stickers = vec![
+            Sticker { emoji: Some("👍".to_string()), image: image(1) },
+            Sticker { emoji: None, image: image(2) },
+        ];
+        let pack = StickerPack::new("Cats", "Ann", stickers.clone()).unwrap();
+        
+        let mut rekeyed = stickers;
+        for sticker in &mut rekeyed {
+            sticker.image.key = rand::random();
+        }
+        assert_eq!(StickerPack::new("Cats", "Ann", rekeyed).unwrap().id, pack.id);
+        assert_ne!(StickerPack::new("Dogs", "Ann", pack.stickers.clone()).unwrap().id, pack.id);
+        
+        let decoded = StickerPack::from_manifest(&pack.to_manifest().unwrap()).unwrap();
+        assert_eq!(decoded, pack);
+    }
+    
+    #[test]
+    fn test_forged_manifest_rejected() {
+        let mut pack = StickerPack::new("Cats", "Ann", vec![Sticker { emoji: None, image: image(1) }]).unwrap();
+        pack.stickers.push(Sticker { emoji: None, image: image(9) });
+        assert!(StickerPack::from_manifest(&pack.to_manifest().unwrap()).is_err());
+        
+        assert!(StickerPack::new("Empty", "Ann", Vec::new()).is_err());
+    }
+}
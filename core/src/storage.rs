@@ -1,15 +1,279 @@
-use sled::Db;
+use sled::{Db, Tree};
 use anyhow::{Result, Context};
-use serde::{Serialize, de::DeserializeOwned};
+use rand::RngCore;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use time::{Date, OffsetDateTime};
 
-use crate::crypto::{EncryptedIdentityKeys, MasterKey};
-use crate::protocol::{Contact, Conversation, LocalMessage, UserProfile, DeviceInfo};
+use crate::bootstrap::BootstrapNode;
+use crate::card::{CardRevocation, IssuedCard};
+use crate::broadcast::{Broadcast, BroadcastList};
+use crate::audit::{self, AuditHead, SecurityEvent, SecurityEventKind};
+use crate::backup::{RecordCategory, RestoreSelection};
+use crate::cache::{CacheStats, RecordCache, DEFAULT_CACHE_BYTES};
+use crate::cancel::CancellationToken;
+use crate::gc::GcReport;
+use crate::maintenance::{self, MaintenanceReport};
+use crate::devices::{Authentication, Capabilities, DeviceSession, ReceivingSession, RemoteDevice, SessionRequirements};
+use crate::durability::{Durability, Flusher, FsyncPolicy};
+use crate::escrow::EscrowedBackupKey;
+use crate::integrity::{IntegrityReport, Problem, RecordProblem};
+use crate::crypto::{EncryptedIdentityKeys, IdentityKeyPair, KeyHierarchy, MasterKey};
+use crate::invite::{ContactPrekey, InvitePrekey};
+use crate::network::PeerInfo;
+use crate::plugins::Annotation;
+use crate::postings::{Directory, ListEdit, Posting};
+use crate::push::PushEndpoint;
+use crate::puzzle;
+use crate::query::{ConversationPage, ConversationQuery, ConversationSort, FolderGroup};
+use crate::retention::{PruneReport, RetentionPolicy};
+use crate::search;
+use crate::stats::{self, ConversationStats, DayCount, StatsRecord};
+use crate::stickers::StickerPack;
+use crate::thumbnail::Thumbnail;
+use crate::transparency::{self, KeyLogEntry, KeyLogEventKind, KeyLogHead};
+use crate::recovery::{RecoveryPhrase, RecoverySlot};
+use crate::importer::PendingContact;
+use crate::requests::MessageRequest;
+use crate::usage::{CategoryUsage, CompactionProgress, CompactionReport, ConversationUsage, UsageReport, COMPACTION_BATCH};
+use crate::username::UsernameClaim;
+use crate::webhooks::Webhook;
+use crate::protocol::{AttachmentRef, ATTACHMENT_CHUNK_SIZE, Contact, ContactLabel, ContactNote, ContactSettings, ChatFolder, ConversationPolicies, DeliveryStatus, ForwardedFrom, PrivacySettings, Conversation, LocalMessage, MessageContent, MessageEnvelope, ProtocolMessage, QuarantinedEnvelope, UserProfile, DeviceInfo, ViewOnce, SELF_CONTACT_ID, wire};
 
-/// Encrypted local storage
+/// Encrypted local storage.
+///
+/// The `mk:` record always holds two password slots in random order: the
+/// account password and either a duress password or a slot no password
+/// opens. Likewise there is always one `profile-<id>` tree besides the
+/// default tree, holding the duress profile or nothing but a random
+/// marker. Both slots are tried on every unlock, so a database with a
+/// duress password looks and behaves like one without.
+#[derive(Clone)]
 pub struct SecureStorage {
     db: Db,
-    pub master_key: [u8; 32],
+    /// Records of the opened profile: the default tree for the account,
+    /// a profile tree for the duress profile
+    tree: Tree,
+    keys: Arc<KeyHierarchy>,
+    /// Index of the `mk:` slot that opened this profile
+    slot: usize,
+    /// Display name for a duress profile that has not been set up yet
+    fresh_profile: Option<String>,
+    /// Shared by every handle, and stopped once the last one is dropped
+    flusher: Arc<Mutex<Flusher>>,
+    /// Decrypted records, shared by every handle
+    cache: Arc<RecordCache>,
+    /// Held by message writes, which read a conversation's stats and
+    /// write them back with the message
+    message_writes: Arc<Mutex<()>>,
+}
+
+type ReadRecord<T> = Box<dyn Fn(&SecureStorage, sled::IVec, sled::IVec) -> Result<Option<T>> + Send + Sync>;
+
+/// Records decrypted one at a time as they are iterated, so large result
+/// sets can be paged without loading them whole. Holds its own storage
+/// handle. Records read by key see writes made while iterating; those
+/// read through an index list see the list as it was when they started.
+pub struct Records<T> {
+    /// Each entry decoded as it is reached; `None` skips it
+    entries: Box<dyn DoubleEndedIterator<Item = Result<Option<T>>> + Send>,
+}
+
+impl<T: 'static> Records<T> {
+    fn new(storage: &SecureStorage, iter: sled::Iter, read: ReadRecord<T>) -> Self {
+        let storage = storage.clone();
+        Self {
+            entries: Box::new(iter.map(move |item| match item {
+                Ok((key, value)) => read(&storage, key, value),
+                Err(e) => Err(anyhow::Error::new(e).context("Failed to read records")),
+            })),
+        }
+    }
+    
+    /// Records an index list's postings point to, read with `read`
+    fn from_postings(storage: &SecureStorage, postings: impl DoubleEndedIterator<Item = Result<Posting>> + Send + 'static, read: fn(&SecureStorage, Posting) -> Result<Option<T>>) -> Self {
+        let storage = storage.clone();
+        Self {
+            entries: Box::new(postings.map(move |posting| read(&storage, posting?))),
+        }
+    }
+}
+
+impl<T> Iterator for Records<T> {
+    type Item = Result<T>;
+    
+    fn next(&mut self) -> Option<Result<T>> {
+        loop {
+            if let Some(record) = self.entries.next()?.transpose() {
+                return Some(record);
+            }
+        }
+    }
+}
+
+impl<T> DoubleEndedIterator for Records<T> {
+    fn next_back(&mut self) -> Option<Result<T>> {
+        loop {
+            if let Some(record) = self.entries.next_back()?.transpose() {
+                return Some(record);
+            }
+        }
+    }
+}
+
+fn read_record<T: DeserializeOwned>(storage: &SecureStorage, key: sled::IVec, value: sled::IVec) -> Result<Option<T>> {
+    let decrypted = storage.decrypt_cached(&key, &value)?;
+    bincode::deserialize(&decrypted)
+        .context("Failed to deserialize record")
+        .map(Some)
+}
+
+/// The message an order posting points to, unless the posting is one
+/// left behind by a message that has since moved or gone
+fn read_ordered_message(storage: &SecureStorage, posting: Posting) -> Result<Option<LocalMessage>> {
+    let message = storage.get::<LocalMessage>(&format!("{}{}", PREFIX_MESSAGE, posting.path))?;
+    Ok(message.filter(|message| order_sort(message) == posting.sort))
+}
+
+/// As `read_ordered_message`, for activity postings
+fn read_activity_message(storage: &SecureStorage, posting: Posting) -> Result<Option<LocalMessage>> {
+    let message = storage.get::<LocalMessage>(&format!("{}{}", PREFIX_MESSAGE, posting.path))?;
+    Ok(message.filter(|message| time_sort(message.timestamp) == posting.sort))
+}
+
+/// Index list changes made by one write, applied to its batch together
+/// so several changes to one list build on each other. Callers hold
+/// `message_writes`.
+struct IndexWrites<'a> {
+    storage: &'a SecureStorage,
+    lists: HashMap<String, ListEdit>,
+}
+
+impl<'a> IndexWrites<'a> {
+    fn new(storage: &'a SecureStorage) -> Self {
+        Self { storage, lists: HashMap::new() }
+    }
+    
+    /// Add a message's postings
+    fn add(&mut self, message: &LocalMessage) -> Result<()> {
+        let storage = self.storage;
+        for (list, posting) in storage.index_postings(message) {
+            self.edit(&list)?.insert(posting, |id| storage.index_block(&list, id))?;
+        }
+        Ok(())
+    }
+    
+    /// Remove a message's postings
+    fn remove(&mut self, message: &LocalMessage) -> Result<()> {
+        let storage = self.storage;
+        for (list, posting) in storage.index_postings(message) {
+            self.edit(&list)?.remove(&posting, |id| storage.index_block(&list, id))?;
+        }
+        Ok(())
+    }
+    
+    fn edit(&mut self, list: &str) -> Result<&mut ListEdit> {
+        Ok(match self.lists.entry(list.to_string()) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => entry.insert(ListEdit::new(self.storage.index_directory(list)?)),
+        })
+    }
+    
+    /// Add the changed directories and blocks to `batch`
+    fn finish(self, batch: &mut sled::Batch) -> Result<()> {
+        for (list, edit) in self.lists {
+            let Some((directory, blocks)) = edit.into_changes() else {
+                continue;
+            };
+            for (id, block) in blocks {
+                let key = index_block_key(&list, id);
+                if block.is_empty() {
+                    batch.remove(key.as_bytes());
+                } else {
+                    self.storage.batch_insert(batch, key.as_bytes(), &bincode::serialize(&block)?)?;
+                }
+            }
+            if directory.blocks.is_empty() {
+                batch.remove(list.as_bytes());
+            } else {
+                self.storage.batch_insert(batch, list.as_bytes(), &bincode::serialize(&directory)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What unlocking with the duress password does. Either way the decoy
+/// profile opens as if it were the account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DuressAction {
+    /// Destroy the real account's key slot and records first
+    WipeData,
+    /// Leave the real account untouched
+    OpenDecoy,
+}
+
+/// Secondary password registered at account setup
+#[derive(Debug, Clone)]
+pub struct DuressPassword {
+    pub password: String,
+    pub action: DuressAction,
+    /// Display name the decoy profile starts with
+    pub display_name: String,
+}
+
+/// Sealed into `meta:profile` of a duress profile tree
+#[derive(Serialize, Deserialize)]
+struct ProfileMarker {
+    action: DuressAction,
+    display_name: String,
+}
+
+/// `LocalMessage` as stored under message schema 1
+#[derive(Deserialize)]
+struct LegacyLocalMessage {
+    id: String,
+    conversation_id: String,
+    sender_id: String,
+    is_outgoing: bool,
+    content: MessageContent,
+    timestamp: OffsetDateTime,
+    sent: bool,
+    delivered: bool,
+    read: bool,
+    reply_to: Option<String>,
+    forwarded_from: Option<ForwardedFrom>,
+    starred: bool,
+    lamport: u64,
+}
+
+impl From<LegacyLocalMessage> for LocalMessage {
+    fn from(legacy: LegacyLocalMessage) -> Self {
+        let status = if legacy.read {
+            DeliveryStatus::Read
+        } else if legacy.delivered || !legacy.is_outgoing {
+            DeliveryStatus::Delivered
+        } else if legacy.sent {
+            DeliveryStatus::Sent
+        } else {
+            DeliveryStatus::Queued
+        };
+        Self {
+            id: legacy.id,
+            conversation_id: legacy.conversation_id,
+            sender_id: legacy.sender_id,
+            is_outgoing: legacy.is_outgoing,
+            content: legacy.content,
+            timestamp: legacy.timestamp,
+            status,
+            reply_to: legacy.reply_to,
+            forwarded_from: legacy.forwarded_from,
+            starred: legacy.starred,
+            lamport: legacy.lamport,
+        }
+    }
 }
 
 /// Key prefixes for different data types
@@ -21,11 +285,160 @@ const PREFIX_MESSAGE: &str = "msg:";
 const PREFIX_PROFILE: &str = "pf:";
 const PREFIX_DEVICE: &str = "dv:";
 const PREFIX_SETTINGS: &str = "st:";
+const PREFIX_META: &str = "meta:";
+const PREFIX_BLOB: &str = "blob:";
+const PREFIX_BLOB_REF: &str = "bref:";
+const PREFIX_STARRED: &str = "star:";
+const PREFIX_STICKER_PACK: &str = "sp:";
+const PREFIX_CONTACT_SETTINGS: &str = "cs:";
+const PREFIX_QUARANTINE: &str = "qr:";
+const PREFIX_AUDIT: &str = "aud:";
+const PREFIX_AUDIT_HEAD: &str = "audh:";
+const PREFIX_USERNAME_PIN: &str = "unp:";
+const PREFIX_INVITE_PREKEY: &str = "ipk:";
+const PREFIX_CONTACT_PREKEY: &str = "cpk:";
+const PREFIX_OUTBOX: &str = "ob:";
+const PREFIX_KNOWN_PEER: &str = "kp:";
+const PREFIX_BOOTSTRAP: &str = "bs:";
+const PREFIX_ENVELOPE_ALIAS: &str = "ea:";
+const PREFIX_RETENTION: &str = "ret:";
+const PREFIX_INGEST_JOURNAL: &str = "ij:";
+/// Conversation id of each contact's conversation
+const PREFIX_CONVERSATION_BY_CONTACT: &str = "cc:";
+/// `ds:<status>/<message path>` for every message
+const PREFIX_MESSAGE_STATUS: &str = "ds:";
+/// Each conversation's messages in conversation order, a list of sealed
+/// postings under a token of the conversation, see `postings`
+const PREFIX_MESSAGE_ORDER: &str = "mo:";
+/// Every message by time across conversations, a list of sealed postings
+const PREFIX_ACTIVITY: &str = "act:";
+/// Statistics of each conversation, by conversation id
+const PREFIX_CONVERSATION_STATS: &str = "cst:";
+/// How many messages a conversation has on each day it has any, one
+/// record under a token of the conversation
+const PREFIX_DAY_COUNT: &str = "day:";
+/// Messages by each term they are indexed under, newest last, a list of
+/// sealed postings under a token of the term, see `search`
+const PREFIX_SEARCH: &str = "sx:";
+/// Thumbnail of a message's attachment, by message path
+const PREFIX_THUMBNAIL: &str = "th:";
+/// Plugins' notes on a message, by message path
+const PREFIX_ANNOTATION: &str = "ann:";
+/// Push endpoints of our devices, by device id
+const PREFIX_PUSH_ENDPOINT: &str = "pe:";
+/// Push endpoints a contact shared, all of them under the contact id
+const PREFIX_CONTACT_PUSH: &str = "pc:";
+/// Devices a contact announced, all of them under the contact id
+const PREFIX_CONTACT_DEVICES: &str = "rd:";
+/// `ses:<contact>/<device hash>`, our session with one of a contact's
+/// devices. Ids can hold slashes, so the device id is hashed.
+const PREFIX_DEVICE_SESSION: &str = "ses:";
+/// `rcv:<contact>/<device hash>`, what we received in one of a contact's
+/// device sessions
+const PREFIX_RECEIVING_SESSION: &str = "rcv:";
+/// `dcap:<contact>/<device hash>`, what one of a contact's devices last
+/// announced it supports
+const PREFIX_DEVICE_CAPABILITIES: &str = "dcap:";
+/// Contact labels, by label id
+const PREFIX_LABEL: &str = "lb:";
+/// Broadcast lists, by list id
+const PREFIX_BROADCAST_LIST: &str = "bl:";
+/// Broadcasts sent, by broadcast id
+const PREFIX_BROADCAST: &str = "bc:";
+/// Messages from unknown senders, by the sender's key
+const PREFIX_MESSAGE_REQUEST: &str = "mr:";
+/// When each blocked unknown sender was blocked, by their key
+const PREFIX_BLOCKED_SENDER: &str = "bk:";
+/// When each contact request stamp we accepted expires, by its hash
+const PREFIX_USED_STAMP: &str = "stp:";
+/// Private notes on contacts, by contact id
+const PREFIX_CONTACT_NOTE: &str = "cn:";
+/// What sessions with a contact must offer, by contact id
+const PREFIX_SESSION_REQUIREMENTS: &str = "sreq:";
+/// Contacts imported without a key, by id
+const PREFIX_PENDING_CONTACT: &str = "pend:";
+/// Contact cards we handed out, by card id
+const PREFIX_ISSUED_CARD: &str = "ic:";
+/// Revocations of other people's contact cards, by card id
+const PREFIX_CARD_REVOCATION: &str = "rc:";
+/// View-once attachments, by blob id
+const PREFIX_VIEW_ONCE: &str = "vo:";
+/// Forwarding and media restrictions, by conversation id
+const PREFIX_CONVERSATION_POLICY: &str = "pol:";
+/// How our messages are authenticated, by conversation id
+const PREFIX_AUTHENTICATION: &str = "auth:";
+/// Chat folders, by folder id
+const PREFIX_FOLDER: &str = "fd:";
+/// Registered webhooks, by webhook id
+const PREFIX_WEBHOOK: &str = "wh:";
+/// Our key transparency log, by sequence number
+const PREFIX_KEY_LOG: &str = "klog:";
+/// Head of our key transparency log, the one record under it
+const PREFIX_KEY_LOG_HEAD: &str = "klh:";
+/// Backup keys received from our other devices, by key id
+const PREFIX_ESCROWED_BACKUP_KEY: &str = "bkk:";
+/// Unreadable records moved aside by an integrity check, stored as found
+const PREFIX_CORRUPT: &str = "bad:";
+
+/// Records are sealed in a versioned envelope:
+/// [magic:2][version:1][cipher:1][flags:1][nonce:24][ciphertext]
+/// with the header and the record's key as associated data, so a record
+/// can't be passed off as another by moving it. Earlier layouts start
+/// with a bare format tag instead and are still read.
+const RECORD_MAGIC: [u8; 2] = *b"SR";
+const RECORD_VERSION: u8 = 1;
+const RECORD_CIPHER_XCHACHA20_POLY1305: u8 = 1;
+const RECORD_HEADER_LEN: usize = 5;
+/// Format tag of XChaCha20-Poly1305 records without a header
+const RECORD_FORMAT_XCHACHA: u8 = 0x02;
+/// As `RECORD_FORMAT_XCHACHA`, with the record's key as associated data
+const RECORD_FORMAT_BOUND: u8 = 0x03;
+const RECORD_NONCE_LEN: usize = 24;
+const RECORD_TAG_LEN: usize = 16;
+
+/// Largest sealed record `decrypt_record` accepts; records are at most an
+/// attachment chunk or a batch of metadata, far below this
+pub const MAX_RECORD_LEN: usize = 16 * 1024 * 1024;
+
+/// Key schema: 1 = everything under the master key, 2 = HKDF subkeys
+const KEY_SCHEMA_VERSION: u32 = 2;
+const META_KEY_SCHEMA: &str = "meta:key_schema";
+
+/// Message schema: 1 = sent/delivered/read flags, 2 = `DeliveryStatus`,
+/// 3 = conversation ids escaped in message paths. Kept per profile and
+/// carried in backups.
+const MESSAGE_SCHEMA_VERSION: u32 = 3;
+const META_MESSAGE_SCHEMA: &str = "meta:message_schema";
+
+/// Index schema: 1 = conversations by contact and messages by status,
+/// 2 = message order, 3 = activity, 4 = search, 5 = messages per day,
+/// 6 = escaped message paths, 7 = sealed postings under opaque keys.
+/// Indexes are rebuilt from the records whenever it goes up.
+const INDEX_SCHEMA_VERSION: u32 = 7;
+const META_INDEX_SCHEMA: &str = "meta:index_schema";
+
+/// Record schema: 1 = records sealed on their own, 2 = bound to their
+/// keys. Kept per profile.
+const RECORD_SCHEMA_VERSION: u32 = 2;
+const META_RECORD_SCHEMA: &str = "meta:record_schema";
+
+/// Status names in message status index keys
+const STATUS_NAMES: [&str; 6] = ["queued", "sending", "sent", "delivered", "read", "failed"];
+
+/// Profile trees and their marker. The marker is padded to a fixed size so
+/// a real one can't be told from the random stand-in.
+const PROFILE_TREE_PREFIX: &str = "profile-";
+const META_PROFILE: &str = "meta:profile";
+const PROFILE_MARKER_LEN: usize = 96;
+/// The master key wrapped under the recovery phrase, or a stand-in
+const META_RECOVERY: &str = "meta:recovery";
+const MAX_DURESS_NAME_LEN: usize = 64;
 
 impl SecureStorage {
     /// Open or create encrypted database
+    #[tracing::instrument(level = "debug", skip_all)]
     pub fn open<P: AsRef<Path>>(path: P, master_key: Option<[u8; 32]>) -> Result<Self> {
-        let db = sled::open(path)
+        let db = open_db(path)
             .context("Failed to open database")?;
         
         let master_key = if let Some(key) = master_key {
@@ -36,8 +449,7 @@ impl SecureStorage {
                 .context("Failed to read master key")?;
             
             if let Some(data) = stored {
-                let encrypted: MasterKey = bincode::deserialize(&data)
-                    .context("Failed to deserialize master key")?;
+                let _slots = decode_key_slots(&data)?;
                 // This will fail if we don't have the password, caller must handle
                 // For now, return error - unlock separately
                 return Err(anyhow::anyhow!("Database exists but needs unlock"));
@@ -46,43 +458,530 @@ impl SecureStorage {
             }
         };
         
-        Ok(Self { db, master_key })
+        let tree = (*db).clone();
+        Self::with_master_key(db, tree, &master_key, 0)
     }
     
     /// Create new database with password
+    #[tracing::instrument(level = "debug", skip_all)]
     pub fn create<P: AsRef<Path>>(path: P, password: &str) -> Result<Self> {
-        let db = sled::open(path)
+        Self::create_with_duress(path, password, None)
+    }
+    
+    /// Create new database with password, optionally registering a duress
+    /// password
+    pub fn create_with_duress<P: AsRef<Path>>(
+        path: P,
+        password: &str,
+        duress: Option<&DuressPassword>,
+    ) -> Result<Self> {
+        Self::create_with_recovery(path, password, duress, None)
+    }
+    
+    /// Create new database, wrapping its master key under `recovery` as
+    /// well so `reset_password` can open it without the password
+    pub fn create_with_recovery<P: AsRef<Path>>(
+        path: P,
+        password: &str,
+        duress: Option<&DuressPassword>,
+        recovery: Option<&RecoveryPhrase>,
+    ) -> Result<Self> {
+        let db = open_db(path)
+            .context("Failed to create database")?;
+        Self::create_in(db, password, duress, recovery)
+    }
+    
+    /// Create a database that lives in memory and is gone once dropped
+    #[cfg(test)]
+    pub(crate) fn create_temporary(password: &str) -> Result<Self> {
+        let db = sled::Config::new().temporary(true).open()
+            .context("Failed to create database")?;
+        Self::create_in(db, password, None, None)
+    }
+    
+    /// An in-memory database under a given master key, skipping the
+    /// password hashing so tests can open many of them
+    #[cfg(test)]
+    pub(crate) fn open_temporary(master_key: &[u8; 32]) -> Result<Self> {
+        let db = sled::Config::new().temporary(true).open()
             .context("Failed to create database")?;
+        let tree = (*db).clone();
+        Self::with_master_key(db, tree, master_key, 0)
+    }
+    
+    fn create_in(
+        db: Db,
+        password: &str,
+        duress: Option<&DuressPassword>,
+        recovery: Option<&RecoveryPhrase>,
+    ) -> Result<Self> {
+        if let Some(duress) = duress {
+            if duress.password == password {
+                return Err(anyhow::anyhow!("Duress password must differ from the account password"));
+            }
+            if duress.display_name.len() > MAX_DURESS_NAME_LEN {
+                return Err(anyhow::anyhow!("Display name too long"));
+            }
+        }
         
         let mut rng = rand::thread_rng();
         let (master_key_store, master_key) = MasterKey::from_password(password, &mut rng)
             .context("Failed to generate master key")?;
         
-        // Store encrypted master key
-        let serialized = bincode::serialize(&master_key_store)
-            .context("Failed to serialize master key")?;
-        db.insert(PREFIX_MASTER_KEY.as_bytes(), serialized)
-            .context("Failed to store master key")?;
+        // The second slot and profile tree, real or stand-in
+        let other_slot = match duress {
+            Some(duress) => {
+                let (store, key) = MasterKey::from_password(&duress.password, &mut rng)
+                    .context("Failed to generate duress key")?;
+                let keys = KeyHierarchy::derive(&key)?;
+                let marker = ProfileMarker {
+                    action: duress.action,
+                    display_name: duress.display_name.clone(),
+                };
+                let mut padded = bincode::serialize(&marker)
+                    .context("Failed to serialize profile marker")?;
+                padded.resize(PROFILE_MARKER_LEN, 0);
+                db.open_tree(profile_tree_name(&keys))
+                    .context("Failed to create profile tree")?
+                    .insert(META_PROFILE.as_bytes(), seal_record(&keys.storage, META_PROFILE.as_bytes(), &padded)?)
+                    .context("Failed to store profile marker")?;
+                store
+            }
+            None => {
+                create_stand_in_profile(&db, &mut rng)?;
+                MasterKey::empty_slot(&mut rng)
+            }
+        };
+        
+        let slot = (rng.next_u32() & 1) as usize;
+        let mut slots = [other_slot.clone(), other_slot];
+        slots[slot] = master_key_store;
+        store_key_slots(&db, &slots)?;
+        let recovery_slot = match recovery {
+            Some(phrase) => RecoverySlot::seal(phrase, &master_key, slot, &mut rng)?,
+            None => RecoverySlot::stand_in(&mut rng),
+        };
+        store_recovery_slot(&db, &recovery_slot)?;
+        
+        // Nothing to migrate in a fresh database
+        db.insert(META_KEY_SCHEMA.as_bytes(), &KEY_SCHEMA_VERSION.to_be_bytes())
+            .context("Failed to store key schema")?;
         
-        Ok(Self { db, master_key })
+        let tree = (*db).clone();
+        Self::with_master_key(db, tree, &master_key, slot)
     }
     
-    /// Unlock existing database
+    /// Unlock existing database. The duress password opens its decoy
+    /// profile instead, after wiping the account if it was set up to.
+    #[tracing::instrument(level = "debug", skip_all)]
     pub fn unlock<P: AsRef<Path>>(path: P, password: &str) -> Result<Self> {
-        let db = sled::open(path)
+        let db = open_db(path)
+            .context("Failed to open database")?;
+        
+        let stored = db.get(PREFIX_MASTER_KEY.as_bytes())
+            .context("Failed to read master key")?
+            .ok_or_else(|| anyhow::anyhow!("No master key found"))?;
+        let mut slots = decode_key_slots(&stored)?;
+        
+        // Try every slot so the work done doesn't depend on which one
+        // opens, side by side so it takes as long as trying one
+        let opened: Vec<Option<[u8; 32]>> = std::thread::scope(|scope| {
+            let attempts: Vec<_> = slots.iter()
+                .map(|slot| scope.spawn(move || slot.unlock(password).ok()))
+                .collect();
+            attempts.into_iter()
+                .map(|attempt| attempt.join().ok().flatten())
+                .collect()
+        });
+        let (slot, master_key) = opened.into_iter()
+            .enumerate()
+            .find_map(|(i, key)| key.map(|key| (i, key)))
+            .ok_or_else(|| anyhow::anyhow!("Failed to unlock database - wrong password?"))?;
+        
+        // Databases from before duress support get their stand-in slot
+        // and profile tree now
+        if slots.len() == 1 {
+            let mut rng = rand::thread_rng();
+            create_stand_in_profile(&db, &mut rng)?;
+            let slot = (rng.next_u32() & 1) as usize;
+            let mut upgraded = [MasterKey::empty_slot(&mut rng), MasterKey::empty_slot(&mut rng)];
+            upgraded[slot] = slots.remove(0);
+            store_key_slots(&db, &upgraded)?;
+            let tree = (*db).clone();
+            return Self::with_master_key(db, tree, &master_key, slot);
+        }
+        
+        let keys = KeyHierarchy::derive(&master_key)?;
+        let tree_name = profile_tree_name(&keys);
+        let is_profile = db.tree_names().iter().any(|name| name.as_ref() == tree_name.as_bytes());
+        if !is_profile {
+            let tree = (*db).clone();
+            return Self::with_master_key(db, tree, &master_key, slot);
+        }
+        
+        let tree = db.open_tree(&tree_name)
+            .context("Failed to open profile")?;
+        let sealed = tree.get(META_PROFILE.as_bytes())
+            .context("Failed to read profile marker")?
+            .ok_or_else(|| anyhow::anyhow!("Profile marker missing"))?;
+        // Markers of profiles made before record headers have none
+        let marker = match open_unbound(&keys.storage, &sealed) {
+            Some(marker) => marker,
+            None => open_record(&keys.storage, META_PROFILE.as_bytes(), &sealed)
+                .context("Failed to decrypt profile marker")?,
+        };
+        let marker: ProfileMarker = bincode::deserialize(&marker)
+            .context("Failed to deserialize profile marker")?;
+        
+        if marker.action == DuressAction::WipeData {
+            wipe_account(&db, &mut slots, slot)
+                .context("Failed to wipe account")?;
+        }
+        
+        let fresh = tree.scan_prefix(PREFIX_IDENTITY.as_bytes()).next().is_none();
+        let storage = Self {
+            flusher: Arc::new(Mutex::new(Flusher::start(db.clone(), Durability::default())?)),
+            db,
+            tree,
+            keys: Arc::new(keys),
+            slot,
+            fresh_profile: fresh.then_some(marker.display_name),
+            cache: Arc::new(RecordCache::new(DEFAULT_CACHE_BYTES)),
+            message_writes: Arc::new(Mutex::new(())),
+        };
+        storage.migrate_record_schema()
+            .context("Failed to migrate records")?;
+        storage.migrate_indexes()
+            .context("Failed to build indexes")?;
+        Ok(storage)
+    }
+    
+    /// Set a new password with the recovery phrase and open the database.
+    /// Only the account the phrase was created with can be reset this way.
+    pub fn reset_password<P: AsRef<Path>>(path: P, phrase: &RecoveryPhrase, new_password: &str) -> Result<Self> {
+        let db = open_db(path)
             .context("Failed to open database")?;
+        let stored = db.get(META_RECOVERY.as_bytes())
+            .context("Failed to read recovery slot")?
+            .ok_or_else(|| anyhow::anyhow!("Account has no recovery phrase"))?;
+        let recovery_slot: RecoverySlot = bincode::deserialize(&stored)
+            .context("Failed to deserialize recovery slot")?;
+        let (master_key, slot) = recovery_slot.open(phrase)?;
         
         let stored = db.get(PREFIX_MASTER_KEY.as_bytes())
             .context("Failed to read master key")?
             .ok_or_else(|| anyhow::anyhow!("No master key found"))?;
+        let slots: [MasterKey; 2] = decode_key_slots(&stored)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Expected two key slots"))?;
+        if slot >= slots.len() {
+            return Err(anyhow::anyhow!("Recovery slot names key slot {}", slot));
+        }
+        // The other slot's password would stop opening the right account
+        if slots.iter().enumerate().any(|(i, other)| i != slot && other.unlock(new_password).is_ok()) {
+            return Err(anyhow::anyhow!("Choose a different password"));
+        }
+        
+        let mut slots = slots;
+        slots[slot] = MasterKey::wrap(new_password, &master_key, &mut rand::thread_rng())
+            .context("Failed to wrap master key")?;
+        store_key_slots(&db, &slots)?;
+        db.flush()
+            .context("Failed to flush new password")?;
+        
+        let tree = (*db).clone();
+        Self::with_master_key(db, tree, &master_key, slot)
+    }
+    
+    /// Derive subkeys and bring older databases up to the current key schema
+    fn with_master_key(db: Db, tree: Tree, master_key: &[u8; 32], slot: usize) -> Result<Self> {
+        let keys = Arc::new(KeyHierarchy::derive(master_key)?);
+        let flusher = Arc::new(Mutex::new(Flusher::start(db.clone(), Durability::default())?));
+        let cache = Arc::new(RecordCache::new(DEFAULT_CACHE_BYTES));
+        let message_writes = Arc::new(Mutex::new(()));
+        let storage = Self { db, tree, keys, slot, fresh_profile: None, flusher, cache, message_writes };
+        storage.migrate_key_schema(master_key)
+            .context("Failed to migrate database keys")?;
+        storage.migrate_record_schema()
+            .context("Failed to migrate records")?;
+        storage.migrate_message_schema()
+            .context("Failed to migrate messages")?;
+        storage.migrate_indexes()
+            .context("Failed to build indexes")?;
+        Ok(storage)
+    }
+    
+    /// Purpose-specific keys derived from the master key
+    pub fn keys(&self) -> &KeyHierarchy {
+        &self.keys
+    }
+    
+    /// The password-wrapped master key of this profile as stored in the
+    /// database
+    pub fn master_key_store(&self) -> Result<MasterKey> {
+        let stored = self.db.get(PREFIX_MASTER_KEY.as_bytes())
+            .context("Failed to read master key")?
+            .ok_or_else(|| anyhow::anyhow!("No master key found"))?;
+        decode_key_slots(&stored)?
+            .into_iter()
+            .nth(self.slot)
+            .ok_or_else(|| anyhow::anyhow!("No master key found"))
+    }
+    
+    /// Display name to set up a never-opened duress profile with. The
+    /// caller creates its identity as for a new account.
+    pub fn fresh_profile_name(&self) -> Option<&str> {
+        self.fresh_profile.as_deref()
+    }
+    
+    /// CRC over all keys and values; changes whenever any record does
+    pub fn checksum(&self) -> Result<u32> {
+        self.tree.checksum()
+            .context("Failed to checksum database")
+    }
+    
+    /// Re-encrypt every record written under the raw master key with the
+    /// storage subkey, and rewrap identity keys with the identity-wrap key.
+    /// Applied as a single batch so an interrupted run leaves v1 intact.
+    #[tracing::instrument(level = "debug", skip_all)]
+    fn migrate_key_schema(&self, master_key: &[u8; 32]) -> Result<()> {
+        let version = match self.db.get(META_KEY_SCHEMA.as_bytes())? {
+            Some(data) => u32::from_be_bytes(
+                data.as_ref().try_into().context("Invalid key schema marker")?
+            ),
+            None => 1,
+        };
+        if version >= KEY_SCHEMA_VERSION {
+            return Ok(());
+        }
+        
+        tracing::info!(from = version, to = KEY_SCHEMA_VERSION, "Migrating key schema");
+        let mut batch = sled::Batch::default();
+        
+        for item in self.db.iter() {
+            let (key, value) = item.context("Failed to read record")?;
+            if key.starts_with(PREFIX_MASTER_KEY.as_bytes())
+                || key.starts_with(PREFIX_SETTINGS.as_bytes())
+                || key.starts_with(PREFIX_META.as_bytes())
+            {
+                continue;
+            }
+            
+            let plaintext = match open_unbound(master_key, &value) {
+                Some(plaintext) => plaintext,
+                None => open_legacy(master_key, &value)?,
+            };
+            let plaintext = rewrap_identity_keys(&key, &plaintext, master_key, &self.keys.identity_wrap)?;
+            
+            batch.insert(key.as_ref(), seal_record(&self.keys.storage, &key, &plaintext)?);
+        }
+        
+        batch.insert(META_KEY_SCHEMA.as_bytes(), &KEY_SCHEMA_VERSION.to_be_bytes());
+        self.db.apply_batch(batch)
+            .context("Failed to apply key migration")?;
+        self.db.flush()
+            .context("Failed to flush key migration")?;
+        
+        Ok(())
+    }
+    
+    /// Reseal records from before records were bound to their keys. Applied as
+    /// a single batch, like the key migration.
+    #[tracing::instrument(level = "debug", skip_all)]
+    fn migrate_record_schema(&self) -> Result<()> {
+        let version = match self.tree.get(META_RECORD_SCHEMA.as_bytes())? {
+            Some(data) => u32::from_be_bytes(
+                data.as_ref().try_into().context("Invalid record schema marker")?
+            ),
+            None => 1,
+        };
+        if version >= RECORD_SCHEMA_VERSION {
+            return Ok(());
+        }
+        
+        let mut batch = sled::Batch::default();
+        let mut migrated = 0;
+        for item in self.tree.iter() {
+            let (key, value) = item.context("Failed to read record")?;
+            if [PREFIX_MASTER_KEY, PREFIX_META, PREFIX_SETTINGS, PREFIX_CORRUPT].iter().any(|p| key.starts_with(p.as_bytes())) {
+                continue;
+            }
+            // Written since, e.g. by a run that created the database
+            if open_record(&self.keys.storage, &key, &value).is_ok() {
+                continue;
+            }
+            // Damaged ones are left for the integrity check to find
+            let Some(plaintext) = open_unbound(&self.keys.storage, &value) else {
+                tracing::warn!(key = %String::from_utf8_lossy(&key), "Record doesn't decrypt, not migrating it");
+                continue;
+            };
+            batch.insert(key.as_ref(), seal_record(&self.keys.storage, &key, &plaintext)?);
+            migrated += 1;
+        }
+        if migrated > 0 {
+            tracing::info!(migrated, to = RECORD_SCHEMA_VERSION, "Bound records to their keys");
+        }
+        
+        batch.insert(META_RECORD_SCHEMA.as_bytes(), &RECORD_SCHEMA_VERSION.to_be_bytes());
+        self.tree.apply_batch(batch)
+            .context("Failed to apply record migration")?;
+        Ok(())
+    }
+    
+    /// Rewrite messages stored with delivery flags to carry a
+    /// `DeliveryStatus` instead
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub(crate) fn migrate_message_schema(&self) -> Result<()> {
+        let version = match self.tree.get(META_MESSAGE_SCHEMA.as_bytes())? {
+            Some(data) => u32::from_be_bytes(
+                data.as_ref().try_into().context("Invalid message schema marker")?
+            ),
+            None => 1,
+        };
+        if version >= MESSAGE_SCHEMA_VERSION {
+            return Ok(());
+        }
         
-        let encrypted: MasterKey = bincode::deserialize(&stored)
-            .context("Failed to deserialize master key")?;
+        let mut batch = sled::Batch::default();
+        let mut migrated = 0;
+        // Old message paths to new, for those that moved
+        let mut moved = HashMap::new();
+        for item in self.tree.scan_prefix(PREFIX_MESSAGE.as_bytes()) {
+            let (key, value) = item.context("Failed to read message")?;
+            let plaintext = self.decrypt_record(&key, &value)?;
+            let message = if version < 2 {
+                bincode::deserialize::<LegacyLocalMessage>(&plaintext).map(LocalMessage::from)
+            } else {
+                bincode::deserialize::<LocalMessage>(&plaintext)
+            }.with_context(|| format!("Failed to read message {}", String::from_utf8_lossy(&key)))?;
+            let path = message_path(&message);
+            let new_key = format!("{}{}", PREFIX_MESSAGE, path);
+            if new_key.as_bytes() != key.as_ref() {
+                batch.remove(key.as_ref());
+                moved.insert(key[PREFIX_MESSAGE.len()..].to_vec(), path);
+            } else if version >= 2 {
+                continue;
+            }
+            batch.insert(new_key.as_bytes(), self.encrypt(new_key.as_bytes(), &bincode::serialize(&message)?)?);
+            migrated += 1;
+        }
+        // Records about a message follow it; indexes are rebuilt after
+        for prefix in [PREFIX_STARRED, PREFIX_THUMBNAIL, PREFIX_ANNOTATION] {
+            for item in self.tree.scan_prefix(prefix.as_bytes()) {
+                let (key, value) = item.context("Failed to read message record")?;
+                if let Some(path) = moved.get(&key[prefix.len()..]) {
+                    let new_key = format!("{}{}", prefix, path);
+                    batch.insert(new_key.as_bytes(), self.encrypt(new_key.as_bytes(), &self.decrypt_record(&key, &value)?)?);
+                    batch.remove(key);
+                }
+            }
+        }
+        // `bref:<blob>/<owner>`; blob ids are hex
+        for item in self.tree.scan_prefix(PREFIX_BLOB_REF.as_bytes()) {
+            let (key, value) = item.context("Failed to read attachment reference")?;
+            let Some(split) = key[PREFIX_BLOB_REF.len()..].iter().position(|b| *b == b'/') else {
+                continue;
+            };
+            let (blob, owner) = key[PREFIX_BLOB_REF.len()..].split_at(split);
+            if let Some(path) = moved.get(&owner[1..]) {
+                let new_key = format!("{}{}/{}", PREFIX_BLOB_REF, String::from_utf8_lossy(blob), path);
+                batch.insert(new_key.as_bytes(), self.encrypt(new_key.as_bytes(), &self.decrypt_record(&key, &value)?)?);
+                batch.remove(key);
+            }
+        }
+        if migrated > 0 {
+            tracing::info!(migrated, to = MESSAGE_SCHEMA_VERSION, "Migrated messages to the new message schema");
+        }
         
-        let master_key = encrypted.unlock(password)
-            .context("Failed to unlock database - wrong password?")?;
+        batch.insert(META_MESSAGE_SCHEMA.as_bytes(), &MESSAGE_SCHEMA_VERSION.to_be_bytes());
+        self.tree.apply_batch(batch)
+            .context("Failed to apply message migration")?;
+        Ok(())
+    }
+    
+    #[tracing::instrument(level = "debug", skip_all)]
+    fn migrate_indexes(&self) -> Result<()> {
+        let version = match self.tree.get(META_INDEX_SCHEMA.as_bytes())? {
+            Some(data) => u32::from_be_bytes(
+                data.as_ref().try_into().context("Invalid index schema marker")?
+            ),
+            None => 0,
+        };
+        if version >= INDEX_SCHEMA_VERSION {
+            return Ok(());
+        }
+        tracing::info!(to = INDEX_SCHEMA_VERSION, "Building indexes");
+        self.rebuild_indexes()
+    }
+    
+    /// Rebuild the conversation and message status indexes from the
+    /// records they index, e.g. after importing a backup. Unreadable
+    /// records are left out for the integrity check to find.
+    pub fn rebuild_indexes(&self) -> Result<()> {
+        self.rebuild_indexes_with(&CancellationToken::new(), |_, _| {})
+    }
+    
+    /// `rebuild_indexes`, reporting the conversations and messages read
+    /// of their total. Nothing changes unless it runs to the end.
+    pub fn rebuild_indexes_with(&self, cancel: &CancellationToken, mut progress: impl FnMut(usize, usize)) -> Result<()> {
+        let total = self.tree.scan_prefix(PREFIX_CONVERSATION.as_bytes()).keys().count()
+            + self.tree.scan_prefix(PREFIX_MESSAGE.as_bytes()).keys().count();
+        let mut done = 0;
         
-        Ok(Self { db, master_key })
+        let mut batch = sled::Batch::default();
+        // Stats are computed again when next asked for
+        for prefix in [PREFIX_CONVERSATION_BY_CONTACT, PREFIX_MESSAGE_STATUS, PREFIX_MESSAGE_ORDER, PREFIX_ACTIVITY, PREFIX_CONVERSATION_STATS, PREFIX_SEARCH, PREFIX_DAY_COUNT] {
+            for key in self.tree.scan_prefix(prefix.as_bytes()).keys() {
+                batch.remove(key.context("Failed to read index")?);
+            }
+        }
+        for item in self.tree.scan_prefix(PREFIX_CONVERSATION.as_bytes()) {
+            cancel.check()?;
+            done += 1;
+            progress(done, total);
+            let (key, value) = item.context("Failed to read conversation")?;
+            if let Ok(conversation) = parse_record::<Conversation>(&self.decrypt_record(&key, &value).unwrap_or_default()) {
+                self.batch_insert(&mut batch, conversation_index_key(&conversation.contact_id).as_bytes(), &bincode::serialize(&conversation.id)?)?;
+            }
+        }
+        let mut days: HashMap<String, BTreeMap<Date, u64>> = HashMap::new();
+        let mut lists: HashMap<String, Vec<Posting>> = HashMap::new();
+        for item in self.tree.scan_prefix(PREFIX_MESSAGE.as_bytes()) {
+            cancel.check()?;
+            done += 1;
+            progress(done, total);
+            let (key, value) = item.context("Failed to read message")?;
+            if let Ok(message) = parse_record::<LocalMessage>(&self.decrypt_record(&key, &value).unwrap_or_default()) {
+                *days.entry(message.conversation_id.clone()).or_default().entry(stats::day_of(message.timestamp)).or_default() += 1;
+                self.batch_insert(&mut batch, status_index_key(&message.status, &message.conversation_id, &message.id).as_bytes(), &[])?;
+                for (list, posting) in self.index_postings(&message) {
+                    lists.entry(list).or_default().push(posting);
+                }
+            }
+        }
+        for (list, mut postings) in lists {
+            postings.sort();
+            postings.dedup();
+            let (directory, blocks) = Directory::build(postings);
+            for (id, block) in blocks {
+                self.batch_insert(&mut batch, index_block_key(&list, id).as_bytes(), &bincode::serialize(&block)?)?;
+            }
+            self.batch_insert(&mut batch, list.as_bytes(), &bincode::serialize(&directory)?)?;
+        }
+        for (conversation_id, counts) in days {
+            self.batch_insert(&mut batch, self.day_count_key(&conversation_id).as_bytes(), &bincode::serialize(&counts)?)?;
+        }
+        batch.insert(META_INDEX_SCHEMA.as_bytes(), &INDEX_SCHEMA_VERSION.to_be_bytes());
+        self.tree.apply_batch(batch)
+            .context("Failed to rebuild indexes")
+    }
+    
+    /// Mark the messages as written under schema `version`, e.g. before
+    /// importing a backup that predates the schema marker
+    pub(crate) fn set_message_schema(&self, version: u32) -> Result<()> {
+        self.tree.insert(META_MESSAGE_SCHEMA.as_bytes(), &version.to_be_bytes())
+            .context("Failed to store message schema")?;
+        Ok(())
     }
     
     /// Store encrypted value
@@ -90,9 +989,9 @@ impl SecureStorage {
         let serialized = bincode::serialize(value)
             .context("Failed to serialize value")?;
         
-        let encrypted = self.encrypt(&serialized)?;
+        let encrypted = self.encrypt(key.as_bytes(), &serialized)?;
         
-        self.db.insert(key.as_bytes(), encrypted)
+        self.tree.insert(key.as_bytes(), encrypted)
             .context("Failed to store value")?;
         
         Ok(())
@@ -100,9 +999,9 @@ impl SecureStorage {
     
     /// Retrieve and decrypt value
     fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
-        match self.db.get(key.as_bytes()) {
+        match self.tree.get(key.as_bytes()) {
             Ok(Some(data)) => {
-                let decrypted = self.decrypt(&data)?;
+                let decrypted = self.decrypt_cached(key.as_bytes(), &data)?;
                 let value: T = bincode::deserialize(&decrypted)
                     .context("Failed to deserialize value")?;
                 Ok(Some(value))
@@ -114,62 +1013,71 @@ impl SecureStorage {
     
     /// Delete value
     fn delete(&self, key: &str) -> Result<()> {
-        self.db.remove(key.as_bytes())
+        self.tree.remove(key.as_bytes())
             .context("Failed to delete value")?;
         Ok(())
     }
     
-    /// Encrypt data with master key + per-entry salt
-    fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        use aes_gcm::{
-            aead::{Aead, AeadCore, KeyInit},
-            Aes256Gcm, Key, Nonce,
-        };
-        use rand::RngCore;
-        
-        let mut salt = [0u8; 16];
-        rand::thread_rng().fill_bytes(&mut salt);
-        
-        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.master_key));
-        let nonce = Aes256Gcm::generate_nonce(aes_gcm::aead::OsRng);
-        
-        let ciphertext = cipher
-            .encrypt(&nonce, data)
-            .map_err(|e| anyhow::anyhow!("Encryption failed: {:?}", e))?;
-        
-        // Format: [salt:16][nonce:12][ciphertext]
-        let mut result = Vec::with_capacity(16 + 12 + ciphertext.len());
-        result.extend_from_slice(&salt);
-        result.extend_from_slice(&nonce);
-        result.extend_from_slice(&ciphertext);
-        
-        Ok(result)
+    /// Encrypt the record to be stored under `key` with the storage key
+    fn encrypt(&self, key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        seal_record(&self.keys.storage, key, data)
     }
     
-    /// Decrypt data
-    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        use aes_gcm::{
-            aead::{Aead, KeyInit},
-            Aes256Gcm, Key, Nonce,
+    /// Add the record `data` to `batch` under `key`, sealed for it
+    fn batch_insert(&self, batch: &mut sled::Batch, key: &[u8], data: &[u8]) -> Result<()> {
+        batch.insert(key, self.encrypt(key, data)?);
+        Ok(())
+    }
+    
+    /// Seal a record as the storage does; the counterpart of
+    /// `decrypt_record`
+    pub fn encrypt_record(&self, key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt(key, data)
+    }
+    
+    /// Decrypt the record stored under `key`. Any input is safe: anything
+    /// that isn't a record sealed with this storage's key for `key` is an
+    /// error, `UnsupportedRecordFormat` for one sealed by a newer build.
+    pub fn decrypt_record(&self, key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() > MAX_RECORD_LEN {
+            return Err(anyhow::anyhow!("Record of {} bytes exceeds the {} byte limit", data.len(), MAX_RECORD_LEN));
+        }
+        open_record(&self.keys.storage, key, data)
+    }
+    
+    /// `decrypt_record` for the record under `key`, through the cache for
+    /// records read often
+    fn decrypt_cached(&self, key: &[u8], data: &[u8]) -> Result<Arc<[u8]>> {
+        let cached = [PREFIX_MESSAGE, PREFIX_CONVERSATION, PREFIX_CONTACT, PREFIX_CONTACT_SETTINGS]
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_bytes()));
+        let nonce = match record_nonce(data) {
+            Some(nonce) if cached => nonce,
+            _ => return Ok(self.decrypt_record(key, data)?.into()),
         };
-        
-        if data.len() < 28 {
-            return Err(anyhow::anyhow!("Invalid encrypted data"));
+        if let Some(plaintext) = self.cache.get(key, nonce) {
+            return Ok(plaintext);
         }
-        
-        let _salt = &data[0..16];
-        let nonce = &data[16..28];
-        let ciphertext = &data[28..];
-        
-        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.master_key));
-        
-        let plaintext = cipher
-            .decrypt(Nonce::from_slice(nonce), ciphertext)
-            .map_err(|e| anyhow::anyhow!("Decryption failed: {:?}", e))?;
-        
+        let generation = self.cache.generation();
+        let plaintext: Arc<[u8]> = self.decrypt_record(key, data)?.into();
+        self.cache.insert(key, nonce, plaintext.clone(), generation);
         Ok(plaintext)
     }
     
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+    
+    /// Most plaintext the decryption cache holds; 0 turns it off
+    pub fn set_cache_capacity(&self, bytes: usize) {
+        self.cache.set_capacity(bytes);
+    }
+    
+    /// Forget every decrypted record, for every handle
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+    }
+    
     // ===== Identity Operations =====
     
     pub fn store_identity(&self, identity: &EncryptedIdentityKeys) -> Result<()> {
@@ -191,182 +1099,3954 @@ impl SecureStorage {
     }
     
     pub fn get_all_contacts(&self) -> Result<Vec<Contact>> {
-        let mut contacts = Vec::new();
-        for item in self.db.scan_prefix(PREFIX_CONTACT.as_bytes()) {
-            let (_, value) = item.context("Failed to read contact")?;
-            let decrypted = self.decrypt(&value)?;
-            let contact: Contact = bincode::deserialize(&decrypted)
-                .context("Failed to deserialize contact")?;
-            contacts.push(contact);
-        }
-        Ok(contacts)
+        self.contacts().collect()
+    }
+    
+    /// Every contact, decrypted as iterated, in no particular order
+    pub fn contacts(&self) -> Records<Contact> {
+        Records::new(self, self.tree.scan_prefix(PREFIX_CONTACT.as_bytes()), Box::new(read_record))
     }
     
     pub fn delete_contact(&self, id: &str) -> Result<()> {
+        for device in self.get_contact_devices(id)? {
+            self.delete(&device_session_key(id, &device.device_id))?;
+            self.delete(&receiving_session_key(id, &device.device_id))?;
+            self.delete(&device_capabilities_key(id, &device.device_id))?;
+        }
+        self.delete(&format!("{}{}", PREFIX_CONTACT_DEVICES, id))?;
+        self.delete(&format!("{}{}", PREFIX_CONTACT_PUSH, id))?;
+        for mut label in self.get_all_labels()? {
+            if label.members.iter().any(|member| member == id) {
+                label.members.retain(|member| member != id);
+                self.store_label(&label)?;
+            }
+        }
+        for mut folder in self.get_all_folders()? {
+            if folder.members.iter().any(|member| member == id) {
+                folder.members.retain(|member| member != id);
+                self.store_folder(&folder)?;
+            }
+        }
+        for mut list in self.get_broadcast_lists()? {
+            if list.members.iter().any(|member| member == id) {
+                list.members.retain(|member| member != id);
+                self.store_broadcast_list(&list)?;
+            }
+        }
+        self.delete(&format!("{}{}", PREFIX_CONTACT_SETTINGS, id))?;
+        self.delete(&format!("{}{}", PREFIX_CONTACT_NOTE, id))?;
+        self.delete(&format!("{}{}", PREFIX_SESSION_REQUIREMENTS, id))?;
         self.delete(&format!("{}{}", PREFIX_CONTACT, id))
     }
     
-    // ===== Conversation Operations =====
-    
-    pub fn store_conversation(&self, conversation: &Conversation) -> Result<()> {
-        self.put(&format!("{}{}", PREFIX_CONVERSATION, conversation.id), conversation)
+    pub fn store_contact_settings(&self, settings: &ContactSettings) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_CONTACT_SETTINGS, settings.contact_id), settings)
     }
     
-    pub fn get_conversation(&self, id: &str) -> Result<Option<Conversation>> {
-        self.get(&format!("{}{}", PREFIX_CONVERSATION, id))
+    pub fn get_contact_settings(&self, contact_id: &str) -> Result<Option<ContactSettings>> {
+        self.get(&format!("{}{}", PREFIX_CONTACT_SETTINGS, contact_id))
     }
     
-    pub fn get_conversation_by_contact(&self, contact_id: &str) -> Result<Option<Conversation>> {
-        for conv in self.get_all_conversations()? {
-            if conv.contact_id == contact_id {
-                return Ok(Some(conv));
+    /// The name each contact is shown under: the nickname given to them,
+    /// else their own display name
+    pub fn shown_names(&self) -> Result<HashMap<String, String>> {
+        let mut names = HashMap::new();
+        for contact in self.contacts() {
+            let contact = contact?;
+            names.insert(contact.id, contact.display_name);
+        }
+        for settings in self.get_all_contact_settings()? {
+            if let (Some(nickname), Some(name)) = (settings.nickname, names.get_mut(&settings.contact_id)) {
+                *name = nickname;
             }
         }
-        Ok(None)
+        Ok(names)
     }
     
-    pub fn get_all_conversations(&self) -> Result<Vec<Conversation>> {
-        let mut conversations = Vec::new();
-        for item in self.db.scan_prefix(PREFIX_CONVERSATION.as_bytes()) {
-            let (_, value) = item.context("Failed to read conversation")?;
-            let decrypted = self.decrypt(&value)?;
-            let conversation: Conversation = bincode::deserialize(&decrypted)
-                .context("Failed to deserialize conversation")?;
-            conversations.push(conversation);
+    pub fn get_all_contact_settings(&self) -> Result<Vec<ContactSettings>> {
+        let mut all = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_CONTACT_SETTINGS.as_bytes()) {
+            let (key, value) = item.context("Failed to read contact settings")?;
+            let decrypted = self.decrypt_record(&key, &value)?;
+            let settings: ContactSettings = bincode::deserialize(&decrypted)
+                .context("Failed to deserialize contact settings")?;
+            all.push(settings);
         }
-        // Sort by updated_at descending
-        conversations.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
-        Ok(conversations)
+        Ok(all)
     }
     
-    // ===== Message Operations =====
+    // ===== Contact Notes =====
     
-    pub fn store_message(&self, message: &LocalMessage) -> Result<()> {
-        let key = format!("{}{}/{}", PREFIX_MESSAGE, message.conversation_id, message.id);
-        self.put(&key, message)
+    pub fn store_contact_note(&self, note: &ContactNote) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_CONTACT_NOTE, note.contact_id), note)
     }
     
-    pub fn get_message(&self, conversation_id: &str, message_id: &str) -> Result<Option<LocalMessage>> {
-        let key = format!("{}{}/{}", PREFIX_MESSAGE, conversation_id, message_id);
-        self.get(&key)
+    pub fn get_contact_note(&self, contact_id: &str) -> Result<Option<ContactNote>> {
+        self.get(&format!("{}{}", PREFIX_CONTACT_NOTE, contact_id))
     }
     
-    pub fn get_messages(&self, conversation_id: &str, limit: usize) -> Result<Vec<LocalMessage>> {
-        let prefix = format!("{}{}/", PREFIX_MESSAGE, conversation_id);
-        let mut messages = Vec::new();
-        
-        for item in self.db.scan_prefix(prefix.as_bytes()) {
-            if messages.len() >= limit {
-                break;
-            }
-            let (_, value) = item.context("Failed to read message")?;
-            let decrypted = self.decrypt(&value)?;
-            let message: LocalMessage = bincode::deserialize(&decrypted)
-                .context("Failed to deserialize message")?;
-            messages.push(message);
+    /// Every note, cleared ones included
+    pub fn get_all_contact_notes(&self) -> Result<Vec<ContactNote>> {
+        let mut notes = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_CONTACT_NOTE.as_bytes()) {
+            let (key, value) = item.context("Failed to read contact note")?;
+            notes.push(parse_record(&self.decrypt_record(&key, &value)?)?);
         }
-        
-        // Sort by timestamp ascending
-        messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
-        Ok(messages)
+        Ok(notes)
     }
     
-    pub fn get_messages_before(&self, conversation_id: &str, before_id: &str, limit: usize) -> Result<Vec<LocalMessage>> {
-        let prefix = format!("{}{}/", PREFIX_MESSAGE, conversation_id);
-        let mut messages = Vec::new();
-        
-        for item in self.db.scan_prefix(prefix.as_bytes()) {
-            let (_, value) = item.context("Failed to read message")?;
-            let decrypted = self.decrypt(&value)?;
-            let message: LocalMessage = bincode::deserialize(&decrypted)
-                .context("Failed to deserialize message")?;
-            messages.push(message);
-        }
-        
-        // Sort and filter
-        messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
-        
-        if let Some(pos) = messages.iter().position(|m| m.id == before_id) {
-            let start = pos.saturating_sub(limit);
-            Ok(messages[start..pos].to_vec())
-        } else {
-            let start = messages.len().saturating_sub(limit);
-            Ok(messages[start..].to_vec())
-        }
+    // ===== Session Requirements =====
+    
+    pub fn store_session_requirements(&self, contact_id: &str, requirements: &SessionRequirements) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_SESSION_REQUIREMENTS, contact_id), requirements)
     }
     
-    pub fn delete_message(&self, conversation_id: &str, message_id: &str) -> Result<()> {
-        let key = format!("{}{}/{}", PREFIX_MESSAGE, conversation_id, message_id);
-        self.delete(&key)
+    pub fn get_session_requirements(&self, contact_id: &str) -> Result<Option<SessionRequirements>> {
+        self.get(&format!("{}{}", PREFIX_SESSION_REQUIREMENTS, contact_id))
     }
     
-    // ===== Profile Operations =====
+    // ===== Pending Contacts =====
+    
+    pub fn store_pending_contact(&self, pending: &PendingContact) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_PENDING_CONTACT, pending.id), pending)
+    }
+    
+    pub fn get_pending_contact(&self, id: &str) -> Result<Option<PendingContact>> {
+        self.get(&format!("{}{}", PREFIX_PENDING_CONTACT, id))
+    }
+    
+    pub fn get_pending_contacts(&self) -> Result<Vec<PendingContact>> {
+        let mut pending = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_PENDING_CONTACT.as_bytes()) {
+            let (key, value) = item.context("Failed to read pending contact")?;
+            pending.push(parse_record(&self.decrypt_record(&key, &value)?)?);
+        }
+        Ok(pending)
+    }
+    
+    pub fn delete_pending_contact(&self, id: &str) -> Result<()> {
+        self.delete(&format!("{}{}", PREFIX_PENDING_CONTACT, id))
+    }
+    
+    // ===== Contact Cards =====
+    
+    pub fn store_issued_card(&self, card: &IssuedCard) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_ISSUED_CARD, card.id), card)
+    }
+    
+    pub fn get_issued_card(&self, id: &str) -> Result<Option<IssuedCard>> {
+        self.get(&format!("{}{}", PREFIX_ISSUED_CARD, id))
+    }
+    
+    pub fn get_issued_cards(&self) -> Result<Vec<IssuedCard>> {
+        let mut cards = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_ISSUED_CARD.as_bytes()) {
+            let (key, value) = item.context("Failed to read issued card")?;
+            cards.push(parse_record(&self.decrypt_record(&key, &value)?)?);
+        }
+        Ok(cards)
+    }
+    
+    pub fn store_card_revocation(&self, revocation: &CardRevocation) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_CARD_REVOCATION, revocation.card_id), revocation)
+    }
+    
+    pub fn get_card_revocation(&self, card_id: &str) -> Result<Option<CardRevocation>> {
+        self.get(&format!("{}{}", PREFIX_CARD_REVOCATION, card_id))
+    }
+    
+    // ===== Escrowed Backup Keys =====
+    
+    pub fn store_escrowed_backup_key(&self, key: &EscrowedBackupKey) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_ESCROWED_BACKUP_KEY, key.info.key_id), key)
+    }
+    
+    pub fn get_escrowed_backup_keys(&self) -> Result<Vec<EscrowedBackupKey>> {
+        let mut keys = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_ESCROWED_BACKUP_KEY.as_bytes()) {
+            let (key, value) = item.context("Failed to read backup key")?;
+            keys.push(parse_record(&self.decrypt_record(&key, &value)?)?);
+        }
+        Ok(keys)
+    }
+    
+    pub fn delete_escrowed_backup_key(&self, key_id: &str) -> Result<()> {
+        self.delete(&format!("{}{}", PREFIX_ESCROWED_BACKUP_KEY, key_id))
+    }
+    
+    // ===== Contact Labels =====
+    
+    pub fn store_label(&self, label: &ContactLabel) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_LABEL, label.id), label)
+    }
+    
+    pub fn get_label(&self, id: &str) -> Result<Option<ContactLabel>> {
+        self.get(&format!("{}{}", PREFIX_LABEL, id))
+    }
+    
+    /// Every label, deleted ones included
+    pub fn get_all_labels(&self) -> Result<Vec<ContactLabel>> {
+        let mut labels = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_LABEL.as_bytes()) {
+            let (key, value) = item.context("Failed to read label")?;
+            labels.push(parse_record(&self.decrypt_record(&key, &value)?)?);
+        }
+        Ok(labels)
+    }
+    
+    // ===== Chat Folders =====
+    
+    pub fn store_folder(&self, folder: &ChatFolder) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_FOLDER, folder.id), folder)
+    }
+    
+    pub fn get_folder(&self, id: &str) -> Result<Option<ChatFolder>> {
+        self.get(&format!("{}{}", PREFIX_FOLDER, id))
+    }
+    
+    /// Every folder, deleted ones included
+    pub fn get_all_folders(&self) -> Result<Vec<ChatFolder>> {
+        let mut folders = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_FOLDER.as_bytes()) {
+            let (key, value) = item.context("Failed to read folder")?;
+            folders.push(parse_record(&self.decrypt_record(&key, &value)?)?);
+        }
+        Ok(folders)
+    }
+    
+    /// Conversations grouped by folder, folders in order and conversations
+    /// in no folder last, read in one pass. Empty folders are included.
+    pub fn conversations_by_folder(&self) -> Result<Vec<FolderGroup>> {
+        let mut folders = self.get_all_folders()?;
+        folders.retain(|folder| !folder.deleted);
+        folders.sort_by(|a, b| a.position.cmp(&b.position)
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase())));
+        
+        // Should two synced folders both claim a contact, the first wins
+        let mut group_of = HashMap::new();
+        for (index, folder) in folders.iter().enumerate() {
+            for member in &folder.members {
+                group_of.entry(member.as_str()).or_insert(index);
+            }
+        }
+        let mut groups: Vec<FolderGroup> = folders.iter()
+            .map(|folder| FolderGroup { folder: Some(folder.clone()), conversations: Vec::new(), unread_count: 0 })
+            .chain(std::iter::once(FolderGroup { folder: None, conversations: Vec::new(), unread_count: 0 }))
+            .collect();
+        let unfiled = groups.len() - 1;
+        for conversation in self.conversations() {
+            let conversation = conversation?;
+            let group = &mut groups[group_of.get(conversation.contact_id.as_str()).copied().unwrap_or(unfiled)];
+            group.unread_count = group.unread_count.saturating_add(conversation.unread_count);
+            group.conversations.push(conversation);
+        }
+        for group in &mut groups {
+            group.conversations.sort_by(|a, b| ConversationSort::Activity.compare((a, ""), (b, "")));
+        }
+        Ok(groups)
+    }
+    
+    // ===== Broadcast Lists =====
+    
+    pub fn store_broadcast_list(&self, list: &BroadcastList) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_BROADCAST_LIST, list.id), list)
+    }
+    
+    pub fn get_broadcast_list(&self, id: &str) -> Result<Option<BroadcastList>> {
+        self.get(&format!("{}{}", PREFIX_BROADCAST_LIST, id))
+    }
+    
+    pub fn get_broadcast_lists(&self) -> Result<Vec<BroadcastList>> {
+        let mut lists = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_BROADCAST_LIST.as_bytes()) {
+            let (key, value) = item.context("Failed to read broadcast list")?;
+            lists.push(parse_record(&self.decrypt_record(&key, &value)?)?);
+        }
+        Ok(lists)
+    }
+    
+    /// Delete a list along with the broadcasts sent to it. The messages
+    /// themselves stay in their conversations.
+    pub fn delete_broadcast_list(&self, id: &str) -> Result<()> {
+        for broadcast in self.get_broadcasts(id)? {
+            self.delete(&format!("{}{}", PREFIX_BROADCAST, broadcast.id))?;
+        }
+        self.delete(&format!("{}{}", PREFIX_BROADCAST_LIST, id))
+    }
+    
+    pub fn store_broadcast(&self, broadcast: &Broadcast) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_BROADCAST, broadcast.id), broadcast)
+    }
+    
+    pub fn get_broadcast(&self, id: &str) -> Result<Option<Broadcast>> {
+        self.get(&format!("{}{}", PREFIX_BROADCAST, id))
+    }
+    
+    /// Broadcasts sent to a list, oldest first
+    pub fn get_broadcasts(&self, list_id: &str) -> Result<Vec<Broadcast>> {
+        let mut broadcasts = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_BROADCAST.as_bytes()) {
+            let (key, value) = item.context("Failed to read broadcast")?;
+            let broadcast: Broadcast = parse_record(&self.decrypt_record(&key, &value)?)?;
+            if broadcast.list_id == list_id {
+                broadcasts.push(broadcast);
+            }
+        }
+        broadcasts.sort_by_key(|broadcast| broadcast.sent_at);
+        Ok(broadcasts)
+    }
+    
+    // ===== Conversation Operations =====
+    
+    pub fn store_conversation(&self, conversation: &Conversation) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        self.batch_conversation(&mut batch, conversation)?;
+        self.tree.apply_batch(batch)
+            .context("Failed to store conversation")
+    }
+    
+    /// Add a conversation record and its index entry to `batch`
+    fn batch_conversation(&self, batch: &mut sled::Batch, conversation: &Conversation) -> Result<()> {
+        let serialized = bincode::serialize(conversation)
+            .context("Failed to serialize conversation")?;
+        self.batch_insert(batch, format!("{}{}", PREFIX_CONVERSATION, conversation.id).as_bytes(), &serialized)?;
+        self.batch_insert(
+            batch,
+            conversation_index_key(&conversation.contact_id).as_bytes(),
+            &bincode::serialize(&conversation.id).context("Failed to serialize conversation id")?,
+        )?;
+        Ok(())
+    }
+    
+    pub fn get_conversation(&self, id: &str) -> Result<Option<Conversation>> {
+        self.get(&format!("{}{}", PREFIX_CONVERSATION, id))
+    }
+    
+    /// Every conversation, decrypted as iterated, in no particular order
+    pub fn conversations(&self) -> Records<Conversation> {
+        Records::new(self, self.tree.scan_prefix(PREFIX_CONVERSATION.as_bytes()), Box::new(read_record))
+    }    
+    pub fn get_conversation_by_contact(&self, contact_id: &str) -> Result<Option<Conversation>> {
+        match self.get::<String>(&conversation_index_key(contact_id))? {
+            Some(conversation_id) => self.get_conversation(&conversation_id),
+            None => Ok(None),
+        }
+    }
+    
+    pub fn get_all_conversations(&self) -> Result<Vec<Conversation>> {
+        let mut conversations = self.conversations().collect::<Result<Vec<_>>>()?;
+        // Sort by updated_at descending
+        conversations.sort_by_key(|c| std::cmp::Reverse(c.updated_at));
+        Ok(conversations)
+    }
+    
+    /// One page of the conversations matching `query`. Conversations are
+    /// filtered as they are decrypted, and contacts are only read when
+    /// sorting by name.
+    pub fn query_conversations(&self, query: &ConversationQuery) -> Result<ConversationPage> {
+        query.validate()?;
+        let labelled = match &query.filter.label_id {
+            Some(label_id) => {
+                let label = self.get_label(label_id)?
+                    .filter(|label| !label.deleted)
+                    .ok_or_else(|| anyhow::anyhow!("Label not found"))?;
+                Some(label.members.into_iter().collect::<HashSet<_>>())
+            }
+            None => None,
+        };
+        let mut matches = Vec::new();
+        for conversation in self.conversations() {
+            let conversation = conversation?;
+            if query.filter.matches(&conversation, labelled.as_ref()) {
+                matches.push(conversation);
+            }
+        }
+        
+        let names = match query.sort {
+            ConversationSort::Name => self.shown_names()?,
+            _ => HashMap::new(),
+        };
+        let name = |conversation: &Conversation| {
+            names.get(&conversation.contact_id).map(String::as_str).unwrap_or_default()
+        };
+        matches.sort_by(|a, b| query.sort.compare((a, name(a)), (b, name(b))));
+        
+        let total = matches.len();
+        let conversations = matches.into_iter()
+            .skip(query.offset)
+            .take(query.limit)
+            .collect();
+        Ok(ConversationPage { conversations, total })
+    }
+    
+    // ===== Message Operations =====
+    
+    #[tracing::instrument(level = "trace", skip_all, fields(conversation_id = %message.conversation_id, message_id = %message.id))]
+    pub fn store_message(&self, message: &LocalMessage) -> Result<()> {
+        let key = format!("{}{}", PREFIX_MESSAGE, message_path(message));
+        let _writes = self.message_writes();
+        // Its order entries move if the clock or time changed. One left
+        // behind by an unreadable record is skipped on reading and
+        // collected later.
+        let old = self.get::<LocalMessage>(&key).ok().flatten();
+        
+        // The message, its index entries and the stats change together
+        let mut batch = sled::Batch::default();
+        let mut index = IndexWrites::new(self);
+        let mut days = BTreeMap::new();
+        *days.entry(stats::day_of(message.timestamp)).or_insert(0) += 1;
+        if let Some(old) = &old {
+            *days.entry(stats::day_of(old.timestamp)).or_insert(0) -= 1;
+            index.remove(old)?;
+        }
+        self.batch_message(&mut batch, &mut index, message)?;
+        index.finish(&mut batch)?;
+        self.batch_stats(&mut batch, &message.conversation_id, |record| match &old {
+            Some(old) => record.replace(old, message),
+            None => record.add(message),
+        })?;
+        self.batch_day_counts(&mut batch, &message.conversation_id, days)?;
+        self.tree.apply_batch(batch)
+            .context("Failed to store message")?;
+        Ok(())
+    }
+    
+    /// Store new messages of `conversation` in one write, skipping those
+    /// already stored, and move the conversation's clock past theirs.
+    /// Returns how many were added.
+    pub fn store_messages_batch(&self, conversation: &mut Conversation, messages: &[LocalMessage]) -> Result<usize> {
+        let _writes = self.message_writes();
+        let mut batch = sled::Batch::default();
+        let mut index = IndexWrites::new(self);
+        let mut added = HashSet::new();
+        let mut stored = Vec::new();
+        let mut days = BTreeMap::new();
+        for message in messages {
+            if message.conversation_id != conversation.id {
+                return Err(anyhow::anyhow!("Message {} is not in conversation {}", message.id, conversation.id));
+            }
+            let key = format!("{}{}", PREFIX_MESSAGE, message_path(message));
+            if self.tree.contains_key(key.as_bytes())? || !added.insert(message.id.as_str()) {
+                continue;
+            }
+            self.batch_message(&mut batch, &mut index, message)?;
+            stored.push(message);
+            *days.entry(stats::day_of(message.timestamp)).or_insert(0) += 1;
+            conversation.lamport = conversation.lamport.max(message.lamport);
+        }
+        if added.is_empty() {
+            return Ok(0);
+        }
+        // In conversation order, so a batch of new messages is added
+        // incrementally
+        stored.sort_by_key(|message| (message.lamport, message.timestamp));
+        self.batch_stats(&mut batch, &conversation.id, |record| {
+            for message in &stored {
+                record.add(message);
+            }
+        })?;
+        self.batch_day_counts(&mut batch, &conversation.id, days)?;
+        self.batch_conversation(&mut batch, conversation)?;
+        index.finish(&mut batch)?;
+        self.tree.apply_batch(batch)
+            .context("Failed to store messages")?;
+        Ok(added.len())
+    }
+    
+    /// Add a message record with its index entries and attachment
+    /// reference to `batch`, its postings to `index`
+    fn batch_message(&self, batch: &mut sled::Batch, index: &mut IndexWrites, message: &LocalMessage) -> Result<()> {
+        let key = format!("{}{}", PREFIX_MESSAGE, message_path(message));
+        if let Some(attachment) = message.content.attachment() {
+            let reference = format!("{}{}/{}", PREFIX_BLOB_REF, self.blob_id(attachment), message_path(message));
+            self.batch_insert(batch, reference.as_bytes(), &[])?;
+        }
+        
+        let serialized = bincode::serialize(message)
+            .context("Failed to serialize message")?;
+        let star_key = format!("{}{}", PREFIX_STARRED, message_path(message));
+        self.batch_insert(batch, key.as_bytes(), &serialized)?;
+        if message.starred {
+            self.batch_insert(batch, star_key.as_bytes(), &[])?;
+        } else {
+            batch.remove(star_key.as_bytes());
+        }
+        for name in STATUS_NAMES {
+            batch.remove(format!("{}{}/{}", PREFIX_MESSAGE_STATUS, name, message_path(message)).as_bytes());
+        }
+        self.batch_insert(batch, status_index_key(&message.status, &message.conversation_id, &message.id).as_bytes(), &[])?;
+        index.add(message)
+    }
+    
+    /// A message's postings, with the index lists they go in: its
+    /// conversation's order, the activity list, and one per term it is
+    /// indexed under
+    fn index_postings(&self, message: &LocalMessage) -> Vec<(String, Posting)> {
+        let path = message_path(message);
+        let time = time_sort(message.timestamp);
+        let mut postings = vec![
+            (self.index_list(PREFIX_MESSAGE_ORDER, &message.conversation_id), Posting { sort: order_sort(message), path: path.clone() }),
+            (self.index_list(PREFIX_ACTIVITY, ""), Posting { sort: time.clone(), path: path.clone() }),
+        ];
+        if let Some(text) = search::message_text(&message.content) {
+            for term in search::index_terms(text) {
+                postings.push((self.index_list(PREFIX_SEARCH, &term), Posting { sort: time.clone(), path: path.clone() }));
+            }
+        }
+        postings
+    }
+    
+    /// `<prefix><token>`, the key of the index list or record `name` has
+    /// under `prefix`. The token is a keyed hash, so keys don't give away
+    /// which conversation or term they are for.
+    fn index_list(&self, prefix: &str, name: &str) -> String {
+        let mut hasher = blake3::Hasher::new_keyed(&self.keys.search_index);
+        hasher.update(prefix.as_bytes());
+        hasher.update(name.as_bytes());
+        format!("{}{}", prefix, &hasher.finalize().to_hex()[..32])
+    }
+    
+    /// An index list's directory; an empty one if the list has none
+    fn index_directory(&self, list: &str) -> Result<Directory> {
+        Ok(self.get(list)?.unwrap_or_default())
+    }
+    
+    /// Block `id` of an index list
+    fn index_block(&self, list: &str, id: u64) -> Result<Vec<Posting>> {
+        Ok(self.get(&index_block_key(list, id))?.unwrap_or_default())
+    }
+    
+    /// An index list's postings from `from` up to, not including,
+    /// `before`, decrypting each block as it is reached
+    fn postings(&self, list: String, from: Option<Posting>, before: Option<Posting>) -> impl DoubleEndedIterator<Item = Result<Posting>> + Send + 'static {
+        let (blocks, error) = match self.index_directory(&list) {
+            Ok(directory) => (directory.blocks_between(from.as_ref(), before.as_ref()), None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        let storage = self.clone();
+        let postings = blocks.into_iter().flat_map(move |id| match storage.index_block(&list, id) {
+            Ok(block) => block.into_iter().map(Ok).collect::<Vec<_>>(),
+            Err(e) => vec![Err(e)],
+        });
+        let in_range = move |posting: &Result<Posting>| posting.as_ref().map_or(true, |posting| {
+            from.as_ref().is_none_or(|from| posting >= from) && before.as_ref().is_none_or(|before| posting < before)
+        });
+        error.map(Err).into_iter().chain(postings.filter(in_range))
+    }
+    
+    pub fn get_message(&self, conversation_id: &str, message_id: &str) -> Result<Option<LocalMessage>> {
+        let key = format!("{}{}", PREFIX_MESSAGE, message_path_of(conversation_id, message_id));
+        self.get(&key)
+    }
+    
+    pub fn get_messages(&self, conversation_id: &str, limit: usize) -> Result<Vec<LocalMessage>> {
+        // The newest `limit` messages in conversation order
+        let mut messages = self.messages(conversation_id).rev().take(limit).collect::<Result<Vec<_>>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+    
+    pub fn get_messages_before(&self, conversation_id: &str, before_id: &str, limit: usize) -> Result<Vec<LocalMessage>> {
+        let mut messages = self.messages_before(conversation_id, before_id)?.rev().take(limit).collect::<Result<Vec<_>>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+    
+    /// A conversation's messages in conversation order, decrypted as
+    /// iterated; reverse it to page back from the newest
+    pub fn messages(&self, conversation_id: &str) -> Records<LocalMessage> {
+        let list = self.index_list(PREFIX_MESSAGE_ORDER, conversation_id);
+        Records::from_postings(self, self.postings(list, None, None), read_ordered_message)
+    }
+    
+    /// The messages before `before_id` in conversation order, or all of
+    /// them if there is no such message
+    pub fn messages_before(&self, conversation_id: &str, before_id: &str) -> Result<Records<LocalMessage>> {
+        let Some(before) = self.get_message(conversation_id, before_id)? else {
+            return Ok(self.messages(conversation_id));
+        };
+        let list = self.index_list(PREFIX_MESSAGE_ORDER, conversation_id);
+        let before = Posting { sort: order_sort(&before), path: message_path(&before) };
+        Ok(Records::from_postings(self, self.postings(list, None, Some(before)), read_ordered_message))
+    }
+    
+    /// Every message by time, across conversations, decrypted as
+    /// iterated; reverse it for the newest first
+    pub fn activity(&self) -> Records<LocalMessage> {
+        let list = self.index_list(PREFIX_ACTIVITY, "");
+        Records::from_postings(self, self.postings(list, None, None), read_activity_message)
+    }
+    
+    /// Look up a message by id alone. Scans message keys without decrypting
+    /// anything but the match.
+    pub fn find_message(&self, message_id: &str) -> Result<Option<LocalMessage>> {
+        let suffix = format!("/{}", message_id);
+        for item in self.tree.scan_prefix(PREFIX_MESSAGE.as_bytes()) {
+            let (key, value) = item.context("Failed to read message")?;
+            if key.ends_with(suffix.as_bytes()) {
+                let decrypted = self.decrypt_cached(&key, &value)?;
+                let message = bincode::deserialize(&decrypted)
+                    .context("Failed to deserialize message")?;
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+    
+    /// Messages matching the query `words`, with their scores, newest
+    /// first. Reads the search index under the longest word and checks at
+    /// most `search::MAX_MESSAGE_CANDIDATES` of the messages it holds.
+    pub fn search_messages(&self, words: &[String]) -> Result<Vec<(LocalMessage, u32)>> {
+        let Some(term) = words.iter().filter_map(|word| search::lookup_term(word)).max_by_key(|term| term.chars().count()) else {
+            return Ok(Vec::new());
+        };
+        let list = self.index_list(PREFIX_SEARCH, &term);
+        let mut found = Vec::new();
+        for posting in self.postings(list, None, None).rev().take(search::MAX_MESSAGE_CANDIDATES) {
+            let posting = posting?;
+            let Some(message) = self.get::<LocalMessage>(&format!("{}{}", PREFIX_MESSAGE, posting.path))? else {
+                continue;
+            };
+            if let Some(score) = search::message_text(&message.content).and_then(|text| search::score(text, words)) {
+                found.push((message, score));
+            }
+        }
+        Ok(found)
+    }
+    
+    pub fn delete_message(&self, conversation_id: &str, message_id: &str) -> Result<()> {
+        self.remove_message(conversation_id, message_id).map(|_| ())
+    }
+    
+    /// Delete a message, returning its attachment if that went with it
+    fn remove_message(&self, conversation_id: &str, message_id: &str) -> Result<Option<AttachmentRef>> {
+        let key = format!("{}{}", PREFIX_MESSAGE, message_path_of(conversation_id, message_id));
+        let _writes = self.message_writes();
+        let mut freed = None;
+        let mut batch = sled::Batch::default();
+        let mut index = IndexWrites::new(self);
+        if let Some(message) = self.get::<LocalMessage>(&key)? {
+            if let Some(attachment) = message.content.attachment() {
+                if self.remove_blob_ref(attachment, &message_path_of(conversation_id, message_id))? {
+                    freed = Some(attachment.clone());
+                }
+            }
+            index.remove(&message)?;
+            self.batch_stats(&mut batch, conversation_id, StatsRecord::remove)?;
+            self.batch_day_counts(&mut batch, conversation_id, BTreeMap::from([(stats::day_of(message.timestamp), -1)]))?;
+        }
+        batch.remove(format!("{}{}", PREFIX_STARRED, message_path_of(conversation_id, message_id)).as_bytes());
+        batch.remove(format!("{}{}", PREFIX_THUMBNAIL, message_path_of(conversation_id, message_id)).as_bytes());
+        batch.remove(format!("{}{}", PREFIX_ANNOTATION, message_path_of(conversation_id, message_id)).as_bytes());
+        for name in STATUS_NAMES {
+            batch.remove(format!("{}{}/{}", PREFIX_MESSAGE_STATUS, name, message_path_of(conversation_id, message_id)).as_bytes());
+        }
+        batch.remove(key.as_bytes());
+        index.finish(&mut batch)?;
+        self.tree.apply_batch(batch)
+            .context("Failed to delete message")?;
+        Ok(freed)
+    }
+    
+    /// Delete the messages `policy` no longer keeps in a conversation
+    pub fn prune_conversation(&self, conversation_id: &str, policy: &RetentionPolicy, now: OffsetDateTime) -> Result<PruneReport> {
+        let mut report = PruneReport::default();
+        if policy.keeps_everything() {
+            return Ok(report);
+        }
+        let messages = self.messages(conversation_id).collect::<Result<Vec<_>>>()?;
+        for message in policy.expired(&messages, now) {
+            if let Some(attachment) = self.remove_message(conversation_id, &message.id)? {
+                report.attachments += 1;
+                report.bytes_freed += attachment.size;
+            }
+            report.messages += 1;
+        }
+        Ok(report)
+    }
+    
+    /// Star or unstar a message
+    pub fn set_message_starred(&self, conversation_id: &str, message_id: &str, starred: bool) -> Result<()> {
+        let mut message = self.get_message(conversation_id, message_id)?
+            .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+        message.starred = starred;
+        self.store_message(&message)
+    }
+    
+    /// Starred messages across all conversations, newest first. Reads the
+    /// starred index instead of scanning every conversation.
+    pub fn get_starred_messages(&self) -> Result<Vec<LocalMessage>> {
+        let mut messages = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_STARRED.as_bytes()) {
+            let (key, _) = item.context("Failed to read starred index")?;
+            let path = std::str::from_utf8(&key[PREFIX_STARRED.len()..])
+                .context("Corrupt starred index key")?;
+            if let Some(message) = self.get::<LocalMessage>(&format!("{}{}", PREFIX_MESSAGE, path))? {
+                messages.push(message);
+            }
+        }
+        
+        messages.sort_by_key(|m| std::cmp::Reverse(m.timestamp));
+        Ok(messages)
+    }
+    
+    /// Messages in any of `statuses` across all conversations, oldest
+    /// first. Any `Failed` status matches every failed message. Reads the
+    /// status index instead of scanning every conversation.
+    pub fn get_messages_by_status(&self, statuses: &[DeliveryStatus]) -> Result<Vec<LocalMessage>> {
+        let mut names: Vec<_> = statuses.iter().map(status_name).collect();
+        names.sort_unstable();
+        names.dedup();
+        let mut messages = Vec::new();
+        for name in names {
+            let prefix = format!("{}{}/", PREFIX_MESSAGE_STATUS, name);
+            for key in self.tree.scan_prefix(prefix.as_bytes()).keys() {
+                let key = key.context("Failed to read status index")?;
+                let path = std::str::from_utf8(&key[prefix.len()..])
+                    .context("Corrupt status index key")?;
+                if let Some(message) = self.get::<LocalMessage>(&format!("{}{}", PREFIX_MESSAGE, path))? {
+                    messages.push(message);
+                }
+            }
+        }
+        
+        messages.sort_by_key(|m| m.timestamp);
+        Ok(messages)
+    }
+    
+    /// Our messages not yet confirmed by the recipient, failed ones included
+    pub fn get_undelivered_messages(&self) -> Result<Vec<LocalMessage>> {
+        let failed = DeliveryStatus::Failed { reason: String::new() };
+        self.get_messages_by_status(&[DeliveryStatus::Queued, DeliveryStatus::Sending, DeliveryStatus::Sent, failed])
+    }
+    
+    /// Add plugins' notes to those already on a message
+    pub fn add_annotations(&self, conversation_id: &str, message_id: &str, annotations: &[Annotation]) -> Result<()> {
+        let mut all = self.get_annotations(conversation_id, message_id)?;
+        all.extend_from_slice(annotations);
+        self.put(&format!("{}{}", PREFIX_ANNOTATION, message_path_of(conversation_id, message_id)), &all)
+    }
+    
+    pub fn get_annotations(&self, conversation_id: &str, message_id: &str) -> Result<Vec<Annotation>> {
+        Ok(self.get(&format!("{}{}", PREFIX_ANNOTATION, message_path_of(conversation_id, message_id)))?
+            .unwrap_or_default())
+    }
+    
+    /// Statistics of a conversation's messages. Kept up to date as they are
+    /// written, and computed from them the first time, or after a change
+    /// that couldn't be applied to the kept ones (see `stats`).
+    pub fn get_conversation_stats(&self, conversation_id: &str) -> Result<ConversationStats> {
+        let key = format!("{}{}", PREFIX_CONVERSATION_STATS, conversation_id);
+        if let Some(record) = self.get::<StatsRecord>(&key)?.filter(|record| !record.is_stale()) {
+            return Ok(record.stats);
+        }
+        // Messages written meanwhile would be missed, or counted twice
+        let _writes = self.message_writes();
+        let messages = self.messages(conversation_id).collect::<Result<Vec<_>>>()?;
+        let record = StatsRecord::compute(&messages);
+        self.put(&key, &record)?;
+        Ok(record.stats)
+    }
+    
+    /// Apply `update` to a conversation's stats in `batch`. Stats not
+    /// computed yet are left to be computed when first asked for.
+    fn batch_stats(&self, batch: &mut sled::Batch, conversation_id: &str, update: impl FnOnce(&mut StatsRecord)) -> Result<()> {
+        let key = format!("{}{}", PREFIX_CONVERSATION_STATS, conversation_id);
+        let Some(mut record) = self.get::<StatsRecord>(&key)? else {
+            return Ok(());
+        };
+        update(&mut record);
+        self.batch_insert(batch, key.as_bytes(), &bincode::serialize(&record)?)
+    }
+    
+    /// Add `changes` to a conversation's message counts per day in `batch`
+    fn batch_day_counts(&self, batch: &mut sled::Batch, conversation_id: &str, changes: BTreeMap<Date, i64>) -> Result<()> {
+        let key = self.day_count_key(conversation_id);
+        let mut counts = self.get::<BTreeMap<Date, u64>>(&key)?.unwrap_or_default();
+        for (day, change) in changes {
+            let count = counts.get(&day).copied().unwrap_or(0).saturating_add_signed(change);
+            if count == 0 {
+                counts.remove(&day);
+            } else {
+                counts.insert(day, count);
+            }
+        }
+        if counts.is_empty() {
+            batch.remove(key.as_bytes());
+        } else {
+            self.batch_insert(batch, key.as_bytes(), &bincode::serialize(&counts)?)?;
+        }
+        Ok(())
+    }
+    
+    /// `day:<token>`, a conversation's counts per day
+    fn day_count_key(&self, conversation_id: &str) -> String {
+        self.index_list(PREFIX_DAY_COUNT, conversation_id)
+    }
+    
+    /// How many messages a conversation has on each day it has any, oldest
+    /// first. Reads one record, kept as messages are written.
+    pub fn get_message_count_by_day(&self, conversation_id: &str) -> Result<Vec<DayCount>> {
+        let counts = self.get::<BTreeMap<Date, u64>>(&self.day_count_key(conversation_id))?.unwrap_or_default();
+        Ok(counts.into_iter()
+            .map(|(date, messages)| DayCount { date, messages })
+            .collect())
+    }
+    
+    /// A conversation's first message at or after `at` by time, for
+    /// jumping to a date. The counts per day give the first day from `at`
+    /// with messages, so only that day's activity is read.
+    pub fn get_message_at_date(&self, conversation_id: &str, at: OffsetDateTime) -> Result<Option<LocalMessage>> {
+        let counts = self.get::<BTreeMap<Date, u64>>(&self.day_count_key(conversation_id))?.unwrap_or_default();
+        let list = self.index_list(PREFIX_ACTIVITY, "");
+        let conversation = conversation_path(conversation_id);
+        for &day in counts.range(stats::day_of(at)..).map(|(day, _)| day) {
+            let from = Posting::at(time_sort(day.midnight().assume_utc().max(at)));
+            let before = day.next_day().map(|next| Posting::at(time_sort(next.midnight().assume_utc())));
+            // Other conversations' postings are skipped without reading
+            // their messages
+            for posting in self.postings(list.clone(), Some(from), before) {
+                let posting = posting?;
+                if !posting.path.starts_with(&conversation) {
+                    continue;
+                }
+                if let Some(message) = read_activity_message(self, posting)? {
+                    return Ok(Some(message));
+                }
+            }
+        }
+        Ok(None)
+    }
+    
+    fn message_writes(&self) -> std::sync::MutexGuard<'_, ()> {
+        self.message_writes.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    
+    // ===== Attachment Operations =====
+    
+    pub fn store_thumbnail(&self, conversation_id: &str, message_id: &str, thumbnail: &Thumbnail) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_THUMBNAIL, message_path_of(conversation_id, message_id)), thumbnail)
+    }
+    
+    pub fn get_thumbnail(&self, conversation_id: &str, message_id: &str) -> Result<Option<Thumbnail>> {
+        self.get(&format!("{}{}", PREFIX_THUMBNAIL, message_path_of(conversation_id, message_id)))
+    }
+    
+    /// Store attachment bytes. Identical content is stored once; the blob
+    /// lives as long as some message refers to it.
+    pub fn store_blob(&self, data: &[u8]) -> Result<AttachmentRef> {
+        let mut writer = self.blob_writer();
+        writer.write(data)?;
+        writer.finish()
+    }
+    
+    /// Store an attachment chunk by chunk, without holding it in memory
+    pub fn blob_writer(&self) -> BlobWriter<'_> {
+        BlobWriter {
+            storage: self,
+            staging: format!("{}staging-{:032x}", PREFIX_BLOB, rand::random::<u128>()),
+            hasher: blake3::Hasher::new(),
+            buffer: Vec::with_capacity(ATTACHMENT_CHUNK_SIZE),
+            chunks: 0,
+            size: 0,
+            finished: false,
+        }
+    }
+    
+    /// Store an attachment read from `reader`, holding one chunk of it in
+    /// memory at a time. Once `cancel` is cancelled, the chunks staged so
+    /// far are removed.
+    pub fn store_blob_from<R: std::io::Read>(&self, mut reader: R, cancel: &CancellationToken) -> Result<AttachmentRef> {
+        let mut writer = self.blob_writer();
+        let mut buffer = vec![0u8; ATTACHMENT_CHUNK_SIZE];
+        loop {
+            cancel.check()?;
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("Failed to read attachment"),
+            };
+            writer.write(&buffer[..read])?;
+        }
+        writer.finish()
+    }
+    
+    /// Read a complete attachment chunk by chunk, or None if it isn't
+    /// (fully) here yet. The digest is checked at the end, so a reader
+    /// must be read to the end before its data is trusted.
+    pub fn blob_reader(&self, attachment: &AttachmentRef) -> Result<Option<BlobReader<'_>>> {
+        if !self.has_blob(attachment)? {
+            return Ok(None);
+        }
+        Ok(Some(BlobReader {
+            storage: self,
+            attachment: attachment.clone(),
+            hasher: blake3::Hasher::new(),
+            chunk: Vec::new(),
+            position: 0,
+            next_index: 0,
+        }))
+    }
+    
+    /// The complete attachment, or None if it isn't (fully) here yet
+    pub fn get_blob(&self, attachment: &AttachmentRef) -> Result<Option<Vec<u8>>> {
+        if !self.has_blob(attachment)? {
+            return Ok(None);
+        }
+        let mut data = Vec::with_capacity(attachment.size as usize);
+        for index in 0..attachment.chunk_count() {
+            let chunk = self.get_blob_chunk(attachment, index)?
+                .ok_or_else(|| anyhow::anyhow!("Attachment chunk vanished"))?;
+            data.extend_from_slice(&chunk);
+        }
+        if blake3::hash(&data).as_bytes() != &attachment.digest {
+            return Err(anyhow::anyhow!("Attachment does not match its digest"));
+        }
+        Ok(Some(data))
+    }
+    
+    /// One chunk of an attachment, for streaming reads while the rest is
+    /// still downloading
+    pub fn get_blob_chunk(&self, attachment: &AttachmentRef, index: u32) -> Result<Option<Vec<u8>>> {
+        let key = self.blob_chunk_key(attachment, index);
+        match self.tree.get(key.as_bytes())? {
+            Some(stored) => Ok(Some(self.decrypt_record(key.as_bytes(), &stored)?)),
+            None => Ok(None),
+        }
+    }
+    
+    /// Whether every chunk of an attachment is stored
+    pub fn has_blob(&self, attachment: &AttachmentRef) -> Result<bool> {
+        let prefix = format!("{}/", self.blob_key(attachment));
+        Ok(self.tree.scan_prefix(prefix.as_bytes()).count() == attachment.chunk_count() as usize)
+    }
+    
+    /// Store a received chunk. Once the last one arrives the whole
+    /// attachment is checked against its digest.
+    pub fn store_blob_chunk(&self, attachment: &AttachmentRef, index: u32, data: &[u8]) -> Result<()> {
+        if attachment.chunk_len(index) != Some(data.len()) {
+            return Err(anyhow::anyhow!("Attachment chunk has wrong index or length"));
+        }
+        if self.get_view_once(attachment)?.is_some_and(|v| v.viewed_at.is_some()) {
+            return Err(anyhow::anyhow!("View-once media was already opened"));
+        }
+        let key = self.blob_chunk_key(attachment, index);
+        self.tree.insert(key.as_bytes(), self.encrypt(key.as_bytes(), data)?)
+            .context("Failed to store attachment chunk")?;
+        
+        if self.has_blob(attachment)? {
+            let mut hasher = blake3::Hasher::new();
+            for index in 0..attachment.chunk_count() {
+                if let Some(chunk) = self.get_blob_chunk(attachment, index)? {
+                    hasher.update(&chunk);
+                }
+            }
+            if hasher.finalize().as_bytes() != &attachment.digest {
+                self.delete_blob(attachment)?;
+                return Err(anyhow::anyhow!("Attachment does not match its digest"));
+            }
+        }
+        Ok(())
+    }
+    
+    /// Mark an attachment view-once. Marking it again keeps whether it
+    /// was opened.
+    pub fn mark_view_once(&self, attachment: &AttachmentRef) -> Result<()> {
+        if self.get_view_once(attachment)?.is_none() {
+            self.put(&format!("{}{}", PREFIX_VIEW_ONCE, self.blob_id(attachment)), &ViewOnce { viewed_at: None })?;
+        }
+        Ok(())
+    }
+    
+    /// None unless the attachment is view-once
+    pub fn get_view_once(&self, attachment: &AttachmentRef) -> Result<Option<ViewOnce>> {
+        self.get(&format!("{}{}", PREFIX_VIEW_ONCE, self.blob_id(attachment)))
+    }
+    
+    /// Record that view-once media was opened and delete it, whatever
+    /// else refers to it
+    pub fn consume_view_once(&self, attachment: &AttachmentRef, at: OffsetDateTime) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_VIEW_ONCE, self.blob_id(attachment)), &ViewOnce { viewed_at: Some(at) })?;
+        self.delete_blob(attachment)
+    }
+    
+    /// Number of messages and sticker packs referring to an attachment
+    pub fn blob_ref_count(&self, attachment: &AttachmentRef) -> Result<usize> {
+        let prefix = format!("{}{}/", PREFIX_BLOB_REF, self.blob_id(attachment));
+        Ok(self.tree.scan_prefix(prefix.as_bytes()).count())
+    }
+    
+    /// Record that `owner` (a message path or a sticker pack) uses an attachment
+    fn add_blob_ref(&self, attachment: &AttachmentRef, owner: &str) -> Result<()> {
+        let key = format!("{}{}/{}", PREFIX_BLOB_REF, self.blob_id(attachment), owner);
+        self.tree.insert(key.as_bytes(), self.encrypt(key.as_bytes(), &[])?)
+            .context("Failed to store attachment reference")?;
+        Ok(())
+    }
+    
+    /// Drop one reference, deleting the blob with its last reference.
+    /// Returns whether the blob was deleted.
+    fn remove_blob_ref(&self, attachment: &AttachmentRef, owner: &str) -> Result<bool> {
+        let key = format!("{}{}/{}", PREFIX_BLOB_REF, self.blob_id(attachment), owner);
+        self.delete(&key)?;
+        if self.blob_ref_count(attachment)? == 0 {
+            self.delete_blob(attachment)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+    
+    fn delete_blob(&self, attachment: &AttachmentRef) -> Result<()> {
+        let prefix = format!("{}/", self.blob_key(attachment));
+        for key in self.tree.scan_prefix(prefix.as_bytes()).keys() {
+            self.tree.remove(key?).context("Failed to delete attachment")?;
+        }
+        Ok(())
+    }
+    
+    /// Keyed hash of the digest, so database keys don't reveal content hashes
+    fn blob_id(&self, attachment: &AttachmentRef) -> String {
+        blake3::keyed_hash(&self.keys.attachment_id, &attachment.digest).to_hex().to_string()
+    }
+    
+    fn blob_key(&self, attachment: &AttachmentRef) -> String {
+        format!("{}{}", PREFIX_BLOB, self.blob_id(attachment))
+    }
+    
+    fn blob_chunk_key(&self, attachment: &AttachmentRef, index: u32) -> String {
+        format!("{}/{:08x}", self.blob_key(attachment), index)
+    }
+    
+    // ===== Sticker Operations =====
+    
+    /// Install a sticker pack; its images are kept until it is removed
+    pub fn store_sticker_pack(&self, pack: &StickerPack) -> Result<()> {
+        let owner = format!("sticker/{}", pack.id_hex());
+        for sticker in &pack.stickers {
+            self.add_blob_ref(&sticker.image, &owner)?;
+        }
+        self.put(&format!("{}{}", PREFIX_STICKER_PACK, pack.id_hex()), pack)
+    }
+    
+    pub fn get_sticker_pack(&self, pack_id: &[u8; 32]) -> Result<Option<StickerPack>> {
+        let id = blake3::Hash::from_bytes(*pack_id).to_hex();
+        self.get(&format!("{}{}", PREFIX_STICKER_PACK, id))
+    }
+    
+    pub fn get_all_sticker_packs(&self) -> Result<Vec<StickerPack>> {
+        let mut packs = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_STICKER_PACK.as_bytes()) {
+            let (key, value) = item.context("Failed to read sticker pack")?;
+            let decrypted = self.decrypt_record(&key, &value)?;
+            let pack: StickerPack = bincode::deserialize(&decrypted)
+                .context("Failed to deserialize sticker pack")?;
+            packs.push(pack);
+        }
+        packs.sort_by(|a, b| a.title.cmp(&b.title));
+        Ok(packs)
+    }
+    
+    /// Remove a pack. Stickers already sent or received stay readable.
+    pub fn delete_sticker_pack(&self, pack_id: &[u8; 32]) -> Result<()> {
+        let Some(pack) = self.get_sticker_pack(pack_id)? else {
+            return Ok(());
+        };
+        let owner = format!("sticker/{}", pack.id_hex());
+        for sticker in &pack.stickers {
+            self.remove_blob_ref(&sticker.image, &owner)?;
+        }
+        self.delete(&format!("{}{}", PREFIX_STICKER_PACK, pack.id_hex()))
+    }
+    
+    // ===== Profile Operations =====
     
     pub fn store_profile(&self, profile: &UserProfile) -> Result<()> {
         self.put(&format!("{}self", PREFIX_PROFILE), profile)
     }
     
-    pub fn get_profile(&self) -> Result<Option<UserProfile>> {
-        self.get(&format!("{}self", PREFIX_PROFILE))
+    pub fn get_profile(&self) -> Result<Option<UserProfile>> {
+        self.get(&format!("{}self", PREFIX_PROFILE))
+    }
+    
+    pub fn store_privacy_settings(&self, settings: &PrivacySettings) -> Result<()> {
+        self.put(&format!("{}privacy", PREFIX_PROFILE), settings)
+    }
+    
+    /// Privacy settings, all signals enabled until changed
+    pub fn get_privacy_settings(&self) -> Result<PrivacySettings> {
+        Ok(self.get(&format!("{}privacy", PREFIX_PROFILE))?.unwrap_or_default())
+    }
+    
+    pub fn store_contact_request_difficulty(&self, difficulty: u8) -> Result<()> {
+        self.put(&format!("{}request_difficulty", PREFIX_PROFILE), &difficulty)
+    }
+    
+    /// Proof of work asked of contact requests from strangers
+    pub fn get_contact_request_difficulty(&self) -> Result<u8> {
+        Ok(self.get(&format!("{}request_difficulty", PREFIX_PROFILE))?.unwrap_or(puzzle::DEFAULT_DIFFICULTY))
+    }
+    
+    pub fn store_default_retention(&self, policy: &RetentionPolicy) -> Result<()> {
+        self.put(&format!("{}retention", PREFIX_PROFILE), policy)
+    }
+    
+    /// Retention for conversations without their own policy, keeping
+    /// everything until changed
+    pub fn get_default_retention(&self) -> Result<RetentionPolicy> {
+        Ok(self.get(&format!("{}retention", PREFIX_PROFILE))?.unwrap_or_default())
+    }
+    
+    /// Set a conversation's own policy, or with `None` go back to the
+    /// default
+    pub fn store_conversation_retention(&self, conversation_id: &str, policy: Option<&RetentionPolicy>) -> Result<()> {
+        let key = format!("{}{}", PREFIX_RETENTION, conversation_id);
+        match policy {
+            Some(policy) => self.put(&key, policy),
+            None => self.delete(&key),
+        }
+    }
+    
+    pub fn get_conversation_retention(&self, conversation_id: &str) -> Result<Option<RetentionPolicy>> {
+        self.get(&format!("{}{}", PREFIX_RETENTION, conversation_id))
+    }
+    
+    /// The policy in effect for a conversation
+    pub fn retention_policy(&self, conversation_id: &str) -> Result<RetentionPolicy> {
+        match self.get_conversation_retention(conversation_id)? {
+            Some(policy) => Ok(policy),
+            None => self.get_default_retention(),
+        }
+    }
+    
+    /// Our own username claim, if we made one
+    pub fn get_username_claim(&self) -> Result<Option<UsernameClaim>> {
+        self.get(&format!("{}username", PREFIX_PROFILE))
+    }
+    
+    pub fn store_username_claim(&self, claim: &UsernameClaim) -> Result<()> {
+        self.put(&format!("{}username", PREFIX_PROFILE), claim)
+    }
+    
+    // ===== Conversation Policies =====
+    
+    pub fn store_conversation_policy(&self, conversation_id: &str, policies: &ConversationPolicies) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_CONVERSATION_POLICY, conversation_id), policies)
+    }
+    
+    pub fn get_conversation_policy(&self, conversation_id: &str) -> Result<Option<ConversationPolicies>> {
+        self.get(&format!("{}{}", PREFIX_CONVERSATION_POLICY, conversation_id))
+    }
+    
+    pub fn store_authentication(&self, conversation_id: &str, authentication: Authentication) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_AUTHENTICATION, conversation_id), &authentication)
+    }
+    
+    pub fn get_authentication(&self, conversation_id: &str) -> Result<Option<Authentication>> {
+        self.get(&format!("{}{}", PREFIX_AUTHENTICATION, conversation_id))
+    }
+    
+    // ===== Webhooks =====
+    
+    pub fn store_webhook(&self, webhook: &Webhook) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_WEBHOOK, webhook.id), webhook)
+    }
+    
+    pub fn get_webhooks(&self) -> Result<Vec<Webhook>> {
+        let mut webhooks = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_WEBHOOK.as_bytes()) {
+            let (key, value) = item.context("Failed to read webhook")?;
+            webhooks.push(parse_record(&self.decrypt_record(&key, &value)?)?);
+        }
+        Ok(webhooks)
+    }
+    
+    pub fn delete_webhook(&self, id: &str) -> Result<()> {
+        self.delete(&format!("{}{}", PREFIX_WEBHOOK, id))
+    }
+    
+    pub fn store_inbound_webhook_secret(&self, secret: &str) -> Result<()> {
+        self.put(&format!("{}webhook_inbound", PREFIX_PROFILE), &secret)
+    }
+    
+    pub fn get_inbound_webhook_secret(&self) -> Result<Option<String>> {
+        self.get(&format!("{}webhook_inbound", PREFIX_PROFILE))
+    }
+    
+    // ===== Username Pins =====
+    
+    /// Identity key `username` resolved to when first looked up
+    pub fn get_username_pin(&self, username: &str) -> Result<Option<[u8; 32]>> {
+        self.get(&format!("{}{}", PREFIX_USERNAME_PIN, username))
+    }
+    
+    pub fn pin_username(&self, username: &str, identity_key: &[u8; 32]) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_USERNAME_PIN, username), identity_key)
+    }
+    
+    // ===== Push Endpoints =====
+    
+    pub fn store_push_endpoint(&self, endpoint: &PushEndpoint) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_PUSH_ENDPOINT, endpoint.device_id), endpoint)
+    }
+    
+    /// Returns whether the device had an endpoint
+    pub fn remove_push_endpoint(&self, device_id: &str) -> Result<bool> {
+        let removed = self.tree.remove(format!("{}{}", PREFIX_PUSH_ENDPOINT, device_id).as_bytes())
+            .context("Failed to delete push endpoint")?;
+        Ok(removed.is_some())
+    }
+    
+    /// Endpoints of our devices
+    pub fn get_push_endpoints(&self) -> Result<Vec<PushEndpoint>> {
+        let mut endpoints = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_PUSH_ENDPOINT.as_bytes()) {
+            let (key, value) = item.context("Failed to read push endpoint")?;
+            endpoints.push(parse_record(&self.decrypt_record(&key, &value)?)?);
+        }
+        Ok(endpoints)
+    }
+    
+    /// Replace the endpoints a contact's devices can be woken at
+    pub fn set_contact_push_endpoints(&self, contact_id: &str, endpoints: &[PushEndpoint]) -> Result<()> {
+        let key = format!("{}{}", PREFIX_CONTACT_PUSH, contact_id);
+        if endpoints.is_empty() {
+            self.delete(&key)
+        } else {
+            self.put(&key, &endpoints.to_vec())
+        }
+    }
+    
+    pub fn get_contact_push_endpoints(&self, contact_id: &str) -> Result<Vec<PushEndpoint>> {
+        Ok(self.get(&format!("{}{}", PREFIX_CONTACT_PUSH, contact_id))?.unwrap_or_default())
+    }
+    
+    // ===== Contact Devices =====
+    
+    /// Replace the registry of a contact's devices, dropping the sessions
+    /// of devices no longer in it
+    pub fn set_contact_devices(&self, contact_id: &str, devices: &[RemoteDevice]) -> Result<()> {
+        for old in self.get_contact_devices(contact_id)? {
+            if !devices.iter().any(|device| device.device_id == old.device_id) {
+                self.delete(&device_session_key(contact_id, &old.device_id))?;
+                self.delete(&receiving_session_key(contact_id, &old.device_id))?;
+                self.delete(&device_capabilities_key(contact_id, &old.device_id))?;
+            }
+        }
+        let key = format!("{}{}", PREFIX_CONTACT_DEVICES, contact_id);
+        if devices.is_empty() {
+            self.delete(&key)
+        } else {
+            self.put(&key, &devices.to_vec())
+        }
+    }
+    
+    pub fn get_contact_devices(&self, contact_id: &str) -> Result<Vec<RemoteDevice>> {
+        Ok(self.get(&format!("{}{}", PREFIX_CONTACT_DEVICES, contact_id))?.unwrap_or_default())
+    }
+    
+    pub fn store_device_session(&self, contact_id: &str, device_id: &str, session: &DeviceSession) -> Result<()> {
+        self.put(&device_session_key(contact_id, device_id), session)
+    }
+    
+    pub fn get_device_session(&self, contact_id: &str, device_id: &str) -> Result<Option<DeviceSession>> {
+        self.get(&device_session_key(contact_id, device_id))
+    }
+    
+    pub fn store_receiving_session(&self, contact_id: &str, device_id: &str, session: &ReceivingSession) -> Result<()> {
+        self.put(&receiving_session_key(contact_id, device_id), session)
+    }
+    
+    pub fn get_receiving_session(&self, contact_id: &str, device_id: &str) -> Result<Option<ReceivingSession>> {
+        self.get(&receiving_session_key(contact_id, device_id))
+    }
+    
+    pub fn store_device_capabilities(&self, contact_id: &str, device_id: &str, capabilities: Capabilities) -> Result<()> {
+        self.put(&device_capabilities_key(contact_id, device_id), &capabilities)
+    }
+    
+    /// None for devices whose announcement we kept from before
+    /// capabilities were recorded
+    pub fn get_device_capabilities(&self, contact_id: &str, device_id: &str) -> Result<Option<Capabilities>> {
+        self.get(&device_capabilities_key(contact_id, device_id))
+    }
+    
+    // ===== Invites =====
+    
+    /// Private half of one of our invites, by its public prekey
+    pub fn get_invite_prekey(&self, prekey: &[u8; 32]) -> Result<Option<InvitePrekey>> {
+        self.get(&invite_prekey_key(prekey))
+    }
+    
+    pub fn store_invite_prekey(&self, prekey: &[u8; 32], private: &InvitePrekey) -> Result<()> {
+        self.put(&invite_prekey_key(prekey), private)
+    }
+    
+    /// Drop the prekeys of invites that expired before `now`. Returns how
+    /// many were removed.
+    pub fn prune_invite_prekeys(&self, now: OffsetDateTime) -> Result<usize> {
+        let mut expired = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_INVITE_PREKEY.as_bytes()) {
+            let (key, value) = item.context("Failed to read invite prekey")?;
+            let private: InvitePrekey = bincode::deserialize(&self.decrypt_record(&key, &value)?)
+                .context("Failed to deserialize invite prekey")?;
+            if private.expires_at <= now {
+                expired.push(key);
+            }
+        }
+        for key in &expired {
+            self.tree.remove(key).context("Failed to delete invite prekey")?;
+        }
+        Ok(expired.len())
+    }
+    
+    /// Delete key material past its use: expired prekeys and request
+    /// stamps, old or excess skipped message keys, and sessions with
+    /// devices or contacts that are gone
+    pub fn clean_key_material(&self, now: OffsetDateTime) -> Result<MaintenanceReport> {
+        let mut report = MaintenanceReport {
+            invite_prekeys: self.prune_invite_prekeys(now)?,
+            stamps: self.prune_used_stamps(now)?,
+            ..Default::default()
+        };
+        
+        let mut live_sessions = HashSet::new();
+        for contact in self.contacts() {
+            let contact = contact?;
+            if self.get_contact_prekey(&contact.id)?.is_some_and(|prekey| prekey.expires_at <= now) {
+                self.delete_contact_prekey(&contact.id)?;
+                report.contact_prekeys += 1;
+            }
+            for device in self.get_contact_devices(&contact.id)? {
+                live_sessions.insert(device_session_key(&contact.id, &device.device_id));
+                live_sessions.insert(receiving_session_key(&contact.id, &device.device_id));
+            }
+        }
+        let sessions = self.tree.scan_prefix(PREFIX_DEVICE_SESSION.as_bytes()).keys()
+            .chain(self.tree.scan_prefix(PREFIX_RECEIVING_SESSION.as_bytes()).keys());
+        for key in sessions {
+            let key = key.context("Failed to read session")?;
+            if !live_sessions.contains(String::from_utf8_lossy(&key).as_ref()) {
+                self.tree.remove(&key).context("Failed to delete session")?;
+                report.sessions += 1;
+            }
+        }
+        
+        for conversation in self.conversations() {
+            let mut conversation = conversation?;
+            let Some(ratchet) = conversation.ratchet_state.as_mut() else {
+                continue;
+            };
+            let trimmed = ratchet.trim_skipped_keys(now, maintenance::SKIPPED_KEY_MAX_AGE, maintenance::SKIPPED_KEYS_KEPT);
+            // Unstamped keys get their time even when none were dropped
+            self.store_conversation(&conversation)?;
+            report.skipped_keys += trimmed;
+        }
+        Ok(report)
+    }
+    
+    /// Prekey from the invite a contact was added through
+    pub fn get_contact_prekey(&self, contact_id: &str) -> Result<Option<ContactPrekey>> {
+        self.get(&format!("{}{}", PREFIX_CONTACT_PREKEY, contact_id))
+    }
+    
+    pub fn store_contact_prekey(&self, contact_id: &str, prekey: &ContactPrekey) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_CONTACT_PREKEY, contact_id), prekey)
+    }
+    
+    pub fn delete_contact_prekey(&self, contact_id: &str) -> Result<()> {
+        self.delete(&format!("{}{}", PREFIX_CONTACT_PREKEY, contact_id))
+    }
+    
+    // ===== Outbox =====
+    
+    /// Keep protocol messages, in wire encoding, to send once the network
+    /// is back
+    pub fn push_outbox(&self, messages: &[Vec<u8>]) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for message in messages {
+            let id = self.db.generate_id().context("Failed to allocate outbox id")?;
+            self.batch_insert(&mut batch, format!("{}{:020}", PREFIX_OUTBOX, id).as_bytes(), message)?;
+        }
+        self.tree.apply_batch(batch).context("Failed to store outbox")
+    }
+    
+    /// Remove and return everything in the outbox, oldest first
+    pub fn take_outbox(&self) -> Result<Vec<Vec<u8>>> {
+        let mut messages = Vec::new();
+        let mut batch = sled::Batch::default();
+        for item in self.tree.scan_prefix(PREFIX_OUTBOX.as_bytes()) {
+            let (key, value) = item.context("Failed to read outbox")?;
+            messages.push(self.decrypt_record(&key, &value)?);
+            batch.remove(key);
+        }
+        self.tree.apply_batch(batch).context("Failed to clear outbox")?;
+        Ok(messages)
+    }
+    
+    /// Number of messages in the outbox
+    pub fn outbox_len(&self) -> usize {
+        self.tree.scan_prefix(PREFIX_OUTBOX.as_bytes()).keys().count()
+    }
+    
+    /// Drop outbox entries carrying any of `message_ids`, returning how
+    /// many were removed
+    pub fn remove_from_outbox(&self, message_ids: &[String]) -> Result<usize> {
+        let mut batch = sled::Batch::default();
+        let mut removed = 0;
+        for item in self.tree.scan_prefix(PREFIX_OUTBOX.as_bytes()) {
+            let (key, value) = item.context("Failed to read outbox")?;
+            let message = ProtocolMessage::decode(&self.decrypt_record(&key, &value)?)?;
+            if message.message_id().is_some_and(|id| message_ids.iter().any(|m| m == id)) {
+                batch.remove(key);
+                removed += 1;
+            }
+        }
+        self.tree.apply_batch(batch).context("Failed to update outbox")?;
+        Ok(removed)
+    }
+    
+    // ===== Ingest Journal =====
+    
+    /// Record an incoming envelope before anything is done with it, flushed
+    /// per the durability settings so it survives a crash. Returns the
+    /// entry's id for `clear_journal`.
+    pub fn journal_envelope(&self, envelope: &MessageEnvelope) -> Result<u64> {
+        let id = self.db.generate_id().context("Failed to allocate journal id")?;
+        let key = format!("{}{:020}", PREFIX_INGEST_JOURNAL, id);
+        let data = self.encrypt(key.as_bytes(), &wire::encode(envelope)?)?;
+        self.tree.insert(key.as_bytes(), data)
+            .context("Failed to journal envelope")?;
+        self.flush_message_writes()?;
+        Ok(id)
+    }
+    
+    /// Envelopes journaled but never cleared, oldest first
+    pub fn journaled_envelopes(&self) -> Result<Vec<(u64, MessageEnvelope)>> {
+        let mut entries = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_INGEST_JOURNAL.as_bytes()) {
+            let (key, value) = item.context("Failed to read ingest journal")?;
+            let id = std::str::from_utf8(&key[PREFIX_INGEST_JOURNAL.len()..]).ok()
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(|| anyhow::anyhow!("Malformed ingest journal key"))?;
+            let envelope = wire::decode(&self.decrypt_record(&key, &value)?)
+                .context("Failed to decode journaled envelope")?;
+            entries.push((id, envelope));
+        }
+        Ok(entries)
+    }
+    
+    pub fn clear_journal(&self, id: u64) -> Result<()> {
+        self.delete(&format!("{}{:020}", PREFIX_INGEST_JOURNAL, id))
+    }
+    
+    // ===== Envelope Aliases =====
+    
+    /// Record that `envelope_id`, used to resend a message, stands for
+    /// `message_id`
+    pub fn store_envelope_alias(&self, envelope_id: &str, message_id: &str) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_ENVELOPE_ALIAS, envelope_id), &message_id.to_string())
+    }
+    
+    /// The message an envelope id refers to; ids without an alias are
+    /// message ids already
+    pub fn resolve_envelope_id(&self, envelope_id: &str) -> Result<String> {
+        Ok(self.get(&format!("{}{}", PREFIX_ENVELOPE_ALIAS, envelope_id))?
+            .unwrap_or_else(|| envelope_id.to_string()))
+    }
+    
+    /// Every envelope id `message_id` went out under, itself included
+    pub fn envelope_ids(&self, message_id: &str) -> Result<Vec<String>> {
+        let mut ids = vec![message_id.to_string()];
+        for item in self.tree.scan_prefix(PREFIX_ENVELOPE_ALIAS.as_bytes()) {
+            let (key, value) = item.context("Failed to read envelope alias")?;
+            let target: String = bincode::deserialize(&self.decrypt_record(&key, &value)?)
+                .context("Failed to deserialize envelope alias")?;
+            if target == message_id {
+                ids.push(String::from_utf8_lossy(&key[PREFIX_ENVELOPE_ALIAS.len()..]).into_owned());
+            }
+        }
+        Ok(ids)
+    }
+    
+    // ===== Known Peers =====
+    
+    pub fn store_known_peer(&self, peer: &PeerInfo) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_KNOWN_PEER, peer.peer_id), peer)
+    }
+    
+    /// Peers we have reached before, most recently seen first
+    pub fn get_known_peers(&self, limit: usize) -> Result<Vec<PeerInfo>> {
+        let mut peers = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_KNOWN_PEER.as_bytes()) {
+            let (key, value) = item.context("Failed to read known peer")?;
+            let peer: PeerInfo = bincode::deserialize(&self.decrypt_record(&key, &value)?)
+                .context("Failed to deserialize known peer")?;
+            peers.push(peer);
+        }
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+        peers.truncate(limit);
+        Ok(peers)
+    }
+    
+    // ===== Bootstrap Nodes =====
+    
+    pub fn store_bootstrap_node(&self, node: &BootstrapNode) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_BOOTSTRAP, node.address), node)
+    }
+    
+    pub fn get_bootstrap_node(&self, address: &str) -> Result<Option<BootstrapNode>> {
+        self.get(&format!("{}{}", PREFIX_BOOTSTRAP, address))
+    }
+    
+    pub fn get_bootstrap_nodes(&self) -> Result<Vec<BootstrapNode>> {
+        let mut nodes = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_BOOTSTRAP.as_bytes()) {
+            let (key, value) = item.context("Failed to read bootstrap node")?;
+            nodes.push(bincode::deserialize(&self.decrypt_record(&key, &value)?)
+                .context("Failed to deserialize bootstrap node")?);
+        }
+        Ok(nodes)
+    }
+    
+    pub fn delete_bootstrap_node(&self, address: &str) -> Result<()> {
+        self.delete(&format!("{}{}", PREFIX_BOOTSTRAP, address))
+    }
+    
+    // ===== Quarantine =====
+    
+    pub fn quarantine_envelope(&self, entry: &QuarantinedEnvelope) -> Result<()> {
+        self.put(&quarantine_key(&entry.envelope.sender_id, &entry.envelope.id), entry)
+    }
+    
+    /// Quarantined envelopes from one sender, or from everyone, oldest first
+    pub fn get_quarantined(&self, sender_id: Option<&str>) -> Result<Vec<QuarantinedEnvelope>> {
+        let prefix = match sender_id {
+            Some(sender_id) => format!("{}{}/", PREFIX_QUARANTINE, sender_id),
+            None => PREFIX_QUARANTINE.to_string(),
+        };
+        let mut entries = Vec::new();
+        for item in self.tree.scan_prefix(prefix.as_bytes()) {
+            let (key, value) = item.context("Failed to read quarantined envelope")?;
+            let entry: QuarantinedEnvelope = bincode::deserialize(&self.decrypt_record(&key, &value)?)
+                .context("Failed to deserialize quarantined envelope")?;
+            // Sender ids may contain '/', so the prefix can match others
+            if sender_id.is_none_or(|id| entry.envelope.sender_id == id) {
+                entries.push(entry);
+            }
+        }
+        entries.sort_by_key(|entry| entry.received_at);
+        Ok(entries)
+    }
+    
+    pub fn remove_quarantined(&self, sender_id: &str, envelope_id: &str) -> Result<()> {
+        self.delete(&quarantine_key(sender_id, envelope_id))
+    }
+    
+    // ===== Message Requests =====
+    
+    pub fn store_message_request(&self, request: &MessageRequest) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_MESSAGE_REQUEST, request.sender_id), request)
+    }
+    
+    pub fn get_message_request(&self, sender_id: &str) -> Result<Option<MessageRequest>> {
+        self.get(&format!("{}{}", PREFIX_MESSAGE_REQUEST, sender_id))
+    }
+    
+    pub fn get_message_requests(&self) -> Result<Vec<MessageRequest>> {
+        let mut requests = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_MESSAGE_REQUEST.as_bytes()) {
+            let (key, value) = item.context("Failed to read message request")?;
+            requests.push(parse_record(&self.decrypt_record(&key, &value)?)?);
+        }
+        Ok(requests)
+    }
+    
+    pub fn count_message_requests(&self) -> usize {
+        self.tree.scan_prefix(PREFIX_MESSAGE_REQUEST.as_bytes()).count()
+    }
+    
+    pub fn delete_message_request(&self, sender_id: &str) -> Result<()> {
+        self.delete(&format!("{}{}", PREFIX_MESSAGE_REQUEST, sender_id))
+    }
+    
+    pub fn block_sender(&self, sender_id: &str, at: OffsetDateTime) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_BLOCKED_SENDER, sender_id), &at)
+    }
+    
+    pub fn unblock_sender(&self, sender_id: &str) -> Result<()> {
+        self.delete(&format!("{}{}", PREFIX_BLOCKED_SENDER, sender_id))
+    }
+    
+    pub fn is_sender_blocked(&self, sender_id: &str) -> Result<bool> {
+        self.tree.contains_key(format!("{}{}", PREFIX_BLOCKED_SENDER, sender_id).as_bytes())
+            .context("Failed to read blocked senders")
+    }
+    
+    /// Blocked senders and when they were blocked
+    pub fn get_blocked_senders(&self) -> Result<Vec<(String, OffsetDateTime)>> {
+        let mut blocked = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_BLOCKED_SENDER.as_bytes()) {
+            let (key, value) = item.context("Failed to read blocked senders")?;
+            let sender_id = String::from_utf8_lossy(&key[PREFIX_BLOCKED_SENDER.len()..]).into_owned();
+            blocked.push((sender_id, parse_record(&self.decrypt_record(&key, &value)?)?));
+        }
+        Ok(blocked)
+    }
+    
+    /// Note a contact request stamp as used until `expires_at`, a Unix
+    /// time. False if it already was.
+    pub fn use_stamp(&self, hash: &[u8; 32], expires_at: i64) -> Result<bool> {
+        let key = format!("{}{}", PREFIX_USED_STAMP, blake3::Hash::from_bytes(*hash).to_hex());
+        let sealed = self.encrypt(key.as_bytes(), &bincode::serialize(&expires_at).context("Failed to serialize stamp")?)?;
+        let swapped = self.tree.compare_and_swap(key.as_bytes(), None as Option<&[u8]>, Some(sealed))
+            .context("Failed to store stamp")?;
+        Ok(swapped.is_ok())
+    }
+    
+    /// Forget used stamps that expired before `now`, as they would be
+    /// refused anyway. Returns how many were removed.
+    pub fn prune_used_stamps(&self, now: OffsetDateTime) -> Result<usize> {
+        let mut expired = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_USED_STAMP.as_bytes()) {
+            let (key, value) = item.context("Failed to read stamp")?;
+            let expires_at: i64 = parse_record(&self.decrypt_record(&key, &value)?)?;
+            if expires_at < now.unix_timestamp() {
+                expired.push(key);
+            }
+        }
+        for key in &expired {
+            self.tree.remove(key).context("Failed to delete stamp")?;
+        }
+        Ok(expired.len())
+    }
+    
+    // ===== Audit Log =====
+    
+    /// Append to a contact's security audit log. Entry and head are
+    /// written in one batch so the chain is never left half-extended.
+    pub fn append_security_event(&self, contact_id: &str, kind: SecurityEventKind) -> Result<SecurityEvent> {
+        let head_key = format!("{}{}", PREFIX_AUDIT_HEAD, contact_id);
+        let head: Option<AuditHead> = self.get(&head_key)?;
+        let event = SecurityEvent::next(contact_id, head.as_ref(), kind)?;
+        let new_head = AuditHead { len: event.seq + 1, hash: event.hash };
+        
+        let mut batch = sled::Batch::default();
+        self.batch_insert(
+            &mut batch,
+            format!("{}{}/{:016x}", PREFIX_AUDIT, contact_id, event.seq).as_bytes(),
+            &bincode::serialize(&event).context("Failed to serialize audit entry")?,
+        )?;
+        self.batch_insert(
+            &mut batch,
+            head_key.as_bytes(),
+            &bincode::serialize(&new_head).context("Failed to serialize audit head")?,
+        )?;
+        self.tree.apply_batch(batch)
+            .context("Failed to append audit entry")?;
+        Ok(event)
+    }
+    
+    /// A contact's audit log, oldest first. Fails if the chain is broken.
+    pub fn get_security_events(&self, contact_id: &str) -> Result<Vec<SecurityEvent>> {
+        let prefix = format!("{}{}/", PREFIX_AUDIT, contact_id);
+        let mut events = Vec::new();
+        for item in self.tree.scan_prefix(prefix.as_bytes()) {
+            let (key, value) = item.context("Failed to read audit entry")?;
+            // Skip logs of contacts whose id extends this one past a '/'
+            if key.len() != prefix.len() + 16 || key[prefix.len()..].contains(&b'/') {
+                continue;
+            }
+            let event: SecurityEvent = bincode::deserialize(&self.decrypt_record(&key, &value)?)
+                .context("Failed to deserialize audit entry")?;
+            events.push(event);
+        }
+        
+        let head: Option<AuditHead> = self.get(&format!("{}{}", PREFIX_AUDIT_HEAD, contact_id))?;
+        audit::verify_chain(contact_id, &events, head.as_ref())
+            .with_context(|| format!("Audit log of {} failed verification", contact_id))?;
+        Ok(events)
+    }
+    
+    // ===== Key Transparency =====
+    
+    /// Append to our key transparency log, signed by `identity`. Entry and
+    /// head are written in one batch, as for the audit log.
+    pub fn append_key_log(&self, identity: &IdentityKeyPair, kind: KeyLogEventKind) -> Result<KeyLogEntry> {
+        let head: Option<KeyLogHead> = self.get(PREFIX_KEY_LOG_HEAD)?;
+        let entry = KeyLogEntry::next(identity, head.as_ref(), kind)?;
+        let new_head = KeyLogHead { len: entry.seq + 1, hash: entry.hash };
+        
+        let mut batch = sled::Batch::default();
+        self.batch_insert(
+            &mut batch,
+            format!("{}{:016x}", PREFIX_KEY_LOG, entry.seq).as_bytes(),
+            &bincode::serialize(&entry).context("Failed to serialize key log entry")?,
+        )?;
+        self.batch_insert(
+            &mut batch,
+            PREFIX_KEY_LOG_HEAD.as_bytes(),
+            &bincode::serialize(&new_head).context("Failed to serialize key log head")?,
+        )?;
+        self.tree.apply_batch(batch)
+            .context("Failed to append key log entry")?;
+        Ok(entry)
+    }
+    
+    /// Our key transparency log, oldest first. Fails if it doesn't verify
+    /// under `identity_key`.
+    pub fn get_key_log(&self, identity_key: &[u8; 32]) -> Result<Vec<KeyLogEntry>> {
+        let mut entries = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_KEY_LOG.as_bytes()) {
+            let (key, value) = item.context("Failed to read key log entry")?;
+            let entry: KeyLogEntry = bincode::deserialize(&self.decrypt_record(&key, &value)?)
+                .context("Failed to deserialize key log entry")?;
+            entries.push(entry);
+        }
+        
+        let head: Option<KeyLogHead> = self.get(PREFIX_KEY_LOG_HEAD)?;
+        transparency::verify_chain(identity_key, &entries, head.as_ref())
+            .context("Key log failed verification")?;
+        Ok(entries)
+    }
+    
+    // ===== Settings Operations =====
+    
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        self.tree.insert(
+            format!("{}{}", PREFIX_SETTINGS, key).as_bytes(),
+            value.as_bytes()
+        ).context("Failed to store setting")?;
+        Ok(())
+    }
+    
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        match self.tree.get(format!("{}{}", PREFIX_SETTINGS, key).as_bytes()) {
+            Ok(Some(data)) => {
+                let value = String::from_utf8(data.to_vec())
+                    .context("Invalid UTF-8 in setting")?;
+                Ok(Some(value))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    
+    // ===== Device Operations =====
+    
+    pub fn store_device(&self, device: &DeviceInfo) -> Result<()> {
+        self.put(&format!("{}{}", PREFIX_DEVICE, device.device_id), device)
+    }
+    
+    pub fn get_device(&self, device_id: &str) -> Result<Option<DeviceInfo>> {
+        self.get(&format!("{}{}", PREFIX_DEVICE, device_id))
+    }
+    
+    pub fn get_all_devices(&self) -> Result<Vec<DeviceInfo>> {
+        let mut devices = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_DEVICE.as_bytes()) {
+            let (key, value) = item.context("Failed to read device")?;
+            let decrypted = self.decrypt_record(&key, &value)?;
+            let device: DeviceInfo = bincode::deserialize(&decrypted)
+                .context("Failed to deserialize device")?;
+            devices.push(device);
+        }
+        Ok(devices)
+    }
+    
+    // ===== Backup Operations =====
+    
+    /// Iterate every record in plaintext, for backup export. Identity keys
+    /// are rewrapped under `transport_key` so they never leave unwrapped.
+    pub fn export_records<'a>(
+        &'a self,
+        transport_key: &'a [u8; 32],
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a {
+        self.tree.iter().filter_map(move |item| {
+            let result = (|| {
+                let (key, value) = item.context("Failed to read record")?;
+                if key.as_ref() == META_MESSAGE_SCHEMA.as_bytes() {
+                    return Ok(Some((key.to_vec(), value.to_vec())));
+                }
+                if key.starts_with(PREFIX_MASTER_KEY.as_bytes()) || key.starts_with(PREFIX_META.as_bytes())
+                    || key.starts_with(PREFIX_CORRUPT.as_bytes()) {
+                    return Ok(None);
+                }
+                if key.starts_with(PREFIX_SETTINGS.as_bytes()) {
+                    return Ok(Some((key.to_vec(), value.to_vec())));
+                }
+                
+                let plaintext = self.decrypt_record(&key, &value)
+                    .with_context(|| format!("Failed to decrypt record {}", String::from_utf8_lossy(&key)))?;
+                let plaintext = rewrap_identity_keys(&key, &plaintext, &self.keys.identity_wrap, transport_key)?;
+                Ok(Some((key.to_vec(), plaintext)))
+            })();
+            result.transpose()
+        })
+    }
+    
+    /// Store a record produced by `export_records` on another database
+    pub fn import_record(&self, key: &[u8], value: &[u8], transport_key: &[u8; 32]) -> Result<()> {
+        self.import_records(&[(key.to_vec(), value.to_vec())], transport_key)
+    }
+    
+    /// Store records produced by `export_records` in one write. None are
+    /// stored if any is refused.
+    pub fn import_records(&self, records: &[(Vec<u8>, Vec<u8>)], transport_key: &[u8; 32]) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in records {
+            if key == META_MESSAGE_SCHEMA.as_bytes() {
+                if value.len() != 4 {
+                    return Err(anyhow::anyhow!("Invalid message schema marker"));
+                }
+                batch.insert(key.as_slice(), value.as_slice());
+                continue;
+            }
+            if key.starts_with(PREFIX_MASTER_KEY.as_bytes()) || key.starts_with(PREFIX_META.as_bytes()) {
+                return Err(anyhow::anyhow!("Refusing to import key material record"));
+            }
+            
+            let stored = if key.starts_with(PREFIX_SETTINGS.as_bytes()) {
+                value.clone()
+            } else {
+                let plaintext = rewrap_identity_keys(key, value, transport_key, &self.keys.identity_wrap)?;
+                self.encrypt(key, &plaintext)?
+            };
+            batch.insert(key.as_slice(), stored);
+        }
+        
+        self.tree.apply_batch(batch)
+            .context("Failed to import records")
+    }
+    
+    /// Add the records `selection` covers from a backup, leaving those
+    /// this database has already as they are. `records` gives the backup's
+    /// records under a key prefix, as exported. Returns how many were
+    /// added.
+    pub fn import_selected(
+        &self,
+        selection: &RestoreSelection,
+        transport_key: &[u8; 32],
+        mut records: impl FnMut(&[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>,
+    ) -> Result<usize> {
+        fn exact(
+            records: &mut impl FnMut(&[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>,
+            key: String,
+        ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+            Ok(records(key.as_bytes())?.into_iter().find(|(found, _)| *found == key.as_bytes()))
+        }
+        
+        let mut selected = Vec::new();
+        match selection {
+            // Sessions, prekeys and audit logs are left out: restoring
+            // them would rewind state contacts have moved past
+            RestoreSelection::Contacts => {
+                for prefix in [
+                    PREFIX_CONTACT, PREFIX_CONTACT_SETTINGS, PREFIX_CONTACT_NOTE, PREFIX_SESSION_REQUIREMENTS,
+                    PREFIX_CONTACT_DEVICES, PREFIX_DEVICE_CAPABILITIES, PREFIX_CONTACT_PUSH,
+                ] {
+                    selected.extend(records(prefix.as_bytes())?);
+                }
+            }
+            RestoreSelection::Conversation { conversation_id } => {
+                // Older backups keep the ids in message paths as they are
+                let schema = exact(&mut records, META_MESSAGE_SCHEMA.to_string())?
+                    .and_then(|(_, value)| value.try_into().ok())
+                    .map_or(1, u32::from_be_bytes);
+                if schema < 3 && conversation_path(conversation_id) != format!("{}/", conversation_id) {
+                    return Err(anyhow::anyhow!("This backup predates the conversation's message paths; restore it whole"));
+                }
+                let conversation = exact(&mut records, format!("{}{}", PREFIX_CONVERSATION, conversation_id))?
+                    .ok_or_else(|| anyhow::anyhow!("Conversation is not in the backup"))?;
+                let contact_id = parse_record::<Conversation>(&conversation.1)?.contact_id;
+                selected.push(conversation);
+                // Without its contact the conversation would be collected
+                selected.extend(exact(&mut records, format!("{}{}", PREFIX_CONTACT, contact_id))?);
+                for prefix in [PREFIX_RETENTION, PREFIX_CONVERSATION_POLICY, PREFIX_AUTHENTICATION] {
+                    selected.extend(exact(&mut records, format!("{}{}", prefix, conversation_id))?);
+                }
+                for prefix in [PREFIX_MESSAGE, PREFIX_THUMBNAIL, PREFIX_ANNOTATION, PREFIX_STARRED] {
+                    selected.extend(records(format!("{}{}", prefix, conversation_path(conversation_id)).as_bytes())?);
+                }
+                
+                // `bref:<blob>/<message path>` for each attachment
+                let owner = conversation_path(conversation_id);
+                for (key, value) in records(PREFIX_BLOB_REF.as_bytes())? {
+                    let path = String::from_utf8_lossy(&key[PREFIX_BLOB_REF.len()..]).into_owned();
+                    let Some((blob, message)) = path.split_once('/') else {
+                        continue;
+                    };
+                    if !message.starts_with(&owner) {
+                        continue;
+                    }
+                    selected.extend(records(format!("{}{}/", PREFIX_BLOB, blob).as_bytes())?);
+                    selected.extend(exact(&mut records, format!("{}{}", PREFIX_VIEW_ONCE, blob))?);
+                    selected.push((key, value));
+                }
+            }
+        }
+        
+        let mut added = Vec::with_capacity(selected.len());
+        for (key, value) in selected {
+            if !self.tree.contains_key(&key)? {
+                added.push((key, value));
+            }
+        }
+        self.import_records(&added, transport_key)?;
+        self.rebuild_indexes()?;
+        Ok(added.len())
+    }
+    
+    // ===== Usage =====
+    
+    /// Size of this profile's records by what they hold
+    pub fn usage_report(&self) -> Result<UsageReport> {
+        let mut report = UsageReport {
+            on_disk: self.db.size_on_disk().context("Failed to read database size")?,
+            ..Default::default()
+        };
+        let mut conversations: HashMap<String, CategoryUsage> = HashMap::new();
+        for item in self.tree.iter() {
+            let (key, value) = item.context("Failed to read record")?;
+            let size = key.len() + value.len();
+            if let Some(conversation_id) = message_conversation_id(&key) {
+                conversations.entry(conversation_id)
+                    .or_default()
+                    .add(size);
+            } else if key.starts_with(PREFIX_BLOB.as_bytes()) || key.starts_with(PREFIX_THUMBNAIL.as_bytes()) {
+                report.attachments.add(size);
+            } else if [PREFIX_BLOB_REF, PREFIX_STARRED, PREFIX_CONVERSATION_BY_CONTACT, PREFIX_MESSAGE_STATUS, PREFIX_MESSAGE_ORDER, PREFIX_ACTIVITY, PREFIX_CONVERSATION_STATS, PREFIX_SEARCH, PREFIX_DAY_COUNT].iter()
+                .any(|prefix| key.starts_with(prefix.as_bytes())) {
+                report.index.add(size);
+            } else {
+                report.other.add(size);
+            }
+        }
+        report.conversations = conversations.into_iter()
+            .map(|(conversation_id, messages)| ConversationUsage { conversation_id, messages })
+            .collect();
+        report.conversations.sort_by_key(|c| std::cmp::Reverse(c.messages.bytes));
+        Ok(report)
+    }
+    
+    /// Number of records, of every kind
+    pub fn record_count(&self) -> usize {
+        self.tree.len()
+    }
+    
+    /// Delete attachment data nothing refers to, then rewrite every record
+    /// so sled can reclaim the space they were spread over. Attachments
+    /// must not be stored meanwhile: one not yet referenced by its message
+    /// counts as orphaned.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn compact(&self, mut progress: impl FnMut(CompactionProgress)) -> Result<CompactionReport> {
+        let mut report = CompactionReport {
+            size_before: self.db.size_on_disk().context("Failed to read database size")?,
+            ..Default::default()
+        };
+        (report.orphaned_blobs, report.orphaned_bytes) = self.delete_orphaned_blobs()?;
+        
+        let records_total = self.tree.len();
+        let mut records_done = 0;
+        for item in self.tree.iter() {
+            let (key, value) = item.context("Failed to read record")?;
+            // A record written since it was read is rewritten already, and
+            // mustn't be put back as it was
+            let _ = self.tree.compare_and_swap(&key, Some(&value), Some(value.clone()))
+                .context("Failed to rewrite record")?;
+            records_done += 1;
+            if records_done % COMPACTION_BATCH == 0 {
+                progress(CompactionProgress { records_done, records_total });
+            }
+        }
+        progress(CompactionProgress { records_done, records_total: records_done });
+        
+        self.db.flush().context("Failed to flush database")?;
+        report.size_after = self.db.size_on_disk().context("Failed to read database size")?;
+        Ok(report)
+    }
+    
+    /// Delete blobs without references and chunks staged by writes that
+    /// never finished, returning how many blobs and bytes went
+    fn delete_orphaned_blobs(&self) -> Result<(usize, u64)> {
+        let orphans = self.orphaned_blobs(&HashSet::new())?;
+        let mut batch = sled::Batch::default();
+        for key in orphans.iter().flat_map(|(keys, _)| keys) {
+            batch.remove(key.clone());
+        }
+        self.tree.apply_batch(batch).context("Failed to delete attachments")?;
+        Ok((orphans.len(), orphans.iter().map(|(_, bytes)| bytes).sum()))
+    }
+    
+    /// Chunk keys and stored size of each blob that no reference outside
+    /// `dropped_refs` keeps, or that was staged and never finished
+    fn orphaned_blobs(&self, dropped_refs: &HashSet<sled::IVec>) -> Result<Vec<(Vec<sled::IVec>, u64)>> {
+        // Chunk keys are `blob:<id>/<index>`, so a blob's chunks are adjacent
+        let mut blobs: Vec<(Vec<u8>, Vec<sled::IVec>, u64)> = Vec::new();
+        for item in self.tree.scan_prefix(PREFIX_BLOB.as_bytes()) {
+            let (key, value) = item.context("Failed to read attachment")?;
+            let id = key[PREFIX_BLOB.len()..].split(|b| *b == b'/').next().unwrap_or_default().to_vec();
+            match blobs.last_mut() {
+                Some((last, keys, bytes)) if *last == id => {
+                    keys.push(key);
+                    *bytes += value.len() as u64;
+                }
+                _ => blobs.push((id, vec![key], value.len() as u64)),
+            }
+        }
+        
+        let mut orphans = Vec::new();
+        for (id, keys, bytes) in blobs {
+            let mut refs = PREFIX_BLOB_REF.as_bytes().to_vec();
+            refs.extend_from_slice(&id);
+            refs.push(b'/');
+            let mut referenced = false;
+            if !id.starts_with(b"staging-") {
+                for key in self.tree.scan_prefix(refs).keys() {
+                    if !dropped_refs.contains(&key?) {
+                        referenced = true;
+                        break;
+                    }
+                }
+            }
+            if !referenced {
+                orphans.push((keys, bytes));
+            }
+        }
+        Ok(orphans)
+    }
+    
+    // ===== Garbage Collection =====
+    
+    /// Find records whose parent is gone, with everything hanging off them,
+    /// and delete them unless `dry_run`. As with `compact`, attachments
+    /// must not be stored meanwhile.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn collect_garbage(&self, dry_run: bool) -> Result<GcReport> {
+        let mut report = GcReport { dry_run, ..Default::default() };
+        let mut doomed: Vec<sled::IVec> = Vec::new();
+        // Records written again, after those doomed
+        let mut rewritten: Vec<(String, Vec<u8>)> = Vec::new();
+        // Index lists are rewritten from what is read here
+        let _writes = self.message_writes();
+        let rest = |key: &[u8], prefix: &str| String::from_utf8_lossy(&key[prefix.len()..]).into_owned();
+        
+        // The note-to-self conversation has no contact record
+        let mut contacts = HashSet::from([SELF_CONTACT_ID.to_string()]);
+        for key in self.tree.scan_prefix(PREFIX_CONTACT.as_bytes()).keys() {
+            contacts.insert(rest(&key.context("Failed to read contact")?, PREFIX_CONTACT));
+        }
+        
+        let mut conversations = HashSet::new();
+        for item in self.tree.scan_prefix(PREFIX_CONVERSATION.as_bytes()) {
+            let (key, value) = item.context("Failed to read conversation")?;
+            let conversation: Conversation = bincode::deserialize(&self.decrypt_record(&key, &value)?)
+                .context("Failed to deserialize conversation")?;
+            if contacts.contains(&conversation.contact_id) {
+                conversations.insert(conversation.id);
+            } else {
+                report.conversations += 1;
+                doomed.push(key);
+            }
+        }
+        
+        // Messages by `<conversation>/<message>` path, and by id alone
+        let mut message_paths = HashSet::new();
+        let mut message_ids = HashSet::new();
+        for conversation_id in &conversations {
+            let prefix = format!("{}{}", PREFIX_MESSAGE, conversation_path(conversation_id));
+            for key in self.tree.scan_prefix(prefix.as_bytes()).keys() {
+                let key = key.context("Failed to read message")?;
+                message_ids.insert(rest(&key, &prefix));
+                message_paths.insert(rest(&key, PREFIX_MESSAGE));
+            }
+        }
+        for key in self.tree.scan_prefix(PREFIX_MESSAGE.as_bytes()).keys() {
+            let key = key.context("Failed to read message")?;
+            if !message_paths.contains(&rest(&key, PREFIX_MESSAGE)) {
+                report.messages += 1;
+                doomed.push(key);
+            }
+        }
+        
+        for key in self.tree.scan_prefix(PREFIX_STARRED.as_bytes()).keys() {
+            let key = key.context("Failed to read starred index")?;
+            if !message_paths.contains(&rest(&key, PREFIX_STARRED)) {
+                report.index_entries += 1;
+                doomed.push(key);
+            }
+        }
+        // `ds:<status>/<message path>`; status names have no '/'
+        for key in self.tree.scan_prefix(PREFIX_MESSAGE_STATUS.as_bytes()).keys() {
+            let key = key.context("Failed to read status index")?;
+            let path = rest(&key, PREFIX_MESSAGE_STATUS);
+            if !path.split_once('/').is_some_and(|(_, path)| message_paths.contains(path)) {
+                report.index_entries += 1;
+                doomed.push(key);
+            }
+        }
+        // Index lists, `<prefix><token>` and its blocks `<prefix><token>/<block>`.
+        // A list losing postings is written again whole; blocks no list
+        // names are dropped.
+        for prefix in [PREFIX_MESSAGE_ORDER, PREFIX_ACTIVITY, PREFIX_SEARCH] {
+            let mut named = HashSet::new();
+            for item in self.tree.scan_prefix(prefix.as_bytes()) {
+                let (key, value) = item.context("Failed to read index")?;
+                if key.contains(&b'/') {
+                    continue;
+                }
+                let list = String::from_utf8_lossy(&key).into_owned();
+                let directory: Directory = parse_record(&self.decrypt_record(&key, &value)?)?;
+                let mut postings = Vec::new();
+                for block in &directory.blocks {
+                    named.insert(index_block_key(&list, block.id));
+                    postings.extend(self.index_block(&list, block.id)?);
+                }
+                let before = postings.len();
+                postings.retain(|posting| message_paths.contains(&posting.path));
+                if postings.len() == before {
+                    continue;
+                }
+                report.index_entries += before - postings.len();
+                for block in &directory.blocks {
+                    doomed.push(index_block_key(&list, block.id).as_bytes().into());
+                }
+                doomed.push(key);
+                if postings.is_empty() {
+                    continue;
+                }
+                let (directory, blocks) = Directory::build(postings);
+                for (id, block) in blocks {
+                    rewritten.push((index_block_key(&list, id), bincode::serialize(&block)?));
+                }
+                rewritten.push((list, bincode::serialize(&directory)?));
+            }
+            for key in self.tree.scan_prefix(prefix.as_bytes()).keys() {
+                let key = key.context("Failed to read index")?;
+                if key.contains(&b'/') && !named.contains(&*String::from_utf8_lossy(&key)) {
+                    report.index_entries += 1;
+                    doomed.push(key);
+                }
+            }
+        }
+        let day_counts: HashSet<String> = conversations.iter()
+            .map(|conversation_id| self.day_count_key(conversation_id))
+            .collect();
+        for key in self.tree.scan_prefix(PREFIX_DAY_COUNT.as_bytes()).keys() {
+            let key = key.context("Failed to read message counts")?;
+            if !day_counts.contains(&*String::from_utf8_lossy(&key)) {
+                report.other += 1;
+                doomed.push(key);
+            }
+        }
+        for key in self.tree.scan_prefix(PREFIX_THUMBNAIL.as_bytes()).keys() {
+            let key = key.context("Failed to read thumbnail")?;
+            if !message_paths.contains(&rest(&key, PREFIX_THUMBNAIL)) {
+                report.other += 1;
+                doomed.push(key);
+            }
+        }
+        for key in self.tree.scan_prefix(PREFIX_ANNOTATION.as_bytes()).keys() {
+            let key = key.context("Failed to read annotation")?;
+            if !message_paths.contains(&rest(&key, PREFIX_ANNOTATION)) {
+                report.other += 1;
+                doomed.push(key);
+            }
+        }
+        for key in self.tree.scan_prefix(PREFIX_CONVERSATION_BY_CONTACT.as_bytes()).keys() {
+            let key = key.context("Failed to read conversation index")?;
+            if !contacts.contains(&rest(&key, PREFIX_CONVERSATION_BY_CONTACT)) {
+                report.index_entries += 1;
+                doomed.push(key);
+            }
+        }
+        
+        // `bref:<blob>/<owner>`, the owner a message path or `sticker/<pack>`
+        let mut dropped_refs = HashSet::new();
+        for key in self.tree.scan_prefix(PREFIX_BLOB_REF.as_bytes()).keys() {
+            let key = key.context("Failed to read attachment reference")?;
+            let path = rest(&key, PREFIX_BLOB_REF);
+            let live = match path.split_once('/') {
+                Some((_, owner)) => match owner.strip_prefix("sticker/") {
+                    Some(pack) => self.tree.contains_key(format!("{}{}", PREFIX_STICKER_PACK, pack))?,
+                    None => message_paths.contains(owner),
+                },
+                None => false,
+            };
+            if !live {
+                report.index_entries += 1;
+                dropped_refs.insert(key.clone());
+                doomed.push(key);
+            }
+        }
+        
+        for item in self.tree.scan_prefix(PREFIX_ENVELOPE_ALIAS.as_bytes()) {
+            let (key, value) = item.context("Failed to read envelope alias")?;
+            let message_id: String = bincode::deserialize(&self.decrypt_record(&key, &value)?)
+                .context("Failed to deserialize envelope alias")?;
+            if !message_ids.contains(&message_id) {
+                report.index_entries += 1;
+                doomed.push(key);
+            }
+        }
+        
+        // Records keyed by a contact or conversation id; audit entries
+        // append `/<seq>`
+        let owned = [
+            (PREFIX_CONTACT_SETTINGS, &contacts),
+            (PREFIX_CONTACT_NOTE, &contacts),
+            (PREFIX_SESSION_REQUIREMENTS, &contacts),
+            (PREFIX_CONTACT_PREKEY, &contacts),
+            (PREFIX_CONTACT_PUSH, &contacts),
+            (PREFIX_CONTACT_DEVICES, &contacts),
+            (PREFIX_DEVICE_SESSION, &contacts),
+            (PREFIX_RECEIVING_SESSION, &contacts),
+            (PREFIX_DEVICE_CAPABILITIES, &contacts),
+            (PREFIX_AUDIT, &contacts),
+            (PREFIX_AUDIT_HEAD, &contacts),
+            (PREFIX_RETENTION, &conversations),
+            (PREFIX_CONVERSATION_POLICY, &conversations),
+            (PREFIX_AUTHENTICATION, &conversations),
+            (PREFIX_CONVERSATION_STATS, &conversations),
+        ];
+        for (prefix, parents) in owned {
+            for key in self.tree.scan_prefix(prefix.as_bytes()).keys() {
+                let key = key.context("Failed to read record")?;
+                let path = rest(&key, prefix);
+                let parent = match prefix {
+                    PREFIX_AUDIT | PREFIX_DEVICE_SESSION | PREFIX_RECEIVING_SESSION | PREFIX_DEVICE_CAPABILITIES => path.rsplit_once('/').map_or(path.as_str(), |(parent, _)| parent),
+                    _ => path.as_str(),
+                };
+                if !parents.contains(parent) {
+                    report.other += 1;
+                    doomed.push(key);
+                }
+            }
+        }
+        
+        for (keys, _) in self.orphaned_blobs(&dropped_refs)? {
+            report.blobs += 1;
+            doomed.extend(keys);
+        }
+        
+        if !dry_run {
+            let mut batch = sled::Batch::default();
+            for key in doomed {
+                batch.remove(key);
+            }
+            for (key, data) in rewritten {
+                self.batch_insert(&mut batch, key.as_bytes(), &data)?;
+            }
+            self.tree.apply_batch(batch).context("Failed to delete orphaned records")?;
+        }
+        Ok(report)
+    }
+    
+    // ===== Integrity =====
+    
+    /// Read every record of the profile, reporting those that don't
+    /// decrypt or parse, and conversations and messages whose contact or
+    /// conversation is missing
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn verify_integrity(&self) -> Result<IntegrityReport> {
+        let mut report = IntegrityReport::default();
+        let mut contacts = HashSet::from([SELF_CONTACT_ID.to_string()]);
+        let mut conversations = Vec::new();
+        let mut messages = Vec::new();
+        for item in self.tree.iter() {
+            let (key, value) = item.context("Failed to read record")?;
+            if key.starts_with(PREFIX_CORRUPT.as_bytes()) {
+                report.quarantined += 1;
+                continue;
+            }
+            // Stored in the clear
+            if [PREFIX_MASTER_KEY, PREFIX_META, PREFIX_SETTINGS].iter().any(|p| key.starts_with(p.as_bytes())) {
+                continue;
+            }
+            report.records_checked += 1;
+            
+            let name = String::from_utf8_lossy(&key).into_owned();
+            let plaintext = match self.decrypt_record(&key, &value) {
+                Ok(plaintext) => plaintext,
+                Err(e) => {
+                    let problem = match e.downcast_ref::<UnsupportedRecordFormat>() {
+                        Some(format) => Problem::UnsupportedFormat { version: format.version },
+                        None => Problem::Undecryptable,
+                    };
+                    report.problems.push(RecordProblem { key: name, problem });
+                    continue;
+                }
+            };
+            let checked = if key.starts_with(PREFIX_CONTACT.as_bytes()) {
+                parse_record::<Contact>(&plaintext).map(|contact| {
+                    contacts.insert(contact.id);
+                })
+            } else if key.starts_with(PREFIX_CONVERSATION.as_bytes()) {
+                parse_record::<Conversation>(&plaintext).map(|conversation| {
+                    conversations.push((name.clone(), conversation.id, conversation.contact_id));
+                })
+            } else if key.starts_with(PREFIX_MESSAGE.as_bytes()) {
+                parse_record::<LocalMessage>(&plaintext).map(|message| {
+                    messages.push((name.clone(), message.conversation_id));
+                })
+            } else {
+                check_record_kind(&key, &plaintext)
+            };
+            if let Err(e) = checked {
+                report.problems.push(RecordProblem { key: name, problem: Problem::Malformed { error: format!("{:#}", e) } });
+            }
+        }
+        
+        let conversation_ids: HashSet<_> = conversations.iter().map(|(_, id, _)| id.clone()).collect();
+        for (key, _, contact_id) in conversations {
+            if !contacts.contains(&contact_id) {
+                report.problems.push(RecordProblem { key, problem: Problem::Dangling { missing: format!("contact {}", contact_id) } });
+            }
+        }
+        for (key, conversation_id) in messages {
+            if !conversation_ids.contains(&conversation_id) {
+                report.problems.push(RecordProblem { key, problem: Problem::Dangling { missing: format!("conversation {}", conversation_id) } });
+            }
+        }
+        Ok(report)
+    }
+    
+    /// Move records aside, as stored, so reading the rest doesn't trip over
+    /// them. Returns how many were moved.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn quarantine_records<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Result<usize> {
+        let mut batch = sled::Batch::default();
+        let mut moved = 0;
+        for key in keys {
+            if let Some(value) = self.tree.get(key.as_bytes()).context("Failed to read record")? {
+                batch.insert(format!("{}{}", PREFIX_CORRUPT, key).as_bytes(), value);
+                batch.remove(key.as_bytes());
+                moved += 1;
+            }
+        }
+        self.tree.apply_batch(batch).context("Failed to quarantine records")?;
+        Ok(moved)
+    }
+    
+    /// Flush all changes to disk
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()
+            .context("Failed to flush database")?;
+        self.flusher().flushed();
+        Ok(())
+    }
+    
+    /// When changes were last written to disk this run
+    pub fn last_flush(&self) -> Option<OffsetDateTime> {
+        self.flusher().last_flush()
+    }
+    
+    pub fn durability(&self) -> Durability {
+        self.flusher().settings()
+    }
+    
+    /// Change how writes reach the disk, for every handle, restarting the
+    /// background flusher
+    pub fn set_durability(&self, durability: Durability) -> Result<()> {
+        durability.validate()?;
+        let mut flusher = self.flusher();
+        *flusher = Flusher::replace(self.db.clone(), durability, &flusher)?;
+        Ok(())
+    }
+    
+    /// Flush after writing a message, as the durability settings ask
+    pub fn flush_message_writes(&self) -> Result<()> {
+        let flusher = self.flusher();
+        let settings = flusher.settings();
+        if !settings.flush_on_message {
+            return Ok(());
+        }
+        match settings.fsync {
+            FsyncPolicy::Always => {
+                drop(flusher);
+                self.flush()
+            }
+            FsyncPolicy::Background => {
+                flusher.wake();
+                Ok(())
+            }
+        }
+    }
+    
+    fn flusher(&self) -> std::sync::MutexGuard<'_, Flusher> {
+        // The flusher is only swapped under the lock, never left half-made
+        self.flusher.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    
+    /// Close the database
+    pub fn close(self) -> Result<()> {
+        self.db.flush()
+            .context("Failed to close database")?;
+        Ok(())
+    }
+}
+
+fn open_db<P: AsRef<Path>>(path: P) -> sled::Result<Db> {
+    // A database closed a moment ago can still be locked by sled's
+    // background threads winding down
+    const LOCK_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
+    const LOCK_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(50);
+    let deadline = std::time::Instant::now() + LOCK_WAIT;
+    loop {
+        // Flushing is left to the storage's own flusher
+        match sled::Config::new().path(path.as_ref()).flush_every_ms(None).open() {
+            Err(sled::Error::Io(_)) if is_locked(path.as_ref()) && std::time::Instant::now() < deadline => {
+                std::thread::sleep(LOCK_RETRY_DELAY);
+            }
+            opened => return opened,
+        }
+    }
+}
+
+/// Whether someone holds the lock on the database at `path`. sled's
+/// error doesn't keep the OS error, so the lock is tried directly.
+fn is_locked(path: &Path) -> bool {
+    std::fs::File::open(path.join("db"))
+        .is_ok_and(|file| matches!(file.try_lock(), Err(std::fs::TryLockError::WouldBlock)))
+}
+
+fn parse_record<T: DeserializeOwned>(plaintext: &[u8]) -> Result<T> {
+    bincode::deserialize(plaintext).context("Failed to deserialize record")
+}
+
+/// What the record under `key` holds, as counted in backup manifests
+pub fn record_category(key: &[u8]) -> RecordCategory {
+    let categories = [
+        (RecordCategory::Account, &[
+            PREFIX_IDENTITY, PREFIX_PROFILE, PREFIX_DEVICE, PREFIX_SETTINGS, PREFIX_META, PREFIX_INVITE_PREKEY,
+            PREFIX_USERNAME_PIN, PREFIX_PUSH_ENDPOINT, PREFIX_KEY_LOG, PREFIX_KEY_LOG_HEAD,
+            PREFIX_ESCROWED_BACKUP_KEY,
+        ][..]),
+        (RecordCategory::Contacts, &[
+            PREFIX_CONTACT, PREFIX_CONTACT_SETTINGS, PREFIX_CONTACT_NOTE, PREFIX_SESSION_REQUIREMENTS,
+            PREFIX_CONTACT_PREKEY, PREFIX_CONTACT_PUSH, PREFIX_CONTACT_DEVICES, PREFIX_DEVICE_SESSION,
+            PREFIX_RECEIVING_SESSION, PREFIX_DEVICE_CAPABILITIES, PREFIX_AUDIT, PREFIX_AUDIT_HEAD, PREFIX_PENDING_CONTACT,
+            PREFIX_ISSUED_CARD, PREFIX_CARD_REVOCATION, PREFIX_LABEL, PREFIX_MESSAGE_REQUEST, PREFIX_BLOCKED_SENDER,
+            PREFIX_USED_STAMP,
+        ][..]),
+        (RecordCategory::Conversations, &[
+            PREFIX_CONVERSATION, PREFIX_RETENTION, PREFIX_CONVERSATION_POLICY, PREFIX_AUTHENTICATION,
+            PREFIX_FOLDER, PREFIX_BROADCAST_LIST, PREFIX_BROADCAST,
+        ][..]),
+        (RecordCategory::Messages, &[
+            PREFIX_MESSAGE, PREFIX_ANNOTATION, PREFIX_ENVELOPE_ALIAS, PREFIX_QUARANTINE,
+        ][..]),
+        (RecordCategory::Attachments, &[PREFIX_BLOB, PREFIX_THUMBNAIL, PREFIX_VIEW_ONCE, PREFIX_STICKER_PACK][..]),
+        // As `usage_report` counts them
+        (RecordCategory::Indexes, &[
+            PREFIX_BLOB_REF, PREFIX_STARRED, PREFIX_CONVERSATION_BY_CONTACT, PREFIX_MESSAGE_STATUS,
+            PREFIX_MESSAGE_ORDER, PREFIX_ACTIVITY, PREFIX_CONVERSATION_STATS, PREFIX_SEARCH, PREFIX_DAY_COUNT,
+        ][..]),
+    ];
+    categories.iter()
+        .find(|(_, prefixes)| prefixes.iter().any(|prefix| key.starts_with(prefix.as_bytes())))
+        .map_or(RecordCategory::Other, |(category, _)| *category)
+}
+
+/// Conversation of the message stored under `key`, if it is one
+pub fn message_conversation_id(key: &[u8]) -> Option<String> {
+    let path = std::str::from_utf8(key.strip_prefix(PREFIX_MESSAGE.as_bytes())?).ok()?;
+    let (conversation, _) = path.split_once('/')?;
+    urlencoding::decode(conversation).ok().map(|id| id.into_owned())
+}
+
+/// Check a decrypted record parses as what its key says it holds. Kinds
+/// not listed only need to decrypt.
+fn check_record_kind(key: &[u8], plaintext: &[u8]) -> Result<()> {
+    fn parses<T: DeserializeOwned>(plaintext: &[u8]) -> Result<()> {
+        parse_record::<T>(plaintext).map(|_| ())
+    }
+    /// A list's directory or one of its blocks
+    fn parses_index_list(plaintext: &[u8]) -> Result<()> {
+        parses::<Directory>(plaintext).or_else(|_| parses::<Vec<Posting>>(plaintext))
+    }
+    type Check = fn(&[u8]) -> Result<()>;
+    let kinds: [(&str, Check); 48] = [
+        (PREFIX_IDENTITY, parses::<EncryptedIdentityKeys>),
+        (PREFIX_DEVICE, parses::<DeviceInfo>),
+        (PREFIX_CONTACT_SETTINGS, parses::<ContactSettings>),
+        (PREFIX_CONTACT_NOTE, parses::<ContactNote>),
+        (PREFIX_SESSION_REQUIREMENTS, parses::<SessionRequirements>),
+        (PREFIX_PENDING_CONTACT, parses::<PendingContact>),
+        (PREFIX_ISSUED_CARD, parses::<IssuedCard>),
+        (PREFIX_CARD_REVOCATION, parses::<CardRevocation>),
+        (PREFIX_VIEW_ONCE, parses::<ViewOnce>),
+        (PREFIX_CONVERSATION_POLICY, parses::<ConversationPolicies>),
+        (PREFIX_AUTHENTICATION, parses::<Authentication>),
+        (PREFIX_STICKER_PACK, parses::<StickerPack>),
+        (PREFIX_QUARANTINE, parses::<QuarantinedEnvelope>),
+        (PREFIX_AUDIT, parses::<SecurityEvent>),
+        (PREFIX_AUDIT_HEAD, parses::<AuditHead>),
+        (PREFIX_KEY_LOG, parses::<KeyLogEntry>),
+        (PREFIX_KEY_LOG_HEAD, parses::<KeyLogHead>),
+        (PREFIX_ESCROWED_BACKUP_KEY, parses::<EscrowedBackupKey>),
+        (PREFIX_USERNAME_PIN, parses::<[u8; 32]>),
+        (PREFIX_INVITE_PREKEY, parses::<InvitePrekey>),
+        (PREFIX_CONTACT_PREKEY, parses::<ContactPrekey>),
+        (PREFIX_KNOWN_PEER, parses::<PeerInfo>),
+        (PREFIX_BOOTSTRAP, parses::<BootstrapNode>),
+        (PREFIX_ENVELOPE_ALIAS, parses::<String>),
+        (PREFIX_RETENTION, parses::<RetentionPolicy>),
+        (PREFIX_CONVERSATION_BY_CONTACT, parses::<String>),
+        (PREFIX_MESSAGE_ORDER, parses_index_list),
+        (PREFIX_ACTIVITY, parses_index_list),
+        (PREFIX_SEARCH, parses_index_list),
+        (PREFIX_CONVERSATION_STATS, parses::<StatsRecord>),
+        (PREFIX_DAY_COUNT, parses::<BTreeMap<Date, u64>>),
+        (PREFIX_THUMBNAIL, parses::<Thumbnail>),
+        (PREFIX_ANNOTATION, parses::<Vec<Annotation>>),
+        (PREFIX_PUSH_ENDPOINT, parses::<PushEndpoint>),
+        (PREFIX_CONTACT_PUSH, parses::<Vec<PushEndpoint>>),
+        (PREFIX_CONTACT_DEVICES, parses::<Vec<RemoteDevice>>),
+        (PREFIX_DEVICE_SESSION, parses::<DeviceSession>),
+        (PREFIX_RECEIVING_SESSION, parses::<ReceivingSession>),
+        (PREFIX_DEVICE_CAPABILITIES, parses::<Capabilities>),
+        (PREFIX_LABEL, parses::<ContactLabel>),
+        (PREFIX_FOLDER, parses::<ChatFolder>),
+        (PREFIX_WEBHOOK, parses::<Webhook>),
+        (PREFIX_BROADCAST_LIST, parses::<BroadcastList>),
+        (PREFIX_BROADCAST, parses::<Broadcast>),
+        (PREFIX_MESSAGE_REQUEST, parses::<MessageRequest>),
+        (PREFIX_BLOCKED_SENDER, parses::<OffsetDateTime>),
+        (PREFIX_INGEST_JOURNAL, |plaintext| wire::decode::<MessageEnvelope>(plaintext).map(|_| ())),
+        (PREFIX_OUTBOX, |plaintext| ProtocolMessage::decode(plaintext).map(|_| ())),
+    ];
+    match kinds.iter().find(|(prefix, _)| key.starts_with(prefix.as_bytes())) {
+        Some((_, check)) => check(plaintext),
+        None => Ok(()),
+    }
+}
+
+/// Parse `mk:`: two slots, or the single master key of older databases
+fn decode_key_slots(data: &[u8]) -> Result<Vec<MasterKey>> {
+    if let Ok(slots) = bincode::deserialize::<[MasterKey; 2]>(data) {
+        return Ok(slots.to_vec());
+    }
+    let legacy: MasterKey = bincode::deserialize(data)
+        .context("Failed to deserialize master key")?;
+    Ok(vec![legacy])
+}
+
+fn store_key_slots(db: &Db, slots: &[MasterKey; 2]) -> Result<()> {
+    let serialized = bincode::serialize(slots)
+        .context("Failed to serialize master key")?;
+    db.insert(PREFIX_MASTER_KEY.as_bytes(), serialized)
+        .context("Failed to store master key")?;
+    Ok(())
+}
+
+fn store_recovery_slot(db: &Db, slot: &RecoverySlot) -> Result<()> {
+    let serialized = bincode::serialize(slot)
+        .context("Failed to serialize recovery slot")?;
+    db.insert(META_RECOVERY.as_bytes(), serialized)
+        .context("Failed to store recovery slot")?;
+    Ok(())
+}
+
+fn conversation_index_key(contact_id: &str) -> String {
+    format!("{}{}", PREFIX_CONVERSATION_BY_CONTACT, contact_id)
+}
+
+fn status_name(status: &DeliveryStatus) -> &'static str {
+    match status {
+        DeliveryStatus::Queued => STATUS_NAMES[0],
+        DeliveryStatus::Sending => STATUS_NAMES[1],
+        DeliveryStatus::Sent => STATUS_NAMES[2],
+        DeliveryStatus::Delivered => STATUS_NAMES[3],
+        DeliveryStatus::Read => STATUS_NAMES[4],
+        DeliveryStatus::Failed { .. } => STATUS_NAMES[5],
+    }
+}
+
+/// `<conversation>/<message>`, under which a message and the records
+/// about it are kept
+fn message_path(message: &LocalMessage) -> String {
+    message_path_of(&message.conversation_id, &message.id)
+}
+
+fn message_path_of(conversation_id: &str, message_id: &str) -> String {
+    format!("{}{}", conversation_path(conversation_id), message_id)
+}
+
+/// Start of the paths of a conversation's messages. Ids may hold '/', so
+/// the conversation's has '%' and '/' percent-encoded and a path splits
+/// back exactly at its first '/'.
+fn conversation_path(conversation_id: &str) -> String {
+    format!("{}/", conversation_id.replace('%', "%25").replace('/', "%2F"))
+}
+
+/// `timestamp` as big-endian bytes, which sort in time order. Flipping
+/// the sign bit keeps timestamps before 1970 in order.
+fn time_sort(timestamp: OffsetDateTime) -> Vec<u8> {
+    ((timestamp.unix_timestamp_nanos() as u128) ^ (1 << 127)).to_be_bytes().to_vec()
+}
+
+/// What a message's order posting sorts by: its clock, then its time, so
+/// with the path after them postings sort as `LocalMessage::causal_cmp`
+/// does
+fn order_sort(message: &LocalMessage) -> Vec<u8> {
+    let mut sort = message.lamport.to_be_bytes().to_vec();
+    sort.extend(time_sort(message.timestamp));
+    sort
+}
+
+/// `<list>/<block>`, the block numbered in fixed-width hex
+fn index_block_key(list: &str, id: u64) -> String {
+    format!("{}/{:016x}", list, id)
+}
+
+fn status_index_key(status: &DeliveryStatus, conversation_id: &str, message_id: &str) -> String {
+    format!("{}{}/{}", PREFIX_MESSAGE_STATUS, status_name(status), message_path_of(conversation_id, message_id))
+}
+
+fn quarantine_key(sender_id: &str, envelope_id: &str) -> String {
+    format!("{}{}/{}", PREFIX_QUARANTINE, sender_id, envelope_id)
+}
+
+fn device_session_key(contact_id: &str, device_id: &str) -> String {
+    format!("{}{}/{}", PREFIX_DEVICE_SESSION, contact_id, blake3::hash(device_id.as_bytes()).to_hex())
+}
+
+fn receiving_session_key(contact_id: &str, device_id: &str) -> String {
+    format!("{}{}/{}", PREFIX_RECEIVING_SESSION, contact_id, blake3::hash(device_id.as_bytes()).to_hex())
+}
+
+fn device_capabilities_key(contact_id: &str, device_id: &str) -> String {
+    format!("{}{}/{}", PREFIX_DEVICE_CAPABILITIES, contact_id, blake3::hash(device_id.as_bytes()).to_hex())
+}
+
+fn invite_prekey_key(prekey: &[u8; 32]) -> String {
+    format!("{}{}", PREFIX_INVITE_PREKEY, blake3::Hash::from_bytes(*prekey).to_hex())
+}
+
+fn profile_tree_name(keys: &KeyHierarchy) -> String {
+    format!("{}{}", PROFILE_TREE_PREFIX, blake3::Hash::from_bytes(keys.profile_id).to_hex())
+}
+
+/// Profile tree for databases without a duress password: a random name
+/// and a random marker of the same size as a sealed one
+fn create_stand_in_profile(db: &Db, rng: &mut impl RngCore) -> Result<()> {
+    let mut id = [0u8; 32];
+    rng.fill_bytes(&mut id);
+    let mut marker = vec![0u8; RECORD_HEADER_LEN + RECORD_NONCE_LEN + PROFILE_MARKER_LEN + RECORD_TAG_LEN];
+    rng.fill_bytes(&mut marker);
+    marker[..RECORD_HEADER_LEN].copy_from_slice(&RecordHeader::CURRENT.to_bytes());
+    
+    db.open_tree(format!("{}{}", PROFILE_TREE_PREFIX, blake3::Hash::from_bytes(id).to_hex()))
+        .context("Failed to create profile tree")?
+        .insert(META_PROFILE.as_bytes(), marker)
+        .context("Failed to store profile marker")?;
+    Ok(())
+}
+
+/// Replace every slot but `keep` with one no password opens and drop the
+/// account's records. Old pages may linger in the sled log until they are
+/// reclaimed, but without the slot nothing can decrypt them.
+fn wipe_account(db: &Db, slots: &mut [MasterKey], keep: usize) -> Result<()> {
+    let mut rng = rand::thread_rng();
+    for (i, slot) in slots.iter_mut().enumerate() {
+        if i != keep {
+            *slot = MasterKey::empty_slot(&mut rng);
+        }
+    }
+    let slots: [MasterKey; 2] = slots.to_vec().try_into()
+        .map_err(|_| anyhow::anyhow!("Expected two key slots"))?;
+    store_key_slots(db, &slots)?;
+    // The phrase would open the wiped account's slot again
+    store_recovery_slot(db, &RecoverySlot::stand_in(&mut rng))?;
+    
+    let mut batch = sled::Batch::default();
+    for key in db.iter().keys() {
+        let key = key.context("Failed to read record")?;
+        if !key.starts_with(PREFIX_MASTER_KEY.as_bytes()) && !key.starts_with(PREFIX_META.as_bytes()) {
+            batch.remove(key);
+        }
+    }
+    db.apply_batch(batch)
+        .context("Failed to remove records")?;
+    db.flush()
+        .context("Failed to flush wipe")?;
+    Ok(())
+}
+
+/// Streams an attachment into the blob store. Chunks are staged under a
+/// temporary key until the digest, and so the final key, is known.
+pub struct BlobWriter<'a> {
+    storage: &'a SecureStorage,
+    staging: String,
+    hasher: blake3::Hasher,
+    buffer: Vec<u8>,
+    chunks: u32,
+    size: u64,
+    finished: bool,
+}
+
+impl BlobWriter<'_> {
+    pub fn write(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let take = data.len().min(ATTACHMENT_CHUNK_SIZE - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buffer.len() == ATTACHMENT_CHUNK_SIZE {
+                self.flush_chunk()?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Commit the attachment under its content address
+    pub fn finish(mut self) -> Result<AttachmentRef> {
+        if !self.buffer.is_empty() || self.chunks == 0 {
+            self.flush_chunk()?;
+        }
+        
+        let attachment = AttachmentRef {
+            digest: *self.hasher.finalize().as_bytes(),
+            size: self.size,
+            key: rand::random(),
+        };
+        
+        // Identical content already stored is simply overwritten with the
+        // same chunks, leaving one copy. Chunks are sealed for their key, so
+        // each is resealed for its place in the blob.
+        let mut batch = sled::Batch::default();
+        for index in 0..self.chunks {
+            let staged = format!("{}/{:08x}", self.staging, index);
+            let chunk = self.storage.tree.get(staged.as_bytes())?
+                .ok_or_else(|| anyhow::anyhow!("Staged attachment chunk vanished"))?;
+            let chunk = self.storage.decrypt_record(staged.as_bytes(), &chunk)?;
+            self.storage.batch_insert(&mut batch, self.storage.blob_chunk_key(&attachment, index).as_bytes(), &chunk)?;
+            batch.remove(staged.as_bytes());
+        }
+        self.storage.tree.apply_batch(batch)
+            .context("Failed to store attachment")?;
+        self.finished = true;
+        
+        Ok(attachment)
+    }
+    
+    fn flush_chunk(&mut self) -> Result<()> {
+        self.hasher.update(&self.buffer);
+        self.size += self.buffer.len() as u64;
+        let key = format!("{}/{:08x}", self.staging, self.chunks);
+        self.storage.tree.insert(key.as_bytes(), self.storage.encrypt(key.as_bytes(), &self.buffer)?)
+            .context("Failed to store attachment chunk")?;
+        self.chunks += 1;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl Drop for BlobWriter<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            for key in self.storage.tree.scan_prefix(self.staging.as_bytes()).keys().flatten() {
+                self.storage.tree.remove(key).ok();
+            }
+        }
+    }
+}
+
+/// Streams an attachment out of the blob store, see
+/// `SecureStorage::blob_reader`
+pub struct BlobReader<'a> {
+    storage: &'a SecureStorage,
+    attachment: AttachmentRef,
+    hasher: blake3::Hasher,
+    chunk: Vec<u8>,
+    position: usize,
+    next_index: u32,
+}
+
+impl BlobReader<'_> {
+    /// Load the next chunk; false after the last, once the digest matched
+    fn next_chunk(&mut self) -> Result<bool> {
+        if self.next_index == self.attachment.chunk_count() {
+            if self.hasher.finalize().as_bytes() != &self.attachment.digest {
+                return Err(anyhow::anyhow!("Attachment does not match its digest"));
+            }
+            return Ok(false);
+        }
+        self.chunk = self.storage.get_blob_chunk(&self.attachment, self.next_index)?
+            .ok_or_else(|| anyhow::anyhow!("Attachment chunk vanished"))?;
+        self.hasher.update(&self.chunk);
+        self.position = 0;
+        self.next_index += 1;
+        Ok(true)
+    }
+}
+
+impl std::io::Read for BlobReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if !self.next_chunk().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))? {
+                return Ok(0);
+            }
+        }
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Move the identity keys inside identity and device records from one
+/// wrapping key to another. Other records pass through unchanged.
+fn rewrap_identity_keys(key: &[u8], plaintext: &[u8], from: &[u8; 32], to: &[u8; 32]) -> Result<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    
+    if key.starts_with(PREFIX_IDENTITY.as_bytes()) {
+        let identity: EncryptedIdentityKeys = bincode::deserialize(plaintext)
+            .context("Failed to deserialize identity")?;
+        let rewrapped = IdentityKeyPair::decrypt(&identity, from)?
+            .encrypt(to, &mut rng)?;
+        return bincode::serialize(&rewrapped).context("Failed to serialize identity");
     }
     
-    // ===== Settings Operations =====
+    if key.starts_with(PREFIX_DEVICE.as_bytes()) {
+        let mut device: DeviceInfo = bincode::deserialize(plaintext)
+            .context("Failed to deserialize device")?;
+        device.identity_key = IdentityKeyPair::decrypt(&device.identity_key, from)?
+            .encrypt(to, &mut rng)?;
+        return bincode::serialize(&device).context("Failed to serialize device");
+    }
     
-    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
-        self.db.insert(
-            format!("{}{}", PREFIX_SETTINGS, key).as_bytes(),
-            value.as_bytes()
-        ).context("Failed to store setting")?;
+    Ok(plaintext.to_vec())
+}
+
+/// A stored record was sealed in a format this build doesn't know,
+/// presumably by a newer one. Find it with `downcast_ref`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Record format version {version} (cipher {cipher}, flags {flags:#04x}) is not supported by this version")]
+pub struct UnsupportedRecordFormat {
+    pub version: u8,
+    pub cipher: u8,
+    pub flags: u8,
+}
+
+/// Header of a record's envelope
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RecordHeader {
+    version: u8,
+    cipher: u8,
+    /// Reserved, zero so far
+    flags: u8,
+}
+
+impl RecordHeader {
+    const CURRENT: Self = Self { version: RECORD_VERSION, cipher: RECORD_CIPHER_XCHACHA20_POLY1305, flags: 0 };
+    
+    fn to_bytes(self) -> [u8; RECORD_HEADER_LEN] {
+        [RECORD_MAGIC[0], RECORD_MAGIC[1], self.version, self.cipher, self.flags]
+    }
+    
+    /// The header `data` starts with, None for a record in an earlier
+    /// layout. No earlier format tag is the magic's first byte.
+    fn parse(data: &[u8]) -> Option<Self> {
+        match data {
+            [a, b, version, cipher, flags, ..] if [*a, *b] == RECORD_MAGIC => {
+                Some(Self { version: *version, cipher: *cipher, flags: *flags })
+            }
+            _ => None,
+        }
+    }
+    
+    /// Refuse formats from newer builds rather than misread them
+    fn check(self) -> Result<(), UnsupportedRecordFormat> {
+        if self != Self::CURRENT {
+            return Err(UnsupportedRecordFormat { version: self.version, cipher: self.cipher, flags: self.flags });
+        }
         Ok(())
     }
+}
+
+/// Seal the record stored under `record_key` in the current envelope.
+/// XChaCha20-Poly1305's 192-bit nonces can be drawn at random for the
+/// lifetime of the database without a realistic collision risk.
+fn seal_record(key: &[u8; 32], record_key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    use chacha20poly1305::{
+        aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+        XChaCha20Poly1305,
+    };
+    
+    let header = RecordHeader::CURRENT.to_bytes();
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let aad = [&header[..], record_key].concat();
+    
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: data, aad: &aad })
+        .map_err(|e| anyhow::anyhow!("Encryption failed: {:?}", e))?;
+    
+    let mut result = Vec::with_capacity(RECORD_HEADER_LEN + RECORD_NONCE_LEN + ciphertext.len());
+    result.extend_from_slice(&header);
+    result.extend_from_slice(&nonce);
+    result.extend_from_slice(&ciphertext);
+    
+    Ok(result)
+}
+
+/// Open the record stored under `record_key`, in the current envelope or
+/// the bound layout before it
+fn open_record(key: &[u8; 32], record_key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    use chacha20poly1305::{
+        aead::{Aead, KeyInit, Payload},
+        XChaCha20Poly1305, XNonce,
+    };
+    
+    let Some(header) = RecordHeader::parse(data) else {
+        return open_bound(key, record_key, data)
+            .ok_or_else(|| anyhow::anyhow!("Decryption failed"));
+    };
+    header.check()?;
+    if data.len() <= RECORD_HEADER_LEN + RECORD_NONCE_LEN {
+        return Err(anyhow::anyhow!("Decryption failed"));
+    }
     
-    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
-        match self.db.get(format!("{}{}", PREFIX_SETTINGS, key).as_bytes()) {
-            Ok(Some(data)) => {
-                let value = String::from_utf8(data.to_vec())
-                    .context("Invalid UTF-8 in setting")?;
-                Ok(Some(value))
+    let nonce = &data[RECORD_HEADER_LEN..RECORD_HEADER_LEN + RECORD_NONCE_LEN];
+    let ciphertext = &data[RECORD_HEADER_LEN + RECORD_NONCE_LEN..];
+    let aad = [&data[..RECORD_HEADER_LEN], record_key].concat();
+    
+    let cipher = XChaCha20Poly1305::new(key.into());
+    cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| anyhow::anyhow!("Decryption failed"))
+}
+
+/// The nonce of a record, unique to each write of it, in either layout
+/// `open_record` reads
+fn record_nonce(data: &[u8]) -> Option<&[u8]> {
+    let start = match (RecordHeader::parse(data), data.first()) {
+        (Some(_), _) => RECORD_HEADER_LEN,
+        (None, Some(&RECORD_FORMAT_BOUND)) => 1,
+        _ => return None,
+    };
+    data.get(start..start + RECORD_NONCE_LEN)
+}
+
+/// Decrypt a record in the bound layout from before record headers, if it
+/// is one
+fn open_bound(key: &[u8; 32], record_key: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    use chacha20poly1305::{
+        aead::{Aead, KeyInit, Payload},
+        XChaCha20Poly1305, XNonce,
+    };
+    
+    if data.len() <= 1 + RECORD_NONCE_LEN || data[0] != RECORD_FORMAT_BOUND {
+        return None;
+    }
+    
+    let nonce = &data[1..1 + RECORD_NONCE_LEN];
+    let ciphertext = &data[1 + RECORD_NONCE_LEN..];
+    
+    let cipher = XChaCha20Poly1305::new(key.into());
+    cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: record_key }).ok()
+}
+
+/// Decrypt a record in the XChaCha20-Poly1305 format from before records
+/// were bound to their keys, if it is one
+fn open_unbound(key: &[u8; 32], data: &[u8]) -> Option<Vec<u8>> {
+    use chacha20poly1305::{
+        aead::{Aead, KeyInit},
+        XChaCha20Poly1305, XNonce,
+    };
+    
+    if data.len() <= 1 + RECORD_NONCE_LEN || data[0] != RECORD_FORMAT_XCHACHA {
+        return None;
+    }
+    
+    let nonce = &data[1..1 + RECORD_NONCE_LEN];
+    let ciphertext = &data[1 + RECORD_NONCE_LEN..];
+    
+    let cipher = XChaCha20Poly1305::new(key.into());
+    cipher.decrypt(XNonce::from_slice(nonce), ciphertext).ok()
+}
+
+/// Decrypt a record written before the XChaCha20 switch:
+/// [unused salt:16][nonce:12][AES-256-GCM ciphertext]
+///
+/// The random salt can collide with the format tag, so this is only tried
+/// after authenticated decryption in the current format has failed.
+fn open_legacy(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>> {
+    use aes_gcm::{
+        aead::{Aead, KeyInit},
+        Aes256Gcm, Key, Nonce,
+    };
+    
+    if data.len() < 28 {
+        return Err(anyhow::anyhow!("Invalid encrypted data"));
+    }
+    
+    let nonce = &data[16..28];
+    let ciphertext = &data[28..];
+    
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|e| anyhow::anyhow!("Decryption failed: {:?}", e))?;
+    
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use tempfile::TempDir;
+    
+    /// Write a record the way storage did before the XChaCha20 switch.
+    /// The salt deliberately starts with the current format tag.
+    fn seal_legacy(key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+        use aes_gcm::{
+            aead::{Aead, AeadCore, KeyInit, OsRng},
+            Aes256Gcm, Key,
+        };
+        
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        let ciphertext = cipher.encrypt(&nonce, data).unwrap();
+        
+        let mut sealed = vec![RECORD_FORMAT_XCHACHA; 16];
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+    
+    /// Write a record in the layout of `tag` from before record headers:
+    /// [tag:1][nonce:24][ciphertext], bound to nothing or to `record_key`
+    fn seal_headerless(key: &[u8; 32], tag: u8, record_key: &[u8], data: &[u8]) -> Vec<u8> {
+        use chacha20poly1305::{
+            aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+            XChaCha20Poly1305,
+        };
+        
+        let aad = if tag == RECORD_FORMAT_BOUND { record_key } else { &[] };
+        let cipher = XChaCha20Poly1305::new(key.into());
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, Payload { msg: data, aad }).unwrap();
+        
+        let mut sealed = vec![tag];
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+    
+    #[test]
+    fn test_schema_v1_database_is_migrated() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("db");
+        let mut rng = rand::thread_rng();
+        let identity = IdentityKeyPair::generate(&mut rng);
+        
+        // Lay out a database the way older releases did: every record
+        // directly under the master key, in both record formats
+        {
+            let db = sled::open(&path).unwrap();
+            let (store, master_key) = MasterKey::from_password("password", &mut rng).unwrap();
+            db.insert(PREFIX_MASTER_KEY, bincode::serialize(&store).unwrap()).unwrap();
+            
+            let contact = Contact::new("c1".to_string(), "Alice".to_string(), [1u8; 32]);
+            let contact_bytes = bincode::serialize(&contact).unwrap();
+            db.insert("ct:c1", seal_legacy(&master_key, &contact_bytes)).unwrap();
+            
+            let wrapped = identity.encrypt(&master_key, &mut rng).unwrap();
+            let identity_bytes = bincode::serialize(&wrapped).unwrap();
+            db.insert("id:self", seal_headerless(&master_key, RECORD_FORMAT_XCHACHA, b"", &identity_bytes)).unwrap();
+            db.flush().unwrap();
+        }
+        
+        let storage = SecureStorage::unlock(&path, "password").unwrap();
+        assert_eq!(storage.get_contact("c1").unwrap().unwrap().display_name, "Alice");
+        
+        let wrapped = storage.get_identity().unwrap().unwrap();
+        let unwrapped = IdentityKeyPair::decrypt(&wrapped, &storage.keys().identity_wrap).unwrap();
+        assert_eq!(unwrapped.public_key, identity.public_key);
+        
+        let marker = storage.db.get(META_KEY_SCHEMA).unwrap().unwrap();
+        assert_eq!(marker.as_ref(), KEY_SCHEMA_VERSION.to_be_bytes());
+        
+        // The single master key gained a stand-in second slot
+        let slots = decode_key_slots(&storage.db.get(PREFIX_MASTER_KEY).unwrap().unwrap()).unwrap();
+        assert_eq!(slots.len(), 2);
+        assert!(storage.master_key_store().unwrap().unlock("password").is_ok());
+    }
+    
+    #[test]
+    fn test_message_flags_are_migrated_to_status() {
+        let storage = SecureStorage::open_temporary(&[7u8; 32]).unwrap();
+        storage.set_message_schema(1).unwrap();
+        
+        // (id, conversation, sender, outgoing, content, timestamp, sent,
+        // delivered, read, reply_to, forwarded_from, starred, lamport)
+        let legacy = |id: &str, outgoing: bool, flags: (bool, bool, bool)| {
+            let record = (
+                id, "c1", "self", outgoing, MessageContent::Text { text: id.to_string() }, OffsetDateTime::UNIX_EPOCH,
+                flags.0, flags.1, flags.2, None::<String>, None::<ForwardedFrom>, false, 1u64,
+            );
+            let key = format!("{}c1/{}", PREFIX_MESSAGE, id);
+            storage.tree.insert(&key, storage.encrypt(key.as_bytes(), &bincode::serialize(&record).unwrap()).unwrap()).unwrap();
+        };
+        legacy("queued", true, (false, false, false));
+        legacy("sent", true, (true, false, false));
+        legacy("delivered", true, (true, true, false));
+        legacy("read", true, (true, true, true));
+        legacy("incoming", false, (true, true, false));
+        
+        storage.migrate_message_schema().unwrap();
+        let status = |id: &str| storage.get_message("c1", id).unwrap().unwrap().status;
+        assert_eq!(status("queued"), DeliveryStatus::Queued);
+        assert_eq!(status("sent"), DeliveryStatus::Sent);
+        assert_eq!(status("delivered"), DeliveryStatus::Delivered);
+        assert_eq!(status("read"), DeliveryStatus::Read);
+        assert_eq!(status("incoming"), DeliveryStatus::Delivered);
+        
+        // The marker travels with backups so restores migrate only old ones
+        let exported: Vec<_> = storage.export_records(&[1u8; 32]).collect::<Result<_>>().unwrap();
+        assert!(exported.iter().any(|(key, value)| key == META_MESSAGE_SCHEMA.as_bytes() && *value == MESSAGE_SCHEMA_VERSION.to_be_bytes()));
+        storage.migrate_message_schema().unwrap();
+        assert_eq!(status("read"), DeliveryStatus::Read);
+    }
+    
+    #[test]
+    fn test_message_paths_are_migrated() {
+        use crate::protocol::MessageContent;
+        
+        let storage = SecureStorage::create_temporary("password").unwrap();
+        let attachment = storage.store_blob(b"photo").unwrap();
+        let blob_id = storage.blob_id(&attachment);
+        let raw = |key: String, plaintext: &[u8]| {
+            storage.tree.insert(&key, storage.encrypt(key.as_bytes(), plaintext).unwrap()).unwrap();
+        };
+        
+        // Under schema 2 paths held conversation ids as they are, so these
+        // two could be told apart only by reading the records
+        storage.set_message_schema(2).unwrap();
+        for conversation_id in ["c", "c/1"] {
+            let message = LocalMessage {
+                id: "m/1".to_string(),
+                conversation_id: conversation_id.to_string(),
+                sender_id: "self".to_string(),
+                is_outgoing: true,
+                content: MessageContent::File {
+                    attachment: attachment.clone(),
+                    filename: "photo.png".to_string(),
+                    mime_type: "image/png".to_string(),
+                },
+                timestamp: OffsetDateTime::UNIX_EPOCH,
+                status: DeliveryStatus::Sent,
+                reply_to: None,
+                forwarded_from: None,
+                starred: false,
+                lamport: 1,
+            };
+            let path = format!("{}/{}", conversation_id, message.id);
+            raw(format!("{}{}", PREFIX_MESSAGE, path), &bincode::serialize(&message).unwrap());
+            let thumbnail = Thumbnail { mime_type: "image/jpeg".to_string(), width: 1, height: 1, data: conversation_id.as_bytes().to_vec() };
+            raw(format!("{}{}", PREFIX_THUMBNAIL, path), &bincode::serialize(&thumbnail).unwrap());
+            raw(format!("{}{}/{}", PREFIX_BLOB_REF, blob_id, path), &[]);
+        }
+        
+        storage.migrate_message_schema().unwrap();
+        storage.rebuild_indexes().unwrap();
+        let conversation_ids: HashSet<_> = storage.tree.scan_prefix(PREFIX_MESSAGE).keys()
+            .map(|key| message_conversation_id(&key.unwrap()).unwrap())
+            .collect();
+        assert_eq!(conversation_ids, HashSet::from(["c".to_string(), "c/1".to_string()]));
+        for conversation_id in ["c", "c/1"] {
+            assert_eq!(storage.get_message(conversation_id, "m/1").unwrap().unwrap().conversation_id, conversation_id);
+            assert_eq!(storage.get_thumbnail(conversation_id, "m/1").unwrap().unwrap().data, conversation_id.as_bytes());
+        }
+        assert!(storage.tree.get(format!("{}c/1/m/1", PREFIX_MESSAGE)).unwrap().is_none());
+        
+        storage.delete_message("c/1", "m/1").unwrap();
+        assert_eq!(storage.blob_ref_count(&attachment).unwrap(), 1);
+        assert!(storage.get_message("c", "m/1").unwrap().is_some());
+        storage.delete_message("c", "m/1").unwrap();
+        assert!(storage.get_blob(&attachment).unwrap().is_none());
+    }
+    
+    #[test]
+    fn test_records_use_fresh_nonces() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecureStorage::create(temp_dir.path().join("db"), "password").unwrap();
+        
+        let first = storage.encrypt(b"ct:a", b"same plaintext").unwrap();
+        let second = storage.encrypt(b"ct:a", b"same plaintext").unwrap();
+        assert_ne!(record_nonce(&first), record_nonce(&second));
+        assert_eq!(storage.decrypt_record(b"ct:a", &first).unwrap(), b"same plaintext");
+    }
+    
+    #[test]
+    fn test_records_are_bound_to_their_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("db");
+        let storage = SecureStorage::create(&path, "password").unwrap();
+        storage.store_contact(&Contact::new("alice".to_string(), "Alice".to_string(), [1u8; 32])).unwrap();
+        storage.store_contact(&Contact::new("bob".to_string(), "Bob".to_string(), [2u8; 32])).unwrap();
+        
+        // Bob's record moved over Alice's doesn't pass for hers
+        let bob = storage.tree.get("ct:bob").unwrap().unwrap();
+        storage.tree.insert("ct:alice", bob.clone()).unwrap();
+        storage.clear_cache();
+        assert!(storage.get_contact("alice").is_err());
+        
+        // Records sealed on their own, as before, are rebound on unlock
+        let plaintext = storage.decrypt_record(b"ct:bob", &bob).unwrap();
+        storage.tree.insert("ct:alice", seal_headerless(&storage.keys.storage, RECORD_FORMAT_XCHACHA, b"", &plaintext)).unwrap();
+        storage.tree.insert(META_RECORD_SCHEMA, &1u32.to_be_bytes()).unwrap();
+        storage.close().unwrap();
+        
+        let storage = SecureStorage::unlock(&path, "password").unwrap();
+        assert_eq!(storage.get_contact("alice").unwrap().unwrap().display_name, "Bob");
+        assert_eq!(RecordHeader::parse(&storage.tree.get("ct:alice").unwrap().unwrap()), Some(RecordHeader::CURRENT));
+        assert!(storage.verify_integrity().unwrap().is_clean());
+    }
+    
+    #[test]
+    fn test_record_envelope_versions() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecureStorage::create(temp_dir.path().join("db"), "password").unwrap();
+        let contact = Contact::new("alice".to_string(), "Alice".to_string(), [1u8; 32]);
+        storage.store_contact(&contact).unwrap();
+        let sealed = storage.tree.get("ct:alice").unwrap().unwrap();
+        assert_eq!(sealed[..RECORD_HEADER_LEN], *b"SR\x01\x01\x00");
+        
+        // Bound records without a header are read, and rewritten with one
+        let bound = seal_headerless(&storage.keys.storage, RECORD_FORMAT_BOUND, b"ct:alice", &bincode::serialize(&contact).unwrap());
+        storage.tree.insert("ct:alice", bound).unwrap();
+        storage.clear_cache();
+        assert_eq!(storage.get_contact("alice").unwrap().unwrap().display_name, "Alice");
+        storage.store_contact(&contact).unwrap();
+        assert_eq!(RecordHeader::parse(&storage.tree.get("ct:alice").unwrap().unwrap()), Some(RecordHeader::CURRENT));
+        
+        // A newer version is recognised as such and left alone
+        let mut newer = sealed.to_vec();
+        newer[2] = RECORD_VERSION + 1;
+        storage.tree.insert("ct:alice", newer).unwrap();
+        storage.clear_cache();
+        let error = storage.get_contact("alice").unwrap_err();
+        assert_eq!(error.downcast_ref::<UnsupportedRecordFormat>().map(|f| f.version), Some(RECORD_VERSION + 1));
+        let report = storage.verify_integrity().unwrap();
+        assert_eq!(report.problems[0].problem, Problem::UnsupportedFormat { version: RECORD_VERSION + 1 });
+        assert_eq!(report.corrupt_keys().count(), 0);
+    }
+    
+    #[test]
+    fn test_audit_log_tampering_detected() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecureStorage::create(temp_dir.path().join("db"), "password").unwrap();
+        
+        for _ in 0..3 {
+            storage.append_security_event("a", SecurityEventKind::SessionReset).unwrap();
+        }
+        storage.append_security_event("a/b", SecurityEventKind::SessionReset).unwrap();
+        assert_eq!(storage.get_security_events("a").unwrap().len(), 3);
+        assert_eq!(storage.get_security_events("a/b").unwrap().len(), 1);
+        assert!(storage.get_security_events("nobody").unwrap().is_empty());
+        
+        // Removing an entry, or replaying one in another's place, breaks the chain
+        let first = storage.tree.get(format!("{}a/{:016x}", PREFIX_AUDIT, 0)).unwrap().unwrap();
+        storage.tree.insert(format!("{}a/{:016x}", PREFIX_AUDIT, 1), first).unwrap();
+        assert!(storage.get_security_events("a").is_err());
+        storage.tree.remove(format!("{}a/{:016x}", PREFIX_AUDIT, 1)).unwrap();
+        assert!(storage.get_security_events("a").is_err());
+    }
+    
+    fn duress(action: DuressAction) -> DuressPassword {
+        DuressPassword { password: "duress".to_string(), action, display_name: "Decoy".to_string() }
+    }
+    
+    /// Record sizes per tree, with tree names and record values left out
+    fn layout(db: &Db) -> Vec<Vec<(Vec<u8>, usize)>> {
+        let mut trees: Vec<_> = db.tree_names().into_iter()
+            .map(|name| {
+                let tree = db.open_tree(name).unwrap();
+                tree.iter().map(|item| {
+                    let (key, value) = item.unwrap();
+                    (key.to_vec(), value.len())
+                }).collect::<Vec<_>>()
+            })
+            .collect();
+        trees.sort();
+        trees
+    }
+    
+    #[test]
+    fn test_duress_password_opens_decoy() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("db");
+        let plain_path = temp_dir.path().join("plain");
+        
+        {
+            let storage = SecureStorage::create_with_duress(&path, "password", Some(&duress(DuressAction::OpenDecoy))).unwrap();
+            storage.store_contact(&Contact::new("c1".to_string(), "Alice".to_string(), [1u8; 32])).unwrap();
+            assert!(storage.fresh_profile_name().is_none());
+            
+            // Same layout as an account without a duress password
+            let plain = SecureStorage::create(&plain_path, "password").unwrap();
+            plain.store_contact(&Contact::new("c1".to_string(), "Alice".to_string(), [1u8; 32])).unwrap();
+            assert_eq!(layout(&storage.db), layout(&plain.db));
+        }
+        
+        {
+            let decoy = SecureStorage::unlock(&path, "duress").unwrap();
+            assert_eq!(decoy.fresh_profile_name(), Some("Decoy"));
+            assert!(decoy.get_all_contacts().unwrap().is_empty());
+            decoy.store_contact(&Contact::new("c2".to_string(), "Bob".to_string(), [2u8; 32])).unwrap();
+            assert!(decoy.master_key_store().unwrap().unlock("duress").is_ok());
+        }
+        
+        let storage = SecureStorage::unlock(&path, "password").unwrap();
+        let contacts = storage.get_all_contacts().unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].id, "c1");
+        assert!(SecureStorage::create_with_duress(temp_dir.path().join("x"), "same", Some(&DuressPassword {
+            password: "same".to_string(),
+            ..duress(DuressAction::OpenDecoy)
+        })).is_err());
+    }
+    
+    #[test]
+    fn test_duress_password_wipes_account() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("db");
+        
+        {
+            let storage = SecureStorage::create_with_duress(&path, "password", Some(&duress(DuressAction::WipeData))).unwrap();
+            storage.store_contact(&Contact::new("c1".to_string(), "Alice".to_string(), [1u8; 32])).unwrap();
+        }
+        
+        {
+            let decoy = SecureStorage::unlock(&path, "duress").unwrap();
+            assert_eq!(decoy.fresh_profile_name(), Some("Decoy"));
+            assert_eq!(decoy.db.scan_prefix(PREFIX_CONTACT).count(), 0);
+            let slots = decode_key_slots(&decoy.db.get(PREFIX_MASTER_KEY).unwrap().unwrap()).unwrap();
+            assert_eq!(slots.len(), 2);
+        }
+        
+        assert!(SecureStorage::unlock(&path, "password").is_err());
+        assert!(SecureStorage::unlock(&path, "duress").is_ok());
+    }
+    
+    #[test]
+    fn test_shared_attachment_outlives_first_message() {
+        use crate::protocol::MessageContent;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecureStorage::create(temp_dir.path().join("db"), "password").unwrap();
+        
+        let attachment = storage.store_blob(b"file contents").unwrap();
+        assert_eq!(storage.store_blob(b"file contents").unwrap().digest, attachment.digest);
+        
+        let message = |conversation_id: &str, id: &str| LocalMessage {
+            id: id.to_string(),
+            conversation_id: conversation_id.to_string(),
+            sender_id: "self".to_string(),
+            is_outgoing: true,
+            content: MessageContent::File {
+                attachment: attachment.clone(),
+                filename: "notes.txt".to_string(),
+                mime_type: "text/plain".to_string(),
+            },
+            timestamp: time::OffsetDateTime::now_utc(),
+            status: DeliveryStatus::Queued,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport: 0,
+        };
+        storage.store_message(&message("c1", "m1")).unwrap();
+        storage.store_message(&message("c2", "m2")).unwrap();
+        assert_eq!(storage.blob_ref_count(&attachment).unwrap(), 2);
+        assert_eq!(storage.db.scan_prefix(PREFIX_BLOB).count(), 1);
+        
+        storage.delete_message("c1", "m1").unwrap();
+        assert_eq!(storage.get_blob(&attachment).unwrap().unwrap(), b"file contents");
+        
+        storage.delete_message("c2", "m2").unwrap();
+        assert!(storage.get_blob(&attachment).unwrap().is_none());
+    }
+    
+    #[test]
+    fn test_usage_report_and_compaction() {
+        let storage = SecureStorage::create_temporary("password").unwrap();
+        let message = |conversation_id: &str, id: &str, content| LocalMessage {
+            id: id.to_string(),
+            conversation_id: conversation_id.to_string(),
+            sender_id: "self".to_string(),
+            is_outgoing: true,
+            content,
+            timestamp: time::OffsetDateTime::now_utc(),
+            status: DeliveryStatus::Sent,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport: 0,
+        };
+        let kept = storage.store_blob(&[1u8; 3000]).unwrap();
+        storage.store_message(&message("big", "m1", MessageContent::File {
+            attachment: kept.clone(),
+            filename: "a.bin".to_string(),
+            mime_type: "application/octet-stream".to_string(),
+        })).unwrap();
+        for i in 0..3 {
+            storage.store_message(&message("small", &format!("s{}", i), MessageContent::Text { text: "hi".to_string() })).unwrap();
+        }
+        storage.store_blob(b"nobody refers to this").unwrap();
+        storage.tree.insert(format!("{}staging-0/00000000", PREFIX_BLOB), storage.encrypt(format!("{}staging-0/00000000", PREFIX_BLOB).as_bytes(), b"interrupted").unwrap()).unwrap();
+        
+        let report = storage.usage_report().unwrap();
+        assert_eq!(report.conversations.iter().map(|c| c.conversation_id.as_str()).collect::<Vec<_>>(), ["small", "big"]);
+        assert_eq!(report.conversations[0].messages.records, 3);
+        assert_eq!(report.attachments.records, 3);
+        assert_eq!(report.index.records, 23);
+        assert!(report.other.records > 0);
+        
+        let mut progress = Vec::new();
+        let compacted = storage.compact(|p| progress.push(p)).unwrap();
+        assert_eq!(compacted.orphaned_blobs, 2);
+        assert_eq!(progress.last().map(|p| p.records_done), Some(storage.tree.len()));
+        assert_eq!(storage.get_blob(&kept).unwrap().unwrap(), [1u8; 3000]);
+        
+        let after = storage.usage_report().unwrap();
+        assert_eq!(after.attachments.records, 1);
+        assert!(after.attachments.bytes + compacted.orphaned_bytes < report.attachments.bytes);
+        assert_eq!((after.index, after.conversations), (report.index, report.conversations));
+    }
+    
+    #[test]
+    fn test_integrity_check_quarantines_unreadable_records() {
+        let storage = SecureStorage::create_temporary("password").unwrap();
+        let contact = Contact {
+            id: "alice".to_string(),
+            display_name: "Alice".to_string(),
+            public_key: [1; 32],
+            added_at: time::OffsetDateTime::now_utc(),
+            last_seen: None,
+            verified: false,
+            blocked: false,
+            privacy: None,
+        };
+        storage.store_contact(&contact).unwrap();
+        let conversation = Conversation::new(contact.id.clone());
+        storage.store_conversation(&conversation).unwrap();
+        let message = |conversation_id: &str, id: &str| LocalMessage {
+            id: id.to_string(),
+            conversation_id: conversation_id.to_string(),
+            sender_id: "self".to_string(),
+            is_outgoing: true,
+            content: MessageContent::Text { text: id.to_string() },
+            timestamp: time::OffsetDateTime::now_utc(),
+            status: DeliveryStatus::Sent,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport: 0,
+        };
+        for id in ["m1", "m2"] {
+            storage.store_message(&message(&conversation.id, id)).unwrap();
+        }
+        assert!(storage.verify_integrity().unwrap().is_clean());
+        
+        // A damaged message, a record of the wrong kind and a stray message
+        let damaged = format!("{}{}", PREFIX_MESSAGE, message_path_of(&conversation.id, "m1"));
+        let mut value = storage.tree.get(&damaged).unwrap().unwrap().to_vec();
+        *value.last_mut().unwrap() ^= 1;
+        storage.tree.insert(&damaged, value).unwrap();
+        storage.tree.insert(format!("{}bob", PREFIX_CONTACT_SETTINGS), storage.encrypt(format!("{}bob", PREFIX_CONTACT_SETTINGS).as_bytes(), b"junk").unwrap()).unwrap();
+        storage.store_message(&message("gone", "m3")).unwrap();
+        assert!(storage.get_messages(&conversation.id, 10).is_err());
+        
+        let report = storage.verify_integrity().unwrap();
+        let problems: Vec<_> = report.problems.iter().map(|p| (p.key.as_str(), &p.problem)).collect();
+        assert!(matches!(&problems[..], [
+            (first, Problem::Malformed { .. }),
+            (second, Problem::Undecryptable),
+            (third, Problem::Dangling { missing }),
+        ] if *first == "cs:bob" && *second == damaged && third.ends_with("m3") && missing == "conversation gone"));
+        
+        assert_eq!(storage.quarantine_records(report.corrupt_keys()).unwrap(), 2);
+        assert_eq!(storage.get_messages(&conversation.id, 10).unwrap().len(), 1);
+        let again = storage.verify_integrity().unwrap();
+        assert_eq!((again.quarantined, again.problems.len()), (2, 1));
+        assert_eq!(storage.export_records(&[0; 32]).filter(|r| r.is_err()).count(), 0);
+    }
+    
+    /// Copy the database files as they are on disk, all a crash would
+    /// leave behind, and open the copy
+    fn open_crashed_copy(from: &Path, to: &Path) -> SecureStorage {
+        fn copy(from: &Path, to: &Path) {
+            std::fs::create_dir_all(to).unwrap();
+            for entry in std::fs::read_dir(from).unwrap() {
+                let entry = entry.unwrap();
+                if entry.file_type().unwrap().is_dir() {
+                    copy(&entry.path(), &to.join(entry.file_name()));
+                } else {
+                    std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+                }
             }
-            Ok(None) => Ok(None),
-            Err(e) => Err(e.into()),
         }
+        copy(from, to);
+        SecureStorage::unlock(to, "password").unwrap()
     }
     
-    // ===== Device Operations =====
+    #[test]
+    fn test_durability_survives_abrupt_termination() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("db");
+        let storage = SecureStorage::create(&path, "password").unwrap();
+        storage.set_durability(Durability { flush_every_ms: None, flush_on_message: true, fsync: FsyncPolicy::Always }).unwrap();
+        storage.flush().unwrap();
+        let contact = |id: &str| Contact::new(id.to_string(), id.to_string(), [1; 32]);
+        
+        // On disk as soon as the write returns
+        storage.store_contact(&contact("alice")).unwrap();
+        storage.flush_message_writes().unwrap();
+        assert!(open_crashed_copy(&path, &dir.path().join("first")).get_contact("alice").unwrap().is_some());
+        
+        // Handed to the flusher, woken by the write or on its interval
+        storage.set_durability(Durability { flush_every_ms: None, flush_on_message: true, fsync: FsyncPolicy::Background }).unwrap();
+        storage.store_contact(&contact("bob")).unwrap();
+        storage.flush_message_writes().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert!(open_crashed_copy(&path, &dir.path().join("second")).get_contact("bob").unwrap().is_some());
+        
+        storage.set_durability(Durability { flush_every_ms: Some(10), flush_on_message: false, fsync: FsyncPolicy::Always }).unwrap();
+        storage.store_contact(&contact("carol")).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert!(open_crashed_copy(&path, &dir.path().join("third")).get_contact("carol").unwrap().is_some());
+        
+        assert!(storage.set_durability(Durability { flush_every_ms: Some(0), ..Default::default() }).is_err());
+    }
     
-    pub fn store_device(&self, device: &DeviceInfo) -> Result<()> {
-        self.put(&format!("{}{}", PREFIX_DEVICE, device.device_id), device)
+    #[test]
+    fn test_cancelled_blob_is_removed() {
+        // Cancels once a few chunks are in
+        struct Cancelling<'a> {
+            cancel: &'a CancellationToken,
+            reads: usize,
+        }
+        impl std::io::Read for Cancelling<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.reads += 1;
+                if self.reads == 3 {
+                    self.cancel.cancel();
+                }
+                buf.fill(7);
+                Ok(buf.len())
+            }
+        }
+        
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecureStorage::create(temp_dir.path().join("db"), "password").unwrap();
+        let cancel = CancellationToken::new();
+        let error = storage.store_blob_from(Cancelling { cancel: &cancel, reads: 0 }, &cancel).unwrap_err();
+        assert!(crate::cancel::is_cancelled(&error));
+        assert_eq!(storage.db.scan_prefix(PREFIX_BLOB.as_bytes()).count(), 0);
     }
     
-    pub fn get_device(&self, device_id: &str) -> Result<Option<DeviceInfo>> {
-        self.get(&format!("{}{}", PREFIX_DEVICE, device_id))
+    #[test]
+    fn test_chunked_attachment_transfer() {
+        use crate::protocol::ProtocolMessage;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let sender = SecureStorage::create(temp_dir.path().join("a"), "password").unwrap();
+        let receiver = SecureStorage::create(temp_dir.path().join("b"), "password").unwrap();
+        
+        let data: Vec<u8> = (0..ATTACHMENT_CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        let mut writer = sender.blob_writer();
+        for piece in data.chunks(10_000) {
+            writer.write(piece).unwrap();
+        }
+        let attachment = writer.finish().unwrap();
+        assert_eq!(attachment.chunk_count(), 3);
+        assert_eq!(sender.get_blob(&attachment).unwrap().unwrap(), data);
+        assert_eq!(sender.db.scan_prefix(format!("{}staging-", PREFIX_BLOB)).count(), 0);
+        
+        let wire: Vec<_> = (0..3)
+            .map(|i| {
+                let chunk = sender.get_blob_chunk(&attachment, i).unwrap().unwrap();
+                match attachment.seal_chunk(i, &chunk).unwrap() {
+                    ProtocolMessage::AttachmentChunk { ciphertext, .. } => ciphertext,
+                    _ => unreachable!(),
+                }
+            })
+            .collect();
+        
+        // Chunks can't be swapped
+        assert!(attachment.open_chunk(1, &wire[0]).is_err());
+        
+        // The first chunk is readable before the rest arrives
+        let first = attachment.open_chunk(0, &wire[0]).unwrap();
+        receiver.store_blob_chunk(&attachment, 0, &first).unwrap();
+        assert_eq!(receiver.get_blob_chunk(&attachment, 0).unwrap().unwrap(), &data[..ATTACHMENT_CHUNK_SIZE]);
+        assert!(receiver.get_blob(&attachment).unwrap().is_none());
+        
+        for i in [2, 1] {
+            let chunk = attachment.open_chunk(i, &wire[i as usize]).unwrap();
+            receiver.store_blob_chunk(&attachment, i, &chunk).unwrap();
+        }
+        assert_eq!(receiver.get_blob(&attachment).unwrap().unwrap(), data);
     }
     
-    pub fn get_all_devices(&self) -> Result<Vec<DeviceInfo>> {
-        let mut devices = Vec::new();
-        for item in self.db.scan_prefix(PREFIX_DEVICE.as_bytes()) {
-            let (_, value) = item.context("Failed to read device")?;
-            let decrypted = self.decrypt(&value)?;
-            let device: DeviceInfo = bincode::deserialize(&decrypted)
-                .context("Failed to deserialize device")?;
-            devices.push(device);
+    #[test]
+    fn test_starred_index() {
+        use crate::protocol::MessageContent;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecureStorage::create(temp_dir.path().join("db"), "password").unwrap();
+        
+        for (conversation_id, id) in [("c1", "m1"), ("c1", "m2"), ("c2", "m3")] {
+            storage.store_message(&LocalMessage {
+                id: id.to_string(),
+                conversation_id: conversation_id.to_string(),
+                sender_id: "self".to_string(),
+                is_outgoing: true,
+                content: MessageContent::Text { text: id.to_string() },
+                timestamp: time::OffsetDateTime::now_utc(),
+                status: DeliveryStatus::Queued,
+                reply_to: None,
+                forwarded_from: None,
+                starred: false,
+                lamport: 0,
+            }).unwrap();
         }
-        Ok(devices)
+        
+        storage.set_message_starred("c1", "m1", true).unwrap();
+        storage.set_message_starred("c2", "m3", true).unwrap();
+        let starred: Vec<_> = storage.get_starred_messages().unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(starred, ["m3", "m1"]);
+        assert!(storage.get_message("c1", "m1").unwrap().unwrap().starred);
+        
+        storage.set_message_starred("c1", "m1", false).unwrap();
+        storage.delete_message("c2", "m3").unwrap();
+        assert!(storage.get_starred_messages().unwrap().is_empty());
+        assert_eq!(storage.db.scan_prefix(PREFIX_STARRED).count(), 0);
     }
     
-    /// Flush all changes to disk
-    pub fn flush(&self) -> Result<()> {
-        self.db.flush()
-            .context("Failed to flush database")?;
-        Ok(())
+    #[test]
+    fn test_message_order_index() {
+        use crate::protocol::MessageContent;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecureStorage::create(temp_dir.path().join("db"), "password").unwrap();
+        let epoch = time::OffsetDateTime::UNIX_EPOCH;
+        // Ids and times out of order; concurrent messages share a clock
+        let mut messages: Vec<_> = [("z", 1, -5), ("b/1", 2, 3), ("a", 2, 7), ("y", 3, -9)].into_iter().map(|(id, lamport, secs)| LocalMessage {
+            id: id.to_string(),
+            conversation_id: "c/1".to_string(),
+            sender_id: "self".to_string(),
+            is_outgoing: true,
+            content: MessageContent::Text { text: id.to_string() },
+            timestamp: epoch + time::Duration::seconds(secs),
+            status: DeliveryStatus::Sent,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport,
+        }).collect();
+        for message in messages.iter().rev() {
+            storage.store_message(message).unwrap();
+        }
+        messages.sort_by(LocalMessage::causal_cmp);
+        let ids = |messages: Vec<LocalMessage>| -> Vec<String> { messages.into_iter().map(|m| m.id).collect() };
+        let expected = ids(messages.clone());
+        
+        assert_eq!(expected, ["z", "b/1", "a", "y"]);
+        assert_eq!(ids(storage.messages("c/1").collect::<Result<_>>().unwrap()), expected);
+        assert_eq!(ids(storage.get_messages("c/1", 2).unwrap()), ["a", "y"]);
+        assert_eq!(ids(storage.get_messages_before("c/1", "a", 10).unwrap()), ["z", "b/1"]);
+        assert!(storage.messages("c").next().is_none());
+        
+        // Moved by a later clock, and read lazily: a message deleted while
+        // iterating isn't returned
+        messages[0].lamport = 9;
+        storage.store_message(&messages[0]).unwrap();
+        let mut newest_first = storage.messages("c/1").rev();
+        assert_eq!(newest_first.next().unwrap().unwrap().id, "z");
+        storage.delete_message("c/1", "b/1").unwrap();
+        assert_eq!(ids(newest_first.collect::<Result<_>>().unwrap()), ["y", "a"]);
+        assert_eq!(storage.postings(storage.index_list(PREFIX_MESSAGE_ORDER, "c/1"), None, None).count(), 3);
+        
+        // Index keys hold a token and a block number; what the entries
+        // sort by and the messages they point to are sealed
+        for prefix in [PREFIX_MESSAGE_ORDER, PREFIX_ACTIVITY, PREFIX_SEARCH, PREFIX_DAY_COUNT] {
+            for key in storage.tree.scan_prefix(prefix).keys() {
+                let key = key.unwrap();
+                let rest = std::str::from_utf8(&key[prefix.len()..]).unwrap();
+                let (token, block) = rest.split_once('/').unwrap_or((rest, "0000000000000000"));
+                assert!(token.len() == 32 && block.len() == 16, "{}", rest);
+                assert!(token.chars().chain(block.chars()).all(|c| c.is_ascii_hexdigit()), "{}", rest);
+            }
+        }
     }
     
-    /// Close the database
-    pub fn close(self) -> Result<()> {
-        self.db.flush()
-            .context("Failed to close database")?;
-        Ok(())
+    #[test]
+    fn test_store_messages_batch() {
+        use crate::protocol::MessageContent;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecureStorage::create(temp_dir.path().join("db"), "password").unwrap();
+        let now = time::OffsetDateTime::now_utc();
+        let mut conversation = Conversation {
+            id: "c1".to_string(),
+            contact_id: "alice".to_string(),
+            created_at: now,
+            updated_at: now,
+            last_message_preview: None,
+            unread_count: 0,
+            archived: false,
+            pinned: false,
+            ratchet_state: None,
+            lamport: 3,
+        };
+        let attachment = storage.store_blob(b"picture").unwrap();
+        let messages: Vec<_> = (0..4u64).map(|i| LocalMessage {
+            id: format!("m{}", i),
+            conversation_id: "c1".to_string(),
+            sender_id: "alice".to_string(),
+            is_outgoing: false,
+            content: match i {
+                2 => MessageContent::File { attachment: attachment.clone(), filename: "a.png".to_string(), mime_type: "image/png".to_string() },
+                _ => MessageContent::Text { text: i.to_string() },
+            },
+            timestamp: now,
+            status: DeliveryStatus::Delivered,
+            reply_to: None,
+            forwarded_from: None,
+            starred: i == 1,
+            lamport: i * 2,
+        }).collect();
+        storage.store_message(&messages[0]).unwrap();
+        
+        // One from another conversation fails the whole batch
+        let mut stray = messages[3].clone();
+        stray.conversation_id = "c2".to_string();
+        assert!(storage.store_messages_batch(&mut conversation, &[messages[1].clone(), stray]).is_err());
+        assert!(storage.get_message("c1", "m1").unwrap().is_none());
+        
+        let mut batch = messages.clone();
+        batch.push(messages[3].clone());
+        assert_eq!(storage.store_messages_batch(&mut conversation, &batch).unwrap(), 3);
+        assert_eq!(storage.store_messages_batch(&mut conversation, &batch).unwrap(), 0);
+        assert_eq!(conversation.lamport, 6);
+        assert_eq!(storage.get_conversation_by_contact("alice").unwrap().unwrap().lamport, 6);
+        let ids: Vec<_> = storage.get_messages("c1", 10).unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, ["m0", "m1", "m2", "m3"]);
+        assert_eq!(storage.get_starred_messages().unwrap()[0].id, "m1");
+        assert_eq!(storage.get_messages_by_status(&[DeliveryStatus::Delivered]).unwrap().len(), 4);
+        assert_eq!(storage.blob_ref_count(&attachment).unwrap(), 1);
+    }
+    
+    #[test]
+    fn test_decryption_cache() {
+        use crate::protocol::MessageContent;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecureStorage::create(temp_dir.path().join("db"), "password").unwrap();
+        let mut message = LocalMessage {
+            id: "m1".to_string(),
+            conversation_id: "c1".to_string(),
+            sender_id: "self".to_string(),
+            is_outgoing: true,
+            content: MessageContent::Text { text: "hi".to_string() },
+            timestamp: time::OffsetDateTime::now_utc(),
+            status: DeliveryStatus::Sent,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport: 0,
+        };
+        storage.store_message(&message).unwrap();
+        
+        storage.get_messages("c1", 10).unwrap();
+        storage.get_messages("c1", 10).unwrap();
+        assert_eq!((storage.cache_stats().hits, storage.cache_stats().misses), (1, 1));
+        
+        // Writes are seen at once, by every handle
+        let other = storage.clone();
+        message.status = DeliveryStatus::Read;
+        storage.store_message(&message).unwrap();
+        assert_eq!(other.get_message("c1", "m1").unwrap().unwrap().status, DeliveryStatus::Read);
+        storage.delete_message("c1", "m1").unwrap();
+        assert!(other.get_messages("c1", 10).unwrap().is_empty());
+        
+        // Only some records are cached
+        let lookups = |stats: CacheStats| stats.hits + stats.misses;
+        let before = lookups(storage.cache_stats());
+        storage.get_identity().unwrap();
+        assert_eq!(lookups(storage.cache_stats()), before);
+        
+        storage.store_message(&message).unwrap();
+        storage.get_messages("c1", 10).unwrap();
+        assert_eq!(storage.cache_stats().entries, 1);
+        other.clear_cache();
+        assert_eq!(storage.cache_stats().entries, 0);
+    }
+    
+    #[test]
+    fn test_conversation_and_status_indexes() {
+        use crate::protocol::MessageContent;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecureStorage::create(temp_dir.path().join("db"), "password").unwrap();
+        let now = time::OffsetDateTime::now_utc();
+        storage.store_conversation(&Conversation {
+            id: "c/1".to_string(),
+            contact_id: "alice".to_string(),
+            created_at: now,
+            updated_at: now,
+            last_message_preview: None,
+            unread_count: 0,
+            archived: false,
+            pinned: false,
+            ratchet_state: None,
+            lamport: 0,
+        }).unwrap();
+        let mut messages: Vec<_> = (0..4i64).map(|i| LocalMessage {
+            id: format!("m{}", i),
+            conversation_id: "c/1".to_string(),
+            sender_id: "self".to_string(),
+            is_outgoing: true,
+            content: MessageContent::Text { text: i.to_string() },
+            timestamp: now + time::Duration::seconds(i),
+            status: DeliveryStatus::Sent,
+            reply_to: None,
+            forwarded_from: None,
+            starred: false,
+            lamport: i as u64,
+        }).collect();
+        messages[1].status = DeliveryStatus::Queued;
+        for message in &messages {
+            storage.store_message(message).unwrap();
+        }
+        
+        let ids = |statuses: &[DeliveryStatus]| -> Vec<_> {
+            storage.get_messages_by_status(statuses).unwrap().into_iter().map(|m| m.id).collect()
+        };
+        assert_eq!(storage.get_conversation_by_contact("alice").unwrap().unwrap().id, "c/1");
+        assert!(storage.get_conversation_by_contact("bob").unwrap().is_none());
+        assert_eq!(ids(&[DeliveryStatus::Sent]), ["m0", "m2", "m3"]);
+        
+        messages[0].status = DeliveryStatus::Delivered;
+        messages[2].status = DeliveryStatus::Failed { reason: "offline".to_string() };
+        storage.store_message(&messages[0]).unwrap();
+        storage.store_message(&messages[2]).unwrap();
+        storage.delete_message("c/1", "m3").unwrap();
+        let undelivered: Vec<_> = storage.get_undelivered_messages().unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(undelivered, ["m1", "m2"]);
+        assert_eq!(ids(&[DeliveryStatus::Delivered, DeliveryStatus::Read]), ["m0"]);
+        assert_eq!(storage.tree.scan_prefix(PREFIX_MESSAGE_STATUS).count(), 3);
+        
+        // Lost index entries come back when the indexes are rebuilt, as on
+        // unlocking a database from before them
+        for key in storage.tree.scan_prefix(PREFIX_CONVERSATION_BY_CONTACT).keys() {
+            storage.tree.remove(key.unwrap()).unwrap();
+        }
+        storage.tree.remove(META_INDEX_SCHEMA).unwrap();
+        storage.tree.insert(format!("{}sent/c/1/m9", PREFIX_MESSAGE_STATUS), storage.encrypt(format!("{}sent/c/1/m9", PREFIX_MESSAGE_STATUS).as_bytes(), &[]).unwrap()).unwrap();
+        assert!(storage.get_conversation_by_contact("alice").unwrap().is_none());
+        storage.migrate_indexes().unwrap();
+        assert_eq!(storage.get_conversation_by_contact("alice").unwrap().unwrap().id, "c/1");
+        assert_eq!(storage.tree.scan_prefix(PREFIX_MESSAGE_STATUS).count(), 3);
+        assert_eq!(ids(&[DeliveryStatus::Queued]), ["m1"]);
+    }
+    
+    #[derive(Debug, Clone)]
+    enum MessageOp {
+        Store { conversation: u8, id: u8, starred: bool, lamport: u8 },
+        Star { conversation: u8, id: u8, starred: bool },
+        Delete { conversation: u8, id: u8 },
+    }
+    
+    fn arb_message_op() -> impl Strategy<Value = MessageOp> {
+        prop_oneof![
+            (0..2u8, 0..6u8, any::<bool>(), any::<u8>())
+                .prop_map(|(conversation, id, starred, lamport)| MessageOp::Store { conversation, id, starred, lamport }),
+            (0..2u8, 0..6u8, any::<bool>()).prop_map(|(conversation, id, starred)| MessageOp::Star { conversation, id, starred }),
+            (0..2u8, 0..6u8).prop_map(|(conversation, id)| MessageOp::Delete { conversation, id }),
+        ]
+    }
+    
+    proptest! {
+        /// Messages and the starred index agree with a plain map after any
+        /// sequence of writes, and every record stays sealed
+        #[test]
+        fn prop_message_operations_match_model(ops in prop::collection::vec(arb_message_op(), 1..40)) {
+            use crate::protocol::MessageContent;
+            use std::collections::BTreeMap;
+            
+            let storage = SecureStorage::open_temporary(&[7u8; 32]).unwrap();
+            let mut model: BTreeMap<(String, String), (bool, u64)> = BTreeMap::new();
+            let names = |conversation: u8, id: u8| (format!("c{}", conversation), format!("m{}", id));
+            
+            for op in ops {
+                match op {
+                    MessageOp::Store { conversation, id, starred, lamport } => {
+                        let (conversation_id, id) = names(conversation, id);
+                        storage.store_message(&LocalMessage {
+                            id: id.clone(),
+                            conversation_id: conversation_id.clone(),
+                            sender_id: "self".to_string(),
+                            is_outgoing: true,
+                            content: MessageContent::Text { text: id.clone() },
+                            timestamp: time::OffsetDateTime::UNIX_EPOCH,
+                            status: DeliveryStatus::Queued,
+                            reply_to: None,
+                            forwarded_from: None,
+                            starred,
+                            lamport: lamport.into(),
+                        }).unwrap();
+                        model.insert((conversation_id, id), (starred, lamport.into()));
+                    }
+                    MessageOp::Star { conversation, id, starred } => {
+                        let key = names(conversation, id);
+                        let result = storage.set_message_starred(&key.0, &key.1, starred);
+                        match model.get_mut(&key) {
+                            Some(entry) => {
+                                prop_assert!(result.is_ok());
+                                entry.0 = starred;
+                            }
+                            None => prop_assert!(result.is_err()),
+                        }
+                    }
+                    MessageOp::Delete { conversation, id } => {
+                        let key = names(conversation, id);
+                        storage.delete_message(&key.0, &key.1).unwrap();
+                        model.remove(&key);
+                    }
+                }
+            }
+            
+            for conversation in 0..2 {
+                let conversation_id = format!("c{}", conversation);
+                let messages = storage.get_messages(&conversation_id, usize::MAX).unwrap();
+                let expected: Vec<_> = model.keys().filter(|(c, _)| *c == conversation_id).collect();
+                prop_assert_eq!(messages.len(), expected.len());
+                prop_assert!(messages.windows(2).all(|pair| pair[0].lamport <= pair[1].lamport));
+                for message in &messages {
+                    let key = (conversation_id.clone(), message.id.clone());
+                    prop_assert_eq!(model.get(&key), Some(&(message.starred, message.lamport)));
+                }
+            }
+            
+            let mut starred: Vec<_> = storage.get_starred_messages().unwrap().into_iter()
+                .map(|m| (m.conversation_id, m.id))
+                .collect();
+            starred.sort();
+            let expected: Vec<_> = model.iter().filter(|(_, (starred, _))| *starred).map(|(key, _)| key.clone()).collect();
+            prop_assert_eq!(starred, expected.clone());
+            prop_assert_eq!(storage.tree.scan_prefix(PREFIX_STARRED).count(), expected.len());
+            
+            for item in storage.tree.scan_prefix(PREFIX_MESSAGE).chain(storage.tree.scan_prefix(PREFIX_STARRED)) {
+                let (key, value) = item.unwrap();
+                prop_assert!(storage.decrypt_record(&key, &value).is_ok());
+            }
+        }
     }
 }
-
-use rand::RngCore;
@@ -0,0 +1,302 @@
+//! Streaming authenticated encryption, for payloads too large to hold in
+//! memory.
+//!
+//! A stream is sealed chunk by chunk with the STREAM construction over
+//! XChaCha20-Poly1305: chunk `i` is sealed with nonce
+//! `nonce_prefix[19] | i as u32 (big endian) | last_flag u8` and the same
+//! associated data. The counter and final flag make reordering, dropping
+//! or truncating chunks detectable, and the associated data binds every
+//! chunk to whatever the caller binds the stream to. A stream ends with
+//! exactly one chunk whose flag is 1.
+//!
+//! `StreamSealer` and `StreamOpener` work on one chunk at a time, for
+//! callers with their own framing such as backups. `EncryptWriter` and
+//! `DecryptReader` frame each chunk as `len u32 (big endian) | ciphertext`
+//! and hold at most one chunk in memory, whatever the stream's size.
+
+use std::io::{self, Read, Write};
+
+use anyhow::{Result, Context};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+
+pub const NONCE_PREFIX_LEN: usize = 19;
+pub const TAG_LEN: usize = 16;
+/// Plaintext size of a chunk written by `EncryptWriter`
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A fresh nonce prefix; one per stream sealed with the same key
+pub fn random_nonce_prefix() -> [u8; NONCE_PREFIX_LEN] {
+    let mut prefix = [0u8; NONCE_PREFIX_LEN];
+    rand::thread_rng().fill_bytes(&mut prefix);
+    prefix
+}
+
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LEN], counter: u32, last: bool) -> [u8; 24] {
+    let mut nonce = [0u8; 24];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..23].copy_from_slice(&counter.to_be_bytes());
+    nonce[23] = last as u8;
+    nonce
+}
+
+/// Seals the chunks of one stream in order
+pub struct StreamSealer {
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    aad: Vec<u8>,
+    counter: u32,
+    finished: bool,
+}
+
+impl StreamSealer {
+    pub fn new(key: &[u8; 32], nonce_prefix: [u8; NONCE_PREFIX_LEN], aad: &[u8]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+            nonce_prefix,
+            aad: aad.to_vec(),
+            counter: 0,
+            finished: false,
+        }
+    }
+
+    /// Seal the next chunk; `last` ends the stream
+    pub fn seal(&mut self, plaintext: &[u8], last: bool) -> Result<Vec<u8>> {
+        if self.finished {
+            return Err(anyhow::anyhow!("Stream already ended"));
+        }
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        let sealed = self.cipher
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad: &self.aad })
+            .map_err(|e| anyhow::anyhow!("Stream chunk encryption failed: {:?}", e))?;
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| anyhow::anyhow!("Stream too long"))?;
+        self.finished = last;
+        Ok(sealed)
+    }
+}
+
+/// Opens the chunks of one stream in order
+pub struct StreamOpener {
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    aad: Vec<u8>,
+    counter: u32,
+    finished: bool,
+}
+
+impl StreamOpener {
+    pub fn new(key: &[u8; 32], nonce_prefix: [u8; NONCE_PREFIX_LEN], aad: &[u8]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+            nonce_prefix,
+            aad: aad.to_vec(),
+            counter: 0,
+            finished: false,
+        }
+    }
+
+    /// Open the next chunk, returning its plaintext and whether it was
+    /// the last
+    pub fn open(&mut self, sealed: &[u8]) -> Result<(Vec<u8>, bool)> {
+        if self.finished {
+            return Err(anyhow::anyhow!("Data after the final chunk"));
+        }
+        // Try as an intermediate chunk first, then as the final one
+        for last in [false, true] {
+            let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+            if let Ok(plaintext) = self.cipher.decrypt(XNonce::from_slice(&nonce), Payload { msg: sealed, aad: &self.aad }) {
+                self.counter = self.counter.checked_add(1)
+                    .ok_or_else(|| anyhow::anyhow!("Stream too long"))?;
+                self.finished = last;
+                return Ok((plaintext, last));
+            }
+        }
+        Err(anyhow::anyhow!("Chunk {} failed authentication", self.counter))
+    }
+
+    /// Open chunk `counter` on its own, for callers that seek. Their
+    /// framing must tell them whether it is the last.
+    pub fn open_at(&self, counter: u32, sealed: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = chunk_nonce(&self.nonce_prefix, counter, last);
+        self.cipher.decrypt(XNonce::from_slice(&nonce), Payload { msg: sealed, aad: &self.aad })
+            .map_err(|_| anyhow::anyhow!("Chunk {} failed authentication", counter))
+    }
+
+    /// Number of the next chunk
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+/// Encrypts everything written to it into `writer` as a framed stream.
+/// `finish` must be called to end it; a stream dropped without is
+/// detected as truncated when read.
+pub struct EncryptWriter<W: Write> {
+    writer: W,
+    sealer: StreamSealer,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(writer: W, key: &[u8; 32], nonce_prefix: [u8; NONCE_PREFIX_LEN], aad: &[u8]) -> Self {
+        Self {
+            writer,
+            sealer: StreamSealer::new(key, nonce_prefix, aad),
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    /// Seal the final chunk and return the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.write_chunk(true)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_chunk(&mut self, last: bool) -> Result<()> {
+        let sealed = self.sealer.seal(&self.buffer, last)?;
+        self.writer.write_all(&(sealed.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&sealed)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let take = data.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..take]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.write_chunk(false).map_err(io::Error::other)?;
+        }
+        Ok(take)
+    }
+
+    /// Flushes the underlying writer; a partial chunk stays buffered, as
+    /// sealing it would end it early
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decrypts a stream written by `EncryptWriter`. Reads fail on tampering,
+/// and on a stream that ends before its final chunk or goes on after it.
+pub struct DecryptReader<R: Read> {
+    reader: R,
+    opener: StreamOpener,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(reader: R, key: &[u8; 32], nonce_prefix: [u8; NONCE_PREFIX_LEN], aad: &[u8]) -> Self {
+        Self {
+            reader,
+            opener: StreamOpener::new(key, nonce_prefix, aad),
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Open the next chunk; false at the end of the stream
+    fn next_chunk(&mut self) -> Result<bool> {
+        if self.opener.is_finished() {
+            return Ok(false);
+        }
+        let mut len = [0u8; 4];
+        self.reader.read_exact(&mut len)
+            .context("Stream truncated before final chunk")?;
+        let len = u32::from_be_bytes(len) as usize;
+        if !(TAG_LEN..=CHUNK_SIZE + TAG_LEN).contains(&len) {
+            return Err(anyhow::anyhow!("Invalid stream chunk length {}", len));
+        }
+        let mut sealed = vec![0u8; len];
+        self.reader.read_exact(&mut sealed)
+            .context("Truncated stream chunk")?;
+        let (chunk, last) = self.opener.open(&sealed)?;
+        if last {
+            let mut trailing = [0u8; 1];
+            if self.reader.read(&mut trailing)? != 0 {
+                return Err(anyhow::anyhow!("Unexpected data after final stream chunk"));
+            }
+        }
+        self.chunk = chunk;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if !self.next_chunk().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))? {
+                return Ok(0);
+            }
+        }
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(data: &[u8], key: &[u8; 32], prefix: [u8; NONCE_PREFIX_LEN]) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), key, prefix, b"aad");
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(sealed: &[u8], key: &[u8; 32], prefix: [u8; NONCE_PREFIX_LEN]) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        DecryptReader::new(sealed, key, prefix, b"aad").read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let key = [7u8; 32];
+        let prefix = random_nonce_prefix();
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE * 3 + 17] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(decrypt(&encrypt(&data, &key, prefix), &key, prefix).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_detects_tampering_and_truncation() {
+        let key = [7u8; 32];
+        let prefix = random_nonce_prefix();
+        let data = vec![1u8; CHUNK_SIZE * 2 + 5];
+        let sealed = encrypt(&data, &key, prefix);
+        let chunk = 4 + CHUNK_SIZE + TAG_LEN;
+
+        assert!(decrypt(&sealed, &[8u8; 32], prefix).is_err());
+        let mut flipped = sealed.clone();
+        flipped[10] ^= 1;
+        assert!(decrypt(&flipped, &key, prefix).is_err());
+        // Whole chunks dropped from the end or swapped
+        assert!(decrypt(&sealed[..chunk * 2], &key, prefix).is_err());
+        let swapped = [&sealed[chunk..chunk * 2], &sealed[..chunk], &sealed[chunk * 2..]].concat();
+        assert!(decrypt(&swapped, &key, prefix).is_err());
+        let mut extended = sealed.clone();
+        extended.push(0);
+        assert!(decrypt(&extended, &key, prefix).is_err());
+
+        // A stream never finished reads as truncated
+        let mut writer = EncryptWriter::new(Vec::new(), &key, prefix, b"aad");
+        writer.write_all(&data).unwrap();
+        assert!(decrypt(&writer.writer, &key, prefix).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! Tracing output.
+//!
+//! The core reports through `tracing`: spans around handshakes, sends,
+//! envelope processing and storage transactions, events inside them.
+//! `init` installs a subscriber that writes them to stderr, filtered per
+//! target, with every field whose name marks it as secret or as message
+//! content replaced by `[REDACTED]`. The last `RECENT_LINES` lines are
+//! also kept in memory for diagnostic bundles. Spans in the core skip their
+//! arguments, so keys and plaintext never reach a field by accident; the
+//! redaction catches the fields added on purpose.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use tracing::field::{Field, Visit};
+use tracing_subscriber::field::{MakeVisitor, VisitFmt, VisitOutput};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::{FmtSpan, Writer};
+use tracing_subscriber::prelude::*;
+
+/// Parts of a field name, split at `_` and `.`, that get its value redacted
+const SENSITIVE: &[&str] = &[
+    "key", "secret", "password", "passphrase", "mnemonic", "seed", "token",
+    "nonce", "signature", "plaintext", "ciphertext", "content", "text", "body",
+];
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Lines of output kept in memory
+pub const RECENT_LINES: usize = 500;
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TelemetryConfig {
+    /// Level for targets not listed in `targets`: one of `off`, `error`,
+    /// `warn`, `info`, `debug` or `trace`
+    pub level: String,
+    /// Levels for targets by module path prefix, such as
+    /// `securechat_core::network`
+    pub targets: Vec<(String, String)>,
+    /// Also report when each span closes, with its busy and idle time
+    pub span_timings: bool,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            level: "warn".to_string(),
+            targets: vec![("securechat_core".to_string(), "info".to_string())],
+            span_timings: false,
+        }
+    }
+}
+
+impl TelemetryConfig {
+    pub fn filter(&self) -> Result<Targets> {
+        let mut filter = Targets::new().with_default(parse_level(&self.level)?);
+        for (target, level) in &self.targets {
+            filter = filter.with_target(target.clone(), parse_level(level)?);
+        }
+        Ok(filter)
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    LevelFilter::from_str(level).map_err(|_| anyhow::anyhow!("Unknown log level: {}", level))
+}
+
+/// Install the global subscriber. It can be installed once per process.
+pub fn init(config: &TelemetryConfig) -> Result<()> {
+    let span_events = if config.span_timings { FmtSpan::CLOSE } else { FmtSpan::NONE };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer()
+            .with_writer(io::stderr)
+            .with_span_events(span_events.clone())
+            .fmt_fields(Redacted))
+        .with(tracing_subscriber::fmt::layer()
+            .with_writer(|| RecentLines)
+            .with_ansi(false)
+            .with_span_events(span_events)
+            .fmt_fields(Redacted))
+        .with(config.filter()?)
+        .try_init()
+        .map_err(|e| anyhow::anyhow!("Telemetry is already set up: {}", e))
+}
+
+/// The latest lines of output, oldest first. Empty unless `init` was
+/// called.
+pub fn recent_logs() -> Vec<String> {
+    RECENT.lock().map(|lines| lines.iter().cloned().collect()).unwrap_or_default()
+}
+
+/// Writer keeping output in `RECENT`. The fmt layer writes each event in
+/// one call, so each write is a line.
+struct RecentLines;
+
+impl io::Write for RecentLines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf).trim_end().to_string();
+        if let Ok(mut lines) = RECENT.lock() {
+            if lines.len() == RECENT_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether a field's value may hold secrets or message content
+pub fn is_sensitive(name: &str) -> bool {
+    name.split(['_', '.'])
+        .any(|part| SENSITIVE.iter().any(|sensitive| part.eq_ignore_ascii_case(sensitive)))
+}
+
+/// Field formatter for the fmt layer, writing `name=value` pairs like the
+/// default one but with sensitive values redacted
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Redacted;
+
+impl<'writer> MakeVisitor<Writer<'writer>> for Redacted {
+    type Visitor = RedactingVisitor<'writer>;
+
+    fn make_visitor(&self, writer: Writer<'writer>) -> Self::Visitor {
+        RedactingVisitor { writer, separate: false, result: Ok(()) }
+    }
+}
+
+pub struct RedactingVisitor<'writer> {
+    writer: Writer<'writer>,
+    separate: bool,
+    result: fmt::Result,
+}
+
+impl Visit for RedactingVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if self.result.is_err() {
+            return;
+        }
+        let separator = if self.separate { " " } else { "" };
+        self.separate = true;
+        self.result = match field.name() {
+            // The format string of the event, written in code
+            "message" => write!(self.writer, "{}{:?}", separator, value),
+            name if is_sensitive(name) => write!(self.writer, "{}{}={}", separator, name, REDACTED),
+            name => write!(self.writer, "{}{}={:?}", separator, name, value),
+        };
+    }
+}
+
+impl VisitOutput<fmt::Result> for RedactingVisitor<'_> {
+    fn finish(self) -> fmt::Result {
+        self.result
+    }
+}
+
+impl VisitFmt for RedactingVisitor<'_> {
+    fn writer(&mut self) -> &mut dyn Write {
+        &mut self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_redaction() {
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .fmt_fields(Redacted)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("send", conversation_id = "c1", message_key = "k3y");
+            let _entered = span.enter();
+            tracing::info!(plaintext = "hello there", peer_id = "p1", "Sending");
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("Sending"));
+        assert!(output.contains("conversation_id=\"c1\""));
+        assert!(output.contains("peer_id=\"p1\""));
+        assert!(output.contains("message_key=[REDACTED]"));
+        assert!(output.contains("plaintext=[REDACTED]"));
+        assert!(!output.contains("k3y"));
+        assert!(!output.contains("hello there"));
+    }
+
+    #[test]
+    fn test_filter() {
+        assert!(is_sensitive("identity_key"));
+        assert!(is_sensitive("Password"));
+        assert!(!is_sensitive("context"));
+        assert!(!is_sensitive("message_id"));
+
+        TelemetryConfig::default().filter().unwrap();
+        let config = TelemetryConfig { level: "loud".to_string(), ..Default::default() };
+        assert!(config.filter().is_err());
+    }
+
+    #[test]
+    fn test_recent_lines() {
+        use std::io::Write;
+        for i in 0..RECENT_LINES + 2 {
+            RecentLines.write_all(format!("line {}\n", i).as_bytes()).unwrap();
+        }
+        let lines = recent_logs();
+        assert_eq!(lines.len(), RECENT_LINES);
+        assert_eq!(lines[0], "line 2");
+        assert_eq!(lines[RECENT_LINES - 1], format!("line {}", RECENT_LINES + 1));
+    }
+}
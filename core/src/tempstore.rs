@@ -0,0 +1,234 @@
+//! Encrypted staging files.
+//!
+//! Data on its way somewhere, such as an attachment being saved out of
+//! the blob store, is staged on disk rather than held in memory, and
+//! never as plaintext. A `SecureTempStore` makes a key when it is opened
+//! and keeps it only in memory. Every file in it is a `stream` sealed
+//! under that key and bound to the file's name. A file is removed when
+//! its handle is dropped. A crash leaves files no key opens any more, and
+//! opening the store again, at the next unlock, removes them.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Result, Context};
+use zeroize::Zeroizing;
+
+use crate::cancel::CancellationToken;
+use crate::stream::{self, DecryptReader, EncryptWriter, NONCE_PREFIX_LEN};
+
+const EXTENSION: &str = "stage";
+
+#[derive(Clone)]
+pub struct SecureTempStore {
+    dir: PathBuf,
+    key: Arc<Zeroizing<[u8; 32]>>,
+}
+
+impl SecureTempStore {
+    /// Open the store in `dir`, removing what an earlier run left there
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).context("Failed to create staging directory")?;
+        let mut removed = 0;
+        for entry in fs::read_dir(&dir).context("Failed to read staging directory")? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == EXTENSION) {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            tracing::info!("Removed {} staging files left by an earlier run", removed);
+        }
+
+        Ok(Self {
+            dir,
+            key: Arc::new(Zeroizing::new(rand::random())),
+        })
+    }
+
+    /// Staging directory of the database at `db_path`, next to it
+    pub fn dir_for(db_path: &Path) -> PathBuf {
+        let mut dir = db_path.as_os_str().to_owned();
+        dir.push(".tmp");
+        PathBuf::from(dir)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// A new, empty staging file
+    pub fn create(&self) -> Result<TempWriter> {
+        let name = format!("{:032x}.{}", rand::random::<u128>(), EXTENSION);
+        let path = self.dir.join(&name);
+        let file = File::options().write(true).create_new(true).open(&path)
+            .context("Failed to create staging file")?;
+        let nonce_prefix = stream::random_nonce_prefix();
+        Ok(TempWriter {
+            writer: EncryptWriter::new(BufWriter::new(file), &self.key, nonce_prefix, name.as_bytes()),
+            file: TempFile { path, name, key: self.key.clone(), nonce_prefix, size: 0 },
+        })
+    }
+
+    /// Stage everything `reader` gives, stopping at `cancel`
+    pub fn stage_from<R: Read>(&self, reader: R, cancel: &CancellationToken) -> Result<TempFile> {
+        let mut writer = self.create()?;
+        copy(reader, &mut writer, cancel)?;
+        writer.finish()
+    }
+}
+
+fn copy<R: Read, W: Write>(mut reader: R, mut writer: W, cancel: &CancellationToken) -> Result<u64> {
+    let mut buffer = vec![0u8; stream::CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        cancel.check()?;
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+    }
+    writer.flush()?;
+    Ok(copied)
+}
+
+/// Writes a staging file. Dropped without `finish`, the file is removed.
+pub struct TempWriter {
+    writer: EncryptWriter<BufWriter<File>>,
+    file: TempFile,
+}
+
+impl TempWriter {
+    /// End the file, to be read back
+    pub fn finish(self) -> Result<TempFile> {
+        let TempWriter { writer, file } = self;
+        writer.finish()?
+            .into_inner()
+            .map_err(|e| anyhow::anyhow!("Failed to write staging file: {}", e))?;
+        Ok(file)
+    }
+}
+
+impl Write for TempWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(data)?;
+        self.file.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A finished staging file, removed when dropped
+pub struct TempFile {
+    path: PathBuf,
+    name: String,
+    key: Arc<Zeroizing<[u8; 32]>>,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    size: u64,
+}
+
+impl TempFile {
+    /// Plaintext size
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Read the plaintext back; reads fail if the file was changed
+    pub fn reader(&self) -> Result<DecryptReader<BufReader<File>>> {
+        let file = File::open(&self.path).context("Failed to open staging file")?;
+        Ok(DecryptReader::new(BufReader::new(file), &self.key, self.nonce_prefix, self.name.as_bytes()))
+    }
+
+    /// Write the plaintext to `writer`, stopping at `cancel`
+    pub fn copy_to<W: Write>(&self, writer: W, cancel: &CancellationToken) -> Result<u64> {
+        copy(self.reader()?, writer, cancel)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn files(store: &SecureTempStore) -> usize {
+        fs::read_dir(store.dir()).unwrap().count()
+    }
+
+    #[test]
+    fn test_roundtrip_and_cleanup() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let store = SecureTempStore::open(temp_dir.path().join("tmp")).unwrap();
+
+        let data = vec![9u8; stream::CHUNK_SIZE + 10];
+        let mut writer = store.create().unwrap();
+        writer.write_all(&data).unwrap();
+        let file = writer.finish().unwrap();
+        assert_eq!(file.size(), data.len() as u64);
+
+        // Nothing of the plaintext is on disk
+        let on_disk = fs::read(&file.path).unwrap();
+        assert!(!on_disk.windows(64).any(|window| window == &data[..64]));
+        let mut read = Vec::new();
+        file.reader().unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        drop(file);
+        let mut abandoned = store.create().unwrap();
+        abandoned.write_all(b"partial").unwrap();
+        drop(abandoned);
+        assert_eq!(files(&store), 0);
+    }
+
+    #[test]
+    fn test_stage_and_copy() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let store = SecureTempStore::open(temp_dir.path().join("tmp")).unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+
+        let file = store.stage_from(&data[..], &CancellationToken::new()).unwrap();
+        let mut copied = Vec::new();
+        assert_eq!(file.copy_to(&mut copied, &CancellationToken::new()).unwrap(), data.len() as u64);
+        assert_eq!(copied, data);
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let error = store.stage_from(&data[..], &cancel).err().unwrap();
+        assert!(crate::cancel::is_cancelled(&error));
+        drop(file);
+        assert_eq!(files(&store), 0);
+    }
+
+    #[test]
+    fn test_leftovers_removed_on_open() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let dir = temp_dir.path().join("tmp");
+        let store = SecureTempStore::open(&dir).unwrap();
+        let mut writer = store.create().unwrap();
+        writer.write_all(b"left behind").unwrap();
+        let file = writer.finish().unwrap();
+        // As after a crash
+        std::mem::forget(file);
+        assert_eq!(files(&store), 1);
+
+        let reopened = SecureTempStore::open(&dir).unwrap();
+        assert_eq!(files(&reopened), 0);
+    }
+}
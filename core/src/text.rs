@@ -0,0 +1,101 @@
+//! Text handling for display.
+//!
+//! Text from peers goes on screen in previews and other one-line places,
+//! where it has to be cut short without splitting a character or an emoji,
+//! and where control characters would break the layout. Names are also
+//! wrapped in a bidi isolate, so a name ending in right-to-left text or
+//! carrying its own direction overrides can't reorder what follows it,
+//! e.g. turn "Mallory: sent a file" around.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Appended to text that was cut short
+pub const ELLIPSIS: &str = "...";
+
+/// First strong isolate: the text between it and `POP_ISOLATE` takes its
+/// direction from its own first strong character
+const FIRST_STRONG_ISOLATE: char = '\u{2068}';
+const POP_ISOLATE: char = '\u{2069}';
+
+/// The first `max` grapheme clusters of `text`
+pub fn truncate(text: &str, max: usize) -> &str {
+    match text.grapheme_indices(true).nth(max) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+/// `text` cut to `max` grapheme clusters, with `ELLIPSIS` if anything was
+/// cut
+pub fn ellipsize(text: &str, max: usize) -> String {
+    let truncated = truncate(text, max);
+    if truncated.len() < text.len() {
+        format!("{}{}", truncated.trim_end(), ELLIPSIS)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Explicit direction marks, embeddings, overrides and isolates
+pub fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// `text` on one line: line breaks and tabs become spaces, runs of
+/// whitespace collapse, and other control characters are dropped
+pub fn single_line(text: &str) -> String {
+    let mut line = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !line.ends_with(' ') {
+                line.push(' ');
+            }
+        } else if !c.is_control() {
+            line.push(c);
+        }
+    }
+    line.trim().to_string()
+}
+
+/// A name from a peer for showing amid other text: on one line, without
+/// direction controls of its own, and isolated from its surroundings
+pub fn isolate(name: &str) -> String {
+    let name: String = single_line(name).chars().filter(|c| !is_bidi_control(*c)).collect();
+    format!("{}{}{}", FIRST_STRONG_ISOLATE, name, POP_ISOLATE)
+}
+
+/// Longest preview, in grapheme clusters
+pub const PREVIEW_LEN: usize = 100;
+
+/// Message text for a one-line preview
+pub fn preview(text: &str) -> String {
+    ellipsize(&single_line(text), PREVIEW_LEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_keeps_graphemes_whole() {
+        assert_eq!(truncate("héllo", 2), "hé");
+        // Family emoji: several code points joined into one grapheme
+        let family = "👨‍👩‍👧";
+        assert_eq!(truncate(&format!("{}{}x", family, family), 1), family);
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(ellipsize(&"ü".repeat(150), 100), format!("{}...", "ü".repeat(100)));
+        assert_eq!(ellipsize("ok", 100), "ok");
+    }
+
+    #[test]
+    fn test_single_line() {
+        assert_eq!(single_line("one\ntwo\r\n\tthree\u{7}"), "one two three");
+        assert_eq!(preview("  hi\n\nthere  "), "hi there");
+    }
+
+    #[test]
+    fn test_isolate() {
+        assert_eq!(isolate("Mallory\u{202E}elif"), "\u{2068}Malloryelif\u{2069}");
+        assert_eq!(isolate("a\nb"), "\u{2068}a b\u{2069}");
+    }
+}
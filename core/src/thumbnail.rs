@@ -0,0 +1,190 @@
+//! Thumbnails of image and video attachments.
+//!
+//! Thumbnails are made when an attachment is sent, stored encrypted next to
+//! the message, and travel in the message's envelope so the recipient can
+//! show the message before the attachment itself arrives. In the envelope a
+//! thumbnail is sealed under a key derived from the attachment key and the
+//! envelope id, so only someone who can read the message can open it.
+//!
+//! Decoding and scaling images is up to a `Thumbnailer`. With the `image`
+//! feature, `ImageThumbnailer` is set by default and handles the common
+//! image formats; applications can set their own to use the platform's
+//! codecs or thumbnail videos. Without either, attachments are sent
+//! without thumbnails.
+
+use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
+
+use crate::crypto;
+use crate::protocol::{wire, AttachmentRef};
+
+/// Longest edge of an attachment thumbnail, in pixels
+pub const THUMBNAIL_EDGE: u32 = 256;
+/// Longest edge of a profile avatar, in pixels
+pub const AVATAR_EDGE: u32 = 128;
+/// Largest encoded thumbnail accepted
+pub const MAX_THUMBNAIL_LEN: usize = 16 * 1024;
+/// A sealed thumbnail: the thumbnail, its framing and the AEAD tag
+pub const MAX_SEALED_THUMBNAIL_LEN: usize = MAX_THUMBNAIL_LEN + 256;
+const MAX_MIME_TYPE_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Thumbnail {
+    /// Format of `data`, e.g. `image/jpeg`
+    pub mime_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl Thumbnail {
+    pub fn validate(&self) -> Result<()> {
+        if !self.mime_type.starts_with("image/") || self.mime_type.len() > MAX_MIME_TYPE_LEN {
+            return Err(anyhow::anyhow!("Thumbnail is not an image: {:?}", self.mime_type));
+        }
+        if self.width == 0 || self.height == 0 || self.width.max(self.height) > THUMBNAIL_EDGE {
+            return Err(anyhow::anyhow!("Thumbnail of {}x{} is out of bounds", self.width, self.height));
+        }
+        if self.data.is_empty() || self.data.len() > MAX_THUMBNAIL_LEN {
+            return Err(anyhow::anyhow!("Thumbnail of {} bytes is out of bounds", self.data.len()));
+        }
+        Ok(())
+    }
+}
+
+/// Makes thumbnails, e.g. with the `image` crate or the platform's codecs
+pub trait Thumbnailer: Send + Sync {
+    /// Scale `data` to fit within `max_edge` pixels, keeping the aspect
+    /// ratio. `None` if the format isn't supported.
+    fn thumbnail(&self, data: &[u8], mime_type: &str, max_edge: u32) -> Result<Option<Thumbnail>>;
+}
+
+/// The thumbnailer set before the application sets one
+pub fn default_thumbnailer() -> Option<std::sync::Arc<dyn Thumbnailer>> {
+    #[cfg(feature = "image")]
+    return Some(std::sync::Arc::new(ImageThumbnailer));
+    #[cfg(not(feature = "image"))]
+    None
+}
+
+/// Thumbnails of JPEG, PNG, GIF and WebP images with the `image` crate,
+/// as JPEGs. Videos are left to a platform thumbnailer.
+#[cfg(feature = "image")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImageThumbnailer;
+
+#[cfg(feature = "image")]
+impl ImageThumbnailer {
+    /// Largest image decoded, in pixels along either edge
+    const MAX_SOURCE_EDGE: u32 = 16_384;
+    /// Most memory decoding may take
+    const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+    /// JPEG qualities tried in turn until the thumbnail fits
+    const QUALITIES: [u8; 4] = [80, 65, 50, 35];
+}
+
+#[cfg(feature = "image")]
+impl Thumbnailer for ImageThumbnailer {
+    fn thumbnail(&self, data: &[u8], mime_type: &str, max_edge: u32) -> Result<Option<Thumbnail>> {
+        use image::codecs::jpeg::JpegEncoder;
+        use image::{ImageFormat, ImageReader, Limits};
+
+        let format = match ImageFormat::from_mime_type(mime_type) {
+            Some(format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif | ImageFormat::WebP)) => format,
+            _ => return Ok(None),
+        };
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(Self::MAX_SOURCE_EDGE);
+        limits.max_image_height = Some(Self::MAX_SOURCE_EDGE);
+        limits.max_alloc = Some(Self::MAX_DECODE_ALLOC);
+        let mut reader = ImageReader::with_format(std::io::Cursor::new(data), format);
+        reader.limits(limits);
+        let image = reader.decode().context("Failed to decode image")?;
+
+        // JPEG has no alpha channel
+        let scaled = image.thumbnail(max_edge, max_edge).into_rgb8();
+        for quality in Self::QUALITIES {
+            let mut encoded = Vec::new();
+            JpegEncoder::new_with_quality(&mut encoded, quality)
+                .encode_image(&scaled)
+                .context("Failed to encode thumbnail")?;
+            if encoded.len() <= MAX_THUMBNAIL_LEN {
+                return Ok(Some(Thumbnail {
+                    mime_type: "image/jpeg".to_string(),
+                    width: scaled.width(),
+                    height: scaled.height(),
+                    data: encoded,
+                }));
+            }
+        }
+        Err(anyhow::anyhow!("Thumbnail doesn't fit in {} bytes", MAX_THUMBNAIL_LEN))
+    }
+}
+
+/// Whether attachments of this type get a thumbnail
+pub fn wants_thumbnail(mime_type: &str) -> bool {
+    mime_type.starts_with("image/") || mime_type.starts_with("video/")
+}
+
+/// Key a thumbnail is sealed under in envelope `envelope_id`. Resends go
+/// out under new envelope ids, so no key seals two thumbnails.
+fn envelope_key(attachment: &AttachmentRef, envelope_id: &str) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key("securechat thumbnail key v1");
+    hasher.update(&attachment.key);
+    hasher.update(envelope_id.as_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+/// Seal a thumbnail for the envelope carrying `attachment`
+pub fn seal(thumbnail: &Thumbnail, attachment: &AttachmentRef, envelope_id: &str) -> Result<Vec<u8>> {
+    let encoded = wire::encode(thumbnail)?;
+    crypto::seal_attachment_chunk(&envelope_key(attachment, envelope_id), &attachment.digest, 0, &encoded)
+}
+
+/// Open a thumbnail sealed by `seal`, checking it is in bounds
+pub fn open(sealed: &[u8], attachment: &AttachmentRef, envelope_id: &str) -> Result<Thumbnail> {
+    let encoded = crypto::open_attachment_chunk(&envelope_key(attachment, envelope_id), &attachment.digest, 0, sealed)
+        .context("Thumbnail failed authentication")?;
+    let thumbnail: Thumbnail = wire::decode(&encoded)
+        .context("Malformed thumbnail")?;
+    thumbnail.validate()?;
+    Ok(thumbnail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_sealed_thumbnail_round_trip() {
+        let attachment = AttachmentRef { digest: [1; 32], size: 1000, key: [2; 32] };
+        let thumbnail = Thumbnail { mime_type: "image/jpeg".to_string(), width: 256, height: 144, data: vec![7; 500] };
+        let sealed = seal(&thumbnail, &attachment, "envelope").unwrap();
+        assert_eq!(open(&sealed, &attachment, "envelope").unwrap(), thumbnail);
+        
+        // Bound to the envelope and the attachment key
+        assert!(open(&sealed, &attachment, "other envelope").is_err());
+        let other = AttachmentRef { key: [3; 32], ..attachment.clone() };
+        assert!(open(&sealed, &other, "envelope").is_err());
+        
+        let oversized = Thumbnail { width: 512, ..thumbnail.clone() };
+        assert!(open(&seal(&oversized, &attachment, "envelope").unwrap(), &attachment, "envelope").is_err());
+        assert!(Thumbnail { mime_type: "text/html".to_string(), ..thumbnail }.validate().is_err());
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_image_thumbnailer() {
+        let image = image::RgbaImage::from_fn(1200, 600, |x, y| image::Rgba([(x % 256) as u8, (y % 256) as u8, 128, 255]));
+        let mut png = Vec::new();
+        image.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+
+        let thumbnail = ImageThumbnailer.thumbnail(&png, "image/png", THUMBNAIL_EDGE).unwrap().unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (THUMBNAIL_EDGE, THUMBNAIL_EDGE / 2));
+        assert_eq!(thumbnail.mime_type, "image/jpeg");
+        thumbnail.validate().unwrap();
+
+        assert!(ImageThumbnailer.thumbnail(&png, "video/mp4", THUMBNAIL_EDGE).unwrap().is_none());
+        assert!(ImageThumbnailer.thumbnail(b"not an image", "image/png", THUMBNAIL_EDGE).is_err());
+    }
+}
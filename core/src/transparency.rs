@@ -0,0 +1,289 @@
+//! Key transparency log.
+//!
+//! Every key we hand out besides the identity key, a device's message key
+//! when it is announced and an invite's prekey when it is created, is
+//! appended to a log of our own. Like the audit log each entry commits to
+//! the one before it, and each is also signed with the identity key, so
+//! the log can't be rewritten by anyone without it.
+//!
+//! The log is exported as a `KeyLog` for an audit. A contact checks it
+//! against the keys they were shown for us with `KeyLog::check`: a key
+//! that isn't in it was handed to them by someone else, as a server or
+//! relay equivocating about our keys would, and is reported as an
+//! `Inconsistency`. Keys announced after the export was made can't be
+//! checked against it and are only counted.
+
+use anyhow::{Result, Context};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::crypto::IdentityKeyPair;
+use crate::devices::RemoteDevice;
+use crate::protocol::wire;
+
+/// Hash of the (absent) entry before the first one
+pub const GENESIS_HASH: [u8; 32] = [0u8; 32];
+
+/// Largest exported log read
+pub const MAX_LOG_LEN: usize = 16 * 1024 * 1024;
+
+const HASH_CONTEXT: &str = "SecureChat key transparency v1";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyLogEventKind {
+    /// A device announced a message key
+    DeviceKey { device_id: String, message_key: [u8; 32] },
+    /// An invite was created with this prekey
+    InvitePrekey { prekey: [u8; 32] },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyLogEntry {
+    /// Position in the log, from 0
+    pub seq: u64,
+    pub kind: KeyLogEventKind,
+    pub timestamp: OffsetDateTime,
+    pub prev_hash: [u8; 32],
+    pub hash: [u8; 32],
+    /// Identity key's signature of `hash`
+    pub signature: Vec<u8>,
+}
+
+/// Newest entry of the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyLogHead {
+    /// Number of entries
+    pub len: u64,
+    pub hash: [u8; 32],
+}
+
+impl KeyLogEntry {
+    /// Entry following `head` (None for an empty log), signed by `identity`
+    pub fn next(identity: &IdentityKeyPair, head: Option<&KeyLogHead>, kind: KeyLogEventKind) -> Result<Self> {
+        let mut entry = Self {
+            seq: head.map_or(0, |h| h.len),
+            kind,
+            timestamp: OffsetDateTime::now_utc(),
+            prev_hash: head.map_or(GENESIS_HASH, |h| h.hash),
+            hash: GENESIS_HASH,
+            signature: Vec::new(),
+        };
+        entry.hash = entry.compute_hash(&identity.public_key.to_bytes())?;
+        entry.signature = identity.sign(&entry.hash).to_bytes().to_vec();
+        Ok(entry)
+    }
+
+    pub fn compute_hash(&self, identity_key: &[u8; 32]) -> Result<[u8; 32]> {
+        let mut hasher = blake3::Hasher::new_derive_key(HASH_CONTEXT);
+        hasher.update(identity_key);
+        hasher.update(&self.prev_hash);
+        hasher.update(&self.seq.to_be_bytes());
+        hasher.update(&self.timestamp.unix_timestamp_nanos().to_be_bytes());
+        hasher.update(&bincode::serialize(&self.kind)?);
+        Ok(*hasher.finalize().as_bytes())
+    }
+}
+
+/// Check that `entries`, oldest first, form one unbroken chain signed by
+/// `identity_key` and ending at `head`
+pub fn verify_chain(identity_key: &[u8; 32], entries: &[KeyLogEntry], head: Option<&KeyLogHead>) -> Result<()> {
+    let key = VerifyingKey::from_bytes(identity_key).context("Invalid identity key")?;
+    let mut prev = GENESIS_HASH;
+    for (i, entry) in entries.iter().enumerate() {
+        let signed = Signature::from_slice(&entry.signature)
+            .ok()
+            .is_some_and(|signature| IdentityKeyPair::verify(&key, &entry.hash, &signature).is_ok());
+        if entry.seq != i as u64
+            || entry.prev_hash != prev
+            || entry.compute_hash(identity_key)? != entry.hash
+            || !signed
+        {
+            return Err(anyhow::anyhow!("Key log broken at entry {}", i));
+        }
+        prev = entry.hash;
+    }
+
+    let expected = head.copied().unwrap_or(KeyLogHead { len: 0, hash: GENESIS_HASH });
+    if expected.len != entries.len() as u64 || expected.hash != prev {
+        return Err(anyhow::anyhow!("Key log is missing entries"));
+    }
+    Ok(())
+}
+
+/// Our log as exported for an audit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyLog {
+    pub identity_key: [u8; 32],
+    pub entries: Vec<KeyLogEntry>,
+}
+
+/// Keys a contact was shown for someone, to check against their log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObservedKeys {
+    pub identity_key: [u8; 32],
+    pub devices: Vec<RemoteDevice>,
+    /// The prekey of the invite we added them from
+    pub invite_prekey: Option<[u8; 32]>,
+}
+
+/// A key someone was shown that their own log doesn't have
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Inconsistency {
+    /// The log is signed by another identity than the contact has
+    IdentityKey { logged: [u8; 32], observed: [u8; 32] },
+    DeviceKey { device_id: String, message_key: [u8; 32] },
+    InvitePrekey { prekey: [u8; 32] },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyLogReport {
+    /// Entries in the log
+    pub entries: u64,
+    pub head: [u8; 32],
+    pub inconsistencies: Vec<Inconsistency>,
+    /// Device keys announced after the log's newest entry, not checked
+    pub newer_than_log: usize,
+}
+
+impl KeyLog {
+    pub fn head(&self) -> KeyLogHead {
+        KeyLogHead {
+            len: self.entries.len() as u64,
+            hash: self.entries.last().map_or(GENESIS_HASH, |entry| entry.hash),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        wire::encode(self)
+    }
+
+    /// Parse and verify an exported log
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() > MAX_LOG_LEN {
+            return Err(anyhow::anyhow!("Key log is too large"));
+        }
+        let log: Self = wire::decode(data).context("Not a key log")?;
+        verify_chain(&log.identity_key, &log.entries, Some(&log.head()))?;
+        Ok(log)
+    }
+
+    /// Check the keys a contact was shown for the log's owner against it
+    pub fn check(&self, observed: &ObservedKeys) -> KeyLogReport {
+        let head = self.head();
+        let mut report = KeyLogReport {
+            entries: head.len,
+            head: head.hash,
+            inconsistencies: Vec::new(),
+            newer_than_log: 0,
+        };
+        if observed.identity_key != self.identity_key {
+            report.inconsistencies.push(Inconsistency::IdentityKey {
+                logged: self.identity_key,
+                observed: observed.identity_key,
+            });
+            return report;
+        }
+
+        let newest = self.entries.last().map(|entry| entry.timestamp);
+        for device in &observed.devices {
+            let logged = self.entries.iter().any(|entry| matches!(
+                &entry.kind,
+                KeyLogEventKind::DeviceKey { device_id, message_key }
+                    if *device_id == device.device_id && *message_key == device.message_key
+            ));
+            if logged {
+                continue;
+            }
+            if newest.is_none_or(|newest| device.announced_at > newest) {
+                report.newer_than_log += 1;
+            } else {
+                report.inconsistencies.push(Inconsistency::DeviceKey {
+                    device_id: device.device_id.clone(),
+                    message_key: device.message_key,
+                });
+            }
+        }
+        if let Some(prekey) = observed.invite_prekey {
+            let logged = self.entries.iter()
+                .any(|entry| entry.kind == KeyLogEventKind::InvitePrekey { prekey });
+            if !logged {
+                report.inconsistencies.push(Inconsistency::InvitePrekey { prekey });
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn log(identity: &IdentityKeyPair, kinds: Vec<KeyLogEventKind>) -> KeyLog {
+        let mut entries: Vec<KeyLogEntry> = Vec::new();
+        for kind in kinds {
+            let head = entries.last().map(|entry| KeyLogHead { len: entry.seq + 1, hash: entry.hash });
+            entries.push(KeyLogEntry::next(identity, head.as_ref(), kind).unwrap());
+        }
+        KeyLog { identity_key: identity.public_key.to_bytes(), entries }
+    }
+
+    #[test]
+    fn test_log_verifies_and_detects_tampering() {
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let log = log(&identity, vec![
+            KeyLogEventKind::DeviceKey { device_id: "d1".to_string(), message_key: [1; 32] },
+            KeyLogEventKind::InvitePrekey { prekey: [2; 32] },
+        ]);
+        assert_eq!(KeyLog::parse(&log.to_bytes().unwrap()).unwrap(), log);
+
+        // Edited by someone without the identity key, even with the
+        // chain rebuilt
+        let mut edited = log.clone();
+        edited.entries[1].kind = KeyLogEventKind::InvitePrekey { prekey: [3; 32] };
+        edited.entries[1].hash = edited.entries[1].compute_hash(&edited.identity_key).unwrap();
+        assert!(KeyLog::parse(&edited.to_bytes().unwrap()).is_err());
+
+        // Signed by someone else
+        let mut other = log.clone();
+        other.identity_key = IdentityKeyPair::generate(&mut OsRng).public_key.to_bytes();
+        assert!(KeyLog::parse(&other.to_bytes().unwrap()).is_err());
+
+        let mut dropped = log;
+        dropped.entries.remove(0);
+        assert!(KeyLog::parse(&dropped.to_bytes().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_check_finds_unlogged_keys() {
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let log = log(&identity, vec![
+            KeyLogEventKind::DeviceKey { device_id: "d1".to_string(), message_key: [1; 32] },
+            KeyLogEventKind::InvitePrekey { prekey: [2; 32] },
+        ]);
+        let device = |message_key, announced_at| RemoteDevice { device_id: "d1".to_string(), message_key, announced_at };
+        let before = log.entries[0].timestamp;
+        let mut observed = ObservedKeys {
+            identity_key: log.identity_key,
+            devices: vec![device([1; 32], before)],
+            invite_prekey: Some([2; 32]),
+        };
+        assert!(log.check(&observed).inconsistencies.is_empty());
+
+        observed.devices.push(device([9; 32], before));
+        observed.devices.push(device([8; 32], before + time::Duration::hours(1)));
+        observed.invite_prekey = Some([7; 32]);
+        let report = log.check(&observed);
+        assert_eq!(report.inconsistencies, [
+            Inconsistency::DeviceKey { device_id: "d1".to_string(), message_key: [9; 32] },
+            Inconsistency::InvitePrekey { prekey: [7; 32] },
+        ]);
+        assert_eq!((report.entries, report.newer_than_log), (2, 1));
+
+        observed.identity_key = [5; 32];
+        assert!(matches!(&log.check(&observed).inconsistencies[..], [Inconsistency::IdentityKey { .. }]));
+    }
+}
@@ -0,0 +1,236 @@
+//! Store-and-forward transports.
+//!
+//! A `MessageTransport` carries opaque frames through some other system,
+//! such as a Matrix room, for peers that can't reach each other directly.
+//! Frames are the same wire-encoded protocol messages the network sends,
+//! so envelopes stay sealed with our own keys end to end and the relaying
+//! service only ever sees ciphertext. `MessageBridge` turns any of them
+//! into a `NetworkTransport` for `SecureChat::set_network_transport`: it
+//! sends what the core hands it, retrying until the transport takes it,
+//! and polls for frames from the other side. Everything above the
+//! network is unchanged.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+
+use crate::network::{NetworkCommand, NetworkConfig, NetworkEvent, NetworkHandle, NetworkTransport};
+use crate::protocol::ProtocolMessage;
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Carries frames to and from the other side. Calls may block; the
+/// bridge makes them off the async runtime.
+pub trait MessageTransport: Send + Sync {
+    /// Short name, e.g. "matrix", which also stands for the peer on the
+    /// other side in network events
+    fn name(&self) -> &str;
+    /// Hand a frame over for delivery
+    fn send(&self, frame: &[u8]) -> Result<()>;
+    /// Frames that arrived since the last call, oldest first
+    fn receive(&self) -> Result<Vec<Vec<u8>>>;
+}
+
+/// Runs a `MessageTransport` as the network
+pub struct MessageBridge {
+    transport: Arc<dyn MessageTransport>,
+    poll_interval: Duration,
+}
+
+impl MessageBridge {
+    pub fn new(transport: Arc<dyn MessageTransport>) -> Self {
+        Self { transport, poll_interval: DEFAULT_POLL_INTERVAL }
+    }
+
+    /// How often to retry sending and check for new frames
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+}
+
+impl NetworkTransport for MessageBridge {
+    fn start(&self, config: NetworkConfig) -> Result<NetworkHandle> {
+        let (mut events, event_rx) = mpsc::channel(100);
+        let (commands, mut command_rx) = mpsc::channel(100);
+        let transport = self.transport.clone();
+        let poll_interval = self.poll_interval;
+
+        let task = tokio::spawn(async move {
+            let peer_id = transport.name().to_string();
+            events.send(NetworkEvent::Connected).await.ok();
+            events.send(NetworkEvent::PeerConnected { peer_id: peer_id.clone() }).await.ok();
+
+            let mut unsent = VecDeque::new();
+            let mut ticks = tokio::time::interval(poll_interval);
+            loop {
+                tokio::select! {
+                    command = command_rx.next() => match command {
+                        None | Some(NetworkCommand::Shutdown) => break,
+                        Some(NetworkCommand::SendMessage { message, .. }) => {
+                            unsent.push_back(message);
+                            flush(&transport, &mut unsent, &mut events).await;
+                        }
+                        Some(NetworkCommand::CancelMessage { message_id }) => {
+                            unsent.retain(|message| message.message_id() != Some(message_id.as_str()));
+                        }
+                        Some(NetworkCommand::GetPeerStats { reply }) => {
+                            reply.send(Vec::new()).ok();
+                        }
+                        Some(_) => {}
+                    },
+                    _ = ticks.tick() => {
+                        flush(&transport, &mut unsent, &mut events).await;
+                        for message in poll(&transport, &config).await {
+                            let received = NetworkEvent::MessageReceived { peer_id: peer_id.clone(), message };
+                            if events.send(received).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            Ok::<_, anyhow::Error>(unsent.into_iter().filter(|message| !message.is_ephemeral()).collect())
+        });
+        Ok(NetworkHandle { events: event_rx, commands, task })
+    }
+}
+
+/// Send what's waiting, in order, until the transport fails. Ephemeral
+/// signals aren't worth retrying and are dropped on failure.
+async fn flush(
+    transport: &Arc<dyn MessageTransport>,
+    unsent: &mut VecDeque<ProtocolMessage>,
+    events: &mut mpsc::Sender<NetworkEvent>,
+) {
+    while let Some(message) = unsent.front() {
+        let frame = match message.encode() {
+            Ok(frame) => frame,
+            Err(e) => {
+                tracing::warn!(error = %e, "Dropping message that failed to encode");
+                unsent.pop_front();
+                continue;
+            }
+        };
+        let sending = transport.clone();
+        let sent = tokio::task::spawn_blocking(move || sending.send(&frame)).await
+            .unwrap_or_else(|e| Err(anyhow::anyhow!("Transport panicked: {}", e)));
+        match sent {
+            Ok(()) => {
+                let message = unsent.pop_front().expect("Front message");
+                if let Some(message_id) = message.message_id() {
+                    events.send(NetworkEvent::MessageSent { message_id: message_id.to_string() }).await.ok();
+                }
+            }
+            Err(e) => {
+                tracing::warn!(transport = transport.name(), error = %e, "Send failed, will retry");
+                if unsent.front().is_some_and(ProtocolMessage::is_ephemeral) {
+                    unsent.pop_front();
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Frames from the other side that decode within our limits
+async fn poll(transport: &Arc<dyn MessageTransport>, config: &NetworkConfig) -> Vec<ProtocolMessage> {
+    let receiving = transport.clone();
+    let frames = match tokio::task::spawn_blocking(move || receiving.receive()).await {
+        Ok(Ok(frames)) => frames,
+        Ok(Err(e)) => {
+            tracing::warn!(transport = transport.name(), error = %e, "Receive failed");
+            return Vec::new();
+        }
+        Err(e) => {
+            tracing::warn!(transport = transport.name(), error = %e, "Transport panicked");
+            return Vec::new();
+        }
+    };
+    frames.iter()
+        .filter_map(|frame| match ProtocolMessage::decode_within(frame, &config.limits) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                tracing::debug!(error = %e, "Ignoring undecodable frame");
+                None
+            }
+        })
+        .collect()
+}
+
+/// One end of a pair of transports joined in memory, for tests
+#[derive(Debug)]
+pub struct MemoryTransport {
+    inbox: Arc<Mutex<Vec<Vec<u8>>>>,
+    outbox: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// Fail sends while set, as a service that is down would
+    pub offline: std::sync::atomic::AtomicBool,
+}
+
+impl MemoryTransport {
+    /// Two ends, each receiving what the other sends
+    pub fn pair() -> (Arc<Self>, Arc<Self>) {
+        let (a, b) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+        let end = |inbox: &Arc<Mutex<Vec<Vec<u8>>>>, outbox: &Arc<Mutex<Vec<Vec<u8>>>>| Arc::new(Self {
+            inbox: inbox.clone(),
+            outbox: outbox.clone(),
+            offline: Default::default(),
+        });
+        (end(&a, &b), end(&b, &a))
+    }
+}
+
+impl MessageTransport for MemoryTransport {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        if self.offline.load(std::sync::atomic::Ordering::Relaxed) {
+            return Err(anyhow::anyhow!("Transport is offline"));
+        }
+        self.outbox.lock().unwrap_or_else(|e| e.into_inner()).push(frame.to_vec());
+        Ok(())
+    }
+
+    fn receive(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(std::mem::take(&mut *self.inbox.lock().unwrap_or_else(|e| e.into_inner())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use crate::harness::{introduce, TestNode};
+
+    #[tokio::test]
+    async fn test_chat_over_bridge() {
+        let (a, b) = MemoryTransport::pair();
+        let mut alice = TestNode::new("Alice").await;
+        let mut bob = TestNode::new("Bob").await;
+        for (node, end) in [(&mut alice, a.clone()), (&mut bob, b.clone())] {
+            let bridge = MessageBridge::new(end).with_poll_interval(Duration::from_millis(10));
+            node.chat.set_network_transport(Arc::new(bridge)).await;
+            node.events = node.chat.start_network(NetworkConfig::default()).await.unwrap();
+        }
+
+        let conversation = introduce(&bob, &alice).await;
+        bob.chat.send_text_message(&conversation, "over the bridge").await.unwrap();
+        assert_eq!(alice.expect_message().await.1, "over the bridge");
+
+        // Held while the transport is down, sent once it's back
+        b.offline.store(true, Ordering::Relaxed);
+        bob.chat.send_text_message(&conversation, "after the outage").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        b.offline.store(false, Ordering::Relaxed);
+        assert_eq!(alice.expect_message().await.1, "after the outage");
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+}
@@ -0,0 +1,72 @@
+//! Storage usage and compaction reports.
+//!
+//! `SecureStorage::usage_report` breaks the profile's records down by what
+//! they hold. Sizes are of the stored, encrypted records; the database
+//! files can be larger until sled reclaims freed space, which
+//! `SecureStorage::compact` speeds up.
+
+use serde::{Serialize, Deserialize};
+
+/// Records rewritten between compaction progress reports
+pub const COMPACTION_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CategoryUsage {
+    pub records: usize,
+    pub bytes: u64,
+}
+
+impl CategoryUsage {
+    pub fn add(&mut self, bytes: usize) {
+        self.records += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationUsage {
+    pub conversation_id: String,
+    pub messages: CategoryUsage,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReport {
+    /// Size of the database files, including space not reclaimed yet
+    pub on_disk: u64,
+    /// Messages of each conversation, largest first
+    pub conversations: Vec<ConversationUsage>,
+    /// Attachment, thumbnail and sticker data
+    pub attachments: CategoryUsage,
+    /// Lookup entries: starred messages, attachment references,
+    /// conversations by contact, messages by status and order
+    pub index: CategoryUsage,
+    /// Contacts, keys, settings and everything else
+    pub other: CategoryUsage,
+}
+
+impl UsageReport {
+    /// Size of all the records counted
+    pub fn total_bytes(&self) -> u64 {
+        self.conversations.iter().map(|c| c.messages.bytes).sum::<u64>()
+            + self.attachments.bytes
+            + self.index.bytes
+            + self.other.bytes
+    }
+}
+
+/// How far a compaction has got
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionProgress {
+    pub records_done: usize,
+    pub records_total: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionReport {
+    /// Attachments no message or sticker pack referred to, and writes
+    /// that never finished
+    pub orphaned_blobs: usize,
+    pub orphaned_bytes: u64,
+    pub size_before: u64,
+    pub size_after: u64,
+}
@@ -0,0 +1,161 @@
+//! Human-readable usernames.
+//!
+//! A username is a self-claim: the name and an identity key, signed by that
+//! key. Claims are published to a `UsernameRegistry`, which may be a shared
+//! directory service or anything else that stores and returns them; the
+//! registry is not trusted, every claim is checked on lookup. When several
+//! valid claims exist for one name the earliest wins, and the key a name
+//! first resolved to is pinned, so a later claim can't silently take over a
+//! name already in use (trust on first use).
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{Result, Context};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
+
+use crate::crypto::IdentityKeyPair;
+use crate::protocol::wire;
+
+pub const MIN_USERNAME_LEN: usize = 3;
+pub const MAX_USERNAME_LEN: usize = 32;
+
+/// Most claims a lookup considers for one name
+pub const MAX_CLAIMS_PER_NAME: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsernameClaim {
+    /// Normalized, see `normalize`
+    pub username: String,
+    pub identity_key: [u8; 32],
+    pub claimed_at: OffsetDateTime,
+    pub signature: Vec<u8>,
+}
+
+/// Result of resolving a username
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UsernameResolution {
+    /// Resolved for the first time; the key is now pinned
+    FirstUse(UsernameClaim),
+    /// Same key as when the name was pinned
+    Pinned(UsernameClaim),
+    /// The name now resolves to a different key than the pinned one. The
+    /// pin stays until the user accepts the new key.
+    KeyChanged { pinned_key: [u8; 32], claim: UsernameClaim },
+}
+
+/// Where claims are published and looked up
+pub trait UsernameRegistry: Send + Sync {
+    fn publish(&self, claim: &UsernameClaim) -> Result<()>;
+    /// Every claim held for `username`, unchecked
+    fn lookup(&self, username: &str) -> Result<Vec<UsernameClaim>>;
+}
+
+/// Registry kept in memory, for tests and local networks
+#[derive(Debug, Default)]
+pub struct MemoryRegistry {
+    claims: Mutex<HashMap<String, Vec<UsernameClaim>>>,
+}
+
+impl UsernameRegistry for MemoryRegistry {
+    fn publish(&self, claim: &UsernameClaim) -> Result<()> {
+        let mut claims = self.claims.lock()
+            .map_err(|_| anyhow::anyhow!("Registry lock poisoned"))?;
+        let entry = claims.entry(claim.username.clone()).or_default();
+        entry.retain(|c| c.identity_key != claim.identity_key);
+        entry.push(claim.clone());
+        Ok(())
+    }
+    
+    fn lookup(&self, username: &str) -> Result<Vec<UsernameClaim>> {
+        let claims = self.claims.lock()
+            .map_err(|_| anyhow::anyhow!("Registry lock poisoned"))?;
+        Ok(claims.get(username).cloned().unwrap_or_default())
+    }
+}
+
+/// Lowercase `username` and check it only uses `a-z`, `0-9`, `.`, `_` and `-`
+pub fn normalize(username: &str) -> Result<String> {
+    let username = username.trim().to_ascii_lowercase();
+    if username.len() < MIN_USERNAME_LEN || username.len() > MAX_USERNAME_LEN {
+        return Err(anyhow::anyhow!("Usernames are {} to {} characters", MIN_USERNAME_LEN, MAX_USERNAME_LEN));
+    }
+    if !username.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"._-".contains(&b)) {
+        return Err(anyhow::anyhow!("Usernames may only use letters, digits, '.', '_' and '-'"));
+    }
+    Ok(username)
+}
+
+impl UsernameClaim {
+    pub fn new(username: &str, identity: &IdentityKeyPair) -> Result<Self> {
+        let username = normalize(username)?;
+        let claimed_at = OffsetDateTime::now_utc();
+        let identity_key = identity.public_key.to_bytes();
+        let signature = identity.sign(&Self::signing_bytes(&username, &identity_key, &claimed_at)?);
+        Ok(Self { username, identity_key, claimed_at, signature: signature.to_bytes().to_vec() })
+    }
+    
+    fn signing_bytes(username: &str, identity_key: &[u8; 32], claimed_at: &OffsetDateTime) -> Result<Vec<u8>> {
+        wire::encode(&(b"SecureChat username claim v1", username, identity_key, claimed_at))
+    }
+    
+    /// Check the name is well-formed and the claim is signed by its key
+    pub fn verify(&self) -> Result<()> {
+        if normalize(&self.username)? != self.username {
+            return Err(anyhow::anyhow!("Username is not normalized"));
+        }
+        let key = VerifyingKey::from_bytes(&self.identity_key)
+            .context("Invalid identity key")?;
+        let signature = Signature::from_slice(&self.signature)
+            .context("Malformed signature")?;
+        IdentityKeyPair::verify(&key, &Self::signing_bytes(&self.username, &self.identity_key, &self.claimed_at)?, &signature)
+            .context("Username claim signature is invalid")
+    }
+}
+
+/// The claim holding `username`: among the valid claims for it, the
+/// earliest, with ties going to the lower key
+pub fn winning_claim(username: &str, claims: Vec<UsernameClaim>) -> Option<UsernameClaim> {
+    claims.into_iter()
+        .take(MAX_CLAIMS_PER_NAME)
+        .filter(|c| c.username == username && c.verify().is_ok())
+        .min_by(|a, b| a.claimed_at.cmp(&b.claimed_at).then(a.identity_key.cmp(&b.identity_key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("  Alice.B ").unwrap(), "alice.b");
+        assert!(normalize("al").is_err());
+        assert!(normalize("alice smith").is_err());
+        assert!(normalize("аlice").is_err()); // Cyrillic a
+        assert!(normalize(&"a".repeat(MAX_USERNAME_LEN + 1)).is_err());
+    }
+    
+    #[test]
+    fn test_earliest_valid_claim_wins() {
+        let first = IdentityKeyPair::generate(&mut OsRng);
+        let second = IdentityKeyPair::generate(&mut OsRng);
+        let early = UsernameClaim::new("alice", &first).unwrap();
+        let mut late = UsernameClaim::new("alice", &second).unwrap();
+        late.claimed_at = early.claimed_at + time::Duration::seconds(1);
+        late.signature = second.sign(&UsernameClaim::signing_bytes("alice", &late.identity_key, &late.claimed_at).unwrap())
+            .to_bytes().to_vec();
+        assert!(late.verify().is_ok());
+        
+        assert_eq!(winning_claim("alice", vec![late.clone(), early.clone()]), Some(early.clone()));
+        
+        // A backdated claim without a valid signature doesn't count
+        let mut forged = late.clone();
+        forged.claimed_at = early.claimed_at - time::Duration::days(1);
+        assert!(forged.verify().is_err());
+        assert_eq!(winning_claim("alice", vec![forged, late.clone()]), Some(late));
+        assert_eq!(winning_claim("bob", vec![early]), None);
+    }
+}
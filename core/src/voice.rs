@@ -0,0 +1,115 @@
+//! Voice note helpers.
+//!
+//! The UI feeds decoded mono PCM to a `VoiceAnalyzer` while recording and
+//! gets back the duration and a compact waveform for the message preview.
+//! The encoded audio itself is streamed into the attachment store, see
+//! `SecureChat::send_voice_note`.
+
+use serde::{Serialize, Deserialize};
+
+/// Number of bars in a voice note waveform
+pub const WAVEFORM_BARS: usize = 64;
+
+/// Peaks are tracked per 10 ms window
+const WINDOWS_PER_SECOND: u32 = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VoiceMetadata {
+    pub duration_secs: u32,
+    /// `WAVEFORM_BARS` peak levels (0-255), loudest bar scaled to 255
+    pub waveform: Vec<u8>,
+}
+
+/// Computes voice note metadata incrementally from PCM samples
+#[derive(Debug, Clone)]
+pub struct VoiceAnalyzer {
+    sample_rate: u32,
+    window_len: usize,
+    window_fill: usize,
+    window_peak: u16,
+    peaks: Vec<u16>,
+    samples: u64,
+}
+
+impl VoiceAnalyzer {
+    pub fn new(sample_rate: u32) -> Self {
+        let sample_rate = sample_rate.max(1);
+        Self {
+            sample_rate,
+            window_len: (sample_rate / WINDOWS_PER_SECOND).max(1) as usize,
+            window_fill: 0,
+            window_peak: 0,
+            peaks: Vec::new(),
+            samples: 0,
+        }
+    }
+    
+    /// Feed the next block of mono samples
+    pub fn push_samples(&mut self, samples: &[i16]) {
+        for sample in samples {
+            self.window_peak = self.window_peak.max(sample.unsigned_abs());
+            self.window_fill += 1;
+            if self.window_fill == self.window_len {
+                self.peaks.push(self.window_peak);
+                self.window_fill = 0;
+                self.window_peak = 0;
+            }
+        }
+        self.samples += samples.len() as u64;
+    }
+    
+    /// Length recorded so far, for a live timer
+    pub fn duration_ms(&self) -> u64 {
+        self.samples * 1000 / u64::from(self.sample_rate)
+    }
+    
+    pub fn finish(mut self) -> VoiceMetadata {
+        if self.window_fill > 0 {
+            self.peaks.push(self.window_peak);
+        }
+        
+        let mut bars = [0u16; WAVEFORM_BARS];
+        if !self.peaks.is_empty() {
+            for (i, bar) in bars.iter_mut().enumerate() {
+                let from = i * self.peaks.len() / WAVEFORM_BARS;
+                let to = ((i + 1) * self.peaks.len() / WAVEFORM_BARS).max(from + 1);
+                *bar = self.peaks[from..to.min(self.peaks.len())].iter().copied().max().unwrap_or(0);
+            }
+        }
+        let loudest = u32::from(bars.iter().copied().max().unwrap_or(0).max(1));
+        
+        VoiceMetadata {
+            duration_secs: self.samples.div_ceil(u64::from(self.sample_rate)) as u32,
+            waveform: bars.iter().map(|bar| (u32::from(*bar) * 255 / loudest) as u8).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_waveform_follows_loudness() {
+        let mut analyzer = VoiceAnalyzer::new(8000);
+        // One quiet second, then one loud second, pushed in odd-sized blocks
+        let quiet = vec![1000i16; 8000];
+        let loud: Vec<i16> = (0..8000).map(|i| if i % 2 == 0 { 20000 } else { -20000 }).collect();
+        for block in quiet.chunks(333).chain(loud.chunks(777)) {
+            analyzer.push_samples(block);
+        }
+        assert_eq!(analyzer.duration_ms(), 2000);
+        
+        let metadata = analyzer.finish();
+        assert_eq!(metadata.duration_secs, 2);
+        assert_eq!(metadata.waveform.len(), WAVEFORM_BARS);
+        assert!(metadata.waveform[..WAVEFORM_BARS / 2].iter().all(|bar| *bar == 12));
+        assert!(metadata.waveform[WAVEFORM_BARS / 2..].iter().all(|bar| *bar == 255));
+    }
+    
+    #[test]
+    fn test_silence() {
+        let metadata = VoiceAnalyzer::new(48000).finish();
+        assert_eq!(metadata, VoiceMetadata { duration_secs: 0, waveform: vec![0; WAVEFORM_BARS] });
+    }
+}
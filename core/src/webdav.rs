@@ -0,0 +1,221 @@
+//! Backups on a WebDAV server, behind the `webdav` feature.
+//!
+//! `WebDavSink` keeps backups in one collection on any HTTP server that
+//! speaks WebDAV, such as Nextcloud or most NAS boxes. A backup is staged
+//! in the `SecureTempStore` as it is written. At `finalize` it is uploaded
+//! under a temporary name, then `MOVE`d into place, so the collection never
+//! holds an unfinished backup under a backup's name. Backups are encrypted
+//! before they get here, so the server only has to be trusted to keep
+//! them.
+
+use std::io::Read;
+use std::time::Duration;
+
+use anyhow::{Result, Context};
+use base64::Engine;
+use serde::{Serialize, Deserialize};
+
+use crate::backup_sink::{self, BackupSink, BackupSource, BackupUpload};
+use crate::tempstore::{SecureTempStore, TempWriter};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Asks only for the names of the collection's members
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/></d:prop></d:propfind>"#;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WebDavConfig {
+    /// The collection backups go in, e.g.
+    /// `https://cloud.example.org/remote.php/dav/files/alice/backups`
+    pub url: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for WebDavConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebDavConfig")
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+impl WebDavConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.url.starts_with("https://") {
+            return Err(anyhow::anyhow!("WebDAV server must be an https URL"));
+        }
+        Ok(())
+    }
+}
+
+/// Backups in a WebDAV collection
+pub struct WebDavSink {
+    config: WebDavConfig,
+    agent: ureq::Agent,
+    staging: SecureTempStore,
+}
+
+impl WebDavSink {
+    /// Keep backups at `config.url`, staging them in `staging`
+    pub fn new(config: WebDavConfig, staging: SecureTempStore) -> Result<Self> {
+        config.validate()?;
+        let agent = ureq::AgentBuilder::new()
+            .timeout(REQUEST_TIMEOUT)
+            .build();
+        Ok(Self { config, agent, staging })
+    }
+
+    fn collection(&self) -> String {
+        format!("{}/", self.config.url.trim_end_matches('/'))
+    }
+
+    fn url(&self, name: &str) -> Result<String> {
+        backup_sink::check_name(name)?;
+        Ok(format!("{}{}", self.collection(), urlencoding::encode(name)))
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        request(&self.agent, &self.config, method, url)
+    }
+}
+
+fn request(agent: &ureq::Agent, config: &WebDavConfig, method: &str, url: &str) -> ureq::Request {
+    let credentials = format!("{}:{}", config.username, config.password);
+    let authorization = format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials));
+    agent.request(method, url).set("Authorization", &authorization)
+}
+
+impl BackupSink for WebDavSink {
+    fn name(&self) -> String {
+        self.config.url.clone()
+    }
+
+    fn create(&self, name: &str) -> Result<Box<dyn BackupUpload>> {
+        Ok(Box::new(WebDavUpload {
+            url: self.url(name)?,
+            agent: self.agent.clone(),
+            config: self.config.clone(),
+            staged: self.staging.create()?,
+        }))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.request("DELETE", &self.url(name)?)
+            .call()
+            .context("Failed to remove backup from the WebDAV server")?;
+        Ok(())
+    }
+}
+
+impl BackupSource for WebDavSink {
+    fn list(&self) -> Result<Vec<String>> {
+        let collection = self.collection();
+        let response = self.request("PROPFIND", &collection)
+            .set("Depth", "1")
+            .set("Content-Type", "application/xml")
+            .send_string(PROPFIND_BODY)
+            .context("Failed to list backups on the WebDAV server")?
+            .into_string()
+            .context("Malformed PROPFIND response")?;
+        Ok(member_names(&response))
+    }
+
+    fn read(&self, name: &str) -> Result<Box<dyn Read + Send>> {
+        let response = self.request("GET", &self.url(name)?)
+            .call()
+            .context("Failed to download backup from the WebDAV server")?;
+        Ok(Box::new(response.into_reader()))
+    }
+}
+
+struct WebDavUpload {
+    url: String,
+    agent: ureq::Agent,
+    config: WebDavConfig,
+    staged: TempWriter,
+}
+
+impl BackupUpload for WebDavUpload {
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        std::io::Write::write_all(&mut self.staged, data)
+            .context("Failed to stage backup")
+    }
+
+    fn finalize(self: Box<Self>) -> Result<String> {
+        let WebDavUpload { url, agent, config, staged } = *self;
+        let staged = staged.finish()?;
+        let partial = format!("{}.partial", url);
+        request(&agent, &config, "PUT", &partial)
+            .set("Content-Type", "application/octet-stream")
+            .send(staged.reader()?)
+            .context("Failed to upload backup to the WebDAV server")?;
+        request(&agent, &config, "MOVE", &partial)
+            .set("Destination", &url)
+            .set("Overwrite", "T")
+            .call()
+            .context("Failed to move backup into place on the WebDAV server")?;
+        Ok(url)
+    }
+}
+
+/// Names of the members in a PROPFIND response. The response's own
+/// collection and partial uploads are left out. The parsing is just
+/// enough for `href` elements, whatever prefix the server gives the DAV
+/// namespace.
+fn member_names(xml: &str) -> Vec<String> {
+    xml.split('<')
+        .filter_map(|tag| {
+            let (name, text) = tag.split_once('>')?;
+            let name = name.split_whitespace().next()?;
+            if name.starts_with('/') || !name.rsplit(':').next()?.eq_ignore_ascii_case("href") {
+                return None;
+            }
+            let href = text.trim().replace("&amp;", "&");
+            // A collection's href ends with a slash, backups' don't
+            if href.ends_with('/') {
+                return None;
+            }
+            let name = urlencoding::decode(href.rsplit('/').next()?).ok()?.into_owned();
+            backup_sink::check_name(&name).is_ok().then_some(name)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> WebDavConfig {
+        WebDavConfig {
+            url: "https://dav.example.org/backups".to_string(),
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        assert!(config().validate().is_ok());
+        assert!(!format!("{:?}", config()).contains("secret"));
+        assert!(WebDavConfig { url: "http://dav.example.org".to_string(), ..config() }.validate().is_err());
+    }
+
+    #[test]
+    fn test_member_names() {
+        let response = r#"<?xml version="1.0"?>
+            <d:multistatus xmlns:d="DAV:">
+              <d:response><d:href>/backups/</d:href></d:response>
+              <d:response><d:href>/backups/securechat-00000000001700000000.scbk</d:href></d:response>
+              <d:response><d:href>/backups/securechat-00000000001700000001.scbk.partial</d:href></d:response>
+              <d:response><d:href>/backups/old%20one.scbk</d:href></d:response>
+              <d:response><d:href>/backups/nested/</d:href></d:response>
+            </d:multistatus>"#;
+        assert_eq!(member_names(response), ["securechat-00000000001700000000.scbk", "old one.scbk"]);
+
+        let plain = "<multistatus xmlns=\"DAV:\"><response><href>https://dav.example.org/b/x.scbk</href></response></multistatus>";
+        assert_eq!(member_names(plain), ["x.scbk"]);
+    }
+}
@@ -0,0 +1,338 @@
+//! Webhooks, for bots and automation around a client that runs
+//! unattended.
+//!
+//! A registered `Webhook` is posted a JSON `WebhookPayload` for each
+//! event it asked for, optionally only those about some contacts. The
+//! body is signed with the webhook's secret in the `SIGNATURE_HEADER`
+//! header as `t=<unix time>,v1=<hex HMAC-SHA256 of "<t>.<body>">`, so the
+//! receiver can check it came from us and isn't a replay. Posting is up to
+//! a `WebhookSender` the application provides, as with `push::PushBridge`.
+//!
+//! The other way, a program can have us send a message by handing
+//! `SecureChat::handle_inbound_webhook` an `InboundMessage` signed the
+//! same way with the inbound secret, each signature accepted only once.
+//! Serving that over HTTP is left to the host application.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Result, Context};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use time::OffsetDateTime;
+
+use crate::ChatEvent;
+
+pub const SIGNATURE_HEADER: &str = "X-SecureChat-Signature";
+/// How far a signature's time may be from ours
+pub const SIGNATURE_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+pub const MAX_URL_LEN: usize = 1024;
+/// Most webhooks registered at once
+pub const MAX_WEBHOOKS: usize = 32;
+/// Largest inbound request body read
+pub const MAX_INBOUND_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    MessageReceived,
+    ContactRequest,
+    MessageRequest,
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Key payloads are signed with
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    /// Only events about these contacts; every contact when empty
+    pub contact_ids: Vec<String>,
+    pub created_at: OffsetDateTime,
+}
+
+impl std::fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Webhook")
+            .field("id", &self.id)
+            .field("url", &self.url)
+            .field("secret", &"<redacted>")
+            .field("events", &self.events)
+            .field("contact_ids", &self.contact_ids)
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
+/// What a webhook is posted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    /// Unique per event, for receivers to drop repeats
+    pub id: String,
+    pub event: WebhookEvent,
+    /// Unix time
+    pub timestamp: i64,
+    /// The contact, or for requests the sender, the event is about
+    pub contact_id: String,
+    pub data: serde_json::Value,
+}
+
+/// A message a program wants us to send
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InboundMessage {
+    pub contact_id: String,
+    pub text: String,
+}
+
+/// Posts payloads to webhook URLs. Calls may block; they are made off
+/// the async runtime.
+pub trait WebhookSender: Send + Sync {
+    /// POST `body`, JSON, to `url` with `headers` added
+    fn post(&self, url: &str, headers: &[(&str, String)], body: &[u8]) -> Result<()>;
+}
+
+/// A fresh random secret
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(secret)
+}
+
+impl Webhook {
+    pub fn new(url: &str, events: Vec<WebhookEvent>, contact_ids: Vec<String>) -> Result<Self> {
+        let webhook = Self {
+            id: crate::protocol::generate_id(),
+            url: url.trim().to_string(),
+            secret: generate_secret(),
+            events,
+            contact_ids,
+            created_at: OffsetDateTime::now_utc(),
+        };
+        webhook.validate()?;
+        Ok(webhook)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.url.len() > MAX_URL_LEN {
+            return Err(anyhow::anyhow!("Webhook URL is longer than {} bytes", MAX_URL_LEN));
+        }
+        // Payloads hold message text
+        if !self.url.starts_with("https://") || self.url.len() == "https://".len() {
+            return Err(anyhow::anyhow!("Webhooks must be https URLs"));
+        }
+        if self.events.is_empty() {
+            return Err(anyhow::anyhow!("A webhook needs at least one event"));
+        }
+        Ok(())
+    }
+
+    pub fn wants(&self, payload: &WebhookPayload) -> bool {
+        self.events.contains(&payload.event)
+            && (self.contact_ids.is_empty() || self.contact_ids.contains(&payload.contact_id))
+    }
+}
+
+impl WebhookPayload {
+    /// The payload for `event`, if webhooks can ask for its kind
+    pub fn from_event(event: &ChatEvent) -> Option<Self> {
+        let (event, contact_id, data) = match event {
+            ChatEvent::MessageReceived { conversation_id, message } => (
+                WebhookEvent::MessageReceived,
+                message.sender_id.clone(),
+                serde_json::json!({
+                    "conversation_id": conversation_id,
+                    "message_id": message.id,
+                    "text": message.preview_text(),
+                }),
+            ),
+            ChatEvent::ContactRequestReceived { contact_id, display_name, message, fingerprint_hint, .. } => (
+                WebhookEvent::ContactRequest,
+                contact_id.clone(),
+                serde_json::json!({
+                    "display_name": display_name,
+                    "message": message,
+                    "fingerprint_hint": fingerprint_hint,
+                }),
+            ),
+            ChatEvent::MessageRequestReceived { sender_id } => (
+                WebhookEvent::MessageRequest,
+                sender_id.clone(),
+                serde_json::json!({}),
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            id: crate::protocol::generate_id(),
+            event,
+            timestamp: OffsetDateTime::now_utc().unix_timestamp(),
+            contact_id,
+            data,
+        })
+    }
+}
+
+/// Sign and post `payload` to `webhook`
+pub fn deliver(sender: &dyn WebhookSender, webhook: &Webhook, payload: &WebhookPayload) -> Result<()> {
+    let body = serde_json::to_vec(payload)?;
+    let signature = sign(&webhook.secret, payload.timestamp, &body);
+    sender.post(&webhook.url, &[(SIGNATURE_HEADER, signature)], &body)
+}
+
+/// Value of the signature header for `body`, signed at `timestamp`
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = mac(secret, timestamp);
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    format!("t={},v1={}", timestamp, digest.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+/// Check a signature header made by `sign` at most
+/// `SIGNATURE_TOLERANCE` from `now`
+pub fn verify(secret: &str, header: &str, body: &[u8], now: OffsetDateTime) -> Result<()> {
+    verify_parts(secret, header, body, now).map(|_| ())
+}
+
+/// Verify and parse a request to send a message, rejecting a signature
+/// `replays` has already seen
+pub fn parse_inbound(secret: &str, header: &str, body: &[u8], now: OffsetDateTime, replays: &ReplayGuard) -> Result<InboundMessage> {
+    if body.len() > MAX_INBOUND_LEN {
+        return Err(anyhow::anyhow!("Request is larger than {} bytes", MAX_INBOUND_LEN));
+    }
+    let (timestamp, signature) = verify_parts(secret, header, body, now)?;
+    let request = serde_json::from_slice(body).context("Malformed request")?;
+    replays.accept(timestamp, signature, now)?;
+    Ok(request)
+}
+
+/// Signatures accepted within the last `SIGNATURE_TOLERANCE`, so a
+/// captured request can't be sent again while its time still passes
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    seen: Mutex<HashMap<Vec<u8>, i64>>,
+}
+
+impl ReplayGuard {
+    /// Record a verified signature, failing if it was accepted before
+    fn accept(&self, timestamp: i64, signature: Vec<u8>, now: OffsetDateTime) -> Result<()> {
+        let mut seen = self.seen.lock().map_err(|_| anyhow::anyhow!("Poisoned"))?;
+        // Anything older fails the time check anyway
+        seen.retain(|_, seen_at| now.unix_timestamp().abs_diff(*seen_at) <= SIGNATURE_TOLERANCE.as_secs());
+        if seen.contains_key(&signature) {
+            return Err(anyhow::anyhow!("Request was already handled"));
+        }
+        seen.insert(signature, timestamp);
+        Ok(())
+    }
+}
+
+/// The time and MAC of a valid signature header
+fn verify_parts(secret: &str, header: &str, body: &[u8], now: OffsetDateTime) -> Result<(i64, Vec<u8>)> {
+    let mut timestamp = None;
+    let mut signature = None;
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signature = decode_hex(value),
+            _ => {}
+        }
+    }
+    let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+        return Err(anyhow::anyhow!("Malformed signature header"));
+    };
+    if now.unix_timestamp().abs_diff(timestamp) > SIGNATURE_TOLERANCE.as_secs() {
+        return Err(anyhow::anyhow!("Signature is too old or too new"));
+    }
+    let mut mac = mac(secret, timestamp);
+    mac.update(body);
+    mac.verify_slice(&signature).map_err(|_| anyhow::anyhow!("Invalid signature"))?;
+    Ok((timestamp, signature))
+}
+
+/// HMAC over "<timestamp>." and then the body
+fn mac(secret: &str, timestamp: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Sender that only records posts, for tests
+#[derive(Debug, Default)]
+pub struct MemoryWebhookSender {
+    posts: Mutex<Vec<(String, String, Vec<u8>)>>,
+}
+
+impl MemoryWebhookSender {
+    /// Every post so far, as URL, signature header and body
+    pub fn posts(&self) -> Vec<(String, String, Vec<u8>)> {
+        self.posts.lock().map(|posts| posts.clone()).unwrap_or_default()
+    }
+}
+
+impl WebhookSender for MemoryWebhookSender {
+    fn post(&self, url: &str, headers: &[(&str, String)], body: &[u8]) -> Result<()> {
+        let signature = headers.iter()
+            .find(|(name, _)| *name == SIGNATURE_HEADER)
+            .map(|(_, value)| value.clone())
+            .unwrap_or_default();
+        self.posts.lock()
+            .map_err(|_| anyhow::anyhow!("Poisoned"))?
+            .push((url.to_string(), signature, body.to_vec()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signatures() {
+        let now = OffsetDateTime::now_utc();
+        let header = sign("secret", now.unix_timestamp(), b"{}");
+        assert!(verify("secret", &header, b"{}", now).is_ok());
+        assert!(verify("other", &header, b"{}", now).is_err());
+        assert!(verify("secret", &header, b"{ }", now).is_err());
+        assert!(verify("secret", &header, b"{}", now + SIGNATURE_TOLERANCE + Duration::from_secs(1)).is_err());
+        assert!(verify("secret", "v1=00", b"{}", now).is_err());
+
+        let body = br#"{"contact_id":"c1","text":"hi"}"#;
+        let header = sign("secret", now.unix_timestamp(), body);
+        let replays = ReplayGuard::default();
+        let inbound = parse_inbound("secret", &header, body, now, &replays).unwrap();
+        assert_eq!(inbound, InboundMessage { contact_id: "c1".to_string(), text: "hi".to_string() });
+        assert!(parse_inbound("secret", &header, body, now, &replays).is_err());
+        // Forgotten once the signature would be too old anyway
+        let later = now + SIGNATURE_TOLERANCE + Duration::from_secs(1);
+        assert!(parse_inbound("secret", &sign("secret", later.unix_timestamp(), body), body, later, &replays).is_ok());
+        assert_eq!(replays.seen.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_filters() {
+        assert!(Webhook::new("http://example.org/hook", vec![WebhookEvent::MessageReceived], Vec::new()).is_err());
+        assert!(Webhook::new("https://example.org/hook", Vec::new(), Vec::new()).is_err());
+        let webhook = Webhook::new("https://example.org/hook", vec![WebhookEvent::MessageRequest], vec!["c1".to_string()]).unwrap();
+        assert!(!format!("{:?}", webhook).contains(&webhook.secret));
+
+        let payload = WebhookPayload::from_event(&ChatEvent::MessageRequestReceived { sender_id: "c1".to_string() }).unwrap();
+        assert!(webhook.wants(&payload));
+        assert!(!webhook.wants(&WebhookPayload { contact_id: "c2".to_string(), ..payload.clone() }));
+        assert!(!webhook.wants(&WebhookPayload { event: WebhookEvent::ContactRequest, ..payload }));
+        assert!(WebhookPayload::from_event(&ChatEvent::SyncCompleted).is_none());
+    }
+}
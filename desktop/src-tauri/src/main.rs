@@ -1,9 +1,10 @@
 // Prevents additional console window on Windows in release
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use securechat_core::{SecureChat, ChatEvent, protocol::{Contact, Conversation, LocalMessage, UserProfile}};
+use securechat_core::{SecureChat, ChatEvent, activity::ActivityItem, audit::SecurityEvent, backup::{BackupManifest, RestoreSelection}, bootstrap::BootstrapNode, broadcast::{Broadcast, BroadcastList, BroadcastStatus}, card::IssuedCard, cache::CacheStats, cancel::CancellationToken, conditions::NetworkConditions, devices::{Authentication, RemoteDevice, SessionInfo, SessionRequirements}, durability::Durability, escrow::BackupKeyInfo, events::EventReplay, health::HealthReport, importer::{ImportFormat, ImportReport, PendingContact}, integrity::IntegrityReport, lifecycle::{ResumeReport, SuspendOptions}, limits::ProtocolLimits, names::ContactName, network::{NetworkStatus, PeerStats}, operations::OperationHandle, plugins::Annotation, push::PushEndpoint, requests::MessageRequest, query::{ConversationPage, ConversationQuery, FolderGroup}, retention::RetentionPolicy, search::SearchResult, startup::CacheWarming, stats::{ConversationStats, DayCount}, usage::{CompactionReport, UsageReport}, protocol::{ChatFolder, ColorTag, Contact, ContactLabel, ContactNote, ContactSettings, Conversation, ConversationPolicies, ConversationPolicy, LocalMessage, MessageContent, UserProfile}, storage::{DuressAction, DuressPassword}, telemetry::TelemetryConfig, thumbnail::Thumbnail, transparency::KeyLogReport};
+use std::collections::HashMap;
 use std::sync::Arc;
-use tauri::{State, Manager, Window};
+use tauri::{ClipboardManager, State, Manager, Window};
 use tokio::sync::{Mutex, mpsc};
 use anyhow::Result;
 
@@ -11,6 +12,8 @@ use anyhow::Result;
 struct AppState {
     chat: Arc<Mutex<Option<SecureChat>>>,
     event_tx: Mutex<Option<mpsc::Sender<AppEvent>>>,
+    /// Running operations by id, for `cancel_operation`
+    operations: Arc<Mutex<HashMap<String, CancellationToken>>>,
 }
 
 #[derive(Clone)]
@@ -27,14 +30,22 @@ async fn create_account(
     state: State<'_, AppState>,
     password: String,
     display_name: String,
+    duress_password: Option<String>,
+    duress_wipe: Option<bool>,
     window: Window,
 ) -> Result<bool, String> {
     let data_dir = get_data_dir()?;
     std::fs::create_dir_all(&data_dir).map_err(|e| e.to_string())?;
     let db_path = data_dir.join("securechat.db");
     
+    let duress = duress_password.filter(|p| !p.is_empty()).map(|password| DuressPassword {
+        password,
+        action: if duress_wipe.unwrap_or(false) { DuressAction::WipeData } else { DuressAction::OpenDecoy },
+        display_name: display_name.clone(),
+    });
+    
     let chat = SecureChat::new(None);
-    match chat.create_account(&db_path, &password, &display_name).await {
+    match chat.create_account_with_duress(&db_path, &password, &display_name, duress.as_ref()).await {
         Ok(_) => {
             *state.chat.lock().await = Some(chat);
             
@@ -63,7 +74,22 @@ async fn unlock_account(
     }
     
     let chat = SecureChat::new(None);
-    match chat.unlock_account(&db_path, &password).await {
+    chat.set_cache_warming(Some(CacheWarming::default())).await;
+    
+    // Show each step of the unlock as it finishes
+    let mut progress = chat.subscribe_from(1);
+    let progress_window = window.clone();
+    let forward = tauri::async_runtime::spawn(async move {
+        while let Some(sequenced) = progress.recv().await {
+            if let ChatEvent::UnlockProgress { .. } = sequenced.event {
+                emit_event(&progress_window, &sequenced.event);
+            }
+        }
+    });
+    let unlocked = chat.unlock_account(&db_path, &password).await;
+    forward.abort();
+    
+    match unlocked {
         Ok(_) => {
             *state.chat.lock().await = Some(chat);
             
@@ -78,6 +104,75 @@ async fn unlock_account(
     }
 }
 
+/// Like `create_account`, returning the recovery phrase to show once
+#[tauri::command]
+async fn create_account_with_recovery(
+    state: State<'_, AppState>,
+    password: String,
+    display_name: String,
+    window: Window,
+) -> Result<String, String> {
+    let data_dir = get_data_dir()?;
+    std::fs::create_dir_all(&data_dir).map_err(|e| e.to_string())?;
+    let db_path = data_dir.join("securechat.db");
+    
+    let chat = SecureChat::new(None);
+    let phrase = chat.create_account_with_recovery(&db_path, &password, &display_name, None).await
+        .map_err(|e| e.to_string())?;
+    *state.chat.lock().await = Some(chat);
+    start_event_listener(&state, window).await?;
+    Ok(phrase.phrase())
+}
+
+#[tauri::command]
+async fn verify_recovery_phrase(state: State<'_, AppState>, phrase: String) -> Result<bool, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.verify_recovery_phrase(&phrase).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn recover_account(
+    state: State<'_, AppState>,
+    phrase: String,
+    password: String,
+    display_name: String,
+    window: Window,
+) -> Result<bool, String> {
+    let data_dir = get_data_dir()?;
+    std::fs::create_dir_all(&data_dir).map_err(|e| e.to_string())?;
+    let db_path = data_dir.join("securechat.db");
+    
+    let chat = SecureChat::new(None);
+    chat.recover_account(&db_path, &phrase, &password, &display_name).await
+        .map_err(|e| e.to_string())?;
+    *state.chat.lock().await = Some(chat);
+    start_event_listener(&state, window).await?;
+    Ok(true)
+}
+
+#[tauri::command]
+async fn reset_password(
+    state: State<'_, AppState>,
+    phrase: String,
+    new_password: String,
+    window: Window,
+) -> Result<bool, String> {
+    let data_dir = get_data_dir()?;
+    let db_path = data_dir.join("securechat.db");
+    
+    if !db_path.exists() {
+        return Err("No account found. Please create one first.".to_string());
+    }
+    
+    let chat = SecureChat::new(None);
+    chat.reset_password(&db_path, &phrase, &new_password).await
+        .map_err(|e| e.to_string())?;
+    *state.chat.lock().await = Some(chat);
+    start_event_listener(&state, window).await?;
+    Ok(true)
+}
+
 #[tauri::command]
 async fn has_account() -> Result<bool, String> {
     let data_dir = get_data_dir()?;
@@ -103,6 +198,50 @@ async fn get_messages(
     chat.get_messages(&conversation_id, limit).await.map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+async fn get_conversation_stats(
+    state: State<'_, AppState>,
+    conversation_id: String,
+) -> Result<ConversationStats, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_conversation_stats(&conversation_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_message_count_by_day(
+    state: State<'_, AppState>,
+    conversation_id: String,
+) -> Result<Vec<DayCount>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_message_count_by_day(&conversation_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_message_at_date(
+    state: State<'_, AppState>,
+    conversation_id: String,
+    unix_time: i64,
+) -> Result<Option<LocalMessage>, String> {
+    let date = time::OffsetDateTime::from_unix_timestamp(unix_time).map_err(|e| e.to_string())?;
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_message_at_date(&conversation_id, date).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_messages_page(
+    state: State<'_, AppState>,
+    conversation_id: String,
+    before_id: Option<String>,
+    limit: usize,
+) -> Result<Vec<LocalMessage>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_messages_page(&conversation_id, before_id.as_deref(), limit).await.map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 async fn send_text_message(
     state: State<'_, AppState>,
@@ -114,6 +253,70 @@ async fn send_text_message(
     chat.send_text_message(&conversation_id, &text).await.map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+async fn send_image(
+    state: State<'_, AppState>,
+    conversation_id: String,
+    data: Vec<u8>,
+    mime_type: String,
+    caption: Option<String>,
+) -> Result<String, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.send_image(&conversation_id, &data, &mime_type, caption.as_deref()).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn send_view_once_image(
+    state: State<'_, AppState>,
+    conversation_id: String,
+    data: Vec<u8>,
+    mime_type: String,
+) -> Result<String, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.send_view_once_image(&conversation_id, &data, &mime_type).await.map_err(|e| e.to_string())
+}
+
+/// The media of a view-once message, which is deleted once returned
+#[tauri::command]
+async fn open_view_once(state: State<'_, AppState>, message_id: String) -> Result<Vec<u8>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.open_view_once(&message_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_thumbnail(
+    state: State<'_, AppState>,
+    message_id: String,
+) -> Result<Option<Thumbnail>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_thumbnail(&message_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_message_annotations(state: State<'_, AppState>, message_id: String) -> Result<Vec<Annotation>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_message_annotations(&message_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn resend_message(state: State<'_, AppState>, message_id: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.resend_message(&message_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn cancel_pending_message(state: State<'_, AppState>, message_id: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.cancel_pending_message(&message_id).await.map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 async fn get_contacts(state: State<'_, AppState>) -> Result<Vec<Contact>, String> {
     let chat_guard = state.chat.lock().await;
@@ -141,115 +344,1184 @@ async fn add_contact(
 }
 
 #[tauri::command]
-async fn get_or_create_conversation(
+async fn import_external_contacts(
     state: State<'_, AppState>,
-    contact_id: String,
-) -> Result<Conversation, String> {
+    data: Vec<u8>,
+    format: ImportFormat,
+) -> Result<ImportReport, String> {
     let chat_guard = state.chat.lock().await;
     let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
-    chat.get_or_create_conversation(&contact_id).await.map_err(|e| e.to_string())
+    chat.import_external_contacts(&data, format).await.map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-async fn get_profile(state: State<'_, AppState>) -> Result<Option<UserProfile>, String> {
+async fn get_pending_contacts(state: State<'_, AppState>) -> Result<Vec<PendingContact>, String> {
     let chat_guard = state.chat.lock().await;
     let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
-    chat.get_profile().await.map_err(|e| e.to_string())
+    chat.get_pending_contacts().await.map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-async fn update_profile(
+async fn add_pending_contact(
     state: State<'_, AppState>,
-    display_name: Option<String>,
-    status_message: Option<String>,
+    pending_id: String,
+    public_key: Vec<u8>,
+) -> Result<Contact, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    let public_key: [u8; 32] = public_key.try_into()
+        .map_err(|_| "Invalid public key length".to_string())?;
+    chat.add_pending_contact(&pending_id, public_key).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_pending_contact(state: State<'_, AppState>, pending_id: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.delete_pending_contact(&pending_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_default_retention(state: State<'_, AppState>) -> Result<RetentionPolicy, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_default_retention().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_default_retention(state: State<'_, AppState>, policy: RetentionPolicy) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_default_retention(policy).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_conversation_retention(
+    state: State<'_, AppState>,
+    conversation_id: String,
+) -> Result<Option<RetentionPolicy>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_conversation_retention(&conversation_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_conversation_retention(
+    state: State<'_, AppState>,
+    conversation_id: String,
+    policy: Option<RetentionPolicy>,
 ) -> Result<(), String> {
     let chat_guard = state.chat.lock().await;
     let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
-    chat.update_profile(display_name.as_deref(), status_message.as_deref()).await.map_err(|e| e.to_string())
+    chat.set_conversation_retention(&conversation_id, policy).await.map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-async fn get_public_key(state: State<'_, AppState>) -> Result<Vec<u8>, String> {
+async fn set_conversation_policy(
+    state: State<'_, AppState>,
+    conversation_id: String,
+    policy: ConversationPolicy,
+) -> Result<(), String> {
     let chat_guard = state.chat.lock().await;
     let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
-    chat.get_public_key().await.map_err(|e| e.to_string()).map(|k| k.to_vec())
+    chat.set_conversation_policy(&conversation_id, policy).await.map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-async fn start_network(state: State<'_, AppState>) -> Result<(), String> {
-    use securechat_core::network::NetworkConfig;
-    
+async fn get_conversation_policy(state: State<'_, AppState>, conversation_id: String) -> Result<ConversationPolicies, String> {
     let chat_guard = state.chat.lock().await;
     let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
-    
-    let config = NetworkConfig::default();
-    chat.start_network(config).await.map_err(|e| e.to_string())?;
-    
-    Ok(())
+    chat.get_conversation_policy(&conversation_id).await.map_err(|e| e.to_string())
 }
 
-// Helper functions
+#[tauri::command]
+async fn set_message_authentication(state: State<'_, AppState>, conversation_id: String, authentication: Authentication) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_message_authentication(&conversation_id, authentication).await.map_err(|e| e.to_string())
+}
 
-fn get_data_dir() -> Result<std::path::PathBuf, String> {
-    let dirs = directories::ProjectDirs::from("com", "securechat", "SecureChat")
-        .ok_or("Failed to get project directories")?;
-    Ok(dirs.data_dir().to_path_buf())
+#[tauri::command]
+async fn get_message_authentication(state: State<'_, AppState>, conversation_id: String) -> Result<Authentication, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_message_authentication(&conversation_id).await.map_err(|e| e.to_string())
 }
 
-async fn start_event_listener(state: &AppState, window: Window) -> Result<(), String> {
+#[tauri::command]
+async fn export_conversation(state: State<'_, AppState>, conversation_id: String) -> Result<String, String> {
     let chat_guard = state.chat.lock().await;
     let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
-    
-    use securechat_core::network::NetworkConfig;
-    let config = NetworkConfig::default();
-    let mut event_rx = chat.start_network(config).await.map_err(|e| e.to_string())?;
-    
-    // Spawn event handler
-    tauri::async_runtime::spawn(async move {
-        while let Some(event) = event_rx.recv().await {
-            let event_name = match &event {
-                ChatEvent::MessageReceived { .. } => "message-received",
-                ChatEvent::MessageSent { .. } => "message-sent",
-                ChatEvent::MessageDelivered { .. } => "message-delivered",
-                ChatEvent::MessageRead { .. } => "message-read",
-                ChatEvent::ContactOnline { .. } => "contact-online",
-                ChatEvent::ContactOffline { .. } => "contact-offline",
-                ChatEvent::ContactRequestReceived { .. } => "contact-request",
-                ChatEvent::SyncCompleted => "sync-completed",
-                ChatEvent::Error { .. } => "error",
-            };
-            
-            if let Err(e) = window.emit(event_name, &event) {
-                log::error!("Failed to emit event: {}", e);
-            }
-        }
-    });
-    
-    Ok(())
+    chat.export_conversation(&conversation_id).await.map_err(|e| e.to_string())
 }
 
-fn main() {
-    let state = AppState {
-        chat: Arc::new(Mutex::new(None)),
-        event_tx: Mutex::new(None),
-    };
+#[tauri::command]
+async fn get_storage_usage(state: State<'_, AppState>) -> Result<UsageReport, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.storage_usage().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn compact_storage(state: State<'_, AppState>) -> Result<CompactionReport, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.compact_storage().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_durability(state: State<'_, AppState>) -> Result<Durability, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    Ok(chat.durability().await)
+}
+
+#[tauri::command]
+async fn set_durability(state: State<'_, AppState>, settings: Durability) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_durability(settings).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_protocol_limits(state: State<'_, AppState>) -> Result<ProtocolLimits, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    Ok(chat.protocol_limits().await)
+}
+
+#[tauri::command]
+async fn set_protocol_limits(state: State<'_, AppState>, limits: ProtocolLimits) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_protocol_limits(limits).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn flush_storage(state: State<'_, AppState>) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.flush().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn register_push_endpoint(state: State<'_, AppState>, url: String) -> Result<PushEndpoint, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.register_push_endpoint(chat.device_id(), &url).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn unregister_push_endpoint(state: State<'_, AppState>) -> Result<bool, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.unregister_push_endpoint(chat.device_id()).await.map_err(|e| e.to_string())
+}
 
+#[tauri::command]
+async fn get_contact_devices(state: State<'_, AppState>, contact_id: String) -> Result<Vec<RemoteDevice>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_contact_devices(&contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_session_info(state: State<'_, AppState>, contact_id: String) -> Result<SessionInfo, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_session_info(&contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_session_requirements(
+    state: State<'_, AppState>,
+    contact_id: String,
+    requirements: SessionRequirements,
+) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_session_requirements(&contact_id, requirements).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_session_requirements(state: State<'_, AppState>, contact_id: String) -> Result<SessionRequirements, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_session_requirements(&contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn suspend(state: State<'_, AppState>, park_network: bool) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.on_suspend(SuspendOptions { park_network }).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn resume(state: State<'_, AppState>) -> Result<ResumeReport, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.on_resume().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_cache_stats(state: State<'_, AppState>) -> Result<CacheStats, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.cache_stats().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn export_diagnostics(state: State<'_, AppState>) -> Result<Vec<u8>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.export_diagnostics().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_health(state: State<'_, AppState>) -> Result<HealthReport, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    Ok(chat.health().await)
+}
+
+#[tauri::command]
+async fn replay_events(state: State<'_, AppState>, from_seq: u64) -> Result<EventReplay, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    Ok(chat.replay_events(from_seq))
+}
+
+/// Export a backup in the background. Progress comes as
+/// `operation-progress` events with the returned id.
+#[tauri::command]
+async fn start_backup_export(state: State<'_, AppState>, path: String, password: String) -> Result<String, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    let export = chat.start_backup_export(path.into(), &password).await.map_err(|e| e.to_string())?;
+    Ok(track_operation(&state, export).await)
+}
+
+/// What a backup file holds, to choose what to restore
+#[tauri::command]
+async fn inspect_backup(state: State<'_, AppState>, path: String, password: String) -> Result<BackupManifest, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.inspect_backup(std::path::Path::new(&path), &password).await.map_err(|e| e.to_string())
+}
+
+/// Numbers of the backup's damaged chunks
+#[tauri::command]
+async fn verify_backup(state: State<'_, AppState>, path: String, password: String) -> Result<Vec<usize>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.verify_backup(std::path::Path::new(&path), &password).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn restore_backup_partial(state: State<'_, AppState>, path: String, password: String, selection: RestoreSelection) -> Result<usize, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.restore_backup_partial(std::path::Path::new(&path), &password, &selection).await.map_err(|e| e.to_string())
+}
+
+/// This account's backup key as a QR link for another of our devices,
+/// with the confirmation code to show beside it
+#[tauri::command]
+async fn offer_backup_key(state: State<'_, AppState>, valid_minutes: u64) -> Result<(String, String), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    let (offer, code) = chat.offer_backup_key(std::time::Duration::from_secs(valid_minutes * 60)).await
+        .map_err(|e| e.to_string())?;
+    Ok((offer.to_link().map_err(|e| e.to_string())?, code))
+}
+
+#[tauri::command]
+async fn accept_backup_key(state: State<'_, AppState>, link: String, code: String) -> Result<BackupKeyInfo, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.accept_backup_key(&link, &code).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_escrowed_backup_keys(state: State<'_, AppState>) -> Result<Vec<BackupKeyInfo>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_escrowed_backup_keys().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn remove_escrowed_backup_key(state: State<'_, AppState>, key_id: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.remove_escrowed_backup_key(&key_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn start_reindex(state: State<'_, AppState>) -> Result<String, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    let reindex = chat.start_reindex().await.map_err(|e| e.to_string())?;
+    Ok(track_operation(&state, reindex).await)
+}
+
+/// Whether an operation with this id was running
+#[tauri::command]
+async fn cancel_operation(state: State<'_, AppState>, id: String) -> Result<bool, String> {
+    let operations = state.operations.lock().await;
+    Ok(operations.get(&id).map(CancellationToken::cancel).is_some())
+}
+
+/// Keep an operation cancellable by id until it ends
+async fn track_operation<T: Send + 'static>(state: &AppState, operation: OperationHandle<T>) -> String {
+    let id = operation.id();
+    let operations = state.operations.clone();
+    operations.lock().await.insert(id.clone(), operation.cancellation_token());
+    let finished = id.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = operation.wait().await {
+            log::error!("Operation {} failed: {}", finished, e);
+        }
+        operations.lock().await.remove(&finished);
+    });
+    id
+}
+
+#[tauri::command]
+async fn verify_integrity(state: State<'_, AppState>, quarantine: bool) -> Result<IntegrityReport, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.verify_integrity(quarantine).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_contact_settings(
+    state: State<'_, AppState>,
+    contact_id: String,
+) -> Result<ContactSettings, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_contact_settings(&contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_contact_name(state: State<'_, AppState>, contact_id: String) -> Result<ContactName, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_contact_name(&contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_contact_nickname(
+    state: State<'_, AppState>,
+    contact_id: String,
+    nickname: Option<String>,
+) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_contact_nickname(&contact_id, nickname.as_deref()).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_contact_note(state: State<'_, AppState>, contact_id: String) -> Result<Option<ContactNote>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_contact_note(&contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_contact_note(state: State<'_, AppState>, contact_id: String, text: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_contact_note(&contact_id, &text).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_contact_note(state: State<'_, AppState>, contact_id: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.delete_contact_note(&contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn search_contact_notes(state: State<'_, AppState>, query: String) -> Result<Vec<ContactNote>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.search_contact_notes(&query).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn search(
+    state: State<'_, AppState>,
+    query: String,
+    limit: usize,
+) -> Result<Vec<SearchResult>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.search(&query, limit).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_contact_color(
+    state: State<'_, AppState>,
+    contact_id: String,
+    color: Option<ColorTag>,
+) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_contact_color(&contact_id, color).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_labels(state: State<'_, AppState>) -> Result<Vec<ContactLabel>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_labels().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn create_label(
+    state: State<'_, AppState>,
+    name: String,
+    color: Option<ColorTag>,
+) -> Result<ContactLabel, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.create_label(&name, color).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn rename_label(state: State<'_, AppState>, label_id: String, name: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.rename_label(&label_id, &name).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_label_color(
+    state: State<'_, AppState>,
+    label_id: String,
+    color: Option<ColorTag>,
+) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_label_color(&label_id, color).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_label(state: State<'_, AppState>, label_id: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.delete_label(&label_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn add_contact_to_label(
+    state: State<'_, AppState>,
+    label_id: String,
+    contact_id: String,
+) -> Result<bool, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.add_contact_to_label(&label_id, &contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn remove_contact_from_label(
+    state: State<'_, AppState>,
+    label_id: String,
+    contact_id: String,
+) -> Result<bool, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.remove_contact_from_label(&label_id, &contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_contacts_with_label(state: State<'_, AppState>, label_id: String) -> Result<Vec<Contact>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_contacts_with_label(&label_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_conversations_with_label(state: State<'_, AppState>, label_id: String) -> Result<Vec<Conversation>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_conversations_with_label(&label_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_folders(state: State<'_, AppState>) -> Result<Vec<ChatFolder>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_folders().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn create_folder(state: State<'_, AppState>, name: String) -> Result<ChatFolder, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.create_folder(&name).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn rename_folder(state: State<'_, AppState>, folder_id: String, name: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.rename_folder(&folder_id, &name).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_folder(state: State<'_, AppState>, folder_id: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.delete_folder(&folder_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn reorder_folders(state: State<'_, AppState>, folder_ids: Vec<String>) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.reorder_folders(&folder_ids).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_conversation_folder(
+    state: State<'_, AppState>,
+    conversation_id: String,
+    folder_id: Option<String>,
+) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_conversation_folder(&conversation_id, folder_id.as_deref()).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_conversations_in_folder(state: State<'_, AppState>, folder_id: String) -> Result<Vec<Conversation>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_conversations_in_folder(&folder_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_conversations_by_folder(state: State<'_, AppState>) -> Result<Vec<FolderGroup>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_conversations_by_folder().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn send_contact_request(state: State<'_, AppState>, message: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.send_contact_request(&message).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_contact_request_difficulty(state: State<'_, AppState>) -> Result<u8, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_contact_request_difficulty().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_contact_request_difficulty(state: State<'_, AppState>, difficulty: u8) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_contact_request_difficulty(difficulty).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_message_requests(state: State<'_, AppState>) -> Result<Vec<MessageRequest>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_message_requests().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn accept_message_request(
+    state: State<'_, AppState>,
+    sender_id: String,
+    display_name: String,
+) -> Result<Contact, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.accept_message_request(&sender_id, &display_name).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn decline_message_request(state: State<'_, AppState>, sender_id: String, block: bool) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.decline_message_request(&sender_id, block).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn unblock_sender(state: State<'_, AppState>, sender_id: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.unblock_sender(&sender_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_broadcast_lists(state: State<'_, AppState>) -> Result<Vec<BroadcastList>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_broadcast_lists().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn create_broadcast_list(
+    state: State<'_, AppState>,
+    name: String,
+    contact_ids: Vec<String>,
+) -> Result<BroadcastList, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.create_broadcast_list(&name, &contact_ids).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn rename_broadcast_list(state: State<'_, AppState>, list_id: String, name: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.rename_broadcast_list(&list_id, &name).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_broadcast_list(state: State<'_, AppState>, list_id: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.delete_broadcast_list(&list_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn add_broadcast_member(state: State<'_, AppState>, list_id: String, contact_id: String) -> Result<bool, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.add_broadcast_member(&list_id, &contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn remove_broadcast_member(state: State<'_, AppState>, list_id: String, contact_id: String) -> Result<bool, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.remove_broadcast_member(&list_id, &contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn send_broadcast_text(state: State<'_, AppState>, list_id: String, text: String) -> Result<Broadcast, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.send_broadcast(&list_id, MessageContent::Text { text }).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_broadcasts(state: State<'_, AppState>, list_id: String) -> Result<Vec<Broadcast>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_broadcasts(&list_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_broadcast_status(state: State<'_, AppState>, broadcast_id: String) -> Result<BroadcastStatus, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_broadcast_status(&broadcast_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn query_conversations(state: State<'_, AppState>, query: ConversationQuery) -> Result<ConversationPage, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.query_conversations(&query).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_recent_activity(state: State<'_, AppState>, limit: usize) -> Result<Vec<ActivityItem>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_recent_activity(limit).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_contact_notification_sound(
+    state: State<'_, AppState>,
+    contact_id: String,
+    sound_id: Option<String>,
+) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_contact_notification_sound(&contact_id, sound_id.as_deref()).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_security_events(
+    state: State<'_, AppState>,
+    contact_id: String,
+) -> Result<Vec<SecurityEvent>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_security_events(&contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn create_invite(
+    state: State<'_, AppState>,
+    valid_hours: u64,
+    one_time: bool,
+) -> Result<String, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    let invite = chat.create_invite(std::time::Duration::from_secs(valid_hours * 3600), one_time).await
+        .map_err(|e| e.to_string())?;
+    invite.to_link().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn accept_invite(
+    state: State<'_, AppState>,
+    link: String,
+) -> Result<Contact, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.accept_invite(&link).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn start_pairing(state: State<'_, AppState>) -> Result<String, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.start_pairing().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn join_pairing(
+    state: State<'_, AppState>,
+    code: String,
+) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.join_pairing(&code).await.map(|_| ()).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_network_conditions(
+    state: State<'_, AppState>,
+    conditions: NetworkConditions,
+) -> Result<usize, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.set_network_conditions(conditions).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn start_history_sync(state: State<'_, AppState>) -> Result<bool, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.start_history_sync().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_or_create_conversation(
+    state: State<'_, AppState>,
+    contact_id: String,
+) -> Result<Conversation, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_or_create_conversation(&contact_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_self_conversation(state: State<'_, AppState>) -> Result<Conversation, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_self_conversation().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_profile(state: State<'_, AppState>) -> Result<Option<UserProfile>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_profile().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn update_profile(
+    state: State<'_, AppState>,
+    display_name: Option<String>,
+    status_message: Option<String>,
+) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.update_profile(display_name.as_deref(), status_message.as_deref()).await.map_err(|e| e.to_string())
+}
+
+/// A new contact card, as a link; a file of it imports too
+#[tauri::command]
+async fn create_contact_card(state: State<'_, AppState>, valid_days: u64) -> Result<String, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    let card = chat.create_contact_card(std::time::Duration::from_secs(valid_days * 86400)).await
+        .map_err(|e| e.to_string())?;
+    card.to_link().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_issued_cards(state: State<'_, AppState>) -> Result<Vec<IssuedCard>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_issued_cards().await.map_err(|e| e.to_string())
+}
+
+/// Revoke one of our cards, returning the revocation as a link to share
+#[tauri::command]
+async fn revoke_contact_card(state: State<'_, AppState>, card_id: String) -> Result<String, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    let revocation = chat.revoke_contact_card(&card_id).await.map_err(|e| e.to_string())?;
+    revocation.to_link().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn import_contact_card(state: State<'_, AppState>, data: Vec<u8>) -> Result<Contact, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.import_contact_card(&data).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn import_card_revocation(state: State<'_, AppState>, data: Vec<u8>) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.import_card_revocation(&data).await.map(|_| ()).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn export_key_log(state: State<'_, AppState>) -> Result<Vec<u8>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.export_key_log().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn verify_contact_key_log(state: State<'_, AppState>, contact_id: String, data: Vec<u8>) -> Result<KeyLogReport, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.verify_contact_key_log(&contact_id, &data).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_public_key(state: State<'_, AppState>) -> Result<Vec<u8>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_public_key().await.map_err(|e| e.to_string()).map(|k| k.to_vec())
+}
+
+/// Copy our armored identity block to the clipboard, returning it too
+#[tauri::command]
+async fn copy_identity_armor(app: tauri::AppHandle, state: State<'_, AppState>) -> Result<String, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    let armored = chat.export_identity_armor().await.map_err(|e| e.to_string())?;
+    app.clipboard_manager().write_text(armored.clone()).map_err(|e| e.to_string())?;
+    Ok(armored)
+}
+
+#[tauri::command]
+async fn add_contact_from_armor(state: State<'_, AppState>, text: String) -> Result<Contact, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.add_contact_from_armor(&text).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn start_network(state: State<'_, AppState>) -> Result<(), String> {
+    use securechat_core::network::NetworkConfig;
+    
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    
+    // Already started with the event listener at login
+    if chat.is_network_running().await {
+        return Ok(());
+    }
+    let config = NetworkConfig::default();
+    chat.start_network(config).await.map_err(|e| e.to_string())?;
+    
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_peer_stats(state: State<'_, AppState>) -> Result<Vec<PeerStats>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_peer_stats().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_network_status(state: State<'_, AppState>) -> Result<NetworkStatus, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    Ok(chat.get_network_status().await)
+}
+
+#[tauri::command]
+async fn get_bootstrap_nodes(state: State<'_, AppState>) -> Result<Vec<BootstrapNode>, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.get_bootstrap_nodes().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn add_bootstrap_node(
+    state: State<'_, AppState>,
+    address: String,
+) -> Result<BootstrapNode, String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.add_bootstrap_node(&address).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn remove_bootstrap_node(state: State<'_, AppState>, address: String) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.remove_bootstrap_node(&address).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn stop_network(state: State<'_, AppState>) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    chat.stop_network().await.map_err(|e| e.to_string())
+}
+
+// Helper functions
+
+fn get_data_dir() -> Result<std::path::PathBuf, String> {
+    let dirs = directories::ProjectDirs::from("com", "securechat", "SecureChat")
+        .ok_or("Failed to get project directories")?;
+    Ok(dirs.data_dir().to_path_buf())
+}
+
+async fn start_event_listener(state: &AppState, window: Window) -> Result<(), String> {
+    let chat_guard = state.chat.lock().await;
+    let chat = chat_guard.as_ref().ok_or("Not authenticated")?;
+    
+    use securechat_core::network::NetworkConfig;
+    let config = NetworkConfig::default();
+    chat.start_network(config).await.map_err(|e| e.to_string())?;
+    chat.start_retention_task().await;
+    chat.start_gc_sweep(false).await;
+    chat.start_key_maintenance().await;
+    
+    // Spawn event handler, from the first event so those reported while
+    // unlocking aren't lost
+    let mut subscription = chat.subscribe_from(1);
+    if subscription.missed > 0 {
+        log::warn!("Missed {} events", subscription.missed);
+    }
+    tauri::async_runtime::spawn(async move {
+        while let Some(sequenced) = subscription.recv().await {
+            emit_event(&window, &sequenced.event);
+        }
+    });
+    
+    Ok(())
+}
+
+fn emit_event(window: &Window, event: &ChatEvent) {
+    let event_name = match event {
+        ChatEvent::MessageReceived { .. } => "message-received",
+        ChatEvent::MessageStatusChanged { .. } => "message-status",
+        ChatEvent::ContactOnline { .. } => "contact-online",
+        ChatEvent::ContactOffline { .. } => "contact-offline",
+        ChatEvent::ContactRequestReceived { .. } => "contact-request",
+        ChatEvent::SyncCompleted => "sync-completed",
+        ChatEvent::BackupCompleted { .. } => "backup-completed",
+        ChatEvent::BackupFailed { .. } => "backup-failed",
+        ChatEvent::Error { .. } => "error",
+        ChatEvent::DecryptionFailed { .. } => "decryption-failed",
+        ChatEvent::PairingCompleted { .. } => "pairing-completed",
+        ChatEvent::PairingFailed { .. } => "pairing-failed",
+        ChatEvent::ContactUnreachable { .. } => "contact-unreachable",
+        ChatEvent::NetworkStatusChanged { .. } => "network-status",
+        ChatEvent::MessagesPruned { .. } => "messages-pruned",
+        ChatEvent::CompactionProgress { .. } => "compaction-progress",
+        ChatEvent::GarbageCollected { .. } => "garbage-collected",
+        ChatEvent::ContactDevicesChanged { .. } => "contact-devices-changed",
+        ChatEvent::MessageRequestReceived { .. } => "message-request",
+        ChatEvent::KeyMaterialCleaned { .. } => "key-material-cleaned",
+        ChatEvent::ConversationPolicyRequested { .. } => "conversation-policy-requested",
+        ChatEvent::EventsDropped { .. } => "events-dropped",
+        ChatEvent::SecurityEventsCoalesced { .. } => "security-events-coalesced",
+        ChatEvent::UnlockProgress { .. } => "unlock-progress",
+        ChatEvent::OperationCancelled { .. } => "operation-cancelled",
+        ChatEvent::OperationProgress { .. } => "operation-progress",
+        ChatEvent::SessionDowngradeRefused { .. } => "session-downgrade-refused",
+        ChatEvent::CapabilityMismatch { .. } => "capability-mismatch",
+    };
+    
+    if let Err(e) = window.emit(event_name, event) {
+        log::error!("Failed to emit event: {}", e);
+    }
+}
+
+fn main() {
+    if let Err(e) = SecureChat::init_telemetry(&TelemetryConfig::default()) {
+        eprintln!("Failed to set up logging: {}", e);
+    }
+
+    let state = AppState {
+        chat: Arc::new(Mutex::new(None)),
+        event_tx: Mutex::new(None),
+        operations: Arc::new(Mutex::new(HashMap::new())),
+    };
+    
     tauri::Builder::default()
         .manage(state)
         .invoke_handler(tauri::generate_handler![
             create_account,
             unlock_account,
+            create_account_with_recovery,
+            verify_recovery_phrase,
+            recover_account,
+            reset_password,
             has_account,
             get_conversations,
             get_messages,
+            get_conversation_stats,
+            get_message_count_by_day,
+            get_message_at_date,
+            get_messages_page,
             send_text_message,
+            send_image,
+            send_view_once_image,
+            open_view_once,
+            get_thumbnail,
+            get_message_annotations,
+            resend_message,
+            cancel_pending_message,
             get_contacts,
             add_contact,
+            import_external_contacts,
+            get_pending_contacts,
+            add_pending_contact,
+            delete_pending_contact,
+            get_default_retention,
+            set_default_retention,
+            get_conversation_retention,
+            set_conversation_retention,
+            set_conversation_policy,
+            get_conversation_policy,
+            set_message_authentication,
+            get_message_authentication,
+            export_conversation,
+            get_storage_usage,
+            compact_storage,
+            verify_integrity,
+            export_diagnostics,
+            get_health,
+            replay_events,
+            start_backup_export,
+            inspect_backup,
+            verify_backup,
+            restore_backup_partial,
+            offer_backup_key,
+            accept_backup_key,
+            get_escrowed_backup_keys,
+            remove_escrowed_backup_key,
+            start_reindex,
+            cancel_operation,
+            get_durability,
+            set_durability,
+            get_protocol_limits,
+            set_protocol_limits,
+            flush_storage,
+            get_cache_stats,
+            suspend,
+            resume,
+            register_push_endpoint,
+            unregister_push_endpoint,
+            get_contact_devices,
+            get_session_info,
+            set_session_requirements,
+            get_session_requirements,
+            get_contact_settings,
+            get_contact_name,
+            set_contact_nickname,
+            get_contact_note,
+            set_contact_note,
+            delete_contact_note,
+            search_contact_notes,
+            search,
+            set_contact_color,
+            get_labels,
+            create_label,
+            rename_label,
+            set_label_color,
+            delete_label,
+            add_contact_to_label,
+            remove_contact_from_label,
+            get_contacts_with_label,
+            get_conversations_with_label,
+            get_folders,
+            create_folder,
+            rename_folder,
+            delete_folder,
+            reorder_folders,
+            set_conversation_folder,
+            get_conversations_in_folder,
+            get_conversations_by_folder,
+            send_contact_request,
+            get_contact_request_difficulty,
+            set_contact_request_difficulty,
+            get_message_requests,
+            accept_message_request,
+            decline_message_request,
+            unblock_sender,
+            get_broadcast_lists,
+            create_broadcast_list,
+            rename_broadcast_list,
+            delete_broadcast_list,
+            add_broadcast_member,
+            remove_broadcast_member,
+            send_broadcast_text,
+            get_broadcasts,
+            get_broadcast_status,
+            query_conversations,
+            get_recent_activity,
+            set_contact_notification_sound,
+            get_security_events,
+            start_history_sync,
+            create_invite,
+            accept_invite,
+            create_contact_card,
+            get_issued_cards,
+            revoke_contact_card,
+            import_contact_card,
+            import_card_revocation,
+            export_key_log,
+            verify_contact_key_log,
+            start_pairing,
+            join_pairing,
+            set_network_conditions,
             get_or_create_conversation,
+            get_self_conversation,
             get_profile,
             update_profile,
             get_public_key,
+            copy_identity_armor,
+            add_contact_from_armor,
             start_network,
+            stop_network,
+            get_peer_stats,
+            get_network_status,
+            get_bootstrap_nodes,
+            add_bootstrap_node,
+            remove_bootstrap_node,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
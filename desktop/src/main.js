@@ -5,6 +5,7 @@ const { listen } = window.__TAURI__.event;
 let currentConversation = null;
 let conversations = [];
 let contacts = [];
+let unlockSteps = 0;
 
 // Initialize
 async function init() {
@@ -26,6 +27,12 @@ async function init() {
     }
   });
   
+  listen('message-status', (event) => {
+    if (currentConversation && event.payload.conversation_id === currentConversation.id) {
+      loadMessages(currentConversation.id);
+    }
+  });
+  
   listen('contact-online', (event) => {
     console.log('Contact online:', event);
     updateContactStatus(event.payload.contact_id, true);
@@ -36,6 +43,44 @@ async function init() {
     updateContactStatus(event.payload.contact_id, false);
   });
   
+  // Steps of an unlock as they finish
+  listen('unlock-progress', () => {
+    unlockSteps += 1;
+    unlockButton().textContent = `Unlocking (${Math.min(unlockSteps, 4)}/4)...`;
+  });
+  
+  // Progress bars for backups, transfers and other long operations
+  listen('operation-progress', (event) => {
+    renderOperationProgress(event.payload.progress);
+  });
+  
+  // Fell behind and lost some updates; reload what they would have changed
+  listen('events-dropped', () => {
+    loadConversations();
+    if (currentConversation) {
+      loadMessages(currentConversation.id);
+    }
+  });
+  
+  listen('pairing-completed', async (event) => {
+    await invoke('get_or_create_conversation', { contactId: event.payload.contact.id });
+    await loadConversations();
+    closeModal();
+  });
+  
+  listen('pairing-failed', (event) => {
+    showError('Pairing failed: ' + event.payload.reason);
+  });
+  
+  // Hold back traffic while the browser reports no connection
+  const reportConditions = () => {
+    invoke('set_network_conditions', {
+      conditions: { metered: false, offline: !navigator.onLine, low_power: false },
+    }).catch((e) => console.debug('Network conditions not sent:', e));
+  };
+  window.addEventListener('online', reportConditions);
+  window.addEventListener('offline', reportConditions);
+  
   listen('error', (event) => {
     console.error('Error:', event);
     showError(event.payload.message);
@@ -79,12 +124,18 @@ async function unlockAccount() {
     return;
   }
   
+  unlockSteps = 0;
   try {
     await invoke('unlock_account', { password });
     await startApp();
   } catch (e) {
     showError(e);
   }
+  unlockButton().textContent = 'Unlock';
+}
+
+function unlockButton() {
+  return document.querySelector('button[onclick="unlockAccount()"]');
 }
 
 async function startApp() {
@@ -200,7 +251,7 @@ async function selectConversation(conv) {
 
 async function loadMessages(conversationId) {
   try {
-    const messages = await invoke('get_messages', { conversationId, limit: 50 });
+    const messages = await invoke('get_messages_page', { conversationId, beforeId: null, limit: 50 });
     renderMessages(messages);
   } catch (e) {
     console.error('Failed to load messages:', e);
@@ -218,14 +269,19 @@ function renderMessages(messages) {
     const content = formatMessageContent(msg.content);
     const time = new Date(msg.timestamp).toLocaleTimeString([], { hour: '2-digit', minute: '2-digit' });
     const status = msg.is_outgoing ? getStatusIcon(msg) : '';
+    const forwarded = msg.forwarded_from ? '<div class="message-forwarded">↪ Forwarded</div>' : '';
     
     messageEl.innerHTML = `
+      ${forwarded}
       ${content}
       <div class="message-meta">
         ${time}
         ${status}
       </div>
     `;
+    if (msg.is_outgoing) {
+      addPendingAction(messageEl.querySelector('.message-meta'), msg);
+    }
     
     container.appendChild(messageEl);
   }
@@ -237,6 +293,8 @@ function renderMessages(messages) {
 function formatMessageContent(content) {
   if (content.Text) {
     return escapeHtml(content.Text.text);
+  } else if (content.RichText) {
+    return escapeHtml(content.RichText.text);
   } else if (content.Image) {
     return '📷 Image' + (content.Image.caption ? ': ' + escapeHtml(content.Image.caption) : '');
   } else if (content.File) {
@@ -245,17 +303,45 @@ function formatMessageContent(content) {
     return '🎤 Voice message (' + content.Voice.duration_secs + 's)';
   } else if (content.Location) {
     return '📍 Location';
+  } else if (content.Sticker) {
+    return escapeHtml(content.Sticker.emoji || 'Sticker');
+  } else if (content.StickerPack) {
+    return '🎨 Sticker pack: ' + escapeHtml(content.StickerPack.title);
+  } else if (content.System) {
+    return '👁 View-once media opened';
   }
   return 'Unknown message type';
 }
 
 function getStatusIcon(msg) {
-  if (msg.read) return '✓✓';
-  if (msg.delivered) return '✓✓';
-  if (msg.sent) return '✓';
+  const status = msg.status;
+  if (status === 'Read' || status === 'Delivered') return '✓✓';
+  if (status === 'Sent') return '✓';
+  if (status && status.Failed) return '⚠';
   return '⏳';
 }
 
+// Retry for failed messages, cancel for ones still waiting to go out
+function addPendingAction(meta, msg) {
+  const failed = msg.status && msg.status.Failed;
+  const pending = msg.status === 'Queued' || msg.status === 'Sending';
+  if (!failed && !pending) return;
+  
+  const button = document.createElement('button');
+  button.className = 'message-action';
+  button.textContent = failed ? 'Retry' : 'Cancel';
+  if (failed) button.title = msg.status.Failed.reason;
+  button.addEventListener('click', async () => {
+    try {
+      await invoke(failed ? 'resend_message' : 'cancel_pending_message', { messageId: msg.id });
+      await loadMessages(currentConversation.id);
+    } catch (e) {
+      showError('Failed to update message: ' + e);
+    }
+  });
+  meta.appendChild(button);
+}
+
 async function sendMessage() {
   const input = document.getElementById('message-input');
   const text = input.value.trim();
@@ -303,10 +389,28 @@ async function showNewContactModal() {
     const publicKey = await invoke('get_public_key');
     const keyStr = btoa(String.fromCharCode(...publicKey));
     document.getElementById('my-public-key').value = keyStr;
-    document.getElementById('qr-code').textContent = keyStr.substring(0, 20) + '...';
   } catch (e) {
     console.error('Failed to get public key:', e);
   }
+  
+  // One-time invite, so the first message can be sent right away
+  try {
+    const link = await invoke('create_invite', { validHours: 24, oneTime: true });
+    const qr = document.getElementById('qr-code');
+    qr.textContent = link.substring(0, 32) + '...';
+    qr.title = link;
+    qr.onclick = () => navigator.clipboard.writeText(link);
+  } catch (e) {
+    console.error('Failed to create invite:', e);
+  }
+  
+  // Code for someone on the same network to pair with
+  try {
+    const code = await invoke('start_pairing');
+    document.getElementById('pairing-code').textContent = code.substring(0, 3) + ' ' + code.substring(3);
+  } catch (e) {
+    console.error('Failed to start pairing:', e);
+  }
 }
 
 function closeModal() {
@@ -317,6 +421,32 @@ async function addContact() {
   const name = document.getElementById('contact-name').value.trim();
   const keyStr = document.getElementById('contact-key').value.trim();
   
+  if (keyStr.startsWith('securechat://invite#')) {
+    try {
+      const contact = await invoke('accept_invite', { link: keyStr });
+      await invoke('get_or_create_conversation', { contactId: contact.id });
+      await loadConversations();
+      closeModal();
+      document.getElementById('contact-name').value = '';
+      document.getElementById('contact-key').value = '';
+    } catch (e) {
+      console.error('Failed to accept invite:', e);
+      showError('Failed to accept invite: ' + e);
+    }
+    return;
+  }
+  
+  if (/^\d{3}[ -]?\d{3}$/.test(keyStr)) {
+    try {
+      await invoke('join_pairing', { code: keyStr });
+      document.getElementById('contact-key').value = '';
+    } catch (e) {
+      console.error('Failed to join pairing:', e);
+      showError('Failed to join pairing: ' + e);
+    }
+    return;
+  }
+  
   if (!name || !keyStr) {
     showError('Please enter both name and public key');
     return;
@@ -358,6 +488,37 @@ function updateContactStatus(contactId, online) {
 }
 
 // Utility functions
+function renderOperationProgress(progress) {
+  let list = document.getElementById('operations');
+  if (!list) {
+    list = document.createElement('div');
+    list.id = 'operations';
+    list.style.cssText = 'position: fixed; right: 16px; bottom: 16px; display: flex; flex-direction: column; gap: 8px;';
+    document.body.appendChild(list);
+  }
+  
+  let row = document.getElementById(`operation-${progress.id}`);
+  if (progress.finished) {
+    row?.remove();
+    return;
+  }
+  if (!row) {
+    row = document.createElement('div');
+    row.id = `operation-${progress.id}`;
+    row.innerHTML = `<span></span> <progress></progress> <button class="btn">Cancel</button>`;
+    row.querySelector('button').onclick = () => invoke('cancel_operation', { id: progress.id });
+    list.appendChild(row);
+  }
+  row.querySelector('span').textContent = progress.operation.replace(/_/g, ' ');
+  const bar = row.querySelector('progress');
+  if (progress.total) {
+    bar.max = progress.total;
+    bar.value = progress.done;
+  } else {
+    bar.removeAttribute('value');
+  }
+}
+
 function escapeHtml(text) {
   const div = document.createElement('div');
   div.textContent = text;